@@ -17,7 +17,20 @@ pub fn generate_mb2_header() -> Vec<u8> {
     builder = builder.module_align_tag(ModuleAlignHeaderTag::new(HeaderTagFlag::Required));
 
     let mut mbi_builder = InformationRequestHeaderTagBuilder::new(HeaderTagFlag::Required);
-    mbi_builder = mbi_builder.add_irs(&[MbiTagType::Mmap, MbiTagType::AcpiV2, MbiTagType::End]);
+    mbi_builder = mbi_builder.add_irs(&[MbiTagType::Mmap, MbiTagType::AcpiV2]);
+
+    // Only a preference; the kernel uses whatever direct color mode GRUB
+    // picks.
+    if env::var_os("CARGO_FEATURE_FRAMEBUFFER").is_some() {
+        builder = builder.framebuffer_tag(FramebufferHeaderTag::new(
+            HeaderTagFlag::Optional,
+            1024,
+            768,
+            32,
+        ));
+        mbi_builder = mbi_builder.add_ir(MbiTagType::Framebuffer);
+    }
+    mbi_builder = mbi_builder.add_ir(MbiTagType::End);
     builder = builder.information_request_tag(mbi_builder);
 
     builder.build()
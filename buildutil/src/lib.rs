@@ -1,3 +1,5 @@
+pub mod qemu;
+
 use std::process::{self, Command};
 
 use eyre::WrapErr;
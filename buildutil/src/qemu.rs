@@ -0,0 +1,220 @@
+//! Booting an image under QEMU and judging the result
+//!
+//! The debug console, and optionally the serial port, are written to files
+//! that are followed while QEMU runs. A boot passes when a success marker
+//! shows up in the output or the kernel exits QEMU through the isa-debug-exit
+//! device with the success code, and fails on a failure marker, any other
+//! exit, or a timeout. A marker ends the boot right away, so a kernel that
+//! keeps running after reporting doesn't have to exit QEMU itself.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Printed by the kernel's panic handler.
+pub const PANIC_MARKER: &str = "PANIC in task";
+
+/// How often the output is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How to boot and what to look for.
+#[derive(Clone, Debug)]
+pub struct Boot {
+    /// The QEMU binary to run.
+    pub qemu: String,
+    /// An ISO, booted as a CD, or a raw disk image.
+    pub image: PathBuf,
+    /// Firmware to boot with instead of QEMU's BIOS, e.g. OVMF for UEFI.
+    pub firmware: Option<PathBuf>,
+    /// Guest memory, in QEMU's syntax, e.g. `256M`.
+    pub memory: String,
+    pub cpus: u32,
+    /// Where the debug console output goes.
+    pub log: PathBuf,
+    /// Where serial port output goes, if anywhere.
+    pub serial_log: Option<PathBuf>,
+    /// Output that means the boot passed.
+    pub success_markers: Vec<String>,
+    /// Output that means the boot failed.
+    pub failure_markers: Vec<String>,
+    pub timeout: Duration,
+    /// Copy the output to stdout as it arrives.
+    pub echo: bool,
+    /// Passed to QEMU after everything else.
+    pub extra_args: Vec<String>,
+}
+
+impl Boot {
+    /// Boot `image` with one CPU, 256 MiB and QEMU from the path, failing on
+    /// a panic.
+    pub fn new(image: impl Into<PathBuf>, log: impl Into<PathBuf>) -> Boot {
+        Boot {
+            qemu: "qemu-system-x86_64".to_string(),
+            image: image.into(),
+            firmware: None,
+            memory: "256M".to_string(),
+            cpus: 1,
+            log: log.into(),
+            serial_log: None,
+            success_markers: Vec::new(),
+            failure_markers: vec![PANIC_MARKER.to_string()],
+            timeout: Duration::from_secs(60),
+            echo: false,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// Why the boot failed.
+    Failed(String),
+    TimedOut,
+}
+
+/// Boot as `boot` says and wait for the outcome. Errors are only returned if
+/// QEMU couldn't be run or its output couldn't be read.
+pub fn run(boot: &Boot) -> eyre::Result<Outcome> {
+    let mut outputs = vec![Output::new(&boot.log)?];
+    if let Some(serial_log) = &boot.serial_log {
+        outputs.push(Output::new(serial_log)?);
+    }
+
+    let mut child = spawn(boot)?;
+    let deadline = Instant::now() + boot.timeout;
+    loop {
+        let status = child.try_wait()?;
+        for output in &mut outputs {
+            output.poll(boot.echo)?;
+            if let Some(marker) = output.find(&boot.failure_markers) {
+                stop(&mut child)?;
+                return Ok(Outcome::Failed(format!("found {marker:?} in the output")));
+            }
+            if output.find(&boot.success_markers).is_some() {
+                stop(&mut child)?;
+                return Ok(Outcome::Passed);
+            }
+        }
+        if let Some(status) = status {
+            return Ok(exit_outcome(status));
+        }
+        if Instant::now() >= deadline {
+            stop(&mut child)?;
+            return Ok(Outcome::TimedOut);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn spawn(boot: &Boot) -> eyre::Result<Child> {
+    let mut command = Command::new(&boot.qemu);
+    if boot.image.extension().is_some_and(|ext| ext == "iso") {
+        command.arg("-cdrom").arg(&boot.image);
+    } else {
+        command
+            .arg("-drive")
+            .arg(format!("format=raw,file={}", boot.image.display()));
+    }
+    if let Some(firmware) = &boot.firmware {
+        command.arg("-bios").arg(firmware);
+    }
+    command
+        .args(["-m", &boot.memory])
+        .args(["-smp", &boot.cpus.to_string()])
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .args(["-display", "none", "-no-reboot"])
+        .arg("-debugcon")
+        .arg(format!("file:{}", boot.log.display()))
+        .arg("-serial");
+    match &boot.serial_log {
+        Some(serial_log) => command.arg(format!("file:{}", serial_log.display())),
+        None => command.arg("none"),
+    };
+    command.args(&boot.extra_args);
+    Ok(command.spawn()?)
+}
+
+fn stop(child: &mut Child) -> io::Result<()> {
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+/// Interpret QEMU's exit status. See the kernel's `qemu::ExitCode`.
+fn exit_outcome(status: ExitStatus) -> Outcome {
+    let reason = match status.code() {
+        Some(1) => return Outcome::Passed,
+        Some(3) => "init exited with an error, or an in-kernel test failed",
+        Some(5) => "the kernel panicked",
+        // Without the exit device QEMU only stops on its own with
+        // `-no-reboot` after a triple fault.
+        Some(0) => "QEMU exited without the exit device, e.g. on a triple fault",
+        _ => return Outcome::Failed(format!("QEMU failed: {status}")),
+    };
+    Outcome::Failed(reason.to_string())
+}
+
+/// An output file QEMU is writing, and what has been read from it.
+struct Output {
+    path: PathBuf,
+    file: Option<File>,
+    text: Vec<u8>,
+    /// Where the text read by the last `poll` starts.
+    new_start: usize,
+}
+
+impl Output {
+    /// Remove any output from an earlier boot, so it isn't mistaken for this
+    /// one's.
+    fn new(path: &Path) -> io::Result<Output> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        Ok(Output {
+            path: path.to_path_buf(),
+            file: None,
+            text: Vec::new(),
+            new_start: 0,
+        })
+    }
+
+    /// Read what QEMU has written since the last call.
+    fn poll(&mut self, echo: bool) -> io::Result<()> {
+        if self.file.is_none() {
+            match File::open(&self.path) {
+                Ok(file) => self.file = Some(file),
+                // QEMU hasn't created it yet.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        self.new_start = self.text.len();
+        self.file.as_mut().unwrap().read_to_end(&mut self.text)?;
+        if echo {
+            io::stdout().write_all(&self.text[self.new_start..])?;
+        }
+        Ok(())
+    }
+
+    /// The first of `markers` in the output the last `poll` read, if any.
+    /// Earlier output was already searched.
+    fn find<'a>(&self, markers: &'a [String]) -> Option<&'a str> {
+        markers
+            .iter()
+            .filter(|marker| !marker.is_empty())
+            .find(|marker| {
+                // Include the end of the earlier output, in case the marker
+                // was split across reads.
+                let start = self.new_start.saturating_sub(marker.len() - 1);
+                self.text[start..]
+                    .windows(marker.len())
+                    .any(|window| window == marker.as_bytes())
+            })
+            .map(String::as_str)
+    }
+}
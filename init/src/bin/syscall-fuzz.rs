@@ -0,0 +1,151 @@
+//! Makes system calls with random numbers, pointers and lengths to check that
+//! the kernel rejects bad arguments instead of crashing.
+//!
+//! Usage: `syscall-fuzz [seconds] [seed]`. Runs for 10 seconds by default. The
+//! seed is logged so a failing run can be repeated. Built with the `qemu_exit`
+//! feature, the kernel exits QEMU with success when this returns, and with an
+//! error if the kernel panics.
+
+#![no_main]
+#![no_std]
+
+use core::time::Duration;
+
+use init::{log, StartInfo, MAX_LOG_LEN, SYSCALL_ERROR, SYS_EXIT, SYS_TIMER_READ};
+
+init::entry!(main);
+
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+
+/// One past the highest system call number to try. Covers every valid call
+/// and some invalid ones.
+const MAX_SYSCALL: u64 = 16;
+
+fn main(start: StartInfo) -> u64 {
+    let mut args = start.args().skip(1);
+
+    let duration = match args.next() {
+        Some(arg) => match arg.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => return usage(),
+        },
+        None => DEFAULT_DURATION,
+    };
+
+    let seed = match args.next() {
+        Some(arg) => match arg.parse() {
+            Ok(seed) => seed,
+            Err(_) => return usage(),
+        },
+        None => start
+            .random()
+            .map(|bytes| u64::from_le_bytes(bytes[..8].try_into().unwrap()))
+            .unwrap_or(0),
+    };
+
+    log!("syscall-fuzz: running for {duration:?} with seed {seed}");
+
+    let mut fuzzer = Fuzzer {
+        rng: Rng(seed),
+        // Nonzero bytes that aren't valid UTF-8, so logging from the buffer
+        // fails without printing anything.
+        buffer: [0xff; 2 * MAX_LOG_LEN],
+    };
+
+    let deadline = init::uptime() + duration;
+    let mut calls: u64 = 0;
+    let mut errors: u64 = 0;
+    while init::uptime() < deadline {
+        if fuzzer.call() == SYSCALL_ERROR {
+            errors += 1;
+        }
+        calls += 1;
+    }
+
+    log!("syscall-fuzz: made {calls} calls, {errors} failed; kernel survived");
+    0
+}
+
+fn usage() -> u64 {
+    log!("usage: syscall-fuzz [seconds] [seed]");
+    1
+}
+
+struct Fuzzer {
+    rng: Rng,
+    buffer: [u8; 2 * MAX_LOG_LEN],
+}
+
+impl Fuzzer {
+    /// Make one random system call and return its result.
+    fn call(&mut self) -> u64 {
+        let num = loop {
+            let num = self.rng.below(MAX_SYSCALL);
+            if num != SYS_EXIT {
+                break num;
+            }
+        };
+        let arg0 = self.pointer();
+        let mut arg1 = self.length();
+        if num == SYS_TIMER_READ {
+            // Don't wait on a timer that random arguments may have set to
+            // expire in centuries.
+            arg1 &= !init::TIMER_WAIT;
+        }
+        let arg2 = self.rng.next();
+        // SAFETY: the kernel must check every argument, which is the point.
+        // Valid calls only read from the arguments.
+        unsafe { init::syscall(num, arg0, arg1, arg2) }
+    }
+
+    fn pointer(&mut self) -> u64 {
+        let buffer = self.buffer.as_ptr() as u64;
+        match self.rng.below(10) {
+            0 => 0,
+            1 => buffer,
+            2 => buffer + self.rng.below(self.buffer.len() as u64),
+            // Top of the user half.
+            3 => 0x0000_7fff_ffff_f000 + self.rng.below(0x1000),
+            // Physical memory map.
+            4 => 0xffff_8000_0000_0000 + self.rng.below(1 << 32),
+            // Kernel image.
+            5 => 0xffff_ffff_8000_0000 + self.rng.below(1 << 24),
+            // Non-canonical.
+            6 => 0x0000_8000_0000_0000 + self.rng.below(1 << 40),
+            // VGA text buffer, identity mapped for the kernel.
+            7 => 0xb8000,
+            8 => u64::MAX - self.rng.below(0x1000),
+            _ => self.rng.next(),
+        }
+    }
+
+    fn length(&mut self) -> u64 {
+        match self.rng.below(8) {
+            0 => 0,
+            1 => 1,
+            2 => MAX_LOG_LEN as u64 - 1 + self.rng.below(3),
+            3 => 4096,
+            4 => u64::MAX,
+            5 => 1 << 63,
+            6 => self.rng.below(self.buffer.len() as u64 + 1),
+            _ => self.rng.next(),
+        }
+    }
+}
+
+/// splitmix64
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
@@ -0,0 +1,269 @@
+//! Runtime for testos user programs
+//!
+//! Provides the process entry point, system call wrappers, and a panic handler
+//! that logs the panic and exits. A program names its main function with
+//! `entry!`.
+
+#![no_std]
+
+use core::arch::{asm, global_asm};
+use core::ffi::{c_char, CStr};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+// Must match the kernel's system call interface.
+pub const SYS_WRITE_LOG: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_UPTIME: u64 = 3;
+pub const SYS_TIMER_CREATE: u64 = 4;
+pub const SYS_TIMER_SET: u64 = 5;
+pub const SYS_TIMER_READ: u64 = 6;
+pub const SYS_TIMER_DELETE: u64 = 7;
+
+/// `SYS_TIMER_READ` flag to wait for an expiry.
+pub const TIMER_WAIT: u64 = 1;
+
+/// Returned by failed system calls.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+/// A system call failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SyscallError;
+
+/// The longest message `SYS_WRITE_LOG` accepts.
+pub const MAX_LOG_LEN: usize = 1024;
+
+/// What the kernel passes a new process.
+pub struct StartInfo {
+    args: &'static [*const c_char],
+    random: Option<[u8; 16]>,
+}
+
+impl StartInfo {
+    /// The arguments, starting with the program name. Arguments that aren't
+    /// UTF-8 are empty.
+    pub fn args(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.args
+            .iter()
+            // SAFETY: the kernel gives null terminated strings that live as
+            // long as the process.
+            .map(|&arg| unsafe { CStr::from_ptr(arg) }.to_str().unwrap_or(""))
+    }
+
+    /// 16 random bytes from the kernel.
+    pub fn random(&self) -> Option<[u8; 16]> {
+        self.random
+    }
+}
+
+/// Define the program's main function, which takes a `StartInfo` and returns
+/// the exit code.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "user_main"]
+        fn __user_main(start: $crate::StartInfo) -> u64 {
+            $main(start)
+        }
+    };
+}
+
+extern "Rust" {
+    fn user_main(start: StartInfo) -> u64;
+}
+
+// The initial stack pointer points at argc, which `start` needs, so the entry
+// point is in assembly. The stack is already aligned.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+const AT_NULL: u64 = 0;
+const AT_RANDOM: u64 = 25;
+
+/// # Safety
+///
+/// `stack` must be the initial stack pointer laid out by the kernel.
+unsafe extern "C" fn start(stack: *const u64) -> ! {
+    let start = unsafe {
+        let argc = *stack as usize;
+        let argv = stack.add(1).cast::<*const c_char>();
+        let args = core::slice::from_raw_parts(argv, argc);
+
+        // Skip argv and envp, with their terminators.
+        let mut word = stack.add(1 + argc + 1);
+        while *word != 0 {
+            word = word.add(1);
+        }
+        word = word.add(1);
+
+        let mut random = None;
+        while *word != AT_NULL {
+            if *word == AT_RANDOM {
+                random = Some(*(*word.add(1) as *const [u8; 16]));
+            }
+            word = word.add(2);
+        }
+
+        StartInfo { args, random }
+    };
+
+    exit(unsafe { user_main(start) })
+}
+
+/// Log a message through the kernel. Messages longer than `MAX_LOG_LEN` are
+/// rejected.
+pub fn log(msg: &str) -> Result<(), SyscallError> {
+    match unsafe { syscall(SYS_WRITE_LOG, msg.as_ptr() as u64, msg.len() as u64, 0) } {
+        SYSCALL_ERROR => Err(SyscallError),
+        _ => Ok(()),
+    }
+}
+
+/// Format and log a message, truncated to `MAX_LOG_LEN` bytes.
+pub fn log_fmt(args: fmt::Arguments<'_>) {
+    let mut buffer = LogBuffer {
+        bytes: [0; MAX_LOG_LEN],
+        len: 0,
+    };
+    let _ = buffer.write_fmt(args);
+    // Truncation may have split a character.
+    let msg = match core::str::from_utf8(&buffer.bytes[..buffer.len]) {
+        Ok(msg) => msg,
+        Err(e) => core::str::from_utf8(&buffer.bytes[..e.valid_up_to()]).unwrap(),
+    };
+    let _ = log(msg);
+}
+
+/// Like `format!`, but logs the result.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log_fmt(format_args!($($arg)*))
+    };
+}
+
+struct LogBuffer {
+    bytes: [u8; MAX_LOG_LEN],
+    len: usize,
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub fn yield_now() {
+    unsafe {
+        syscall(SYS_YIELD, 0, 0, 0);
+    }
+}
+
+pub fn exit(code: u64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code, 0, 0);
+    }
+    unreachable!()
+}
+
+/// The time since the kernel booted.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(unsafe { syscall(SYS_UPTIME, 0, 0, 0) })
+}
+
+/// Block for at least `duration`.
+pub fn sleep(duration: Duration) -> Result<(), SyscallError> {
+    if duration.is_zero() {
+        return Ok(());
+    }
+    let timer = Timer::new()?;
+    timer.set(duration, Duration::ZERO)?;
+    timer.wait().map(|_| ())
+}
+
+/// A kernel timer, deleted when dropped.
+pub struct Timer {
+    id: u64,
+}
+
+impl Timer {
+    /// A disarmed timer.
+    pub fn new() -> Result<Timer, SyscallError> {
+        let id = check(unsafe { syscall(SYS_TIMER_CREATE, 0, 0, 0) })?;
+        Ok(Timer { id })
+    }
+
+    /// Expire after `initial`, then every `interval` unless it is zero. A
+    /// zero `initial` disarms the timer. Expiries not yet read are
+    /// discarded.
+    pub fn set(&self, initial: Duration, interval: Duration) -> Result<(), SyscallError> {
+        let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        check(unsafe { syscall(SYS_TIMER_SET, self.id, nanos(initial), nanos(interval)) })
+            .map(|_| ())
+    }
+
+    /// The number of expiries since the last read, possibly zero.
+    pub fn read(&self) -> Result<u64, SyscallError> {
+        check(unsafe { syscall(SYS_TIMER_READ, self.id, 0, 0) })
+    }
+
+    /// Block until the timer has expired, then return the number of
+    /// expiries since the last read. Fails if the timer is disarmed.
+    pub fn wait(&self) -> Result<u64, SyscallError> {
+        check(unsafe { syscall(SYS_TIMER_READ, self.id, TIMER_WAIT, 0) })
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe {
+            syscall(SYS_TIMER_DELETE, self.id, 0, 0);
+        }
+    }
+}
+
+fn check(result: u64) -> Result<u64, SyscallError> {
+    match result {
+        SYSCALL_ERROR => Err(SyscallError),
+        result => Ok(result),
+    }
+}
+
+/// Make a raw system call.
+///
+/// # Safety
+///
+/// The arguments must be valid for the call, e.g. pointers the kernel reads
+/// must point to memory that may be read.
+pub unsafe fn syscall(num: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let result;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") num => result,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            out("rcx") _,
+            out("r11") _,
+        );
+    }
+    result
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo<'_>) -> ! {
+    log!("panic: {info}");
+    exit(101)
+}
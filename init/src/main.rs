@@ -1,14 +1,20 @@
 #![no_main]
 #![no_std]
 
-use core::panic::PanicInfo;
+use core::time::Duration;
 
-#[export_name = "_start"]
-pub extern "C" fn start() -> ! {
-    loop {}
-}
+init::entry!(main);
+
+fn main(_start: init::StartInfo) -> u64 {
+    init::log!("Hello from init");
+    init::yield_now();
+    init::log!("init is back after yielding");
 
-#[panic_handler]
-fn panic(_info: &PanicInfo<'_>) -> ! {
-    loop {}
+    let start = init::uptime();
+    let slept = init::sleep(Duration::from_millis(50)).map(|()| init::uptime() - start);
+    init::log!("init slept for {slept:?}");
+    if !slept.is_ok_and(|slept| slept >= Duration::from_millis(50)) {
+        return 1;
+    }
+    0
 }
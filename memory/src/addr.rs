@@ -253,7 +253,7 @@ impl<Type: AddressType> Extent<Type> {
     ///
     ///
     /// ```
-    /// use shared::memory::addr::*;
+    /// use testos_memory::addr::*;
     /// assert_eq!(PhysExtent::from_raw(0, 4).last_address(), PhysAddress::from_raw(3));
     /// ```
     pub fn last_address(self) -> Address<Type> {
@@ -1,5 +1,9 @@
 #[cfg(feature = "alloc")]
 pub mod heap;
 pub mod phys;
+pub mod refcount;
+pub mod zone;
 
 pub use phys::*;
+pub use refcount::*;
+pub use zone::*;
@@ -0,0 +1,1037 @@
+//! A simple heap allocator for arbitrary-sized allocations.
+
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use intrusive_collections::UnsafeRef;
+use intrusive_collections::{singly_linked_list as sll, Adapter};
+use num_traits::{FromPrimitive, ToPrimitive};
+use spin::{Mutex, MutexGuard};
+use static_assertions::const_assert;
+
+#[cfg(feature = "debug_alloc")]
+mod debug;
+
+pub const DEFAULT_CHUNK_SIZE: usize = crate::page::PAGE_SIZE.as_raw() as usize;
+
+/// Provides backing memory to `Heap`. `CHUNK_SIZE` must be a power of 2.
+///
+/// # Safety
+///
+/// Follow safety comments on methods.
+pub unsafe trait ChunkProvider<const CHUNK_SIZE: usize = DEFAULT_CHUNK_SIZE> {
+    /// Allocate `num_chunks` contiguous chunks.
+    ///
+    /// # Safety
+    ///
+    /// The implementation must return a valid slice sized and aligned to
+    /// CHUNK_SIZE. The client of `ChunkProvider` has exclusive access to this
+    /// slice thereafter.
+    fn allocate(&mut self, num_chunks: usize) -> *mut [MaybeUninit<u8>];
+
+    /// Take back chunks returned by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// `chunks` must be exactly what one call to `allocate` returned, and the
+    /// client must not use it afterwards.
+    unsafe fn release(&mut self, chunks: *mut [MaybeUninit<u8>]);
+}
+
+/// Size-class heap. Each size class's free list has its own lock, and
+/// fetching chunks from the provider takes a separate lock, so allocations of
+/// different sizes don't serialize.
+///
+/// No two of these locks are ever held at once, so there is no lock order to
+/// follow.
+///
+/// Freed blocks can be held in a quarantine before they are reused, to catch
+/// use after free. See `set_quarantine_len`. With the `debug_alloc` feature,
+/// allocations also get red zones and are tracked, to catch overflows and
+/// double frees; see `debug`.
+pub struct Heap<Provider, const CHUNK_SIZE: usize = DEFAULT_CHUNK_SIZE> {
+    free_lists: [CountedMutex<sll::SinglyLinkedList<BlockAdapter>>; NUM_BLOCK_SIZES],
+    provider: CountedMutex<Provider>,
+    quarantine: CountedMutex<Quarantine>,
+    /// How many freed blocks `quarantine` holds before releasing the oldest.
+    quarantine_len: AtomicUsize,
+    quarantine_added: AtomicUsize,
+    quarantine_released: AtomicUsize,
+    quarantine_corrupted: AtomicUsize,
+    /// Bytes requested by every allocation and free so far.
+    allocated_bytes: AtomicUsize,
+    freed_bytes: AtomicUsize,
+    #[cfg(feature = "debug_alloc")]
+    debug: debug::Tracker,
+}
+
+/// How often each of `Heap`'s locks was found already held.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapContention {
+    /// Per size class, smallest first.
+    pub free_lists: [usize; NUM_BLOCK_SIZES],
+    pub provider: usize,
+    pub quarantine: usize,
+}
+
+/// Bytes allocated and freed since the heap was created, as requested rather
+/// than rounded up to a block or chunk.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapStats {
+    pub allocated: usize,
+    pub freed: usize,
+}
+
+impl HeapStats {
+    /// Bytes currently allocated.
+    pub fn in_use(&self) -> usize {
+        self.allocated.saturating_sub(self.freed)
+    }
+}
+
+/// The most freed blocks the quarantine can hold.
+pub const MAX_QUARANTINE_LEN: usize = 1024;
+
+/// Freed blocks are filled with this while quarantined.
+const POISON: u8 = 0x6b;
+
+/// What the quarantine has done since the heap was created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QuarantineStats {
+    /// Blocks waiting to be reused.
+    pub quarantined: usize,
+    /// Blocks that left the quarantine to be reused.
+    pub released: usize,
+    /// Released blocks whose poison had been overwritten, i.e. that were
+    /// written after being freed.
+    pub corrupted: usize,
+}
+
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+    addr: usize,
+    key: BlockSizeKey,
+}
+
+/// A FIFO of freed blocks.
+struct Quarantine {
+    blocks: [QuarantinedBlock; MAX_QUARANTINE_LEN],
+    /// Index of the oldest block.
+    head: usize,
+    len: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Quarantine {
+            blocks: [QuarantinedBlock {
+                addr: 0,
+                key: BlockSizeKey::Size16,
+            }; MAX_QUARANTINE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Add `block`. There must be room.
+    fn push(&mut self, block: QuarantinedBlock) {
+        assert!(self.len < MAX_QUARANTINE_LEN);
+        self.blocks[(self.head + self.len) % MAX_QUARANTINE_LEN] = block;
+        self.len += 1;
+    }
+
+    /// Remove the oldest block if there are more than `limit`.
+    fn pop_over(&mut self, limit: usize) -> Option<QuarantinedBlock> {
+        if self.len <= limit {
+            return None;
+        }
+        let block = self.blocks[self.head];
+        self.head = (self.head + 1) % MAX_QUARANTINE_LEN;
+        self.len -= 1;
+        Some(block)
+    }
+}
+
+/// A spin lock that counts how often it was contended.
+struct CountedMutex<T> {
+    inner: Mutex<T>,
+    contended: AtomicUsize,
+}
+
+impl<T> CountedMutex<T> {
+    const fn new(val: T) -> Self {
+        CountedMutex {
+            inner: Mutex::new(val),
+            contended: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        if let Some(guard) = self.inner.try_lock() {
+            return guard;
+        }
+
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.inner.lock()
+    }
+
+    fn contended(&self) -> usize {
+        self.contended.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Default)]
+struct BlockAdapter {
+    link_ops: sll::AtomicLinkOps,
+    pointer_ops: intrusive_collections::DefaultPointerOps<UnsafeRef<FreeBlock>>,
+}
+
+impl BlockAdapter {
+    const fn new() -> Self {
+        BlockAdapter {
+            link_ops: sll::AtomicLinkOps,
+            pointer_ops: intrusive_collections::DefaultPointerOps::new(),
+        }
+    }
+}
+
+unsafe impl Adapter for BlockAdapter {
+    type LinkOps = sll::AtomicLinkOps;
+    type PointerOps = intrusive_collections::DefaultPointerOps<UnsafeRef<FreeBlock>>;
+
+    unsafe fn get_link(
+        &self,
+        value: *const <Self::PointerOps as intrusive_collections::PointerOps>::Value,
+    ) -> <Self::LinkOps as intrusive_collections::LinkOps>::LinkPtr {
+        unsafe { NonNull::new_unchecked(addr_of!((*value).header.link) as *mut _) }
+    }
+
+    unsafe fn get_value(
+        &self,
+        link: <Self::LinkOps as intrusive_collections::LinkOps>::LinkPtr,
+    ) -> *const <Self::PointerOps as intrusive_collections::PointerOps>::Value {
+        let offset = memoffset::offset_of!(FreeBlockData, link);
+        // SAFETY: `link` points to the `link` field of a `FreeBlockData`. We
+        // offset the pointer accordingly and get a reference to the owning
+        // `FreeBlockData`.
+        let header =
+            unsafe { &*(link.as_ptr().byte_offset(-(offset as isize)) as *const FreeBlockData) };
+
+        let size = header.size.size();
+        core::ptr::from_raw_parts::<FreeBlock>(
+            header as *const _ as *const (),
+            FreeBlock::metadata_from_size(size),
+        )
+    }
+
+    fn link_ops(&self) -> &Self::LinkOps {
+        &self.link_ops
+    }
+
+    fn link_ops_mut(&mut self) -> &mut Self::LinkOps {
+        &mut self.link_ops
+    }
+
+    fn pointer_ops(&self) -> &Self::PointerOps {
+        &self.pointer_ops
+    }
+}
+
+impl<Provider: ChunkProvider<CHUNK_SIZE>, const CHUNK_SIZE: usize> Heap<Provider, CHUNK_SIZE> {
+    pub const fn new(provider: Provider) -> Self {
+        // Ideally this would be a static assertion like in C++, but I can't
+        // figure out how. This will almost definitely be optimized out anyway.
+        assert!(CHUNK_SIZE >= *BLOCK_SIZES.last().unwrap());
+        assert!(CHUNK_SIZE.is_power_of_two());
+        Heap {
+            free_lists: [
+                CountedMutex::new(sll::SinglyLinkedList::new(BlockAdapter::new())),
+                CountedMutex::new(sll::SinglyLinkedList::new(BlockAdapter::new())),
+                CountedMutex::new(sll::SinglyLinkedList::new(BlockAdapter::new())),
+                CountedMutex::new(sll::SinglyLinkedList::new(BlockAdapter::new())),
+                CountedMutex::new(sll::SinglyLinkedList::new(BlockAdapter::new())),
+            ],
+            provider: CountedMutex::new(provider),
+            quarantine: CountedMutex::new(Quarantine::new()),
+            quarantine_len: AtomicUsize::new(0),
+            quarantine_added: AtomicUsize::new(0),
+            quarantine_released: AtomicUsize::new(0),
+            quarantine_corrupted: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            freed_bytes: AtomicUsize::new(0),
+            #[cfg(feature = "debug_alloc")]
+            debug: debug::Tracker::new(),
+        }
+    }
+
+    /// Lock contention counts since the heap was created.
+    pub fn contention(&self) -> HeapContention {
+        HeapContention {
+            free_lists: core::array::from_fn(|i| self.free_lists[i].contended()),
+            provider: self.provider.contended(),
+            quarantine: self.quarantine.contended(),
+        }
+    }
+
+    /// Hold the last `len` freed blocks, at most `MAX_QUARANTINE_LEN`, before
+    /// reusing them. They are poisoned while held, and a block whose poison
+    /// changed by the time it is released counts as corrupted in
+    /// `quarantine_stats`. 0, the default, reuses blocks immediately.
+    pub fn set_quarantine_len(&self, len: usize) {
+        self.quarantine_len
+            .store(len.min(MAX_QUARANTINE_LEN), Ordering::Relaxed);
+    }
+
+    /// Doesn't take any locks, so it is safe to call when panicking.
+    pub fn quarantine_stats(&self) -> QuarantineStats {
+        let released = self.quarantine_released.load(Ordering::Relaxed);
+        QuarantineStats {
+            quarantined: self
+                .quarantine_added
+                .load(Ordering::Relaxed)
+                .saturating_sub(released),
+            released,
+            corrupted: self.quarantine_corrupted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Doesn't take any locks, so it is safe to call when panicking.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            allocated: self.allocated_bytes.load(Ordering::Relaxed),
+            freed: self.freed_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn allocate(&self, layout: Layout) -> *mut [u8] {
+        #[cfg(not(feature = "debug_alloc"))]
+        let ptr = self.allocate_unchecked(layout);
+        #[cfg(feature = "debug_alloc")]
+        let ptr = match self.allocate_unchecked(debug::padded(layout)) {
+            padded if padded.is_null() => padded,
+            // SAFETY: just allocated for `padded(layout)`.
+            padded => unsafe { self.debug.track(padded as *mut u8, layout) },
+        };
+
+        if !ptr.is_null() {
+            self.allocated_bytes
+                .fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    /// `allocate`, without the `debug_alloc` red zones.
+    fn allocate_unchecked(&self, layout: Layout) -> *mut [u8] {
+        match self.key_for_size_align(layout.size(), layout.align()) {
+            Some(key) => self.allocate_small(key, layout),
+            None => {
+                let chunks = layout.size().div_ceil(CHUNK_SIZE);
+                let ptr: *mut [MaybeUninit<u8>] = self.provider.lock().allocate(chunks);
+                ptr as *mut [u8]
+            }
+        }
+    }
+
+    fn allocate_small(&self, key: BlockSizeKey, layout: Layout) -> *mut [u8] {
+        // Each list is locked only long enough to pop a block, so another
+        // allocation may race us to a list. If all are empty by the time we
+        // get to them, fetch a chunk and retry.
+        let block = loop {
+            let popped = self.free_lists[key.to_usize().unwrap()..]
+                .iter()
+                .find_map(|l| l.lock().pop_front());
+            match popped {
+                Some(block) => break block,
+                None => self.fetch_chunk(),
+            }
+        };
+
+        let block_ptr = UnsafeRef::into_raw(block);
+        assert!(block_ptr.is_aligned_to(layout.align()));
+        assert!(key.size() >= layout.size());
+
+        // A block from a larger class is split, keeping its first `key`-sized
+        // piece. Block sizes double, so the rest is one piece of each class
+        // from `key` up, each at an offset of its own size. Freeing the
+        // allocation then puts back exactly what was taken.
+        //
+        // SAFETY: the block was just unlinked, so its header is intact.
+        let from = unsafe { (*block_ptr).header.size };
+        let addr = block_ptr as *mut u8 as usize;
+        let mut piece = key;
+        while piece < from {
+            // SAFETY: the piece is part of the block we own, and nothing else
+            // uses it.
+            unsafe {
+                self.release(QuarantinedBlock {
+                    addr: addr + piece.size(),
+                    key: piece,
+                })
+            };
+            piece = BlockSizeKey::from_usize(piece as usize + 1).unwrap();
+        }
+
+        // The data in `block` does not need to be dropped. It was already
+        // unlinked from the list. It can be returned directly as a pointer,
+        // taking into account the size.
+        core::ptr::slice_from_raw_parts_mut(block_ptr as *mut u8, layout.size())
+    }
+
+    /// Free an allocation made with `layout`.
+    ///
+    /// Allocations too big for a block came straight from the provider, and
+    /// are released back to it. Blocks go back on the free list for
+    /// `layout`'s size class, which is the class `allocate_small` split them
+    /// to.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated from this heap with `layout`, and must
+    /// not be used afterwards.
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        self.freed_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+        #[cfg(feature = "debug_alloc")]
+        // SAFETY: the caller gave up `ptr`.
+        let (ptr, layout) = (
+            unsafe { self.debug.untrack(ptr, layout) },
+            debug::padded(layout),
+        );
+        unsafe { self.deallocate_unchecked(ptr, layout) }
+    }
+
+    /// `deallocate`, without the `debug_alloc` checks.
+    ///
+    /// # Safety
+    ///
+    /// As `deallocate`.
+    unsafe fn deallocate_unchecked(&self, ptr: *mut u8, layout: Layout) {
+        let Some(key) = self.key_for_size_align(layout.size(), layout.align()) else {
+            let chunks = layout.size().div_ceil(CHUNK_SIZE);
+            let mem = core::ptr::slice_from_raw_parts_mut(
+                ptr as *mut MaybeUninit<u8>,
+                chunks * CHUNK_SIZE,
+            );
+            // SAFETY: `allocate` got exactly these chunks from the provider,
+            // and the caller gave them up.
+            unsafe { self.provider.lock().release(mem) };
+            return;
+        };
+        let block = QuarantinedBlock {
+            addr: ptr as usize,
+            key,
+        };
+
+        let limit = self.quarantine_len.load(Ordering::Relaxed);
+        if limit == 0 {
+            unsafe { self.release(block) };
+        } else {
+            // SAFETY: the caller gave up the whole block.
+            unsafe { core::ptr::write_bytes(ptr, POISON, key.size()) };
+            self.quarantine.lock().push(block);
+            self.quarantine_added.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Release the oldest blocks beyond the limit, including any left over
+        // from a larger one. The quarantine lock is dropped before taking a
+        // free list lock.
+        loop {
+            let Some(block) = self.quarantine.lock().pop_over(limit) else {
+                break;
+            };
+            // SAFETY: the block was poisoned when it was freed, and nothing
+            // may have used it since.
+            let poisoned =
+                unsafe { core::slice::from_raw_parts(block.addr as *const u8, block.key.size()) };
+            if poisoned.iter().any(|&b| b != POISON) {
+                self.quarantine_corrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            self.quarantine_released.fetch_add(1, Ordering::Relaxed);
+            unsafe { self.release(block) };
+        }
+    }
+
+    /// Put a freed block on its free list.
+    ///
+    /// # Safety
+    ///
+    /// The block must be free and not on any list.
+    unsafe fn release(&self, block: QuarantinedBlock) {
+        // SAFETY: the block is `block.key.size()` bytes that nothing else
+        // uses.
+        let mem = unsafe {
+            core::slice::from_raw_parts_mut(block.addr as *mut MaybeUninit<u8>, block.key.size())
+        };
+        let (free_block, _) = FreeBlock::build(mem, block.key);
+        self.free_lists[block.key.to_usize().unwrap()]
+            .lock()
+            .push_front(unsafe { UnsafeRef::from_raw(free_block as *mut _) });
+    }
+
+    /// Get the smallest `BlockSizeKey` to fit `size`, or `None` if no block
+    /// size is large enough.
+    fn key_for_size_align(&self, size: usize, align: usize) -> Option<BlockSizeKey> {
+        let size = core::cmp::max(size, align);
+        let key_ndx = match BLOCK_SIZES.binary_search(&size) {
+            Ok(ndx) => ndx,
+            // Too big...need to allocate chunks directly for this.
+            Err(NUM_BLOCK_SIZES) => return None,
+            // `ndx` is the insertion point for `size` to keep it sorted. This
+            // means it points to the first element larger than `size`, which
+            // is what we want.
+            Err(ndx) => ndx,
+        };
+
+        Some(BlockSizeKey::from_usize(key_ndx).unwrap())
+    }
+
+    /// Get a new chunk from the system and link in its free blocks.
+    fn fetch_chunk(&self) {
+        let chunk_ptr = self.provider.lock().allocate(1);
+
+        // For little runtime cost, double-check `provider` met its
+        // requirements.
+        assert_eq!(chunk_ptr.len(), CHUNK_SIZE);
+        assert!(chunk_ptr.is_aligned_to(CHUNK_SIZE));
+
+        // These checks are a little paranoid but they can't hurt. They only
+        // depend on const-evaluable expressions so hopefully they'll be
+        // optimized out.
+        assert!(chunk_ptr.cast::<FreeBlockData>().is_aligned());
+        assert!(chunk_ptr.len() >= core::mem::size_of::<FreeBlockData>());
+
+        // SAFETY: `provider` is guaranteed to return a valid pointer to
+        // CHUNK_SIZE size and aligned bytes, and `MaybeUninit<_>` means we can
+        // create a reference despite it not being initialized.
+        let mut chunk: &'static mut [MaybeUninit<u8>] = unsafe { &mut *chunk_ptr };
+
+        let mut free_list = self.free_lists.last().unwrap().lock();
+        while chunk.len() >= MAXIMAL_BLOCK_SIZE {
+            let block;
+            (block, chunk) = FreeBlock::build(chunk, BlockSizeKey::Size256);
+            free_list.push_front(unsafe { UnsafeRef::from_raw(block as *mut _) });
+        }
+    }
+}
+
+const NUM_BLOCK_SIZES: usize = 5;
+const BLOCK_SIZES: [usize; NUM_BLOCK_SIZES] = [16, 32, 64, 128, 256];
+const MAXIMAL_BLOCK_SIZE: usize = *BLOCK_SIZES.last().unwrap();
+
+// `allocate_small` splits blocks assuming each size is double the last.
+const _: () = {
+    let mut i = 1;
+    while i < NUM_BLOCK_SIZES {
+        assert!(BLOCK_SIZES[i] == 2 * BLOCK_SIZES[i - 1]);
+        i += 1;
+    }
+};
+
+/// Adapts `Heap` to the `GlobalAlloc` and `Allocator` interfaces.
+pub struct CheckedHeap<Provider, const CHUNK_SIZE: usize = DEFAULT_CHUNK_SIZE>(
+    pub Heap<Provider, CHUNK_SIZE>,
+);
+
+impl<Provider, const CHUNK_SIZE: usize> CheckedHeap<Provider, CHUNK_SIZE> {
+    pub const fn new(heap: Heap<Provider, CHUNK_SIZE>) -> Self {
+        CheckedHeap(heap)
+    }
+
+    pub fn get(&self) -> &Heap<Provider, CHUNK_SIZE> {
+        &self.0
+    }
+}
+
+unsafe impl<Provider: ChunkProvider<CHUNK_SIZE>, const CHUNK_SIZE: usize> GlobalAlloc
+    for CheckedHeap<Provider, CHUNK_SIZE>
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.get().allocate(layout) as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.get().deallocate(ptr, layout) }
+    }
+}
+
+unsafe impl<Provider: ChunkProvider<CHUNK_SIZE>, const CHUNK_SIZE: usize> Allocator
+    for CheckedHeap<Provider, CHUNK_SIZE>
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        NonNull::new(self.0.allocate(layout)).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.0.deallocate(ptr.as_ptr(), layout) }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    num_derive::FromPrimitive,
+    num_derive::ToPrimitive,
+)]
+#[repr(usize)]
+enum BlockSizeKey {
+    Size16 = 0,
+    Size32 = 1,
+    Size64 = 2,
+    Size128 = 3,
+    Size256 = 4,
+}
+
+impl BlockSizeKey {
+    const fn size(self) -> usize {
+        BLOCK_SIZES[self as usize]
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct FreeBlockData {
+    size: BlockSizeKey,
+    link: sll::AtomicLink,
+}
+
+const_assert!(core::mem::size_of::<FreeBlockData>() <= BLOCK_SIZES[0]);
+
+#[repr(C)]
+#[derive(Debug)]
+struct FreeBlock {
+    header: FreeBlockData,
+    _rest: [MaybeUninit<u8>],
+}
+
+impl FreeBlock {
+    /// Construct `FreeBlock` from a reference to a block. This constructs the
+    /// block header and returns a `&mut FreeBlock`, which is dynamically-sized.
+    /// It also returns a reference to the remaining memory after the block.
+    fn build(
+        mem: &mut [MaybeUninit<u8>],
+        size: BlockSizeKey,
+    ) -> (&mut FreeBlock, &mut [MaybeUninit<u8>]) {
+        let (block_mem, rest) = mem.split_at_mut(size.size());
+        let block_len = block_mem.len();
+
+        // Get a pointer to write our header at the start of the block.
+        let block_header = block_mem as *mut _ as *mut FreeBlockData;
+        assert!(block_header.is_aligned());
+
+        // Write the block header.
+        //
+        // SAFETY: `block_header` is valid and aligned. Since it's derived from an
+        // exclusive reference, it is safe to write to it.
+        unsafe {
+            block_header.write(FreeBlockData {
+                size,
+                link: sll::AtomicLink::new(),
+            });
+        }
+
+        // Create a `FreeBlock` pointer, where the written `FreeBlockData` value
+        // coincides with `FreeBlock`'s `header` field. Note that `block`'s
+        // metadata is the size of the `_rest` field, not of the entire value.
+        let block: *mut FreeBlock = core::ptr::from_raw_parts_mut(
+            block_mem as *mut _ as *mut (),
+            Self::metadata_from_size(block_len),
+        );
+
+        // SAFETY: `block` points to a valid `FreeBlockData` value followed by
+        // uninitialized data, which matches the representation of `FreeBlock`.
+        // `block` is correctly sized for the `_rest` field, and it is properly
+        // aligned.
+        assert_eq!(block_len, core::mem::size_of_val(unsafe { &*block }));
+        let block: &mut FreeBlock = unsafe { &mut *block };
+
+        (block, rest)
+    }
+
+    fn metadata_from_size(size: usize) -> usize {
+        size - core::mem::size_of::<FreeBlockData>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use test_log::test;
+
+    const PAGE_SIZE: usize = crate::page::PAGE_SIZE.as_raw() as usize;
+
+    #[test]
+    fn block_build() {
+        let mut mem_array = aligned::Aligned::<aligned::A64, _>([MaybeUninit::uninit(); PAGE_SIZE]);
+        let mem = &mut *mem_array;
+        let (block, _rest) = FreeBlock::build(mem, BlockSizeKey::Size256);
+
+        assert_eq!(block.header.size, BlockSizeKey::Size256);
+        assert_eq!(core::mem::size_of_val(&*block), 256);
+    }
+
+    #[test]
+    fn heap() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+
+        // Fetch a bunch of chunks and see what happens.
+        for _i in 0..50 {
+            heap.fetch_chunk();
+        }
+
+        let mut free_list = heap.free_lists.last().unwrap().lock();
+        for block in free_list.iter() {
+            assert_eq!(core::mem::size_of_val(block), block.header.size.size());
+            assert_eq!(BlockSizeKey::Size256, block.header.size);
+        }
+
+        while let Some(block) = free_list.pop_front() {
+            let block = unsafe { &*UnsafeRef::into_raw(block) };
+            assert_eq!(core::mem::size_of_val(block), block.header.size.size());
+            assert_eq!(BlockSizeKey::Size256, block.header.size);
+        }
+    }
+
+    // Using standard collections with `Heap` should be enough of a stress test.
+    #[test]
+    fn test_heap_with_collections() {
+        let provider = TestProvider {
+            allocations: Vec::new(),
+        };
+        let allocator = CheckedHeap::new(Heap::new(provider));
+        let mut vec = Vec::new_in(&allocator);
+        for i in 0..1000 {
+            vec.push(i);
+        }
+
+        let mut set = std::collections::HashSet::new();
+        for i in 0..1000 {
+            set.insert(i);
+        }
+
+        for i in (0..1000).rev() {
+            set.remove(&i);
+        }
+    }
+
+    #[test]
+    fn concurrent_allocations() {
+        let provider = TestProvider {
+            allocations: Vec::new(),
+        };
+        let heap = Heap::new(provider);
+        assert_eq!(heap.contention(), HeapContention::default());
+
+        // Each thread allocates a different size. No block may be handed out
+        // twice.
+        let blocks: Vec<Vec<usize>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = BLOCK_SIZES
+                .iter()
+                .map(|&size| {
+                    let heap = &heap;
+                    scope.spawn(move || {
+                        let layout = Layout::from_size_align(size, 8).unwrap();
+                        (0..500)
+                            .map(|_| heap.allocate(layout) as *mut u8 as usize)
+                            .collect()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        let mut all: Vec<usize> = blocks.into_iter().flatten().collect();
+        let count = all.len();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), count);
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let a = heap.allocate(layout(16)) as *mut u8;
+        let b = heap.allocate(layout(100)) as *mut u8;
+        unsafe {
+            heap.deallocate(a, layout(16));
+            heap.deallocate(b, layout(100));
+        }
+        assert_eq!(heap.allocate(layout(10)) as *mut u8, a);
+        assert_eq!(heap.allocate(layout(128)) as *mut u8, b);
+        assert_eq!(heap.quarantine_stats(), QuarantineStats::default());
+    }
+
+    #[test]
+    fn small_allocs_split_larger_blocks() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let chunk_blocks = PAGE_SIZE / MAXIMAL_BLOCK_SIZE;
+
+        // As many small allocations as the chunk has blocks only need one
+        // block between them.
+        let small: Vec<*mut u8> = (0..chunk_blocks)
+            .map(|_| heap.allocate(layout(16)) as *mut u8)
+            .collect();
+        for &ptr in &small {
+            unsafe { heap.deallocate(ptr, layout(16)) };
+        }
+
+        // The rest are still there for large allocations.
+        for _ in 1..chunk_blocks {
+            heap.allocate(layout(MAXIMAL_BLOCK_SIZE));
+        }
+        assert_eq!(heap.provider.lock().allocations.len(), 1);
+    }
+
+    #[test]
+    fn quarantine_delays_reuse() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        heap.set_quarantine_len(3);
+        let blocks: Vec<*mut u8> = (0..4)
+            .map(|_| heap.allocate(layout(16)) as *mut u8)
+            .collect();
+        for &block in &blocks[..3] {
+            unsafe { heap.deallocate(block, layout(16)) };
+            assert_eq!(unsafe { *block }, POISON);
+        }
+        let fresh = heap.allocate(layout(16)) as *mut u8;
+        assert!(!blocks.contains(&fresh));
+
+        // A fourth free pushes the first out.
+        unsafe { heap.deallocate(blocks[3], layout(16)) };
+        assert_eq!(heap.allocate(layout(16)) as *mut u8, blocks[0]);
+        assert_eq!(
+            heap.quarantine_stats(),
+            QuarantineStats {
+                quarantined: 3,
+                released: 1,
+                corrupted: 0,
+            }
+        );
+
+        // Shrinking the quarantine releases the extra blocks on the next
+        // free.
+        heap.set_quarantine_len(1);
+        unsafe { heap.deallocate(fresh, layout(16)) };
+        assert_eq!(heap.quarantine_stats().quarantined, 1);
+        assert_eq!(heap.quarantine_stats().released, 4);
+    }
+
+    #[test]
+    fn quarantine_detects_write_after_free() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        heap.set_quarantine_len(1);
+        let a = heap.allocate(layout(32)) as *mut u8;
+        let b = heap.allocate(layout(32)) as *mut u8;
+        unsafe {
+            heap.deallocate(a, layout(32));
+            *a.add(20) = 1;
+            heap.deallocate(b, layout(32));
+        }
+        assert_eq!(
+            heap.quarantine_stats(),
+            QuarantineStats {
+                quarantined: 1,
+                released: 1,
+                corrupted: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn stats_count_requested_bytes() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let small = heap.allocate(layout(10)) as *mut u8;
+        let large = heap.allocate(layout(2 * PAGE_SIZE)) as *mut u8;
+        unsafe { heap.deallocate(small, layout(10)) };
+        let stats = heap.stats();
+        assert_eq!(
+            stats,
+            HeapStats {
+                allocated: 10 + 2 * PAGE_SIZE,
+                freed: 10,
+            }
+        );
+        assert_eq!(stats.in_use(), 2 * PAGE_SIZE);
+        unsafe { heap.deallocate(large, layout(2 * PAGE_SIZE)) };
+        assert_eq!(heap.stats().in_use(), 0);
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    fn debug_alloc_keeps_alignment() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        for (size, align) in [(1, 1), (24, 8), (100, 64), (3000, 128), (5000, 4096)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = heap.allocate(layout) as *mut u8;
+            assert!(ptr.is_aligned_to(align), "{ptr:?} for {layout:?}");
+            unsafe {
+                core::ptr::write_bytes(ptr, 0xaa, size);
+                heap.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    fn debug_alloc_poisons_freed_memory() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(100)) as *mut u8;
+        unsafe {
+            core::ptr::write_bytes(ptr, 0xaa, 100);
+            heap.deallocate(ptr, layout(100));
+            // Small blocks stay with the heap, so are still readable.
+            let freed = core::slice::from_raw_parts(ptr, 100);
+            assert!(freed.iter().all(|&b| b == POISON));
+        }
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    #[should_panic(expected = "2 bytes after the end of the 24 byte allocation")]
+    fn debug_alloc_catches_overflow() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(24)) as *mut u8;
+        unsafe {
+            *ptr.add(25) = 0;
+            heap.deallocate(ptr, layout(24));
+        }
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    #[should_panic(expected = "2 bytes before the start of the 300 byte allocation")]
+    fn debug_alloc_catches_underflow() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(300)) as *mut u8;
+        unsafe {
+            *ptr.sub(2) = 0;
+            heap.deallocate(ptr, layout(300));
+        }
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    #[should_panic(expected = "which is not allocated")]
+    fn debug_alloc_catches_double_free() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(16)) as *mut u8;
+        unsafe {
+            heap.deallocate(ptr, layout(16));
+            heap.deallocate(ptr, layout(16));
+        }
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    #[should_panic(expected = "which is not allocated")]
+    fn debug_alloc_catches_bad_free() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(64)) as *mut u8;
+        unsafe { heap.deallocate(ptr.add(8), layout(56)) };
+    }
+
+    #[cfg(feature = "debug_alloc")]
+    #[test]
+    #[should_panic(expected = "as 32 bytes, but it was allocated with 48")]
+    fn debug_alloc_catches_wrong_size() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let ptr = heap.allocate(layout(48)) as *mut u8;
+        unsafe { heap.deallocate(ptr, layout(32)) };
+    }
+
+    #[test]
+    fn large_allocations_are_released() {
+        let heap = Heap::new(TestProvider {
+            allocations: Vec::new(),
+        });
+        let small = heap.allocate(layout(16)) as *mut u8;
+        let large = heap.allocate(layout(3 * PAGE_SIZE - 1)) as *mut u8;
+        assert_eq!(heap.provider.lock().allocations.len(), 2);
+
+        unsafe { heap.deallocate(large, layout(3 * PAGE_SIZE - 1)) };
+        let allocations = heap.provider.lock().allocations.clone();
+        assert_eq!(allocations.len(), 1);
+        assert_ne!(allocations[0].0, large as usize);
+
+        // Small blocks go back on a free list, not to the provider.
+        unsafe { heap.deallocate(small, layout(16)) };
+        assert_eq!(heap.provider.lock().allocations.len(), 1);
+    }
+
+    struct TestProvider {
+        /// To avoid memory leaks in tests, keep track of pointers and dealloc
+        /// them later. In the kernel this doesn't matter; the heap lives
+        /// forever.
+        allocations: Vec<(usize, std::alloc::Layout)>,
+    }
+
+    impl Drop for TestProvider {
+        fn drop(&mut self) {
+            for (p, l) in self.allocations.drain(..) {
+                unsafe {
+                    std::alloc::dealloc(p as *mut u8, l);
+                }
+            }
+        }
+    }
+
+    unsafe impl ChunkProvider for TestProvider {
+        fn allocate(&mut self, num_chunks: usize) -> *mut [MaybeUninit<u8>] {
+            use std::alloc::*;
+
+            let len = num_chunks * PAGE_SIZE;
+            let layout = Layout::from_size_align(len, PAGE_SIZE).unwrap();
+            let raw = unsafe { alloc(layout) };
+            assert!(!raw.is_null());
+            self.allocations.push((raw as usize, layout));
+
+            core::ptr::slice_from_raw_parts_mut(raw as *mut MaybeUninit<u8>, len)
+        }
+
+        unsafe fn release(&mut self, chunks: *mut [MaybeUninit<u8>]) {
+            let index = self
+                .allocations
+                .iter()
+                .position(|&(p, l)| p == chunks as *mut u8 as usize && l.size() == chunks.len())
+                .unwrap();
+            let (p, l) = self.allocations.swap_remove(index);
+            unsafe { std::alloc::dealloc(p as *mut u8, l) };
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Red zones and allocation tracking, for the `debug_alloc` feature
+//!
+//! Each allocation is padded on both sides with red zones filled with
+//! `CANARY`, and every live allocation is recorded in a fixed-size table
+//! keyed by address. Freeing checks both, so writing past either end of an
+//! allocation, freeing it twice, freeing something that was never allocated
+//! or freeing with the wrong size panics with the address involved. The
+//! table is separate from the heap's memory, so corrupting the heap can't
+//! corrupt it too. Freed memory is poisoned, so a use after free reads
+//! obviously bad data.
+
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+use super::POISON;
+
+/// The smallest red zone on each side of an allocation. The one before it is
+/// bigger if the allocation's alignment is.
+const RED_ZONE: usize = 16;
+
+/// Red zones are filled with this.
+const CANARY: u8 = 0xfd;
+
+/// The most allocations that can be live at once. Must be a power of 2.
+const MAX_TRACKED: usize = 8192;
+
+/// Slot addresses that aren't allocations. Allocations are always after a red
+/// zone, so can't be at either.
+const EMPTY: usize = 0;
+const REMOVED: usize = 1;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    addr: usize,
+    size: usize,
+}
+
+/// An open-addressed hash table of live allocations' addresses and sizes.
+struct Table {
+    slots: [Slot; MAX_TRACKED],
+    live: usize,
+}
+
+impl Table {
+    /// Where to start looking for `addr`.
+    fn home(addr: usize) -> usize {
+        // Fibonacci hashing, so allocations a block apart spread out.
+        addr.wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize)
+            >> (usize::BITS - MAX_TRACKED.trailing_zeros())
+    }
+
+    fn probe(addr: usize) -> impl Iterator<Item = usize> {
+        (0..MAX_TRACKED).map(move |i| (Table::home(addr) + i) % MAX_TRACKED)
+    }
+
+    fn insert(&mut self, addr: usize, size: usize) {
+        assert!(
+            self.live < MAX_TRACKED,
+            "heap: more than {MAX_TRACKED} live allocations to track"
+        );
+        let index = Table::probe(addr)
+            .find(|&i| matches!(self.slots[i].addr, EMPTY | REMOVED))
+            .unwrap();
+        self.slots[index] = Slot { addr, size };
+        self.live += 1;
+    }
+
+    /// Remove `addr` and return its size.
+    fn remove(&mut self, addr: usize) -> Option<usize> {
+        let index = Table::probe(addr)
+            .take_while(|&i| self.slots[i].addr != EMPTY)
+            .find(|&i| self.slots[i].addr == addr)?;
+        self.slots[index].addr = REMOVED;
+        self.live -= 1;
+        Some(self.slots[index].size)
+    }
+}
+
+pub(super) struct Tracker {
+    table: Mutex<Table>,
+}
+
+impl Tracker {
+    pub(super) const fn new() -> Self {
+        Tracker {
+            table: Mutex::new(Table {
+                slots: [Slot {
+                    addr: EMPTY,
+                    size: 0,
+                }; MAX_TRACKED],
+                live: 0,
+            }),
+        }
+    }
+
+    /// Fill the red zones of `padded`, an allocation for `padded(layout)`, and
+    /// record it. Returns the caller's part.
+    ///
+    /// # Safety
+    ///
+    /// `padded` must be a new allocation for `padded(layout)`.
+    pub(super) unsafe fn track(&self, padded: *mut u8, layout: Layout) -> *mut [u8] {
+        let front = front(layout);
+        // SAFETY: the red zones are within `padded`, which the caller owns.
+        let ptr = unsafe {
+            core::ptr::write_bytes(padded, CANARY, front);
+            let ptr = padded.add(front);
+            core::ptr::write_bytes(ptr.add(layout.size()), CANARY, RED_ZONE);
+            ptr
+        };
+        self.table.lock().insert(ptr as usize, layout.size());
+        core::ptr::slice_from_raw_parts_mut(ptr, layout.size())
+    }
+
+    /// Check that `ptr` is a live allocation of `layout` whose red zones are
+    /// intact, forget it and poison it. Returns the padded allocation to
+    /// free. Panics if any check fails.
+    ///
+    /// # Safety
+    ///
+    /// If `ptr` is a live allocation, the caller must have given it up.
+    pub(super) unsafe fn untrack(&self, ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let addr = ptr as usize;
+        let size = self.table.lock().remove(addr);
+        match size {
+            None => panic!("heap: freeing {addr:#x}, which is not allocated (freed twice?)"),
+            Some(size) if size != layout.size() => panic!(
+                "heap: freeing {addr:#x} as {} bytes, but it was allocated with {size}",
+                layout.size()
+            ),
+            Some(_) => (),
+        }
+
+        let front = front(layout);
+        // SAFETY: `ptr` was allocated with its red zones, and so is all
+        // ours now.
+        let (before, after) = unsafe {
+            (
+                core::slice::from_raw_parts(ptr.sub(front), front),
+                core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE),
+            )
+        };
+        if let Some(i) = before.iter().rposition(|&b| b != CANARY) {
+            panic!(
+                "heap: overflow at {:#x}, {} bytes before the start of the {} byte allocation at {addr:#x}",
+                addr - front + i,
+                front - i,
+                layout.size()
+            );
+        }
+        if let Some(i) = after.iter().position(|&b| b != CANARY) {
+            panic!(
+                "heap: overflow at {:#x}, {} bytes after the end of the {} byte allocation at {addr:#x}",
+                addr + layout.size() + i,
+                i + 1,
+                layout.size()
+            );
+        }
+
+        // SAFETY: as above.
+        unsafe {
+            core::ptr::write_bytes(ptr, POISON, layout.size());
+            ptr.sub(front)
+        }
+    }
+}
+
+/// How far into the padded allocation the caller's part starts: a red zone,
+/// or more to keep the alignment.
+fn front(layout: Layout) -> usize {
+    RED_ZONE.max(layout.align())
+}
+
+/// The layout to allocate for `layout` with its red zones.
+pub(super) fn padded(layout: Layout) -> Layout {
+    Layout::from_size_align(front(layout) + layout.size() + RED_ZONE, layout.align()).unwrap()
+}
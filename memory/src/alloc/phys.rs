@@ -1,7 +1,9 @@
-use crate::memory::addr::*;
-use crate::memory::page::*;
+use crate::addr::*;
+use crate::page::*;
 
 use core::convert::TryInto;
+use core::fmt;
+use core::ops::Range;
 
 /// `FrameAllocator` clients may attempt to reserve a specific frame of memory.
 /// This can fail for one of the reasons listed below.
@@ -12,6 +14,31 @@ pub enum FrameReserveError {
     FrameInUse,
 }
 
+/// Why frames couldn't be removed from an allocator. Nothing is removed if
+/// any of them can't be.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameRemoveError {
+    /// The frame is allocated or reserved.
+    FrameInUse(Frame),
+    /// The frame is beyond the memory the allocator covers.
+    OutOfRange(Frame),
+}
+
+impl fmt::Display for FrameRemoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameRemoveError::FrameInUse(frame) => {
+                write!(f, "frame at {:#x} is in use", frame.start().as_raw())
+            }
+            FrameRemoveError::OutOfRange(frame) => write!(
+                f,
+                "frame at {:#x} is beyond the allocator's memory",
+                frame.start().as_raw()
+            ),
+        }
+    }
+}
+
 /// A physical frame allocator
 ///
 /// # Safety
@@ -125,7 +152,31 @@ impl<'a> BitmapFrameAllocator<'a> {
     /// `frame` must obviously be a valid frame of physical memory. In addition,
     /// it must not have been known by the allocator when constructed.
     pub unsafe fn add_new_frame(&mut self, frame: Frame) {
-        self.unreserve_impl(frame)
+        self.mark_free(FrameRange::one(frame))
+    }
+
+    /// Stop managing the frames in `range`, so they are never allocated again,
+    /// e.g. because the memory is being unplugged. Only free frames can be
+    /// removed; if any isn't, nothing changes. `add_new_frame` gives them
+    /// back.
+    pub fn remove_range(&mut self, range: FrameRange) -> Result<(), FrameRemoveError> {
+        if range.last().index() >= self.frame_count() {
+            return Err(FrameRemoveError::OutOfRange(range.last()));
+        }
+        if let Some(frame) = range.iter().find(|&frame| !self.is_free(frame)) {
+            return Err(FrameRemoveError::FrameInUse(frame));
+        }
+
+        for frame in range.iter() {
+            let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
+            self.bitmap[byte_offset] &= !(1 << bit_offset);
+        }
+        Ok(())
+    }
+
+    fn is_free(&self, frame: Frame) -> bool {
+        let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
+        self.bitmap[byte_offset] & (1 << bit_offset) != 0
     }
 
     // Finds the first byte of `bitmap` after `offset` with an available slot.
@@ -141,6 +192,10 @@ impl<'a> BitmapFrameAllocator<'a> {
         ))
     }
 
+    fn index_to_frame(index: u64) -> Frame {
+        Frame::new(PhysAddress::from_raw(index * PAGE_SIZE.as_raw()))
+    }
+
     fn frame_to_offsets(frame: Frame) -> (usize, u32) {
         let addr_raw = frame.start().as_raw();
         (
@@ -149,27 +204,29 @@ impl<'a> BitmapFrameAllocator<'a> {
         )
     }
 
-    fn deallocate_impl(&mut self, frame: Frame) {
-        let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
-        let mask = 1 << bit_offset;
-        assert_eq!(self.bitmap[byte_offset] & mask, 0);
-        self.bitmap[byte_offset] |= mask;
+    /// The number of frames the bitmap covers.
+    pub fn frame_count(&self) -> u64 {
+        self.bitmap.len() as u64 * 8
     }
 
-    fn unreserve_impl(&mut self, frame: Frame) {
-        let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
-        let mask = 1 << bit_offset;
-        assert_eq!(self.bitmap[byte_offset] & mask, 0);
-        self.bitmap[byte_offset] |= mask;
+    /// Count the free frames with indices in `frames`. Both ends must be
+    /// multiples of 8.
+    pub fn count_free(&self, frames: Range<u64>) -> u64 {
+        self.bitmap[self.byte_range(frames)]
+            .iter()
+            .map(|byte| u64::from(byte.count_ones()))
+            .sum()
     }
-}
 
-unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
-    fn allocate_range(&mut self, order: usize) -> Option<FrameRange> {
+    /// Allocate 2^order frames aligned to 2^order, only considering frames
+    /// with indices in `frames`. Both ends must be multiples of 8.
+    pub fn allocate_range_in(&mut self, order: usize, frames: Range<u64>) -> Option<FrameRange> {
         // An order of 24 gives a size of 8 MiB. Let this be the max size.
         assert!(order <= 24);
         let size = 1 << order;
 
+        let bytes = self.byte_range(frames);
+
         // Must find `size` contiguous free frames, aligned to `size`. For
         // `size` = 1, this corresponds to finding any 1 bit in the bitmap. For
         // `size` <= 8, a correctly aligned range will be contained within one
@@ -180,7 +237,7 @@ unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
         // instead.
 
         if size < 8 {
-            for i in 0..self.bitmap.len() {
+            for i in bytes {
                 let byte = &mut self.bitmap[i];
                 if *byte == 0 {
                     continue;
@@ -202,8 +259,8 @@ unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
         // For sizes >= 8, an allocation will correspond to a power-of-two
         // length of bytes in the bitmap, aligned appropriately.
 
-        'outer: for i in (0..self.bitmap.len()).step_by(byte_len) {
-            if i + byte_len > self.bitmap.len() {
+        'outer: for i in (bytes.start.next_multiple_of(byte_len)..bytes.end).step_by(byte_len) {
+            if i + byte_len > bytes.end {
                 return None;
             }
 
@@ -223,18 +280,96 @@ unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
             return FrameRange::new(Self::offsets_to_frame(i, 0), size as u64);
         }
 
-        unreachable!();
+        None
     }
 
-    fn deallocate(&mut self, frame: Frame) {
-        self.deallocate_impl(frame)
+    /// Choose the 2^order frames aligned to 2^order, with indices in `frames`,
+    /// that compaction can free by moving the fewest allocated frames
+    /// elsewhere. Every allocated frame in the range must be `movable`, and
+    /// there must be at least as many free frames outside it. Ties go to the
+    /// lowest range. Both ends of `frames` must be multiples of 8.
+    pub fn compaction_candidate(
+        &self,
+        order: usize,
+        frames: Range<u64>,
+        mut movable: impl FnMut(Frame) -> bool,
+    ) -> Option<FrameRange> {
+        assert!(order <= 24);
+        let size = 1 << order;
+
+        let bytes = self.byte_range(frames);
+        let (start, end) = (bytes.start as u64 * 8, bytes.end as u64 * 8);
+        let free = self.count_free(start..end);
+
+        // The first frame of the best range so far and how many frames it
+        // has allocated.
+        let mut best: Option<(u64, u64)> = None;
+        'outer: for first in (start.next_multiple_of(size)..end).step_by(size as usize) {
+            if first + size > end {
+                break;
+            }
+
+            let mut used = 0;
+            for index in first..first + size {
+                let frame = Self::index_to_frame(index);
+                if self.is_free(frame) {
+                    continue;
+                }
+                used += 1;
+                let better = best.is_none_or(|(_, best_used)| used < best_used);
+                if !better || !movable(frame) {
+                    continue 'outer;
+                }
+            }
+
+            // Each allocated frame needs a free one outside the range.
+            let better = best.is_none_or(|(_, best_used)| used < best_used);
+            if better && free - (size - used) >= used {
+                best = Some((first, used));
+            }
+        }
+
+        let (first, _) = best?;
+        FrameRange::new(Self::index_to_frame(first), size)
     }
 
-    fn deallocate_range(&mut self, range: FrameRange) {
+    /// The range of bitmap bytes for `frames`, clamped to the bitmap's size.
+    fn byte_range(&self, frames: Range<u64>) -> Range<usize> {
+        assert!(frames.start.is_multiple_of(8) && frames.end.is_multiple_of(8));
+        let len = self.bitmap.len();
+        let start = usize::try_from(frames.start / 8).unwrap_or(len).min(len);
+        let end = usize::try_from(frames.end / 8)
+            .unwrap_or(len)
+            .clamp(start, len);
+        start..end
+    }
+
+    /// Mark every frame of `range` free. Panics, changing nothing, if any of
+    /// them is beyond the bitmap or already free.
+    fn mark_free(&mut self, range: FrameRange) {
+        assert!(
+            range.last().index() < self.frame_count(),
+            "freeing {range:?}, beyond the allocator's {} frames",
+            self.frame_count()
+        );
+        if let Some(frame) = range.iter().find(|&frame| self.is_free(frame)) {
+            panic!("freeing {range:?}, but {frame:?} is already free");
+        }
         for frame in range.iter() {
-            self.deallocate(frame);
+            let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
+            self.bitmap[byte_offset] |= 1 << bit_offset;
         }
     }
+}
+
+unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
+    fn allocate_range(&mut self, order: usize) -> Option<FrameRange> {
+        self.allocate_range_in(order, 0..self.frame_count())
+    }
+
+    fn deallocate_range(&mut self, range: FrameRange) {
+        self.mark_free(range)
+    }
 
     fn reserve(&mut self, frame: Frame) -> Result<(), FrameReserveError> {
         let (byte_offset, bit_offset) = Self::frame_to_offsets(frame);
@@ -255,7 +390,7 @@ unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
     }
 
     fn unreserve(&mut self, frame: Frame) {
-        self.unreserve_impl(frame)
+        self.mark_free(FrameRange::one(frame))
     }
 }
 
@@ -264,8 +399,8 @@ unsafe impl FrameAllocator for BitmapFrameAllocator<'_> {
 /// be large enough. Specifically, if the last entry in `memory_map` ends just
 /// before address x, `bitmap` must have length at least x / 32768 (which is the
 /// frame size, 4096, times the number of bits in a u8, 8).
-pub fn fill_bitmap_from_map(bitmap: &mut [u8], memory_map: &crate::memory::Map) {
-    use crate::memory::MemoryType;
+pub fn fill_bitmap_from_map<const N: usize>(bitmap: &mut [u8], memory_map: &crate::Map<N>) {
+    use crate::MemoryType;
 
     // The number of memory frames per byte of `bitmap`
     const FRAMES_PER_ENTRY: u64 = 8;
@@ -290,8 +425,7 @@ pub fn fill_bitmap_from_map(bitmap: &mut [u8], memory_map: &crate::memory::Map)
         *x = 0;
     }
 
-    for avail_frames in crate::memory::iter_map_frames(memory_map.iter_type(MemoryType::Available))
-    {
+    for avail_frames in crate::iter_map_frames(memory_map.iter_type(MemoryType::Available)) {
         // Ensure `bitmap` is large enough.
         assert!(bitmap.len() as u64 >= avail_frames.count() / FRAMES_PER_ENTRY);
 
@@ -351,7 +485,7 @@ fn find_bit_group(byte: u8, len: usize) -> Option<u8> {
     assert!(len < 8);
     assert!(len.is_power_of_two());
 
-    let mask = ((len << 1) - 1) as u8;
+    let mask = ((1u16 << len) - 1) as u8;
     let mut shift = 0;
 
     while shift < 8 {
@@ -392,7 +526,7 @@ fn ceil_divide(x: u64, divisor: u64) -> u64 {
 mod tests {
     use super::*;
 
-    use crate::memory;
+    use crate as memory;
 
     use std::vec::Vec;
 
@@ -441,6 +575,8 @@ mod tests {
 
         assert_eq!(find_bit_group(0b01010101, 2), None);
         assert_eq!(find_bit_group(0b11101110, 4), None);
+        assert_eq!(find_bit_group(0b01110000, 4), None);
+        assert_eq!(find_bit_group(0b00000111, 4), None);
     }
 
     #[test]
@@ -669,9 +805,176 @@ mod tests {
         assert_eq!(allocator.allocate().unwrap(), frame1);
     }
 
+    #[test]
+    fn bitmap_allocator_frees_ranges() {
+        let original = [0b11111111, 0b11111111, 0b00001111];
+        let mut bitmap = original;
+        let mut allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+
+        let eight = allocator.allocate_range(3).unwrap();
+        let four = allocator.allocate_range(2).unwrap();
+        let two = allocator.allocate_range(1).unwrap();
+        assert_eq!(
+            [eight, four, two],
+            [
+                FrameRange::new(frame(0), 8).unwrap(),
+                FrameRange::new(frame(8), 4).unwrap(),
+                FrameRange::new(frame(12), 2).unwrap(),
+            ]
+        );
+
+        allocator.deallocate_range(four);
+        allocator.deallocate_range(eight);
+        assert_eq!(allocator.allocate_range(3), Some(eight));
+        allocator.deallocate_range(eight);
+        allocator.deallocate_range(two);
+        assert_eq!(bitmap, original);
+    }
+
+    #[test]
+    fn bitmap_allocator_rejects_bad_frees() {
+        let mut bitmap = [0b11111111];
+        let mut allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+        let four = allocator.allocate_range(2).unwrap();
+        assert_eq!(four, FrameRange::new(frame(0), 4).unwrap());
+
+        // Partly free, and partly beyond the bitmap. Neither changes
+        // anything.
+        for range in [
+            FrameRange::new(frame(0), 8).unwrap(),
+            FrameRange::new(frame(2), 8).unwrap(),
+        ] {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                allocator.deallocate_range(range)
+            }));
+            assert!(result.is_err(), "freeing {range:?}");
+        }
+        assert_eq!(allocator.allocate_range(2), FrameRange::new(frame(4), 4));
+        assert_eq!(allocator.allocate_range(0), None);
+
+        allocator.deallocate_range(FrameRange::new(frame(0), 8).unwrap());
+        assert_eq!(bitmap, [0b11111111]);
+    }
+
+    fn frame(index: u64) -> Frame {
+        Frame::new(PhysAddress::from_zero(PAGE_SIZE * index))
+    }
+
+    #[test]
+    fn compaction_picks_fewest_moves() {
+        // Frames 0 to 3, 8 and 23 are allocated.
+        let mut bitmap = [0b11110000, 0b11111110, 0b01111111];
+        let allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+
+        assert_eq!(
+            allocator.compaction_candidate(3, 0..24, |_| true),
+            FrameRange::new(frame(8), 8)
+        );
+        assert_eq!(
+            allocator.compaction_candidate(3, 0..24, |frame| frame.index() != 8),
+            FrameRange::new(frame(16), 8)
+        );
+        assert_eq!(
+            allocator.compaction_candidate(2, 0..24, |_| true),
+            FrameRange::new(frame(4), 4)
+        );
+        assert_eq!(
+            allocator.compaction_candidate(3, 0..24, |frame| frame.index() < 8),
+            FrameRange::new(frame(0), 8)
+        );
+        assert_eq!(allocator.compaction_candidate(3, 0..24, |_| false), None);
+    }
+
+    #[test]
+    fn compaction_needs_room_outside() {
+        // Frames 0 to 8 are allocated, leaving 7 free.
+        let mut bitmap = [0b00000000, 0b11111110];
+        let allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+        assert_eq!(allocator.compaction_candidate(3, 0..16, |_| true), None);
+        assert_eq!(allocator.compaction_candidate(3, 8..16, |_| true), None);
+        assert_eq!(
+            allocator.compaction_candidate(2, 0..16, |_| true),
+            FrameRange::new(frame(12), 4)
+        );
+    }
+
     use proptest::prelude::*;
 
     proptest! {
+        #[test]
+        fn compaction_candidate_is_best(
+            mut bitmap in proptest::collection::vec(any::<u8>(), 0..16),
+            order in 0usize..6,
+            unmovable in 1u64..8,
+        ) {
+            let movable = |frame: Frame| !frame.index().is_multiple_of(unmovable);
+            let is_free = |bitmap: &[u8], index: u64| bitmap[index as usize / 8] & (1 << (index % 8)) != 0;
+            let size = 1u64 << order;
+            let len = bitmap.len() as u64 * 8;
+            let free = (0..len).filter(|&i| is_free(&bitmap, i)).count() as u64;
+            // How many frames compaction must move to free each range, if it
+            // can.
+            let moves = |first: u64| {
+                let used: Vec<u64> = (first..first + size).filter(|&i| !is_free(&bitmap, i)).collect();
+                let movable = used.iter().all(|&i| movable(frame(i)));
+                let room = free - (size - used.len() as u64) >= used.len() as u64;
+                (movable && room).then_some(used.len() as u64)
+            };
+            let expected = (0..len / size)
+                .map(|i| i * size)
+                .filter_map(|first| Some((moves(first)?, first)))
+                .min()
+                .map(|(_, first)| FrameRange::new(frame(first), size).unwrap());
+
+            let allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+            prop_assert_eq!(allocator.compaction_candidate(order, 0..len, movable), expected);
+        }
+
+        #[test]
+        fn mixed_order_cycles(
+            original in proptest::collection::vec(any::<u8>(), 1..16),
+            ops in proptest::collection::vec((any::<bool>(), 0usize..5, any::<usize>()), 0..64),
+        ) {
+            let len = original.len() as u64 * 8;
+            let was_free = |index: u64| original[index as usize / 8] & (1 << (index % 8)) != 0;
+            let mut bitmap = original.clone();
+            let mut allocator = unsafe { BitmapFrameAllocator::new(&mut bitmap) };
+            let mut live: Vec<FrameRange> = Vec::new();
+            let is_live = |live: &[FrameRange], index: u64| {
+                live.iter().any(|r| (r.first().index()..=r.last().index()).contains(&index))
+            };
+
+            for (allocate, order, pick) in ops {
+                if !allocate && !live.is_empty() {
+                    allocator.deallocate_range(live.swap_remove(pick % live.len()));
+                    continue;
+                }
+
+                let size = 1u64 << order;
+                let fits = |first: u64| {
+                    (first..first + size).all(|i| was_free(i) && !is_live(&live, i))
+                };
+                match allocator.allocate_range(order) {
+                    Some(range) => {
+                        let first = range.first().index();
+                        prop_assert_eq!(range.count(), size);
+                        prop_assert!(first.is_multiple_of(size));
+                        prop_assert!(fits(first), "{:?} wasn't free", range);
+                        live.push(range);
+                    }
+                    None => {
+                        let first = (0..len / size).map(|i| i * size).find(|&first| fits(first));
+                        prop_assert_eq!(first, None);
+                    }
+                }
+            }
+
+            for range in live {
+                allocator.deallocate_range(range);
+            }
+            prop_assert_eq!(bitmap, original);
+        }
+
         #[test]
         fn bitmap_allocator_uses_all_available_memory(mut bitmap in any::<Vec<u8>>()) {
             let free_frame_count = bitmap
@@ -0,0 +1,143 @@
+//! Reference counts for frames shared between owners
+//!
+//! Most frames have a single owner, which frees them when done. Frames mapped
+//! in several places, e.g. copy-on-write or shared memory, instead have a
+//! count of references here, and are freed by whoever removes the last one.
+//! The counts live in an array with an entry per frame, set aside next to the
+//! frame allocator.
+
+use crate::page::*;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A reference count for each frame from 0 to `len() - 1`. Frames without
+/// references, including all frames that aren't shared, have a count of 0.
+pub struct FrameRefCounts<'a> {
+    counts: &'a [AtomicU32],
+}
+
+impl<'a> FrameRefCounts<'a> {
+    /// Use `counts` as the counts of frames 0 to `counts.len() - 1`. They
+    /// should all be 0.
+    pub fn new(counts: &'a [AtomicU32]) -> Self {
+        FrameRefCounts { counts }
+    }
+
+    /// The number of frames with counts.
+    pub fn len(&self) -> u64 {
+        self.counts.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// The number of references to `frame`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` has no count.
+    pub fn get(&self, frame: Frame) -> u32 {
+        self.count(frame).load(Ordering::Acquire)
+    }
+
+    /// Add a reference to `frame`. Returns the new count.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` has no count, or if it would overflow.
+    pub fn add_ref(&self, frame: Frame) -> u32 {
+        let old = self.count(frame).fetch_add(1, Ordering::Relaxed);
+        assert!(old < u32::MAX, "{frame:?} has too many references");
+        old + 1
+    }
+
+    /// Remove a reference to `frame`. Returns the new count; once it is 0,
+    /// the caller had the last reference and may free the frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` has no count, or no references.
+    pub fn remove_ref(&self, frame: Frame) -> u32 {
+        // Release so the last owner sees every other owner's writes.
+        let old = self.count(frame).fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "{frame:?} has no references");
+        old - 1
+    }
+
+    fn count(&self, frame: Frame) -> &AtomicU32 {
+        let index = frame.index();
+        assert!(index < self.len(), "{frame:?} has no reference count");
+        &self.counts[index as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::addr::PhysAddress;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn frame(index: u64) -> Frame {
+        Frame::new(PhysAddress::from_zero(PAGE_SIZE * index))
+    }
+
+    fn zeroed(len: usize) -> Vec<AtomicU32> {
+        (0..len).map(|_| AtomicU32::new(0)).collect()
+    }
+
+    #[test]
+    fn counts_references() {
+        let counts = zeroed(4);
+        let refs = FrameRefCounts::new(&counts);
+        assert_eq!(refs.len(), 4);
+        assert_eq!(refs.add_ref(frame(2)), 1);
+        assert_eq!(refs.add_ref(frame(2)), 2);
+        assert_eq!(refs.add_ref(frame(3)), 1);
+        assert_eq!(refs.get(frame(2)), 2);
+        assert_eq!(refs.get(frame(0)), 0);
+        assert_eq!(refs.remove_ref(frame(2)), 1);
+        assert_eq!(refs.remove_ref(frame(2)), 0);
+        assert_eq!(refs.get(frame(3)), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "has no references")]
+    fn removing_unreferenced_frame_panics() {
+        let counts = zeroed(1);
+        FrameRefCounts::new(&counts).remove_ref(frame(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "has no reference count")]
+    fn frame_past_end_panics() {
+        let counts = zeroed(2);
+        FrameRefCounts::new(&counts).add_ref(frame(2));
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(ops in proptest::collection::vec((0..8u64, any::<bool>()), 0..200)) {
+            let counts = zeroed(8);
+            let refs = FrameRefCounts::new(&counts);
+            let mut model = [0u32; 8];
+            for (index, add) in ops {
+                if add {
+                    model[index as usize] += 1;
+                    prop_assert_eq!(refs.add_ref(frame(index)), model[index as usize]);
+                } else if model[index as usize] > 0 {
+                    model[index as usize] -= 1;
+                    prop_assert_eq!(refs.remove_ref(frame(index)), model[index as usize]);
+                }
+            }
+            for (index, &count) in model.iter().enumerate() {
+                prop_assert_eq!(refs.get(frame(index as u64)), count);
+            }
+        }
+    }
+}
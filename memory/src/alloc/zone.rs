@@ -0,0 +1,541 @@
+//! Physical memory zones
+//!
+//! Some devices can only address low physical memory, and real mode code must
+//! be below 1 MiB, so frames are grouped into zones by address. An allocation
+//! names the highest zone it may use and falls back to lower zones in turn.
+//! Each zone has watermarks: an allocation that had to fall back may only take
+//! a lower zone's frames while it stays above its high watermark, so ordinary
+//! allocations don't exhaust the memory only low-memory devices can use.
+//! Dropping below the low watermark is reported as a request to reclaim
+//! memory.
+
+use super::phys::{BitmapFrameAllocator, FrameAllocator, FrameRemoveError, FrameReserveError};
+use crate::page::*;
+
+use core::ops::Range;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Zone {
+    /// Below 1 MiB, for code run in real mode, like the AP trampoline.
+    Low = 0,
+    /// Below 16 MiB, for ISA DMA.
+    Dma = 1,
+    /// Below 4 GiB, for devices with 32 bit DMA.
+    Dma32 = 2,
+    /// Everything else.
+    Normal = 3,
+}
+
+pub const NUM_ZONES: usize = 4;
+
+const LOW_END_FRAME: u64 = (1 << 20) / PAGE_SIZE.as_raw();
+const DMA_END_FRAME: u64 = (16 << 20) / PAGE_SIZE.as_raw();
+const DMA32_END_FRAME: u64 = (4 << 30) / PAGE_SIZE.as_raw();
+/// Physical addresses are at most 52 bits.
+const END_FRAME: u64 = (1 << 52) / PAGE_SIZE.as_raw();
+
+impl Zone {
+    /// All zones, lowest first.
+    pub const ALL: [Zone; NUM_ZONES] = [Zone::Low, Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// The indices of the frames in this zone.
+    pub fn frames(self) -> Range<u64> {
+        match self {
+            Zone::Low => 0..LOW_END_FRAME,
+            Zone::Dma => LOW_END_FRAME..DMA_END_FRAME,
+            Zone::Dma32 => DMA_END_FRAME..DMA32_END_FRAME,
+            Zone::Normal => DMA32_END_FRAME..END_FRAME,
+        }
+    }
+
+    pub fn containing(frame: Frame) -> Zone {
+        let index = frame.index();
+        *Zone::ALL
+            .iter()
+            .find(|zone| zone.frames().contains(&index))
+            .unwrap()
+    }
+
+    /// The next zone to try after this one, if any.
+    fn fallback(self) -> Option<Zone> {
+        match self {
+            Zone::Low => None,
+            Zone::Dma => Some(Zone::Low),
+            Zone::Dma32 => Some(Zone::Dma),
+            Zone::Normal => Some(Zone::Dma32),
+        }
+    }
+}
+
+/// Free frame thresholds for a zone, in frames. `min <= low <= high`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Watermarks {
+    /// Allocations preferring this zone may not take it below this.
+    pub min: u64,
+    /// Below this, reclaim is requested.
+    pub low: u64,
+    /// Allocations falling back to this zone may not take it below this.
+    pub high: u64,
+}
+
+impl Watermarks {
+    /// Reasonable watermarks for a zone with `managed` frames.
+    pub fn for_zone_size(managed: u64) -> Watermarks {
+        // Roughly 1/128th of the zone, but at most 64 MiB.
+        let min = (managed / 128).min(16384);
+        Watermarks {
+            min,
+            low: min + min / 4,
+            high: min + min / 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ZoneInfo {
+    /// Frames currently free.
+    pub free: u64,
+    /// Frames the allocator manages, free or allocated.
+    pub managed: u64,
+    pub watermarks: Watermarks,
+}
+
+/// Wraps a `BitmapFrameAllocator`, tracking usage per zone.
+#[derive(Debug)]
+pub struct ZonedFrameAllocator<'a> {
+    inner: BitmapFrameAllocator<'a>,
+    zones: [ZoneInfo; NUM_ZONES],
+
+    /// Zones that went below their low watermark and haven't recovered above
+    /// their high watermark since.
+    below_low: [bool; NUM_ZONES],
+
+    /// Zones that went below their low watermark since the last
+    /// `take_reclaim_requests`.
+    reclaim_requested: [bool; NUM_ZONES],
+}
+
+impl<'a> ZonedFrameAllocator<'a> {
+    /// All of `inner`'s free frames are managed. Watermarks are set with
+    /// `Watermarks::for_zone_size`.
+    pub fn new(inner: BitmapFrameAllocator<'a>) -> ZonedFrameAllocator<'a> {
+        let mut zones = [ZoneInfo::default(); NUM_ZONES];
+        for zone in Zone::ALL {
+            let free = inner.count_free(zone.frames());
+            zones[zone as usize] = ZoneInfo {
+                free,
+                managed: free,
+                watermarks: Watermarks::default(),
+            };
+        }
+
+        let mut allocator = ZonedFrameAllocator {
+            inner,
+            zones,
+            below_low: [false; NUM_ZONES],
+            reclaim_requested: [false; NUM_ZONES],
+        };
+        allocator.set_default_watermarks();
+        allocator
+    }
+
+    pub fn zone_info(&self, zone: Zone) -> ZoneInfo {
+        self.zones[zone as usize]
+    }
+
+    pub fn set_watermarks(&mut self, zone: Zone, watermarks: Watermarks) {
+        assert!(watermarks.min <= watermarks.low && watermarks.low <= watermarks.high);
+        self.zones[zone as usize].watermarks = watermarks;
+    }
+
+    /// Recompute every zone's watermarks from its current size, e.g. after
+    /// reserving frames at boot.
+    pub fn set_default_watermarks(&mut self) {
+        for zone in Zone::ALL {
+            let managed = self.zones[zone as usize].managed;
+            self.set_watermarks(zone, Watermarks::for_zone_size(managed));
+        }
+    }
+
+    /// Allocate 2^order frames aligned to 2^order from `zone` or, failing
+    /// that, successively lower zones.
+    pub fn allocate_range_from(&mut self, order: usize, zone: Zone) -> Option<FrameRange> {
+        let count = 1 << order;
+        let mut candidate = Some(zone);
+        while let Some(current) = candidate {
+            let info = self.zones[current as usize];
+            let floor = if current == zone {
+                info.watermarks.min
+            } else {
+                info.watermarks.high
+            };
+
+            if info.free >= floor + count {
+                if let Some(range) = self.inner.allocate_range_in(order, current.frames()) {
+                    self.account_allocated(range);
+                    return Some(range);
+                }
+            }
+
+            candidate = current.fallback();
+        }
+
+        None
+    }
+
+    /// Choose frames for compaction to free for an allocation of 2^order
+    /// frames from `zone`. See `BitmapFrameAllocator::compaction_candidate`.
+    /// Lower zones aren't compacted, so their frames are kept for devices
+    /// that need them.
+    pub fn compaction_candidate(
+        &self,
+        order: usize,
+        zone: Zone,
+        movable: impl FnMut(Frame) -> bool,
+    ) -> Option<FrameRange> {
+        self.inner
+            .compaction_candidate(order, zone.frames(), movable)
+    }
+
+    /// Allocate `frame` in particular, e.g. to free a range by compaction.
+    /// Returns false if it isn't free.
+    pub fn allocate_at(&mut self, frame: Frame) -> bool {
+        if self.inner.reserve(frame).is_err() {
+            return false;
+        }
+        self.account_allocated(FrameRange::one(frame));
+        true
+    }
+
+    /// Zones that went below their low watermark since the last call. A zone
+    /// is reported again only after recovering above its high watermark.
+    pub fn take_reclaim_requests(&mut self) -> impl Iterator<Item = Zone> {
+        let requested = core::mem::replace(&mut self.reclaim_requested, [false; NUM_ZONES]);
+        Zone::ALL
+            .into_iter()
+            .filter(move |&zone| requested[zone as usize])
+    }
+
+    /// Add a frame that wasn't present in the initial bitmap.
+    ///
+    /// # Safety
+    ///
+    /// See `BitmapFrameAllocator::add_new_frame`.
+    pub unsafe fn add_new_frame(&mut self, frame: Frame) {
+        unsafe {
+            self.inner.add_new_frame(frame);
+        }
+        let info = &mut self.zones[Zone::containing(frame) as usize];
+        info.managed += 1;
+        info.free += 1;
+    }
+
+    /// Stop managing the frames in `range`. See
+    /// `BitmapFrameAllocator::remove_range`.
+    pub fn remove_range(&mut self, range: FrameRange) -> Result<(), FrameRemoveError> {
+        self.inner.remove_range(range)?;
+        for zone in Zone::ALL {
+            self.zones[zone as usize].managed -= frames_in_zone(range, zone);
+        }
+        // Removed frames are no longer free, so they count against the
+        // watermarks like allocated ones.
+        self.account_allocated(range);
+        Ok(())
+    }
+
+    fn account_allocated(&mut self, range: FrameRange) {
+        for zone in Zone::ALL {
+            let count = frames_in_zone(range, zone);
+            if count == 0 {
+                continue;
+            }
+
+            let info = &mut self.zones[zone as usize];
+            info.free -= count;
+            if info.free < info.watermarks.low && !self.below_low[zone as usize] {
+                self.below_low[zone as usize] = true;
+                self.reclaim_requested[zone as usize] = true;
+            }
+        }
+    }
+
+    fn account_freed(&mut self, range: FrameRange) {
+        for zone in Zone::ALL {
+            let info = &mut self.zones[zone as usize];
+            info.free += frames_in_zone(range, zone);
+            if info.free >= info.watermarks.high {
+                self.below_low[zone as usize] = false;
+            }
+        }
+    }
+}
+
+unsafe impl FrameAllocator for ZonedFrameAllocator<'_> {
+    fn allocate_range(&mut self, order: usize) -> Option<FrameRange> {
+        self.allocate_range_from(order, Zone::Normal)
+    }
+
+    fn deallocate_range(&mut self, range: FrameRange) {
+        self.inner.deallocate_range(range);
+        self.account_freed(range);
+    }
+
+    fn reserve(&mut self, frame: Frame) -> Result<(), FrameReserveError> {
+        self.inner.reserve(frame)?;
+        let info = &mut self.zones[Zone::containing(frame) as usize];
+        info.managed -= 1;
+        info.free -= 1;
+        Ok(())
+    }
+
+    fn unreserve(&mut self, frame: Frame) {
+        self.inner.unreserve(frame);
+        let info = &mut self.zones[Zone::containing(frame) as usize];
+        info.managed += 1;
+        info.free += 1;
+    }
+}
+
+/// The number of frames of `range` in `zone`.
+fn frames_in_zone(range: FrameRange, zone: Zone) -> u64 {
+    let zone_frames = zone.frames();
+    let start = range.first().index().max(zone_frames.start);
+    let end = (range.last().index() + 1).min(zone_frames.end);
+    end.saturating_sub(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::addr::PhysAddress;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    /// A bitmap with every frame in the low and DMA zones and the first
+    /// `dma32_frames` of the DMA32 zone free.
+    fn bitmap(dma32_frames: usize) -> Vec<u8> {
+        assert!(dma32_frames.is_multiple_of(8));
+        vec![u8::MAX; (DMA_END_FRAME as usize + dma32_frames) / 8]
+    }
+
+    fn frame(index: u64) -> Frame {
+        Frame::new(PhysAddress::from_zero(PAGE_SIZE * index))
+    }
+
+    #[test]
+    fn zone_boundaries() {
+        assert_eq!(Zone::containing(frame(0)), Zone::Low);
+        assert_eq!(Zone::containing(frame(LOW_END_FRAME - 1)), Zone::Low);
+        assert_eq!(Zone::containing(frame(LOW_END_FRAME)), Zone::Dma);
+        assert_eq!(Zone::containing(frame(DMA_END_FRAME - 1)), Zone::Dma);
+        assert_eq!(Zone::containing(frame(DMA_END_FRAME)), Zone::Dma32);
+        assert_eq!(Zone::containing(frame(DMA32_END_FRAME - 1)), Zone::Dma32);
+        assert_eq!(Zone::containing(frame(DMA32_END_FRAME)), Zone::Normal);
+    }
+
+    #[test]
+    fn counts_zones() {
+        let mut bitmap = bitmap(64);
+        let allocator = ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+        assert_eq!(allocator.zone_info(Zone::Low).free, LOW_END_FRAME);
+        assert_eq!(
+            allocator.zone_info(Zone::Dma).free,
+            DMA_END_FRAME - LOW_END_FRAME
+        );
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 64);
+        assert_eq!(allocator.zone_info(Zone::Normal).managed, 0);
+    }
+
+    #[test]
+    fn fallback_respects_watermarks() {
+        let mut bitmap = bitmap(64);
+        let mut allocator =
+            ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+        allocator.set_watermarks(
+            Zone::Low,
+            Watermarks {
+                min: 0,
+                low: 0,
+                high: 8,
+            },
+        );
+        allocator.set_watermarks(
+            Zone::Dma,
+            Watermarks {
+                min: 10,
+                low: 20,
+                high: 100,
+            },
+        );
+        allocator.set_watermarks(
+            Zone::Dma32,
+            Watermarks {
+                min: 4,
+                low: 8,
+                high: 16,
+            },
+        );
+
+        // Normal is empty, so these come from DMA32 down to its min, then DMA
+        // and low memory down to their highs.
+        let mut frames = Vec::new();
+        while let Some(range) = allocator.allocate_range_from(0, Zone::Normal) {
+            frames.push(range.first());
+        }
+        assert_eq!(allocator.zone_info(Zone::Dma32).free, 16);
+        assert_eq!(allocator.zone_info(Zone::Dma).free, 100);
+        assert_eq!(allocator.zone_info(Zone::Low).free, 8);
+        assert_eq!(frames.len() as u64, 48 + DMA_END_FRAME - 108);
+
+        // Preferring DMA32 may go down to its min.
+        while allocator.allocate_range_from(0, Zone::Dma32).is_some() {}
+        assert_eq!(allocator.zone_info(Zone::Dma32).free, 4);
+        assert_eq!(allocator.zone_info(Zone::Dma).free, 100);
+
+        // Only DMA allocations may use the rest of DMA.
+        while allocator.allocate_range_from(0, Zone::Dma).is_some() {}
+        assert_eq!(allocator.zone_info(Zone::Dma).free, 10);
+        assert_eq!(allocator.zone_info(Zone::Low).free, 8);
+
+        // Only low memory allocations may use the rest of it.
+        while allocator.allocate_range_from(0, Zone::Low).is_some() {}
+        assert_eq!(allocator.zone_info(Zone::Low).free, 0);
+
+        for frame in frames {
+            allocator.deallocate(frame);
+        }
+        assert_eq!(
+            allocator.zone_info(Zone::Dma).free,
+            DMA_END_FRAME - LOW_END_FRAME - 90
+        );
+        assert_eq!(allocator.zone_info(Zone::Low).free, LOW_END_FRAME - 8);
+    }
+
+    #[test]
+    fn requests_reclaim_once_below_low() {
+        let mut bitmap = bitmap(64);
+        let mut allocator =
+            ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+        allocator.set_watermarks(
+            Zone::Dma32,
+            Watermarks {
+                min: 4,
+                low: 8,
+                high: 16,
+            },
+        );
+
+        let mut frames = Vec::new();
+        for _ in 0..56 {
+            frames.push(allocator.allocate_range_from(0, Zone::Dma32).unwrap());
+        }
+        assert_eq!(allocator.take_reclaim_requests().collect::<Vec<_>>(), []);
+
+        frames.push(allocator.allocate_range_from(0, Zone::Dma32).unwrap());
+        assert_eq!(
+            allocator.take_reclaim_requests().collect::<Vec<_>>(),
+            [Zone::Dma32]
+        );
+
+        // Not reported again until it recovers.
+        frames.push(allocator.allocate_range_from(0, Zone::Dma32).unwrap());
+        assert_eq!(allocator.take_reclaim_requests().collect::<Vec<_>>(), []);
+        for range in frames.drain(..) {
+            allocator.deallocate_range(range);
+        }
+        for _ in 0..57 {
+            frames.push(allocator.allocate_range_from(0, Zone::Dma32).unwrap());
+        }
+        assert_eq!(
+            allocator.take_reclaim_requests().collect::<Vec<_>>(),
+            [Zone::Dma32]
+        );
+    }
+
+    #[test]
+    fn reserve_updates_counts() {
+        let mut bitmap = bitmap(64);
+        let mut allocator =
+            ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+        allocator.reserve(frame(DMA_END_FRAME)).unwrap();
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 63);
+        assert_eq!(allocator.zone_info(Zone::Dma32).free, 63);
+
+        allocator.unreserve(frame(DMA_END_FRAME));
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 64);
+    }
+
+    #[test]
+    fn remove_range_only_takes_free_frames() {
+        let mut bitmap = bitmap(64);
+        let mut allocator =
+            ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+        let allocated = allocator.allocate_range_from(0, Zone::Dma32).unwrap();
+        assert_eq!(allocated.first(), frame(DMA_END_FRAME));
+
+        // Nothing is removed if any frame is in use or out of range.
+        let range = FrameRange::new(frame(DMA_END_FRAME), 16).unwrap();
+        assert_eq!(
+            allocator.remove_range(range),
+            Err(FrameRemoveError::FrameInUse(frame(DMA_END_FRAME)))
+        );
+        let range = FrameRange::new(frame(DMA_END_FRAME + 32), 64).unwrap();
+        assert_eq!(
+            allocator.remove_range(range),
+            Err(FrameRemoveError::OutOfRange(frame(DMA_END_FRAME + 95)))
+        );
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 64);
+        assert_eq!(allocator.zone_info(Zone::Dma32).free, 63);
+
+        // The last frames of DMA and the first of DMA32, once they are free.
+        allocator.deallocate_range(allocated);
+        let range = FrameRange::new(frame(DMA_END_FRAME - 4), 12).unwrap();
+        allocator.remove_range(range).unwrap();
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 56);
+        assert_eq!(allocator.zone_info(Zone::Dma32).free, 56);
+        assert_eq!(
+            allocator.zone_info(Zone::Dma).managed,
+            DMA_END_FRAME - LOW_END_FRAME - 4
+        );
+        while let Some(range) = allocator.allocate_range_from(0, Zone::Dma) {
+            assert!(range.first().index() < DMA_END_FRAME - 4);
+        }
+
+        for frame in range.iter() {
+            unsafe { allocator.add_new_frame(frame) };
+        }
+        assert_eq!(allocator.zone_info(Zone::Dma32).managed, 64);
+    }
+
+    proptest! {
+        #[test]
+        fn free_counts_match_bitmap(orders in proptest::collection::vec((0usize..5, 0usize..NUM_ZONES), 0..100)) {
+            let mut bitmap = bitmap(256);
+            let mut allocator =
+                ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(&mut bitmap) });
+
+            let mut allocated = Vec::new();
+            for (order, zone) in orders {
+                if let Some(range) = allocator.allocate_range_from(order, Zone::ALL[zone]) {
+                    prop_assert!(Zone::containing(range.first()) <= Zone::ALL[zone]);
+                    allocated.push(range);
+                }
+                if allocated.len() > 10 {
+                    allocator.deallocate_range(allocated.swap_remove(0));
+                }
+
+                for zone in Zone::ALL {
+                    prop_assert_eq!(
+                        allocator.zone_info(zone).free,
+                        allocator.inner.count_free(zone.frames())
+                    );
+                }
+            }
+        }
+    }
+}
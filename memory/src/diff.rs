@@ -0,0 +1,250 @@
+//! Differences between two memory maps
+//!
+//! Used to check one boot stage's changes to the map against another's, e.g.
+//! that the kernel only marked the areas it meant to. Nothing is allocated,
+//! so it works before there is a heap.
+
+use crate::{MapEntry, MemoryType, PhysAddress, PhysExtent};
+
+/// An extent whose type differs between two maps. `None` means the map
+/// doesn't cover it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapChange {
+    pub extent: PhysExtent,
+    pub before: Option<MemoryType>,
+    pub after: Option<MemoryType>,
+}
+
+/// The extents whose types differ between `before` and `after`, in address
+/// order. Both must be sorted by address and non-overlapping, as `Map`
+/// entries are. Adjacent extents with the same change are merged.
+pub fn diff<'a>(before: &'a [MapEntry], after: &'a [MapEntry]) -> MapDiff<'a> {
+    MapDiff {
+        before,
+        after,
+        pos: 0,
+    }
+}
+
+/// Iterator returned by `diff`.
+#[derive(Clone, Debug)]
+pub struct MapDiff<'a> {
+    /// Entries not wholly below `pos`.
+    before: &'a [MapEntry],
+    after: &'a [MapEntry],
+    /// Where the next piece starts, or somewhere in the gap before it.
+    pos: u64,
+}
+
+impl MapDiff<'_> {
+    /// The next extent, from `pos` on, over which neither map changes type,
+    /// and its type in each. Skips addresses neither map covers.
+    fn next_piece(&mut self) -> Option<(PhysExtent, Option<MemoryType>, Option<MemoryType>)> {
+        let pos = self.pos;
+        let below_pos = |e: &MapEntry| e.extent.end_address().as_raw() <= pos;
+        while self.before.first().is_some_and(below_pos) {
+            self.before = &self.before[1..];
+        }
+        while self.after.first().is_some_and(below_pos) {
+            self.after = &self.after[1..];
+        }
+
+        let (before, after) = (self.before.first(), self.after.first());
+        let start = [before, after]
+            .into_iter()
+            .flatten()
+            .map(|e| e.extent.address().as_raw())
+            .min()?
+            .max(pos);
+        // The type at `start`, and where the entry stops applying to the
+        // piece: at its end if it covers `start`, or its start if it is
+        // further on.
+        let at_start = |e: Option<&MapEntry>| match e {
+            Some(e) if e.extent.address().as_raw() <= start => {
+                (Some(e.mem_type), e.extent.end_address().as_raw())
+            }
+            Some(e) => (None, e.extent.address().as_raw()),
+            None => (None, u64::MAX),
+        };
+        let (before_type, before_end) = at_start(before);
+        let (after_type, after_end) = at_start(after);
+        let end = before_end.min(after_end);
+
+        self.pos = end;
+        Some((
+            PhysExtent::from_range_exclusive(
+                PhysAddress::from_raw(start),
+                PhysAddress::from_raw(end),
+            ),
+            before_type,
+            after_type,
+        ))
+    }
+}
+
+impl Iterator for MapDiff<'_> {
+    type Item = MapChange;
+
+    fn next(&mut self) -> Option<MapChange> {
+        let mut change: Option<MapChange> = None;
+        loop {
+            let saved = self.clone();
+            let Some((extent, before, after)) = self.next_piece() else {
+                return change;
+            };
+            if before == after {
+                match change {
+                    Some(_) => return change,
+                    None => continue,
+                }
+            }
+            match &mut change {
+                None => {
+                    change = Some(MapChange {
+                        extent,
+                        before,
+                        after,
+                    })
+                }
+                Some(c)
+                    if c.before == before
+                        && c.after == after
+                        && c.extent.end_address() == extent.address() =>
+                {
+                    c.extent = c.extent.join(extent);
+                }
+                Some(_) => {
+                    // Leave this piece for the next call.
+                    *self = saved;
+                    return change;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mark_areas, Map};
+    use MemoryType::*;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    fn entry(start: u64, end: u64, mem_type: MemoryType) -> MapEntry {
+        MapEntry {
+            extent: PhysExtent::from_raw_range_exclusive(start, end),
+            mem_type,
+        }
+    }
+
+    fn change(
+        start: u64,
+        end: u64,
+        before: Option<MemoryType>,
+        after: Option<MemoryType>,
+    ) -> MapChange {
+        MapChange {
+            extent: PhysExtent::from_raw_range_exclusive(start, end),
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn same_map() {
+        let map = [entry(0, 100, Available), entry(200, 300, Reserved)];
+        assert_eq!(diff(&map, &map).collect::<Vec<_>>(), []);
+    }
+
+    #[test]
+    fn split_entries_are_not_changes() {
+        let before = [entry(0, 100, Available)];
+        let after = [entry(0, 40, Available), entry(40, 100, Available)];
+        assert_eq!(diff(&before, &after).collect::<Vec<_>>(), []);
+    }
+
+    #[test]
+    fn marked_areas() {
+        let before = [entry(0, 100, Available), entry(100, 200, Reserved)];
+        let after = [
+            entry(0, 10, Available),
+            entry(10, 20, KernelLoad),
+            entry(20, 30, Reserved),
+            entry(30, 100, Available),
+            entry(100, 200, Reserved),
+        ];
+        assert_eq!(
+            diff(&before, &after).collect::<Vec<_>>(),
+            [
+                change(10, 20, Some(Available), Some(KernelLoad)),
+                change(20, 30, Some(Available), Some(Reserved)),
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_and_new_regions() {
+        let before = [entry(0, 100, Available), entry(200, 300, Acpi)];
+        let after = [
+            entry(0, 50, Available),
+            entry(150, 160, Reserved),
+            entry(200, 300, Acpi),
+            entry(300, 310, Available),
+        ];
+        assert_eq!(
+            diff(&before, &after).collect::<Vec<_>>(),
+            [
+                change(50, 100, Some(Available), None),
+                change(150, 160, None, Some(Reserved)),
+                change(300, 310, None, Some(Available)),
+            ]
+        );
+    }
+
+    #[test]
+    fn adjacent_changes_merge() {
+        let before = [entry(0, 50, Available), entry(50, 100, Available)];
+        let after = [entry(0, 100, Reserved)];
+        assert_eq!(
+            diff(&before, &after).collect::<Vec<_>>(),
+            [change(0, 100, Some(Available), Some(Reserved))]
+        );
+    }
+
+    fn map() -> impl Strategy<Value = Vec<MapEntry>> {
+        let mem_type = prop_oneof![Just(Available), Just(Acpi), Just(Reserved)];
+        proptest::collection::vec((0..100u64, 1..100u64, mem_type), 0..10).prop_map(|pieces| {
+            let mut start = 0;
+            pieces
+                .into_iter()
+                .map(|(gap, len, mem_type)| {
+                    start += gap;
+                    let e = entry(start, start + len, mem_type);
+                    start += len;
+                    e
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn only_marked_areas_change(before in map(), start in 0..1000u64, len in 1..500u64) {
+            let area = PhysExtent::from_raw(start, len);
+            let after: Map = Map::from_entries(mark_areas(
+                before.iter().copied(),
+                [area],
+                KernelLoad,
+            ));
+            for c in diff(&before, after.entries()) {
+                prop_assert!(area.contains(c.extent), "{c:?}");
+                prop_assert!(matches!(c.before, Some(Available | Acpi)), "{c:?}");
+                prop_assert_eq!(c.after, Some(KernelLoad));
+            }
+            prop_assert_eq!(diff(&before, &before).count(), 0);
+        }
+    }
+}
@@ -0,0 +1,935 @@
+//! Physical and virtual memory types, the memory map, and allocators
+//!
+//! This is everything the kernel and whatever boots it need to agree on about
+//! memory, with no dependencies on either. Its API is versioned on its own:
+//! anything that hands a `Map` or addresses between boot stages should use
+//! the same minor version.
+
+#![feature(allocator_api)]
+#![feature(const_option)]
+#![feature(int_roundings)]
+#![feature(maybe_uninit_slice)]
+#![feature(pointer_is_aligned)]
+#![feature(ptr_metadata)]
+#![feature(slice_ptr_len)]
+#![deny(unsafe_op_in_unsafe_fn)]
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod addr;
+pub mod alloc;
+pub mod diff;
+pub mod page;
+pub mod uefi;
+
+use page::{FrameRange, PAGE_SIZE};
+
+use core::iter::IntoIterator;
+
+use arrayvec::ArrayVec;
+use itertools::put_back;
+use itertools::structs::PutBack;
+
+pub use addr::*;
+
+/// The number of entries a `Map` holds unless its type says otherwise.
+pub const DEFAULT_MAP_CAPACITY: usize = 256;
+
+/// A map of the machine's physical memory, with room for `N` entries.
+///
+/// It is a fixed size array so it can be built before there is a heap and
+/// handed between boot stages as is.
+#[derive(Clone)]
+#[repr(C)]
+pub struct Map<const N: usize = DEFAULT_MAP_CAPACITY> {
+    entries: [MapEntry; N],
+    num_entries: u64,
+}
+
+impl<const N: usize> Map<N> {
+    /// `src` must be sorted by start address, and the extents must not overlap,
+    /// unless the map is `normalize`d afterwards. Adjacent entries of the same
+    /// type are merged, so `src` may have more than `N` entries as long as the
+    /// merged map fits.
+    pub fn from_entries<T: IntoIterator<Item = MapEntry>>(src: T) -> Self {
+        // Create an array filled with meaningless dummy entries. We will
+        // overwrite them with values from `src`.
+        let mut map = Map {
+            entries: [MapEntry {
+                extent: PhysExtent::from_raw(0, 1),
+                mem_type: MemoryType::Reserved,
+            }; N],
+            num_entries: 0,
+        };
+        for entry in src.into_iter() {
+            assert!(
+                map.push_merged(entry),
+                "memory map has more than {N} entries after merging"
+            );
+        }
+        map
+    }
+
+    /// Append `entry`, merging it with the last entry if they are adjacent
+    /// and of the same type. Returns false if there is no room.
+    fn push_merged(&mut self, entry: MapEntry) -> bool {
+        let len = self.num_entries as usize;
+        if let Some(last) = len.checked_sub(1).map(|i| &mut self.entries[i]) {
+            if last.mem_type == entry.mem_type
+                && last.extent.end_address() == entry.extent.address()
+            {
+                last.extent = last.extent.join(entry.extent);
+                return true;
+            }
+        }
+        if len == N {
+            return false;
+        }
+        self.entries[len] = entry;
+        self.num_entries += 1;
+        true
+    }
+
+    /// Make a map as firmware reports it, in any order and with overlapping
+    /// or partial-page entries, fit to allocate from. Afterwards entries are
+    /// sorted and page aligned, don't overlap, and adjacent ones differ in
+    /// type.
+    ///
+    /// Where entries overlap, the type whose memory is more restricted wins,
+    /// e.g. `Reserved` over `Acpi` over `Available`. `Available` entries only
+    /// keep the whole pages within them, and every other type grows to cover
+    /// the pages it touches, so a page is only available if all of it is.
+    /// Empty entries are dropped.
+    ///
+    /// Fails, leaving the map unchanged, if the result has more than `N`
+    /// entries, as when an entry is split around another inside it.
+    pub fn normalize(&mut self) -> Result<(), TooManyEntries> {
+        let clipped = |e: &MapEntry| {
+            if e.extent.length().as_raw() == 0 {
+                return None;
+            }
+            let extent = match e.mem_type {
+                MemoryType::Available => e.extent.shrink_to_alignment(PAGE_SIZE.as_raw())?,
+                _ => e.extent.expand_to_alignment(PAGE_SIZE.as_raw()),
+            };
+            Some(MapEntry {
+                extent,
+                mem_type: e.mem_type,
+            })
+        };
+        let sources = || self.entries().iter().filter_map(clipped);
+
+        // Sweep the boundaries of all entries in order. Between each two, the
+        // memory has the type of the strongest entry covering it, if any.
+        let mut normalized = Self::from_entries([]);
+        let mut pos = sources().map(|e| e.extent.address()).min();
+        while let Some(start) = pos {
+            pos = sources()
+                .flat_map(|e| [e.extent.address(), e.extent.end_address()])
+                .filter(|&a| a > start)
+                .min();
+            let Some(end) = pos else {
+                break;
+            };
+            let covering = sources()
+                .filter(|e| e.extent.address() <= start && start < e.extent.end_address())
+                .max_by_key(|e| e.mem_type.precedence());
+            if let Some(covering) = covering {
+                let entry = MapEntry {
+                    extent: PhysExtent::from_range_exclusive(start, end),
+                    mem_type: covering.mem_type,
+                };
+                if !normalized.push_merged(entry) {
+                    return Err(TooManyEntries);
+                }
+            }
+        }
+        *self = normalized;
+        Ok(())
+    }
+
+    /// Convert a UEFI memory map. The descriptors may be in any order.
+    /// Adjacent ones of the same `MemoryType` are merged, which usually keeps
+    /// large maps within the entry limit, since boot services memory merges
+    /// with conventional memory. Empty descriptors are skipped.
+    pub fn from_uefi_entries<T: IntoIterator<Item = uefi::MemoryDescriptor>>(
+        src: T,
+    ) -> Result<Self, FromUefiError> {
+        let mut map = Self::from_entries([]);
+        for desc in src {
+            if desc.number_of_pages == 0 {
+                continue;
+            }
+            let extent = desc.extent().ok_or(FromUefiError::Invalid(desc))?;
+            map.insert_merged(MapEntry {
+                extent,
+                mem_type: desc.mem_type(),
+            })?;
+        }
+        Ok(map)
+    }
+
+    /// Insert `entry` in order, merging it with adjacent entries of the same
+    /// type.
+    fn insert_merged(&mut self, entry: MapEntry) -> Result<(), FromUefiError> {
+        let len = self.num_entries as usize;
+        let i = self
+            .entries()
+            .partition_point(|e| e.extent.address() < entry.extent.address());
+        let prev = i.checked_sub(1).map(|j| self.entries[j]);
+        let next = self.entries().get(i).copied();
+
+        for neighbor in prev.iter().chain(next.iter()) {
+            if neighbor.extent.has_overlap(entry.extent) {
+                return Err(FromUefiError::Overlap(entry.extent));
+            }
+        }
+
+        let merge_prev = prev.is_some_and(|p| {
+            p.mem_type == entry.mem_type && p.extent.end_address() == entry.extent.address()
+        });
+        let merge_next = next.is_some_and(|n| {
+            n.mem_type == entry.mem_type && entry.extent.end_address() == n.extent.address()
+        });
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.entries[i - 1].extent =
+                    self.entries[i - 1].extent.join(self.entries[i].extent);
+                self.entries.copy_within(i + 1..len, i);
+                self.num_entries -= 1;
+            }
+            (true, false) => {
+                self.entries[i - 1].extent = self.entries[i - 1].extent.join(entry.extent);
+            }
+            (false, true) => {
+                self.entries[i].extent = self.entries[i].extent.join(entry.extent);
+            }
+            (false, false) => {
+                if len == self.entries.len() {
+                    return Err(FromUefiError::TooManyEntries);
+                }
+                self.entries.copy_within(i..len, i + 1);
+                self.entries[i] = entry;
+                self.num_entries += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[MapEntry] {
+        &self.entries[0..self.num_entries as usize]
+    }
+
+    pub fn entries_mut(&mut self) -> &mut [MapEntry] {
+        &mut self.entries[0..self.num_entries as usize]
+    }
+
+    pub fn iter_type(&self, mem_type: MemoryType) -> impl Iterator<Item = MapEntry> + '_ {
+        self.entries()
+            .iter()
+            .filter(move |e| e.mem_type == mem_type)
+            .copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FromUefiError {
+    /// The descriptor runs past the end of the address space.
+    Invalid(uefi::MemoryDescriptor),
+    /// The extent overlaps another descriptor's.
+    Overlap(PhysExtent),
+    /// The map doesn't fit even after merging.
+    TooManyEntries,
+}
+
+impl core::fmt::Display for FromUefiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FromUefiError::Invalid(desc) => write!(f, "invalid memory descriptor {desc:?}"),
+            FromUefiError::Overlap(extent) => write!(f, "overlapping memory at {extent:?}"),
+            FromUefiError::TooManyEntries => write!(f, "too many memory map entries"),
+        }
+    }
+}
+
+/// A normalized map doesn't fit in its entries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TooManyEntries;
+
+impl core::fmt::Display for TooManyEntries {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "too many memory map entries")
+    }
+}
+
+/// Given a sequence of memory regions, mark which areas contain kernel data
+/// from another sequence of extents. Both sequences must be sorted and
+/// non-overlapping.
+///
+/// Returns a sorted sequence of corrected regions.
+pub fn mark_kernel_areas<T: IntoIterator<Item = MapEntry>, U: IntoIterator<Item = PhysExtent>>(
+    regions: T,
+    kernel_areas: U,
+) -> impl Iterator<Item = MapEntry> {
+    mark_areas(regions, kernel_areas, MemoryType::KernelLoad)
+}
+
+/// Like `mark_kernel_areas`, but marks the overlapping parts of available
+/// regions as `mem_type`.
+pub fn mark_areas<T: IntoIterator<Item = MapEntry>, U: IntoIterator<Item = PhysExtent>>(
+    regions: T,
+    areas: U,
+    mem_type: MemoryType,
+) -> impl Iterator<Item = MapEntry> {
+    KernelAreaMarker {
+        regions: put_back(regions),
+        kernel_areas: put_back(areas),
+        mem_type,
+    }
+    .flatten()
+}
+
+/// Mark all available memory at or above `limit` as reserved, splitting a
+/// region that straddles it.
+pub fn limit_available<T: IntoIterator<Item = MapEntry>>(
+    regions: T,
+    limit: PhysAddress,
+) -> impl Iterator<Item = MapEntry> {
+    let above_limit = PhysExtent::from_range_exclusive(limit, PhysAddress::from_raw(u64::MAX));
+    mark_areas(regions, core::iter::once(above_limit), MemoryType::Reserved)
+}
+
+/// Implementation of `mark_kernel_areas`. Ideally we'd have a generator
+/// function but that's too unstable to rely on.
+struct KernelAreaMarker<T: Iterator<Item = MapEntry>, U: Iterator<Item = PhysExtent>> {
+    regions: PutBack<T>,
+    kernel_areas: PutBack<U>,
+    mem_type: MemoryType,
+}
+
+impl<T: Iterator<Item = MapEntry>, U: Iterator<Item = PhysExtent>> Iterator
+    for KernelAreaMarker<T, U>
+{
+    type Item = ArrayVec<MapEntry, 2>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cur = self.regions.next()?;
+
+        let one_vec = |x| {
+            let mut v = ArrayVec::new();
+            v.push(x);
+            v
+        };
+
+        // Some types we don't care about. Kernel extents shouldn't overlap with
+        // anything other than `Available`, and in the off chance they do
+        // there's nothing we can do.
+        match cur.mem_type {
+            MemoryType::Available => (),
+            MemoryType::Acpi => (),
+            _ => return Some(one_vec(cur)),
+        }
+
+        // Skip kernel pieces until we overlap the current region. This should
+        // only be one, but just in case...
+        while let Some(kernel) = self.kernel_areas.next() {
+            if kernel.overlap(cur.extent).is_none() && kernel.address < cur.extent.address {
+                continue;
+            }
+
+            self.kernel_areas.put_back(kernel);
+            break;
+        }
+
+        // If there's no more kernel areas to consider, we just return the
+        // region as is.
+        let Some(kernel) = self.kernel_areas.next() else {
+            return Some(one_vec(cur));
+        };
+
+        // If this extent is completely after `cur`, we can return `cur`.
+        // Put the extent back so we can consider it next round.
+        if kernel.overlap(cur.extent).is_none() && kernel.address > cur.extent.address {
+            self.kernel_areas.put_back(kernel);
+            return Some(one_vec(cur));
+        }
+
+        // Otherwise, we have overlap and need to split. We return the left
+        // difference and the kernel overlap, but not the right side which
+        // we put back. We also put back the kernel extent. The right may
+        // overlap the next kernel piece, and the next kernel piece might
+        // overlap the next region.
+        if let Some(right) = cur.extent.right_difference(kernel) {
+            self.regions.put_back(MapEntry {
+                extent: right,
+                mem_type: cur.mem_type,
+            })
+        }
+
+        let mut parts = ArrayVec::new();
+
+        // Yield the left side (if it exists) and the kernel region.
+        if let Some(left) = cur.extent.left_difference(kernel) {
+            parts.push(MapEntry {
+                extent: left,
+                mem_type: cur.mem_type,
+            });
+        }
+
+        parts.push(MapEntry {
+            extent: cur.extent.overlap(kernel).unwrap(),
+            mem_type: self.mem_type,
+        });
+
+        self.kernel_areas.put_back(kernel);
+
+        Some(parts)
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for Map<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Map")
+            .field("entries", &self.entries())
+            .field("num_entries", &self.num_entries)
+            .finish()
+    }
+}
+
+pub fn iter_map_frames<Iter: IntoIterator<Item = MapEntry>>(
+    iter: Iter,
+) -> impl Iterator<Item = FrameRange> {
+    iter.into_iter().flat_map(|e| {
+        Some(FrameRange::containing_extent(
+            e.extent.shrink_to_alignment(PAGE_SIZE.as_raw())?,
+        ))
+    })
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct MapEntry {
+    pub extent: PhysExtent,
+    pub mem_type: MemoryType,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum MemoryType {
+    /// Available for use
+    Available,
+    /// Contains ACPI information, but otherwise usable
+    Acpi,
+    /// Reserved and must be preserved on hibernation
+    ReservedPreserveOnHibernation,
+    /// Corrupt and unusable
+    Defective,
+    /// Cannot be used
+    Reserved,
+    /// Available, but where the bootloader loaded us. Can't be used unless
+    /// relocated.
+    KernelLoad,
+}
+
+impl MemoryType {
+    /// Which type `Map::normalize` gives memory that entries of both types
+    /// cover: the higher, whose memory can be used for less.
+    fn precedence(self) -> u8 {
+        match self {
+            MemoryType::Available => 0,
+            MemoryType::Acpi => 1,
+            MemoryType::KernelLoad => 2,
+            MemoryType::ReservedPreserveOnHibernation => 3,
+            MemoryType::Reserved => 4,
+            MemoryType::Defective => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_mark_kernel_areas() {
+        let regions = [
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(0, 100),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(100, 200),
+                mem_type: MemoryType::Reserved,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(200, 300),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(325, 375),
+                mem_type: MemoryType::Defective,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(400, 600),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(700, 800),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(800, 900),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(900, 1000),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(1100, 1200),
+                mem_type: MemoryType::Available,
+            },
+        ];
+
+        let areas = [
+            PhysExtent::from_raw_range_exclusive(25, 75),
+            PhysExtent::from_raw_range_exclusive(200, 300),
+            PhysExtent::from_raw_range_exclusive(400, 500),
+            PhysExtent::from_raw_range_exclusive(750, 775),
+            PhysExtent::from_raw_range_exclusive(790, 825),
+            PhysExtent::from_raw_range_exclusive(950, 1000),
+        ];
+
+        let correct = [
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(0, 25),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(25, 75),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(75, 100),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(100, 200),
+                mem_type: MemoryType::Reserved,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(200, 300),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(325, 375),
+                mem_type: MemoryType::Defective,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(400, 500),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(500, 600),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(700, 750),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(750, 775),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(775, 790),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(790, 800),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(800, 825),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(825, 900),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(900, 950),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(950, 1000),
+                mem_type: MemoryType::KernelLoad,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(1100, 1200),
+                mem_type: MemoryType::Available,
+            },
+        ];
+
+        pretty_assertions::assert_eq!(
+            mark_kernel_areas(regions, areas).collect::<Vec<_>>(),
+            correct.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_limit_available() {
+        let regions = [
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(0, 100),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(100, 200),
+                mem_type: MemoryType::Reserved,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(200, 300),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(400, 500),
+                mem_type: MemoryType::Available,
+            },
+        ];
+
+        let correct = [
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(0, 100),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(100, 200),
+                mem_type: MemoryType::Reserved,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(200, 250),
+                mem_type: MemoryType::Available,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(250, 300),
+                mem_type: MemoryType::Reserved,
+            },
+            MapEntry {
+                extent: PhysExtent::from_raw_range_exclusive(400, 500),
+                mem_type: MemoryType::Reserved,
+            },
+        ];
+
+        pretty_assertions::assert_eq!(
+            limit_available(regions, PhysAddress::from_raw(250)).collect::<Vec<_>>(),
+            correct.to_vec()
+        );
+    }
+
+    #[test]
+    fn from_entries_merges() {
+        let entries = (0..1000).map(|i| {
+            let mem_type = if i < 500 {
+                MemoryType::Available
+            } else {
+                MemoryType::Reserved
+            };
+            MapEntry {
+                extent: PhysExtent::from_raw(i * 10, 10),
+                mem_type,
+            }
+        });
+        let map = Map::<3>::from_entries(entries.chain([MapEntry {
+            extent: PhysExtent::from_raw(20000, 10),
+            mem_type: MemoryType::Reserved,
+        }]));
+        pretty_assertions::assert_eq!(
+            map.entries(),
+            [
+                MapEntry {
+                    extent: PhysExtent::from_raw(0, 5000),
+                    mem_type: MemoryType::Available,
+                },
+                MapEntry {
+                    extent: PhysExtent::from_raw(5000, 5000),
+                    mem_type: MemoryType::Reserved,
+                },
+                // Not merged across the gap.
+                MapEntry {
+                    extent: PhysExtent::from_raw(20000, 10),
+                    mem_type: MemoryType::Reserved,
+                },
+            ]
+        );
+    }
+
+    fn descriptor(ty: u32, start: u64, pages: u64) -> uefi::MemoryDescriptor {
+        uefi::MemoryDescriptor {
+            ty,
+            physical_start: start * uefi::PAGE_SIZE,
+            virtual_start: 0,
+            number_of_pages: pages,
+            attribute: 0,
+        }
+    }
+
+    fn entry(start: u64, pages: u64, mem_type: MemoryType) -> MapEntry {
+        MapEntry {
+            extent: PhysExtent::from_raw(start * uefi::PAGE_SIZE, pages * uefi::PAGE_SIZE),
+            mem_type,
+        }
+    }
+
+    #[test]
+    fn from_uefi_entries_merges() {
+        use uefi::*;
+
+        // Out of order, with boot services memory between conventional
+        // memory.
+        let descriptors = [
+            descriptor(CONVENTIONAL_MEMORY, 0, 16),
+            descriptor(BOOT_SERVICES_DATA, 20, 4),
+            descriptor(RUNTIME_SERVICES_DATA, 16, 2),
+            descriptor(BOOT_SERVICES_CODE, 18, 2),
+            descriptor(LOADER_DATA, 30, 1),
+            descriptor(CONVENTIONAL_MEMORY, 24, 6),
+            descriptor(ACPI_RECLAIM_MEMORY, 40, 1),
+            descriptor(CONVENTIONAL_MEMORY, 41, 0),
+            descriptor(CONVENTIONAL_MEMORY, 31, 9),
+            descriptor(MEMORY_MAPPED_IO, 100, 10),
+        ];
+        let map: Map = Map::from_uefi_entries(descriptors).unwrap();
+
+        pretty_assertions::assert_eq!(
+            map.entries(),
+            [
+                entry(0, 16, MemoryType::Available),
+                entry(16, 2, MemoryType::Reserved),
+                entry(18, 12, MemoryType::Available),
+                entry(30, 1, MemoryType::KernelLoad),
+                entry(31, 9, MemoryType::Available),
+                entry(40, 1, MemoryType::Acpi),
+                entry(100, 10, MemoryType::Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_uefi_entries_limits() {
+        use uefi::*;
+
+        // Many more descriptors than entries, as long as they merge.
+        let merging = (0..1000).map(|i| {
+            let ty = [CONVENTIONAL_MEMORY, BOOT_SERVICES_CODE, BOOT_SERVICES_DATA][i % 3];
+            descriptor(ty, i as u64, 1)
+        });
+        pretty_assertions::assert_eq!(
+            Map::<1>::from_uefi_entries(merging).unwrap().entries(),
+            [entry(0, 1000, MemoryType::Available)]
+        );
+
+        // Alternating types never merge.
+        let alternating = |n: u64| {
+            (0..n).map(|i| {
+                let ty = [CONVENTIONAL_MEMORY, RESERVED_MEMORY_TYPE][i as usize % 2];
+                descriptor(ty, i, 1)
+            })
+        };
+        assert_eq!(
+            Map::<128>::from_uefi_entries(alternating(128))
+                .unwrap()
+                .entries()
+                .len(),
+            128
+        );
+        assert_eq!(
+            Map::<128>::from_uefi_entries(alternating(129)).unwrap_err(),
+            FromUefiError::TooManyEntries
+        );
+
+        assert_eq!(
+            Map::<2>::from_uefi_entries([
+                descriptor(CONVENTIONAL_MEMORY, 0, 4),
+                descriptor(CONVENTIONAL_MEMORY, 3, 4),
+            ])
+            .unwrap_err(),
+            FromUefiError::Overlap(PhysExtent::from_raw(3 * PAGE_SIZE, 4 * PAGE_SIZE))
+        );
+        let huge = descriptor(CONVENTIONAL_MEMORY, 1, u64::MAX / PAGE_SIZE);
+        assert_eq!(
+            Map::<1>::from_uefi_entries([huge]).unwrap_err(),
+            FromUefiError::Invalid(huge)
+        );
+    }
+
+    fn raw(start: u64, end: u64, mem_type: MemoryType) -> MapEntry {
+        MapEntry {
+            extent: PhysExtent::from_raw_range_exclusive(start, end),
+            mem_type,
+        }
+    }
+
+    #[test]
+    fn normalize_sorts_and_clips_bios_map() {
+        use MemoryType::*;
+
+        // What QEMU's BIOS reports, in the order a buggy one might: the EBDA
+        // boundary and the end of low memory aren't page aligned.
+        let mut map = Map::<16>::from_entries([
+            raw(0x1_0000_0000, 0x2_8000_0000, Available),
+            raw(0x9_fc00, 0xa_0000, Reserved),
+            raw(0xfffc_0000, 0x1_0000_0000, Reserved),
+            raw(0x0, 0x9_fc00, Available),
+            raw(0x7ffe_0000, 0x8000_0000, Reserved),
+            raw(0xf_0000, 0x10_0000, Reserved),
+            raw(0x10_0000, 0x7ffe_0000, Available),
+            raw(0xfeff_c000, 0xff00_0000, Reserved),
+        ]);
+        map.normalize().unwrap();
+        pretty_assertions::assert_eq!(
+            map.entries(),
+            [
+                raw(0x0, 0x9_f000, Available),
+                raw(0x9_f000, 0xa_0000, Reserved),
+                raw(0xf_0000, 0x10_0000, Reserved),
+                raw(0x10_0000, 0x7ffe_0000, Available),
+                raw(0x7ffe_0000, 0x8000_0000, Reserved),
+                raw(0xfeff_c000, 0xff00_0000, Reserved),
+                raw(0xfffc_0000, 0x1_0000_0000, Reserved),
+                raw(0x1_0000_0000, 0x2_8000_0000, Available),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalize_resolves_overlaps() {
+        use MemoryType::*;
+
+        let mut map = Map::<16>::from_entries([
+            raw(0x0, 0x10_0000, Available),
+            // A hole in the middle splits it.
+            raw(0x8_0000, 0x9_0000, Reserved),
+            // ACPI tables straddling two available ranges, with a reserved
+            // page in them.
+            raw(0xf_0000, 0x11_0000, Acpi),
+            raw(0x10_0000, 0x20_0000, Available),
+            raw(0x10_8000, 0x10_9000, Reserved),
+            // Duplicated, and empty.
+            raw(0x18_0000, 0x20_0000, Available),
+            raw(0x15_0000, 0x15_0000, Reserved),
+            // Less than a page of available memory is none.
+            raw(0x30_0100, 0x30_0f00, Available),
+            // Less than a page of defective memory is a whole one.
+            raw(0x1f_f800, 0x1f_f900, Defective),
+            // Later entries don't win by being later.
+            raw(0x40_0000, 0x40_2000, Reserved),
+            raw(0x40_1000, 0x40_3000, Acpi),
+            raw(0x40_0000, 0x40_4000, Available),
+        ]);
+        map.normalize().unwrap();
+        pretty_assertions::assert_eq!(
+            map.entries(),
+            [
+                raw(0x0, 0x8_0000, Available),
+                raw(0x8_0000, 0x9_0000, Reserved),
+                raw(0x9_0000, 0xf_0000, Available),
+                raw(0xf_0000, 0x10_8000, Acpi),
+                raw(0x10_8000, 0x10_9000, Reserved),
+                raw(0x10_9000, 0x11_0000, Acpi),
+                raw(0x11_0000, 0x1f_f000, Available),
+                raw(0x1f_f000, 0x20_0000, Defective),
+                raw(0x40_0000, 0x40_2000, Reserved),
+                raw(0x40_2000, 0x40_3000, Acpi),
+                raw(0x40_3000, 0x40_4000, Available),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalize_fails_without_room() {
+        use MemoryType::*;
+
+        let entries = [raw(0x0, 0x1_0000, Available), raw(0x4000, 0x5000, Reserved)];
+        let mut map = Map::<2>::from_entries(entries);
+        assert_eq!(map.normalize(), Err(TooManyEntries));
+        assert_eq!(map.entries(), entries);
+
+        let mut map = Map::<3>::from_entries(entries);
+        map.normalize().unwrap();
+        assert_eq!(map.entries().len(), 3);
+    }
+
+    fn memory_type() -> impl Strategy<Value = MemoryType> {
+        use MemoryType::*;
+        prop_oneof![
+            Just(Available),
+            Just(Acpi),
+            Just(ReservedPreserveOnHibernation),
+            Just(Defective),
+            Just(Reserved),
+            Just(KernelLoad),
+        ]
+    }
+
+    const TEST_PAGES: u64 = 32;
+
+    proptest! {
+        #[test]
+        fn normalize_matches_each_page(
+            entries in prop::collection::vec(
+                (0..TEST_PAGES * PAGE_SIZE.as_raw(), 0..8 * PAGE_SIZE.as_raw(), memory_type()),
+                0..20,
+            ),
+        ) {
+            let entries: Vec<MapEntry> = entries
+                .into_iter()
+                .map(|(start, len, mem_type)| raw(start, start + len, mem_type))
+                .collect();
+            let mut map = Map::<64>::from_entries(entries.iter().copied());
+            map.normalize().unwrap();
+            let normalized = map.entries();
+
+            for e in normalized {
+                prop_assert!(e.extent.is_aligned_to(PAGE_SIZE.as_raw()), "{e:?}");
+            }
+            for pair in normalized.windows(2) {
+                let [a, b] = pair else { unreachable!() };
+                prop_assert!(a.extent.end_address() <= b.extent.address(), "{a:?} {b:?}");
+                prop_assert!(
+                    a.mem_type != b.mem_type || a.extent.end_address() != b.extent.address(),
+                    "{a:?} and {b:?} aren't merged"
+                );
+            }
+
+            // Each page has the strongest type of the entries covering it,
+            // where available memory only covers whole pages.
+            let page_size = PAGE_SIZE.as_raw();
+            for page in 0..TEST_PAGES + 8 {
+                let extent = PhysExtent::from_raw(page * page_size, page_size);
+                let expected = entries
+                    .iter()
+                    .filter(|e| e.extent.length().as_raw() > 0)
+                    .filter(|e| match e.mem_type {
+                        MemoryType::Available => e.extent.contains(extent),
+                        _ => e.extent.has_overlap(extent),
+                    })
+                    .map(|e| e.mem_type)
+                    .max_by_key(|t| t.precedence());
+                let actual = normalized
+                    .iter()
+                    .find(|e| e.extent.has_overlap(extent))
+                    .map(|e| e.mem_type);
+                prop_assert_eq!(actual, expected, "page {:#x}", page * page_size);
+            }
+
+            let mut again = map.clone();
+            again.normalize().unwrap();
+            prop_assert_eq!(again.entries(), map.entries());
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! Data structures representing pages and frames
+
+use super::addr::{Length, PhysAddress, PhysExtent, VirtAddress, VirtExtent};
+
+use core::iter::{self, FusedIterator, Iterator};
+use core::num::NonZeroU64;
+
+pub const PAGE_SIZE: Length = Length::from_raw(4096);
+
+/// A 4 KiB physical memory frame
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Frame {
+    start: PhysAddress,
+}
+
+impl Frame {
+    /// Creates a `Frame` representing the frame beginning at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` is not aligned to `PAGE_SIZE`.
+    pub fn new(start: PhysAddress) -> Frame {
+        assert!(start.is_aligned_to(PAGE_SIZE.as_raw()));
+        Frame { start }
+    }
+
+    /// Which number frame this is; in other words, the start address divided by
+    /// the page size.
+    pub fn index(self) -> u64 {
+        self.start.as_raw() / PAGE_SIZE.as_raw()
+    }
+
+    /// Gets the `Frame` that contains `addr`.
+    pub fn containing(addr: PhysAddress) -> Frame {
+        Self::new(addr.align_down(PAGE_SIZE.as_raw()))
+    }
+
+    /// Start address of the frame
+    pub fn start(self) -> PhysAddress {
+        self.start
+    }
+
+    /// Extent of memory contained in the frame
+    pub fn extent(self) -> PhysExtent {
+        PhysExtent::new(self.start, PAGE_SIZE)
+    }
+
+    /// The nth frame after `self`, or `None` if it's not addressable
+    pub fn next(self, n: u64) -> Option<Frame> {
+        let next_start = self
+            .start
+            .offset_by_checked(Length::from_raw(PAGE_SIZE.as_raw().checked_mul(n)?))?;
+        Some(Self::new(next_start))
+    }
+}
+
+/// A 4 KiB virtual memory page
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Page {
+    start: VirtAddress,
+}
+
+impl Page {
+    /// Creates a `Page` representing the page beginning at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` is not aligned to `PAGE_SIZE`.
+    pub fn new(start: VirtAddress) -> Page {
+        assert!(start.is_aligned_to(PAGE_SIZE.as_raw()));
+        Page { start }
+    }
+
+    /// Gets the `Page` that contains `addr`.
+    pub fn containing(addr: VirtAddress) -> Page {
+        Self::new(addr.align_down(PAGE_SIZE.as_raw()))
+    }
+
+    /// Start address of the page
+    pub fn start(&self) -> VirtAddress {
+        self.start
+    }
+
+    /// Extent of virtual address space contained in the page
+    pub fn extent(&self) -> VirtExtent {
+        VirtExtent::new(self.start, PAGE_SIZE)
+    }
+
+    /// The nth page after `self`, or `None` if it's not addressable
+    pub fn next(self, n: u64) -> Option<Page> {
+        let next_start = self
+            .start
+            .offset_by_checked(Length::from_raw(PAGE_SIZE.as_raw().checked_mul(n)?))?;
+        Some(Self::new(next_start))
+    }
+
+    pub fn l4_index(self) -> usize {
+        const FIRST_BIT: u32 = 12 + 9 + 9 + 9;
+        ((self.start.as_raw() & (0b1_1111_1111 << FIRST_BIT)) >> FIRST_BIT) as usize
+    }
+
+    pub fn l3_index(self) -> usize {
+        const FIRST_BIT: u32 = 12 + 9 + 9;
+        ((self.start.as_raw() & (0b1_1111_1111 << FIRST_BIT)) >> FIRST_BIT) as usize
+    }
+
+    pub fn l2_index(self) -> usize {
+        const FIRST_BIT: u32 = 12 + 9;
+        ((self.start.as_raw() & (0b1_1111_1111 << FIRST_BIT)) >> FIRST_BIT) as usize
+    }
+
+    pub fn l1_index(self) -> usize {
+        const FIRST_BIT: u32 = 12;
+        ((self.start.as_raw() & (0b1_1111_1111 << FIRST_BIT)) >> FIRST_BIT) as usize
+    }
+}
+
+/// A contiguous range of physical memory frames. Always non-empty.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameRange {
+    first: Frame,
+    count: NonZeroU64,
+}
+
+impl FrameRange {
+    pub fn new(first: Frame, count: u64) -> Option<FrameRange> {
+        let count = NonZeroU64::new(count)?;
+
+        // Check that `count` frames after and including `first` are
+        // addressable. `first.next(count)` may not be addressable if the range
+        // includes the last frame.
+        first.next(count.get() - 1)?;
+
+        Some(FrameRange { first, count })
+    }
+
+    // A single frame
+    pub fn one(frame: Frame) -> FrameRange {
+        Self::new(frame, 1).unwrap()
+    }
+
+    // All frames between and including `first` to `last`
+    pub fn between_inclusive(first: Frame, last: Frame) -> FrameRange {
+        let len = last.start() - first.start();
+        let count = len.as_raw() / PAGE_SIZE.as_raw() + 1;
+        Self::new(first, count).unwrap()
+    }
+
+    // All frames between `first` to `last`, including `first` but not `last`
+    pub fn between_exclusive(first: Frame, last: Frame) -> FrameRange {
+        let len = last.start() - first.start();
+        let count = len.as_raw() / PAGE_SIZE.as_raw();
+        Self::new(first, count).unwrap()
+    }
+
+    /// The minimal range fully containing `extent`.
+    pub fn containing_extent(extent: PhysExtent) -> FrameRange {
+        let first = Frame::containing(extent.address());
+        let last = Frame::containing(extent.last_address());
+        Self::between_inclusive(first, last)
+    }
+
+    /// The maximal range fully contained in `extent`.
+    pub fn contained_by_extent(extent: PhysExtent) -> Option<FrameRange> {
+        let first = extent.address().align_up(PAGE_SIZE.as_raw());
+        let last = (extent.last_address() - PAGE_SIZE + Length::from_raw(1))
+            .align_down(PAGE_SIZE.as_raw());
+        if first >= last {
+            return None;
+        }
+
+        let len = last - first;
+        assert!(len.is_aligned_to(PAGE_SIZE.as_raw()));
+        FrameRange::new(Frame::new(first), len.as_raw() / PAGE_SIZE.as_raw())
+    }
+
+    pub fn first(&self) -> Frame {
+        self.first
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    // The last `Frame` within the range
+    pub fn last(&self) -> Frame {
+        self.first.next(self.count.get() - 1).unwrap()
+    }
+
+    // The first `Frame` after the range, or `None` if it ends at the last frame.
+    pub fn end(&self) -> Option<Frame> {
+        self.first.next(self.count.get())
+    }
+}
+
+/// A contiguous range of virtual memory pages. Always non-empty.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageRange {
+    first: Page,
+    count: u64,
+}
+
+impl PageRange {
+    pub fn new(first: Page, count: u64) -> Option<PageRange> {
+        if count == 0 {
+            return None;
+        }
+
+        // Check that `count` frames after and including `first` are
+        // addressable. `first.next(count)` may not be addressable if the range
+        // includes the last frame.
+        first.next(count - 1)?;
+
+        Some(PageRange { first, count })
+    }
+
+    // A single page
+    pub fn one(page: Page) -> PageRange {
+        Self::new(page, 1).unwrap()
+    }
+
+    // All frames between and including `first` to `last`
+    pub fn between_inclusive(first: Page, last: Page) -> PageRange {
+        let len = last.start() - first.start();
+        let count = len.as_raw() / PAGE_SIZE.as_raw() + 1;
+        Self::new(first, count).unwrap()
+    }
+
+    // All frames between `first` to `last`, including `first` but not `last`
+    pub fn between_exclusive(first: Page, last: Page) -> Option<PageRange> {
+        let len = last.start() - first.start();
+        let count = len.as_raw() / PAGE_SIZE.as_raw();
+        Self::new(first, count)
+    }
+
+    pub fn containing_extent(extent: VirtExtent) -> PageRange {
+        let first = Page::containing(extent.address());
+        let last = Page::containing(extent.last_address());
+        Self::between_inclusive(first, last)
+    }
+
+    pub fn first(&self) -> Page {
+        self.first
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // The last `Page` within the range
+    pub fn last(&self) -> Page {
+        self.first.next(self.count - 1).unwrap()
+    }
+
+    // The first `Page` after the range, or `None` if it ends at the last frame.
+    pub fn end(&self) -> Option<Page> {
+        self.first.next(self.count)
+    }
+}
+
+/// Arithmetic and iteration for `$range`, a range of `$unit`s, which
+/// `FrameRange` and `PageRange` share.
+macro_rules! impl_range {
+    ($range:ident, $unit:ident, $iter:ident) => {
+        impl $range {
+            pub fn iter(&self) -> $iter {
+                $iter {
+                    first: self.first(),
+                    remaining: self.count(),
+                }
+            }
+
+            /// The units in both `self` and `other`, if any.
+            pub fn intersect(self, other: Self) -> Option<Self> {
+                let first = self.first().max(other.first());
+                let last = self.last().min(other.last());
+                (first <= last).then(|| Self::between_inclusive(first, last))
+            }
+
+            /// The units of `self` before and after `other`, if any.
+            pub fn difference(self, other: Self) -> (Option<Self>, Option<Self>) {
+                let before = if self.first() < other.first() {
+                    let count = (other.first().start() - self.first().start()).as_raw()
+                        / PAGE_SIZE.as_raw();
+                    Self::new(self.first(), count.min(self.count()))
+                } else {
+                    None
+                };
+                let after = (other.last() < self.last()).then(|| {
+                    let first = self.first().max(other.last().next(1).unwrap());
+                    Self::between_inclusive(first, self.last())
+                });
+                (before, after)
+            }
+
+            /// The first `n` units and the rest, if any. Panics if `n` is more
+            /// than `count()`.
+            pub fn split_at(self, n: u64) -> (Option<Self>, Option<Self>) {
+                assert!(
+                    n <= self.count(),
+                    "can't split {} units at {n}",
+                    self.count()
+                );
+                let rest = self
+                    .first()
+                    .next(n)
+                    .and_then(|first| Self::new(first, self.count() - n));
+                (Self::new(self.first(), n), rest)
+            }
+
+            /// Consecutive ranges of `n` units, except that the last may be
+            /// shorter. Panics if `n` is 0.
+            pub fn chunks(self, n: u64) -> impl Clone + Iterator<Item = Self> {
+                assert!(n > 0, "chunks must not be empty");
+                let mut rest = Some(self);
+                iter::from_fn(move || {
+                    let (chunk, tail) = rest?.split_at(n.min(rest?.count()));
+                    rest = tail;
+                    chunk
+                })
+            }
+        }
+
+        impl IntoIterator for $range {
+            type Item = $unit;
+            type IntoIter = $iter;
+
+            fn into_iter(self) -> $iter {
+                self.iter()
+            }
+        }
+
+        /// The units of a range in order, taken from either end.
+        #[derive(Clone, Debug)]
+        pub struct $iter {
+            first: $unit,
+            remaining: u64,
+        }
+
+        impl Iterator for $iter {
+            type Item = $unit;
+
+            fn next(&mut self) -> Option<$unit> {
+                self.remaining = self.remaining.checked_sub(1)?;
+                let unit = self.first;
+                if self.remaining > 0 {
+                    self.first = unit.next(1).unwrap();
+                }
+                Some(unit)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (self.remaining as usize, Some(self.remaining as usize))
+            }
+        }
+
+        impl DoubleEndedIterator for $iter {
+            fn next_back(&mut self) -> Option<$unit> {
+                self.remaining = self.remaining.checked_sub(1)?;
+                Some(self.first.next(self.remaining).unwrap())
+            }
+        }
+
+        impl ExactSizeIterator for $iter {}
+
+        impl FusedIterator for $iter {}
+    };
+}
+
+impl_range!(FrameRange, Frame, FrameIter);
+impl_range!(PageRange, Page, PageIter);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn frames(first: u64, count: u64) -> FrameRange {
+        FrameRange::new(Frame::new(PhysAddress::from_zero(PAGE_SIZE * first)), count).unwrap()
+    }
+
+    fn pages(first: u64, count: u64) -> PageRange {
+        PageRange::new(Page::new(VirtAddress::from_zero(PAGE_SIZE * first)), count).unwrap()
+    }
+
+    #[test]
+    fn ranges_intersect() {
+        assert_eq!(frames(0, 4).intersect(frames(2, 4)), Some(frames(2, 2)));
+        assert_eq!(frames(2, 4).intersect(frames(0, 4)), Some(frames(2, 2)));
+        assert_eq!(frames(0, 8).intersect(frames(2, 2)), Some(frames(2, 2)));
+        assert_eq!(frames(0, 2).intersect(frames(2, 2)), None);
+        assert_eq!(pages(5, 1).intersect(pages(0, 10)), Some(pages(5, 1)));
+    }
+
+    #[test]
+    fn ranges_subtract() {
+        assert_eq!(
+            frames(0, 8).difference(frames(2, 2)),
+            (Some(frames(0, 2)), Some(frames(4, 4)))
+        );
+        assert_eq!(
+            frames(0, 4).difference(frames(2, 4)),
+            (Some(frames(0, 2)), None)
+        );
+        assert_eq!(
+            frames(2, 4).difference(frames(0, 4)),
+            (None, Some(frames(4, 2)))
+        );
+        assert_eq!(frames(2, 2).difference(frames(0, 8)), (None, None));
+        // Disjoint ranges are all before or all after.
+        assert_eq!(
+            pages(0, 2).difference(pages(4, 2)),
+            (Some(pages(0, 2)), None)
+        );
+        assert_eq!(
+            pages(4, 2).difference(pages(0, 2)),
+            (None, Some(pages(4, 2)))
+        );
+    }
+
+    #[test]
+    fn ranges_split() {
+        assert_eq!(
+            frames(0, 4).split_at(1),
+            (Some(frames(0, 1)), Some(frames(1, 3)))
+        );
+        assert_eq!(frames(0, 4).split_at(0), (None, Some(frames(0, 4))));
+        assert_eq!(frames(0, 4).split_at(4), (Some(frames(0, 4)), None));
+        assert_eq!(
+            pages(3, 5).chunks(2).collect::<Vec<_>>(),
+            [pages(3, 2), pages(5, 2), pages(7, 1)]
+        );
+        assert_eq!(frames(0, 4).chunks(4).collect::<Vec<_>>(), [frames(0, 4)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't split 4 units at 5")]
+    fn splitting_past_the_end_panics() {
+        frames(0, 4).split_at(5);
+    }
+
+    #[test]
+    fn iterators_go_both_ways() {
+        let mut iter = pages(10, 3).iter();
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.next_back(), Some(pages(12, 1).first()));
+        assert_eq!(iter.next(), Some(pages(10, 1).first()));
+        assert_eq!(iter.len(), 1);
+        assert_eq!(iter.next(), Some(pages(11, 1).first()));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn ranges_reach_the_last_frame() {
+        let last_frame = u64::MAX / PAGE_SIZE.as_raw();
+        let top = frames(last_frame - 2, 3);
+        assert_eq!(top.end(), None);
+        assert_eq!(top.iter().count(), 3);
+        assert_eq!(top.iter().next_back(), Some(top.last()));
+        assert_eq!(top.split_at(3), (Some(top), None));
+        assert_eq!(top.chunks(2).count(), 2);
+        assert_eq!(
+            top.difference(frames(last_frame - 1, 1)),
+            (Some(frames(last_frame - 2, 1)), Some(frames(last_frame, 1)))
+        );
+    }
+
+    fn indexes(range: Option<FrameRange>) -> Vec<u64> {
+        range
+            .iter()
+            .flat_map(|r| r.iter())
+            .map(Frame::index)
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn range_operations_match_sets(
+            (a_first, a_count, b_first, b_count) in (0..32u64, 1..16u64, 0..32u64, 1..16u64),
+            n in 1..20u64,
+        ) {
+            let a = frames(a_first, a_count);
+            let b = frames(b_first, b_count);
+            let in_b = |i: &u64| (b_first..b_first + b_count).contains(i);
+            let a_indexes = indexes(Some(a));
+
+            let both: Vec<u64> = a_indexes.iter().copied().filter(in_b).collect();
+            prop_assert_eq!(indexes(a.intersect(b)), both);
+
+            let (before, after) = a.difference(b);
+            prop_assert_eq!(
+                indexes(before),
+                a_indexes.iter().copied().filter(|i| *i < b_first).collect::<Vec<_>>()
+            );
+            prop_assert_eq!(
+                indexes(after),
+                a_indexes
+                    .iter()
+                    .copied()
+                    .filter(|i| *i >= b_first + b_count)
+                    .collect::<Vec<_>>()
+            );
+
+            let split = n.min(a_count);
+            let (head, tail) = a.split_at(split);
+            prop_assert_eq!(indexes(head), a_indexes[..split as usize].to_vec());
+            prop_assert_eq!(indexes(tail), a_indexes[split as usize..].to_vec());
+
+            let chunks: Vec<FrameRange> = a.chunks(n).collect();
+            prop_assert!(chunks.iter().all(|c| c.count() <= n));
+            prop_assert_eq!(
+                chunks.iter().flat_map(|c| c.iter()).map(Frame::index).collect::<Vec<_>>(),
+                a_indexes.clone()
+            );
+
+            let mut reversed = indexes(Some(a));
+            reversed.reverse();
+            prop_assert_eq!(a.iter().rev().map(Frame::index).collect::<Vec<_>>(), reversed);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! UEFI memory map descriptors
+//!
+//! A UEFI loader gets the memory map from `GetMemoryMap` as an array of
+//! `MemoryDescriptor`s. The firmware's descriptor size may be larger than
+//! `MemoryDescriptor`, so the loader must step through the array by the size
+//! the firmware reports. `Map::from_uefi_entries` converts the descriptors
+//! for the kernel.
+
+use super::{MemoryType, PhysAddress, PhysExtent};
+use crate::addr::Length;
+
+/// UEFI pages are always 4 KiB.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// `EFI_MEMORY_DESCRIPTOR`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct MemoryDescriptor {
+    /// An `EFI_MEMORY_TYPE`, e.g. `CONVENTIONAL_MEMORY`.
+    pub ty: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+// `EFI_MEMORY_TYPE` values.
+pub const RESERVED_MEMORY_TYPE: u32 = 0;
+pub const LOADER_CODE: u32 = 1;
+pub const LOADER_DATA: u32 = 2;
+pub const BOOT_SERVICES_CODE: u32 = 3;
+pub const BOOT_SERVICES_DATA: u32 = 4;
+pub const RUNTIME_SERVICES_CODE: u32 = 5;
+pub const RUNTIME_SERVICES_DATA: u32 = 6;
+pub const CONVENTIONAL_MEMORY: u32 = 7;
+pub const UNUSABLE_MEMORY: u32 = 8;
+pub const ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const ACPI_MEMORY_NVS: u32 = 10;
+pub const MEMORY_MAPPED_IO: u32 = 11;
+pub const MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+pub const PAL_CODE: u32 = 13;
+pub const PERSISTENT_MEMORY: u32 = 14;
+
+impl MemoryDescriptor {
+    /// The memory described, or `None` if the descriptor is empty or runs
+    /// past the end of the address space.
+    pub fn extent(&self) -> Option<PhysExtent> {
+        let length = self.number_of_pages.checked_mul(PAGE_SIZE)?;
+        PhysExtent::new_checked(
+            PhysAddress::from_raw(self.physical_start),
+            Length::from_raw(length),
+        )
+    }
+
+    /// How the kernel may use the memory once boot services have exited.
+    pub fn mem_type(&self) -> MemoryType {
+        match self.ty {
+            // Boot services memory is free once they exit.
+            CONVENTIONAL_MEMORY | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA => MemoryType::Available,
+            // The loader's allocations hold the kernel and what it hands over.
+            LOADER_CODE | LOADER_DATA => MemoryType::KernelLoad,
+            ACPI_RECLAIM_MEMORY => MemoryType::Acpi,
+            ACPI_MEMORY_NVS => MemoryType::ReservedPreserveOnHibernation,
+            UNUSABLE_MEMORY => MemoryType::Defective,
+            // Runtime services, MMIO, persistent memory and types from newer
+            // specifications.
+            _ => MemoryType::Reserved,
+        }
+    }
+}
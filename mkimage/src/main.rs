@@ -1,50 +1,99 @@
+mod testsuite;
+mod uefi;
+mod verify;
+
 use buildutil::*;
 
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use cargo_metadata::Message;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use shared::cpio;
 
+/// Build a bootable ISO from the kernel and init, check one, or run the tests.
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    build: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Check that a built image has what GRUB loads, laid out the way the
+    /// kernel expects, and print its contents.
+    Verify {
+        #[arg(default_value = "out/kernel.iso")]
+        image: PathBuf,
+    },
+    /// Run the host unit tests, then boot the kernel under QEMU with each
+    /// integration test program as init and with the in-kernel tests, and
+    /// report whether all passed.
+    Test {
+        /// The QEMU binary to run.
+        #[arg(long, default_value = "qemu-system-x86_64")]
+        qemu: String,
+    },
+}
+
+// Building is the default, so mkimage can be the kernel's cargo runner.
+#[derive(clap::Args, Debug)]
 struct Args {
-    kernel_image: PathBuf,
+    #[arg(required = true)]
+    kernel_image: Option<PathBuf>,
+
+    /// Which program from the init package to run as the first process.
+    #[arg(long, default_value = "init")]
+    init: String,
+
+    /// Arguments passed to the init program, separated by spaces.
+    #[arg(long, default_value = "")]
+    init_args: String,
+
+    /// The kernel's command line, e.g. `test_mode` or `log=debug`.
+    #[arg(long, default_value = "")]
+    kernel_args: String,
+
+    /// A previous image's boot directory, with its kernel, init and initrd,
+    /// to fall back to if the new kernel repeatedly fails to boot. See
+    /// src/boot_slot.rs.
+    #[arg(long)]
+    known_good: Option<PathBuf>,
+
+    /// Also build out/kernel.img, a GPT disk image that boots with UEFI, e.g.
+    /// under QEMU with OVMF.
+    #[arg(long)]
+    uefi: bool,
 }
 
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
 
-    let args = Args::parse();
-
-    // Build init binary:
-    let mut init_build_command = Command::new(env::var("CARGO")?)
-        .args(&["ibuild", "--message-format=json-render-diagnostics"])
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
-
-    let mut init_bin: Option<PathBuf> = None;
-    for message in cargo_metadata::Message::parse_stream(std::io::BufReader::new(
-        init_build_command.stdout.take().unwrap(),
-    )) {
-        let message = message?;
-        match message {
-            Message::CompilerArtifact(artifact) => {
-                if let Some(ref exe) = artifact.executable {
-                    assert_eq!(init_bin, None, "other artifact {:?}", artifact);
-                    init_bin = Some(exe.as_std_path().to_path_buf());
-                }
-            }
-            Message::BuildFinished(m) => assert!(m.success),
-            _ => (),
-        }
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Commands::Verify { image }) => verify::verify(&image),
+        Some(Commands::Test { qemu }) => testsuite::run(&qemu),
+        None => build(cli.build),
     }
+}
+
+fn build(args: Args) -> eyre::Result<()> {
+    let kernel_image = args.kernel_image.expect("required by clap");
 
-    assert!(init_build_command.wait()?.success());
-    let init_bin = init_bin.unwrap();
+    // Build init binary. Every program in the init package, by name:
+    let programs = cargo_build(&["ibuild"])?;
+    let Some((_, init_bin)) = programs.iter().find(|(name, _)| *name == args.init) else {
+        eyre::bail!("init package has no program named {}", args.init);
+    };
 
-    println!("Building image from {}...", args.kernel_image.display());
+    println!("Building image from {}...", kernel_image.display());
 
     // mkdir -p out/iso/boot/grub
     // cp grub.cfg out/iso/boot/grub
@@ -53,9 +102,31 @@ fn main() -> eyre::Result<()> {
     // grub-mkrescue -o out/kernel.iso -d /usr/lib/grub/i386-pc out/iso
 
     fs::create_dir_all("out/iso/boot/grub").unwrap();
-    fs::copy("grub.cfg", "out/iso/boot/grub/grub.cfg").unwrap();
-    fs::copy(args.kernel_image, "out/iso/boot/kernel").unwrap();
+    // The init module's command line becomes its arguments, starting with the
+    // program name.
+    let init_cmdline = format!("{} {}", args.init, args.init_args);
+    let grub_cfg = match &args.known_good {
+        Some(known_good) => {
+            fs::create_dir_all("out/iso/boot/good")?;
+            for file in ["kernel", "init", "initrd"] {
+                fs::copy(known_good.join(file), format!("out/iso/boot/good/{file}"))?;
+            }
+            slotted_grub_cfg(&fs::read(&kernel_image)?)?
+        }
+        None => {
+            if fs::metadata("out/iso/boot/good").is_ok() {
+                fs::remove_dir_all("out/iso/boot/good")?;
+            }
+            fs::read_to_string("grub.cfg")?
+        }
+    };
+    let grub_cfg = grub_cfg
+        .replace("@KERNEL_CMDLINE@", args.kernel_args.trim())
+        .replace("@INIT_CMDLINE@", init_cmdline.trim());
+    fs::write("out/iso/boot/grub/grub.cfg", grub_cfg)?;
+    fs::copy(&kernel_image, "out/iso/boot/kernel").unwrap();
     fs::copy(init_bin, "out/iso/boot/init").unwrap();
+    write_initrd(&programs, "out/iso/boot/initrd")?;
 
     if cfg!(feature = "grub-mkrescue") {
         run_and_check(
@@ -95,5 +166,79 @@ fn main() -> eyre::Result<()> {
         ]))?
     }
 
+    if args.uefi {
+        uefi::build(Path::new("out/iso/boot"), Path::new("out/kernel.img"))?;
+    }
+    Ok(())
+}
+
+/// grub-slots.cfg, with the trial kernel's generation filled in: eight bits of
+/// a hash of `kernel`, so a rebuilt kernel almost always gets fresh boot
+/// attempts.
+fn slotted_grub_cfg(kernel: &[u8]) -> eyre::Result<String> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    kernel.hash(&mut hasher);
+    let generation = hasher.finish() as u8;
+
+    let bits: String = (0..8)
+        .map(|bit| if generation & 1 << bit != 0 { '1' } else { '0' })
+        .collect();
+    let set_generation: Vec<String> = (0..8)
+        .map(|bit| {
+            let command = if generation & 1 << bit != 0 {
+                "cmosset"
+            } else {
+                "cmosclean"
+            };
+            format!("    {command} 0x7d:{bit}")
+        })
+        .collect();
+    Ok(fs::read_to_string("grub-slots.cfg")?
+        .replace("@GENERATION@", &bits)
+        .replace("@SET_GENERATION@", &set_generation.join("\n")))
+}
+
+/// Run a cargo build command, e.g. `["ibuild"]`, and return the executables
+/// it built, by name.
+fn cargo_build(args: &[&str]) -> eyre::Result<Vec<(String, PathBuf)>> {
+    let mut command = Command::new(env::var("CARGO")?)
+        .args(args)
+        .arg("--message-format=json-render-diagnostics")
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    let mut executables = Vec::new();
+    for message in cargo_metadata::Message::parse_stream(std::io::BufReader::new(
+        command.stdout.take().unwrap(),
+    )) {
+        let message = message?;
+        match message {
+            Message::CompilerArtifact(artifact) => {
+                if let Some(ref exe) = artifact.executable {
+                    executables.push((artifact.target.name, exe.as_std_path().to_path_buf()));
+                }
+            }
+            Message::BuildFinished(m) => eyre::ensure!(m.success, "cargo {args:?} failed"),
+            _ => (),
+        }
+    }
+
+    eyre::ensure!(command.wait()?.success(), "cargo {args:?} failed");
+    Ok(executables)
+}
+
+/// Pack the init package's programs into a cpio archive at `path`, as
+/// `/bin/<name>`.
+fn write_initrd(programs: &[(String, PathBuf)], path: &str) -> eyre::Result<()> {
+    let files = programs
+        .iter()
+        .map(|(name, bin)| Ok((format!("bin/{name}"), fs::read(bin)?)))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let archive = cpio::write(
+        files
+            .iter()
+            .map(|(path, data)| (path.as_str(), data.as_slice())),
+    );
+    fs::write(path, archive)?;
     Ok(())
 }
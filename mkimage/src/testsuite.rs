@@ -0,0 +1,198 @@
+//! `mkimage test`: the whole test suite in one command
+//!
+//! Runs the host unit tests, then boots the kernel under QEMU once per
+//! integration case, with a different init program, kernel features or kernel
+//! command line. The kernel is built with the `qemu_exit` feature, so init
+//! exiting or a panic ends QEMU with a status that says which. Each boot's
+//! debug console output is saved in `out/test/`. Some boots also get a
+//! virtio disk with known contents and the built init, rewritten for every
+//! run.
+
+use crate::{build, cargo_build, Args};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use buildutil::qemu::{self, Boot, Outcome};
+use shared::test_disk;
+
+/// An init program to boot, and how long it may take.
+struct Case {
+    /// Names the case's log file.
+    name: &'static str,
+    /// Kernel features, which must include `qemu_exit`.
+    features: &'static str,
+    kernel_args: &'static str,
+    init: &'static str,
+    init_args: &'static str,
+    /// Attach the test disk, see `shared::test_disk`.
+    test_disk: bool,
+    timeout: Duration,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "init",
+        features: "qemu_exit",
+        kernel_args: "",
+        init: "init",
+        init_args: "",
+        test_disk: false,
+        timeout: Duration::from_secs(60),
+    },
+    // A fixed seed, so a failure can be reproduced.
+    Case {
+        name: "syscall-fuzz",
+        features: "qemu_exit",
+        kernel_args: "",
+        init: "syscall-fuzz",
+        init_args: "20 1",
+        test_disk: false,
+        timeout: Duration::from_secs(120),
+    },
+    // The in-kernel tests, e.g. of the scheduler, instead of init.
+    Case {
+        name: "itest",
+        features: "qemu_exit",
+        kernel_args: "test_mode",
+        init: "init",
+        init_args: "",
+        test_disk: true,
+        timeout: Duration::from_secs(120),
+    },
+    // init loaded from the test disk's filesystem instead of its module.
+    Case {
+        name: "init-from-disk",
+        features: "qemu_exit",
+        kernel_args: "init=/bin/init",
+        init: "init",
+        init_args: "",
+        test_disk: true,
+        timeout: Duration::from_secs(60),
+    },
+];
+
+const LOG_DIR: &str = "out/test";
+
+const TEST_DISK: &str = "out/test/disk.img";
+
+/// Lines of a failed boot's log to print.
+const LOG_TAIL_LINES: usize = 30;
+
+pub fn run(qemu: &str) -> eyre::Result<()> {
+    let mut failures = Vec::new();
+
+    println!("host unit tests...");
+    let status = Command::new(std::env::var("CARGO")?)
+        .args(["test", "--package", "shared", "--package", "testos-memory"])
+        .status()?;
+    report("host unit tests", status.success(), &mut failures);
+
+    println!("host heap tests with debug_alloc...");
+    let status = Command::new(std::env::var("CARGO")?)
+        .args([
+            "test",
+            "--package",
+            "testos-memory",
+            "--features",
+            "debug_alloc",
+        ])
+        .status()?;
+    report(
+        "host heap tests with debug_alloc",
+        status.success(),
+        &mut failures,
+    );
+
+    fs::create_dir_all(LOG_DIR)?;
+    for case in CASES {
+        let name = format!("boot {}", case.name);
+        println!("{name}...");
+        let log = Path::new(LOG_DIR).join(format!("{}.log", case.name));
+        match build_kernel(case.features).and_then(|kernel| boot(qemu, &kernel, case, &log)) {
+            Ok(()) => report(&name, true, &mut failures),
+            Err(e) => {
+                eprintln!("{e}");
+                print_tail(&log);
+                report(&name, false, &mut failures);
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        println!("all tests passed");
+        return Ok(());
+    }
+    eyre::bail!("{} failed: {}", failures.len(), failures.join(", "));
+}
+
+fn report(name: &str, passed: bool, failures: &mut Vec<String>) {
+    println!("{}: {name}", if passed { "PASS" } else { "FAIL" });
+    if !passed {
+        failures.push(name.to_string());
+    }
+}
+
+/// Build the kernel with `features` and return its path. Cargo only rebuilds
+/// it when the features change.
+fn build_kernel(features: &str) -> eyre::Result<PathBuf> {
+    let executables = cargo_build(&["kbuild", "--features", features])?;
+    let Some((_, kernel)) = executables.into_iter().find(|(name, _)| name == "kernel") else {
+        eyre::bail!("kernel build produced no kernel");
+    };
+    Ok(kernel)
+}
+
+/// Build an image running `case` and boot it, saving the debug console to
+/// `log`. Succeeds if init exits with 0, or in test mode if the in-kernel
+/// tests pass.
+fn boot(qemu: &str, kernel: &Path, case: &Case, log: &Path) -> eyre::Result<()> {
+    build(Args {
+        kernel_image: Some(PathBuf::from(kernel)),
+        init: case.init.to_string(),
+        init_args: case.init_args.to_string(),
+        kernel_args: case.kernel_args.to_string(),
+        known_good: None,
+        uefi: false,
+    })?;
+
+    let mut extra_args = Vec::new();
+    if case.test_disk {
+        write_test_disk()?;
+        extra_args.push("-drive".to_string());
+        extra_args.push(format!("file={TEST_DISK},format=raw,if=virtio"));
+    }
+
+    let outcome = qemu::run(&Boot {
+        qemu: qemu.to_string(),
+        timeout: case.timeout,
+        extra_args,
+        ..Boot::new("out/kernel.iso", log)
+    })?;
+    match outcome {
+        Outcome::Passed => Ok(()),
+        Outcome::Failed(reason) => eyre::bail!("{reason}"),
+        Outcome::TimedOut => eyre::bail!("timed out after {:?}", case.timeout),
+    }
+}
+
+/// Write the test disk afresh, undoing writes from the last boot. `build`
+/// put init in the image's boot directory, and it goes in `/bin` too.
+fn write_test_disk() -> eyre::Result<()> {
+    let init = fs::read("out/iso/boot/init")?;
+    fs::write(TEST_DISK, test_disk::image(&[("/bin/init", &init)]))?;
+    Ok(())
+}
+
+fn print_tail(log: &Path) {
+    let Ok(text) = fs::read_to_string(log) else {
+        return;
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    eprintln!("last lines of {}:", log.display());
+    for line in &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..] {
+        eprintln!("  {line}");
+    }
+}
@@ -0,0 +1,213 @@
+//! `mkimage --uefi`: a GPT disk image with an EFI System Partition
+//!
+//! testos has no UEFI loader of its own, so the ESP holds GRUB built for
+//! x86_64-efi as `/EFI/BOOT/BOOTX64.EFI`, the path firmware boots from
+//! removable media. It loads the same grub.cfg, kernel, init and initrd as the
+//! ISO, copied from `out/iso/boot` to `/boot`. GRUB's x86_64-efi modules must
+//! be installed, e.g. from Debian's grub-efi-amd64-bin.
+//!
+//! The partition table is written here rather than with a partitioning tool:
+//! a protective MBR, and primary and backup GPT headers describing the single
+//! partition.
+
+use buildutil::*;
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::process::Command;
+
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Where the ESP starts, and the space left for the backup GPT after it.
+const ALIGNMENT: u64 = 1 << 20;
+
+/// FAT32 needs at least 65525 clusters, so with 512 byte clusters the
+/// partition can't be much smaller than this.
+const ESP_SIZE: u64 = 64 << 20;
+
+const PARTITION_ENTRIES: u64 = 128;
+const PARTITION_ENTRY_SIZE: u64 = 128;
+/// Sectors taken by the partition entries.
+const ENTRY_SECTORS: u64 = PARTITION_ENTRIES * PARTITION_ENTRY_SIZE / SECTOR_SIZE;
+
+const GPT_HEADER_SIZE: u32 = 92;
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B, with the first three fields
+/// little-endian as GPT stores them.
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// GRUB modules built into BOOTX64.EFI.
+const GRUB_MODULES: &[&str] = &[
+    "part_gpt",
+    "fat",
+    "normal",
+    "multiboot2",
+    "efi_gop",
+    "all_video",
+    "test",
+    "cmostest",
+];
+
+/// Write a UEFI-bootable disk image of `boot_dir`, the ISO's `/boot`, to
+/// `image`.
+pub fn build(boot_dir: &Path, image: &Path) -> eyre::Result<()> {
+    let grub_efi = Path::new("out/BOOTX64.EFI");
+    run_and_check(
+        Command::new("grub-mkimage")
+            .args(["-O", "x86_64-efi", "-d", "/usr/lib/grub/x86_64-efi"])
+            .args(["-p", "/boot/grub", "-o"])
+            .arg(grub_efi)
+            .args(GRUB_MODULES),
+    )?;
+
+    let mut esp = Cursor::new(vec![0; ESP_SIZE as usize]);
+    fatfs::format_volume(
+        &mut esp,
+        FormatVolumeOptions::new()
+            .fat_type(FatType::Fat32)
+            .bytes_per_cluster(SECTOR_SIZE as u32)
+            .total_sectors((ESP_SIZE / SECTOR_SIZE) as u32)
+            .volume_label(*b"TESTOS ESP "),
+    )?;
+    esp.set_position(0);
+    {
+        let fs = FileSystem::new(&mut esp, FsOptions::new())?;
+        let root = fs.root_dir();
+        root.create_dir("EFI")?.create_dir("BOOT")?;
+        root.create_file("EFI/BOOT/BOOTX64.EFI")?
+            .write_all(&fs::read(grub_efi)?)?;
+        copy_dir(boot_dir, &root.create_dir("boot")?)?;
+    }
+
+    fs::write(image, gpt_disk(&esp.into_inner()))?;
+    Ok(())
+}
+
+/// Copy the files under `from` into `to`, recursively.
+fn copy_dir(from: &Path, to: &fatfs::Dir<&mut Cursor<Vec<u8>>>) -> eyre::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| eyre::eyre!("non-UTF-8 file name {name:?}"))?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.create_dir(name)?)?;
+        } else {
+            to.create_file(name)?.write_all(&fs::read(entry.path())?)?;
+        }
+    }
+    Ok(())
+}
+
+/// A disk holding `esp` as its only partition.
+fn gpt_disk(esp: &[u8]) -> Vec<u8> {
+    let sectors = (2 * ALIGNMENT + esp.len() as u64) / SECTOR_SIZE;
+    let first_lba = ALIGNMENT / SECTOR_SIZE;
+    let last_lba = first_lba + esp.len() as u64 / SECTOR_SIZE - 1;
+    let mut disk = vec![0; (sectors * SECTOR_SIZE) as usize];
+
+    // Derived from the contents, so identical inputs give identical images.
+    let disk_guid = guid(esp, 0);
+    let partition_guid = guid(esp, 1);
+
+    // A protective MBR covering the whole disk, so MBR tools leave it alone.
+    let mbr = &mut disk[446..512];
+    mbr[..16].copy_from_slice(&protective_mbr_entry(sectors));
+    mbr[64..].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut entries = vec![0; (PARTITION_ENTRIES * PARTITION_ENTRY_SIZE) as usize];
+    entries[0..16].copy_from_slice(&ESP_TYPE_GUID);
+    entries[16..32].copy_from_slice(&partition_guid);
+    entries[32..40].copy_from_slice(&first_lba.to_le_bytes());
+    entries[40..48].copy_from_slice(&last_lba.to_le_bytes());
+    for (i, unit) in "EFI System Partition".encode_utf16().enumerate() {
+        entries[56 + 2 * i..58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+    }
+
+    let backup_header_lba = sectors - 1;
+    let backup_entries_lba = backup_header_lba - ENTRY_SECTORS;
+    let header = |lba: u64, alternate_lba: u64, entries_lba: u64| {
+        let mut header = vec![0; GPT_HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + ENTRY_SECTORS).to_le_bytes());
+        header[48..56].copy_from_slice(&(backup_entries_lba - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(PARTITION_ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(PARTITION_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    };
+
+    let mut write = |lba: u64, data: &[u8]| {
+        let offset = (lba * SECTOR_SIZE) as usize;
+        disk[offset..offset + data.len()].copy_from_slice(data);
+    };
+    write(1, &header(1, backup_header_lba, 2));
+    write(2, &entries);
+    write(first_lba, esp);
+    write(backup_entries_lba, &entries);
+    write(
+        backup_header_lba,
+        &header(backup_header_lba, 1, backup_entries_lba),
+    );
+    disk
+}
+
+/// The MBR partition entry marking a GPT disk of `sectors` sectors.
+fn protective_mbr_entry(sectors: u64) -> [u8; 16] {
+    let mut entry = [0; 16];
+    // Start CHS 0/0/2, i.e. LBA 1.
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xee;
+    // End CHS unrepresentable.
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let len = u32::try_from(sectors - 1).unwrap_or(u32::MAX);
+    entry[12..16].copy_from_slice(&len.to_le_bytes());
+    entry
+}
+
+/// A version 4 style GUID from a hash of `data` and `salt`.
+fn guid(data: &[u8], salt: u64) -> [u8; 16] {
+    let mut guid = [0; 16];
+    for (half, chunk) in guid.chunks_mut(8).enumerate() {
+        let mut hasher = DefaultHasher::new();
+        (salt, half).hash(&mut hasher);
+        data.hash(&mut hasher);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    guid[7] = guid[7] & 0x0f | 0x40;
+    guid[8] = guid[8] & 0x3f | 0x80;
+    guid
+}
+
+/// The CRC-32 GPT uses, as in zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
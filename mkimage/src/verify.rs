@@ -0,0 +1,260 @@
+//! `mkimage verify`: check a built image before booting it
+//!
+//! Extracts `/boot` from the ISO with xorriso, checks the files GRUB loads are
+//! there, that the kernel and init ELFs are laid out the way the kernel
+//! expects and that the initrd is a valid archive, and prints a manifest. Packaging mistakes show up here instead of
+//! as a hang or triple fault at boot.
+
+use buildutil::*;
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use shared::cpio;
+use shared::elf64::{Elf, FileKind, Section};
+
+/// Where the kernel's sections must be, other than the bootstrap ones. Must
+/// match `VirtualMap::kernel_image()` in the kernel.
+const KERNEL_IMAGE: Range<u64> = 0xffff_ffff_8000_0000..u64::MAX;
+
+/// Where the bootstrap sections must be: the bootstrap page table identity
+/// maps the first GiB.
+const BOOTSTRAP: Range<u64> = 0..1 << 30;
+
+/// Where init's sections must be. Must match `VirtualMap::user()` in the
+/// kernel.
+const USER: Range<u64> = 1 << 20..0x0000_8000_0000_0000;
+
+const MULTIBOOT2_MAGIC: u32 = 0xe852_50d6;
+
+/// GRUB only looks for the multiboot2 header this far into the kernel file.
+const MULTIBOOT2_SEARCH_LEN: usize = 32768;
+
+pub fn verify(image: &Path) -> eyre::Result<()> {
+    let dir = std::env::temp_dir().join(format!("mkimage-verify-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    let result = verify_extracted(image, &dir);
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+fn verify_extracted(image: &Path, dir: &Path) -> eyre::Result<()> {
+    run_and_check(
+        Command::new("xorriso")
+            .args(["-osirrox", "on", "-indev"])
+            .arg(image)
+            .args(["-extract", "/boot"])
+            .arg(dir.join("boot")),
+    )?;
+
+    let mut problems = Vec::new();
+    println!("{}:", image.display());
+
+    let grub_cfg = read(dir, "boot/grub/grub.cfg", &mut problems);
+    let mut slotted = false;
+    if let Some(cfg) = grub_cfg {
+        let cfg = String::from_utf8_lossy(&cfg);
+        slotted = cfg.contains("/boot/good/kernel");
+        if cfg.contains("@INIT_CMDLINE@") {
+            problems.push("boot/grub/grub.cfg has no init command line".to_string());
+        }
+        if cfg.contains("@KERNEL_CMDLINE@") {
+            problems.push("boot/grub/grub.cfg has no kernel command line".to_string());
+        }
+        for file in ["/boot/kernel", "/boot/init", "/boot/initrd"] {
+            if !cfg.contains(file) {
+                problems.push(format!("boot/grub/grub.cfg doesn't load {file}"));
+            }
+        }
+    }
+
+    // With A/B boot slots, the known-good slot's files are in boot/good.
+    let slots: &[&str] = if slotted {
+        &["boot", "boot/good"]
+    } else {
+        &["boot"]
+    };
+    for slot in slots {
+        check_slot(dir, slot, &mut problems);
+    }
+
+    if problems.is_empty() {
+        println!("OK");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    eyre::bail!("{} problems found in {}", problems.len(), image.display());
+}
+
+/// Read `path` under `dir` and print its manifest line, or note that it is
+/// missing.
+fn read(dir: &Path, path: &str, problems: &mut Vec<String>) -> Option<Vec<u8>> {
+    let full: PathBuf = dir.join(path);
+    match fs::read(&full) {
+        Ok(data) => {
+            println!("  {path}: {} bytes", data.len());
+            Some(data)
+        }
+        Err(e) => {
+            problems.push(format!("{path} is missing: {e}"));
+            None
+        }
+    }
+}
+
+/// Check the kernel, init and initrd in `slot`, e.g. `boot`.
+fn check_slot(dir: &Path, slot: &str, problems: &mut Vec<String>) {
+    let kernel_path = format!("{slot}/kernel");
+    if let Some(kernel) = read(dir, &kernel_path, problems) {
+        check_kernel(&kernel_path, &kernel, problems);
+    }
+    let init_path = format!("{slot}/init");
+    if let Some(init) = read(dir, &init_path, problems) {
+        check_elf(&init_path, &init, problems, |elf, section| {
+            in_range(USER, section).then_some(()).ok_or_else(|| {
+                format!(
+                    "section {} at {:#x} is outside user space",
+                    name(elf, &section),
+                    section.address
+                )
+            })
+        });
+    }
+
+    let initrd_path = format!("{slot}/initrd");
+    if let Some(initrd) = read(dir, &initrd_path, problems) {
+        match cpio::Archive::new(&initrd) {
+            Ok(archive) => {
+                for entry in archive.entries().filter(|e| e.is_file()) {
+                    println!("    /{:<30} {:>10} bytes", entry.path, entry.data.len());
+                }
+            }
+            Err(e) => problems.push(format!("{initrd_path} is not a valid archive: {e}")),
+        }
+    }
+}
+
+fn check_kernel(path: &str, data: &[u8], problems: &mut Vec<String>) {
+    match find_multiboot2_header(data) {
+        Some(offset) => println!("    multiboot2 header at offset {offset:#x}"),
+        None => problems.push(format!(
+            "{path} has no multiboot2 header in its first {MULTIBOOT2_SEARCH_LEN} bytes"
+        )),
+    }
+
+    let entry_in_bootstrap = check_elf(path, data, problems, |elf, section| {
+        let name = name(elf, &section);
+        let (range, area) = if name.starts_with(".bootstrap") {
+            (BOOTSTRAP, "the bootstrap identity map")
+        } else {
+            (KERNEL_IMAGE, "the kernel image area")
+        };
+        in_range(range, section)
+            .then_some(())
+            .ok_or_else(|| format!("section {name} at {:#x} is outside {area}", section.address))
+    })
+    .map(|elf| {
+        // GRUB jumps to the entry point in 32 bit protected mode, before
+        // paging, so it must be in a bootstrap section.
+        let entry = elf.entry();
+        elf.sections().any(|section| {
+            name(&elf, &section).starts_with(".bootstrap") && section.contains(entry)
+        })
+    });
+    if entry_in_bootstrap == Some(false) {
+        problems.push(format!("{path} entry point is not in a bootstrap section"));
+    }
+}
+
+/// Check that `data` is an x86_64 executable whose allocated sections pass
+/// `check_section`, and print its entry point and sections. Returns the
+/// parsed file if it is one.
+fn check_elf<'a>(
+    path: &str,
+    data: &'a [u8],
+    problems: &mut Vec<String>,
+    check_section: impl Fn(&Elf<'a>, Section) -> Result<(), String>,
+) -> Option<Elf<'a>> {
+    let elf = match Elf::parse(data) {
+        Ok(elf) => elf,
+        Err(e) => {
+            problems.push(format!("{path} is not a valid x86_64 ELF file: {e}"));
+            return None;
+        }
+    };
+    if elf.kind() != FileKind::Executable {
+        problems.push(format!("{path} is not a static executable"));
+        return None;
+    }
+
+    let entry = elf.entry();
+    println!("    entry {entry:#x}");
+    let mut entry_in_section = false;
+    for section in elf.sections() {
+        if !section.is_alloc() || section.size == 0 {
+            continue;
+        }
+        println!(
+            "    {:<24} {:#018x} {:>10} bytes",
+            name(&elf, &section),
+            section.address,
+            section.size
+        );
+        entry_in_section |= section.contains(entry);
+        if let Err(problem) = check_section(&elf, section) {
+            problems.push(format!("{path}: {problem}"));
+        }
+    }
+    if !entry_in_section {
+        problems.push(format!(
+            "{path}: entry point {entry:#x} is not in any section"
+        ));
+    }
+    Some(elf)
+}
+
+fn name<'a>(elf: &Elf<'a>, section: &Section) -> &'a str {
+    elf.section_name(section).unwrap_or("<unnamed>")
+}
+
+/// Whether all of `section` is in `range`.
+fn in_range(range: Range<u64>, section: Section) -> bool {
+    let start = section.address;
+    let end = start.checked_add(section.size);
+    range.contains(&start) && end.is_some_and(|end| end <= range.end)
+}
+
+/// The offset of a valid multiboot2 header in `data`, if there is one where
+/// GRUB looks.
+fn find_multiboot2_header(data: &[u8]) -> Option<usize> {
+    let word = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    (0..data.len().min(MULTIBOOT2_SEARCH_LEN))
+        .step_by(8)
+        .find(|&offset| {
+            let (Some(magic), Some(arch), Some(len), Some(checksum)) = (
+                word(offset),
+                word(offset + 4),
+                word(offset + 8),
+                word(offset + 12),
+            ) else {
+                return false;
+            };
+            magic == MULTIBOOT2_MAGIC
+                && magic
+                    .wrapping_add(arch)
+                    .wrapping_add(len)
+                    .wrapping_add(checksum)
+                    == 0
+        })
+}
@@ -0,0 +1,189 @@
+//! Boots a built image in QEMU and reports whether it passed, for scripted
+//! boot tests.
+//!
+//! The debug console (and, with `--serial-log`, the serial port) is followed
+//! until a success or failure marker shows up, QEMU exits, or the timeout
+//! passes. A kernel panic always counts as a failure. Exits with status 0 if
+//! the boot passed, 1 if it failed, 2 if it timed out, 3 if QEMU couldn't be
+//! run and 4 if the boot passed but its metrics regressed.
+//!
+//! With `--baseline`, the boot metrics the kernel reports (see
+//! `shared::boot_metrics`) are compared with the ones saved in the baseline
+//! file once the boot passes. `--update-baseline` saves them instead.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use buildutil::qemu::{self, Boot, Outcome};
+use clap::Parser;
+use eyre::WrapErr;
+use shared::boot_metrics::{self, Metric, Thresholds};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The image to boot: an ISO, or a raw disk image such as the one
+    /// `mkimage --uefi` builds.
+    #[arg(default_value = "out/kernel.iso")]
+    image: PathBuf,
+
+    /// Guest memory, in QEMU's syntax.
+    #[arg(short, long, default_value = "256M")]
+    memory: String,
+
+    #[arg(short, long, default_value_t = 1)]
+    cpus: u32,
+
+    /// Firmware to boot with, e.g. OVMF for UEFI images.
+    #[arg(long)]
+    firmware: Option<PathBuf>,
+
+    /// Where to save the debug console output.
+    #[arg(long, default_value = "out/qemurun.log")]
+    log: PathBuf,
+
+    /// Also capture the serial port, to this file.
+    #[arg(long)]
+    serial_log: Option<PathBuf>,
+
+    /// Output that means the boot passed. May be repeated.
+    #[arg(long = "success")]
+    success_markers: Vec<String>,
+
+    /// Output that means the boot failed, in addition to a panic. May be
+    /// repeated.
+    #[arg(long = "failure")]
+    failure_markers: Vec<String>,
+
+    /// Seconds to wait before giving up.
+    #[arg(short, long, default_value_t = 60)]
+    timeout: u64,
+
+    /// Don't copy the output to stdout.
+    #[arg(short, long)]
+    quiet: bool,
+
+    #[arg(long, default_value = "qemu-system-x86_64")]
+    qemu: String,
+
+    /// Compare the boot's metrics with the ones saved in this file.
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// Save the boot's metrics to the `--baseline` file instead of comparing.
+    #[arg(long, requires = "baseline")]
+    update_baseline: bool,
+
+    /// How many percent a metric may grow over the baseline.
+    #[arg(long, default_value_t = 10)]
+    max_regression: u64,
+
+    /// How many percent metrics starting with PREFIX may grow instead, as
+    /// PREFIX=PERCENT, e.g. `phase.=50` for the noisier phase timings. May
+    /// be repeated.
+    #[arg(long = "threshold", value_parser = parse_threshold)]
+    thresholds: Vec<(String, u64)>,
+
+    /// Extra arguments for QEMU, after `--`.
+    #[arg(last = true)]
+    qemu_args: Vec<String>,
+}
+
+fn main() -> eyre::Result<ExitCode> {
+    color_eyre::install()?;
+    let args = Args::parse();
+
+    let mut boot = Boot::new(args.image, args.log);
+    boot.qemu = args.qemu;
+    boot.firmware = args.firmware;
+    boot.memory = args.memory;
+    boot.cpus = args.cpus;
+    boot.serial_log = args.serial_log;
+    boot.success_markers = args.success_markers;
+    boot.failure_markers.extend(args.failure_markers);
+    boot.timeout = Duration::from_secs(args.timeout);
+    boot.echo = !args.quiet;
+    boot.extra_args = args.qemu_args;
+
+    let code = match qemu::run(&boot) {
+        Ok(Outcome::Passed) => match &args.baseline {
+            Some(baseline) => {
+                let thresholds = Thresholds {
+                    default_percent: args.max_regression,
+                    overrides: args.thresholds,
+                };
+                check_metrics(&boot, baseline, args.update_baseline, &thresholds)?
+            }
+            None => {
+                println!("PASS");
+                0
+            }
+        },
+        Ok(Outcome::Failed(reason)) => {
+            println!("FAIL: {reason}");
+            1
+        }
+        Ok(Outcome::TimedOut) => {
+            println!("TIMEOUT after {:?}", boot.timeout);
+            2
+        }
+        Err(e) => {
+            eprintln!("{e:?}");
+            3
+        }
+    };
+    Ok(ExitCode::from(code))
+}
+
+fn parse_threshold(s: &str) -> Result<(String, u64), String> {
+    let (prefix, percent) = s
+        .split_once('=')
+        .ok_or_else(|| "expected PREFIX=PERCENT".to_string())?;
+    let percent = percent.parse().map_err(|e| format!("{percent:?}: {e}"))?;
+    Ok((prefix.to_string(), percent))
+}
+
+/// Compare the metrics of the boot that just passed with `baseline`, or save
+/// them there if `update`. Returns the exit code.
+fn check_metrics(
+    boot: &Boot,
+    baseline: &Path,
+    update: bool,
+    thresholds: &Thresholds,
+) -> eyre::Result<u8> {
+    let mut current = BTreeMap::new();
+    for log in std::iter::once(&boot.log).chain(&boot.serial_log) {
+        let text = fs::read(log).wrap_err_with(|| format!("reading {}", log.display()))?;
+        current.extend(boot_metrics::parse_all(&String::from_utf8_lossy(&text)));
+    }
+    eyre::ensure!(!current.is_empty(), "the kernel reported no boot metrics");
+
+    if update {
+        let text: String = current
+            .iter()
+            .map(|(name, &value)| format!("{}\n", Metric { name, value }))
+            .collect();
+        fs::write(baseline, text).wrap_err_with(|| format!("writing {}", baseline.display()))?;
+        println!(
+            "PASS, saved {} metrics to {}",
+            current.len(),
+            baseline.display()
+        );
+        return Ok(0);
+    }
+
+    let saved =
+        fs::read_to_string(baseline).wrap_err_with(|| format!("reading {}", baseline.display()))?;
+    let regressions = boot_metrics::compare(&boot_metrics::parse_all(&saved), &current, thresholds);
+    if regressions.is_empty() {
+        println!("PASS, no metrics regressed");
+        return Ok(0);
+    }
+    for regression in &regressions {
+        println!("REGRESSED: {regression}");
+    }
+    println!("FAIL: {} metrics regressed", regressions.len());
+    Ok(4)
+}
@@ -0,0 +1,391 @@
+//! Reconstructs how tasks interleaved from a kernel built with the
+//! `sched_trace` feature.
+//!
+//! Reads the kernel's output (e.g. from QEMU's `-debugcon`), picks out the
+//! `trace:` lines written on panic, and prints the events in logical time
+//! order with one column per CPU. Then reports pairs of locks acquired in
+//! both orders, which could deadlock, and what each CPU was running and
+//! holding when the trace ends.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use clap::Parser;
+use shared::trace::{Event, Record};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Kernel output to read. Reads stdin if omitted.
+    log: Option<PathBuf>,
+
+    /// Width of each CPU's column in the timeline.
+    #[arg(long, default_value_t = 28)]
+    column_width: usize,
+}
+
+fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::parse();
+
+    let input = match args.log {
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+
+    let mut records: Vec<Record> = input
+        .lines()
+        .filter_map(|line| Record::parse(line.split_once("trace: ")?.1))
+        .collect();
+    eyre::ensure!(!records.is_empty(), "no trace records found");
+    records.sort_by_key(|r| r.time);
+
+    let mut names = Names::default();
+    for r in &records {
+        match r.event {
+            Event::Switch { from, to } => {
+                names.task(from);
+                names.task(to);
+            }
+            Event::Wake { task } => names.task(task),
+            Event::Acquire { lock } | Event::Release { lock } => names.lock(lock),
+        }
+    }
+
+    print_timeline(&records, &names, args.column_width);
+    println!();
+    print_lock_inversions(&lock_inversions(&records), &names);
+    println!();
+    print_final_state(&final_state(&records), &names);
+
+    Ok(())
+}
+
+/// Short names for tasks and locks, in order of first appearance.
+#[derive(Default)]
+struct Names {
+    tasks: HashMap<u64, String>,
+    locks: HashMap<u64, String>,
+}
+
+impl Names {
+    fn task(&mut self, address: u64) {
+        let n = self.tasks.len();
+        self.tasks.entry(address).or_insert_with(|| format!("T{n}"));
+    }
+
+    fn lock(&mut self, address: u64) {
+        let n = self.locks.len();
+        self.locks.entry(address).or_insert_with(|| format!("L{n}"));
+    }
+
+    fn event(&self, event: Event) -> String {
+        match event {
+            Event::Switch { from, to } => format!("{} -> {}", self.tasks[&from], self.tasks[&to]),
+            Event::Wake { task } => format!("wake {}", self.tasks[&task]),
+            Event::Acquire { lock } => format!("acquire {}", self.locks[&lock]),
+            Event::Release { lock } => format!("release {}", self.locks[&lock]),
+        }
+    }
+}
+
+fn print_timeline(records: &[Record], names: &Names, width: usize) {
+    let num_cpus = records.iter().map(|r| r.cpu).max().unwrap() as usize + 1;
+
+    print!("{:>8}", "time");
+    for cpu in 0..num_cpus {
+        print!("  {:width$}", format!("cpu{cpu}"));
+    }
+    println!();
+
+    let mut expected = records[0].time;
+    for r in records {
+        if r.time != expected {
+            println!("{:>8}  ({} records lost)", "...", r.time - expected);
+        }
+        expected = r.time + 1;
+
+        let cpu = r.cpu as usize;
+        print!("{:>8}", r.time);
+        print!("{}", " ".repeat(cpu * (width + 2)));
+        println!("  {}", names.event(r.event));
+    }
+
+    println!();
+    print_legend("tasks", &names.tasks);
+    print_legend("locks", &names.locks);
+}
+
+fn print_legend(title: &str, names: &HashMap<u64, String>) {
+    let mut names: Vec<_> = names.iter().collect();
+    names.sort_by_key(|(_, name)| name[1..].parse::<usize>().unwrap());
+    println!("{title}:");
+    for (address, name) in names {
+        println!("  {name:>4} = {address:#x}");
+    }
+}
+
+/// Replays lock acquisitions and task switches, tracking what each CPU is
+/// running and holding. A task can't hold a spinlock across a switch except
+/// inside the scheduler, so attributing locks to CPUs is enough.
+#[derive(Default)]
+struct Replay {
+    running: BTreeMap<u32, u64>,
+    held: BTreeMap<u32, Vec<u64>>,
+}
+
+impl Replay {
+    fn apply(&mut self, r: &Record) {
+        match r.event {
+            Event::Switch { to, .. } => {
+                self.running.insert(r.cpu, to);
+            }
+            Event::Acquire { lock } => self.held.entry(r.cpu).or_default().push(lock),
+            Event::Release { lock } => {
+                // The acquisition may have been before the trace starts.
+                let held = self.held.entry(r.cpu).or_default();
+                if let Some(i) = held.iter().rposition(|&l| l == lock) {
+                    held.remove(i);
+                }
+            }
+            Event::Wake { .. } => (),
+        }
+    }
+}
+
+/// The first time a CPU acquired `second` while holding `first`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct LockOrder {
+    first: u64,
+    second: u64,
+    time: u64,
+    task: Option<u64>,
+}
+
+/// Two locks acquired in both orders, which could deadlock. `forward` has
+/// the lower addressed lock first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Inversion {
+    forward: LockOrder,
+    reverse: LockOrder,
+}
+
+fn lock_inversions(records: &[Record]) -> Vec<Inversion> {
+    let mut replay = Replay::default();
+    let mut orders: BTreeMap<(u64, u64), LockOrder> = BTreeMap::new();
+    for r in records {
+        if let Event::Acquire { lock } = r.event {
+            for &first in replay.held.get(&r.cpu).into_iter().flatten() {
+                orders.entry((first, lock)).or_insert(LockOrder {
+                    first,
+                    second: lock,
+                    time: r.time,
+                    task: replay.running.get(&r.cpu).copied(),
+                });
+            }
+        }
+        replay.apply(r);
+    }
+
+    orders
+        .iter()
+        .filter(|(&(first, second), _)| first < second)
+        .filter_map(|(&(first, second), &forward)| {
+            let reverse = *orders.get(&(second, first))?;
+            Some(Inversion { forward, reverse })
+        })
+        .collect()
+}
+
+fn print_lock_inversions(inversions: &[Inversion], names: &Names) {
+    let describe = |order: &LockOrder| {
+        let task = match order.task {
+            Some(task) => names.tasks[&task].as_str(),
+            None => "unknown task",
+        };
+        format!(
+            "{} then {} at time {} by {task}",
+            names.locks[&order.first], names.locks[&order.second], order.time
+        )
+    };
+
+    for inversion in inversions {
+        println!("lock order inversion:");
+        println!("  {}", describe(&inversion.forward));
+        println!("  {}", describe(&inversion.reverse));
+    }
+    if inversions.is_empty() {
+        println!("no lock order inversions");
+    }
+}
+
+/// What a CPU was doing when the trace ends.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct CpuState {
+    running: Option<u64>,
+    /// In the order they were acquired.
+    holding: Vec<u64>,
+}
+
+/// The state of every CPU up to the highest numbered one in `records`.
+fn final_state(records: &[Record]) -> Vec<CpuState> {
+    let mut replay = Replay::default();
+    for r in records {
+        replay.apply(r);
+    }
+
+    let num_cpus = records.iter().map(|r| r.cpu).max().map_or(0, |cpu| cpu + 1);
+    (0..num_cpus)
+        .map(|cpu| CpuState {
+            running: replay.running.get(&cpu).copied(),
+            holding: replay.held.remove(&cpu).unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn print_final_state(state: &[CpuState], names: &Names) {
+    println!("at end of trace:");
+    for (cpu, state) in state.iter().enumerate() {
+        let task = state
+            .running
+            .map_or("unknown task", |t| names.tasks[&t].as_str());
+        let locks: Vec<&str> = state
+            .holding
+            .iter()
+            .map(|l| names.locks[l].as_str())
+            .collect();
+        if locks.is_empty() {
+            println!("  cpu{cpu}: running {task}");
+        } else {
+            println!("  cpu{cpu}: running {task}, holding {}", locks.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    const A: u64 = 0x1000;
+    const B: u64 = 0x2000;
+    const C: u64 = 0x3000;
+
+    const T0: u64 = 0x10_0000;
+    const T1: u64 = 0x20_0000;
+
+    /// Records numbered in the order given.
+    fn records(events: &[(u32, Event)]) -> Vec<Record> {
+        events
+            .iter()
+            .enumerate()
+            .map(|(time, &(cpu, event))| Record {
+                time: time as u64,
+                cpu,
+                event,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_inversion_across_cpus() {
+        let records = records(&[
+            (0, Event::Switch { from: 0, to: T0 }),
+            (1, Event::Switch { from: 0, to: T1 }),
+            (0, Event::Acquire { lock: A }),
+            (0, Event::Acquire { lock: B }),
+            (0, Event::Release { lock: B }),
+            (0, Event::Release { lock: A }),
+            (1, Event::Acquire { lock: B }),
+            (1, Event::Acquire { lock: A }),
+        ]);
+        assert_eq!(
+            lock_inversions(&records),
+            vec![Inversion {
+                forward: LockOrder {
+                    first: A,
+                    second: B,
+                    time: 3,
+                    task: Some(T0),
+                },
+                reverse: LockOrder {
+                    first: B,
+                    second: A,
+                    time: 7,
+                    task: Some(T1),
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn consistent_order_is_not_an_inversion() {
+        let records = records(&[
+            (0, Event::Acquire { lock: A }),
+            (0, Event::Acquire { lock: B }),
+            (0, Event::Release { lock: B }),
+            (0, Event::Release { lock: A }),
+            (1, Event::Acquire { lock: A }),
+            (1, Event::Acquire { lock: B }),
+            // Taking B alone orders nothing.
+            (0, Event::Acquire { lock: B }),
+        ]);
+        assert_eq!(lock_inversions(&records), vec![]);
+    }
+
+    #[test]
+    fn release_before_trace_is_ignored() {
+        // A was acquired before the trace starts, so releasing it must not
+        // drop anything else, and later acquisitions aren't ordered after it.
+        let records = records(&[
+            (0, Event::Acquire { lock: B }),
+            (0, Event::Release { lock: A }),
+            (0, Event::Acquire { lock: C }),
+            (0, Event::Release { lock: C }),
+            (0, Event::Acquire { lock: A }),
+        ]);
+        assert_eq!(lock_inversions(&records), vec![]);
+        assert_eq!(
+            final_state(&records),
+            vec![CpuState {
+                running: None,
+                holding: vec![B, A],
+            }]
+        );
+    }
+
+    #[test]
+    fn final_state_per_cpu() {
+        let records = records(&[
+            (0, Event::Switch { from: 0, to: T0 }),
+            (0, Event::Acquire { lock: A }),
+            (2, Event::Switch { from: 0, to: T1 }),
+            (2, Event::Acquire { lock: B }),
+            (2, Event::Release { lock: B }),
+            (0, Event::Switch { from: T0, to: T1 }),
+            (0, Event::Wake { task: T0 }),
+        ]);
+        assert_eq!(
+            final_state(&records),
+            vec![
+                CpuState {
+                    running: Some(T1),
+                    holding: vec![A],
+                },
+                // CPU 1 recorded nothing.
+                CpuState::default(),
+                CpuState {
+                    running: Some(T1),
+                    holding: vec![],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! ACPI table parsing
+//!
+//! Only the tables the kernel needs are understood. Tables are read through
+//! `TableMemory`, so they can be parsed in place from physical memory in the
+//! kernel or from fixtures in tests. Every table's checksum is validated when
+//! it is read.
+
+pub mod hpet;
+pub mod madt;
+pub mod rsdp;
+
+pub use hpet::*;
+pub use madt::*;
+pub use rsdp::*;
+
+/// Access to the physical memory holding ACPI tables.
+pub trait TableMemory {
+    /// The `len` bytes at physical address `address`, or `None` if they can't
+    /// be accessed.
+    fn read(&self, address: u64, len: usize) -> Option<&[u8]>;
+}
+
+/// The root of the table tree, as found in the RSDP.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RootTable {
+    /// ACPI 1.0 root table with 32 bit table pointers.
+    Rsdt(u64),
+    /// ACPI 2.0+ root table with 64 bit table pointers.
+    Xsdt(u64),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AcpiError {
+    /// The table at this address couldn't be read.
+    Unreadable(u64),
+    /// A table didn't have the expected signature.
+    BadSignature([u8; 4]),
+    /// A table's bytes don't sum to zero.
+    BadChecksum([u8; 4]),
+    /// A table is shorter than its contents require.
+    Truncated,
+}
+
+impl core::fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AcpiError::Unreadable(address) => write!(f, "can't read table at {address:#x}"),
+            AcpiError::BadSignature(sig) => {
+                write!(f, "unexpected signature {:?}", sig.escape_ascii())
+            }
+            AcpiError::BadChecksum(sig) => {
+                write!(f, "bad checksum in {:?} table", sig.escape_ascii())
+            }
+            AcpiError::Truncated => write!(f, "truncated table"),
+        }
+    }
+}
+
+/// Length of the header common to all system description tables.
+pub const HEADER_LEN: usize = 36;
+
+/// Read the whole table at `address` and validate its checksum.
+pub fn read_table(mem: &impl TableMemory, address: u64) -> Result<&[u8], AcpiError> {
+    let header = mem
+        .read(address, HEADER_LEN)
+        .ok_or(AcpiError::Unreadable(address))?;
+    let length = read_u32(header, 4).unwrap() as usize;
+    if length < HEADER_LEN {
+        return Err(AcpiError::Truncated);
+    }
+
+    let table = mem
+        .read(address, length)
+        .ok_or(AcpiError::Unreadable(address))?;
+    if !checksum_is_valid(table) {
+        return Err(AcpiError::BadChecksum(signature(table)));
+    }
+    Ok(table)
+}
+
+/// Find the first table with `signature` under `root`. Returns the whole
+/// table including its header.
+pub fn find_table<'a>(
+    mem: &'a impl TableMemory,
+    root: RootTable,
+    signature: &[u8; 4],
+) -> Result<Option<&'a [u8]>, AcpiError> {
+    let (root_address, root_signature, pointer_len) = match root {
+        RootTable::Rsdt(address) => (address, b"RSDT", 4),
+        RootTable::Xsdt(address) => (address, b"XSDT", 8),
+    };
+
+    let root_table = read_table(mem, root_address)?;
+    check_signature(root_table, root_signature)?;
+
+    for pointer in root_table[HEADER_LEN..].chunks_exact(pointer_len) {
+        let address = match pointer_len {
+            4 => u64::from(u32::from_le_bytes(pointer.try_into().unwrap())),
+            _ => u64::from_le_bytes(pointer.try_into().unwrap()),
+        };
+        let table = read_table(mem, address)?;
+        if table[..4] == signature[..] {
+            return Ok(Some(table));
+        }
+    }
+
+    Ok(None)
+}
+
+fn signature(table: &[u8]) -> [u8; 4] {
+    table[..4].try_into().unwrap()
+}
+
+fn check_signature(table: &[u8], expected: &[u8; 4]) -> Result<(), AcpiError> {
+    let actual = signature(table);
+    if actual == *expected {
+        Ok(())
+    } else {
+        Err(AcpiError::BadSignature(actual))
+    }
+}
+
+/// ACPI structures are valid if all their bytes sum to zero.
+fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod test_util {
+    use super::*;
+
+    use std::vec::Vec;
+
+    /// Tables from QEMU's pc machine with one CPU.
+    pub const QEMU_MADT: &[u8] = include_bytes!("acpi/testdata/qemu_madt.bin");
+    pub const QEMU_HPET: &[u8] = include_bytes!("acpi/testdata/qemu_hpet.bin");
+
+    /// Regions placed at fake physical addresses.
+    pub struct FakeMemory(pub Vec<(u64, Vec<u8>)>);
+
+    impl TableMemory for FakeMemory {
+        fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+            self.0.iter().find_map(|(base, bytes)| {
+                let offset = usize::try_from(address.checked_sub(*base)?).ok()?;
+                bytes.get(offset..offset.checked_add(len)?)
+            })
+        }
+    }
+
+    /// Fix up `bytes[checksum_offset]` so `bytes[range]` sums to zero.
+    pub fn fix_checksum(bytes: &mut [u8], range: core::ops::Range<usize>, checksum_offset: usize) {
+        bytes[checksum_offset] = 0;
+        let sum = bytes[range].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        bytes[checksum_offset] = sum.wrapping_neg();
+    }
+
+    /// A table with a valid header and checksum.
+    pub fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = Vec::new();
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&((HEADER_LEN + body.len()) as u32).to_le_bytes());
+        table.resize(HEADER_LEN, 0);
+        table.extend_from_slice(body);
+        let len = table.len();
+        fix_checksum(&mut table, 0..len, 9);
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::*;
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn find_table_through_xsdt() {
+        let mut xsdt_body = std::vec::Vec::new();
+        xsdt_body.extend_from_slice(&0x2000u64.to_le_bytes());
+        xsdt_body.extend_from_slice(&0x3000u64.to_le_bytes());
+        let mem = FakeMemory(std::vec![
+            (0x1000, table(b"XSDT", &xsdt_body)),
+            (0x2000, QEMU_HPET.to_vec()),
+            (0x3000, QEMU_MADT.to_vec()),
+        ]);
+
+        assert_eq!(
+            find_table(&mem, RootTable::Xsdt(0x1000), Madt::SIGNATURE).unwrap(),
+            Some(QEMU_MADT)
+        );
+        assert_eq!(
+            find_table(&mem, RootTable::Xsdt(0x1000), Hpet::SIGNATURE).unwrap(),
+            Some(QEMU_HPET)
+        );
+        assert_eq!(
+            find_table(&mem, RootTable::Xsdt(0x1000), b"FACP").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn find_table_through_rsdt() {
+        let mem = FakeMemory(std::vec![
+            (0x1000, table(b"RSDT", &0x2000u32.to_le_bytes())),
+            (0x2000, QEMU_MADT.to_vec()),
+        ]);
+        assert_eq!(
+            find_table(&mem, RootTable::Rsdt(0x1000), Madt::SIGNATURE).unwrap(),
+            Some(QEMU_MADT)
+        );
+        assert_eq!(
+            find_table(&mem, RootTable::Xsdt(0x1000), Madt::SIGNATURE),
+            Err(AcpiError::BadSignature(*b"RSDT"))
+        );
+    }
+
+    #[test]
+    fn bad_checksums_are_rejected() {
+        let mut madt = QEMU_MADT.to_vec();
+        madt[40] ^= 1;
+        let mem = FakeMemory(std::vec![
+            (0x1000, table(b"RSDT", &0x2000u32.to_le_bytes())),
+            (0x2000, madt),
+        ]);
+        assert_eq!(
+            find_table(&mem, RootTable::Rsdt(0x1000), Madt::SIGNATURE),
+            Err(AcpiError::BadChecksum(*b"APIC"))
+        );
+    }
+
+    #[test]
+    fn unreadable_tables_are_reported() {
+        let mem = FakeMemory(std::vec![(
+            0x1000,
+            table(b"RSDT", &0x2000u32.to_le_bytes())
+        )]);
+        assert_eq!(
+            find_table(&mem, RootTable::Rsdt(0x1000), Madt::SIGNATURE),
+            Err(AcpiError::Unreadable(0x2000))
+        );
+    }
+}
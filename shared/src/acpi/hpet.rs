@@ -0,0 +1,130 @@
+//! High Precision Event Timer description table
+
+use super::{check_signature, read_u16, read_u32, read_u64, AcpiError};
+
+/// Describes one HPET block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Hpet {
+    event_timer_block_id: u32,
+    address_space: u8,
+    base_address: u64,
+    hpet_number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+/// Address space of a generic address structure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    Other(u8),
+}
+
+impl Hpet {
+    pub const SIGNATURE: &'static [u8; 4] = b"HPET";
+
+    /// Parse an HPET table, given the whole table as returned by
+    /// `find_table`.
+    pub fn parse(table: &[u8]) -> Result<Hpet, AcpiError> {
+        check_signature(table, Self::SIGNATURE)?;
+        Ok(Hpet {
+            event_timer_block_id: read_u32(table, 36).ok_or(AcpiError::Truncated)?,
+            address_space: *table.get(40).ok_or(AcpiError::Truncated)?,
+            base_address: read_u64(table, 44).ok_or(AcpiError::Truncated)?,
+            hpet_number: *table.get(52).ok_or(AcpiError::Truncated)?,
+            min_tick: read_u16(table, 53).ok_or(AcpiError::Truncated)?,
+            page_protection: *table.get(55).ok_or(AcpiError::Truncated)?,
+        })
+    }
+
+    /// The raw event timer block ID, which mirrors the low half of the
+    /// HPET's capabilities register.
+    pub fn event_timer_block_id(&self) -> u32 {
+        self.event_timer_block_id
+    }
+
+    pub fn hardware_revision(&self) -> u8 {
+        self.event_timer_block_id as u8
+    }
+
+    /// Number of comparators in the block.
+    pub fn num_comparators(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+
+    /// Whether the main counter is 64 bits wide.
+    pub fn has_64_bit_counter(&self) -> bool {
+        self.event_timer_block_id & (1 << 13) != 0
+    }
+
+    /// Whether the block can replace the PIT and RTC interrupts.
+    pub fn legacy_replacement_capable(&self) -> bool {
+        self.event_timer_block_id & (1 << 15) != 0
+    }
+
+    pub fn pci_vendor_id(&self) -> u16 {
+        (self.event_timer_block_id >> 16) as u16
+    }
+
+    /// Where the block's registers are. Nearly always memory.
+    pub fn address_space(&self) -> AddressSpace {
+        match self.address_space {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            other => AddressSpace::Other(other),
+        }
+    }
+
+    /// The physical address of the block's registers.
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+
+    /// Which HPET block this is, starting from 0.
+    pub fn hpet_number(&self) -> u8 {
+        self.hpet_number
+    }
+
+    /// The minimum number of main counter ticks a periodic comparator can be
+    /// set to without losing interrupts.
+    pub fn min_tick(&self) -> u16 {
+        self.min_tick
+    }
+
+    /// Page protection and OEM attributes of the register block.
+    pub fn page_protection(&self) -> u8 {
+        self.page_protection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::super::HEADER_LEN;
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn qemu_hpet() {
+        let hpet = Hpet::parse(QEMU_HPET).unwrap();
+        assert_eq!(hpet.event_timer_block_id(), 0x8086_a201);
+        assert_eq!(hpet.hardware_revision(), 1);
+        assert_eq!(hpet.num_comparators(), 3);
+        assert!(hpet.has_64_bit_counter());
+        assert!(hpet.legacy_replacement_capable());
+        assert_eq!(hpet.pci_vendor_id(), 0x8086);
+        assert_eq!(hpet.address_space(), AddressSpace::Memory);
+        assert_eq!(hpet.base_address(), 0xfed0_0000);
+        assert_eq!(hpet.hpet_number(), 0);
+        assert_eq!(hpet.min_tick(), 0x80);
+        assert_eq!(hpet.page_protection(), 0);
+    }
+
+    #[test]
+    fn truncated_hpet() {
+        let table = table(b"HPET", &QEMU_HPET[HEADER_LEN..QEMU_HPET.len() - 1]);
+        assert_eq!(Hpet::parse(&table), Err(AcpiError::Truncated));
+    }
+}
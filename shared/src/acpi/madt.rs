@@ -0,0 +1,306 @@
+//! Multiple APIC Description Table
+
+use super::{check_signature, read_u16, read_u32, read_u64, AcpiError, HEADER_LEN};
+
+/// The Multiple APIC Description Table, describing the machine's interrupt
+/// controllers.
+#[derive(Clone, Copy, Debug)]
+pub struct Madt<'a> {
+    local_apic_address: u32,
+    flags: u32,
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    /// Parse a MADT, given the whole table as returned by `find_table`.
+    pub fn parse(table: &'a [u8]) -> Result<Madt<'a>, AcpiError> {
+        check_signature(table, Self::SIGNATURE)?;
+        Ok(Madt {
+            local_apic_address: read_u32(table, HEADER_LEN).ok_or(AcpiError::Truncated)?,
+            flags: read_u32(table, HEADER_LEN + 4).ok_or(AcpiError::Truncated)?,
+            entries: &table[HEADER_LEN + 8..],
+        })
+    }
+
+    /// The physical address of each CPU's local APIC registers.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(u64::from(self.local_apic_address))
+    }
+
+    /// Whether the machine also has legacy 8259 PICs, which must be disabled
+    /// to use the APICs.
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// The APIC IDs of the processors that are enabled or can be brought
+    /// online, in table order.
+    pub fn processor_apic_ids(&self) -> impl Iterator<Item = u32> + 'a {
+        const ENABLED: u32 = 1 << 0;
+        const ONLINE_CAPABLE: u32 = 1 << 1;
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. }
+                if flags & (ENABLED | ONLINE_CAPABLE) != 0 =>
+            {
+                Some(u32::from(apic_id))
+            }
+            MadtEntry::LocalX2Apic {
+                x2apic_id, flags, ..
+            } if flags & (ENABLED | ONLINE_CAPABLE) != 0 => Some(x2apic_id),
+            _ => None,
+        })
+    }
+
+    pub fn entries(&self) -> MadtEntries<'a> {
+        MadtEntries {
+            remaining: self.entries,
+        }
+    }
+}
+
+/// An interrupt controller structure in the MADT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    /// An ISA IRQ that isn't identity mapped to a global system interrupt, or
+    /// that doesn't have ISA's usual polarity and trigger mode.
+    InterruptSourceOverride {
+        source: u8,
+        gsi: u32,
+        flags: InterruptFlags,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    /// An entry type we don't interpret.
+    Other {
+        kind: u8,
+    },
+}
+
+pub struct MadtEntries<'a> {
+    remaining: &'a [u8],
+}
+
+impl Iterator for MadtEntries<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        let kind = *self.remaining.first()?;
+        let len = *self.remaining.get(1)? as usize;
+        if len < 2 || len > self.remaining.len() {
+            // Malformed. Stop rather than misinterpret the rest.
+            self.remaining = &[];
+            return None;
+        }
+
+        let entry = &self.remaining[..len];
+        self.remaining = &self.remaining[len..];
+
+        let parsed = match kind {
+            0 => MadtEntry::LocalApic {
+                processor_id: *entry.get(2)?,
+                apic_id: *entry.get(3)?,
+                flags: read_u32(entry, 4)?,
+            },
+            1 => MadtEntry::IoApic {
+                id: *entry.get(2)?,
+                address: read_u32(entry, 4)?,
+                gsi_base: read_u32(entry, 8)?,
+            },
+            2 => MadtEntry::InterruptSourceOverride {
+                source: *entry.get(3)?,
+                gsi: read_u32(entry, 4)?,
+                flags: InterruptFlags(read_u16(entry, 8)?),
+            },
+            5 => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(entry, 4)?,
+            },
+            9 => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(entry, 4)?,
+                flags: read_u32(entry, 8)?,
+                processor_uid: read_u32(entry, 12)?,
+            },
+            kind => MadtEntry::Other { kind },
+        };
+        Some(parsed)
+    }
+}
+
+/// Polarity and trigger mode of an interrupt source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InterruptFlags(pub u16);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    /// Whatever the bus specifies. Active high for ISA.
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TriggerMode {
+    /// Whatever the bus specifies. Edge triggered for ISA.
+    Conforming,
+    Edge,
+    Level,
+}
+
+impl InterruptFlags {
+    pub fn polarity(self) -> Polarity {
+        match self.0 & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::Conforming,
+        }
+    }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Conforming,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::*;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn qemu_madt() {
+        let madt = Madt::parse(QEMU_MADT).unwrap();
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        assert!(madt.has_legacy_pics());
+
+        let level_high = InterruptFlags(0x0d);
+        assert_eq!(
+            madt.entries().collect::<Vec<_>>(),
+            [
+                MadtEntry::LocalApic {
+                    processor_id: 0,
+                    apic_id: 0,
+                    flags: 1
+                },
+                MadtEntry::IoApic {
+                    id: 0,
+                    address: 0xfec0_0000,
+                    gsi_base: 0
+                },
+                MadtEntry::InterruptSourceOverride {
+                    source: 0,
+                    gsi: 2,
+                    flags: InterruptFlags(0)
+                },
+                MadtEntry::InterruptSourceOverride {
+                    source: 5,
+                    gsi: 5,
+                    flags: level_high
+                },
+                MadtEntry::InterruptSourceOverride {
+                    source: 9,
+                    gsi: 9,
+                    flags: level_high
+                },
+                MadtEntry::InterruptSourceOverride {
+                    source: 10,
+                    gsi: 10,
+                    flags: level_high
+                },
+                MadtEntry::InterruptSourceOverride {
+                    source: 11,
+                    gsi: 11,
+                    flags: level_high
+                },
+                MadtEntry::Other { kind: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn local_apic_address_override() {
+        let mut body = QEMU_MADT[HEADER_LEN..].to_vec();
+        body.extend_from_slice(&[5, 12, 0, 0]);
+        body.extend_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        let table = table(b"APIC", &body);
+        let madt = Madt::parse(&table).unwrap();
+        assert_eq!(madt.local_apic_address(), 0x1_0000_0000);
+    }
+
+    #[test]
+    fn processor_apic_ids() {
+        let madt = Madt::parse(QEMU_MADT).unwrap();
+        assert_eq!(madt.processor_apic_ids().collect::<Vec<_>>(), [0]);
+
+        let mut body = QEMU_MADT[HEADER_LEN..].to_vec();
+        // Disabled, online capable, and enabled local APICs.
+        body.extend_from_slice(&[0, 8, 1, 1, 0, 0, 0, 0]);
+        body.extend_from_slice(&[0, 8, 2, 2, 2, 0, 0, 0]);
+        body.extend_from_slice(&[0, 8, 3, 3, 1, 0, 0, 0]);
+        // An enabled x2APIC.
+        body.extend_from_slice(&[9, 16, 0, 0]);
+        body.extend_from_slice(&0x100u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&4u32.to_le_bytes());
+        let table = table(b"APIC", &body);
+        let madt = Madt::parse(&table).unwrap();
+        assert_eq!(
+            madt.processor_apic_ids().collect::<Vec<_>>(),
+            [0, 2, 3, 0x100]
+        );
+    }
+
+    #[test]
+    fn interrupt_flags() {
+        assert_eq!(InterruptFlags(0).polarity(), Polarity::Conforming);
+        assert_eq!(InterruptFlags(0).trigger_mode(), TriggerMode::Conforming);
+        assert_eq!(InterruptFlags(0x0d).polarity(), Polarity::ActiveHigh);
+        assert_eq!(InterruptFlags(0x0d).trigger_mode(), TriggerMode::Level);
+        assert_eq!(InterruptFlags(0x07).polarity(), Polarity::ActiveLow);
+        assert_eq!(InterruptFlags(0x07).trigger_mode(), TriggerMode::Edge);
+    }
+
+    #[test]
+    fn malformed_entries_stop_iteration() {
+        // Keep the local APIC entry and half of the IOAPIC entry.
+        let table = table(b"APIC", &QEMU_MADT[HEADER_LEN..HEADER_LEN + 8 + 8 + 4]);
+        let madt = Madt::parse(&table).unwrap();
+        assert_eq!(madt.entries().count(), 1);
+    }
+
+    #[test]
+    fn wrong_signature() {
+        assert_eq!(
+            Madt::parse(QEMU_HPET).map(|_| ()),
+            Err(AcpiError::BadSignature(*b"HPET"))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Root System Description Pointer
+
+use super::{checksum_is_valid, read_u32, read_u64, AcpiError, RootTable, TableMemory};
+
+/// The RSDP, which points to the root table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    rsdt_address: u32,
+    xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+    /// Length of the ACPI 1.0 structure.
+    pub const V1_LEN: usize = 20;
+
+    /// Length of the ACPI 2.0+ structure.
+    pub const V2_LEN: usize = 36;
+
+    /// Parse an RSDP, such as one passed by the bootloader. `bytes` may be
+    /// longer than the structure.
+    pub fn parse(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+        let v1 = bytes.get(..Self::V1_LEN).ok_or(AcpiError::Truncated)?;
+        let signature: [u8; 4] = v1[..4].try_into().unwrap();
+        if v1[..8] != Self::SIGNATURE[..] {
+            return Err(AcpiError::BadSignature(signature));
+        }
+        if !checksum_is_valid(v1) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+
+        let revision = v1[15];
+        let xsdt_address = if revision >= 2 {
+            let length = read_u32(bytes, 20).ok_or(AcpiError::Truncated)? as usize;
+            let v2 = bytes
+                .get(..length.max(Self::V2_LEN))
+                .ok_or(AcpiError::Truncated)?;
+            if !checksum_is_valid(v2) {
+                return Err(AcpiError::BadChecksum(signature));
+            }
+            Some(read_u64(v2, 24).unwrap()).filter(|&address| address != 0)
+        } else {
+            None
+        };
+
+        Ok(Rsdp {
+            revision,
+            oem_id: v1[9..15].try_into().unwrap(),
+            rsdt_address: read_u32(v1, 16).unwrap(),
+            xsdt_address,
+        })
+    }
+
+    /// 0 for ACPI 1.0, 2 for later versions.
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn oem_id(&self) -> &[u8; 6] {
+        &self.oem_id
+    }
+
+    /// The XSDT if there is one, and the RSDT otherwise.
+    pub fn root_table(&self) -> RootTable {
+        match self.xsdt_address {
+            Some(address) => RootTable::Xsdt(address),
+            None => RootTable::Rsdt(self.rsdt_address.into()),
+        }
+    }
+}
+
+/// Where the BIOS data area stores the EBDA's segment.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+
+/// The RSDP is in the EBDA's first KiB.
+const EBDA_SEARCH_LEN: usize = 1024;
+
+/// Or in the BIOS read-only memory area.
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+/// Search for the RSDP on a legacy BIOS machine. UEFI machines don't place
+/// it in these areas; there it must come from the bootloader.
+pub fn find_rsdp(mem: &impl TableMemory) -> Option<Rsdp> {
+    let ebda = mem
+        .read(EBDA_SEGMENT_POINTER, 2)
+        .map(|segment| u64::from(u16::from_le_bytes(segment.try_into().unwrap())) << 4)
+        .filter(|&address| address != 0);
+
+    let areas = [
+        ebda.map(|address| (address, EBDA_SEARCH_LEN)),
+        Some((BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize)),
+    ];
+
+    areas
+        .into_iter()
+        .flatten()
+        .filter_map(|(address, len)| mem.read(address, len))
+        .find_map(search_area)
+}
+
+/// The RSDP is on a 16 byte boundary.
+fn search_area(area: &[u8]) -> Option<Rsdp> {
+    (0..area.len())
+        .step_by(16)
+        .filter(|&offset| area[offset..].starts_with(Rsdp::SIGNATURE))
+        .find_map(|offset| Rsdp::parse(&area[offset..]).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::*;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    fn rsdp_v1(rsdt: u32) -> Vec<u8> {
+        let mut rsdp = Vec::new();
+        rsdp.extend_from_slice(Rsdp::SIGNATURE);
+        rsdp.push(0);
+        rsdp.extend_from_slice(b"BOCHS ");
+        rsdp.push(0);
+        rsdp.extend_from_slice(&rsdt.to_le_bytes());
+        fix_checksum(&mut rsdp, 0..20, 8);
+        rsdp
+    }
+
+    fn rsdp_v2(rsdt: u32, xsdt: u64) -> Vec<u8> {
+        let mut rsdp = rsdp_v1(rsdt);
+        rsdp[15] = 2;
+        rsdp.extend_from_slice(&36u32.to_le_bytes());
+        rsdp.extend_from_slice(&xsdt.to_le_bytes());
+        rsdp.extend_from_slice(&[0; 4]);
+        fix_checksum(&mut rsdp, 0..20, 8);
+        fix_checksum(&mut rsdp, 0..36, 32);
+        rsdp
+    }
+
+    #[test]
+    fn parse_v1() {
+        let rsdp = Rsdp::parse(&rsdp_v1(0x7fe_14a0)).unwrap();
+        assert_eq!(rsdp.revision(), 0);
+        assert_eq!(rsdp.oem_id(), b"BOCHS ");
+        assert_eq!(rsdp.root_table(), RootTable::Rsdt(0x7fe_14a0));
+    }
+
+    #[test]
+    fn parse_v2() {
+        let rsdp = Rsdp::parse(&rsdp_v2(0x7fe_14a0, 0x7fe_1500)).unwrap();
+        assert_eq!(rsdp.revision(), 2);
+        assert_eq!(rsdp.root_table(), RootTable::Xsdt(0x7fe_1500));
+
+        // Some firmware leaves the XSDT address zero.
+        let rsdp = Rsdp::parse(&rsdp_v2(0x7fe_14a0, 0)).unwrap();
+        assert_eq!(rsdp.root_table(), RootTable::Rsdt(0x7fe_14a0));
+    }
+
+    #[test]
+    fn bad_rsdps() {
+        let mut rsdp = rsdp_v1(0x1000);
+        rsdp[16] ^= 1;
+        assert_eq!(Rsdp::parse(&rsdp), Err(AcpiError::BadChecksum(*b"RSD ")));
+
+        let mut rsdp = rsdp_v2(0x1000, 0x2000);
+        rsdp[24] ^= 1;
+        assert_eq!(Rsdp::parse(&rsdp), Err(AcpiError::BadChecksum(*b"RSD ")));
+
+        let rsdp = rsdp_v2(0x1000, 0x2000);
+        assert_eq!(Rsdp::parse(&rsdp[..30]), Err(AcpiError::Truncated));
+
+        let mut rsdp = rsdp_v1(0x1000);
+        rsdp[0] = b'X';
+        assert_eq!(Rsdp::parse(&rsdp), Err(AcpiError::BadSignature(*b"XSD ")));
+    }
+
+    #[test]
+    fn find_rsdp_in_bios_area() {
+        let mut area = std::vec![0; (BIOS_AREA_END - BIOS_AREA_START) as usize];
+        // A stray signature with a bad checksum is skipped.
+        let mut bad = rsdp_v1(0x1000);
+        bad[8] ^= 1;
+        area[0x10..0x10 + 20].copy_from_slice(&bad);
+        area[0xf5a0..0xf5a0 + 36].copy_from_slice(&rsdp_v2(0x1000, 0x2000));
+
+        let mem = FakeMemory(std::vec![(BIOS_AREA_START, area)]);
+        assert_eq!(
+            find_rsdp(&mem).map(|rsdp| rsdp.root_table()),
+            Some(RootTable::Xsdt(0x2000))
+        );
+    }
+
+    #[test]
+    fn find_rsdp_in_ebda() {
+        let mut low = std::vec![0; 0x1000];
+        low[0x40e..0x410].copy_from_slice(&0x9fc0u16.to_le_bytes());
+        let mut ebda = std::vec![0; EBDA_SEARCH_LEN];
+        ebda[0x20..0x20 + 20].copy_from_slice(&rsdp_v1(0x3000));
+
+        let mem = FakeMemory(std::vec![(0, low), (0x9_fc00, ebda)]);
+        assert_eq!(
+            find_rsdp(&mem).map(|rsdp| rsdp.root_table()),
+            Some(RootTable::Rsdt(0x3000))
+        );
+    }
+
+    #[test]
+    fn no_rsdp() {
+        let mem = FakeMemory(std::vec![(
+            BIOS_AREA_START,
+            std::vec![0; (BIOS_AREA_END - BIOS_AREA_START) as usize]
+        )]);
+        assert_eq!(find_rsdp(&mem), None);
+    }
+}
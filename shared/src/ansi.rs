@@ -0,0 +1,227 @@
+//! ANSI escape sequence parsing for the text consoles
+//!
+//! `Parser` separates characters to display from CSI sequences (`ESC [ ...`),
+//! which the console then acts on. Other escape sequences are skipped. Only
+//! the first `MAX_PARAMS` parameters of a sequence are kept.
+
+/// The most parameters kept from one escape sequence. Extra ones are ignored.
+pub const MAX_PARAMS: usize = 4;
+
+/// The result of feeding a character to a `Parser`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// The character is part of an unfinished escape sequence.
+    None,
+    /// Display the character, or act on it if it is a control character.
+    Char(char),
+    /// A CSI sequence ending in `command`.
+    Csi { command: char, params: Params },
+}
+
+/// The numeric parameters of a CSI sequence.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    /// May exceed `MAX_PARAMS`.
+    count: usize,
+}
+
+impl Params {
+    pub fn as_slice(&self) -> &[u16] {
+        &self.values[..self.count.min(MAX_PARAMS)]
+    }
+
+    /// The `i`th parameter, or `default` if it is missing or 0.
+    pub fn get(&self, i: usize, default: u16) -> u16 {
+        match self.as_slice().get(i) {
+            Some(&p) if p != 0 => p,
+            _ => default,
+        }
+    }
+
+    /// Interpret the parameters of an SGR (`ESC [ ... m`) sequence.
+    pub fn sgr(&self) -> impl Iterator<Item = Sgr> + '_ {
+        // No parameters means reset.
+        let reset = self.count == 0;
+        core::iter::once(Sgr::Reset)
+            .filter(move |_| reset)
+            .chain(self.as_slice().iter().filter_map(|&p| Sgr::from_param(p)))
+    }
+}
+
+/// A supported SGR parameter. Colors are numbered 0-7 as in `ESC [ 3n m`, and
+/// 8-15 for the bright versions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sgr {
+    Reset,
+    Bold(bool),
+    /// `None` selects the default color.
+    Foreground(Option<u8>),
+    Background(Option<u8>),
+}
+
+impl Sgr {
+    fn from_param(p: u16) -> Option<Sgr> {
+        Some(match p {
+            0 => Sgr::Reset,
+            1 => Sgr::Bold(true),
+            22 => Sgr::Bold(false),
+            30..=37 => Sgr::Foreground(Some((p - 30) as u8)),
+            39 => Sgr::Foreground(None),
+            40..=47 => Sgr::Background(Some((p - 40) as u8)),
+            49 => Sgr::Background(None),
+            90..=97 => Sgr::Foreground(Some((p - 90) as u8 + 8)),
+            100..=107 => Sgr::Background(Some((p - 100) as u8 + 8)),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Ground,
+    /// After ESC.
+    Escape,
+    /// After `ESC [`, collecting parameters.
+    Csi(Params),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Parser {
+    state: State,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+        }
+    }
+
+    pub fn feed(&mut self, c: char) -> Action {
+        match self.state {
+            State::Ground => {
+                if c == '\x1b' {
+                    self.state = State::Escape;
+                    Action::None
+                } else {
+                    Action::Char(c)
+                }
+            }
+            State::Escape => {
+                self.state = if c == '[' {
+                    State::Csi(Params::default())
+                } else {
+                    State::Ground
+                };
+                Action::None
+            }
+            State::Csi(mut params) => match c {
+                '0'..='9' => {
+                    // The first digit starts the first parameter.
+                    params.count = params.count.max(1);
+                    if let Some(p) = params.values.get_mut(params.count - 1) {
+                        *p = p.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    self.state = State::Csi(params);
+                    Action::None
+                }
+                // Private parameter markers, as in `ESC [ ? 25 l`.
+                '?' | '<' | '=' | '>' => Action::None,
+                ';' => {
+                    params.count = params.count.max(1) + 1;
+                    self.state = State::Csi(params);
+                    Action::None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    Action::Csi { command: c, params }
+                }
+            },
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    fn parse(s: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        s.chars()
+            .map(|c| parser.feed(c))
+            .filter(|a| *a != Action::None)
+            .collect()
+    }
+
+    fn csi(s: &str) -> (char, Vec<u16>) {
+        match parse(s)[..] {
+            [Action::Csi { command, params }] => (command, params.as_slice().into()),
+            ref actions => panic!("{actions:?}"),
+        }
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(csi("\x1b[H"), ('H', vec![]));
+        assert_eq!(csi("\x1b[12;34H"), ('H', vec![12, 34]));
+        assert_eq!(csi("\x1b[;5H"), ('H', vec![0, 5]));
+        assert_eq!(csi("\x1b[?25l"), ('l', vec![25]));
+        assert_eq!(csi("\x1b[1;2;3;4;5;6m"), ('m', vec![1, 2, 3, 4]));
+        assert_eq!(csi("\x1b[99999999m"), ('m', vec![u16::MAX]));
+    }
+
+    #[test]
+    fn text_between_escapes() {
+        assert_eq!(
+            parse("a\x1b7b\x1b[Kc\n"),
+            vec![
+                Action::Char('a'),
+                Action::Char('b'),
+                Action::Csi {
+                    command: 'K',
+                    params: Params::default()
+                },
+                Action::Char('c'),
+                Action::Char('\n'),
+            ]
+        );
+    }
+
+    #[test]
+    fn sgr() {
+        let sgr = |s: &str| match parse(s)[..] {
+            [Action::Csi { params, .. }] => params.sgr().collect::<Vec<_>>(),
+            ref actions => panic!("{actions:?}"),
+        };
+        assert_eq!(sgr("\x1b[m"), vec![Sgr::Reset]);
+        assert_eq!(
+            sgr("\x1b[0;1;31;44m"),
+            vec![
+                Sgr::Reset,
+                Sgr::Bold(true),
+                Sgr::Foreground(Some(1)),
+                Sgr::Background(Some(4))
+            ]
+        );
+        assert_eq!(
+            sgr("\x1b[93;101;39;5m"),
+            vec![
+                Sgr::Foreground(Some(11)),
+                Sgr::Background(Some(9)),
+                Sgr::Foreground(None)
+            ]
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Boot metrics and regression checks
+//!
+//! The kernel writes a line for each metric it measures while booting, like
+//! how long each phase took or how many frames are in use once init starts,
+//! e.g. `boot-metric: phase.mm 1234`. The host runner picks the lines out of
+//! the kernel's output with `parse_all` and compares them with a baseline
+//! saved from an earlier boot. Baselines are stored in the same format, so a
+//! boot log can serve as one.
+//!
+//! Every metric is a count or a duration where smaller is better. A metric
+//! regresses when it grows by more than its threshold, a percentage of the
+//! baseline value.
+
+use core::fmt;
+
+#[cfg(feature = "alloc")]
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// Starts each metric line. The line may have a prefix, e.g. from the logger.
+pub const PREFIX: &str = "boot-metric:";
+
+/// A measurement from one boot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Metric<'a> {
+    /// Dot separated, without whitespace, e.g. `heap.bytes`.
+    pub name: &'a str,
+    pub value: u64,
+}
+
+impl<'a> Metric<'a> {
+    /// Parse a line with a metric formatted with `Display` somewhere in it.
+    pub fn parse(line: &'a str) -> Option<Metric<'a>> {
+        let (_, rest) = line.split_once(PREFIX)?;
+        let mut words = rest.split_whitespace();
+        let name = words.next()?;
+        let value = words.next()?.parse().ok()?;
+        if words.next().is_some() {
+            return None;
+        }
+        Some(Metric { name, value })
+    }
+}
+
+impl fmt::Display for Metric<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX} {} {}", self.name, self.value)
+    }
+}
+
+/// The metrics in `text` by name. A metric reported more than once keeps its
+/// last value, so a log spanning several boots gives the last one's.
+#[cfg(feature = "alloc")]
+pub fn parse_all(text: &str) -> BTreeMap<String, u64> {
+    text.lines()
+        .filter_map(Metric::parse)
+        .map(|metric| (metric.name.into(), metric.value))
+        .collect()
+}
+
+/// How much each metric may grow before it counts as a regression.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Thresholds {
+    /// Percent of the baseline for metrics without an override.
+    pub default_percent: u64,
+    /// Name prefixes and their thresholds in percent, e.g. `phase.` for all
+    /// the phase timings. The longest matching prefix applies.
+    pub overrides: Vec<(String, u64)>,
+}
+
+#[cfg(feature = "alloc")]
+impl Thresholds {
+    /// The threshold for `name`, in percent.
+    pub fn percent(&self, name: &str) -> u64 {
+        self.overrides
+            .iter()
+            .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default_percent, |&(_, percent)| percent)
+    }
+
+    /// The largest value of `name` that doesn't regress from `baseline`.
+    pub fn limit(&self, name: &str, baseline: u64) -> u64 {
+        let allowed = u128::from(baseline) * u128::from(self.percent(name)) / 100;
+        baseline.saturating_add(u64::try_from(allowed).unwrap_or(u64::MAX))
+    }
+}
+
+/// A metric that grew past its threshold, or is missing.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Regression {
+    pub name: String,
+    pub baseline: u64,
+    /// `None` if the boot didn't report the metric.
+    pub current: Option<u64>,
+    /// The largest value that wouldn't have regressed.
+    pub limit: u64,
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.current {
+            Some(current) => write!(
+                f,
+                "{} is {current}, up from {} (limit {})",
+                self.name, self.baseline, self.limit
+            ),
+            None => write!(f, "{} is missing, was {}", self.name, self.baseline),
+        }
+    }
+}
+
+/// The metrics in `baseline` that regressed in `current`, by name. Metrics
+/// only in `current` are new, and aren't regressions.
+#[cfg(feature = "alloc")]
+pub fn compare(
+    baseline: &BTreeMap<String, u64>,
+    current: &BTreeMap<String, u64>,
+    thresholds: &Thresholds,
+) -> Vec<Regression> {
+    baseline
+        .iter()
+        .filter_map(|(name, &baseline)| {
+            let limit = thresholds.limit(name, baseline);
+            let current = current.get(name).copied();
+            match current {
+                Some(current) if current <= limit => None,
+                _ => Some(Regression {
+                    name: name.clone(),
+                    baseline,
+                    current,
+                    limit,
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::ToString;
+    use std::vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn metrics(pairs: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    fn thresholds(default_percent: u64, overrides: &[(&str, u64)]) -> Thresholds {
+        Thresholds {
+            default_percent,
+            overrides: overrides
+                .iter()
+                .map(|&(prefix, percent)| (prefix.to_string(), percent))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn format() {
+        let metric = Metric {
+            name: "heap.bytes",
+            value: 4096,
+        };
+        assert_eq!(metric.to_string(), "boot-metric: heap.bytes 4096");
+        assert_eq!(
+            Metric::parse("[INFO kernel] boot-metric: heap.bytes 4096"),
+            Some(metric)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        assert_eq!(Metric::parse(""), None);
+        assert_eq!(Metric::parse("heap.bytes 4096"), None);
+        assert_eq!(Metric::parse("boot-metric: heap.bytes"), None);
+        assert_eq!(Metric::parse("boot-metric: heap.bytes -1"), None);
+        assert_eq!(Metric::parse("boot-metric: heap.bytes 1 2"), None);
+    }
+
+    #[test]
+    fn last_value_wins() {
+        let text = "boot-metric: ticks 5\nother output\nboot-metric: ticks 7\n";
+        assert_eq!(parse_all(text), metrics(&[("ticks", 7)]));
+    }
+
+    #[test]
+    fn longest_prefix_applies() {
+        let thresholds = thresholds(10, &[("phase.", 50), ("phase.smp", 100)]);
+        assert_eq!(thresholds.percent("heap.bytes"), 10);
+        assert_eq!(thresholds.percent("phase.mm"), 50);
+        assert_eq!(thresholds.percent("phase.smp"), 100);
+        assert_eq!(thresholds.limit("phase.mm", 1000), 1500);
+    }
+
+    #[test]
+    fn finds_regressions() {
+        let baseline = metrics(&[("frames.used", 100), ("heap.bytes", 1000), ("ticks", 10)]);
+        let current = metrics(&[("frames.used", 110), ("heap.bytes", 1101), ("new", 1)]);
+        assert_eq!(
+            compare(&baseline, &current, &thresholds(10, &[])),
+            vec![
+                Regression {
+                    name: "heap.bytes".to_string(),
+                    baseline: 1000,
+                    current: Some(1101),
+                    limit: 1100,
+                },
+                Regression {
+                    name: "ticks".to_string(),
+                    baseline: 10,
+                    current: None,
+                    limit: 11,
+                },
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(name in "[a-z][a-z._]{0,20}", value: u64) {
+            let line = Metric { name: &name, value }.to_string();
+            prop_assert_eq!(Metric::parse(&line), Some(Metric { name: &name, value }));
+        }
+
+        #[test]
+        fn same_metrics_never_regress(
+            values in proptest::collection::btree_map("[a-z.]{1,10}", any::<u64>(), 0..10),
+            percent in 0..200u64,
+        ) {
+            prop_assert_eq!(compare(&values, &values, &thresholds(percent, &[])), vec![]);
+        }
+    }
+}
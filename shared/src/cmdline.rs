@@ -0,0 +1,509 @@
+//! Kernel command line parsing
+//!
+//! The command line is a whitespace separated list of `key=value` options
+//! and bare flags.
+//! Unknown options are ignored so that other components (e.g. the boot
+//! loader) can share the same command line.
+
+use testos_memory::{PhysAddress, PhysExtent};
+
+use arrayvec::ArrayVec;
+use log::{warn, LevelFilter};
+
+/// The most `reserve=` options honored.
+pub const MAX_RESERVED: usize = 8;
+
+/// The most `log.<target>=` options honored.
+pub const MAX_LOG_TARGETS: usize = 8;
+
+/// Options that restrict which physical memory the kernel may use. Mainly
+/// useful to reproduce low-memory conditions for testing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryOptions {
+    /// `mem=<size>`: ignore available memory at and above this address.
+    pub limit: Option<PhysAddress>,
+
+    /// `reserve=<start>+<length>`: never use these extents. Sorted by address
+    /// and non-overlapping.
+    pub reserved: ArrayVec<PhysExtent, MAX_RESERVED>,
+}
+
+impl MemoryOptions {
+    /// Collect memory options from `cmdline`. Malformed options are logged
+    /// and ignored.
+    pub fn parse(cmdline: &str) -> MemoryOptions {
+        let mut options = MemoryOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            match key {
+                "mem" => match parse_size(value) {
+                    Some(size) => options.limit = Some(PhysAddress::from_raw(size)),
+                    None => warn!("ignoring malformed option mem={value}"),
+                },
+                "reserve" => match parse_extent(value) {
+                    Some(extent) => {
+                        if options.reserved.try_push(extent).is_err() {
+                            warn!("ignoring reserve={value}: too many reserved extents");
+                        }
+                    }
+                    None => warn!("ignoring malformed option reserve={value}"),
+                },
+                _ => (),
+            }
+        }
+
+        options.reserved.sort_unstable_by_key(|e| e.address());
+        options.reserved = merge_overlapping(&options.reserved);
+        options
+    }
+}
+
+/// Heap debugging options.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeapOptions {
+    /// `heap.quarantine=<n>`: hold the last `n` freed heap blocks before
+    /// reusing them, to catch use after free.
+    pub quarantine: Option<usize>,
+}
+
+impl HeapOptions {
+    /// Collect heap options from `cmdline`. Malformed options are logged and
+    /// ignored.
+    pub fn parse(cmdline: &str) -> HeapOptions {
+        let mut options = HeapOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key == "heap.quarantine" {
+                match value.parse() {
+                    Ok(len) => options.quarantine = Some(len),
+                    Err(_) => warn!("ignoring malformed option {key}={value}"),
+                }
+            }
+        }
+
+        options
+    }
+}
+
+/// A/B boot slots: GRUB boots a trial kernel a few times, then falls back to
+/// a known-good one if the trial kernel never confirms it booted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BootSlot {
+    Trial,
+    Good,
+}
+
+/// Boot slot options, set by GRUB on slotted images.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BootSlotOptions {
+    /// `boot_slot=trial` or `boot_slot=good`: which slot was booted.
+    pub slot: Option<BootSlot>,
+}
+
+impl BootSlotOptions {
+    /// Collect boot slot options from `cmdline`. Malformed options are logged
+    /// and ignored.
+    pub fn parse(cmdline: &str) -> BootSlotOptions {
+        let mut options = BootSlotOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key != "boot_slot" {
+                continue;
+            }
+            options.slot = match value {
+                "trial" => Some(BootSlot::Trial),
+                "good" => Some(BootSlot::Good),
+                _ => {
+                    warn!("ignoring malformed option {key}={value}");
+                    continue;
+                }
+            };
+        }
+
+        options
+    }
+}
+
+/// What to do after a panic has been reported.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PanicAction {
+    /// Halt the CPU.
+    #[default]
+    Halt,
+    /// Reset the machine after this many seconds.
+    Reboot { delay_secs: u64 },
+    /// Blink an indicator forever, so a panicked machine stands out.
+    Blink,
+}
+
+/// Panic handling options.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PanicOptions {
+    /// `panic=halt`, `panic=blink`, or `panic=<seconds>` to reboot after that
+    /// many seconds, as on Linux.
+    pub action: PanicAction,
+}
+
+impl PanicOptions {
+    /// Collect panic options from `cmdline`. Malformed options are logged and
+    /// ignored.
+    pub fn parse(cmdline: &str) -> PanicOptions {
+        let mut options = PanicOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key != "panic" {
+                continue;
+            }
+            options.action = match value {
+                "halt" => PanicAction::Halt,
+                "blink" => PanicAction::Blink,
+                _ => match value.parse() {
+                    Ok(delay_secs) => PanicAction::Reboot { delay_secs },
+                    Err(_) => {
+                        warn!("ignoring malformed option {key}={value}");
+                        continue;
+                    }
+                },
+            };
+        }
+
+        options
+    }
+}
+
+/// Test options.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TestOptions {
+    /// `test_mode`: run the in-kernel tests instead of init, then exit QEMU.
+    pub test_mode: bool,
+}
+
+impl TestOptions {
+    /// Collect test options from `cmdline`.
+    pub fn parse(cmdline: &str) -> TestOptions {
+        TestOptions {
+            test_mode: cmdline.split_whitespace().any(|opt| opt == "test_mode"),
+        }
+    }
+}
+
+/// Options for the first process.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InitOptions<'a> {
+    /// `init=<path>`: load init from this path on the boot disk instead of
+    /// from its multiboot module. The module's command line still gives its
+    /// arguments.
+    pub path: Option<&'a str>,
+}
+
+impl<'a> InitOptions<'a> {
+    /// Collect init options from `cmdline`. The last `init=` wins.
+    pub fn parse(cmdline: &'a str) -> InitOptions<'a> {
+        let mut options = InitOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key == "init" {
+                if value.is_empty() {
+                    warn!("ignoring empty option init=");
+                } else {
+                    options.path = Some(value);
+                }
+            }
+        }
+
+        options
+    }
+}
+
+/// Lockup detector options.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WatchdogOptions {
+    /// `watchdog=<seconds>`: how long a CPU may hold or wait for a lock
+    /// without switching tasks before the kernel panics. `watchdog=0` turns
+    /// the check off.
+    pub timeout_secs: Option<u64>,
+}
+
+impl WatchdogOptions {
+    /// Collect watchdog options from `cmdline`. Malformed options are logged
+    /// and ignored.
+    pub fn parse(cmdline: &str) -> WatchdogOptions {
+        let mut options = WatchdogOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key == "watchdog" {
+                match value.parse() {
+                    Ok(secs) => options.timeout_secs = Some(secs),
+                    Err(_) => warn!("ignoring malformed option {key}={value}"),
+                }
+            }
+        }
+
+        options
+    }
+}
+
+/// Log levels to start with.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LogOptions<'a> {
+    /// `log=<level>`: the level for targets without their own.
+    pub default: Option<LevelFilter>,
+
+    /// `log.<target>=<level>`: levels for individual targets, in command line
+    /// order.
+    pub targets: ArrayVec<(&'a str, LevelFilter), MAX_LOG_TARGETS>,
+}
+
+impl<'a> LogOptions<'a> {
+    /// Collect log options from `cmdline`. Levels are names as accepted by
+    /// `LevelFilter::from_str`, e.g. `debug` or `off`. `log=` also takes a
+    /// comma separated list of a default level and `<target>=<level>` pairs,
+    /// e.g. `log=warn,mm=debug,sched=off`. Malformed options are logged and
+    /// ignored.
+    pub fn parse(cmdline: &'a str) -> LogOptions<'a> {
+        let mut options = LogOptions::default();
+
+        for (key, value) in cmdline
+            .split_whitespace()
+            .filter_map(|opt| opt.split_once('='))
+        {
+            if key == "log" {
+                for item in value.split(',') {
+                    match item.split_once('=') {
+                        Some((target, level)) => options.set(target, level),
+                        None => options.set_default(item),
+                    }
+                }
+            } else if let Some(target) = key.strip_prefix("log.") {
+                options.set(target, value);
+            }
+        }
+
+        options
+    }
+
+    fn set_default(&mut self, level: &str) {
+        match level.parse() {
+            Ok(level) => self.default = Some(level),
+            Err(_) => warn!("ignoring malformed log level {level}"),
+        }
+    }
+
+    fn set(&mut self, target: &'a str, level: &str) {
+        let Ok(level) = level.parse() else {
+            warn!("ignoring malformed log level {target}={level}");
+            return;
+        };
+        if target.is_empty() {
+            warn!("ignoring log level without a target");
+        } else if self.targets.try_push((target, level)).is_err() {
+            warn!("ignoring log level for {target}: too many log targets");
+        }
+    }
+}
+
+/// Parse a size: a decimal or `0x`-prefixed hex number with an optional `K`,
+/// `M`, or `G` binary suffix.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 1 << 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 1 << 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+
+    value.checked_mul(multiplier)
+}
+
+/// Parse `<start>+<length>`, where both are sizes as accepted by
+/// `parse_size`.
+fn parse_extent(s: &str) -> Option<PhysExtent> {
+    let (start, length) = s.split_once('+')?;
+    let start = parse_size(start)?;
+    let length = parse_size(length)?;
+    if length == 0 {
+        return None;
+    }
+    start.checked_add(length)?;
+    Some(PhysExtent::from_raw(start, length))
+}
+
+/// Merge overlapping or touching extents. `extents` must be sorted by address.
+fn merge_overlapping<const N: usize>(extents: &[PhysExtent]) -> ArrayVec<PhysExtent, N> {
+    let mut merged: ArrayVec<PhysExtent, N> = ArrayVec::new();
+    for &extent in extents {
+        match merged.last_mut() {
+            Some(last) if extent.address() <= last.end_address() => {
+                *last = last.join(extent);
+            }
+            _ => merged.push(extent),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("0x1000"), Some(4096));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("0x10M"), Some(16 << 20));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("12Q"), None);
+        assert_eq!(parse_size("0xffffffffffffffffK"), None);
+    }
+
+    #[test]
+    fn memory_options() {
+        let options = MemoryOptions::parse(
+            "quiet mem=512M reserve=0x2000000+0x100000 reserve=0x1000000+0x100000 root",
+        );
+        assert_eq!(options.limit, Some(PhysAddress::from_raw(512 << 20)));
+        assert_eq!(
+            options.reserved.as_slice(),
+            &[
+                PhysExtent::from_raw(0x1000000, 0x100000),
+                PhysExtent::from_raw(0x2000000, 0x100000),
+            ]
+        );
+    }
+
+    #[test]
+    fn log_options() {
+        let options =
+            LogOptions::parse("log=warn log.serial=debug log.=info log.acpi=loud log.fb=OFF");
+        assert_eq!(options.default, Some(LevelFilter::Warn));
+        assert_eq!(
+            options.targets.as_slice(),
+            &[("serial", LevelFilter::Debug), ("fb", LevelFilter::Off)]
+        );
+        assert_eq!(LogOptions::parse("quiet logfile=x"), LogOptions::default());
+    }
+
+    #[test]
+    fn log_option_lists() {
+        let options = LogOptions::parse("log=mm=debug,warn,,sched=off,=info log.serial=trace");
+        assert_eq!(options.default, Some(LevelFilter::Warn));
+        assert_eq!(
+            options.targets.as_slice(),
+            &[
+                ("mm", LevelFilter::Debug),
+                ("sched", LevelFilter::Off),
+                ("serial", LevelFilter::Trace)
+            ]
+        );
+    }
+
+    #[test]
+    fn panic_options() {
+        let action = |cmdline| PanicOptions::parse(cmdline).action;
+        assert_eq!(action(""), PanicAction::Halt);
+        assert_eq!(action("panic=blink"), PanicAction::Blink);
+        assert_eq!(
+            action("panic=blink panic=30"),
+            PanicAction::Reboot { delay_secs: 30 }
+        );
+        assert_eq!(action("panic=0 panic=halt"), PanicAction::Halt);
+        assert_eq!(
+            action("panic=blink panic=-1 panic=soon"),
+            PanicAction::Blink
+        );
+    }
+
+    #[test]
+    fn heap_options() {
+        assert_eq!(
+            HeapOptions::parse("heap.quarantine=64 heap.quarantine=128").quarantine,
+            Some(128)
+        );
+        assert_eq!(
+            HeapOptions::parse("heap.quarantine=some"),
+            HeapOptions::default()
+        );
+    }
+
+    #[test]
+    fn boot_slot_options() {
+        let slot = |cmdline| BootSlotOptions::parse(cmdline).slot;
+        assert_eq!(slot(""), None);
+        assert_eq!(slot("boot_slot=trial"), Some(BootSlot::Trial));
+        assert_eq!(slot("boot_slot=trial boot_slot=good"), Some(BootSlot::Good));
+        assert_eq!(slot("boot_slot=good boot_slot=b"), Some(BootSlot::Good));
+    }
+
+    #[test]
+    fn test_options() {
+        let test_mode = |cmdline| TestOptions::parse(cmdline).test_mode;
+        assert!(!test_mode(""));
+        assert!(test_mode("log=debug test_mode"));
+        assert!(!test_mode("test_mode=1 test_modes"));
+    }
+
+    #[test]
+    fn init_options() {
+        let path = |cmdline| InitOptions::parse(cmdline).path;
+        assert_eq!(path(""), None);
+        assert_eq!(path("log=debug init=/bin/init"), Some("/bin/init"));
+        assert_eq!(path("init=/a init=/b"), Some("/b"));
+        assert_eq!(path("init= initrd=x"), None);
+    }
+
+    #[test]
+    fn watchdog_options() {
+        let timeout = |cmdline| WatchdogOptions::parse(cmdline).timeout_secs;
+        assert_eq!(timeout(""), None);
+        assert_eq!(timeout("watchdog=30"), Some(30));
+        assert_eq!(timeout("watchdog=0"), Some(0));
+        assert_eq!(timeout("watchdog=5 watchdog=off"), Some(5));
+    }
+
+    #[test]
+    fn overlapping_reservations_are_merged() {
+        let options = MemoryOptions::parse("reserve=0x1000+0x2000 reserve=0x2000+0x2000");
+        assert_eq!(
+            options.reserved.as_slice(),
+            &[PhysExtent::from_raw_range_exclusive(0x1000, 0x4000)]
+        );
+    }
+
+    #[test]
+    fn malformed_options_are_ignored() {
+        let options = MemoryOptions::parse("mem=lots reserve=0x1000 reserve=0x1000+0");
+        assert_eq!(options, MemoryOptions::default());
+    }
+}
@@ -0,0 +1,5 @@
+//! Collections usable without allocation
+
+pub mod intrusive_list;
+
+pub use intrusive_list::{IntrusiveList, Linked, Links};
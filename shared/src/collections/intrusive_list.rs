@@ -0,0 +1,442 @@
+//! An intrusive doubly linked list.
+//!
+//! Items embed their own `Links`, so adding an item to a list never
+//! allocates. The list only stores pointers: it does not own its items, and
+//! callers are responsible for keeping items alive and in place while they are
+//! linked.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// Link fields embedded in each item. An item can be in at most one list at a
+/// time per `Links` field.
+pub struct Links<T> {
+    prev: Option<NonNull<T>>,
+    next: Option<NonNull<T>>,
+    linked: bool,
+}
+
+impl<T> Links<T> {
+    pub const fn new() -> Self {
+        Links {
+            prev: None,
+            next: None,
+            linked: false,
+        }
+    }
+
+    /// Whether the owning item is currently in a list.
+    pub fn is_linked(&self) -> bool {
+        self.linked
+    }
+}
+
+impl<T> Default for Links<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> core::fmt::Debug for Links<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Links")
+            .field("prev", &self.prev)
+            .field("next", &self.next)
+            .field("linked", &self.linked)
+            .finish()
+    }
+}
+
+/// Implemented by types that can be put in an `IntrusiveList`.
+///
+/// # Safety
+///
+/// `links` must return a pointer to a `Links` field inside `*this`, and must
+/// return the same field every time for the same item.
+pub unsafe trait Linked: Sized {
+    fn links(this: NonNull<Self>) -> NonNull<Links<Self>>;
+}
+
+pub struct IntrusiveList<T: Linked> {
+    head: Option<NonNull<T>>,
+    tail: Option<NonNull<T>>,
+    len: usize,
+    _phantom: PhantomData<NonNull<T>>,
+}
+
+// SAFETY: the list only holds pointers to items. Sending the list is like
+// sending the items, which is fine if they are `Send`.
+unsafe impl<T: Linked + Send> Send for IntrusiveList<T> {}
+
+impl<T: Linked> IntrusiveList<T> {
+    pub const fn new() -> Self {
+        IntrusiveList {
+            head: None,
+            tail: None,
+            len: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn front(&self) -> Option<NonNull<T>> {
+        self.head
+    }
+
+    pub fn back(&self) -> Option<NonNull<T>> {
+        self.tail
+    }
+
+    /// Add `item` at the end.
+    ///
+    /// # Safety
+    ///
+    /// `item` must be valid and not in any list. It must remain valid and not
+    /// move until it is removed.
+    pub unsafe fn push_back(&mut self, item: NonNull<T>) {
+        unsafe {
+            self.link_between(self.tail, item, None);
+        }
+    }
+
+    /// Add `item` at the start.
+    ///
+    /// # Safety
+    ///
+    /// Same as `push_back`.
+    pub unsafe fn push_front(&mut self, item: NonNull<T>) {
+        unsafe {
+            self.link_between(None, item, self.head);
+        }
+    }
+
+    /// Add `item` immediately before `next`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `push_back`, and `next` must be in `self`.
+    pub unsafe fn insert_before(&mut self, next: NonNull<T>, item: NonNull<T>) {
+        unsafe {
+            let prev = links(next).prev;
+            self.link_between(prev, item, Some(next));
+        }
+    }
+
+    /// Remove and return the first item.
+    pub fn pop_front(&mut self) -> Option<NonNull<T>> {
+        let head = self.head?;
+        // SAFETY: `head` is in `self`.
+        unsafe {
+            self.remove(head);
+        }
+        Some(head)
+    }
+
+    /// Unlink `item`.
+    ///
+    /// # Safety
+    ///
+    /// `item` must be in `self`.
+    pub unsafe fn remove(&mut self, item: NonNull<T>) {
+        let item_links = unsafe { links(item) };
+        assert!(item_links.linked);
+
+        let (prev, next) = (item_links.prev.take(), item_links.next.take());
+        item_links.linked = false;
+
+        match prev {
+            Some(prev) => unsafe { links(prev).next = next },
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => unsafe { links(next).prev = prev },
+            None => self.tail = prev,
+        }
+        self.len -= 1;
+    }
+
+    /// Move all items of `other` to the end of `self`, keeping their order.
+    pub fn append(&mut self, other: &mut IntrusiveList<T>) {
+        let Some(other_head) = other.head.take() else {
+            return;
+        };
+
+        // SAFETY: all items in both lists are valid.
+        unsafe {
+            links(other_head).prev = self.tail;
+            match self.tail {
+                Some(tail) => links(tail).next = Some(other_head),
+                None => self.head = Some(other_head),
+            }
+        }
+        self.tail = other.tail.take();
+        self.len += other.len;
+        other.len = 0;
+    }
+
+    /// Iterate over pointers to the items, front to back. The list must not
+    /// be modified while iterating.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head,
+            _list: PhantomData,
+        }
+    }
+
+    unsafe fn link_between(
+        &mut self,
+        prev: Option<NonNull<T>>,
+        item: NonNull<T>,
+        next: Option<NonNull<T>>,
+    ) {
+        let item_links = unsafe { links(item) };
+        assert!(!item_links.linked);
+        item_links.prev = prev;
+        item_links.next = next;
+        item_links.linked = true;
+
+        match prev {
+            Some(prev) => unsafe { links(prev).next = Some(item) },
+            None => self.head = Some(item),
+        }
+        match next {
+            Some(next) => unsafe { links(next).prev = Some(item) },
+            None => self.tail = Some(item),
+        }
+        self.len += 1;
+    }
+}
+
+impl<T: Linked> Default for IntrusiveList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Iter<'a, T: Linked> {
+    next: Option<NonNull<T>>,
+    _list: PhantomData<&'a IntrusiveList<T>>,
+}
+
+impl<T: Linked> Iterator for Iter<'_, T> {
+    type Item = NonNull<T>;
+
+    fn next(&mut self) -> Option<NonNull<T>> {
+        let item = self.next?;
+        // SAFETY: items in the list are valid, and the list is borrowed so it
+        // can't change.
+        self.next = unsafe { links(item).next };
+        Some(item)
+    }
+}
+
+/// # Safety
+///
+/// `item` must be valid, and no other reference to its links may be live.
+unsafe fn links<'a, T: Linked>(item: NonNull<T>) -> &'a mut Links<T> {
+    unsafe { &mut *T::links(item).as_ptr() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    struct Node {
+        value: u32,
+        links: Links<Node>,
+    }
+
+    unsafe impl Linked for Node {
+        fn links(this: NonNull<Self>) -> NonNull<Links<Self>> {
+            unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*this.as_ptr()).links)) }
+        }
+    }
+
+    /// Allocates nodes and frees them when dropped.
+    struct Nodes(Vec<NonNull<Node>>);
+
+    impl Nodes {
+        fn new(count: u32) -> Nodes {
+            Nodes(
+                (0..count)
+                    .map(|value| {
+                        NonNull::from(Box::leak(Box::new(Node {
+                            value,
+                            links: Links::new(),
+                        })))
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    impl Drop for Nodes {
+        fn drop(&mut self) {
+            for node in self.0.drain(..) {
+                drop(unsafe { Box::from_raw(node.as_ptr()) });
+            }
+        }
+    }
+
+    fn values(list: &IntrusiveList<Node>) -> Vec<u32> {
+        list.iter().map(|n| unsafe { n.as_ref().value }).collect()
+    }
+
+    fn check_invariants(list: &IntrusiveList<Node>) {
+        assert_eq!(list.iter().count(), list.len());
+        assert_eq!(list.is_empty(), list.iter().next().is_none());
+
+        let mut prev = None;
+        for node in list.iter() {
+            let node_links = unsafe { &node.as_ref().links };
+            assert!(node_links.is_linked());
+            assert_eq!(node_links.prev, prev);
+            prev = Some(node);
+        }
+        assert_eq!(list.back(), prev);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let nodes = Nodes::new(3);
+        let mut list = IntrusiveList::new();
+        unsafe {
+            list.push_back(nodes.0[1]);
+            list.push_back(nodes.0[2]);
+            list.push_front(nodes.0[0]);
+        }
+        check_invariants(&list);
+        assert_eq!(values(&list), [0, 1, 2]);
+
+        assert_eq!(list.pop_front(), Some(nodes.0[0]));
+        assert!(!unsafe { nodes.0[0].as_ref() }.links.is_linked());
+        assert_eq!(values(&list), [1, 2]);
+        check_invariants(&list);
+
+        assert_eq!(list.pop_front(), Some(nodes.0[1]));
+        assert_eq!(list.pop_front(), Some(nodes.0[2]));
+        assert_eq!(list.pop_front(), None);
+        check_invariants(&list);
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let nodes = Nodes::new(4);
+        let mut list = IntrusiveList::new();
+        unsafe {
+            list.push_back(nodes.0[0]);
+            list.push_back(nodes.0[3]);
+            list.insert_before(nodes.0[3], nodes.0[1]);
+            list.insert_before(nodes.0[3], nodes.0[2]);
+        }
+        assert_eq!(values(&list), [0, 1, 2, 3]);
+        check_invariants(&list);
+
+        unsafe {
+            list.remove(nodes.0[2]);
+            list.remove(nodes.0[0]);
+        }
+        assert_eq!(values(&list), [1, 3]);
+        check_invariants(&list);
+
+        unsafe {
+            list.insert_before(nodes.0[1], nodes.0[0]);
+        }
+        assert_eq!(values(&list), [0, 1, 3]);
+        check_invariants(&list);
+    }
+
+    #[test]
+    fn append() {
+        let nodes = Nodes::new(4);
+        let mut a = IntrusiveList::new();
+        let mut b = IntrusiveList::new();
+        unsafe {
+            a.push_back(nodes.0[0]);
+            b.push_back(nodes.0[1]);
+            b.push_back(nodes.0[2]);
+        }
+
+        a.append(&mut b);
+        assert_eq!(values(&a), [0, 1, 2]);
+        assert!(b.is_empty());
+        check_invariants(&a);
+        check_invariants(&b);
+
+        // Appending to an empty list.
+        unsafe {
+            b.push_back(nodes.0[3]);
+        }
+        let mut c = IntrusiveList::new();
+        c.append(&mut b);
+        assert_eq!(values(&c), [3]);
+        check_invariants(&c);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        PushBack(usize),
+        PushFront(usize),
+        PopFront,
+        Remove(usize),
+    }
+
+    fn op_strategy(count: usize) -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..count).prop_map(Op::PushBack),
+            (0..count).prop_map(Op::PushFront),
+            Just(Op::PopFront),
+            (0..count).prop_map(Op::Remove),
+        ]
+    }
+
+    proptest! {
+        // Compare against a `VecDeque` model.
+        #[test]
+        fn matches_model(ops in proptest::collection::vec(op_strategy(8), 0..64)) {
+            let nodes = Nodes::new(8);
+            let mut list = IntrusiveList::new();
+            let mut model = std::collections::VecDeque::new();
+
+            for op in ops {
+                match op {
+                    Op::PushBack(i) if !model.contains(&i) => {
+                        unsafe { list.push_back(nodes.0[i]) };
+                        model.push_back(i);
+                    }
+                    Op::PushFront(i) if !model.contains(&i) => {
+                        unsafe { list.push_front(nodes.0[i]) };
+                        model.push_front(i);
+                    }
+                    Op::PopFront => {
+                        prop_assert_eq!(list.pop_front(), model.pop_front().map(|i| nodes.0[i]));
+                    }
+                    Op::Remove(i) if model.contains(&i) => {
+                        unsafe { list.remove(nodes.0[i]) };
+                        model.retain(|&j| j != i);
+                    }
+                    _ => (),
+                }
+
+                check_invariants(&list);
+                prop_assert_eq!(
+                    values(&list),
+                    model.iter().map(|&i| i as u32).collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! "newc" cpio archives, as used for the initrd
+//!
+//! Each entry is a 110 byte ASCII header, the NUL terminated path, and the
+//! file's data, with the path and data each padded to 4 bytes. The archive
+//! ends with an entry named `TRAILER!!!`. This is what `cpio -H newc` and
+//! Linux initramfs use, so archives can be inspected with standard tools.
+//!
+//! Only the name, mode and data of each entry are used. Paths are relative
+//! and may start with `./`.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of `Entry::mode`.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+/// Where a field is in the header: `MAGIC` followed by 13 fields of 8 hex
+/// digits.
+const fn field(index: usize) -> usize {
+    MAGIC.len() + 8 * index
+}
+const MODE: usize = field(1);
+const FILESIZE: usize = field(6);
+const NAMESIZE: usize = field(11);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The entry at this offset doesn't start with the newc magic number.
+    BadMagic(usize),
+    /// The header at this offset has a field that isn't 8 hex digits.
+    BadHeader(usize),
+    /// The entry at this offset runs past the end of the archive.
+    Truncated(usize),
+    /// The entry at this offset has a path that isn't NUL terminated UTF-8.
+    BadName(usize),
+    /// The archive ends without a trailer entry.
+    NoTrailer,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::BadMagic(offset) => write!(f, "bad magic number at offset {offset:#x}"),
+            ParseError::BadHeader(offset) => write!(f, "bad header at offset {offset:#x}"),
+            ParseError::Truncated(offset) => write!(f, "entry at offset {offset:#x} is truncated"),
+            ParseError::BadName(offset) => write!(f, "bad path at offset {offset:#x}"),
+            ParseError::NoTrailer => write!(f, "no trailer entry"),
+        }
+    }
+}
+
+/// A file, directory or other entry in an `Archive`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entry<'a> {
+    /// The path, without any leading `./`.
+    pub path: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// A validated archive.
+#[derive(Clone, Copy, Debug)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Check that `data` is a well formed archive. Anything after the trailer
+    /// is ignored, such as the zero padding `cpio` adds.
+    pub fn new(data: &'a [u8]) -> Result<Self, ParseError> {
+        let mut offset = 0;
+        while let Some((_, next)) = parse_entry(data, offset)? {
+            offset = next;
+        }
+        Ok(Archive { data })
+    }
+
+    /// The entries in archive order, not including the trailer.
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'a>> {
+        let data = self.data;
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            // `new` checked every entry.
+            let (entry, next) = parse_entry(data, offset).unwrap()?;
+            offset = next;
+            Some(entry)
+        })
+    }
+
+    /// The entry at `path`. A leading `/` or `./` is ignored.
+    pub fn get(&self, path: &str) -> Option<Entry<'a>> {
+        let path = normalize(path);
+        self.entries().find(|e| e.path == path)
+    }
+
+    /// The contents of the regular file at `path`.
+    pub fn open(&self, path: &str) -> Option<&'a [u8]> {
+        self.get(path).filter(Entry::is_file).map(|e| e.data)
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_start_matches('/')
+}
+
+/// Parse the entry at `offset`, returning it and the offset of the next, or
+/// `None` at the trailer.
+fn parse_entry(data: &[u8], offset: usize) -> Result<Option<(Entry<'_>, usize)>, ParseError> {
+    let Some(header) = data.get(offset..offset + HEADER_LEN) else {
+        return Err(if offset >= data.len() {
+            ParseError::NoTrailer
+        } else {
+            ParseError::Truncated(offset)
+        });
+    };
+    if !header.starts_with(MAGIC) {
+        return Err(ParseError::BadMagic(offset));
+    }
+    let read_field = |start: usize| -> Result<usize, ParseError> {
+        core::str::from_utf8(&header[start..start + 8])
+            .ok()
+            .and_then(|s| u32::from_str_radix(s, 16).ok())
+            .map(|n| n as usize)
+            .ok_or(ParseError::BadHeader(offset))
+    };
+    let mode = read_field(MODE)? as u32;
+    let file_size = read_field(FILESIZE)?;
+    let name_size = read_field(NAMESIZE)?;
+
+    let name_start = offset + HEADER_LEN;
+    let data_start = (name_start + name_size).next_multiple_of(4);
+    let data_end = data_start + file_size;
+    let name = data
+        .get(name_start..name_start + name_size)
+        .ok_or(ParseError::Truncated(offset))?;
+    let path = name
+        .split_last()
+        .filter(|(&nul, _)| nul == 0)
+        .and_then(|(_, name)| core::str::from_utf8(name).ok())
+        .ok_or(ParseError::BadName(offset))?;
+    if path == TRAILER {
+        return Ok(None);
+    }
+    let file_data = data
+        .get(data_start..data_end)
+        .ok_or(ParseError::Truncated(offset))?;
+
+    let entry = Entry {
+        path: normalize(path),
+        mode,
+        data: file_data,
+    };
+    Ok(Some((entry, data_end.next_multiple_of(4))))
+}
+
+/// Build an archive of read-only regular files from `(path, data)` pairs.
+/// Directories aren't added; `Archive` doesn't need them.
+#[cfg(feature = "alloc")]
+pub fn write<'a>(files: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (ino, (path, data)) in (1..).zip(files) {
+        assert!(!path.contains('\0'), "cpio paths can't contain NUL");
+        write_entry(&mut out, ino, S_IFREG | 0o444, path, data);
+    }
+    write_entry(&mut out, 0, 0, TRAILER, &[]);
+    out
+}
+
+#[cfg(feature = "alloc")]
+fn write_entry(out: &mut Vec<u8>, ino: u32, mode: u32, path: &str, data: &[u8]) {
+    use core::fmt::Write;
+
+    let file_size = u32::try_from(data.len()).expect("cpio files must be under 4 GiB");
+    let name_size = path.len() as u32 + 1;
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+    // rdevmajor, rdevminor, namesize, check.
+    let fields = [ino, mode, 0, 0, 1, 0, file_size, 0, 0, 0, 0, name_size, 0];
+    let mut header = arrayvec::ArrayString::<HEADER_LEN>::new();
+    header.push_str(core::str::from_utf8(MAGIC).unwrap());
+    for field in fields {
+        write!(header, "{field:08x}").unwrap();
+    }
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(path.as_bytes());
+    out.push(0);
+    pad(out);
+    out.extend_from_slice(data);
+    pad(out);
+}
+
+#[cfg(feature = "alloc")]
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parses_written_archive() {
+        let archive_data = write([
+            ("bin/init", &b"\x7fELF"[..]),
+            ("etc/motd", b"hello\n"),
+            ("empty", b""),
+        ]);
+        let archive = Archive::new(&archive_data).unwrap();
+        let paths: Vec<&str> = archive.entries().map(|e| e.path).collect();
+        assert_eq!(paths, ["bin/init", "etc/motd", "empty"]);
+        assert_eq!(archive.open("etc/motd"), Some(&b"hello\n"[..]));
+        assert_eq!(archive.open("/bin/init"), Some(&b"\x7fELF"[..]));
+        assert_eq!(archive.open("./empty"), Some(&b""[..]));
+        assert_eq!(archive.open("bin"), None);
+        assert_eq!(archive.open("motd"), None);
+    }
+
+    // `find . | cpio -o -H newc` output: a directory, a file, and the trailer
+    // padded to 512 bytes.
+    fn cpio_tool_archive() -> Vec<u8> {
+        let mut data = Vec::new();
+        for (ino, mode, name, contents) in [
+            (2u32, 0o040755u32, ".", ""),
+            (3, 0o040755, "./sbin", ""),
+            (4, 0o100644, "./sbin/hello", "hi"),
+            (0, 0, TRAILER, ""),
+        ] {
+            let mut header = String::from("070701");
+            for field in [ino, mode, 0, 0, 1, 0, contents.len() as u32, 0, 0, 0, 0] {
+                header += &std::format!("{field:08X}");
+            }
+            header += &std::format!("{:08X}{:08X}", name.len() + 1, 0);
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.resize(data.len().next_multiple_of(4), 0);
+            data.extend_from_slice(contents.as_bytes());
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        data.resize(512, 0);
+        data
+    }
+
+    #[test]
+    fn parses_cpio_tool_archive() {
+        let data = cpio_tool_archive();
+        let archive = Archive::new(&data).unwrap();
+        let entries: Vec<(&str, bool)> = archive.entries().map(|e| (e.path, e.is_dir())).collect();
+        assert_eq!(
+            entries,
+            [(".", true), ("sbin", true), ("sbin/hello", false)]
+        );
+        assert_eq!(archive.open("/sbin/hello"), Some(&b"hi"[..]));
+        assert_eq!(archive.open("sbin"), None);
+        assert!(archive.get("sbin").unwrap().is_dir());
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        let data = write([("a", &b"abc"[..])]);
+        assert_eq!(Archive::new(&[]).unwrap_err(), ParseError::NoTrailer);
+        assert_eq!(
+            Archive::new(&data[..50]).unwrap_err(),
+            ParseError::Truncated(0)
+        );
+        // Cut off in the trailer.
+        let trailer = data.len() - 124;
+        assert_eq!(
+            Archive::new(&data[..trailer + 112]).unwrap_err(),
+            ParseError::Truncated(trailer)
+        );
+        assert_eq!(
+            Archive::new(&data[..trailer]).unwrap_err(),
+            ParseError::NoTrailer
+        );
+
+        let mut bad = data.clone();
+        bad[0] = b'1';
+        assert_eq!(Archive::new(&bad).unwrap_err(), ParseError::BadMagic(0));
+
+        let mut bad = data.clone();
+        bad[FILESIZE] = b'x';
+        assert_eq!(Archive::new(&bad).unwrap_err(), ParseError::BadHeader(0));
+
+        let mut bad = data;
+        bad[HEADER_LEN + 1] = b'!';
+        assert_eq!(Archive::new(&bad).unwrap_err(), ParseError::BadName(0));
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(files in prop::collection::vec(("[a-z/.]{1,20}", prop::collection::vec(any::<u8>(), 0..100)), 0..10)) {
+            let data = write(files.iter().map(|(path, data)| (path.as_str(), &data[..])));
+            let archive = Archive::new(&data).unwrap();
+            let entries: Vec<(&str, &[u8])> = archive.entries().map(|e| (e.path, e.data)).collect();
+            let expected: Vec<(&str, &[u8])> = files.iter().map(|(path, data)| (normalize(path), &data[..])).collect();
+            prop_assert_eq!(entries, expected);
+        }
+
+        #[test]
+        fn never_panics(data in prop::collection::vec(any::<u8>(), 0..300)) {
+            let _ = Archive::new(&data).map(|a| a.entries().count());
+        }
+    }
+
+    #[test]
+    fn truncated_everywhere() {
+        let data = write(vec![("x", &[1u8, 2, 3][..]), ("yz", &[4][..])]);
+        // The padding after the trailer's path isn't needed.
+        for len in 0..data.len() - 3 {
+            assert!(Archive::new(&data[..len]).is_err(), "{len}");
+        }
+    }
+}
@@ -0,0 +1,657 @@
+//! 64-bit x86_64 ELF executables
+//!
+//! `Elf::parse` validates the file header and the program and section header
+//! tables up front, so the iterators over them never fail. Only little-endian
+//! x86_64 executables and static-PIEs are accepted. Segments can be loaded
+//! with `Elf::load_into`, which leaves where and how memory is mapped to a
+//! `MapTarget`.
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+
+const HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+const SECTION_HEADER_LEN: usize = 64;
+const DYNAMIC_ENTRY_LEN: usize = 16;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+pub const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+pub const SHF_ALLOC: u64 = 1 << 1;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const DT_NULL: i64 = 0;
+pub const DT_NEEDED: i64 = 1;
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_RELAENT: i64 = 9;
+pub const DT_REL: i64 = 17;
+pub const DT_JMPREL: i64 = 23;
+pub const DT_RELR: i64 = 36;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number.
+    BadMagic,
+    /// The file isn't 64-bit, little-endian, or the current ELF version.
+    UnsupportedFormat,
+    /// The file is for another architecture, with this `e_machine`.
+    WrongMachine(u16),
+    /// The file isn't an executable or static-PIE, but this `e_type`.
+    NotExecutable(u16),
+    /// The file ends before the header or a table it points to.
+    Truncated,
+    /// A header table has entries too small for their type.
+    BadEntrySize,
+    /// The loadable segment at this index is malformed: its file contents
+    /// are larger than it or past the end of the file, its alignment isn't a
+    /// power of two or doesn't match its offset, or it wraps around the
+    /// address space.
+    BadSegment(usize),
+    /// The dynamic segment is malformed.
+    BadDynamic,
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedFormat => write!(f, "not a 64-bit little-endian ELF file"),
+            ElfError::WrongMachine(machine) => write!(f, "not for x86_64 (machine {machine})"),
+            ElfError::NotExecutable(kind) => write!(f, "not an executable (type {kind})"),
+            ElfError::Truncated => write!(f, "truncated file"),
+            ElfError::BadEntrySize => write!(f, "bad header table entry size"),
+            ElfError::BadSegment(index) => write!(f, "bad loadable segment {index}"),
+            ElfError::BadDynamic => write!(f, "bad dynamic segment"),
+        }
+    }
+}
+
+/// The kind of executable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    /// Linked to run at fixed addresses.
+    Executable,
+    /// Position independent; may be loaded at any suitably aligned bias.
+    SharedObject,
+}
+
+/// A program header, describing a segment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Segment {
+    /// `PT_LOAD`, `PT_DYNAMIC`, etc.
+    pub kind: u32,
+    /// `PF_R`, `PF_W` and `PF_X`.
+    pub flags: u32,
+    pub offset: u64,
+    pub address: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl Segment {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A section header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Section {
+    /// Offset of the name in the section name string table.
+    pub name_offset: u32,
+    /// `SHT_PROGBITS`, `SHT_NOBITS`, etc.
+    pub kind: u32,
+    /// `SHF_ALLOC` etc.
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Section {
+    /// Whether the section occupies memory when loaded.
+    pub fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+
+    /// Whether `address` is in the section's memory.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.address && address - self.address < self.size
+    }
+}
+
+/// A validated ELF file.
+#[derive(Clone, Copy, Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    kind: FileKind,
+    entry: u64,
+    program_headers: Table,
+    section_headers: Table,
+    section_names: Option<Section>,
+}
+
+/// Where a header table is in the file.
+#[derive(Clone, Copy, Debug)]
+struct Table {
+    offset: usize,
+    entry_len: usize,
+    count: usize,
+}
+
+impl Table {
+    fn parse(
+        data: &[u8],
+        offset: u64,
+        entry_len: u16,
+        count: u16,
+        min_entry_len: usize,
+    ) -> Result<Table, ElfError> {
+        let table = Table {
+            offset: usize::try_from(offset).map_err(|_| ElfError::Truncated)?,
+            entry_len: entry_len.into(),
+            count: count.into(),
+        };
+        if table.count == 0 {
+            return Ok(table);
+        }
+        if table.entry_len < min_entry_len {
+            return Err(ElfError::BadEntrySize);
+        }
+        let end = table
+            .offset
+            .checked_add(table.entry_len * table.count)
+            .ok_or(ElfError::Truncated)?;
+        if end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+        Ok(table)
+    }
+
+    fn entry<'a>(&self, data: &'a [u8], index: usize) -> &'a [u8] {
+        let start = self.offset + index * self.entry_len;
+        &data[start..start + self.entry_len]
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// Validate `data` as an x86_64 executable or static-PIE.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.get(..MAGIC.len()) != Some(MAGIC) {
+            return Err(ElfError::BadMagic);
+        }
+        if data.len() < HEADER_LEN {
+            return Err(ElfError::Truncated);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN || data[6] != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        let kind = match read_u16(data, 16) {
+            ET_EXEC => FileKind::Executable,
+            ET_DYN => FileKind::SharedObject,
+            other => return Err(ElfError::NotExecutable(other)),
+        };
+        let machine = read_u16(data, 18);
+        if machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine(machine));
+        }
+
+        let program_headers = Table::parse(
+            data,
+            read_u64(data, 32),
+            read_u16(data, 54),
+            read_u16(data, 56),
+            PROGRAM_HEADER_LEN,
+        )?;
+        let section_headers = Table::parse(
+            data,
+            read_u64(data, 40),
+            read_u16(data, 58),
+            read_u16(data, 60),
+            SECTION_HEADER_LEN,
+        )?;
+
+        let mut elf = Elf {
+            data,
+            kind,
+            entry: read_u64(data, 24),
+            program_headers,
+            section_headers,
+            section_names: None,
+        };
+
+        for (index, segment) in elf.segments().enumerate() {
+            if segment.is_load() && !is_valid_load_segment(&segment, data.len()) {
+                return Err(ElfError::BadSegment(index));
+            }
+        }
+
+        let names_index = usize::from(read_u16(data, 62));
+        if names_index != 0 && names_index < section_headers.count {
+            let names = elf.section(names_index);
+            if elf.file_range(names.offset, names.size).is_none() {
+                return Err(ElfError::Truncated);
+            }
+            elf.section_names = Some(names);
+        }
+        Ok(elf)
+    }
+
+    pub fn kind(&self) -> FileKind {
+        self.kind
+    }
+
+    /// The entry point, before applying any load bias.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The number of program headers.
+    pub fn segment_count(&self) -> usize {
+        self.program_headers.count
+    }
+
+    /// The size of each program header in the file.
+    pub fn program_header_len(&self) -> usize {
+        self.program_headers.entry_len
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let (data, table) = (self.data, self.program_headers);
+        (0..table.count).map(move |index| {
+            let entry = table.entry(data, index);
+            Segment {
+                kind: read_u32(entry, 0),
+                flags: read_u32(entry, 4),
+                offset: read_u64(entry, 8),
+                address: read_u64(entry, 16),
+                file_size: read_u64(entry, 32),
+                mem_size: read_u64(entry, 40),
+                align: read_u64(entry, 48),
+            }
+        })
+    }
+
+    /// The contents of `segment` in the file, or `None` if they are past its
+    /// end. Always `Some` for loadable segments.
+    pub fn segment_data(&self, segment: &Segment) -> Option<&'a [u8]> {
+        self.file_range(segment.offset, segment.file_size)
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = Section> + 'a {
+        let elf = *self;
+        (0..self.section_headers.count).map(move |index| elf.section(index))
+    }
+
+    /// The name of `section`, if the file has a section name table and the
+    /// name in it is valid UTF-8.
+    pub fn section_name(&self, section: &Section) -> Option<&'a str> {
+        let names = self.section_names?;
+        let names = self.file_range(names.offset, names.size)?;
+        let name = names.get(usize::try_from(section.name_offset).ok()?..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()
+    }
+
+    /// The entries of the dynamic segment, up to `DT_NULL`, as tag and value
+    /// pairs. Empty if there is no dynamic segment.
+    pub fn dynamic(&self) -> Result<impl Iterator<Item = (i64, u64)> + 'a, ElfError> {
+        let entries = match self.segments().find(|segment| segment.kind == PT_DYNAMIC) {
+            Some(segment) => self.segment_data(&segment).ok_or(ElfError::BadDynamic)?,
+            None => &[],
+        };
+        Ok(entries
+            .chunks_exact(DYNAMIC_ENTRY_LEN)
+            .map(|entry| (read_u64(entry, 0) as i64, read_u64(entry, 8)))
+            .take_while(|&(tag, _)| tag != DT_NULL))
+    }
+
+    /// The `len` bytes of the file loaded at `address`, before applying any
+    /// load bias, or `None` if they aren't all in one segment's file
+    /// contents.
+    pub fn data_at(&self, address: u64, len: u64) -> Option<&'a [u8]> {
+        self.segments()
+            .filter(Segment::is_load)
+            .find_map(|segment| {
+                let start = address.checked_sub(segment.address)?;
+                if start.checked_add(len)? > segment.file_size {
+                    return None;
+                }
+                self.file_range(segment.offset + start, len)
+            })
+    }
+
+    /// Where the program headers are loaded, before applying any load bias,
+    /// if they are loaded at all.
+    pub fn program_headers_address(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments().find(|segment| segment.kind == PT_PHDR) {
+            return Some(phdr.address);
+        }
+
+        let offset = self.program_headers.offset as u64;
+        self.segments()
+            .filter(Segment::is_load)
+            .find(|segment| offset >= segment.offset && offset - segment.offset < segment.file_size)
+            .map(|segment| segment.address + (offset - segment.offset))
+    }
+
+    /// Load every loadable segment into `target`, offset by `load_bias`: map
+    /// its memory, then copy in its file contents. The rest of each segment
+    /// is left zeroed.
+    pub fn load_into<T: MapTarget>(
+        &self,
+        target: &mut T,
+        load_bias: u64,
+    ) -> Result<(), LoadError<T::Error>> {
+        for segment in self.segments().filter(Segment::is_load) {
+            if segment.mem_size == 0 {
+                continue;
+            }
+            let address = segment
+                .address
+                .checked_add(load_bias)
+                .filter(|address| address.checked_add(segment.mem_size).is_some())
+                .ok_or(LoadError::OutOfRange)?;
+            target
+                .map_zeroed(address, segment.mem_size, segment.flags)
+                .map_err(LoadError::Target)?;
+
+            let data = self.segment_data(&segment).unwrap();
+            if !data.is_empty() {
+                target.write(address, data).map_err(LoadError::Target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn section(&self, index: usize) -> Section {
+        let entry = self.section_headers.entry(self.data, index);
+        Section {
+            name_offset: read_u32(entry, 0),
+            kind: read_u32(entry, 4),
+            flags: read_u64(entry, 8),
+            address: read_u64(entry, 16),
+            offset: read_u64(entry, 24),
+            size: read_u64(entry, 32),
+        }
+    }
+
+    fn file_range(&self, offset: u64, len: u64) -> Option<&'a [u8]> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        self.data.get(start..end)
+    }
+}
+
+fn is_valid_load_segment(segment: &Segment, file_len: usize) -> bool {
+    let in_file = segment
+        .offset
+        .checked_add(segment.file_size)
+        .is_some_and(|end| end <= file_len as u64);
+    let aligned = match segment.align {
+        0 | 1 => true,
+        align => align.is_power_of_two() && segment.address % align == segment.offset % align,
+    };
+    segment.file_size <= segment.mem_size
+        && in_file
+        && aligned
+        && segment.address.checked_add(segment.mem_size).is_some()
+}
+
+/// Somewhere to load an executable's segments, e.g. a new address space.
+pub trait MapTarget {
+    type Error;
+
+    /// Make the `len` bytes at `address` available, zero-filled, with the
+    /// access `flags` (`PF_R` etc.) give. Called once for each loadable
+    /// segment, with the load bias applied.
+    fn map_zeroed(&mut self, address: u64, len: u64, flags: u32) -> Result<(), Self::Error>;
+
+    /// Copy `data` to `address`, in memory from `map_zeroed`.
+    fn write(&mut self, address: u64, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoadError<E> {
+    /// A segment doesn't fit in the address space with the load bias.
+    OutOfRange,
+    /// The target failed.
+    Target(E),
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    /// Built from `testdata/prog.S`, which has the commands.
+    const STATIC: &[u8] = include_bytes!("elf64/testdata/static.elf");
+    const PIE: &[u8] = include_bytes!("elf64/testdata/pie.elf");
+
+    /// Memory as a list of mapped ranges and their contents.
+    #[derive(Default)]
+    struct FakeTarget {
+        mappings: Vec<(u64, u32, Vec<u8>)>,
+    }
+
+    impl MapTarget for FakeTarget {
+        type Error = &'static str;
+
+        fn map_zeroed(&mut self, address: u64, len: u64, flags: u32) -> Result<(), Self::Error> {
+            self.mappings
+                .push((address, flags, std::vec![0; len as usize]));
+            Ok(())
+        }
+
+        fn write(&mut self, address: u64, data: &[u8]) -> Result<(), Self::Error> {
+            let (start, _, bytes) = self
+                .mappings
+                .iter_mut()
+                .find(|(start, _, bytes)| {
+                    address >= *start && address + data.len() as u64 <= start + bytes.len() as u64
+                })
+                .ok_or("write outside of mapping")?;
+            let offset = (address - *start) as usize;
+            bytes[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_static_executable() {
+        let elf = Elf::parse(STATIC).unwrap();
+        assert_eq!(elf.kind(), FileKind::Executable);
+        assert_eq!(elf.entry(), 0x4000b0);
+
+        let loads: Vec<_> = elf.segments().filter(Segment::is_load).collect();
+        assert_eq!(loads.len(), 2);
+        assert_eq!(loads[0].address, 0x400000);
+        assert!(loads[0].is_executable() && !loads[0].is_writable());
+        assert!(loads[1].is_writable() && !loads[1].is_executable());
+        assert!(loads[1].mem_size > loads[1].file_size);
+
+        let names: Vec<_> = elf
+            .sections()
+            .filter(Section::is_alloc)
+            .map(|section| elf.section_name(&section).unwrap())
+            .collect();
+        assert_eq!(names, [".text", ".data", ".bss"]);
+        let text = elf.sections().nth(1).unwrap();
+        assert!(text.contains(elf.entry()));
+    }
+
+    #[test]
+    fn parses_static_pie() {
+        let elf = Elf::parse(PIE).unwrap();
+        assert_eq!(elf.kind(), FileKind::SharedObject);
+        assert!(elf.segments().all(|segment| segment.kind != PT_INTERP));
+
+        let dynamic: Vec<_> = elf.dynamic().unwrap().collect();
+        let rela = dynamic.iter().find(|&&(tag, _)| tag == DT_RELA).unwrap().1;
+        let rela_len = dynamic
+            .iter()
+            .find(|&&(tag, _)| tag == DT_RELASZ)
+            .unwrap()
+            .1;
+        assert_eq!(rela_len, 24);
+        // One R_X86_64_RELATIVE relocation, for `pointer`.
+        let entry = elf.data_at(rela, rela_len).unwrap();
+        assert_eq!(read_u64(entry, 8), 8);
+    }
+
+    #[test]
+    fn program_headers_are_loaded() {
+        // Both are loaded as part of the first segment, at file offset 64.
+        assert_eq!(
+            Elf::parse(STATIC).unwrap().program_headers_address(),
+            Some(0x400040)
+        );
+        assert_eq!(
+            Elf::parse(PIE).unwrap().program_headers_address(),
+            Some(0x40)
+        );
+    }
+
+    #[test]
+    fn loads_segments_at_bias() {
+        let elf = Elf::parse(PIE).unwrap();
+        let mut target = FakeTarget::default();
+        elf.load_into(&mut target, 0x10_0000).unwrap();
+
+        let loads: Vec<_> = elf.segments().filter(Segment::is_load).collect();
+        assert_eq!(target.mappings.len(), loads.len());
+        for ((address, flags, bytes), segment) in target.mappings.iter().zip(&loads) {
+            assert_eq!(*address, segment.address + 0x10_0000);
+            assert_eq!(*flags, segment.flags);
+            let file_size = segment.file_size as usize;
+            assert_eq!(&bytes[..file_size], elf.segment_data(segment).unwrap());
+            assert!(bytes[file_size..].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn load_reports_overflow() {
+        let elf = Elf::parse(STATIC).unwrap();
+        assert_eq!(
+            elf.load_into(&mut FakeTarget::default(), u64::MAX - 0x1000),
+            Err(LoadError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let with = |offset: usize, bytes: &[u8]| {
+            let mut data = STATIC.to_vec();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Elf::parse(&data).map(|_| ())
+        };
+        assert_eq!(with(0, b"\x7fELG"), Err(ElfError::BadMagic));
+        assert_eq!(with(4, &[1]), Err(ElfError::UnsupportedFormat));
+        assert_eq!(with(5, &[2]), Err(ElfError::UnsupportedFormat));
+        // Relocatable object.
+        assert_eq!(
+            with(16, &1u16.to_le_bytes()),
+            Err(ElfError::NotExecutable(1))
+        );
+        // AArch64.
+        assert_eq!(
+            with(18, &183u16.to_le_bytes()),
+            Err(ElfError::WrongMachine(183))
+        );
+        assert_eq!(with(54, &32u16.to_le_bytes()), Err(ElfError::BadEntrySize));
+        assert_eq!(with(56, &1000u16.to_le_bytes()), Err(ElfError::Truncated));
+        assert_eq!(
+            Elf::parse(&STATIC[..40]).map(|_| ()),
+            Err(ElfError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        // Fields of the second program header.
+        let segment = 64 + PROGRAM_HEADER_LEN;
+        let with = |field: usize, value: u64| {
+            let mut data = STATIC.to_vec();
+            data[segment + field..segment + field + 8].copy_from_slice(&value.to_le_bytes());
+            Elf::parse(&data).map(|_| ())
+        };
+        // File size larger than memory size.
+        assert_eq!(with(32, 0x10_0000), Err(ElfError::BadSegment(1)));
+        // Offset past the end of the file.
+        assert_eq!(with(8, 0x10_0000), Err(ElfError::BadSegment(1)));
+        // Alignment not a power of two.
+        assert_eq!(with(48, 0x3000), Err(ElfError::BadSegment(1)));
+        // Address not congruent with the offset.
+        assert_eq!(with(16, 0x401000), Err(ElfError::BadSegment(1)));
+        // Wraps around.
+        assert_eq!(with(16, u64::MAX - 0xf3c), Err(ElfError::BadSegment(1)));
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(
+            edits in proptest::collection::vec((0..PIE.len(), any::<u8>()), 0..16),
+            len in 0..=PIE.len(),
+        ) {
+            let mut data = PIE.to_vec();
+            for (offset, byte) in edits {
+                data[offset] = byte;
+            }
+            data.truncate(len);
+            if let Ok(elf) = Elf::parse(&data) {
+                for segment in elf.segments() {
+                    if segment.is_load() {
+                        prop_assert!(elf.segment_data(&segment).is_some());
+                    }
+                }
+                for section in elf.sections() {
+                    elf.section_name(&section);
+                }
+                if let Ok(dynamic) = elf.dynamic() {
+                    dynamic.count();
+                }
+                elf.program_headers_address();
+                let _ = elf.load_into(&mut FakeTarget::default(), 0);
+            }
+        }
+    }
+}
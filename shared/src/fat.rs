@@ -0,0 +1,951 @@
+//! Read-only FAT32 volumes
+//!
+//! A `Volume` reads the boot sector to find the FATs and the data area, then
+//! follows cluster chains through the first FAT to walk directories and read
+//! files. Long file names are used when their checksum matches the short
+//! entry they precede; otherwise the 8.3 name is. Names are compared without
+//! regard to ASCII case, as Windows does.
+//!
+//! The FAT type is taken from the boot sector's fields, not from the number
+//! of clusters, so the small volumes `write_image` makes are FAT32 too.
+//! Only 512 byte sectors are supported.
+//!
+//! `partitions` finds FAT32 volumes in an MBR partition table, and
+//! `write_image` builds a volume holding given files, for tests and for
+//! building disk images.
+
+use core::fmt;
+use core::ops::ControlFlow;
+
+use arrayvec::ArrayString;
+
+#[cfg(feature = "alloc")]
+use alloc::{collections::BTreeMap, format, vec, vec::Vec};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Marks a long file name entry.
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// In a directory entry's first byte.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+
+/// In a long name entry's order byte.
+const LONG_NAME_LAST: u8 = 0x40;
+/// UTF-16 units in each long name entry.
+const LONG_NAME_UNITS: usize = 13;
+/// Where the units are in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// The longest name, in UTF-16 units.
+const MAX_NAME_UNITS: usize = 255;
+
+/// In a short entry's reserved byte: the base name or extension is
+/// lowercase.
+const SHORT_BASE_LOWER: u8 = 0x08;
+const SHORT_EXT_LOWER: u8 = 0x10;
+
+/// FAT entries at or above this end a chain.
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+/// The first cluster of the data area.
+const FIRST_CLUSTER: u32 = 2;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// MBR partition types for FAT32, without and with LBA addressing.
+const PARTITION_FAT32: [u8; 2] = [0x0b, 0x0c];
+
+/// Names can be 255 UTF-16 units, which take at most 3 UTF-8 bytes each.
+pub type Name = ArrayString<{ MAX_NAME_UNITS * 3 }>;
+
+/// Sectors a volume is read from, numbered from the start of the volume.
+pub trait Disk {
+    type Error;
+
+    /// Fill `buf`, a whole number of sectors, from the sectors starting at
+    /// `sector`.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+impl<D: Disk + ?Sized> Disk for &D {
+    type Error = D::Error;
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        (**self).read(sector, buf)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<E> {
+    Disk(E),
+    /// The boot sector isn't a FAT32 one this supports.
+    NotFat32,
+    /// A cluster chain is broken, e.g. it points outside the volume or
+    /// loops.
+    BadChain(u32),
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Disk(e) => write!(f, "disk error: {e}"),
+            Error::NotFat32 => write!(f, "not a FAT32 volume"),
+            Error::BadChain(cluster) => write!(f, "broken cluster chain at cluster {cluster}"),
+            Error::NotFound => write!(f, "no such file or directory"),
+            Error::NotADirectory => write!(f, "not a directory"),
+            Error::IsADirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+/// A file or directory on a volume.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Node {
+    /// 0 for an empty file.
+    pub first_cluster: u32,
+    /// In bytes. Always 0 for directories.
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+/// An entry read from a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: Name,
+    pub node: Node,
+}
+
+/// A mounted FAT32 volume.
+pub struct Volume<D> {
+    disk: D,
+    sectors_per_cluster: u32,
+    /// The first sector of the first FAT.
+    fat_start: u64,
+    /// The sector cluster 2 starts at.
+    data_start: u64,
+    root_cluster: u32,
+    /// Clusters in the data area.
+    num_clusters: u32,
+}
+
+impl<D: Disk> Volume<D> {
+    /// Check the boot sector of the volume on `disk`.
+    pub fn mount(disk: D) -> Result<Self, Error<D::Error>> {
+        let mut boot = [0; SECTOR_SIZE];
+        disk.read(0, &mut boot).map_err(Error::Disk)?;
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                boot[offset],
+                boot[offset + 1],
+                boot[offset + 2],
+                boot[offset + 3],
+            ])
+        };
+
+        let bytes_per_sector = u16_at(0x0b);
+        let sectors_per_cluster = u32::from(boot[0x0d]);
+        let reserved_sectors = u64::from(u16_at(0x0e));
+        let num_fats = u64::from(boot[0x10]);
+        let root_entries = u16_at(0x11);
+        let fat_size_16 = u16_at(0x16);
+        let total_sectors = u64::from(u32_at(0x20));
+        let fat_size = u64::from(u32_at(0x24));
+        let root_cluster = u32_at(0x2c);
+        if boot[0x1fe..] != BOOT_SIGNATURE
+            || usize::from(bytes_per_sector) != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || num_fats == 0
+            || root_entries != 0
+            || fat_size_16 != 0
+            || fat_size == 0
+        {
+            return Err(Error::NotFat32);
+        }
+
+        let data_start = reserved_sectors + num_fats * fat_size;
+        let data_clusters = total_sectors
+            .checked_sub(data_start)
+            .ok_or(Error::NotFat32)?
+            / u64::from(sectors_per_cluster);
+        // Only as many clusters as the FAT has entries for.
+        let fat_entries = fat_size * SECTOR_SIZE as u64 / 4 - u64::from(FIRST_CLUSTER);
+        let num_clusters = data_clusters
+            .min(fat_entries)
+            .min(u64::from(CLUSTER_MASK - FIRST_CLUSTER)) as u32;
+        let volume = Volume {
+            disk,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            data_start,
+            root_cluster,
+            num_clusters,
+        };
+        if !volume.is_data_cluster(root_cluster) {
+            return Err(Error::NotFat32);
+        }
+        Ok(volume)
+    }
+
+    pub fn root(&self) -> Node {
+        Node {
+            first_cluster: self.root_cluster,
+            size: 0,
+            is_dir: true,
+        }
+    }
+
+    /// What the volume is read from.
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    /// Bytes per cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// Find `path`, whose components are separated by `/`. Leading, trailing
+    /// and repeated separators are ignored, so `""` is the root.
+    pub fn lookup(&self, path: &str) -> Result<Node, Error<D::Error>> {
+        let mut node = self.root();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = self.find(node, component)?;
+        }
+        Ok(node)
+    }
+
+    /// Find the entry named `name` in `dir`.
+    pub fn find(&self, dir: Node, name: &str) -> Result<Node, Error<D::Error>> {
+        let mut found = None;
+        self.read_dir(dir, |entry| {
+            if entry.name.eq_ignore_ascii_case(name) {
+                found = Some(entry.node);
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        found.ok_or(Error::NotFound)
+    }
+
+    /// Call `f` with each entry of `dir` until it breaks, skipping deleted
+    /// entries and the volume label.
+    pub fn read_dir(
+        &self,
+        dir: Node,
+        mut f: impl FnMut(&DirEntry) -> ControlFlow<()>,
+    ) -> Result<(), Error<D::Error>> {
+        if !dir.is_dir {
+            return Err(Error::NotADirectory);
+        }
+        let mut long_name = LongName::new();
+        let mut sector_buf = [0; SECTOR_SIZE];
+        let mut cluster = dir.first_cluster;
+        // A ".." entry for the root has cluster 0.
+        if cluster == 0 {
+            cluster = self.root_cluster;
+        }
+        for _ in 0..self.num_clusters {
+            for sector in 0..self.sectors_per_cluster {
+                self.disk
+                    .read(
+                        self.cluster_sector(cluster) + u64::from(sector),
+                        &mut sector_buf,
+                    )
+                    .map_err(Error::Disk)?;
+                for raw in sector_buf.chunks_exact(DIR_ENTRY_SIZE) {
+                    match raw[0] {
+                        ENTRY_END => return Ok(()),
+                        ENTRY_DELETED => {
+                            long_name.clear();
+                            continue;
+                        }
+                        _ => (),
+                    }
+                    let attributes = raw[11];
+                    if attributes & 0x3f == ATTR_LONG_NAME {
+                        long_name.add(raw);
+                        continue;
+                    }
+                    let name = long_name.take(raw).unwrap_or_else(|| short_name(raw));
+                    if attributes & ATTR_VOLUME_ID != 0 {
+                        continue;
+                    }
+                    let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+                    let is_dir = attributes & ATTR_DIRECTORY != 0;
+                    let entry = DirEntry {
+                        name,
+                        node: Node {
+                            first_cluster: u32::from(u16_at(20)) << 16 | u32::from(u16_at(26)),
+                            size: if is_dir {
+                                0
+                            } else {
+                                u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]])
+                            },
+                            is_dir,
+                        },
+                    };
+                    if f(&entry).is_break() {
+                        return Ok(());
+                    }
+                }
+            }
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(()),
+            }
+        }
+        Err(Error::BadChain(cluster))
+    }
+
+    /// Read from `file` at `offset` into `buf`. Returns how many bytes were
+    /// read, which is less than `buf.len()` only at the end of the file.
+    pub fn read_at(
+        &self,
+        file: Node,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<D::Error>> {
+        if file.is_dir {
+            return Err(Error::IsADirectory);
+        }
+        let size = u64::from(file.size);
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let cluster_size = self.cluster_size() as u64;
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self
+                .next_cluster(cluster)?
+                .ok_or(Error::BadChain(cluster))?;
+        }
+
+        let mut sector_buf = [0; SECTOR_SIZE];
+        let mut done = 0;
+        let mut position = offset;
+        while done < len {
+            let in_cluster = position % cluster_size;
+            let sector = self.cluster_sector(cluster) + in_cluster / SECTOR_SIZE as u64;
+            let in_sector = (in_cluster % SECTOR_SIZE as u64) as usize;
+            let chunk = (SECTOR_SIZE - in_sector).min(len - done);
+            if chunk == SECTOR_SIZE {
+                self.disk
+                    .read(sector, &mut buf[done..done + SECTOR_SIZE])
+                    .map_err(Error::Disk)?;
+            } else {
+                self.disk
+                    .read(sector, &mut sector_buf)
+                    .map_err(Error::Disk)?;
+                buf[done..done + chunk].copy_from_slice(&sector_buf[in_sector..in_sector + chunk]);
+            }
+            done += chunk;
+            position += chunk as u64;
+            if done < len && position.is_multiple_of(cluster_size) {
+                cluster = self
+                    .next_cluster(cluster)?
+                    .ok_or(Error::BadChain(cluster))?;
+            }
+        }
+        Ok(len)
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error<D::Error>> {
+        if !self.is_data_cluster(cluster) {
+            return Err(Error::BadChain(cluster));
+        }
+        let offset = u64::from(cluster) * 4;
+        let mut sector = [0; SECTOR_SIZE];
+        self.disk
+            .read(self.fat_start + offset / SECTOR_SIZE as u64, &mut sector)
+            .map_err(Error::Disk)?;
+        let at = (offset % SECTOR_SIZE as u64) as usize;
+        let next = u32::from_le_bytes([sector[at], sector[at + 1], sector[at + 2], sector[at + 3]])
+            & CLUSTER_MASK;
+        if next >= END_OF_CHAIN {
+            Ok(None)
+        } else if self.is_data_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::BadChain(cluster))
+        }
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.num_clusters).contains(&cluster)
+    }
+
+    /// Where `cluster`, a data cluster, starts.
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * u64::from(self.sectors_per_cluster)
+    }
+}
+
+/// The long name entries seen since the last short entry.
+struct LongName {
+    units: [u16; MAX_NAME_UNITS + LONG_NAME_UNITS],
+    /// The sequence number expected next, counting down to 1, or 0 if no
+    /// valid name is being collected.
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            units: [0xffff; MAX_NAME_UNITS + LONG_NAME_UNITS],
+            next: 0,
+            checksum: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.next = 0;
+    }
+
+    /// Collect a long name entry. They come in reverse order, last part
+    /// first.
+    fn add(&mut self, raw: &[u8]) {
+        let order = raw[0];
+        let sequence = order & !LONG_NAME_LAST;
+        if order & LONG_NAME_LAST != 0 {
+            self.units.fill(0xffff);
+            self.next = sequence;
+            self.checksum = raw[13];
+        }
+        let index = usize::from(sequence);
+        if sequence == 0
+            || sequence != self.next
+            || raw[13] != self.checksum
+            || index * LONG_NAME_UNITS > self.units.len()
+        {
+            self.next = 0;
+            return;
+        }
+        let start = (index - 1) * LONG_NAME_UNITS;
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.units[start + i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        }
+        self.next -= 1;
+    }
+
+    /// The long name belonging to the short entry `raw`, if one was
+    /// collected.
+    fn take(&mut self, raw: &[u8]) -> Option<Name> {
+        // All the parts were seen once the next expected is 0 again.
+        let complete = self.next == 0 && self.units[0] != 0xffff;
+        if !complete || self.checksum != short_name_checksum(&raw[..11]) {
+            self.units[0] = 0xffff;
+            return None;
+        }
+        let len = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0 || unit == 0xffff)
+            .unwrap_or(MAX_NAME_UNITS)
+            .min(MAX_NAME_UNITS);
+        let mut name = Name::new();
+        for c in char::decode_utf16(self.units[..len].iter().copied()) {
+            name.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+        self.units[0] = 0xffff;
+        Some(name)
+    }
+}
+
+/// The 8.3 name of the short entry `raw`, as `BASE.EXT`.
+fn short_name(raw: &[u8]) -> Name {
+    let case = raw[12];
+    let mut name = Name::new();
+    let mut push = |bytes: &[u8], lower: bool| {
+        for &byte in bytes.iter().take_while(|&&b| b != b' ') {
+            // 0x05 stands for a leading 0xe5. Other bytes are in an OEM code
+            // page, and only ASCII is understood.
+            let c = match byte {
+                0x05 => '\u{e5}',
+                b if b.is_ascii() && lower => b.to_ascii_lowercase() as char,
+                b if b.is_ascii() => b as char,
+                _ => char::REPLACEMENT_CHARACTER,
+            };
+            name.push(c);
+        }
+    };
+    push(&raw[..8], case & SHORT_BASE_LOWER != 0);
+    if raw[8] != b' ' {
+        push(b".", false);
+        push(&raw[8..11], case & SHORT_EXT_LOWER != 0);
+    }
+    name
+}
+
+/// Ties long name entries to their short entry.
+fn short_name_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The start and length in sectors of each FAT32 partition in the MBR
+/// `sector`. Empty if it has no partition table.
+pub fn partitions(sector: &[u8; SECTOR_SIZE]) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let has_table = sector[0x1fe..] == BOOT_SIGNATURE;
+    (0..4)
+        .filter(move |_| has_table)
+        .map(|i| &sector[0x1be + 16 * i..0x1be + 16 * (i + 1)])
+        .filter(|entry| PARTITION_FAT32.contains(&entry[4]))
+        .map(|entry| {
+            let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+            let len = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]);
+            (u64::from(start), u64::from(len))
+        })
+        .filter(|&(start, len)| start != 0 && len != 0)
+}
+
+/// A directory being laid out by `write_image`.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+struct DirTree<'a> {
+    dirs: BTreeMap<&'a str, DirTree<'a>>,
+    files: BTreeMap<&'a str, &'a [u8]>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> DirTree<'a> {
+    /// `is_root` because the root has no "." and ".." entries.
+    fn entries(&self, is_root: bool) -> usize {
+        // Each child's long name entries and short entry.
+        let names = self.dirs.keys().chain(self.files.keys());
+        let dots = if is_root { 0 } else { 2 };
+        dots + names
+            .map(|name| name.encode_utf16().count().div_ceil(LONG_NAME_UNITS) + 1)
+            .sum::<usize>()
+    }
+}
+
+/// Build a FAT32 volume with one sector per cluster holding `files`, by path
+/// relative to the root. Directories are made as needed. Every entry gets a
+/// long name, and short names are made up. Panics if a path is both a file
+/// and a directory, or a name is longer than 255 UTF-16 units.
+#[cfg(feature = "alloc")]
+pub fn write_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut root = DirTree::default();
+    for &(path, data) in files {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let name = components.pop().expect("empty path");
+        let mut dir = &mut root;
+        for component in components {
+            assert!(!dir.files.contains_key(component), "{path} is under a file");
+            dir = dir.dirs.entry(component).or_default();
+        }
+        assert!(!dir.dirs.contains_key(name), "{path} is a directory");
+        dir.files.insert(name, data);
+    }
+
+    // Lay out every directory's and file's clusters contiguously, depth
+    // first, starting at cluster 2.
+    let mut clusters: Vec<Vec<u8>> = Vec::new();
+    let root_cluster = layout_dir(&root, None, &mut clusters);
+
+    let reserved_sectors = 32u32;
+    let num_fats = 2u32;
+    let fat_entries = clusters.len() + FIRST_CLUSTER as usize;
+    let fat_size = (fat_entries * 4).div_ceil(SECTOR_SIZE) as u32;
+    let data_start = reserved_sectors + num_fats * fat_size;
+    let total_sectors = data_start + clusters.len() as u32;
+
+    let mut image = vec![0; total_sectors as usize * SECTOR_SIZE];
+    let boot = &mut image[..SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"testos  ");
+    boot[0x0b..0x0d].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[0x0d] = 1;
+    boot[0x0e..0x10].copy_from_slice(&(reserved_sectors as u16).to_le_bytes());
+    boot[0x10] = num_fats as u8;
+    boot[0x15] = 0xf8;
+    boot[0x20..0x24].copy_from_slice(&total_sectors.to_le_bytes());
+    boot[0x24..0x28].copy_from_slice(&fat_size.to_le_bytes());
+    boot[0x2c..0x30].copy_from_slice(&root_cluster.to_le_bytes());
+    boot[0x42] = 0x29;
+    boot[0x47..0x52].copy_from_slice(b"NO NAME    ");
+    boot[0x52..0x5a].copy_from_slice(b"FAT32   ");
+    boot[0x1fe..].copy_from_slice(&BOOT_SIGNATURE);
+
+    // Entries 0 and 1 are reserved. Each cluster is followed by the next
+    // unless it ends its chain, which `layout_dir` marked by leaving the
+    // cluster's data one byte too long.
+    let mut fat = vec![0x0fff_fff8, CLUSTER_MASK];
+    for (index, data) in clusters.iter_mut().enumerate() {
+        let cluster = index as u32 + FIRST_CLUSTER;
+        if data.len() > SECTOR_SIZE {
+            data.truncate(SECTOR_SIZE);
+            fat.push(CLUSTER_MASK);
+        } else {
+            fat.push(cluster + 1);
+        }
+    }
+    for copy in 0..num_fats {
+        let start = (reserved_sectors + copy * fat_size) as usize * SECTOR_SIZE;
+        for (i, entry) in fat.iter().enumerate() {
+            image[start + 4 * i..start + 4 * i + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+    for (index, data) in clusters.iter().enumerate() {
+        let start = (data_start as usize + index) * SECTOR_SIZE;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+/// Add `dir`'s clusters, then its children's, to `clusters`. Returns its
+/// first cluster. `parent` is the parent's first cluster, 0 standing for the
+/// root, or `None` if `dir` is the root.
+#[cfg(feature = "alloc")]
+fn layout_dir(dir: &DirTree<'_>, parent: Option<u32>, clusters: &mut Vec<Vec<u8>>) -> u32 {
+    let first = clusters.len() as u32 + FIRST_CLUSTER;
+    let num_clusters = (dir.entries(parent.is_none()) * DIR_ENTRY_SIZE).div_ceil(SECTOR_SIZE);
+    let start = clusters.len();
+    push_chain(clusters, &vec![0; num_clusters * SECTOR_SIZE]);
+
+    let mut entries: Vec<u8> = Vec::new();
+    let dot = |name: &[u8; 11], cluster: u32| short_entry(name, ATTR_DIRECTORY, cluster, 0);
+    if let Some(parent) = parent {
+        entries.extend(dot(b".          ", first));
+        entries.extend(dot(b"..         ", parent));
+    }
+    for (index, (name, child)) in dir.dirs.iter().enumerate() {
+        let cluster = layout_dir(
+            child,
+            Some(if parent.is_none() { 0 } else { first }),
+            clusters,
+        );
+        let short = made_up_short_name(index);
+        entries.extend(long_name_entries(name, &short));
+        entries.extend(short_entry(&short, ATTR_DIRECTORY, cluster, 0));
+    }
+    for (index, (name, data)) in dir.files.iter().enumerate() {
+        let cluster = if data.is_empty() {
+            0
+        } else {
+            let cluster = clusters.len() as u32 + FIRST_CLUSTER;
+            push_chain(clusters, data);
+            cluster
+        };
+        let short = made_up_short_name(dir.dirs.len() + index);
+        entries.extend(long_name_entries(name, &short));
+        entries.extend(short_entry(&short, 0, cluster, data.len() as u32));
+    }
+
+    for (i, chunk) in entries.chunks(SECTOR_SIZE).enumerate() {
+        clusters[start + i][..chunk.len()].copy_from_slice(chunk);
+    }
+    first
+}
+
+/// Add `data`'s clusters, marking the last as ending the chain for
+/// `write_image` by leaving it a byte too long.
+#[cfg(feature = "alloc")]
+fn push_chain(clusters: &mut Vec<Vec<u8>>, data: &[u8]) {
+    let start = clusters.len();
+    for chunk in data.chunks(SECTOR_SIZE) {
+        let mut cluster = chunk.to_vec();
+        cluster.resize(SECTOR_SIZE, 0);
+        clusters.push(cluster);
+    }
+    clusters[start..].last_mut().unwrap().push(0);
+}
+
+#[cfg(feature = "alloc")]
+fn made_up_short_name(index: usize) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..8].copy_from_slice(format!("F{index:07X}").as_bytes());
+    short
+}
+
+#[cfg(feature = "alloc")]
+fn short_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// The long name entries for `name`, in the order they are stored.
+#[cfg(feature = "alloc")]
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    assert!(units.len() <= MAX_NAME_UNITS, "{name:?} is too long");
+    let count = units.len().div_ceil(LONG_NAME_UNITS);
+    if !units.len().is_multiple_of(LONG_NAME_UNITS) {
+        units.push(0);
+    }
+    units.resize(count * LONG_NAME_UNITS, 0xffff);
+
+    let checksum = short_name_checksum(short);
+    let mut entries = Vec::new();
+    for sequence in (1..=count).rev() {
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry[0] = sequence as u8 | if sequence == count { LONG_NAME_LAST } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let part = &units[(sequence - 1) * LONG_NAME_UNITS..sequence * LONG_NAME_UNITS];
+        for (&unit, &offset) in part.iter().zip(&LONG_NAME_OFFSETS) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.extend(entry);
+    }
+    entries
+}
+
+/// Write an MBR with one FAT32 partition of `len` sectors at `start` into
+/// `sector`, keeping its first 446 bytes.
+pub fn write_mbr(sector: &mut [u8; SECTOR_SIZE], start: u32, len: u32) {
+    let entry = &mut sector[0x1be..0x1ce];
+    entry.fill(0);
+    entry[4] = PARTITION_FAT32[1];
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&len.to_le_bytes());
+    sector[0x1ce..0x1fe].fill(0);
+    sector[0x1fe..].copy_from_slice(&BOOT_SIGNATURE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    struct Image(Vec<u8>);
+
+    impl Disk for Image {
+        type Error = &'static str;
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+            let start = usize::try_from(sector).map_err(|_| "too far")? * SECTOR_SIZE;
+            let data = self.0.get(start..start + buf.len()).ok_or("past the end")?;
+            buf.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn read_file<D: Disk>(volume: &Volume<D>, path: &str) -> Result<Vec<u8>, Error<D::Error>> {
+        let node = volume.lookup(path)?;
+        let mut data = vec![0; node.size as usize];
+        let len = volume.read_at(node, 0, &mut data)?;
+        assert_eq!(len, data.len());
+        Ok(data)
+    }
+
+    fn names<D: Disk>(volume: &Volume<D>, path: &str) -> Vec<String>
+    where
+        D::Error: fmt::Debug,
+    {
+        let mut names = Vec::new();
+        let dir = volume.lookup(path).unwrap();
+        volume
+            .read_dir(dir, |entry| {
+                names.push(entry.name.to_string());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        names
+    }
+
+    fn big_file() -> Vec<u8> {
+        (0..3000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn sample() -> Volume<Image> {
+        let big = big_file();
+        let image = write_image(&[
+            ("hello.txt", b"Hello, world!\n"),
+            ("bin/init", b"\x7fELF"),
+            ("docs/A rather long file name.bin", &big),
+            ("empty", b""),
+        ]);
+        Volume::mount(Image(image)).unwrap()
+    }
+
+    #[test]
+    fn reads_files() {
+        let volume = sample();
+        assert_eq!(
+            read_file(&volume, "/hello.txt").unwrap(),
+            b"Hello, world!\n"
+        );
+        assert_eq!(read_file(&volume, "bin/init").unwrap(), b"\x7fELF");
+        assert_eq!(
+            read_file(&volume, "/docs/A rather long file name.bin").unwrap(),
+            big_file()
+        );
+        assert_eq!(read_file(&volume, "empty").unwrap(), b"");
+    }
+
+    #[test]
+    fn lists_directories() {
+        let volume = sample();
+        assert_eq!(names(&volume, "/"), ["bin", "docs", "empty", "hello.txt"]);
+        assert_eq!(
+            names(&volume, "/docs"),
+            [".", "..", "A rather long file name.bin"]
+        );
+        assert_eq!(names(&volume, "/docs/.."), names(&volume, "/"));
+    }
+
+    #[test]
+    fn lookup_ignores_case_and_separators() {
+        let volume = sample();
+        assert_eq!(
+            volume.lookup("//BIN///Init/").unwrap(),
+            volume.lookup("bin/init").unwrap()
+        );
+        assert!(volume.lookup("/").unwrap().is_dir);
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let volume = sample();
+        assert_eq!(volume.lookup("/nope"), Err(Error::NotFound));
+        assert_eq!(volume.lookup("/hello.txt/x"), Err(Error::NotADirectory));
+        let dir = volume.lookup("/bin").unwrap();
+        assert_eq!(
+            volume.read_at(dir, 0, &mut [0; 4]),
+            Err(Error::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn reads_at_offsets() {
+        let volume = sample();
+        let big = big_file();
+        let file = volume.lookup("docs/a rather long file name.BIN").unwrap();
+        // Within a sector, across clusters, and past the end.
+        for (offset, len) in [
+            (0, 10),
+            (500, 30),
+            (1023, 1026),
+            (2990, 100),
+            (3000, 1),
+            (5000, 1),
+        ] {
+            let mut buf = vec![0; len];
+            let read = volume.read_at(file, offset as u64, &mut buf).unwrap();
+            let expected = &big[offset.min(big.len())..(offset + len).min(big.len())];
+            assert_eq!(&buf[..read], expected, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn short_names_without_long_names() {
+        let mut raw = [0; DIR_ENTRY_SIZE];
+        raw[..11].copy_from_slice(b"README  TXT");
+        assert_eq!(short_name(&raw).as_str(), "README.TXT");
+        raw[12] = SHORT_BASE_LOWER;
+        assert_eq!(short_name(&raw).as_str(), "readme.TXT");
+        raw[8..11].copy_from_slice(b"   ");
+        assert_eq!(short_name(&raw).as_str(), "readme");
+    }
+
+    #[test]
+    fn rejects_other_volumes() {
+        assert!(matches!(
+            Volume::mount(Image(vec![0; SECTOR_SIZE])),
+            Err(Error::NotFat32)
+        ));
+        let mut image = write_image(&[("a", b"a")]);
+        // A FAT16 boot sector has a 16 bit FAT size and root entries.
+        image[0x16] = 1;
+        assert!(matches!(Volume::mount(Image(image)), Err(Error::NotFat32)));
+    }
+
+    #[test]
+    fn detects_looping_chains() {
+        let big = big_file();
+        let mut image = write_image(&[("big", &big)]);
+        let volume = Volume::mount(Image(image.clone())).unwrap();
+        let first = volume.lookup("big").unwrap().first_cluster;
+        // Point the file's first cluster at itself.
+        let at = volume.fat_start as usize * SECTOR_SIZE + first as usize * 4;
+        image[at..at + 4].copy_from_slice(&first.to_le_bytes());
+        let volume = Volume::mount(Image(image)).unwrap();
+        let file = volume.lookup("big").unwrap();
+        // Reading follows the chain as far as the file's size says, so reads
+        // the first cluster over and over rather than hanging.
+        let mut buf = vec![0; big.len()];
+        volume.read_at(file, 0, &mut buf).unwrap();
+        assert_eq!(&buf[SECTOR_SIZE..2 * SECTOR_SIZE], &big[..SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn finds_partitions() {
+        let mut mbr = [0; SECTOR_SIZE];
+        assert_eq!(partitions(&mbr).count(), 0);
+        write_mbr(&mut mbr, 2048, 4096);
+        assert_eq!(partitions(&mbr).collect::<Vec<_>>(), [(2048, 4096)]);
+        mbr[0x1be + 4] = 0x83;
+        assert_eq!(partitions(&mbr).count(), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            files in prop::collection::btree_map(
+                "[a-zA-Z0-9 ._-]{1,40}",
+                prop::collection::vec(any::<u8>(), 0..2000),
+                1..20,
+            ),
+        ) {
+            let files: Vec<(String, Vec<u8>)> = files
+                .into_iter()
+                // Names that differ only in case would be the same file.
+                .filter(|(name, _)| name != "." && name != "..")
+                .map(|(name, data)| (format!("d/{name}"), data))
+                .collect();
+            let unique: std::collections::BTreeSet<String> =
+                files.iter().map(|(path, _)| path.to_ascii_lowercase()).collect();
+            prop_assume!(unique.len() == files.len());
+            let refs: Vec<(&str, &[u8])> =
+                files.iter().map(|(path, data)| (path.as_str(), data.as_slice())).collect();
+            let volume = Volume::mount(Image(write_image(&refs))).unwrap();
+            for (path, data) in &files {
+                prop_assert_eq!(&read_file(&volume, path).unwrap(), data);
+            }
+        }
+
+        #[test]
+        fn never_panics(flips in prop::collection::vec((0..4096usize, any::<u8>()), 0..20)) {
+            let mut image = write_image(&[("dir/file", &big_file())]);
+            for (at, byte) in flips {
+                let at = at % image.len();
+                image[at] = byte;
+            }
+            let Ok(volume) = Volume::mount(Image(image)) else {
+                return Ok(());
+            };
+            let mut buf = vec![0; 4096];
+            if let Ok(node) = volume.lookup("dir/file") {
+                let _ = volume.read_at(node, 0, &mut buf);
+            }
+            let _ = volume.read_dir(volume.root(), |_| ControlFlow::Continue(()));
+        }
+    }
+}
@@ -0,0 +1,631 @@
+//! Text console on a linear framebuffer
+//!
+//! `FbConsole` draws text in an embedded 8x13 bitmap font on a direct color
+//! framebuffer, such as one set up by the bootloader, scrolling when the
+//! screen fills. It understands the same ANSI escape sequences as
+//! `vga::VgaWriter`, but has no cursor.
+
+mod font;
+
+use core::fmt::Write;
+
+use crate::ansi;
+
+use font::{GLYPHS, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+const TAB_WIDTH: usize = 8;
+
+/// Where a color channel is in a pixel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ColorField {
+    /// The channel's lowest bit.
+    pub position: u8,
+    /// The channel's width in bits.
+    pub size: u8,
+}
+
+/// A direct color framebuffer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Framebuffer {
+    /// The top left pixel.
+    pub address: *mut u8,
+    /// In pixels.
+    pub width: usize,
+    /// In pixels.
+    pub height: usize,
+    /// Bytes from the start of one row of pixels to the next.
+    pub pitch: usize,
+    pub bits_per_pixel: u8,
+    pub red: ColorField,
+    pub green: ColorField,
+    pub blue: ColorField,
+}
+
+impl Framebuffer {
+    fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// The pixel value for `color`.
+    pub fn encode(&self, color: Rgb) -> u32 {
+        let channel =
+            |value: u8, field: ColorField| (value as u32 >> (8 - field.size)) << field.position;
+        channel(color.red, self.red)
+            | channel(color.green, self.green)
+            | channel(color.blue, self.blue)
+    }
+
+    fn check(&self) -> Result<(), FormatError> {
+        if !matches!(self.bits_per_pixel, 16 | 24 | 32) {
+            return Err(FormatError::BitsPerPixel(self.bits_per_pixel));
+        }
+        for field in [self.red, self.green, self.blue] {
+            if field.size == 0
+                || field.size > 8
+                || field.position as u32 + field.size as u32 > self.bits_per_pixel as u32
+            {
+                return Err(FormatError::ColorField(field));
+            }
+        }
+        if self.width < GLYPH_WIDTH
+            || self.height < GLYPH_HEIGHT
+            || self.pitch < self.width * self.bytes_per_pixel()
+        {
+            return Err(FormatError::Size {
+                width: self.width,
+                height: self.height,
+                pitch: self.pitch,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FormatError {
+    /// Only 16, 24 and 32 bits per pixel are supported.
+    BitsPerPixel(u8),
+    /// Channels must be 1 to 8 bits and fit in a pixel.
+    ColorField(ColorField),
+    /// Too small for one character, or rows overlap.
+    Size {
+        width: usize,
+        height: usize,
+        pitch: usize,
+    },
+}
+
+impl core::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FormatError::BitsPerPixel(bpp) => write!(f, "unsupported {bpp} bits per pixel"),
+            FormatError::ColorField(field) => write!(f, "unsupported color field {field:?}"),
+            FormatError::Size {
+                width,
+                height,
+                pitch,
+            } => write!(f, "unusable size {width}x{height} with pitch {pitch}"),
+        }
+    }
+}
+
+/// A color with 8 bits per channel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+}
+
+/// The ANSI colors, numbered as in `ansi::Sgr`, with the VGA text mode's
+/// shades.
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0xaa, 0x00, 0x00),
+    Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0xff, 0x55, 0x55),
+    Rgb::new(0x55, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0x55, 0x55, 0xff),
+    Rgb::new(0xff, 0x55, 0xff),
+    Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
+const DEFAULT_FOREGROUND: u8 = 7;
+const DEFAULT_BACKGROUND: u8 = 0;
+
+pub struct FbConsole {
+    fb: Framebuffer,
+    rows: usize,
+    cols: usize,
+    /// The cell the next character goes in. May be one past the end of the
+    /// screen, in which case the screen scrolls before the next character.
+    offset: usize,
+    /// ANSI color numbers.
+    foreground: u8,
+    background: u8,
+    /// Bold in SGR terms, shown as the bright version of the foreground.
+    bright: bool,
+    parser: ansi::Parser,
+}
+
+impl FbConsole {
+    /// Create a console drawing on `fb`, and clear the screen.
+    ///
+    /// # Safety
+    /// * `fb.address` must point to `fb.height` rows of `fb.pitch` bytes,
+    ///   writable for as long as the console exists
+    /// * nothing else should draw on the framebuffer meanwhile
+    pub unsafe fn new(fb: Framebuffer) -> Result<FbConsole, FormatError> {
+        fb.check()?;
+
+        let mut console = FbConsole {
+            fb,
+            rows: fb.height / GLYPH_HEIGHT,
+            cols: fb.width / GLYPH_WIDTH,
+            offset: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bright: false,
+            parser: ansi::Parser::new(),
+        };
+        // Also clear the margins the text doesn't cover.
+        let background = console.pixel(DEFAULT_BACKGROUND);
+        console.fill(0, 0, fb.width, fb.height, background);
+        Ok(console)
+    }
+
+    /// The screen size in characters, as (rows, columns).
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn clear(&mut self) {
+        for i in 0..self.rows {
+            self.clear_line(i);
+        }
+
+        self.offset = 0;
+    }
+
+    /// Move to `row` and `col`, counted from 0 and clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.offset = row.min(self.rows - 1) * self.cols + col.min(self.cols - 1);
+    }
+
+    fn pixel(&self, color: u8) -> u32 {
+        self.fb.encode(PALETTE[color as usize])
+    }
+
+    /// Fill a rectangle, given in pixels, with `pixel`.
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, pixel: u32) {
+        for row in y..y + height {
+            for col in x..x + width {
+                unsafe { self.write_pixel(col, row, pixel) };
+            }
+        }
+    }
+
+    /// # Safety
+    /// `x` and `y` must be on the screen.
+    unsafe fn write_pixel(&mut self, x: usize, y: usize, pixel: u32) {
+        let bytes_per_pixel = self.fb.bytes_per_pixel();
+        let bytes = pixel.to_le_bytes();
+        unsafe {
+            let p = self.fb.address.add(y * self.fb.pitch + x * bytes_per_pixel);
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), p, bytes_per_pixel);
+        }
+    }
+
+    fn put(&mut self, cell: usize, c: char) {
+        let glyph = match c {
+            ' '..='~' => &GLYPHS[c as usize - font::FIRST as usize],
+            _ => &GLYPHS['?' as usize - font::FIRST as usize],
+        };
+        let foreground = self.pixel(if self.bright {
+            self.foreground | 8
+        } else {
+            self.foreground
+        });
+        let background = self.pixel(self.background);
+
+        let x = cell % self.cols * GLYPH_WIDTH;
+        let y = cell / self.cols * GLYPH_HEIGHT;
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let pixel = if bits & (0x80 >> dx) != 0 {
+                    foreground
+                } else {
+                    background
+                };
+                unsafe { self.write_pixel(x + dx, y + dy, pixel) };
+            }
+        }
+    }
+
+    /// Clear `cols` of `line` to the background color.
+    fn clear_cols(&mut self, line: usize, cols: core::ops::Range<usize>) {
+        assert!(line < self.rows && cols.end <= self.cols);
+        let background = self.pixel(self.background);
+        self.fill(
+            cols.start * GLYPH_WIDTH,
+            line * GLYPH_HEIGHT,
+            cols.len() * GLYPH_WIDTH,
+            GLYPH_HEIGHT,
+            background,
+        );
+    }
+
+    fn clear_line(&mut self, line: usize) {
+        self.clear_cols(line, 0..self.cols);
+    }
+
+    fn scroll(&mut self, lines: usize) {
+        if lines == 0 {
+            return;
+        }
+
+        let lines = core::cmp::min(lines, self.rows);
+        if lines == self.rows {
+            self.clear();
+            return;
+        }
+
+        let line_bytes = GLYPH_HEIGHT * self.fb.pitch;
+        unsafe {
+            core::ptr::copy(
+                self.fb.address.add(lines * line_bytes),
+                self.fb.address,
+                (self.rows - lines) * line_bytes,
+            );
+        }
+
+        for i in (self.rows - lines)..self.rows {
+            self.clear_line(i);
+        }
+
+        self.offset = self.offset.saturating_sub(lines * self.cols);
+    }
+
+    fn write_char(&mut self, c: char) {
+        let c = match self.parser.feed(c) {
+            ansi::Action::None => return,
+            ansi::Action::Char(c) => c,
+            ansi::Action::Csi { command, params } => {
+                self.run_escape(command, &params);
+                return;
+            }
+        };
+
+        let cells = self.rows * self.cols;
+        match c {
+            '\n' => {
+                if self.offset >= cells {
+                    self.scroll(1);
+                }
+                self.offset = (self.offset / self.cols + 1) * self.cols;
+                return;
+            }
+            '\r' => {
+                self.offset -= self.offset % self.cols;
+                return;
+            }
+            '\x08' => {
+                self.offset = self.offset.saturating_sub(1);
+                return;
+            }
+            '\t' => {
+                if self.offset < cells {
+                    let col = self.offset % self.cols;
+                    let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+                    self.offset += next.min(self.cols) - col;
+                }
+                return;
+            }
+            _ => (),
+        }
+
+        if self.offset >= cells {
+            self.scroll(1);
+            assert!(self.offset < cells);
+        }
+
+        self.put(self.offset, c);
+        self.offset += 1;
+    }
+
+    /// Act on a CSI sequence ending in `command`.
+    fn run_escape(&mut self, command: char, params: &ansi::Params) {
+        let param = |i, default| params.get(i, default);
+
+        match command {
+            'm' => {
+                for sgr in params.sgr() {
+                    match sgr {
+                        ansi::Sgr::Reset => {
+                            self.foreground = DEFAULT_FOREGROUND;
+                            self.background = DEFAULT_BACKGROUND;
+                            self.bright = false;
+                        }
+                        ansi::Sgr::Bold(bright) => self.bright = bright,
+                        ansi::Sgr::Foreground(color) => {
+                            self.foreground = color.unwrap_or(DEFAULT_FOREGROUND)
+                        }
+                        ansi::Sgr::Background(color) => {
+                            self.background = color.unwrap_or(DEFAULT_BACKGROUND)
+                        }
+                    }
+                }
+            }
+            // Cursor position, counted from 1.
+            'H' | 'f' => self.set_position(param(0, 1) as usize - 1, param(1, 1) as usize - 1),
+            // Erase in display. Only erasing everything is supported.
+            'J' if param(0, 0) == 2 => self.clear(),
+            // Erase to the end of the line.
+            'K' if param(0, 0) == 0 && self.offset < self.rows * self.cols => {
+                let (line, col) = (self.offset / self.cols, self.offset % self.cols);
+                self.clear_cols(line, col..self.cols);
+            }
+            _ => (),
+        }
+    }
+}
+
+unsafe impl Send for FbConsole {}
+
+impl Write for FbConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    const ROWS: usize = 3;
+    const COLS: usize = 10;
+    /// Bytes past the end of each row of pixels, which must not be touched.
+    const PADDING: usize = 12;
+    const PADDING_BYTE: u8 = 0xa5;
+
+    const XRGB: (ColorField, ColorField, ColorField) = (
+        ColorField {
+            position: 16,
+            size: 8,
+        },
+        ColorField {
+            position: 8,
+            size: 8,
+        },
+        ColorField {
+            position: 0,
+            size: 8,
+        },
+    );
+
+    struct Screen {
+        pixels: Vec<u8>,
+        fb: Framebuffer,
+        console: FbConsole,
+    }
+
+    impl Screen {
+        /// A screen with a few pixels left over on the right and bottom.
+        fn new() -> Screen {
+            let width = COLS * GLYPH_WIDTH + 3;
+            let height = ROWS * GLYPH_HEIGHT + 5;
+            let pitch = width * 4 + PADDING;
+            let mut pixels = vec![PADDING_BYTE; height * pitch];
+            let fb = Framebuffer {
+                address: pixels.as_mut_ptr(),
+                width,
+                height,
+                pitch,
+                bits_per_pixel: 32,
+                red: XRGB.0,
+                green: XRGB.1,
+                blue: XRGB.2,
+            };
+            let console = unsafe { FbConsole::new(fb) }.unwrap();
+            Screen {
+                pixels,
+                fb,
+                console,
+            }
+        }
+
+        fn pixel(&self, x: usize, y: usize) -> u32 {
+            let i = y * self.fb.pitch + x * 4;
+            u32::from_le_bytes(self.pixels[i..i + 4].try_into().unwrap())
+        }
+
+        /// The glyph drawn in a cell, as pixels that aren't the default
+        /// background.
+        fn glyph(&self, row: usize, col: usize) -> [u8; GLYPH_HEIGHT] {
+            let background = self.fb.encode(PALETTE[DEFAULT_BACKGROUND as usize]);
+            let mut glyph = [0; GLYPH_HEIGHT];
+            for (dy, bits) in glyph.iter_mut().enumerate() {
+                for dx in 0..GLYPH_WIDTH {
+                    let pixel = self.pixel(col * GLYPH_WIDTH + dx, row * GLYPH_HEIGHT + dy);
+                    if pixel != background {
+                        *bits |= 0x80 >> dx;
+                    }
+                }
+            }
+            glyph
+        }
+
+        fn row(&self, row: usize) -> String {
+            let text: String = (0..COLS)
+                .map(|col| {
+                    let glyph = self.glyph(row, col);
+                    let i = GLYPHS.iter().position(|g| *g == glyph).unwrap();
+                    (font::FIRST as u8 + i as u8) as char
+                })
+                .collect();
+            text.trim_end().into()
+        }
+    }
+
+    #[test]
+    fn draws_glyphs() {
+        let mut screen = Screen::new();
+        write!(screen.console, "A~").unwrap();
+        assert_eq!(screen.glyph(0, 0), GLYPHS['A' as usize - 0x20]);
+        assert_eq!(screen.glyph(0, 1), GLYPHS['~' as usize - 0x20]);
+        assert_eq!(screen.row(0), "A~");
+        assert_eq!(screen.console.size(), (ROWS, COLS));
+
+        let padding = screen.fb.width * 4..screen.fb.pitch;
+        for row in screen.pixels.chunks(screen.fb.pitch) {
+            assert!(row[padding.clone()].iter().all(|&b| b == PADDING_BYTE));
+        }
+    }
+
+    #[test]
+    fn lines_and_scrolling() {
+        let mut screen = Screen::new();
+        for i in 0..ROWS {
+            writeln!(screen.console, "line {i}").unwrap();
+        }
+        // The trailing newline doesn't scroll until something is written.
+        assert_eq!(screen.row(0), "line 0");
+        assert_eq!(screen.row(ROWS - 1), "line 2");
+
+        write!(screen.console, "last\nwraps around\u{e9}").unwrap();
+        assert_eq!(screen.row(0), "last");
+        assert_eq!(screen.row(1), "wraps arou");
+        assert_eq!(screen.row(2), "nd?");
+    }
+
+    #[test]
+    fn control_characters_and_escapes() {
+        let mut screen = Screen::new();
+        write!(screen.console, "abc\rX\tY\x08Z").unwrap();
+        assert_eq!(screen.row(0), "Xbc     Z");
+
+        write!(screen.console, "\x1b[2;3Hhi\x1b[1;2H\x1b[K\x1b[?25l").unwrap();
+        assert_eq!(screen.row(0), "X");
+        assert_eq!(screen.row(1), "  hi");
+
+        write!(screen.console, "\x1b[2J").unwrap();
+        assert!((0..ROWS).all(|row| screen.row(row).is_empty()));
+    }
+
+    #[test]
+    fn colors() {
+        let mut screen = Screen::new();
+        write!(screen.console, "\x1b[31m_\x1b[1;44m_\x1b[0m_").unwrap();
+
+        // The underscore's bottom row is set, except the last column, and the
+        // top row is not.
+        let (top, bottom) = (0, 11);
+        assert_eq!(GLYPHS['_' as usize - 0x20][bottom], 0xfe);
+        assert_eq!(screen.pixel(0, bottom), 0xaa0000);
+        assert_eq!(screen.pixel(0, top), 0x000000);
+        assert_eq!(screen.pixel(GLYPH_WIDTH, bottom), 0xff5555);
+        assert_eq!(screen.pixel(GLYPH_WIDTH, top), 0x0000aa);
+        assert_eq!(screen.pixel(2 * GLYPH_WIDTH, bottom), 0xaaaaaa);
+        assert_eq!(screen.pixel(2 * GLYPH_WIDTH, top), 0x000000);
+    }
+
+    #[test]
+    fn pixel_formats() {
+        let mut pixels = vec![0u8; 2 * GLYPH_WIDTH * 3 * GLYPH_HEIGHT];
+        let mut fb = Framebuffer {
+            address: pixels.as_mut_ptr(),
+            width: 2 * GLYPH_WIDTH,
+            height: GLYPH_HEIGHT,
+            pitch: 2 * GLYPH_WIDTH * 3,
+            bits_per_pixel: 24,
+            red: XRGB.0,
+            green: XRGB.1,
+            blue: XRGB.2,
+        };
+        assert_eq!(fb.encode(Rgb::new(0x12, 0x34, 0x56)), 0x123456);
+
+        let mut console = unsafe { FbConsole::new(fb) }.unwrap();
+        write!(console, "\x1b[97m_").unwrap();
+        let bottom = 11 * fb.pitch;
+        assert_eq!(pixels[bottom..bottom + 3], [0xff, 0xff, 0xff]);
+        assert_eq!(
+            pixels[bottom + 6 * 3..bottom + 8 * 3],
+            [0xff, 0xff, 0xff, 0, 0, 0]
+        );
+
+        // RGB 5:6:5.
+        fb.bits_per_pixel = 16;
+        fb.red = ColorField {
+            position: 11,
+            size: 5,
+        };
+        fb.green = ColorField {
+            position: 5,
+            size: 6,
+        };
+        fb.blue = ColorField {
+            position: 0,
+            size: 5,
+        };
+        assert_eq!(fb.encode(Rgb::new(0xff, 0x80, 0x08)), 0xf801 | 0x20 << 5);
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let fb = Framebuffer {
+            address: core::ptr::null_mut(),
+            width: 640,
+            height: 480,
+            pitch: 640 * 4,
+            bits_per_pixel: 32,
+            red: XRGB.0,
+            green: XRGB.1,
+            blue: XRGB.2,
+        };
+        assert_eq!(fb.check(), Ok(()));
+        assert_eq!(
+            Framebuffer {
+                bits_per_pixel: 8,
+                ..fb
+            }
+            .check(),
+            Err(FormatError::BitsPerPixel(8))
+        );
+        let wide = ColorField {
+            position: 20,
+            size: 16,
+        };
+        assert_eq!(
+            Framebuffer { red: wide, ..fb }.check(),
+            Err(FormatError::ColorField(wide))
+        );
+        assert!(matches!(
+            Framebuffer { pitch: 640, ..fb }.check(),
+            Err(FormatError::Size { .. })
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+//! Display adapters for diagnostic output
+//!
+//! `ByteSize` shows a byte count in binary units and `HexDump` shows memory
+//! the way `xxd` does. Neither allocates, so both work in panic handlers.
+
+use core::fmt;
+
+/// A number of bytes, displayed in the largest binary unit it has at least
+/// one of, with one decimal place when inexact: "512 B", "4 KiB", "1.5 GiB".
+/// Fractions are rounded down, so a size is never overstated.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ByteSize(pub u64);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+        let unit = (0..UNITS.len())
+            .rev()
+            .find(|&unit| self.0 >> (10 * unit) > 0)
+            .unwrap_or(0);
+        if unit == 0 {
+            return write!(f, "{} B", self.0);
+        }
+        let tenths = (u128::from(self.0) * 10) >> (10 * unit);
+        match tenths % 10 {
+            0 => write!(f, "{} {}", tenths / 10, UNITS[unit]),
+            frac => write!(f, "{}.{} {}", tenths / 10, frac, UNITS[unit]),
+        }
+    }
+}
+
+/// Bytes per `HexDump` line.
+pub const HEX_DUMP_WIDTH: usize = 16;
+
+/// `bytes` displayed as one line per `HEX_DUMP_WIDTH` bytes: the offset, the
+/// bytes in hex, and the bytes as ASCII with `.` for unprintable ones.
+///
+/// ```text
+/// ffffffff80001000  48 89 e5 0f 0b 00 00 00  48 65 6c 6c 6f 00        |H.......Hello.|
+/// ```
+///
+/// Log each of `lines` separately rather than the whole dump, so every line
+/// gets its own log prefix and no record is longer than a line.
+#[derive(Clone, Copy, Debug)]
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base: u64,
+}
+
+impl<'a> HexDump<'a> {
+    /// A dump of `bytes` with offsets from 0.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        HexDump { bytes, base: 0 }
+    }
+
+    /// Number offsets from `base` instead, e.g. the bytes' address.
+    pub fn with_base(self, base: u64) -> Self {
+        HexDump { base, ..self }
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = HexDumpLine<'a>> + '_ {
+        self.bytes
+            .chunks(HEX_DUMP_WIDTH)
+            .enumerate()
+            .map(|(i, bytes)| HexDumpLine {
+                bytes,
+                offset: self.base.wrapping_add((i * HEX_DUMP_WIDTH) as u64),
+            })
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// One line of a `HexDump`, without a line break.
+#[derive(Clone, Copy, Debug)]
+pub struct HexDumpLine<'a> {
+    bytes: &'a [u8],
+    offset: u64,
+}
+
+impl fmt::Display for HexDumpLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x} ", self.offset)?;
+        for i in 0..HEX_DUMP_WIDTH {
+            if i == HEX_DUMP_WIDTH / 2 {
+                write!(f, " ")?;
+            }
+            match self.bytes.get(i) {
+                Some(b) => write!(f, " {b:02x}")?,
+                None => write!(f, "   ")?,
+            }
+        }
+        write!(f, "  |")?;
+        for &b in self.bytes {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            write!(f, "{c}")?;
+        }
+        write!(f, "|")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::format;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn byte_sizes() {
+        let show = |n| ByteSize(n).to_string();
+        assert_eq!(show(0), "0 B");
+        assert_eq!(show(1023), "1023 B");
+        assert_eq!(show(1024), "1 KiB");
+        assert_eq!(show(1536), "1.5 KiB");
+        assert_eq!(show(2047), "1.9 KiB");
+        assert_eq!(show(640 << 10), "640 KiB");
+        assert_eq!(show((3 << 30) + (256 << 20)), "3.2 GiB");
+        assert_eq!(show(1 << 40), "1 TiB");
+        assert_eq!(show(u64::MAX), "15.9 EiB");
+    }
+
+    #[test]
+    fn hex_dump() {
+        let bytes: Vec<u8> = b"Hello, world!\n\x00\xff"
+            .iter()
+            .copied()
+            .chain(0..4)
+            .collect();
+        let dump = HexDump::new(&bytes).with_base(0xffff_ffff_8000_1000);
+        assert_eq!(
+            format!("{dump}"),
+            "ffffffff80001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n\
+             ffffffff80001010  00 01 02 03                                       |....|"
+        );
+        assert_eq!(dump.lines().count(), 2);
+        assert_eq!(format!("{}", HexDump::new(&[])), "");
+    }
+}
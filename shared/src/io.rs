@@ -0,0 +1,249 @@
+//! Character output through I/O ports
+//!
+//! A `PortCharSink` takes bytes one at a time, or a run at once, and sends
+//! them on, e.g. to QEMU's debug console or a UART. `LineWriter` turns one
+//! into a `core::fmt::Write` that collects whole lines before sending them, so
+//! a sink that locks or polls does so once per line rather than per byte, and
+//! concurrent writers' lines aren't interleaved mid-line. `put_text` is the
+//! unbuffered equivalent for when buffering isn't wanted, such as in a panic.
+
+use core::fmt::Write;
+
+use arrayvec::ArrayVec;
+use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+
+/// Somewhere to send output a byte at a time.
+pub trait PortCharSink {
+    /// Whether line feeds must be sent as CR LF, as terminals on serial lines
+    /// expect.
+    const CRLF: bool = false;
+
+    fn put(&mut self, byte: u8);
+
+    /// Send `bytes` in order. Sinks that lock or wait per call can override
+    /// this to do so once.
+    fn put_all(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.put(byte);
+        }
+    }
+}
+
+/// Send `text` to `sink`, with line feeds translated if it needs.
+pub fn put_text<S: PortCharSink>(sink: &mut S, text: &[u8]) {
+    if !S::CRLF {
+        return sink.put_all(text);
+    }
+    let mut lines = text.split(|&b| b == b'\n');
+    if let Some(first) = lines.next() {
+        sink.put_all(first);
+    }
+    for line in lines {
+        sink.put_all(b"\r\n");
+        sink.put_all(line);
+    }
+}
+
+/// QEMU's debug console, port 0xe9.
+pub struct Debugcon {
+    _phantom: core::marker::PhantomData<*mut u8>,
+}
+
+unsafe impl Send for Debugcon {}
+
+impl Debugcon {
+    /// # Safety
+    ///
+    /// Caller must ensure x86 port 0xe9 is safe to write to.
+    pub const unsafe fn new() -> Self {
+        Debugcon {
+            _phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+impl PortCharSink for Debugcon {
+    fn put(&mut self, byte: u8) {
+        unsafe { PortWriteOnly::new(0xe9).write(byte) };
+    }
+}
+
+/// The first serial port's I/O base.
+pub const COM1: u16 = 0x3f8;
+
+/// A 16550 UART, written to by polling. The port is used as the firmware or
+/// boot loader left it.
+pub struct PolledSerialPort {
+    base: u16,
+    _phantom: core::marker::PhantomData<*mut u8>,
+}
+
+unsafe impl Send for PolledSerialPort {}
+
+impl PolledSerialPort {
+    /// # Safety
+    ///
+    /// Caller must ensure the UART's ports, `base` through `base + 7`, are
+    /// safe to access.
+    pub const unsafe fn new(base: u16) -> Self {
+        PolledSerialPort {
+            base,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+impl PortCharSink for PolledSerialPort {
+    const CRLF: bool = true;
+
+    fn put(&mut self, byte: u8) {
+        const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+        // Don't wait forever: the UART may be missing or misconfigured, and
+        // losing output is better than hanging.
+        let mut status = PortReadOnly::<u8>::new(self.base + 5);
+        for _ in 0..10_000 {
+            if unsafe { status.read() } & LINE_STATUS_TRANSMIT_EMPTY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        unsafe { PortWriteOnly::<u8>::new(self.base).write(byte) };
+    }
+}
+
+/// Writes to a sink a line at a time. A line longer than `N` bytes is sent in
+/// pieces. A partial line is sent by `flush` or when the writer is dropped.
+pub struct LineWriter<S: PortCharSink, const N: usize = 256> {
+    sink: S,
+    line: ArrayVec<u8, N>,
+}
+
+impl<S: PortCharSink, const N: usize> LineWriter<S, N> {
+    pub fn new(sink: S) -> Self {
+        LineWriter {
+            sink,
+            line: ArrayVec::new(),
+        }
+    }
+
+    /// Send whatever has been written since the last line feed.
+    pub fn flush(&mut self) {
+        put_text(&mut self.sink, &self.line);
+        self.line.clear();
+    }
+}
+
+impl<S: PortCharSink, const N: usize> Write for LineWriter<S, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.line.is_full() {
+                self.flush();
+            }
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.flush();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: PortCharSink, const N: usize> Drop for LineWriter<S, N> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    /// Records each call's bytes.
+    #[derive(Default)]
+    struct Plain(Vec<Vec<u8>>);
+
+    impl PortCharSink for Plain {
+        fn put(&mut self, byte: u8) {
+            self.0.push(std::vec![byte]);
+        }
+
+        fn put_all(&mut self, bytes: &[u8]) {
+            self.0.push(bytes.to_vec());
+        }
+    }
+
+    /// Only has `put`, so records everything as one run.
+    #[derive(Default)]
+    struct Terminal(Vec<u8>);
+
+    impl PortCharSink for Terminal {
+        const CRLF: bool = true;
+
+        fn put(&mut self, byte: u8) {
+            self.0.push(byte);
+        }
+    }
+
+    #[test]
+    fn sends_whole_lines() {
+        let mut writer = LineWriter::<_, 16>::new(Plain::default());
+        write!(writer, "one\ntw").unwrap();
+        write!(writer, "o\n\nthree").unwrap();
+        assert_eq!(
+            writer.sink.0,
+            [b"one\n".to_vec(), b"two\n".to_vec(), b"\n".to_vec()]
+        );
+        writer.flush();
+        assert_eq!(writer.sink.0.last().unwrap(), b"three");
+    }
+
+    #[test]
+    fn splits_long_lines() {
+        let mut writer = LineWriter::<_, 4>::new(Plain::default());
+        writeln!(writer, "abcdefghij").unwrap();
+        assert_eq!(
+            writer.sink.0,
+            [b"abcd".to_vec(), b"efgh".to_vec(), b"ij\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn sends_crlf_when_needed() {
+        let mut writer = LineWriter::<_, 8>::new(Terminal::default());
+        write!(writer, "one\ntwo\n\nthree and more").unwrap();
+        writer.flush();
+        assert_eq!(writer.sink.0, b"one\r\ntwo\r\n\r\nthree and more");
+
+        let mut sink = Plain::default();
+        put_text(&mut sink, b"a\nb");
+        assert_eq!(sink.0.concat(), b"a\nb");
+    }
+
+    #[test]
+    fn flushes_when_dropped() {
+        let mut sink = Terminal::default();
+        {
+            let mut writer = LineWriter::<_, 8>::new(&mut sink);
+            write!(writer, "partial").unwrap();
+        }
+        assert_eq!(sink.0, b"partial");
+    }
+
+    impl<S: PortCharSink> PortCharSink for &mut S {
+        const CRLF: bool = S::CRLF;
+
+        fn put(&mut self, byte: u8) {
+            (**self).put(byte);
+        }
+
+        fn put_all(&mut self, bytes: &[u8]) {
+            (**self).put_all(bytes);
+        }
+    }
+}
@@ -0,0 +1,516 @@
+//! Reference counted kernel objects
+//!
+//! `KArc<T>` is a shared pointer like `Arc<T>`, with `KWeak<T>` as its weak
+//! counterpart, but creating one returns an error when the heap is exhausted
+//! instead of aborting, so objects made on behalf of user programs can fail
+//! cleanly.
+//!
+//! Reference cycles keep objects alive forever, which is easy to do by
+//! accident with objects that point at each other. Every live object is
+//! registered with its type name and counts, so `for_each_live` can list what
+//! is still around when everything should have been freed.
+
+use crate::collections::{IntrusiveList, Linked, Links};
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+/// The heap had no room for the object.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "out of memory")
+    }
+}
+
+/// Counts are capped well below overflow, as with `Arc`, so leaked clones
+/// panic instead of wrapping around to a use after free.
+const MAX_COUNT: usize = isize::MAX as usize;
+
+/// The part of every object that doesn't depend on its type, first so the
+/// registry can point at it.
+struct Header {
+    links: Links<Header>,
+    strong: AtomicUsize,
+    /// Weak references, plus one shared by all the strong ones.
+    weak: AtomicUsize,
+    type_name: &'static str,
+}
+
+// SAFETY: the links are only touched with `LIVE` locked, and the rest is
+// atomic or immutable.
+unsafe impl Send for Header {}
+
+// SAFETY: `links` is only used by `LIVE`.
+unsafe impl Linked for Header {
+    fn links(this: NonNull<Self>) -> NonNull<Links<Self>> {
+        // SAFETY: `this` points to a valid `Header`.
+        unsafe { NonNull::new_unchecked(ptr::addr_of_mut!((*this.as_ptr()).links)) }
+    }
+}
+
+#[repr(C)]
+struct Inner<T> {
+    header: Header,
+    value: T,
+}
+
+/// Objects whose values haven't been dropped.
+static LIVE: spin::Mutex<IntrusiveList<Header>> = spin::Mutex::new(IntrusiveList::new());
+
+/// An object that is live, for `for_each_live`.
+#[derive(Clone, Copy, Debug)]
+pub struct LiveObject {
+    pub type_name: &'static str,
+    pub strong: usize,
+    /// Excluding the one the strong references share.
+    pub weak: usize,
+}
+
+/// Call `f` for each object with strong references. Objects made or freed
+/// meanwhile wait until it returns, so `f` must not make or free any.
+pub fn for_each_live(mut f: impl FnMut(LiveObject)) {
+    let live = LIVE.lock();
+    for header in live.iter() {
+        // SAFETY: objects are removed from the list before they are freed,
+        // which waits for the lock.
+        let header = unsafe { header.as_ref() };
+        f(LiveObject {
+            type_name: header.type_name,
+            strong: header.strong.load(Ordering::Relaxed),
+            weak: header.weak.load(Ordering::Relaxed).saturating_sub(1),
+        });
+    }
+}
+
+/// A strong reference to a heap allocated `T`.
+pub struct KArc<T> {
+    inner: NonNull<Inner<T>>,
+    phantom: PhantomData<Inner<T>>,
+}
+
+// SAFETY: as for `Arc`.
+unsafe impl<T: Send + Sync> Send for KArc<T> {}
+unsafe impl<T: Send + Sync> Sync for KArc<T> {}
+
+impl<T> KArc<T> {
+    /// Move `value` to the heap, or return it if there is no room.
+    pub fn try_new(value: T) -> Result<KArc<T>, (OutOfMemory, T)> {
+        let layout = Layout::new::<Inner<T>>();
+        // SAFETY: `Inner` contains a `Header`, so isn't zero sized.
+        let Some(inner) = NonNull::new(unsafe { alloc(layout) }.cast::<Inner<T>>()) else {
+            return Err((OutOfMemory, value));
+        };
+        // SAFETY: just allocated with `Inner`'s layout.
+        unsafe {
+            inner.as_ptr().write(Inner {
+                header: Header {
+                    links: Links::new(),
+                    strong: AtomicUsize::new(1),
+                    weak: AtomicUsize::new(1),
+                    type_name: core::any::type_name::<T>(),
+                },
+                value,
+            });
+            LIVE.lock().push_back(inner.cast());
+        }
+        Ok(KArc {
+            inner,
+            phantom: PhantomData,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: a strong reference keeps the allocation alive.
+        unsafe { &self.inner.as_ref().header }
+    }
+
+    pub fn downgrade(this: &KArc<T>) -> KWeak<T> {
+        let weak = &this.header().weak;
+        let mut count = weak.load(Ordering::Relaxed);
+        loop {
+            // Another strong reference's `get_mut` has locked the count.
+            if count == usize::MAX {
+                core::hint::spin_loop();
+                count = weak.load(Ordering::Relaxed);
+                continue;
+            }
+            assert!(count < MAX_COUNT, "too many weak references");
+            match weak.compare_exchange_weak(count, count + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    return KWeak {
+                        inner: this.inner,
+                        phantom: PhantomData,
+                    }
+                }
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    pub fn strong_count(this: &KArc<T>) -> usize {
+        this.header().strong.load(Ordering::Relaxed)
+    }
+
+    /// Excluding the one the strong references share.
+    pub fn weak_count(this: &KArc<T>) -> usize {
+        this.header().weak.load(Ordering::Relaxed) - 1
+    }
+
+    /// Whether `a` and `b` refer to the same object.
+    pub fn ptr_eq(a: &KArc<T>, b: &KArc<T>) -> bool {
+        a.inner == b.inner
+    }
+
+    /// A mutable reference to the value, if there are no other references,
+    /// strong or weak.
+    pub fn get_mut(this: &mut KArc<T>) -> Option<&mut T> {
+        // Lock out upgrades, as `Arc` does, so a weak reference can't become
+        // strong between the checks.
+        let header = this.header();
+        if header
+            .weak
+            .compare_exchange(1, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let unique = header.strong.load(Ordering::Acquire) == 1;
+        header.weak.store(1, Ordering::Release);
+        if !unique {
+            return None;
+        }
+        // SAFETY: this is the only reference.
+        Some(unsafe { &mut this.inner.as_mut().value })
+    }
+
+    /// The value, if this is the only strong reference. Weak references to it
+    /// can no longer be upgraded.
+    pub fn try_unwrap(this: KArc<T>) -> Result<T, KArc<T>> {
+        if this
+            .header()
+            .strong
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        atomic::fence(Ordering::Acquire);
+        let this = core::mem::ManuallyDrop::new(this);
+        // SAFETY: the strong count is now 0, so nothing else reads the value,
+        // and the shared weak reference keeps the allocation alive until it
+        // is dropped below.
+        unsafe {
+            unregister(this.inner);
+            let value = ptr::addr_of!((*this.inner.as_ptr()).value).read();
+            drop(KWeak {
+                inner: this.inner,
+                phantom: PhantomData,
+            });
+            Ok(value)
+        }
+    }
+
+    /// Give up the reference without dropping it, returning a pointer to the
+    /// value. `from_raw` takes it back.
+    pub fn into_raw(this: KArc<T>) -> *const T {
+        let this = core::mem::ManuallyDrop::new(this);
+        // SAFETY: the allocation is alive.
+        unsafe { ptr::addr_of!((*this.inner.as_ptr()).value) }
+    }
+
+    /// Take back a reference given up with `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` with the same `T`, and be passed here
+    /// only once.
+    pub unsafe fn from_raw(ptr: *const T) -> KArc<T> {
+        let offset = core::mem::offset_of!(Inner<T>, value);
+        // SAFETY: `ptr` points `offset` bytes into an `Inner<T>`.
+        let inner = unsafe { ptr.byte_sub(offset) }
+            .cast::<Inner<T>>()
+            .cast_mut();
+        KArc {
+            // SAFETY: derived from a valid pointer.
+            inner: unsafe { NonNull::new_unchecked(inner) },
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for KArc<T> {
+    fn clone(&self) -> KArc<T> {
+        let old = self.header().strong.fetch_add(1, Ordering::Relaxed);
+        assert!(old < MAX_COUNT, "too many strong references");
+        KArc {
+            inner: self.inner,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for KArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: a strong reference keeps the value alive.
+        unsafe { &self.inner.as_ref().value }
+    }
+}
+
+impl<T> Drop for KArc<T> {
+    fn drop(&mut self) {
+        if self.header().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        // SAFETY: that was the last strong reference. The shared weak
+        // reference keeps the allocation alive until it is dropped here.
+        unsafe {
+            unregister(self.inner);
+            ptr::drop_in_place(ptr::addr_of_mut!((*self.inner.as_ptr()).value));
+            drop(KWeak {
+                inner: self.inner,
+                phantom: PhantomData,
+            });
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for KArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for KArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// Remove the object from the registry of live ones.
+///
+/// # Safety
+///
+/// `inner` must be registered, and its strong count 0.
+unsafe fn unregister<T>(inner: NonNull<Inner<T>>) {
+    unsafe { LIVE.lock().remove(inner.cast()) };
+}
+
+/// A reference that doesn't keep the value alive, only its allocation.
+pub struct KWeak<T> {
+    inner: NonNull<Inner<T>>,
+    phantom: PhantomData<Inner<T>>,
+}
+
+// SAFETY: as for `Weak`.
+unsafe impl<T: Send + Sync> Send for KWeak<T> {}
+unsafe impl<T: Send + Sync> Sync for KWeak<T> {}
+
+impl<T> KWeak<T> {
+    fn header(&self) -> &Header {
+        // SAFETY: a weak reference keeps the allocation alive.
+        unsafe { &self.inner.as_ref().header }
+    }
+
+    /// A strong reference, unless the value has been dropped.
+    pub fn upgrade(&self) -> Option<KArc<T>> {
+        let strong = &self.header().strong;
+        let mut count = strong.load(Ordering::Relaxed);
+        loop {
+            if count == 0 {
+                return None;
+            }
+            assert!(count < MAX_COUNT, "too many strong references");
+            match strong.compare_exchange_weak(
+                count,
+                count + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(KArc {
+                        inner: self.inner,
+                        phantom: PhantomData,
+                    })
+                }
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.header().strong.load(Ordering::Relaxed)
+    }
+
+    /// Whether `self` and `other` refer to the same object.
+    pub fn ptr_eq(&self, other: &KWeak<T>) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl<T> Clone for KWeak<T> {
+    fn clone(&self) -> KWeak<T> {
+        // `get_mut` only locks the count while there are no weak references,
+        // so it can't be locked now.
+        let old = self.header().weak.fetch_add(1, Ordering::Relaxed);
+        assert!(old < MAX_COUNT, "too many weak references");
+        KWeak {
+            inner: self.inner,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for KWeak<T> {
+    fn drop(&mut self) {
+        if self.header().weak.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        // SAFETY: that was the last reference of any kind, and the value was
+        // dropped with the last strong one.
+        unsafe { dealloc(self.inner.as_ptr().cast(), Layout::new::<Inner<T>>()) };
+    }
+}
+
+impl<T> fmt::Debug for KWeak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(KWeak)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+    use pretty_assertions::assert_eq;
+
+    /// Records that it was dropped.
+    struct DropFlag<'a>(&'a AtomicBool);
+
+    impl Drop for DropFlag<'_> {
+        fn drop(&mut self) {
+            assert!(!self.0.swap(true, Ordering::SeqCst), "dropped twice");
+        }
+    }
+
+    fn karc<T>(value: T) -> KArc<T> {
+        KArc::try_new(value).map_err(|(e, _)| e).unwrap()
+    }
+
+    #[test]
+    fn drops_with_last_strong_reference() {
+        let dropped = AtomicBool::new(false);
+        let a = karc(DropFlag(&dropped));
+        let b = a.clone();
+        let weak = KArc::downgrade(&a);
+        assert_eq!((KArc::strong_count(&a), KArc::weak_count(&a)), (2, 1));
+        drop(a);
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert!(weak.upgrade().is_some());
+        drop(b);
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+    }
+
+    #[test]
+    fn get_mut_needs_unique_reference() {
+        let mut a = karc(1);
+        *KArc::get_mut(&mut a).unwrap() += 1;
+        let weak = KArc::downgrade(&a);
+        assert_eq!(KArc::get_mut(&mut a), None);
+        drop(weak);
+        let b = a.clone();
+        assert_eq!(KArc::get_mut(&mut a), None);
+        drop(b);
+        assert_eq!(KArc::get_mut(&mut a), Some(&mut 2));
+    }
+
+    #[test]
+    fn try_unwrap() {
+        let a = karc(alloc::string::String::from("x"));
+        let b = a.clone();
+        let weak = KArc::downgrade(&a);
+        let a = KArc::try_unwrap(a).unwrap_err();
+        drop(b);
+        assert_eq!(KArc::try_unwrap(a).unwrap(), "x");
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let a = karc(7u64);
+        let b = a.clone();
+        let raw = KArc::into_raw(a);
+        assert_eq!(unsafe { *raw }, 7);
+        let a = unsafe { KArc::from_raw(raw) };
+        assert!(KArc::ptr_eq(&a, &b));
+        assert_eq!(KArc::strong_count(&a), 2);
+    }
+
+    /// Only used here, so other tests' objects don't show up.
+    struct Node {
+        next: spin::Mutex<Option<KArc<Node>>>,
+    }
+
+    fn live_nodes() -> Vec<usize> {
+        let mut strong = Vec::new();
+        for_each_live(|object| {
+            if object.type_name.ends_with("karc::tests::Node") {
+                strong.push(object.strong);
+            }
+        });
+        strong
+    }
+
+    #[test]
+    fn cycles_stay_live() {
+        let a = karc(Node {
+            next: spin::Mutex::new(None),
+        });
+        let b = karc(Node {
+            next: spin::Mutex::new(Some(a.clone())),
+        });
+        *a.next.lock() = Some(b.clone());
+        assert_eq!(live_nodes(), [2, 2]);
+
+        // Breaking the cycle frees both.
+        a.next.lock().take();
+        drop((a, b));
+        assert_eq!(live_nodes(), []);
+    }
+
+    #[test]
+    fn concurrent_clones() {
+        let dropped = AtomicBool::new(false);
+        let a = karc(DropFlag(&dropped));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let a = a.clone();
+                s.spawn(move || {
+                    let weak = KArc::downgrade(&a);
+                    for _ in 0..1000 {
+                        let b = weak.upgrade().unwrap();
+                        drop(b.clone());
+                    }
+                });
+            }
+        });
+        assert_eq!((KArc::strong_count(&a), KArc::weak_count(&a)), (1, 0));
+        drop(a);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
@@ -2,15 +2,10 @@
 //!
 //! This crate contains code that is either shared by both the kernel and
 //! loader, or is fairly self-contained. Unit testing is a big motivation for
-//! this crate.
+//! this crate. Memory management lives in its own crate, `testos-memory`.
 //!
-#![feature(allocator_api)]
 #![feature(const_option)]
 #![feature(int_roundings)]
-#![feature(maybe_uninit_slice)]
-#![feature(pointer_is_aligned)]
-#![feature(ptr_metadata)]
-#![feature(slice_ptr_len)]
 #![deny(unsafe_op_in_unsafe_fn)]
 #![cfg_attr(not(test), no_std)]
 
@@ -20,6 +15,23 @@ extern crate alloc;
 #[cfg(test)]
 extern crate std;
 
+pub mod acpi;
+pub mod ansi;
+pub mod boot_metrics;
+pub mod cmdline;
+pub mod collections;
+pub mod cpio;
+pub mod elf64;
+pub mod fat;
+pub mod fb;
+pub mod fmt;
+pub mod io;
+#[cfg(feature = "alloc")]
+pub mod karc;
 pub mod log;
-pub mod memory;
+pub mod mmio;
+pub mod sched;
+pub mod test_disk;
+pub mod timer;
+pub mod trace;
 pub mod vga;
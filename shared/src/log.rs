@@ -1,7 +1,18 @@
 //! Basic logging facilities used with the `log` crate.
 
+mod filter;
+mod history;
+mod ring;
+
+pub use filter::{Target, TargetFilter, UnknownTarget};
+pub use history::RingBufferSink;
+pub use ring::LogRing;
+
 use core::fmt::Write;
 use core::marker::Send;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::io::{put_text, Debugcon, PolledSerialPort, COM1};
 
 use log::{Level, Log, Metadata, Record};
 use spin::Mutex;
@@ -14,6 +25,12 @@ pub trait LogExt {
     fn is_locked(&self) -> bool;
 }
 
+impl<L: LogExt + ?Sized> LogExt for &L {
+    fn is_locked(&self) -> bool {
+        (**self).is_locked()
+    }
+}
+
 /// Writes formatted log messages to any `core::fmt::Write` impl. Locks
 /// internally.
 pub struct LogSink<W> {
@@ -55,14 +72,93 @@ impl<W: Write + Send> LogExt for LogSink<W> {
     }
 }
 
+/// Like `LogSink`, but safe to log to from any context, including interrupt
+/// handlers and while another task is in the middle of logging.
+///
+/// Records are formatted into a lock-free `LogRing`. Whoever gets hold of the
+/// writer then drains the ring; if the writer is busy, the current holder or a
+/// later `log` or `flush` call writes the record out instead.
+pub struct RingLogSink<W, const SLOTS: usize = 64, const LEN: usize = 256> {
+    ring: LogRing<SLOTS, LEN>,
+    writer: Mutex<W>,
+    /// The value of `ring.dropped()` last reported to `writer`.
+    reported_dropped: AtomicUsize,
+}
+
+impl<W: Write + Send, const SLOTS: usize, const LEN: usize> RingLogSink<W, SLOTS, LEN> {
+    pub fn new(writer: W) -> Self {
+        RingLogSink {
+            ring: LogRing::new(),
+            writer: Mutex::new(writer),
+            reported_dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Write out queued records, unless someone else is already doing so.
+    pub fn drain(&self) {
+        // A record pushed after the holder's last pop but before it unlocks
+        // would otherwise sit in the ring until the next drain, so check
+        // again after unlocking.
+        while self.ring.has_pending() {
+            let Some(mut writer) = self.writer.try_lock() else {
+                return;
+            };
+
+            // SAFETY: holding `writer` makes us the only consumer.
+            let mut write_msg = |msg: &str| {
+                let _ = writeln!(&mut writer, "{msg}");
+            };
+            while unsafe { self.ring.pop(&mut write_msg) } {}
+
+            let dropped = self.ring.dropped();
+            let reported = self.reported_dropped.swap(dropped, Ordering::Relaxed);
+            if dropped != reported {
+                let _ = writeln!(
+                    &mut writer,
+                    "[{}] log: {} records dropped",
+                    level_as_string(Level::Warn),
+                    dropped - reported
+                );
+            }
+        }
+    }
+}
+
+impl<W: Write + Send, const SLOTS: usize, const LEN: usize> Log for RingLogSink<W, SLOTS, LEN> {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.ring.push(format_args!(
+            "[{}] {}: {}",
+            level_as_string(record.level()),
+            record.target(),
+            record.args()
+        ));
+        self.drain();
+    }
+
+    fn flush(&self) {
+        self.drain();
+    }
+}
+
+impl<W: Write + Send, const SLOTS: usize, const LEN: usize> LogExt for RingLogSink<W, SLOTS, LEN> {
+    fn is_locked(&self) -> bool {
+        self.writer.is_locked()
+    }
+}
+
+/// The level's name, colored with ANSI escape sequences.
 fn level_as_string(level: Level) -> &'static str {
     use Level::*;
 
     match level {
-        Error => "ERROR",
-        Warn => " WARN",
-        Info => " INFO",
-        Debug => "DEBUG",
+        Error => "\x1b[91mERROR\x1b[0m",
+        Warn => "\x1b[93m WARN\x1b[0m",
+        Info => "\x1b[92m INFO\x1b[0m",
+        Debug => "\x1b[96mDEBUG\x1b[0m",
         Trace => "TRACE",
     }
 }
@@ -93,28 +189,140 @@ impl<L1: LogExt, L2: LogExt> LogExt for LogTee<L1, L2> {
     }
 }
 
-/// Writes to QEMU's debug out port.
-pub struct QemuDebugWriter {
-    _phantom: core::marker::PhantomData<*mut u8>,
+/// Forwards records to `L` with their message prefixed by the name `context`
+/// returns, if any, e.g. the running task's.
+pub struct ContextLogger<L> {
+    inner: L,
+    context: fn() -> Option<&'static str>,
 }
 
-unsafe impl Send for QemuDebugWriter {}
+impl<L> ContextLogger<L> {
+    pub const fn new(inner: L, context: fn() -> Option<&'static str>) -> Self {
+        ContextLogger { inner, context }
+    }
+}
+
+impl<L: Log> Log for ContextLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(name) = (self.context)() else {
+            return self.inner.log(record);
+        };
+        self.inner.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("[{name}] {}", record.args()))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl<L: LogExt> LogExt for ContextLogger<L> {
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+/// Forwards the same output to two writers, in order.
+pub struct WriteTee<W1, W2>(pub W1, pub W2);
+
+impl<W1: Write, W2: Write> Write for WriteTee<W1, W2> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let r1 = self.0.write_str(s);
+        let r2 = self.1.write_str(s);
+        r1.and(r2)
+    }
+}
 
-impl QemuDebugWriter {
+/// Last-resort output for panics. Writes straight to the first serial port
+/// and, optionally, QEMU's debug out port, unbuffered. Takes no locks, needs
+/// no allocation or initialization, and so works at any point during boot.
+pub struct EmergencyWriter {
+    debugcon: Option<Debugcon>,
+    serial: PolledSerialPort,
+}
+
+impl EmergencyWriter {
+    /// Create a writer. If `debugcon` is set, output is also written to port
+    /// 0xe9.
+    ///
     /// # Safety
     ///
-    /// Caller must ensure x86 port 0xe9 is safe to write to.
-    pub unsafe fn new() -> Self {
-        QemuDebugWriter {
-            _phantom: core::marker::PhantomData,
+    /// Caller must ensure the COM1 ports (0x3f8-0x3ff), and port 0xe9 if
+    /// `debugcon` is set, are safe to access.
+    pub unsafe fn new(debugcon: bool) -> Self {
+        EmergencyWriter {
+            debugcon: debugcon.then(|| unsafe { Debugcon::new() }),
+            serial: unsafe { PolledSerialPort::new(COM1) },
         }
     }
 }
 
-impl Write for QemuDebugWriter {
+impl Write for EmergencyWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut port = x86_64::instructions::port::PortWriteOnly::new(0xe9);
-        s.bytes().for_each(|b| unsafe { port.write(b) });
+        if let Some(debugcon) = &mut self.debugcon {
+            put_text(debugcon, s.as_bytes());
+        }
+        put_text(&mut self.serial, s.as_bytes());
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<String>>);
+
+    impl Log for Collect {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(std::format!("{} {}", record.target(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn context_prefixes_messages() {
+        fn log(logger: &impl Log) {
+            logger.log(
+                &Record::builder()
+                    .target("sched")
+                    .args(format_args!("hello {}", 1))
+                    .build(),
+            );
+        }
+
+        let named = ContextLogger::new(Collect::default(), || Some("flusher"));
+        log(&named);
+        assert_eq!(*named.inner.0.lock().unwrap(), ["sched [flusher] hello 1"]);
+
+        let unnamed = ContextLogger::new(Collect::default(), || None);
+        log(&unnamed);
+        assert_eq!(*unnamed.inner.0.lock().unwrap(), ["sched hello 1"]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Per-target log levels that can be changed at runtime.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use super::LogExt;
+
+/// `Target::level` value meaning the filter's default level applies.
+const INHERIT: usize = usize::MAX;
+
+/// A named log target with its own level. Log to it with
+/// `log::info!(target: TARGET.name(), ...)` and register it with a
+/// `TargetFilter`.
+///
+/// The filter recognizes a target by the address of the string `name`
+/// returns, not its contents, so records must use that string and not an
+/// equal literal.
+///
+/// A module target instead covers the records logged without an explicit
+/// target in a module and its submodules, whose target is the module path.
+pub struct Target {
+    name: &'static str,
+    /// For a module target, the module path.
+    module: Option<&'static str>,
+    /// A `LevelFilter` as `usize`, or `INHERIT`.
+    level: AtomicUsize,
+}
+
+impl Target {
+    /// A target that uses the filter's default level until set otherwise.
+    pub const fn new(name: &'static str) -> Target {
+        Target {
+            name,
+            module: None,
+            level: AtomicUsize::new(INHERIT),
+        }
+    }
+
+    /// A target called `name` for the module at `path`, e.g. `kernel::mm`,
+    /// and its submodules.
+    pub const fn module(name: &'static str, path: &'static str) -> Target {
+        Target {
+            name,
+            module: Some(path),
+            level: AtomicUsize::new(INHERIT),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether `target`, from a record's metadata, is this target.
+    fn is(&self, target: &str) -> bool {
+        match self.module {
+            None => core::ptr::eq(self.name, target),
+            Some(path) => target
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::")),
+        }
+    }
+
+    /// Which of the targets a record is for wins: named targets over modules,
+    /// and submodules over their parents.
+    fn specificity(&self) -> usize {
+        self.module.map_or(usize::MAX, str::len)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnknownTarget;
+
+impl core::fmt::Display for UnknownTarget {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unknown log target")
+    }
+}
+
+/// Passes records on to another logger if their level is enabled for their
+/// target. Records for unregistered targets, such as module paths without a
+/// module target, use the default level.
+///
+/// Checking a record compares its target with each registered target's, so
+/// keep the registry small. `max_level` should be passed to
+/// `log::set_max_level` after changing any level so the `log` macros skip
+/// disabled records early.
+pub struct TargetFilter<L> {
+    inner: L,
+    targets: &'static [&'static Target],
+    default: AtomicUsize,
+}
+
+impl<L: Log> TargetFilter<L> {
+    pub fn new(inner: L, targets: &'static [&'static Target], default: LevelFilter) -> Self {
+        TargetFilter {
+            inner,
+            targets,
+            default: AtomicUsize::new(default as usize),
+        }
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// The registered targets, in registration order.
+    pub fn targets(&self) -> impl Iterator<Item = &'static Target> + '_ {
+        self.targets.iter().copied()
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        level_from_usize(self.default.load(Ordering::Relaxed))
+    }
+
+    pub fn set_default_level(&self, level: LevelFilter) {
+        self.default.store(level as usize, Ordering::Relaxed);
+    }
+
+    /// The level in effect for `target`.
+    pub fn level(&self, target: &Target) -> LevelFilter {
+        match target.level.load(Ordering::Relaxed) {
+            INHERIT => self.default_level(),
+            level => level_from_usize(level),
+        }
+    }
+
+    /// Set the level of the registered target called `name`. `None` makes it
+    /// follow the default level again.
+    pub fn set_level(&self, name: &str, level: Option<LevelFilter>) -> Result<(), UnknownTarget> {
+        let target = self
+            .targets()
+            .find(|t| t.name == name)
+            .ok_or(UnknownTarget)?;
+        let level = level.map_or(INHERIT, |l| l as usize);
+        target.level.store(level, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The most verbose level any target has.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets()
+            .map(|t| self.level(t))
+            .fold(self.default_level(), Ord::max)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        match self
+            .targets()
+            .filter(|t| t.is(target))
+            .max_by_key(|t| t.specificity())
+        {
+            Some(t) => self.level(t),
+            None => self.default_level(),
+        }
+    }
+}
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    LevelFilter::iter().nth(level).unwrap()
+}
+
+impl<L: Log> Log for TargetFilter<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl<L: LogExt> LogExt for TargetFilter<L> {
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use log::Level;
+    use pretty_assertions::assert_eq;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<String>>);
+
+    impl Log for Collect {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(std::format!("{} {}", record.target(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &impl Log, target: &str, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .target(target)
+                .level(level)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn levels_per_target() {
+        static DISK: Target = Target::new("disk");
+        static NET: Target = Target::new("net");
+        static TARGETS: [&Target; 2] = [&DISK, &NET];
+
+        let filter = TargetFilter::new(Collect::default(), &TARGETS, LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+
+        filter.set_level("disk", Some(LevelFilter::Debug)).unwrap();
+        filter.set_level("net", Some(LevelFilter::Off)).unwrap();
+        assert_eq!(filter.set_level("gpu", None), Err(UnknownTarget));
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        log(&filter, DISK.name(), Level::Debug, "a");
+        log(&filter, DISK.name(), Level::Trace, "b");
+        log(&filter, NET.name(), Level::Error, "c");
+        log(&filter, "kernel::sched", Level::Info, "d");
+        log(&filter, "kernel::sched", Level::Debug, "e");
+        // An equal string that isn't the target's name isn't the target.
+        log(&filter, &String::from("net"), Level::Info, "f");
+
+        // Back to following the default.
+        filter.set_level("net", None).unwrap();
+        filter.set_default_level(LevelFilter::Warn);
+        log(&filter, NET.name(), Level::Warn, "g");
+        log(&filter, NET.name(), Level::Info, "h");
+        assert_eq!(filter.level(&NET), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!(
+            *filter.inner().0.lock().unwrap(),
+            ["disk a", "kernel::sched d", "net f", "net g"]
+        );
+    }
+
+    #[test]
+    fn module_targets() {
+        static MM: Target = Target::module("mm", "kernel::mm");
+        static PAGING: Target = Target::module("paging", "kernel::mm::paging");
+        static PAGES: Target = Target::new("pages");
+        static TARGETS: [&Target; 3] = [&MM, &PAGING, &PAGES];
+
+        let filter = TargetFilter::new(Collect::default(), &TARGETS, LevelFilter::Warn);
+        filter.set_level("mm", Some(LevelFilter::Debug)).unwrap();
+        filter
+            .set_level("paging", Some(LevelFilter::Error))
+            .unwrap();
+
+        log(&filter, "kernel::mm", Level::Debug, "a");
+        log(&filter, "kernel::mm::address_space", Level::Debug, "b");
+        log(&filter, "kernel::mm::paging", Level::Warn, "c");
+        log(&filter, "kernel::mm::paging::walk", Level::Error, "d");
+        // Not a submodule.
+        log(&filter, "kernel::mmio", Level::Debug, "e");
+        // A named target wins over the module it's logged from.
+        log(&filter, PAGES.name(), Level::Info, "f");
+        log(&filter, "kernel::sched", Level::Warn, "g");
+
+        assert_eq!(
+            *filter.inner().0.lock().unwrap(),
+            [
+                "kernel::mm a",
+                "kernel::mm::address_space b",
+                "kernel::mm::paging::walk d",
+                "kernel::sched g"
+            ]
+        );
+    }
+}
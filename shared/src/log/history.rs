@@ -0,0 +1,290 @@
+//! A log sink that keeps the most recent records in memory.
+
+use super::{level_as_string, LogExt};
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{Log, Metadata, Record};
+use spin::Mutex;
+
+/// Ends each record in the buffer. Records may span several lines, so a line
+/// break can't be used.
+const SEPARATOR: u8 = 0;
+
+/// Keeps the last `SIZE` bytes of formatted log records, evicting the oldest
+/// whole records to make room, so a panic handler can show what led up to it.
+/// Records longer than `SIZE - 2` bytes are truncated.
+///
+/// Everything is in the sink itself, so it can be a `static` and used before
+/// there is a heap. Combine it with the sink that writes records out using a
+/// `LogTee`.
+///
+/// A record logged while the buffer is in use, e.g. from an interrupt handler
+/// that interrupted another `log` call, is counted instead of stored so that
+/// logging never blocks.
+pub struct RingBufferSink<const SIZE: usize> {
+    history: Mutex<History<SIZE>>,
+    /// Records not stored because the buffer was in use.
+    missed: AtomicUsize,
+}
+
+impl<const SIZE: usize> RingBufferSink<SIZE> {
+    pub const fn new() -> Self {
+        assert!(SIZE > 1);
+        RingBufferSink {
+            history: Mutex::new(History {
+                buf: [0; SIZE],
+                head: 0,
+                len: 0,
+                record_len: 0,
+            }),
+            missed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Write the stored records to `writer`, most recent first, one per line.
+    /// Doesn't wait for the buffer, so it is safe to call from a panic handler
+    /// even if the panic happened while storing a record.
+    pub fn dump(&self, writer: &mut impl Write) -> fmt::Result {
+        let Some(history) = self.history.try_lock() else {
+            return writeln!(writer, "(log history is in use)");
+        };
+        history.for_each_newest_first(|first, second| {
+            write_split(writer, first, second)?;
+            writeln!(writer)
+        })?;
+        match self.missed.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            missed => writeln!(writer, "({missed} records not kept)"),
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for RingBufferSink<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> Log for RingBufferSink<SIZE> {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(mut history) = self.history.try_lock() else {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let _ = write!(
+            history,
+            "[{}] {}: {}",
+            level_as_string(record.level()),
+            record.target(),
+            record.args()
+        );
+        history.end_record();
+    }
+
+    fn flush(&self) {}
+}
+
+impl<const SIZE: usize> LogExt for RingBufferSink<SIZE> {
+    /// Always false: `log` never waits for the buffer.
+    fn is_locked(&self) -> bool {
+        false
+    }
+}
+
+/// A ring of bytes holding whole records, each followed by `SEPARATOR`, and
+/// then the record being written.
+struct History<const SIZE: usize> {
+    buf: [u8; SIZE],
+    /// Where the next byte goes.
+    head: usize,
+    /// Bytes in use, ending at `head`.
+    len: usize,
+    /// Bytes of the record being written.
+    record_len: usize,
+}
+
+impl<const SIZE: usize> History<SIZE> {
+    fn push(&mut self, byte: u8) {
+        if self.len == SIZE {
+            self.evict();
+        }
+        self.buf[self.head] = byte;
+        self.head = (self.head + 1) % SIZE;
+        self.len += 1;
+    }
+
+    /// Remove the oldest whole record.
+    fn evict(&mut self) {
+        let tail = (self.head + SIZE - self.len) % SIZE;
+        // `record_len < SIZE - 1`, so a whole record comes first.
+        let record = (0..self.len - self.record_len)
+            .find(|&i| self.buf[(tail + i) % SIZE] == SEPARATOR)
+            .unwrap();
+        self.len -= record + 1;
+    }
+
+    fn end_record(&mut self) {
+        self.push(SEPARATOR);
+        self.record_len = 0;
+    }
+
+    /// Call `f` with each whole record, newest first. A record that wraps
+    /// around the end of the buffer is passed in two parts.
+    fn for_each_newest_first(&self, mut f: impl FnMut(&[u8], &[u8]) -> fmt::Result) -> fmt::Result {
+        let tail = (self.head + SIZE - self.len) % SIZE;
+        // Offsets from `tail`, to the end of the newest whole record's
+        // separator.
+        let mut end = self.len - self.record_len;
+        while end > 0 {
+            let start = (0..end - 1)
+                .rev()
+                .find(|&i| self.buf[(tail + i) % SIZE] == SEPARATOR)
+                .map_or(0, |i| i + 1);
+            let (first, second) = self.range(tail + start, end - 1 - start);
+            f(first, second)?;
+            end = start;
+        }
+        Ok(())
+    }
+
+    /// The `len` bytes from `start`, which may wrap around.
+    fn range(&self, start: usize, len: usize) -> (&[u8], &[u8]) {
+        let start = start % SIZE;
+        if start + len <= SIZE {
+            (&self.buf[start..start + len], &[])
+        } else {
+            (&self.buf[start..], &self.buf[..start + len - SIZE])
+        }
+    }
+}
+
+impl<const SIZE: usize> Write for History<SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Leave room for the separator and at least part of an older record,
+        // so eviction never has to remove the record being written.
+        let mut n = core::cmp::min(s.len(), SIZE - 2 - self.record_len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        for &b in &s.as_bytes()[..n] {
+            self.push(b);
+        }
+        self.record_len += n;
+        Ok(())
+    }
+}
+
+/// Write the UTF-8 text split into `first` and `second`, possibly in the middle
+/// of a character.
+fn write_split(writer: &mut impl Write, first: &[u8], second: &[u8]) -> fmt::Result {
+    // Records are stored from `&str`s, so the only invalid sequence is a
+    // character cut in two by the split.
+    let valid = match core::str::from_utf8(first) {
+        Ok(s) => s.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    writer.write_str(core::str::from_utf8(&first[..valid]).unwrap())?;
+
+    let partial = &first[valid..];
+    let rest = second.len().min(4 - partial.len());
+    let mut char_buf = [0; 4];
+    char_buf[..partial.len()].copy_from_slice(partial);
+    char_buf[partial.len()..partial.len() + rest].copy_from_slice(&second[..rest]);
+    let char_len = if partial.is_empty() {
+        0
+    } else {
+        match core::str::from_utf8(&char_buf[..partial.len() + rest]) {
+            Ok(s) => s.len(),
+            Err(e) => e.valid_up_to(),
+        }
+    };
+    writer.write_str(core::str::from_utf8(&char_buf[..char_len]).unwrap())?;
+
+    let used = char_len.saturating_sub(partial.len());
+    writer.write_str(core::str::from_utf8(&second[used..]).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::vec::Vec;
+
+    use log::Level;
+    use pretty_assertions::assert_eq;
+
+    fn log<const SIZE: usize>(sink: &RingBufferSink<SIZE>, msg: &str) {
+        sink.log(
+            &Record::builder()
+                .level(Level::Info)
+                .target("t")
+                .args(format_args!("{msg}"))
+                .build(),
+        );
+    }
+
+    fn dump<const SIZE: usize>(sink: &RingBufferSink<SIZE>) -> Vec<String> {
+        let mut out = String::new();
+        sink.dump(&mut out).unwrap();
+        let prefix = std::format!("[{}] t: ", level_as_string(Level::Info));
+        out.lines()
+            .map(|line| line.strip_prefix(&prefix).unwrap_or(line).into())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_newest_records() {
+        let sink = RingBufferSink::<64>::new();
+        assert_eq!(dump(&sink), Vec::<String>::new());
+
+        log(&sink, "one");
+        log(&sink, "two");
+        assert_eq!(dump(&sink), ["two", "one"]);
+
+        // With their prefixes, only the last two fit.
+        for msg in ["three", "four\nlines", "five"] {
+            log(&sink, msg);
+        }
+        assert_eq!(dump(&sink), ["five", "four", "lines"]);
+    }
+
+    #[test]
+    fn truncates_long_records() {
+        let sink = RingBufferSink::<64>::new();
+        log(&sink, "a");
+        log(&sink, &"\u{e9}".repeat(40));
+        // A record can be 62 bytes, of which the prefix takes 20.
+        assert_eq!(dump(&sink), ["\u{e9}".repeat(21)]);
+    }
+
+    #[test]
+    fn missed_records_are_counted() {
+        let sink = RingBufferSink::<64>::new();
+        log(&sink, "kept");
+        let history = sink.history.lock();
+        log(&sink, "missed");
+        let mut out = String::new();
+        sink.dump(&mut out).unwrap();
+        assert_eq!(out, "(log history is in use)\n");
+        drop(history);
+        assert_eq!(dump(&sink), ["kept", "(1 records not kept)"]);
+    }
+
+    #[test]
+    fn split_characters() {
+        let text = "a\u{e9}\u{20ac}\u{1f600}b";
+        for split in 0..=text.len() {
+            let (first, second) = text.as_bytes().split_at(split);
+            let mut out = String::new();
+            write_split(&mut out, first, second).unwrap();
+            assert_eq!(out, text, "split at {split}");
+        }
+    }
+}
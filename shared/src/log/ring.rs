@@ -0,0 +1,251 @@
+//! Lock-free multi-producer, single-consumer ring of text messages.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A bounded queue of up to `SLOTS` messages, each truncated to `LEN` bytes.
+///
+/// Any number of producers may `push` concurrently, including from interrupt
+/// handlers, without taking locks. Only one consumer may `pop` at a time,
+/// which the caller must guarantee.
+///
+/// Each slot has a `turn` counter. For the message at position `pos`, which
+/// lives in slot `pos % SLOTS` on lap `pos / SLOTS`, the slot's turn is
+/// `2 * lap` while it is free for writing and `2 * lap + 1` once written.
+pub struct LogRing<const SLOTS: usize, const LEN: usize> {
+    slots: [Slot<LEN>; SLOTS],
+    /// Next position to write.
+    head: AtomicUsize,
+    /// Next position to read. Only touched by the consumer.
+    tail: AtomicUsize,
+    /// Messages discarded because the ring was full.
+    dropped: AtomicUsize,
+}
+
+struct Slot<const LEN: usize> {
+    turn: AtomicUsize,
+    len: UnsafeCell<usize>,
+    data: UnsafeCell<[u8; LEN]>,
+}
+
+impl<const LEN: usize> Slot<LEN> {
+    const fn new() -> Self {
+        Slot {
+            turn: AtomicUsize::new(0),
+            len: UnsafeCell::new(0),
+            data: UnsafeCell::new([0; LEN]),
+        }
+    }
+}
+
+// SAFETY: slot contents are only accessed by the one producer that claimed the
+// slot, or by the consumer after the producer published it.
+unsafe impl<const SLOTS: usize, const LEN: usize> Sync for LogRing<SLOTS, LEN> {}
+
+impl<const SLOTS: usize, const LEN: usize> LogRing<SLOTS, LEN> {
+    pub const fn new() -> Self {
+        assert!(SLOTS > 0);
+        LogRing {
+            slots: [const { Slot::new() }; SLOTS],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Format `args` into the next free slot. Returns false, and counts the
+    /// message as dropped, if the ring is full.
+    pub fn push(&self, args: core::fmt::Arguments) -> bool {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos % SLOTS];
+            let free_turn = 2 * (pos / SLOTS);
+            let turn = slot.turn.load(Ordering::Acquire);
+
+            if turn == free_turn {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(actual) => pos = actual,
+                }
+            } else if turn < free_turn {
+                // The consumer hasn't read this slot's previous message.
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            } else {
+                // Another producer claimed `pos` first.
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        };
+
+        // SAFETY: we claimed this slot by advancing `head` past `pos`. Nobody
+        // else touches it until we publish it below.
+        let (len, data) = unsafe { (&mut *slot.len.get(), &mut *slot.data.get()) };
+        let mut writer = TruncatingWriter { buf: data, len: 0 };
+        let _ = writer.write_fmt(args);
+        *len = writer.len;
+
+        slot.turn.store(2 * (pos / SLOTS) + 1, Ordering::Release);
+        true
+    }
+
+    /// Pass the oldest message to `f` and remove it. Returns false if there is
+    /// no complete message.
+    ///
+    /// # Safety
+    ///
+    /// Must not be called concurrently with itself.
+    pub unsafe fn pop(&self, f: impl FnOnce(&str)) -> bool {
+        let pos = self.tail.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % SLOTS];
+        if slot.turn.load(Ordering::Acquire) != 2 * (pos / SLOTS) + 1 {
+            return false;
+        }
+
+        // SAFETY: the producer published this slot, and the caller guarantees
+        // we are the only consumer.
+        let (len, data) = unsafe { (*slot.len.get(), &*slot.data.get()) };
+        // `TruncatingWriter` only ever cuts at character boundaries.
+        f(core::str::from_utf8(&data[..len]).unwrap());
+
+        slot.turn.store(2 * (pos / SLOTS + 1), Ordering::Release);
+        self.tail.store(pos + 1, Ordering::Relaxed);
+        true
+    }
+
+    /// Whether a complete message is ready to `pop`.
+    pub fn has_pending(&self) -> bool {
+        let pos = self.tail.load(Ordering::Relaxed);
+        self.slots[pos % SLOTS].turn.load(Ordering::Acquire) == 2 * (pos / SLOTS) + 1
+    }
+
+    /// The number of messages dropped because the ring was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<const SLOTS: usize, const LEN: usize> Default for LogRing<SLOTS, LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes into a fixed buffer, silently discarding whatever doesn't fit.
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    fn pop_string<const SLOTS: usize, const LEN: usize>(
+        ring: &LogRing<SLOTS, LEN>,
+    ) -> Option<String> {
+        let mut out = None;
+        unsafe { ring.pop(|s| out = Some(s.to_string())) };
+        out
+    }
+
+    #[test]
+    fn fifo_order() {
+        let ring = LogRing::<4, 32>::new();
+        for i in 0..3 {
+            assert!(ring.push(format_args!("msg {i}")));
+        }
+
+        assert_eq!(pop_string(&ring).as_deref(), Some("msg 0"));
+        assert!(ring.push(format_args!("msg 3")));
+        assert!(ring.push(format_args!("msg 4")));
+        for i in 1..5 {
+            assert_eq!(pop_string(&ring), Some(format!("msg {i}")));
+        }
+        assert_eq!(pop_string(&ring), None);
+    }
+
+    #[test]
+    fn full_ring_drops() {
+        let ring = LogRing::<2, 32>::new();
+        assert!(ring.push(format_args!("a")));
+        assert!(ring.push(format_args!("b")));
+        assert!(!ring.push(format_args!("c")));
+        assert_eq!(ring.dropped(), 1);
+
+        assert_eq!(pop_string(&ring).as_deref(), Some("a"));
+        assert!(ring.push(format_args!("d")));
+        assert_eq!(pop_string(&ring).as_deref(), Some("b"));
+        assert_eq!(pop_string(&ring).as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        let ring = LogRing::<1, 4>::new();
+        assert!(ring.push(format_args!("ab\u{e9}\u{e9}")));
+        assert_eq!(pop_string(&ring).as_deref(), Some("ab\u{e9}"));
+    }
+
+    #[test]
+    fn concurrent_producers() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 1000;
+
+        let ring = LogRing::<16, 16>::new();
+        let mut received: Vec<Vec<usize>> = vec![Vec::new(); PRODUCERS];
+
+        std::thread::scope(|scope| {
+            for p in 0..PRODUCERS {
+                let ring = &ring;
+                scope.spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        while !ring.push(format_args!("{p} {i}")) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            let mut count = 0;
+            while count < PRODUCERS * PER_PRODUCER {
+                let got = unsafe {
+                    ring.pop(|s| {
+                        let (p, i) = s.split_once(' ').unwrap();
+                        received[p.parse::<usize>().unwrap()].push(i.parse().unwrap());
+                    })
+                };
+                if got {
+                    count += 1;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        // Messages from each producer arrive in order.
+        for r in received {
+            assert_eq!(r, (0..PER_PRODUCER).collect::<Vec<_>>());
+        }
+    }
+}
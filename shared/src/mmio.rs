@@ -0,0 +1,363 @@
+//! Volatile access to device registers
+//!
+//! Drivers reach registers through a `Bus`: the kernel's is memory mapped,
+//! and `mock::MockBus` records accesses so a driver's register sequences can
+//! be tested on the host. A `RegisterBlock` is a window into a bus, such as
+//! one of several identical timers or queues, so the code driving it only
+//! needs offsets within it. Registers laid out as a `#[repr(C)]` struct can
+//! instead be `VolatileCell`s.
+//!
+//! Every access is volatile and followed or preceded by a compiler fence, so
+//! the compiler neither elides, merges nor reorders it, not even with plain
+//! accesses to memory. A buffer filled before a register write that hands it
+//! to a device is therefore filled by the time the device sees the write.
+//! Fences order the compiler, not the CPU: register mappings must be uncached
+//! for accesses to reach the device in program order.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// A value a register can hold: the sizes devices are accessed in, all valid
+/// for any bit pattern.
+pub trait Register: Copy + private::Sealed {
+    /// Zero-extend to 64 bits.
+    fn to_u64(self) -> u64;
+
+    /// Truncate from 64 bits.
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_register {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+
+            impl Register for $t {
+                fn to_u64(self) -> u64 {
+                    self.into()
+                }
+
+                fn from_u64(value: u64) -> Self {
+                    value as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_register!(u8, u16, u32, u64);
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Read `ptr` volatilely, with no later access moved before it.
+///
+/// # Safety
+///
+/// As `core::ptr::read_volatile`.
+pub unsafe fn read<T: Register>(ptr: *const T) -> T {
+    // SAFETY: the caller's.
+    let value = unsafe { ptr.read_volatile() };
+    compiler_fence(Ordering::SeqCst);
+    value
+}
+
+/// Write `value` to `ptr` volatilely, with no earlier access moved after it.
+///
+/// # Safety
+///
+/// As `core::ptr::write_volatile`.
+pub unsafe fn write<T: Register>(ptr: *mut T, value: T) {
+    compiler_fence(Ordering::SeqCst);
+    // SAFETY: the caller's.
+    unsafe { ptr.write_volatile(value) }
+}
+
+/// A register in a `#[repr(C)]` struct of them.
+#[repr(transparent)]
+pub struct VolatileCell<T> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Register> VolatileCell<T> {
+    pub const fn new(value: T) -> Self {
+        VolatileCell {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        // SAFETY: the cell is valid for reads while borrowed.
+        unsafe { read(self.value.get()) }
+    }
+
+    /// # Safety
+    ///
+    /// Writing `value` to the register must be safe: for example, it must not
+    /// make a device write to memory it doesn't own.
+    pub unsafe fn set(&self, value: T) {
+        // SAFETY: the cell is valid for writes while borrowed, since it is
+        // an `UnsafeCell`.
+        unsafe { write(self.value.get(), value) }
+    }
+
+    /// Set the register to `f` of its value. Not atomic: the device may
+    /// change it in between.
+    ///
+    /// # Safety
+    ///
+    /// As `set`.
+    pub unsafe fn modify(&self, f: impl FnOnce(T) -> T) {
+        unsafe { self.set(f(self.get())) }
+    }
+}
+
+/// Registers addressed by byte offset.
+pub trait Bus {
+    /// Read the register at `offset`.
+    fn read<T: Register>(&self, offset: usize) -> T;
+
+    /// Write `value` to the register at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// Writing `value` to the register must be safe: for example, it must not
+    /// make the device write to memory it doesn't own.
+    unsafe fn write<T: Register>(&self, offset: usize, value: T);
+
+    /// Set the register at `offset` to `f` of its value. Not atomic: the
+    /// device may change it in between.
+    ///
+    /// # Safety
+    ///
+    /// As `write`.
+    unsafe fn modify<T: Register>(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        unsafe { self.write(offset, f(self.read(offset))) }
+    }
+}
+
+impl<B: Bus + ?Sized> Bus for &B {
+    fn read<T: Register>(&self, offset: usize) -> T {
+        (**self).read(offset)
+    }
+
+    unsafe fn write<T: Register>(&self, offset: usize, value: T) {
+        unsafe { (**self).write(offset, value) }
+    }
+}
+
+/// `len` bytes of registers starting at `base` on a bus. Accesses outside
+/// them panic.
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterBlock<B> {
+    bus: B,
+    base: usize,
+    len: usize,
+}
+
+impl<B: Bus> RegisterBlock<B> {
+    pub fn new(bus: B, base: usize, len: usize) -> Self {
+        RegisterBlock { bus, base, len }
+    }
+
+    /// The `len` bytes of registers at `offset` in this block.
+    pub fn block(&self, offset: usize, len: usize) -> RegisterBlock<&B> {
+        self.check(offset, len);
+        RegisterBlock::new(&self.bus, self.base + offset, len)
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    fn check(&self, offset: usize, size: usize) {
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "{size} byte register access at {offset:#x} is outside a {:#x} byte block",
+            self.len
+        );
+    }
+}
+
+impl<B: Bus> Bus for RegisterBlock<B> {
+    fn read<T: Register>(&self, offset: usize) -> T {
+        self.check(offset, core::mem::size_of::<T>());
+        self.bus.read(self.base + offset)
+    }
+
+    unsafe fn write<T: Register>(&self, offset: usize, value: T) {
+        self.check(offset, core::mem::size_of::<T>());
+        unsafe { self.bus.write(self.base + offset, value) }
+    }
+}
+
+/// A bus that records accesses instead of reaching a device.
+#[cfg(feature = "alloc")]
+pub mod mock {
+    use super::*;
+
+    use alloc::collections::{BTreeMap, VecDeque};
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum Access {
+        Read {
+            offset: usize,
+            size: usize,
+            value: u64,
+        },
+        Write {
+            offset: usize,
+            size: usize,
+            value: u64,
+        },
+    }
+
+    /// Registers hold what was last written or `set`, 0 to start with. Reads
+    /// queued with `queue_reads` come first, e.g. to poll a status register
+    /// until it changes.
+    #[derive(Default)]
+    pub struct MockBus {
+        values: RefCell<BTreeMap<usize, u64>>,
+        queued: RefCell<BTreeMap<usize, VecDeque<u64>>>,
+        accesses: RefCell<Vec<Access>>,
+    }
+
+    impl MockBus {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Set the register at `offset` without recording an access.
+        pub fn set(&self, offset: usize, value: u64) {
+            self.values.borrow_mut().insert(offset, value);
+        }
+
+        /// Make the next reads at `offset` return `values`, in order.
+        pub fn queue_reads(&self, offset: usize, values: impl IntoIterator<Item = u64>) {
+            self.queued
+                .borrow_mut()
+                .entry(offset)
+                .or_default()
+                .extend(values);
+        }
+
+        /// Take the accesses made since the last call.
+        pub fn take_accesses(&self) -> Vec<Access> {
+            core::mem::take(&mut self.accesses.borrow_mut())
+        }
+    }
+
+    impl Bus for MockBus {
+        fn read<T: Register>(&self, offset: usize) -> T {
+            let queued = self
+                .queued
+                .borrow_mut()
+                .get_mut(&offset)
+                .and_then(VecDeque::pop_front);
+            let value =
+                queued.unwrap_or_else(|| self.values.borrow().get(&offset).copied().unwrap_or(0));
+            let value = T::from_u64(value);
+            self.accesses.borrow_mut().push(Access::Read {
+                offset,
+                size: core::mem::size_of::<T>(),
+                value: value.to_u64(),
+            });
+            value
+        }
+
+        unsafe fn write<T: Register>(&self, offset: usize, value: T) {
+            self.set(offset, value.to_u64());
+            self.accesses.borrow_mut().push(Access::Write {
+                offset,
+                size: core::mem::size_of::<T>(),
+                value: value.to_u64(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{Access, MockBus};
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn cells_read_and_write() {
+        #[repr(C)]
+        struct Regs {
+            status: VolatileCell<u32>,
+            control: VolatileCell<u16>,
+        }
+
+        let regs = Regs {
+            status: VolatileCell::new(0x8000_0001),
+            control: VolatileCell::new(0),
+        };
+        assert_eq!(regs.status.get(), 0x8000_0001);
+        unsafe {
+            regs.control.set(0x10);
+            regs.control.modify(|c| c | 1);
+        }
+        assert_eq!(regs.control.get(), 0x11);
+    }
+
+    #[test]
+    fn blocks_offset_accesses() {
+        let bus = MockBus::new();
+        bus.set(0x120, 7);
+        let block = RegisterBlock::new(&bus, 0x100, 0x100);
+        let timer = block.block(0x20, 0x20);
+        assert_eq!(timer.read::<u64>(0), 7);
+        unsafe {
+            timer.modify::<u32>(8, |v| v | 0b100);
+            block.write::<u8>(0xff, 0xaa);
+        }
+        assert_eq!(
+            bus.take_accesses(),
+            [
+                Access::Read {
+                    offset: 0x120,
+                    size: 8,
+                    value: 7
+                },
+                Access::Read {
+                    offset: 0x128,
+                    size: 4,
+                    value: 0
+                },
+                Access::Write {
+                    offset: 0x128,
+                    size: 4,
+                    value: 0b100
+                },
+                Access::Write {
+                    offset: 0x1ff,
+                    size: 1,
+                    value: 0xaa
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mock_returns_queued_reads_first() {
+        let bus = MockBus::new();
+        bus.set(4, 1);
+        bus.queue_reads(4, [0x100, 0x1_0000_0002]);
+        let reads: std::vec::Vec<u32> = (0..3).map(|_| bus.read(4)).collect();
+        // Reads are truncated to their size.
+        assert_eq!(reads, [0x100, 2, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "4 byte register access at 0x1e is outside a 0x20 byte block")]
+    fn blocks_reject_accesses_outside() {
+        let bus = MockBus::new();
+        RegisterBlock::new(&bus, 0x100, 0x20).read::<u32>(0x1e);
+    }
+}
@@ -0,0 +1,184 @@
+//! Scheduling policy
+//!
+//! The kernel's scheduler keeps each CPU's ready tasks in a `RunQueue`, and
+//! its timers, which wake sleeping tasks, in a `SleepQueue`. These hold the
+//! policy, i.e. which task runs next, while the kernel does the context
+//! switching and locking. Keeping them here lets the policy be tested on the
+//! host against a simulated clock (see `sim`) instead of only in QEMU.
+
+#[cfg(test)]
+mod sim;
+
+use crate::collections::{IntrusiveList, Linked};
+
+use core::ptr::NonNull;
+
+/// Scheduling priority. Ready tasks of a higher priority always run before
+/// those of a lower one, except that all ready tasks are periodically boosted
+/// to the highest level so none starve.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+    Highest = 3,
+}
+
+impl Priority {
+    pub const ALL: [Priority; NUM_PRIORITIES] = [
+        Priority::Low,
+        Priority::Normal,
+        Priority::High,
+        Priority::Highest,
+    ];
+}
+
+pub const NUM_PRIORITIES: usize = 4;
+
+/// How often ready tasks are boosted to `Priority::Highest`.
+pub const BOOST_INTERVAL_TICKS: u64 = 20;
+
+/// Ready tasks, in one FIFO per priority.
+pub struct RunQueue<T: Linked> {
+    /// Indexed by `Priority`.
+    lists: [IntrusiveList<T>; NUM_PRIORITIES],
+}
+
+// SAFETY: as for `IntrusiveList`.
+unsafe impl<T: Linked + Send> Send for RunQueue<T> {}
+
+impl<T: Linked> RunQueue<T> {
+    pub const fn new() -> Self {
+        RunQueue {
+            lists: [const { IntrusiveList::new() }; NUM_PRIORITIES],
+        }
+    }
+
+    /// Queue `task` behind the other ready tasks of `priority`.
+    ///
+    /// # Safety
+    ///
+    /// As for `IntrusiveList::push_back`.
+    pub unsafe fn push(&mut self, task: NonNull<T>, priority: Priority) {
+        unsafe {
+            self.lists[priority as usize].push_back(task);
+        }
+    }
+
+    /// Take the task that should run next: the longest waiting one of the
+    /// highest priority.
+    pub fn pop(&mut self) -> Option<NonNull<T>> {
+        self.lists
+            .iter_mut()
+            .rev()
+            .find_map(IntrusiveList::pop_front)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.iter().all(IntrusiveList::is_empty)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(IntrusiveList::len).sum()
+    }
+
+    /// Iterate over the tasks in the order `pop` would take them. The queue
+    /// must not be modified while iterating.
+    pub fn iter(&self) -> impl Iterator<Item = NonNull<T>> + '_ {
+        self.lists.iter().rev().flat_map(IntrusiveList::iter)
+    }
+
+    /// Move every task to the highest priority, keeping their relative order.
+    /// Each returns to its own priority the next time it is queued.
+    pub fn boost(&mut self) {
+        let (lower, highest) = self.lists.split_at_mut(NUM_PRIORITIES - 1);
+        for list in lower.iter_mut().rev() {
+            highest[0].append(list);
+        }
+    }
+}
+
+impl<T: Linked> Default for RunQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implemented by tasks that can be put in a `SleepQueue`.
+pub trait Sleeper: Linked {
+    /// The tick `this` should be woken at. Only called on tasks in a queue,
+    /// which must be valid while they are.
+    fn wake_tick(this: NonNull<Self>) -> u64;
+}
+
+/// Sleeping tasks, or anything else waiting for a tick like timers, in the
+/// order they wake. Tasks waking on the same tick wake in the order they went
+/// to sleep.
+pub struct SleepQueue<T: Sleeper> {
+    list: IntrusiveList<T>,
+}
+
+// SAFETY: as for `IntrusiveList`.
+unsafe impl<T: Sleeper + Send> Send for SleepQueue<T> {}
+
+impl<T: Sleeper> SleepQueue<T> {
+    pub const fn new() -> Self {
+        SleepQueue {
+            list: IntrusiveList::new(),
+        }
+    }
+
+    /// Queue `task` to wake at its wake tick.
+    ///
+    /// # Safety
+    ///
+    /// As for `IntrusiveList::push_back`.
+    pub unsafe fn insert(&mut self, task: NonNull<T>) {
+        let wake_tick = T::wake_tick(task);
+        let later = self
+            .list
+            .iter()
+            .find(|&next| T::wake_tick(next) > wake_tick);
+        unsafe {
+            match later {
+                Some(later) => self.list.insert_before(later, task),
+                None => self.list.push_back(task),
+            }
+        }
+    }
+
+    /// Take the next task whose wake tick is at or before `now`, if any.
+    pub fn pop_due(&mut self, now: u64) -> Option<NonNull<T>> {
+        let next = self.list.front()?;
+        if T::wake_tick(next) > now {
+            return None;
+        }
+        self.list.pop_front()
+    }
+
+    /// Take `task` out of the queue before it wakes.
+    ///
+    /// # Safety
+    ///
+    /// As for `IntrusiveList::remove`.
+    pub unsafe fn remove(&mut self, task: NonNull<T>) {
+        unsafe { self.list.remove(task) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Iterate over the tasks in the order they wake. The queue must not be
+    /// modified while iterating.
+    pub fn iter(&self) -> impl Iterator<Item = NonNull<T>> + '_ {
+        self.list.iter()
+    }
+}
+
+impl<T: Sleeper> Default for SleepQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
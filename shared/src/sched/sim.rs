@@ -0,0 +1,302 @@
+//! A deterministic simulation of one CPU's scheduler
+//!
+//! `Sim` drives a `RunQueue` and `SleepQueue` the way the kernel's `sched`
+//! module does on yields, sleeps and timer ticks, with context switches
+//! replaced by picking the next task. Time is counted in turns: each turn the
+//! running task does what its `Behavior` says, and every `TURNS_PER_TICK`
+//! turns the timer ticks. Nothing depends on real time, so every run with the
+//! same tasks makes the same choices.
+
+use super::*;
+
+use crate::collections::Links;
+
+use std::vec::Vec;
+
+use pretty_assertions::assert_eq;
+use proptest::prelude::*;
+
+/// Turns the running tasks get between timer ticks.
+const TURNS_PER_TICK: u64 = 10;
+
+/// What a task does each turn.
+#[derive(Clone, Copy, Debug)]
+enum Behavior {
+    /// Yield, staying ready.
+    Yield,
+    /// Sleep for this many ticks.
+    Sleep(u64),
+}
+
+struct Task {
+    behavior: Behavior,
+    priority: Priority,
+    wake_tick: u64,
+    links: Links<Task>,
+}
+
+unsafe impl Linked for Task {
+    fn links(this: NonNull<Self>) -> NonNull<Links<Self>> {
+        unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*this.as_ptr()).links)) }
+    }
+}
+
+impl Sleeper for Task {
+    fn wake_tick(this: NonNull<Self>) -> u64 {
+        // SAFETY: `Sim` keeps its tasks alive while they are queued.
+        unsafe { this.as_ref().wake_tick }
+    }
+}
+
+/// A task taking a turn.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Run {
+    tick: u64,
+    task: usize,
+}
+
+struct Sim {
+    run_queue: RunQueue<Task>,
+    sleep_queue: SleepQueue<Task>,
+    /// The queues point into this, so it is never resized.
+    tasks: Vec<Task>,
+    now: u64,
+    turns: u64,
+    /// The tick each ready task was last queued at.
+    ready_since: Vec<u64>,
+    /// The longest each task waited between being queued and running.
+    max_wait: Vec<u64>,
+    /// Every turn taken, in order.
+    runs: Vec<Run>,
+}
+
+impl Sim {
+    /// Start with every task in `tasks` ready, in order.
+    fn new(tasks: &[(Behavior, Priority)]) -> Sim {
+        let mut sim = Sim {
+            run_queue: RunQueue::new(),
+            sleep_queue: SleepQueue::new(),
+            tasks: tasks
+                .iter()
+                .map(|&(behavior, priority)| Task {
+                    behavior,
+                    priority,
+                    wake_tick: 0,
+                    links: Links::new(),
+                })
+                .collect(),
+            now: 0,
+            turns: 0,
+            ready_since: std::vec![0; tasks.len()],
+            max_wait: std::vec![0; tasks.len()],
+            runs: Vec::new(),
+        };
+        for index in 0..tasks.len() {
+            sim.make_ready(index);
+        }
+        sim
+    }
+
+    fn ptr(&mut self, index: usize) -> NonNull<Task> {
+        NonNull::from(&mut self.tasks[index])
+    }
+
+    fn index(&self, task: NonNull<Task>) -> usize {
+        self.tasks
+            .iter()
+            .position(|t| core::ptr::eq(t, task.as_ptr()))
+            .unwrap()
+    }
+
+    fn make_ready(&mut self, index: usize) {
+        let task = self.ptr(index);
+        self.ready_since[index] = self.now;
+        unsafe {
+            self.run_queue.push(task, self.tasks[index].priority);
+        }
+    }
+
+    /// Like the kernel's `timer_tick`: boost on the interval, then wake the
+    /// sleepers that are due.
+    fn tick(&mut self) {
+        self.now += 1;
+        if self.now.is_multiple_of(BOOST_INTERVAL_TICKS) {
+            self.run_queue.boost();
+        }
+        while let Some(task) = self.sleep_queue.pop_due(self.now) {
+            let index = self.index(task);
+            self.make_ready(index);
+        }
+    }
+
+    /// Run until `ticks` have passed.
+    fn run(&mut self, ticks: u64) {
+        while self.now < ticks {
+            let Some(task) = self.run_queue.pop() else {
+                // Idle until the next tick.
+                self.turns = 0;
+                self.tick();
+                continue;
+            };
+            let index = self.index(task);
+            assert!(
+                self.tasks[index].wake_tick <= self.now,
+                "task {index} ran before its wake tick"
+            );
+            let waited = self.now - self.ready_since[index];
+            self.max_wait[index] = self.max_wait[index].max(waited);
+            self.runs.push(Run {
+                tick: self.now,
+                task: index,
+            });
+
+            match self.tasks[index].behavior {
+                Behavior::Yield => self.make_ready(index),
+                Behavior::Sleep(ticks) => {
+                    self.tasks[index].wake_tick = self.now + ticks;
+                    unsafe {
+                        self.sleep_queue.insert(task);
+                    }
+                }
+            }
+
+            self.turns += 1;
+            if self.turns == TURNS_PER_TICK {
+                self.turns = 0;
+                self.tick();
+            }
+        }
+    }
+
+    fn runs_of(&self, task: usize) -> impl Iterator<Item = u64> + '_ {
+        self.runs
+            .iter()
+            .filter(move |run| run.task == task)
+            .map(|run| run.tick)
+    }
+}
+
+#[test]
+fn equal_tasks_take_turns_in_order() {
+    let mut sim = Sim::new(&[(Behavior::Yield, Priority::Normal); 3]);
+    sim.run(5);
+    let order: Vec<usize> = sim.runs.iter().map(|run| run.task).collect();
+    let expected: Vec<usize> = (0..3).cycle().take(order.len()).collect();
+    assert_eq!(order, expected);
+}
+
+#[test]
+fn lower_priority_runs_once_per_boost() {
+    let mut sim = Sim::new(&[
+        (Behavior::Yield, Priority::High),
+        (Behavior::Yield, Priority::Low),
+    ]);
+    let ticks = BOOST_INTERVAL_TICKS * 5;
+    sim.run(ticks);
+    let low: Vec<u64> = sim.runs_of(1).collect();
+    let boosts: Vec<u64> = (1..5).map(|n| n * BOOST_INTERVAL_TICKS).collect();
+    assert_eq!(low, boosts);
+}
+
+#[test]
+fn sleepers_wake_on_their_tick() {
+    let mut sim = Sim::new(&[
+        (Behavior::Sleep(3), Priority::Normal),
+        (Behavior::Yield, Priority::Normal),
+    ]);
+    sim.run(30);
+    let sleeper: Vec<u64> = sim.runs_of(0).collect();
+    let expected: Vec<u64> = (0..30).step_by(3).collect();
+    assert_eq!(sleeper, expected);
+}
+
+#[test]
+fn queues_iterate_in_order() {
+    let mut sim = Sim::new(&[
+        (Behavior::Yield, Priority::Low),
+        (Behavior::Yield, Priority::High),
+        (Behavior::Yield, Priority::Normal),
+        (Behavior::Yield, Priority::High),
+    ]);
+    let ready: Vec<usize> = sim.run_queue.iter().map(|task| sim.index(task)).collect();
+    assert_eq!(ready, [1, 3, 2, 0]);
+
+    // Tasks waking on the same tick stay in the order they went to sleep.
+    for (task, wake_tick) in [(1, 5), (3, 2), (2, 5)] {
+        assert_eq!(sim.run_queue.pop().map(|t| sim.index(t)), Some(task));
+        sim.tasks[task].wake_tick = wake_tick;
+        let task = sim.ptr(task);
+        unsafe {
+            sim.sleep_queue.insert(task);
+        }
+    }
+    let sleeping: Vec<usize> = sim.sleep_queue.iter().map(|task| sim.index(task)).collect();
+    assert_eq!(sleeping, [3, 1, 2]);
+
+    let task = sim.ptr(1);
+    unsafe {
+        sim.sleep_queue.remove(task);
+    }
+    let due: Vec<NonNull<Task>> = std::iter::from_fn(|| sim.sleep_queue.pop_due(5)).collect();
+    let due: Vec<usize> = due.into_iter().map(|task| sim.index(task)).collect();
+    assert_eq!(due, [3, 2]);
+}
+
+#[test]
+fn idle_cpu_still_ticks() {
+    let mut sim = Sim::new(&[(Behavior::Sleep(7), Priority::Low)]);
+    sim.run(30);
+    assert_eq!(
+        sim.runs_of(0).collect::<Vec<_>>(),
+        std::vec![0, 7, 14, 21, 28]
+    );
+}
+
+fn behavior() -> impl Strategy<Value = Behavior> {
+    prop_oneof![Just(Behavior::Yield), (1..30u64).prop_map(Behavior::Sleep)]
+}
+
+fn priority() -> impl Strategy<Value = Priority> {
+    proptest::sample::select(Priority::ALL.to_vec())
+}
+
+proptest! {
+    #[test]
+    fn no_ready_task_starves(tasks in proptest::collection::vec((behavior(), priority()), 1..8)) {
+        let mut sim = Sim::new(&tasks);
+        sim.run(BOOST_INTERVAL_TICKS * 10);
+        // A ready task is boosted within an interval, and then runs before
+        // the next tick, since there are fewer tasks than turns per tick.
+        for (index, &wait) in sim.max_wait.iter().enumerate() {
+            prop_assert!(
+                wait <= BOOST_INTERVAL_TICKS,
+                "task {} waited {} ticks", index, wait
+            );
+        }
+    }
+
+    #[test]
+    fn runs_are_deterministic(tasks in proptest::collection::vec((behavior(), priority()), 1..8)) {
+        let mut first = Sim::new(&tasks);
+        first.run(BOOST_INTERVAL_TICKS * 3);
+        let mut second = Sim::new(&tasks);
+        second.run(BOOST_INTERVAL_TICKS * 3);
+        prop_assert_eq!(first.runs, second.runs);
+    }
+
+    #[test]
+    fn higher_priority_runs_first_between_boosts(
+        tasks in proptest::collection::vec((Just(Behavior::Yield), priority()), 2..8),
+    ) {
+        let mut sim = Sim::new(&tasks);
+        sim.run(BOOST_INTERVAL_TICKS * 3);
+        // With every task always ready, only tasks of the highest priority
+        // present run, except that each boost lets every other task run once.
+        let top = tasks.iter().map(|&(_, priority)| priority).max().unwrap();
+        for (index, &(_, priority)) in tasks.iter().enumerate() {
+            if priority != top {
+                prop_assert_eq!(sim.runs_of(index).count(), 2, "task {}", index);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! The disk the test suite boots with
+//!
+//! `mkimage test` writes a raw image, see `image`, and attaches it as a
+//! virtio disk for the in-kernel tests. Sector 0 is an MBR with one FAT32
+//! partition. The sectors after it, up to `NUM_SECTORS`, hold bytes following
+//! `byte`, which the block driver's test checks it reads back. Each of those
+//! sectors starts with its own index, so reading the wrong sector is caught
+//! too. The partition starts at `NUM_SECTORS` and holds `FILES`, which the
+//! filesystem's test reads, and whatever else the test suite adds.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Sectors before the partition.
+pub const NUM_SECTORS: u64 = 512;
+
+/// Files in the partition, by path.
+pub const FILES: &[(&str, &[u8])] = &[
+    ("/hello.txt", b"Hello from the test disk!\n"),
+    (
+        "/dir/A file with a long name.txt",
+        b"Long names are stored in several directory entries.\n",
+    ),
+    ("/dir/empty", b""),
+];
+
+/// Long enough to span several clusters. Its contents follow `byte`, as if
+/// it were sector `NUM_SECTORS`.
+pub const BIG_FILE: &str = "/dir/big.bin";
+pub const BIG_FILE_SIZE: usize = 10_000;
+
+/// The byte at `offset` in `sector` of the image, for sectors 1 up to
+/// `NUM_SECTORS`.
+pub fn byte(sector: u64, offset: usize) -> u8 {
+    match sector.to_le_bytes().get(offset) {
+        Some(&byte) => byte,
+        // Mixing in the high byte of the offset tells a sector's halves
+        // apart.
+        None => (sector as u8).wrapping_mul(7) ^ (offset as u8) ^ (offset >> 8) as u8,
+    }
+}
+
+/// The contents of `BIG_FILE`.
+#[cfg(feature = "alloc")]
+pub fn big_file() -> Vec<u8> {
+    (0..BIG_FILE_SIZE)
+        .map(|offset| byte(NUM_SECTORS, offset))
+        .collect()
+}
+
+/// The whole image, with `extra_files` in the partition as well as `FILES`
+/// and `BIG_FILE`.
+#[cfg(feature = "alloc")]
+pub fn image(extra_files: &[(&str, &[u8])]) -> Vec<u8> {
+    let big_file = big_file();
+    let mut files = FILES.to_vec();
+    files.push((BIG_FILE, &big_file));
+    files.extend_from_slice(extra_files);
+    let partition = crate::fat::write_image(&files);
+
+    let mut image: Vec<u8> = (0..NUM_SECTORS)
+        .flat_map(|sector| (0..SECTOR_SIZE).map(move |offset| byte(sector, offset)))
+        .collect();
+    let mbr: &mut [u8; SECTOR_SIZE] = (&mut image[..SECTOR_SIZE]).try_into().unwrap();
+    mbr.fill(0);
+    crate::fat::write_mbr(
+        mbr,
+        NUM_SECTORS as u32,
+        (partition.len() / SECTOR_SIZE) as u32,
+    );
+    image.extend(partition);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fat::{self, Disk, Volume};
+
+    use std::vec::Vec;
+
+    use pretty_assertions::{assert_eq, assert_ne};
+
+    fn sector(sector: u64) -> Vec<u8> {
+        (0..SECTOR_SIZE)
+            .map(|offset| byte(sector, offset))
+            .collect()
+    }
+
+    #[test]
+    fn sectors_differ() {
+        let sectors: Vec<Vec<u8>> = (0..NUM_SECTORS).map(sector).collect();
+        for (i, a) in sectors.iter().enumerate() {
+            for b in &sectors[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn halves_differ() {
+        let sector = sector(0);
+        let (low, high) = sector.split_at(SECTOR_SIZE / 2);
+        assert_ne!(low, high);
+    }
+
+    /// The partition at `start` in an image.
+    struct Partition<'a> {
+        image: &'a [u8],
+        start: u64,
+    }
+
+    impl Disk for Partition<'_> {
+        type Error = ();
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+            let start = (self.start + sector) as usize * SECTOR_SIZE;
+            buf.copy_from_slice(self.image.get(start..start + buf.len()).ok_or(())?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn image_has_pattern_and_files() {
+        let image = image(&[("/bin/init", b"init")]);
+        for sector_index in 1..NUM_SECTORS {
+            let start = sector_index as usize * SECTOR_SIZE;
+            assert_eq!(image[start..start + SECTOR_SIZE], sector(sector_index));
+        }
+
+        let mbr = image[..SECTOR_SIZE].try_into().unwrap();
+        let partitions: Vec<(u64, u64)> = fat::partitions(mbr).collect();
+        assert_eq!(partitions.len(), 1);
+        let (start, len) = partitions[0];
+        assert_eq!(start, NUM_SECTORS);
+        assert_eq!((start + len) as usize * SECTOR_SIZE, image.len());
+
+        let volume = Volume::mount(Partition {
+            image: &image,
+            start,
+        })
+        .unwrap();
+        let big_file = big_file();
+        let expected = FILES
+            .iter()
+            .copied()
+            .chain([(BIG_FILE, big_file.as_slice()), ("/bin/init", b"init")]);
+        for (path, data) in expected {
+            let node = volume.lookup(path).unwrap();
+            let mut buf = vec![0; data.len()];
+            assert_eq!(volume.read_at(node, 0, &mut buf), Ok(data.len()));
+            assert_eq!(buf, data, "{path}");
+        }
+    }
+}
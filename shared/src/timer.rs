@@ -0,0 +1,149 @@
+//! One-shot and periodic timers
+//!
+//! An `IntervalTimer` counts how many times it has expired, like a Linux
+//! timerfd. Nothing needs to run when it expires: the count is worked out
+//! from the current time whenever it is read. Times are in whatever unit the
+//! caller uses consistently, e.g. scheduler ticks.
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IntervalTimer {
+    /// When the timer next expires, if it is armed.
+    next: Option<u64>,
+    /// The time between expiries, or 0 for a one-shot timer.
+    interval: u64,
+    /// Expiries counted but not yet taken.
+    pending: u64,
+}
+
+impl IntervalTimer {
+    /// A disarmed timer.
+    pub const fn new() -> IntervalTimer {
+        IntervalTimer {
+            next: None,
+            interval: 0,
+            pending: 0,
+        }
+    }
+
+    /// Expire `initial` after `now`, then every `interval` if that is
+    /// nonzero. An `initial` of 0 disarms the timer instead. Discards any
+    /// expiries not yet taken.
+    pub fn set(&mut self, now: u64, initial: u64, interval: u64) {
+        self.next = (initial != 0).then(|| now.saturating_add(initial));
+        self.interval = interval;
+        self.pending = 0;
+    }
+
+    /// When the timer next expires, if it is armed.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.next
+    }
+
+    /// The number of times the timer has expired by `now` since the last
+    /// call, resetting the count.
+    pub fn take_expiries(&mut self, now: u64) -> u64 {
+        self.update(now);
+        core::mem::take(&mut self.pending)
+    }
+
+    /// Whether `take_expiries` would return a nonzero count at `now`.
+    pub fn is_ready(&self, now: u64) -> bool {
+        let mut timer = *self;
+        timer.update(now);
+        timer.pending > 0
+    }
+
+    /// Count the expiries up to `now`.
+    fn update(&mut self, now: u64) {
+        let Some(next) = self.next else {
+            return;
+        };
+        if now < next {
+            return;
+        }
+        if self.interval == 0 {
+            self.next = None;
+            self.pending = self.pending.saturating_add(1);
+            return;
+        }
+        let periods = (now - next) / self.interval + 1;
+        self.pending = self.pending.saturating_add(periods);
+        self.next = next.checked_add(periods * self.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    #[test]
+    fn one_shot() {
+        let mut timer = IntervalTimer::new();
+        timer.set(100, 10, 0);
+        assert_eq!(timer.next_expiry(), Some(110));
+        assert_eq!(timer.take_expiries(109), 0);
+        assert!(timer.is_ready(110));
+        assert_eq!(timer.take_expiries(200), 1);
+        assert_eq!(timer.take_expiries(300), 0);
+        assert_eq!(timer.next_expiry(), None);
+    }
+
+    #[test]
+    fn periodic() {
+        let mut timer = IntervalTimer::new();
+        timer.set(0, 5, 3);
+        assert_eq!(timer.take_expiries(4), 0);
+        assert_eq!(timer.take_expiries(5), 1);
+        assert_eq!(timer.next_expiry(), Some(8));
+        // Expiries at 8, 11 and 14.
+        assert_eq!(timer.take_expiries(15), 3);
+        assert_eq!(timer.next_expiry(), Some(17));
+    }
+
+    #[test]
+    fn set_discards_pending() {
+        let mut timer = IntervalTimer::new();
+        timer.set(0, 1, 1);
+        assert!(timer.is_ready(10));
+        timer.set(10, 0, 0);
+        assert_eq!(timer.next_expiry(), None);
+        assert_eq!(timer.take_expiries(20), 0);
+    }
+
+    #[test]
+    fn far_future() {
+        let mut timer = IntervalTimer::new();
+        timer.set(10, u64::MAX, 0);
+        assert_eq!(timer.next_expiry(), Some(u64::MAX));
+        timer.set(10, 1, u64::MAX);
+        assert_eq!(timer.take_expiries(u64::MAX), 1);
+        assert_eq!(timer.next_expiry(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn reads_add_up(
+            initial in 1..1000u64,
+            interval in 1..100u64,
+            reads in proptest::collection::vec(0..500u64, 1..10),
+        ) {
+            let mut timer = IntervalTimer::new();
+            timer.set(0, initial, interval);
+            let mut now = 0;
+            let mut total = 0;
+            for step in reads {
+                now += step;
+                total += timer.take_expiries(now);
+            }
+            let expected = if now < initial {
+                0
+            } else {
+                (now - initial) / interval + 1
+            };
+            prop_assert_eq!(total, expected);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Scheduler event traces
+//!
+//! The kernel can record each context switch, wake-up and lock acquisition
+//! into a `TraceBuffer`, stamped with a logical clock shared by all CPUs. The
+//! stamps give a total order of events, so the interleaving of tasks across
+//! CPUs can be reconstructed after a failing run. Records are written out as
+//! text lines and parsed back with `Record::parse`.
+
+use core::fmt;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// Something the scheduler did. Tasks and locks are identified by address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// The CPU switched from running `from` to running `to`.
+    Switch { from: u64, to: u64 },
+    /// `task` was made ready to run.
+    Wake { task: u64 },
+    /// The task running on the CPU acquired `lock`.
+    Acquire { lock: u64 },
+    /// The task running on the CPU released `lock`.
+    Release { lock: u64 },
+}
+
+impl Event {
+    const SWITCH: u64 = 1;
+    const WAKE: u64 = 2;
+    const ACQUIRE: u64 = 3;
+    const RELEASE: u64 = 4;
+
+    fn encode(self) -> (u64, u64, u64) {
+        match self {
+            Event::Switch { from, to } => (Self::SWITCH, from, to),
+            Event::Wake { task } => (Self::WAKE, task, 0),
+            Event::Acquire { lock } => (Self::ACQUIRE, lock, 0),
+            Event::Release { lock } => (Self::RELEASE, lock, 0),
+        }
+    }
+
+    fn decode(kind: u64, a: u64, b: u64) -> Option<Event> {
+        Some(match kind {
+            Self::SWITCH => Event::Switch { from: a, to: b },
+            Self::WAKE => Event::Wake { task: a },
+            Self::ACQUIRE => Event::Acquire { lock: a },
+            Self::RELEASE => Event::Release { lock: a },
+            _ => return None,
+        })
+    }
+}
+
+/// An event with the logical time and CPU it happened on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub time: u64,
+    pub cpu: u32,
+    pub event: Event,
+}
+
+impl Record {
+    /// Parse a record formatted with `Display`, e.g. `42 cpu1 wake 0x1000`.
+    pub fn parse(s: &str) -> Option<Record> {
+        let mut words = s.split_whitespace();
+        let time = words.next()?.parse().ok()?;
+        let cpu = words.next()?.strip_prefix("cpu")?.parse().ok()?;
+        let kind = words.next()?;
+        let mut address = || u64::from_str_radix(words.next()?.strip_prefix("0x")?, 16).ok();
+        let event = match kind {
+            "switch" => Event::Switch {
+                from: address()?,
+                to: address()?,
+            },
+            "wake" => Event::Wake { task: address()? },
+            "acquire" => Event::Acquire { lock: address()? },
+            "release" => Event::Release { lock: address()? },
+            _ => return None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        Some(Record { time, cpu, event })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} cpu{} ", self.time, self.cpu)?;
+        match self.event {
+            Event::Switch { from, to } => write!(f, "switch {from:#x} {to:#x}"),
+            Event::Wake { task } => write!(f, "wake {task:#x}"),
+            Event::Acquire { lock } => write!(f, "acquire {lock:#x}"),
+            Event::Release { lock } => write!(f, "release {lock:#x}"),
+        }
+    }
+}
+
+/// A lock-free ring holding the last `N` records. Any number of CPUs may
+/// record concurrently, including from interrupt handlers.
+///
+/// Record `t` goes in slot `t % N`. A slot's stamp is `t + 1` once record `t`
+/// is complete, and 0 while it is being written. A record can only be torn if
+/// `N` more records are made while it is being written.
+pub struct TraceBuffer<const N: usize> {
+    slots: [Slot; N],
+    /// The next logical time.
+    clock: AtomicU64,
+}
+
+struct Slot {
+    stamp: AtomicU64,
+    /// Event kind in the low 32 bits, CPU in the high 32 bits.
+    header: AtomicU64,
+    a: AtomicU64,
+    b: AtomicU64,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            stamp: AtomicU64::new(0),
+            header: AtomicU64::new(0),
+            a: AtomicU64::new(0),
+            b: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<const N: usize> TraceBuffer<N> {
+    pub const fn new() -> Self {
+        assert!(N > 0);
+        TraceBuffer {
+            slots: [const { Slot::new() }; N],
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Record `event` on `cpu`, overwriting the oldest record if the buffer
+    /// is full. Returns the record's time.
+    pub fn record(&self, cpu: u32, event: Event) -> u64 {
+        let time = self.clock.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(time % N as u64) as usize];
+        let (kind, a, b) = event.encode();
+
+        slot.stamp.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.header
+            .store(kind | (cpu as u64) << 32, Ordering::Relaxed);
+        slot.a.store(a, Ordering::Relaxed);
+        slot.b.store(b, Ordering::Relaxed);
+        slot.stamp.store(time + 1, Ordering::Release);
+        time
+    }
+
+    /// Pass each complete record still in the buffer to `f`, oldest first.
+    /// Records being written or overwritten meanwhile are skipped.
+    pub fn for_each(&self, mut f: impl FnMut(Record)) {
+        let end = self.clock.load(Ordering::Relaxed);
+        for time in end.saturating_sub(N as u64)..end {
+            let slot = &self.slots[(time % N as u64) as usize];
+            if slot.stamp.load(Ordering::Acquire) != time + 1 {
+                continue;
+            }
+            let header = slot.header.load(Ordering::Relaxed);
+            let a = slot.a.load(Ordering::Relaxed);
+            let b = slot.b.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if slot.stamp.load(Ordering::Relaxed) != time + 1 {
+                continue;
+            }
+
+            if let Some(event) = Event::decode(header & 0xffff_ffff, a, b) {
+                f(Record {
+                    time,
+                    cpu: (header >> 32) as u32,
+                    event,
+                });
+            }
+        }
+    }
+
+    /// The number of records made, including overwritten ones.
+    pub fn recorded(&self) -> u64 {
+        self.clock.load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for TraceBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            (any::<u64>(), any::<u64>()).prop_map(|(from, to)| Event::Switch { from, to }),
+            any::<u64>().prop_map(|task| Event::Wake { task }),
+            any::<u64>().prop_map(|lock| Event::Acquire { lock }),
+            any::<u64>().prop_map(|lock| Event::Release { lock }),
+        ]
+    }
+
+    fn collect<const N: usize>(buffer: &TraceBuffer<N>) -> Vec<Record> {
+        let mut records = Vec::new();
+        buffer.for_each(|r| records.push(r));
+        records
+    }
+
+    #[test]
+    fn format() {
+        let record = Record {
+            time: 42,
+            cpu: 1,
+            event: Event::Switch {
+                from: 0x1000,
+                to: 0x2000,
+            },
+        };
+        assert_eq!(std::format!("{record}"), "42 cpu1 switch 0x1000 0x2000");
+        assert_eq!(Record::parse("42 cpu1 switch 0x1000 0x2000"), Some(record));
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        assert_eq!(Record::parse(""), None);
+        assert_eq!(Record::parse("42 cpu1"), None);
+        assert_eq!(Record::parse("42 1 wake 0x1000"), None);
+        assert_eq!(Record::parse("42 cpu1 wake 1000"), None);
+        assert_eq!(Record::parse("42 cpu1 switch 0x1000"), None);
+        assert_eq!(Record::parse("42 cpu1 sleep 0x1000"), None);
+    }
+
+    #[test]
+    fn keeps_last_records() {
+        let buffer = TraceBuffer::<4>::new();
+        assert_eq!(collect(&buffer), []);
+
+        for task in 0..6 {
+            buffer.record(task as u32 % 2, Event::Wake { task });
+        }
+        assert_eq!(buffer.recorded(), 6);
+        assert_eq!(
+            collect(&buffer),
+            (2..6)
+                .map(|task| Record {
+                    time: task,
+                    cpu: task as u32 % 2,
+                    event: Event::Wake { task },
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn concurrent_records_are_ordered() {
+        let buffer = TraceBuffer::<4096>::new();
+        std::thread::scope(|s| {
+            for cpu in 0..4 {
+                let buffer = &buffer;
+                s.spawn(move || {
+                    for lock in 0..500 {
+                        buffer.record(cpu, Event::Acquire { lock });
+                    }
+                });
+            }
+        });
+
+        let records = collect(&buffer);
+        assert_eq!(records.len(), 2000);
+        assert!(records.windows(2).all(|w| w[0].time + 1 == w[1].time));
+        // Each CPU's own records are in program order.
+        for cpu in 0..4 {
+            let locks: Vec<u64> = records
+                .iter()
+                .filter(|r| r.cpu == cpu)
+                .map(|r| match r.event {
+                    Event::Acquire { lock } => lock,
+                    e => panic!("unexpected event {e:?}"),
+                })
+                .collect();
+            assert_eq!(locks, (0..500).collect::<Vec<_>>());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(time: u64, cpu: u32, event in event()) {
+            let record = Record { time, cpu, event };
+            prop_assert_eq!(Record::parse(&std::format!("{record}")), Some(record));
+
+            let buffer = TraceBuffer::<1>::new();
+            buffer.record(cpu, event);
+            prop_assert_eq!(
+                collect(&buffer),
+                [Record { time: 0, cpu, event }]
+            );
+        }
+    }
+}
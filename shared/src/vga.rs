@@ -1,42 +1,169 @@
-//! VGA helpers
+//! VGA text mode console
+//!
+//! `VgaWriter` writes to the 80x25 text buffer, scrolling when the screen
+//! fills and moving the hardware cursor. It understands a subset of ANSI
+//! escape sequences: SGR colors (`ESC [ 31 m` etc.), cursor positioning
+//! (`ESC [ row ; col H`), and erasing the screen (`ESC [ 2 J`) or the rest of
+//! the line (`ESC [ K`).
 
 use core::fmt::Write;
 
+use crate::ansi;
+
 const ROWS: usize = 25;
 const COLS: usize = 80;
 
+const TAB_WIDTH: usize = 8;
+
+/// The 16 text mode colors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    LightMagenta = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+impl Color {
+    /// ANSI color numbers (0-7, as in `ESC [ 3n m`) in VGA order.
+    const FROM_ANSI: [Color; 8] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Brown,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightGray,
+    ];
+
+    /// From an ANSI color number, 8-15 being the bright versions.
+    fn from_ansi(n: u8) -> Color {
+        Self::from_u8(Self::FROM_ANSI[n as usize & 7] as u8 | n & 8)
+    }
+
+    fn from_u8(n: u8) -> Color {
+        use Color::*;
+        [
+            Black,
+            Blue,
+            Green,
+            Cyan,
+            Red,
+            Magenta,
+            Brown,
+            LightGray,
+            DarkGray,
+            LightBlue,
+            LightGreen,
+            LightCyan,
+            LightRed,
+            LightMagenta,
+            Yellow,
+            White,
+        ][n as usize & 0xf]
+    }
+}
+
+const DEFAULT_FOREGROUND: Color = Color::LightGray;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+
 pub struct VgaWriter {
     vmem: *mut u8,
+    /// The cell the next character goes in. May be one past the end of the
+    /// screen, in which case the screen scrolls before the next character.
     offset: usize,
+    foreground: Color,
+    background: Color,
+    /// Bold in SGR terms, shown as the bright version of the foreground.
+    bright: bool,
+    hardware_cursor: bool,
+    parser: ansi::Parser,
 }
 
 impl VgaWriter {
-    /// Create formatter writing to raw vga memory at `vmem`.
+    /// Create formatter writing to raw vga memory at `vmem`, and clear the
+    /// screen.
     ///
     /// # Safety
     /// * `vmem` must point to valid VGA memory
     /// * only one instance should exist
+    /// * the VGA CRT controller ports 0x3d4 and 0x3d5 must be safe to use
     pub unsafe fn new(vmem: *mut u8) -> VgaWriter {
-        let mut vga_writer = VgaWriter { vmem, offset: 0 };
+        unsafe { Self::with_hardware_cursor(vmem, true) }
+    }
+
+    unsafe fn with_hardware_cursor(vmem: *mut u8, hardware_cursor: bool) -> VgaWriter {
+        let mut vga_writer = VgaWriter {
+            vmem,
+            offset: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bright: false,
+            hardware_cursor,
+            parser: ansi::Parser::new(),
+        };
         vga_writer.clear();
         vga_writer
     }
 
+    /// Set the colors of characters written from now on.
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+        self.bright = false;
+    }
+
     pub fn clear(&mut self) {
         for i in 0..ROWS {
             self.clear_line(i);
         }
 
         self.offset = 0;
+        self.update_cursor();
+    }
+
+    /// Move to `row` and `col`, counted from 0 and clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.offset = row.min(ROWS - 1) * COLS + col.min(COLS - 1);
+        self.update_cursor();
+    }
+
+    fn attribute(&self) -> u8 {
+        let foreground = self.foreground as u8 | if self.bright { 8 } else { 0 };
+        (self.background as u8) << 4 | foreground
+    }
+
+    fn put(&mut self, cell: usize, b: u8) {
+        let attribute = self.attribute();
+        unsafe {
+            *self.vmem.add(2 * cell) = b;
+            *self.vmem.add(2 * cell + 1) = attribute;
+        }
+    }
+
+    fn clear_cells(&mut self, cells: core::ops::Range<usize>) {
+        for cell in cells {
+            self.put(cell, b' ');
+        }
     }
 
     fn clear_line(&mut self, line: usize) {
         assert!(line < ROWS);
-        for i in 0..COLS {
-            unsafe {
-                *self.vmem.offset(2 * (i + line * COLS) as isize) = 0;
-            }
-        }
+        self.clear_cells(line * COLS..(line + 1) * COLS);
     }
 
     fn scroll(&mut self, lines: usize) {
@@ -64,34 +191,240 @@ impl VgaWriter {
 
         self.offset = self.offset.saturating_sub(lines * COLS);
     }
-}
 
-unsafe impl Send for VgaWriter {}
+    fn update_cursor(&mut self) {
+        if !self.hardware_cursor {
+            return;
+        }
 
-impl Write for VgaWriter {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.chars() {
-            if self.offset >= ROWS * COLS {
-                self.scroll(1);
-                assert!(self.offset < ROWS * COLS);
+        use x86_64::instructions::port::Port;
+
+        const CRTC_INDEX: u16 = 0x3d4;
+        const CRTC_DATA: u16 = 0x3d5;
+        const CURSOR_HIGH: u8 = 0x0e;
+        const CURSOR_LOW: u8 = 0x0f;
+
+        let position = self.offset.min(ROWS * COLS - 1) as u16;
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        unsafe {
+            index.write(CURSOR_HIGH);
+            data.write((position >> 8) as u8);
+            index.write(CURSOR_LOW);
+            data.write(position as u8);
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        let c = match self.parser.feed(c) {
+            ansi::Action::None => return,
+            ansi::Action::Char(c) => c,
+            ansi::Action::Csi { command, params } => {
+                self.run_escape(command, &params);
+                return;
             }
+        };
 
-            if c == '\n' {
-                self.offset = ((self.offset + COLS) / COLS) * COLS;
-                continue;
+        match c {
+            '\n' => {
+                if self.offset >= ROWS * COLS {
+                    self.scroll(1);
+                }
+                self.offset = (self.offset / COLS + 1) * COLS;
+                return;
+            }
+            '\r' => {
+                self.offset -= self.offset % COLS;
+                return;
+            }
+            '\x08' => {
+                self.offset = self.offset.saturating_sub(1);
+                return;
             }
+            '\t' => {
+                if self.offset < ROWS * COLS {
+                    self.offset += TAB_WIDTH - self.offset % TAB_WIDTH;
+                }
+                return;
+            }
+            _ => (),
+        }
 
-            let b = if c.is_ascii() { c as u8 } else { b'?' };
+        if self.offset >= ROWS * COLS {
+            self.scroll(1);
+            assert!(self.offset < ROWS * COLS);
+        }
+
+        let b = if c.is_ascii() && !c.is_ascii_control() {
+            c as u8
+        } else {
+            b'?'
+        };
+        self.put(self.offset, b);
+        self.offset += 1;
+    }
 
-            unsafe {
-                *self.vmem.offset(2 * self.offset as isize) = b;
+    /// Act on a CSI sequence ending in `command`.
+    fn run_escape(&mut self, command: char, params: &ansi::Params) {
+        let param = |i, default| params.get(i, default);
+
+        match command {
+            'm' => {
+                for sgr in params.sgr() {
+                    match sgr {
+                        ansi::Sgr::Reset => self.set_colors(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+                        ansi::Sgr::Bold(bright) => self.bright = bright,
+                        ansi::Sgr::Foreground(color) => {
+                            self.foreground = color.map_or(DEFAULT_FOREGROUND, Color::from_ansi)
+                        }
+                        ansi::Sgr::Background(color) => {
+                            self.background = color.map_or(DEFAULT_BACKGROUND, Color::from_ansi)
+                        }
+                    }
+                }
+            }
+            // Cursor position, counted from 1.
+            'H' | 'f' => self.set_position(param(0, 1) as usize - 1, param(1, 1) as usize - 1),
+            // Erase in display. Only erasing everything is supported.
+            'J' if param(0, 0) == 2 => self.clear(),
+            // Erase to the end of the line.
+            'K' if param(0, 0) == 0 && self.offset < ROWS * COLS => {
+                let end = (self.offset / COLS + 1) * COLS;
+                self.clear_cells(self.offset..end);
             }
+            _ => (),
+        }
+    }
+}
+
+unsafe impl Send for VgaWriter {}
 
-            self.offset += 1;
+impl Write for VgaWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
         }
+        self.update_cursor();
 
         Ok(())
     }
 }
 
 pub type VgaLog = crate::log::LogSink<VgaWriter>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    use pretty_assertions::assert_eq;
+
+    struct Screen {
+        vmem: Vec<u8>,
+        writer: VgaWriter,
+    }
+
+    impl Screen {
+        fn new() -> Screen {
+            let mut vmem = vec![0; ROWS * COLS * 2];
+            let writer = unsafe { VgaWriter::with_hardware_cursor(vmem.as_mut_ptr(), false) };
+            Screen { vmem, writer }
+        }
+
+        fn row(&self, row: usize) -> String {
+            let cells = &self.vmem[row * COLS * 2..(row + 1) * COLS * 2];
+            let text: String = cells.iter().step_by(2).map(|&b| b as char).collect();
+            text.trim_end().into()
+        }
+
+        fn attribute(&self, row: usize, col: usize) -> u8 {
+            self.vmem[(row * COLS + col) * 2 + 1]
+        }
+    }
+
+    #[test]
+    fn lines_and_scrolling() {
+        let mut screen = Screen::new();
+        for i in 0..ROWS {
+            writeln!(screen.writer, "line {i}").unwrap();
+        }
+        // The trailing newline doesn't scroll until something is written.
+        assert_eq!(screen.row(0), "line 0");
+        assert_eq!(screen.row(ROWS - 1), "line 24");
+
+        write!(screen.writer, "last\n\nend").unwrap();
+        assert_eq!(screen.row(0), "line 3");
+        assert_eq!(screen.row(ROWS - 4), "line 24");
+        assert_eq!(screen.row(ROWS - 3), "last");
+        assert_eq!(screen.row(ROWS - 2), "");
+        assert_eq!(screen.row(ROWS - 1), "end");
+    }
+
+    #[test]
+    fn long_lines_wrap() {
+        let mut screen = Screen::new();
+        let line: String = (0..COLS + 5)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        write!(screen.writer, "{line}").unwrap();
+        assert_eq!(screen.row(0), &line[..COLS]);
+        assert_eq!(screen.row(1), &line[COLS..]);
+    }
+
+    #[test]
+    fn control_characters() {
+        let mut screen = Screen::new();
+        write!(screen.writer, "abc\rX\tY\x08Z\u{e9}").unwrap();
+        assert_eq!(screen.row(0), "Xbc     Z?");
+    }
+
+    #[test]
+    fn colors() {
+        let mut screen = Screen::new();
+        assert_eq!(screen.attribute(0, 0), 0x07);
+
+        write!(
+            screen.writer,
+            "\x1b[31ma\x1b[1;44mb\x1b[0mc\x1b[93;101md\x1b[me"
+        )
+        .unwrap();
+        assert_eq!(screen.row(0), "abcde");
+        assert_eq!(screen.attribute(0, 0), 0x04);
+        assert_eq!(screen.attribute(0, 1), 0x1c);
+        assert_eq!(screen.attribute(0, 2), 0x07);
+        assert_eq!(screen.attribute(0, 3), 0xce);
+        assert_eq!(screen.attribute(0, 4), 0x07);
+
+        screen.writer.set_colors(Color::White, Color::Blue);
+        write!(screen.writer, "f").unwrap();
+        assert_eq!(screen.attribute(0, 5), 0x1f);
+    }
+
+    #[test]
+    fn cursor_and_erase() {
+        let mut screen = Screen::new();
+        write!(screen.writer, "hello\nworld").unwrap();
+        write!(screen.writer, "\x1b[1;3H\x1b[K").unwrap();
+        assert_eq!(screen.row(0), "he");
+        assert_eq!(screen.row(1), "world");
+
+        write!(screen.writer, "\x1b[2;6Hwide\x1b[99;99H!").unwrap();
+        assert_eq!(screen.row(1), "worldwide");
+        assert_eq!(screen.row(ROWS - 1).len(), COLS);
+
+        write!(screen.writer, "\x1b[2J").unwrap();
+        assert!((0..ROWS).all(|row| screen.row(row).is_empty()));
+        write!(screen.writer, "top").unwrap();
+        assert_eq!(screen.row(0), "top");
+    }
+
+    #[test]
+    fn unknown_escapes_are_ignored() {
+        let mut screen = Screen::new();
+        write!(screen.writer, "a\x1b[5;1;2;3;4;5qb\x1b7c\x1b[?25ld").unwrap();
+        assert_eq!(screen.row(0), "abcd");
+    }
+}
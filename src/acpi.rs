@@ -0,0 +1,107 @@
+//! Access to the firmware's ACPI tables
+//!
+//! Parsing lives in `shared::acpi`. This finds the tables through the RSDP in
+//! the multiboot2 info, or by searching the BIOS areas for it, and reads them
+//! through the physical memory map.
+
+use crate::mm::{self, PhysAddress};
+
+use log::{info, warn};
+use multiboot2 as mb2;
+use shared::acpi::{AcpiError, Hpet, RootTable, Rsdp, TableMemory};
+
+static ROOT_TABLE: spin::Mutex<Option<RootTable>> = spin::Mutex::new(None);
+
+/// Reads tables through the physical memory map, which covers all memory the
+/// firmware reported, including its ACPI areas.
+struct PhysTableMemory;
+
+impl TableMemory for PhysTableMemory {
+    fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+        let extent = mm::PhysExtent::new_checked(
+            PhysAddress::from_raw(address),
+            mm::Length::from_raw(len as u64),
+        )?;
+        if !mm::is_phys_mapped(extent) {
+            return None;
+        }
+        // SAFETY: ACPI tables are never written after boot, and the memory
+        // they are in is never handed out by the frame allocator.
+        Some(unsafe { &*mm::phys_extent_to_virt(extent).as_slice() })
+    }
+}
+
+/// Find the root table from the RSDP the boot loader gave us, or failing that
+/// from the one the BIOS left in low memory. Must be called after `mm::init`.
+pub fn init(mbinfo: &mb2::BootInformation) {
+    let from_boot_loader = if let Some(tag) = mbinfo.rsdp_v2_tag() {
+        Some(unsafe { parse_rsdp_tag(tag) })
+    } else {
+        mbinfo
+            .rsdp_v1_tag()
+            .map(|tag| unsafe { parse_rsdp_tag(tag) })
+    };
+
+    let rsdp = match from_boot_loader {
+        Some(Ok(rsdp)) => Some(rsdp),
+        Some(Err(e)) => {
+            warn!("boot loader's RSDP is invalid: {e}");
+            None
+        }
+        None => {
+            warn!("boot loader did not provide an RSDP, searching for it");
+            None
+        }
+    }
+    .or_else(|| shared::acpi::find_rsdp(&PhysTableMemory));
+
+    let Some(rsdp) = rsdp else {
+        warn!("no ACPI tables");
+        return;
+    };
+
+    let root = rsdp.root_table();
+    info!(
+        "ACPI {} from {:?}, root table: {root:x?}",
+        rsdp.revision(),
+        rsdp.oem_id().escape_ascii()
+    );
+    *ROOT_TABLE.lock() = Some(root);
+
+    match find_table(Hpet::SIGNATURE).and_then(|hpet| hpet.map(Hpet::parse).transpose()) {
+        Ok(Some(hpet)) => info!(
+            "HPET {} at {:#x}: {} comparators, min tick {}",
+            hpet.hpet_number(),
+            hpet.base_address(),
+            hpet.num_comparators(),
+            hpet.min_tick()
+        ),
+        Ok(None) => info!("no HPET"),
+        Err(e) => warn!("bad HPET table: {e}"),
+    }
+}
+
+/// Parse the copy of the RSDP in a multiboot2 RSDP tag.
+///
+/// # Safety
+///
+/// `T` must be one of the RSDP tag types, which hold the RSDP right after the
+/// 8 byte tag header.
+unsafe fn parse_rsdp_tag<T>(tag: &T) -> Result<Rsdp, AcpiError> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            (tag as *const T).cast::<u8>().add(8),
+            core::mem::size_of::<T>() - 8,
+        )
+    };
+    Rsdp::parse(bytes)
+}
+
+/// Find the table with `signature`. Returns `Ok(None)` if there are no ACPI
+/// tables or no such table.
+pub fn find_table(signature: &[u8; 4]) -> Result<Option<&'static [u8]>, AcpiError> {
+    let Some(root) = *ROOT_TABLE.lock() else {
+        return Ok(None);
+    };
+    shared::acpi::find_table(&PhysTableMemory, root, signature)
+}
@@ -0,0 +1,412 @@
+//! Local APIC and IOAPIC support
+//!
+//! The local APIC is used in x2APIC mode when the CPU supports it, and
+//! through its memory mapped registers otherwise. ISA IRQs are routed to the
+//! boot CPU through the IOAPIC that handles them, as described by the ACPI
+//! MADT. The boot CPU also uses its local APIC to start the others.
+
+use crate::acpi;
+use crate::arch::msr::{self, Feature};
+use crate::idt::install_interrupt_handler;
+use crate::irq::{IRQ_INTERRUPT_OFFSET, NUM_IRQS};
+use crate::mm::{self, PhysExtent, VolatileRegion};
+use crate::vectors::{self, Route};
+
+use log::info;
+use shared::acpi::{AcpiError, Madt, MadtEntry, Polarity, TriggerMode};
+use x86_64::instructions::segmentation::GS;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Vector for the local APIC's spurious interrupts. Its low four bits must be
+/// set on older CPUs.
+const SPURIOUS_VECTOR: u8 = 0xff;
+
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets, as in the xAPIC MMIO layout.
+const REG_ID: u32 = 0x20;
+const REG_TASK_PRIORITY: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SPURIOUS: u32 = 0xf0;
+/// The first of eight in-service registers, each 32 vectors' bits.
+const REG_IN_SERVICE: u32 = 0x100;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_LINT0: u32 = 0x350;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SELF: u32 = 0b01 << 18;
+
+#[derive(Debug)]
+pub enum InitError {
+    /// The CPU has no local APIC.
+    NoLocalApic,
+    /// There is no MADT describing the interrupt controllers.
+    NoMadt,
+    /// The ACPI tables couldn't be read.
+    Acpi(AcpiError),
+    /// No IOAPIC handles the ISA IRQs.
+    NoIoApic,
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::NoLocalApic => write!(f, "no local APIC"),
+            InitError::NoMadt => write!(f, "no MADT"),
+            InitError::Acpi(e) => write!(f, "bad ACPI tables: {e}"),
+            InitError::NoIoApic => write!(f, "no IOAPIC for ISA IRQs"),
+        }
+    }
+}
+
+impl From<AcpiError> for InitError {
+    fn from(e: AcpiError) -> Self {
+        InitError::Acpi(e)
+    }
+}
+
+enum LocalApic {
+    /// Registers are memory mapped.
+    XApic(VolatileRegion),
+    /// Registers are MSRs.
+    X2Apic,
+}
+
+impl LocalApic {
+    fn read(&self, reg: u32) -> u32 {
+        match self {
+            LocalApic::XApic(regs) => regs.read(reg as usize),
+            // x2APIC mode is only used if supported, and `msr` logs otherwise.
+            LocalApic::X2Apic => msr::x2apic(reg).read().unwrap_or(0) as u32,
+        }
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        match self {
+            LocalApic::XApic(regs) => unsafe { regs.write(reg as usize, value) },
+            LocalApic::X2Apic => {
+                let _ = unsafe { msr::x2apic(reg).write(value.into()) };
+            }
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(_) => self.read(REG_ID) >> 24,
+            LocalApic::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    /// Enable the executing CPU's local APIC in this mode. Each CPU has its
+    /// own, so this is done once on every CPU.
+    unsafe fn enable(&self) {
+        // `init` checked for a local APIC, and `msr` logs if there isn't one.
+        let Ok(base) = msr::APIC_BASE.read() else {
+            return;
+        };
+        unsafe {
+            // xAPIC mode must be enabled before x2APIC mode.
+            let _ = msr::APIC_BASE.write(base | APIC_BASE_GLOBAL_ENABLE);
+            if let LocalApic::X2Apic = self {
+                let _ =
+                    msr::APIC_BASE.write(base | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE);
+            }
+
+            self.write(REG_TASK_PRIORITY, 0);
+            // The PICs deliver through LINT0. They are masked, but make sure.
+            self.write(REG_LVT_LINT0, LVT_MASKED);
+            self.write(
+                REG_SPURIOUS,
+                SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+            );
+        }
+    }
+
+    /// Send an interprocessor interrupt described by `command`, the low half
+    /// of the interrupt command register, to the CPU with APIC ID
+    /// `destination`.
+    unsafe fn send_ipi(&self, destination: u32, command: u32) {
+        match self {
+            LocalApic::XApic(_) => unsafe {
+                self.write(REG_ICR_HIGH, destination << 24);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            },
+            // The x2APIC command register is a single 64 bit MSR.
+            LocalApic::X2Apic => {
+                let _ = unsafe {
+                    msr::x2apic(REG_ICR_LOW)
+                        .write(u64::from(destination) << 32 | u64::from(command))
+                };
+            }
+        }
+    }
+}
+
+struct IoApic {
+    /// `IOREGSEL`, with `IOWIN` at offset 0x10.
+    regs: VolatileRegion,
+
+    /// The first global system interrupt this IOAPIC handles.
+    gsi_base: u32,
+
+    /// The redirection table entry each ISA IRQ is routed through, if this
+    /// IOAPIC handles it.
+    irq_entries: [Option<u32>; NUM_IRQS as usize],
+}
+
+impl IoApic {
+    const REG_VERSION: u32 = 0x01;
+    const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+    const ACTIVE_LOW: u32 = 1 << 13;
+    const LEVEL_TRIGGERED: u32 = 1 << 15;
+    const MASKED: u32 = 1 << 16;
+
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            self.regs.write(0, reg);
+            self.regs.read(0x10)
+        }
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            self.regs.write(0, reg);
+            self.regs.write(0x10, value);
+        }
+    }
+
+    fn num_entries(&mut self) -> u32 {
+        ((self.read(Self::REG_VERSION) >> 16) & 0xff) + 1
+    }
+
+    unsafe fn set_entry(&mut self, entry: u32, low: u32, destination: u32) {
+        let reg = Self::REG_REDIRECTION_TABLE + 2 * entry;
+        unsafe {
+            // Mask first so a half written entry is never live.
+            self.write(reg, Self::MASKED);
+            self.write(reg + 1, destination << 24);
+            self.write(reg, low);
+        }
+    }
+
+    fn set_masked(&mut self, irq_num: u8, masked: bool) {
+        let entry = self.irq_entries[irq_num as usize]
+            .unwrap_or_else(|| panic!("IRQ {irq_num} is not routed through the IOAPIC"));
+        let reg = Self::REG_REDIRECTION_TABLE + 2 * entry;
+        let low = self.read(reg);
+        let low = if masked {
+            low | Self::MASKED
+        } else {
+            low & !Self::MASKED
+        };
+        unsafe {
+            self.write(reg, low);
+        }
+    }
+}
+
+static LOCAL_APIC: spin::Once<LocalApic> = spin::Once::new();
+
+static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+
+/// Enable the boot CPU's local APIC and route ISA IRQs to it, all masked. On
+/// failure nothing is changed. Must be called with interrupts disabled and
+/// the PICs masked.
+pub unsafe fn init() -> Result<(), InitError> {
+    if !Feature::Apic.is_present() {
+        return Err(InitError::NoLocalApic);
+    }
+    let has_x2apic = Feature::X2Apic.is_present();
+
+    let madt = acpi::find_table(Madt::SIGNATURE)?.ok_or(InitError::NoMadt)?;
+    let madt = Madt::parse(madt)?;
+
+    // TODO: support more than one IOAPIC.
+    let (io_apic_address, gsi_base) = madt
+        .entries()
+        .find_map(|entry| match entry {
+            MadtEntry::IoApic {
+                address, gsi_base, ..
+            } if gsi_base < u32::from(NUM_IRQS) => Some((address, gsi_base)),
+            _ => None,
+        })
+        .ok_or(InitError::NoIoApic)?;
+
+    let local_apic = if has_x2apic {
+        LocalApic::X2Apic
+    } else {
+        LocalApic::XApic(unsafe {
+            mm::map_mmio(PhysExtent::from_raw(madt.local_apic_address(), 4096))
+        })
+    };
+
+    vectors::claim(SPURIOUS_VECTOR, "apic spurious", Route::AllCpus).unwrap();
+    unsafe {
+        install_interrupt_handler(SPURIOUS_VECTOR, Some(spurious_interrupt_handler));
+        local_apic.enable();
+    }
+
+    let apic_id = local_apic.id();
+    info!(
+        "Local APIC {apic_id} ({}), IOAPIC at {io_apic_address:#x}",
+        if has_x2apic { "x2APIC" } else { "xAPIC" }
+    );
+    // TODO: destinations above 255 need interrupt remapping.
+    assert!(apic_id <= 0xff, "APIC ID {apic_id} too large");
+
+    let regs = unsafe { mm::map_mmio(PhysExtent::from_raw(io_apic_address.into(), 4096)) };
+    let mut io_apic = IoApic {
+        regs,
+        gsi_base,
+        irq_entries: [None; NUM_IRQS as usize],
+    };
+    let num_entries = io_apic.num_entries();
+
+    for irq in 0..NUM_IRQS {
+        // ISA IRQs are identity mapped, edge triggered, and active high
+        // unless overridden.
+        let (gsi, polarity, trigger_mode) = madt
+            .entries()
+            .find_map(|entry| match entry {
+                MadtEntry::InterruptSourceOverride { source, gsi, flags } if source == irq => {
+                    Some((gsi, flags.polarity(), flags.trigger_mode()))
+                }
+                _ => None,
+            })
+            .unwrap_or((irq.into(), Polarity::Conforming, TriggerMode::Conforming));
+
+        let Some(entry) = gsi
+            .checked_sub(io_apic.gsi_base)
+            .filter(|&entry| entry < num_entries)
+        else {
+            continue;
+        };
+        io_apic.irq_entries[irq as usize] = Some(entry);
+
+        let mut low = u32::from(IRQ_INTERRUPT_OFFSET + irq) | IoApic::MASKED;
+        if polarity == Polarity::ActiveLow {
+            low |= IoApic::ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            low |= IoApic::LEVEL_TRIGGERED;
+        }
+        unsafe {
+            io_apic.set_entry(entry, low, apic_id);
+        }
+    }
+
+    LOCAL_APIC.call_once(|| local_apic);
+    *IO_APIC.lock() = Some(io_apic);
+    Ok(())
+}
+
+/// Whether `init` succeeded, so the local APIC can be used.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.get().is_some()
+}
+
+/// Enable the local APIC of an application processor in the same mode as the
+/// boot CPU's. Must be called on that processor with interrupts disabled,
+/// after `init` succeeded.
+pub unsafe fn init_ap() {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.enable();
+    }
+}
+
+/// Send an INIT IPI to the CPU with local APIC ID `apic_id`, which resets it
+/// to wait for a startup IPI.
+pub unsafe fn send_init(apic_id: u32) {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+}
+
+/// Send a startup IPI to the CPU with local APIC ID `apic_id`. A CPU waiting
+/// after an INIT IPI starts in real mode at physical address `page << 12`.
+pub unsafe fn send_startup(apic_id: u32, page: u8) {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.send_ipi(
+            apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page),
+        );
+    }
+}
+
+/// Send an NMI to the CPU with local APIC ID `apic_id`. It is delivered even
+/// if the CPU has interrupts disabled.
+pub unsafe fn send_nmi(apic_id: u32) {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.send_ipi(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+    }
+}
+
+/// Interrupt the executing CPU with `vector`, as a device would.
+pub unsafe fn send_self(vector: u8) {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.send_ipi(
+            0,
+            ICR_LEVEL_ASSERT | ICR_DESTINATION_SELF | u32::from(vector),
+        );
+    }
+}
+
+pub fn set_masked(irq_num: u8, masked: bool) {
+    IO_APIC
+        .lock()
+        .as_mut()
+        .expect("apic::init did not succeed")
+        .set_masked(irq_num, masked);
+}
+
+/// Whether the executing CPU's local APIC delivered `vector` and is waiting
+/// for `end_of_interrupt`. Vectors raised with `int` never are.
+pub fn is_in_service(vector: u8) -> bool {
+    let Some(local_apic) = LOCAL_APIC.get() else {
+        return false;
+    };
+    let reg = REG_IN_SERVICE + 0x10 * u32::from(vector / 32);
+    local_apic.read(reg) & (1 << (vector % 32)) != 0
+}
+
+/// Signal the end of the interrupt being handled.
+pub fn end_of_interrupt() {
+    let local_apic = LOCAL_APIC.get().expect("apic::init did not succeed");
+    unsafe {
+        local_apic.write(REG_EOI, 0);
+    }
+}
+
+/// Spurious interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(stack: InterruptStackFrame) {
+    // Counting one uses the kernel's GS base, which an interrupt from user
+    // mode doesn't have.
+    let from_user = stack.code_segment & 3 == 3;
+    unsafe {
+        if from_user {
+            GS::swap();
+        }
+        vectors::record(SPURIOUS_VECTOR);
+        if from_user {
+            GS::swap();
+        }
+    }
+}
@@ -0,0 +1,7 @@
+//! x86_64 specific helpers
+
+pub mod fpu;
+pub mod gdt;
+pub mod msr;
+pub mod quirks;
+pub mod regs;
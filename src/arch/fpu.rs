@@ -0,0 +1,217 @@
+//! x87, SSE and AVX state
+//!
+//! The kernel is built without floating point or SIMD, but user programs use
+//! SSE, so every task has its own copy of those registers in an `FpuState`,
+//! saved and restored whenever the scheduler switches tasks. Switching is
+//! eager: trapping the first use after a switch with CR0.TS would save work
+//! for tasks that never touch the registers, but costs a fault in every task
+//! that does, and user processes usually do.
+//!
+//! With XSAVE, `init` enables x87, SSE and, if the CPU has it, AVX state in
+//! XCR0, and the save area is as large as the CPU says they need. Without
+//! it, FXSAVE saves x87 and SSE state in 512 bytes.
+
+use super::msr::Feature;
+use super::regs::{self, Cr4Flags, XCr0Flags};
+use crate::itest::{kernel_test, TestResult};
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use alloc::format;
+use core::alloc::Layout;
+use core::arch::x86_64::{__cpuid_count, _fxrstor64, _fxsave64, _xrstor64, _xsave64};
+use core::ptr::NonNull;
+
+use log::info;
+use x86_64::registers::mxcsr::{self, MxCsr};
+
+/// How tasks' state is saved, the same on every CPU.
+#[derive(Clone, Copy, Debug)]
+struct Format {
+    /// The components XSAVE saves, or `None` to use FXSAVE.
+    xsave: Option<XCr0Flags>,
+    /// The save area's size in bytes.
+    size: usize,
+}
+
+static FORMAT: spin::Once<Format> = spin::Once::new();
+
+const FXSAVE_SIZE: usize = 512;
+
+/// XSAVE needs 64 byte alignment, and FXSAVE 16.
+const ALIGN: usize = 64;
+
+// Where the legacy region of either format holds the x87 control word and
+// MXCSR.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// The x87 control word after `fninit`: all exceptions masked, 64 bit
+/// precision, rounding to nearest.
+const DEFAULT_FCW: u16 = 0x37f;
+
+/// MXCSR at reset: all exceptions masked, rounding to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Let the boot CPU run x87, SSE and, where supported, AVX instructions, and
+/// choose how tasks' state is saved. Must be called once, before any task is
+/// created.
+pub fn init() {
+    let xsave = Feature::Xsave.is_present().then(|| {
+        // The components the CPU can save.
+        let supported = __cpuid_count(0xd, 0).eax;
+        let mut features = XCr0Flags::X87 | XCr0Flags::SSE;
+        if supported & XCr0Flags::AVX.bits() as u32 != 0 {
+            features |= XCr0Flags::AVX;
+        }
+        features
+    });
+    let size = enable(xsave);
+    let format = FORMAT.call_once(|| Format { xsave, size });
+    match format.xsave {
+        Some(features) => info!("Saving {features:?} with XSAVE, {size} bytes per task"),
+        None => info!("Saving x87 and SSE state with FXSAVE"),
+    }
+}
+
+/// Set up an application processor like `init` did the boot CPU.
+pub fn init_ap() {
+    let format = FORMAT.get().expect("fpu::init wasn't called");
+    let size = enable(format.xsave);
+    assert_eq!(
+        size, format.size,
+        "this CPU's XSAVE area differs from the boot CPU's"
+    );
+}
+
+/// Enable the registers on this CPU, saved with XSAVE if `xsave` says which
+/// components. Returns the save area's size.
+fn enable(xsave: Option<XCr0Flags>) -> usize {
+    // SAFETY: long mode implies SSE and FXSAVE, and the scheduler switches
+    // the state with `FpuState`.
+    unsafe {
+        regs::enable_native_fpu();
+        regs::enable_cr4_features(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+    }
+    let Some(features) = xsave else {
+        return FXSAVE_SIZE;
+    };
+    // SAFETY: the CPU supports XSAVE and `features`, as `init` checked.
+    unsafe {
+        regs::enable_cr4_features(Cr4Flags::OSXSAVE);
+        regs::set_xsave_features(features);
+    }
+    // The size for the components now enabled in XCR0.
+    __cpuid_count(0xd, 0).ebx as usize
+}
+
+fn format() -> Format {
+    *FORMAT.get().expect("fpu::init wasn't called")
+}
+
+/// A task's copy of the registers while it isn't running.
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+// SAFETY: the area is owned, like a `Box`.
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// The state a task starts with: as after `fninit`, with no SSE
+    /// exceptions unmasked.
+    pub fn new() -> Self {
+        let layout = Self::layout();
+        // SAFETY: the layout isn't zero sized.
+        let area = unsafe { alloc_zeroed(layout) };
+        let area = NonNull::new(area).unwrap_or_else(|| handle_alloc_error(layout));
+        // With XSAVE, the zeroed header marks every component as in its
+        // initial state, except that MXCSR is always loaded from the area.
+        // SAFETY: both are within the legacy region, and aligned.
+        unsafe {
+            area.as_ptr()
+                .add(FCW_OFFSET)
+                .cast::<u16>()
+                .write(DEFAULT_FCW);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+        FpuState { area }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(format().size, ALIGN).unwrap()
+    }
+
+    /// Save this CPU's registers.
+    ///
+    /// # Safety
+    ///
+    /// `init` or `init_ap` must have run on this CPU.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        // SAFETY: the area is aligned and as large as the format needs, and
+        // the caller vouches that the CPU has the features enabled.
+        unsafe {
+            match format().xsave {
+                Some(_) => _xsave64(area, u64::MAX),
+                None => _fxsave64(area),
+            }
+        }
+    }
+
+    /// Load this CPU's registers from the last `save`, or the initial state
+    /// if there wasn't one.
+    ///
+    /// # Safety
+    ///
+    /// As `save`.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        // SAFETY: as in `save`. The area holds either the state `new`
+        // wrote or what `save` saved, both valid to load.
+        unsafe {
+            match format().xsave {
+                Some(_) => _xrstor64(area, u64::MAX),
+                None => _fxrstor64(area),
+            }
+        }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with the same layout.
+        unsafe { dealloc(self.area.as_ptr(), Self::layout()) };
+    }
+}
+
+kernel_test!("fpu: saved state is restored", saved_state_is_restored);
+fn saved_state_is_restored() -> TestResult {
+    let original = mxcsr::read();
+    let rounding = MxCsr::ROUNDING_CONTROL_ZERO;
+    let mut saved = FpuState::new();
+    let fresh = FpuState::new();
+
+    // SAFETY: `init` ran during boot. Only MXCSR changes, which nothing in
+    // the kernel depends on, and it is put back.
+    let (after_fresh, after_saved) = unsafe {
+        mxcsr::write(original | rounding);
+        saved.save();
+        fresh.restore();
+        let after_fresh = mxcsr::read();
+        saved.restore();
+        let after_saved = mxcsr::read();
+        mxcsr::write(original);
+        (after_fresh, after_saved)
+    };
+
+    if after_fresh.bits() != DEFAULT_MXCSR {
+        return Err(format!("a new state has MXCSR {after_fresh:?}"));
+    }
+    if after_saved != original | rounding {
+        return Err(format!("MXCSR was restored as {after_saved:?}"));
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Routines to set up the x86_64 GDT and TSS
+//!
+//! The GDT in 64 bit mode has limited capabilities. It is important for
+//! switching between userspace and kernel space, entering 32-bit
+//! compatibility mode, and a couple other random things.
+//!
+//! Each CPU has its own GDT, since each needs its own TSS. The boot CPU's are
+//! statics; application processors' are allocated when they start.
+//!
+//! The GDT holds kernel and user code/data segments plus the TSS. The segment
+//! order is fixed by the SYSCALL/SYSRET conventions: kernel data must follow
+//! kernel code, and user code must follow user data.
+//!
+//! The TSS gives the stacks the CPU switches to: one per IST slot for
+//! exceptions that can't trust the current stack, and RSP0 for interrupts
+//! from ring 3. RSP0 must be the running task's kernel stack, so the
+//! scheduler sets it with `set_kernel_stack` whenever it switches to a task
+//! that runs user code.
+use x86_64::instructions::segmentation::*;
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::*;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::PrivilegeLevel;
+use x86_64::VirtAddr;
+
+use spin::mutex::{SpinMutex, SpinMutexGuard};
+
+use crate::mm::{self, Length, VirtAddress};
+use crate::smp::{PerCpu, MAX_CPUS};
+
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+static GDT: SpinMutex<GlobalDescriptorTable> = SpinMutex::new(GlobalDescriptorTable::new());
+
+// The CPU reads the TSS whenever it switches to ring 0 from a lower privilege
+// level. It must never move once loaded.
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Each CPU's TSS, once loaded. Only the CPU itself changes its TSS, through
+/// `set_kernel_stack`.
+static TSS: PerCpu<AtomicPtr<TaskStateSegment>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS]);
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// The interrupt stack table slot of the stack double faults are handled on.
+/// A double fault is often a kernel stack overflow, so it can't use the stack
+/// it happened on.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// The interrupt stack table slot of the stack NMIs are handled on. An NMI
+/// can arrive at any instruction, including while the stack is unusable.
+pub const NMI_IST_INDEX: u16 = 1;
+
+/// The interrupt stack table slot of the stack machine checks are handled on,
+/// for the same reason as NMIs.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_INDEXES: [u16; 3] = [
+    DOUBLE_FAULT_IST_INDEX,
+    NMI_IST_INDEX,
+    MACHINE_CHECK_IST_INDEX,
+];
+
+const IST_STACK_ORDER: usize = 2;
+const IST_STACK_LEN: usize = (1 << IST_STACK_ORDER) * mm::PAGE_SIZE.as_raw() as usize;
+
+#[derive(Clone, Copy)]
+#[repr(align(16))]
+#[allow(unused)] // Only the CPU touches the contents.
+struct IstStack([u8; IST_STACK_LEN]);
+
+/// The boot CPU's interrupt stacks, in `IST_INDEXES` order. They are set up
+/// before the frame allocator, so they can't be allocated. Only the CPU writes
+/// to them.
+static mut BSP_IST_STACKS: [IstStack; IST_INDEXES.len()] =
+    [IstStack([0; IST_STACK_LEN]); IST_INDEXES.len()];
+
+/// Set up and load the boot CPU's GDT and TSS. Must be called before
+/// `smp::init_bsp`, since loading segment registers clears the GS base.
+pub fn init() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    let stacks = ptr::addr_of!(BSP_IST_STACKS);
+    let tss = ptr::addr_of_mut!(BSP_TSS);
+    for (i, index) in IST_INDEXES.into_iter().enumerate() {
+        // SAFETY: `i` is in bounds, and only the address is taken. Nothing
+        // else uses the TSS yet.
+        unsafe {
+            let stack = ptr::addr_of!((*stacks)[i]);
+            (*tss).interrupt_stack_table[index as usize] =
+                VirtAddr::from_ptr(stack) + IST_STACK_LEN;
+        }
+    }
+    load(SpinMutexGuard::leak(GDT.lock()), 0, tss);
+}
+
+/// Give application processor `cpu` its own GDT and TSS and load them. They
+/// are leaked, since CPUs are never taken offline. Must be called before the
+/// CPU's GS base is set, like `init`.
+pub fn init_ap(cpu: usize) {
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    for index in IST_INDEXES {
+        tss.interrupt_stack_table[index as usize] = allocate_ist_stack();
+    }
+    load(gdt, cpu, tss);
+}
+
+/// Allocate an interrupt stack for an application processor, returning its
+/// top. It is never freed.
+fn allocate_ist_stack() -> VirtAddr {
+    let frames = mm::allocate_frames(IST_STACK_ORDER).expect("out of memory for interrupt stack");
+    let top = mm::phys_to_virt(frames.first().start()) + Length::from_raw(IST_STACK_LEN as u64);
+    VirtAddr::new(top.as_raw())
+}
+
+/// Fill in `gdt`, which must be empty, with the kernel's segments and `tss`,
+/// then load it on the executing CPU, CPU `cpu`. `tss` must never move or be
+/// freed.
+fn load(gdt: &'static mut GlobalDescriptorTable, cpu: usize, tss: *mut TaskStateSegment) {
+    let previous = TSS.get_for(cpu).swap(tss, Ordering::Relaxed);
+    assert!(previous.is_null(), "CPU {cpu}'s GDT loaded twice");
+    // SAFETY: the descriptor only captures the address, and the caller
+    // vouches that it stays valid. Later changes go through `TSS`.
+    let tss: &'static TaskStateSegment = unsafe { &*tss };
+
+    assert_eq!(
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        KERNEL_CODE_SELECTOR
+    );
+    assert_eq!(
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        KERNEL_DATA_SELECTOR
+    );
+    assert_eq!(
+        gdt.add_entry(Descriptor::user_data_segment()),
+        USER_DATA_SELECTOR
+    );
+    assert_eq!(
+        gdt.add_entry(Descriptor::user_code_segment()),
+        USER_CODE_SELECTOR
+    );
+    assert_eq!(gdt.add_entry(Descriptor::tss_segment(tss)), TSS_SELECTOR);
+
+    gdt.load();
+
+    unsafe {
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        DS::set_reg(KERNEL_DATA_SELECTOR);
+        ES::set_reg(KERNEL_DATA_SELECTOR);
+        FS::set_reg(KERNEL_DATA_SELECTOR);
+        GS::set_reg(KERNEL_DATA_SELECTOR);
+        SS::set_reg(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
+}
+
+/// Set the stack the current CPU switches to when an interrupt arrives while
+/// running in ring 3 (RSP0 in the TSS).
+pub fn set_kernel_stack(stack_top: VirtAddress) {
+    assert!(stack_top.is_aligned_to(16), "{stack_top:?}");
+    let tss = TSS.get().load(Ordering::Relaxed);
+    assert!(!tss.is_null(), "no TSS loaded on this CPU");
+    // SAFETY: the TSS is never freed, and only this CPU changes it. The CPU
+    // only reads RSP0 when entering the kernel from ring 3, which can't
+    // happen while the kernel is running here.
+    unsafe {
+        (*tss).privilege_stack_table[0] = VirtAddr::new(stack_top.as_raw());
+    }
+}
@@ -0,0 +1,315 @@
+//! Model-specific registers
+//!
+//! Every MSR the kernel reads or writes goes through here. Each `Msr` knows
+//! the CPUID feature that provides it, and accessing one the CPU lacks logs an
+//! error and fails instead of raising #GP. As in `regs`, the AP trampoline in
+//! `smp` is the exception.
+
+use crate::mm::VirtAddress;
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use log::error;
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::PrivilegeLevel;
+
+/// A CPU feature some MSRs depend on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Feature {
+    /// `rdmsr` and `wrmsr` themselves.
+    Msr,
+    /// Long mode, which the kernel runs in, so always present.
+    LongMode,
+    /// No-execute pages, enabled by EFER.NXE.
+    NoExecute,
+    /// The `syscall` and `sysret` instructions.
+    Syscall,
+    /// A local APIC.
+    Apic,
+    /// x2APIC mode of the local APIC.
+    X2Apic,
+    /// The local APIC timer's TSC deadline mode.
+    TscDeadline,
+    /// The machine check architecture.
+    MachineCheck,
+    /// The `xsave` family of instructions and XCR0.
+    Xsave,
+}
+
+/// Where CPUID reports a feature.
+enum CpuidReg {
+    Ecx,
+    Edx,
+}
+
+impl Feature {
+    const ALL: [Feature; 9] = [
+        Feature::Msr,
+        Feature::LongMode,
+        Feature::NoExecute,
+        Feature::Syscall,
+        Feature::Apic,
+        Feature::X2Apic,
+        Feature::TscDeadline,
+        Feature::MachineCheck,
+        Feature::Xsave,
+    ];
+
+    /// Whether the CPU has the feature and it isn't disabled. CPUID is only
+    /// executed the first time, since it is slow under virtualization.
+    pub fn is_present(self) -> bool {
+        static PRESENT: spin::Once<u32> = spin::Once::new();
+        let present = PRESENT.call_once(|| {
+            Feature::ALL
+                .into_iter()
+                .filter(|feature| feature.query())
+                .fold(0, |present, feature| present | feature.mask())
+        });
+        present & !DISABLED.load(Ordering::Relaxed) & self.mask() != 0
+    }
+
+    /// Treat the feature as missing from now on, e.g. because of an erratum.
+    pub fn disable(self) {
+        DISABLED.fetch_or(self.mask(), Ordering::Relaxed);
+    }
+
+    fn mask(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// The CPUID leaf, register and bit reporting the feature.
+    fn cpuid_bit(self) -> (u32, CpuidReg, u32) {
+        match self {
+            Feature::Msr => (1, CpuidReg::Edx, 5),
+            Feature::LongMode => (0x8000_0001, CpuidReg::Edx, 29),
+            Feature::NoExecute => (0x8000_0001, CpuidReg::Edx, 20),
+            Feature::Syscall => (0x8000_0001, CpuidReg::Edx, 11),
+            Feature::Apic => (1, CpuidReg::Edx, 9),
+            Feature::X2Apic => (1, CpuidReg::Ecx, 21),
+            Feature::TscDeadline => (1, CpuidReg::Ecx, 24),
+            Feature::MachineCheck => (1, CpuidReg::Edx, 14),
+            Feature::Xsave => (1, CpuidReg::Ecx, 26),
+        }
+    }
+
+    fn query(self) -> bool {
+        let (leaf, reg, bit) = self.cpuid_bit();
+        // The highest leaf in the basic or extended range.
+        if __cpuid(leaf & 0x8000_0000).eax < leaf {
+            return false;
+        }
+        let result = __cpuid(leaf);
+        let value = match reg {
+            CpuidReg::Ecx => result.ecx,
+            CpuidReg::Edx => result.edx,
+        };
+        value & (1 << bit) != 0
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Feature::Msr => "MSRs",
+            Feature::LongMode => "long mode",
+            Feature::NoExecute => "NX",
+            Feature::Syscall => "syscall",
+            Feature::Apic => "a local APIC",
+            Feature::X2Apic => "x2APIC",
+            Feature::TscDeadline => "the TSC deadline timer",
+            Feature::MachineCheck => "machine check architecture",
+            Feature::Xsave => "XSAVE",
+        })
+    }
+}
+
+/// Features disabled with `Feature::disable`.
+static DISABLED: AtomicU32 = AtomicU32::new(0);
+
+/// A model-specific register and the feature that provides it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Msr {
+    address: u32,
+    name: &'static str,
+    feature: Feature,
+}
+
+pub const EFER: Msr = Msr::new(0xc000_0080, "EFER", Feature::LongMode);
+pub const STAR: Msr = Msr::new(0xc000_0081, "STAR", Feature::Syscall);
+pub const LSTAR: Msr = Msr::new(0xc000_0082, "LSTAR", Feature::Syscall);
+pub const FMASK: Msr = Msr::new(0xc000_0084, "FMASK", Feature::Syscall);
+pub const FS_BASE: Msr = Msr::new(0xc000_0100, "FS_BASE", Feature::LongMode);
+pub const GS_BASE: Msr = Msr::new(0xc000_0101, "GS_BASE", Feature::LongMode);
+pub const KERNEL_GS_BASE: Msr = Msr::new(0xc000_0102, "KERNEL_GS_BASE", Feature::LongMode);
+pub const APIC_BASE: Msr = Msr::new(0x1b, "APIC_BASE", Feature::Apic);
+pub const TSC_DEADLINE: Msr = Msr::new(0x6e0, "TSC_DEADLINE", Feature::TscDeadline);
+pub const MCG_STATUS: Msr = Msr::new(0x17a, "MCG_STATUS", Feature::MachineCheck);
+/// The loaded microcode revision. See `quirks::read_microcode`.
+pub const BIOS_SIGN_ID: Msr = Msr::new(0x8b, "BIOS_SIGN_ID", Feature::Msr);
+
+/// The x2APIC register at `offset` in the xAPIC MMIO layout.
+pub const fn x2apic(offset: u32) -> Msr {
+    Msr::new(0x800 + (offset >> 4), "x2APIC", Feature::X2Apic)
+}
+
+impl Msr {
+    const fn new(address: u32, name: &'static str, feature: Feature) -> Msr {
+        Msr {
+            address,
+            name,
+            feature,
+        }
+    }
+
+    /// Whether the CPU has this MSR.
+    pub fn is_supported(self) -> bool {
+        self.feature.is_present()
+    }
+
+    pub fn read(self) -> Result<u64, MsrError> {
+        self.check()?;
+        // SAFETY: the CPU has the MSR, and reading these has no side effects.
+        Ok(unsafe { x86_64::registers::model_specific::Msr::new(self.address).read() })
+    }
+
+    /// # Safety
+    ///
+    /// As for `wrmsr`: the value must be valid for the MSR, and whatever it
+    /// changes must not break the kernel's assumptions.
+    pub unsafe fn write(self, value: u64) -> Result<(), MsrError> {
+        self.check()?;
+        unsafe {
+            x86_64::registers::model_specific::Msr::new(self.address).write(value);
+        }
+        Ok(())
+    }
+
+    fn check(self) -> Result<(), MsrError> {
+        if self.is_supported() {
+            return Ok(());
+        }
+        let e = MsrError::Unsupported(self);
+        error!("{e}");
+        Err(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MsrError {
+    /// The CPU lacks the feature the MSR needs, so accessing it would #GP.
+    Unsupported(Msr),
+}
+
+impl fmt::Display for MsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsrError::Unsupported(msr) => write!(
+                f,
+                "{} MSR {:#x} needs {}, which this CPU lacks or has broken",
+                msr.name, msr.address, msr.feature
+            ),
+        }
+    }
+}
+
+pub fn efer() -> Result<EferFlags, MsrError> {
+    EFER.read().map(EferFlags::from_bits_truncate)
+}
+
+/// Change EFER with `f`.
+///
+/// # Safety
+///
+/// As for `Msr::write`.
+pub unsafe fn update_efer(f: impl FnOnce(&mut EferFlags)) -> Result<(), MsrError> {
+    let mut flags = efer()?;
+    f(&mut flags);
+    unsafe { EFER.write(flags.bits()) }
+}
+
+/// Set the segments `syscall` and `sysret` switch to. The kernel's data
+/// segment must follow its code segment, and the user code segment must
+/// follow the user data segment, as `sysret` computes one from the other.
+///
+/// # Safety
+///
+/// The selectors must be valid in the GDT.
+pub unsafe fn set_syscall_segments(
+    user_code: SegmentSelector,
+    user_data: SegmentSelector,
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+) -> Result<(), MsrError> {
+    assert_eq!(kernel_data.0, kernel_code.0 + 8);
+    assert_eq!(user_code.0, user_data.0 + 8);
+    assert_eq!(kernel_code.rpl(), PrivilegeLevel::Ring0);
+    assert_eq!(user_data.rpl(), PrivilegeLevel::Ring3);
+    // `sysret` loads the user data segment from base + 8.
+    let star = u64::from(user_data.0 - 8) << 48 | u64::from(kernel_code.0) << 32;
+    unsafe { STAR.write(star) }
+}
+
+/// Set the entry point `syscall` jumps to.
+///
+/// # Safety
+///
+/// `entry` must be a valid `syscall` entry point.
+pub unsafe fn set_syscall_entry(entry: VirtAddress) -> Result<(), MsrError> {
+    unsafe { LSTAR.write(entry.as_raw()) }
+}
+
+/// Set the RFLAGS bits `syscall` clears.
+///
+/// # Safety
+///
+/// The entry point must be able to run with the flags left set.
+pub unsafe fn set_syscall_flag_mask(mask: RFlags) -> Result<(), MsrError> {
+    unsafe { FMASK.write(mask.bits()) }
+}
+
+#[allow(unused)]
+pub fn fs_base() -> Result<VirtAddress, MsrError> {
+    FS_BASE.read().map(VirtAddress::from_raw)
+}
+
+/// # Safety
+///
+/// Nothing may be using FS-relative addresses based on the old value.
+// No thread-local storage uses FS yet.
+#[allow(unused)]
+pub unsafe fn set_fs_base(base: VirtAddress) -> Result<(), MsrError> {
+    unsafe { FS_BASE.write(base.as_raw()) }
+}
+
+/// # Safety
+///
+/// Nothing may be using GS-relative addresses based on the old value, such
+/// as `smp::current_cpu`.
+pub unsafe fn set_gs_base(base: VirtAddress) -> Result<(), MsrError> {
+    unsafe { GS_BASE.write(base.as_raw()) }
+}
+
+/// Set the GS base `swapgs` exchanges with the active one.
+///
+/// # Safety
+///
+/// As for `set_gs_base`, after the next `swapgs`.
+pub unsafe fn set_kernel_gs_base(base: VirtAddress) -> Result<(), MsrError> {
+    unsafe { KERNEL_GS_BASE.write(base.as_raw()) }
+}
+
+/// Arm the local APIC timer in TSC deadline mode to fire when the TSC reaches
+/// `deadline`. Zero disarms it.
+///
+/// # Safety
+///
+/// The timer's LVT entry must be set up for TSC deadline mode.
+// The PIT drives the timer interrupt for now.
+#[allow(unused)]
+pub unsafe fn set_tsc_deadline(deadline: u64) -> Result<(), MsrError> {
+    unsafe { TSC_DEADLINE.write(deadline) }
+}
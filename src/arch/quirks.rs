@@ -0,0 +1,214 @@
+//! CPU quirks and errata
+//!
+//! Some CPUs, or the firmware and hypervisors they run under, lack features
+//! the kernel otherwise relies on or have them broken. `init` identifies the
+//! CPU, warns about each known problem and what the kernel does instead, and
+//! records the fallbacks:
+//!
+//! * Without NX, `entry.nasm` and the AP trampoline leave EFER.NXE clear and
+//!   page table entries are never marked no-execute.
+//! * A TSC that isn't invariant on bare metal may drift with the CPU's
+//!   frequency, so the PIT is the clock instead.
+//! * Intel CPUs whose microcode predates fixes for TSC deadline timer errata
+//!   have `Feature::TscDeadline` disabled. The revisions are from Linux.
+
+use super::msr::{self, Feature};
+use crate::mm::paging;
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::{info, warn};
+
+/// The CPU's vendor, as CPUID reports it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Vendor {
+    Intel,
+    Amd,
+    Other([u8; 12]),
+}
+
+/// The boot CPU's identity. The others are assumed to match.
+#[derive(Clone, Copy, Debug)]
+struct Cpu {
+    vendor: Vendor,
+    family: u32,
+    model: u32,
+    stepping: u32,
+    /// The loaded microcode revision, if it can be read.
+    microcode: Option<u32>,
+    /// Whether a hypervisor says the kernel runs under it.
+    hypervisor: bool,
+}
+
+impl Cpu {
+    fn identify() -> Cpu {
+        let leaf0 = __cpuid(0);
+        let mut vendor = [0; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+        let vendor = match &vendor {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other(vendor),
+        };
+
+        let leaf1 = __cpuid(1);
+        let base_family = (leaf1.eax >> 8) & 0xf;
+        let family = match base_family {
+            0xf => base_family + ((leaf1.eax >> 20) & 0xff),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xf => ((leaf1.eax >> 16) & 0xf) << 4 | (leaf1.eax >> 4) & 0xf,
+            _ => (leaf1.eax >> 4) & 0xf,
+        };
+
+        Cpu {
+            vendor,
+            family,
+            model,
+            stepping: leaf1.eax & 0xf,
+            microcode: read_microcode(vendor),
+            hypervisor: leaf1.ecx & (1 << 31) != 0,
+        }
+    }
+
+    /// The first microcode revision without TSC deadline timer errata, if
+    /// this CPU has any.
+    fn tsc_deadline_fixed_in(&self) -> Option<u32> {
+        if self.vendor != Vendor::Intel || self.family != 6 {
+            return None;
+        }
+        let revision = match (self.model, self.stepping) {
+            // Haswell
+            (0x3c, _) => 0x22,
+            (0x45, _) => 0x20,
+            (0x46, _) => 0x17,
+            (0x3f, 2) => 0x3a,
+            (0x3f, 4) => 0x0f,
+            // Broadwell
+            (0x3d, _) => 0x25,
+            (0x47, _) => 0x17,
+            (0x4f, _) => 0x0b00_0020,
+            (0x56, 2) => 0x0000_0011,
+            (0x56, 3) => 0x0700_000e,
+            (0x56, 4) => 0x0f00_000c,
+            (0x56, 5) => 0x0e00_0003,
+            // Skylake
+            (0x4e | 0x5e, _) => 0xb2,
+            (0x55, 3) => 0x0100_0136,
+            (0x55, 4) => 0x0200_0014,
+            // Kaby Lake
+            (0x8e | 0x9e, _) => 0x52,
+            _ => return None,
+        };
+        Some(revision)
+    }
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.vendor {
+            Vendor::Intel => f.write_str("Intel")?,
+            Vendor::Amd => f.write_str("AMD")?,
+            Vendor::Other(vendor) => match core::str::from_utf8(vendor) {
+                Ok(vendor) => write!(f, "{vendor:?}")?,
+                Err(_) => write!(f, "{vendor:x?}")?,
+            },
+        }
+        write!(
+            f,
+            " family {:#x} model {:#x} stepping {:#x}",
+            self.family, self.model, self.stepping
+        )?;
+        if let Some(microcode) = self.microcode {
+            write!(f, ", microcode {microcode:#x}")?;
+        }
+        if self.hypervisor {
+            f.write_str(", under a hypervisor")?;
+        }
+        Ok(())
+    }
+}
+
+/// The loaded microcode revision. Hypervisors often report zero, which is
+/// treated as unknown.
+fn read_microcode(vendor: Vendor) -> Option<u32> {
+    let revision = match vendor {
+        Vendor::Intel => {
+            // The revision is only latched by CPUID leaf 1 after clearing
+            // the MSR.
+            // SAFETY: writing zero has no effect besides that.
+            unsafe { msr::BIOS_SIGN_ID.write(0).ok()? };
+            __cpuid(1);
+            (msr::BIOS_SIGN_ID.read().ok()? >> 32) as u32
+        }
+        Vendor::Amd => msr::BIOS_SIGN_ID.read().ok()? as u32,
+        Vendor::Other(_) => return None,
+    };
+    (revision != 0).then_some(revision)
+}
+
+/// Whether the TSC can be the clock. See the module docs.
+static TSC_CLOCK_USABLE: AtomicBool = AtomicBool::new(true);
+
+/// Identify the boot CPU, warn about its known problems and choose fallbacks.
+/// Must be called before `time::init` and `mm::init`, which use them.
+pub fn init() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, Ordering::SeqCst));
+
+    let cpu = Cpu::identify();
+    info!("CPU: {cpu}");
+
+    if !Feature::NoExecute.is_present() {
+        warn!(
+            "CPU lacks NX, so all memory is executable and stray jumps into data go \
+             unnoticed; enable the \"execute disable\" option in the firmware setup if \
+             there is one"
+        );
+        paging::disable_no_execute();
+    }
+
+    if !is_tsc_invariant() {
+        if cpu.hypervisor {
+            info!("TSC isn't reported invariant, as usual under a hypervisor; trusting it");
+        } else {
+            warn!(
+                "TSC isn't invariant, so it may drift with CPU frequency changes; using \
+                 the PIT as the clock, with {} ms resolution",
+                1000 / crate::time::TIMER_HZ
+            );
+            TSC_CLOCK_USABLE.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Microcode revisions mean nothing under a hypervisor, which emulates
+    // the timer anyway.
+    if let (Some(fixed_in), Some(microcode), false) =
+        (cpu.tsc_deadline_fixed_in(), cpu.microcode, cpu.hypervisor)
+    {
+        if microcode < fixed_in && Feature::TscDeadline.is_present() {
+            warn!(
+                "TSC deadline timer has errata with microcode {microcode:#x}; not using \
+                 it. Update the firmware or load microcode {fixed_in:#x} or later"
+            );
+            Feature::TscDeadline.disable();
+        }
+    }
+}
+
+/// Whether the TSC may be used as the clock, if it can be calibrated.
+pub fn tsc_clock_usable() -> bool {
+    TSC_CLOCK_USABLE.load(Ordering::Relaxed)
+}
+
+/// Whether the TSC runs at a constant rate regardless of power states.
+fn is_tsc_invariant() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
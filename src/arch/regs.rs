@@ -0,0 +1,133 @@
+//! Control registers and EFER
+//!
+//! Everything that reads or changes CR0, CR2, CR3, CR4, XCR0 or EFER goes
+//! through here, so the code that decides what the CPU enforces (write
+//! protection, no-execute, which page table is active) is in one place. EFER itself is
+//! accessed through `msr`. The trampoline in `smp` is the exception: it sets
+//! up an AP's registers before it can call into the kernel.
+
+use super::msr::{self, MsrError};
+use crate::mm::{PhysAddress, VirtAddress};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr3Flags, Cr4};
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::xcontrol::XCr0;
+use x86_64::structures::paging::PhysFrame;
+
+pub use x86_64::registers::control::Cr4Flags;
+pub use x86_64::registers::xcontrol::XCr0Flags;
+
+/// The address whose access caused the last page fault, from CR2.
+pub fn page_fault_address() -> VirtAddress {
+    VirtAddress::from_raw(Cr2::read_raw())
+}
+
+/// The physical address of the active root page table, from CR3.
+pub fn page_table_root() -> PhysAddress {
+    PhysAddress::from_raw(Cr3::read().0.start_address().as_u64())
+}
+
+/// Make the root page table at `root` active. This flushes all non-global
+/// TLB entries.
+///
+/// # Safety
+///
+/// `root` must be a root page table that maps the kernel, including the code
+/// and stack in use.
+pub unsafe fn set_page_table_root(root: PhysAddress) {
+    let frame = PhysFrame::from_start_address(x86_64::PhysAddr::new(root.as_raw())).unwrap();
+    unsafe {
+        Cr3::write(frame, Cr3Flags::empty());
+    }
+}
+
+/// Whether CR0.WP is set, so the kernel can't write to read-only pages.
+pub fn write_protect() -> bool {
+    Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+}
+
+/// Set or clear CR0.WP.
+///
+/// # Safety
+///
+/// Clearing it lets the kernel write to read-only memory, such as page tables
+/// and code. Only `mm::protect` should do that, in short windows.
+pub unsafe fn set_write_protect(enabled: bool) {
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::WRITE_PROTECT, enabled));
+    }
+}
+
+/// Run x87 and SSE instructions natively, reporting x87 errors as #MF: set
+/// CR0.MP and CR0.NE, and clear CR0.EM and CR0.TS.
+///
+/// # Safety
+///
+/// Every task's x87 and SSE state must be saved and restored when switching
+/// tasks, since nothing traps the first use any more.
+pub unsafe fn enable_native_fpu() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+    }
+}
+
+/// The CPU features enabled in CR4.
+#[allow(unused)]
+pub fn cr4_features() -> Cr4Flags {
+    Cr4::read()
+}
+
+/// Enable `features` in CR4, leaving the others as they are.
+///
+/// # Safety
+///
+/// The CPU must support `features`, and the kernel must be ready for what
+/// they enforce (e.g. SMAP faults on user memory accesses).
+pub unsafe fn enable_cr4_features(features: Cr4Flags) {
+    unsafe {
+        Cr4::update(|flags| flags.insert(features));
+    }
+}
+
+/// Disable `features` in CR4, leaving the others as they are.
+///
+/// # Safety
+///
+/// Nothing may depend on `features`, e.g. PAE while paging is enabled.
+#[allow(unused)]
+pub unsafe fn disable_cr4_features(features: Cr4Flags) {
+    unsafe {
+        Cr4::update(|flags| flags.remove(features));
+    }
+}
+
+/// Set XCR0, the state components `xsave` saves and AVX and other
+/// instructions may use. CR4.OSXSAVE must be set.
+///
+/// # Safety
+///
+/// The CPU must support `features`, and every task's state for them must be
+/// saved and restored when switching tasks.
+pub unsafe fn set_xsave_features(features: XCr0Flags) {
+    unsafe {
+        XCr0::write(features);
+    }
+}
+
+/// Whether EFER.NXE is set, so page table entries may forbid execution.
+/// `entry.nasm` sets it before enabling paging.
+pub fn no_execute_enabled() -> bool {
+    msr::efer().is_ok_and(|efer| efer.contains(EferFlags::NO_EXECUTE_ENABLE))
+}
+
+/// Set EFER.SCE, enabling `syscall` and `sysret`.
+///
+/// # Safety
+///
+/// The `syscall` MSRs must already point at a valid entry point.
+pub unsafe fn enable_syscall() -> Result<(), MsrError> {
+    unsafe { msr::update_efer(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) }
+}
@@ -0,0 +1,51 @@
+//! Boot metrics for regression checks
+//!
+//! Logs how long each boot phase took and, once init is about to start, how
+//! much memory the kernel is using and how many interrupts it took. The host
+//! runner compares them with a baseline; see `shared::boot_metrics` for the
+//! format.
+//!
+//! Phase times are measured on the clock `time::init` starts. If the clock
+//! is the PIT, it only advances on timer interrupts, so phases before
+//! interrupts are enabled show as taking no time.
+
+use crate::mm::{self, Zone};
+use crate::sched;
+use crate::time;
+use crate::vectors;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+use shared::boot_metrics::Metric;
+
+/// Microseconds since `time::init` when the last phase ended.
+static LAST_PHASE_END: AtomicU64 = AtomicU64::new(0);
+
+fn report(name: &str, value: u64) {
+    info!("{}", Metric { name, value });
+}
+
+/// Report the time since the last phase ended, or since `time::init` for
+/// the first, as `name` in microseconds. Phases are named `phase.<what>`.
+pub fn phase(name: &str) {
+    let now = time::uptime().as_micros() as u64;
+    let start = LAST_PHASE_END.swap(now, Ordering::Relaxed);
+    report(name, now.saturating_sub(start));
+}
+
+/// Report the last phase and the counters as of entering user space.
+pub fn report_userspace_entry() {
+    phase("phase.userspace");
+    report("boot.us", time::uptime().as_micros() as u64);
+
+    let used_frames = Zone::ALL
+        .into_iter()
+        .filter_map(mm::try_zone_info)
+        .map(|info| info.managed - info.free)
+        .sum();
+    report("frames.used", used_frames);
+    report("heap.bytes", mm::kernel_heap::mapped_bytes());
+    report("interrupts", vectors::total_count());
+    report("ticks", sched::current_tick());
+}
@@ -0,0 +1,70 @@
+//! A/B boot slots
+//!
+//! Images built with `mkimage --known-good` have two slots: the trial kernel
+//! at `/boot` and a known-good one at `/boot/good`. Before booting the trial
+//! slot GRUB sets one of `ATTEMPT_BITS` in CMOS, and once all are set it
+//! boots the known-good slot instead (see grub-slots.cfg). The trial kernel
+//! clears them with `confirm` once init is loaded and about to enter user
+//! mode, so only boots that fail before then count against it.
+//!
+//! GRUB tells the kernel which slot it booted with `boot_slot=` on the
+//! command line. Kernels booted without slots leave CMOS alone.
+
+use log::{info, warn};
+use shared::cmdline::BootSlot;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// The CMOS byte holding the attempt bits. QEMU and SeaBIOS leave it unused.
+/// Must match grub-slots.cfg.
+const STATE_REGISTER: u8 = 0x7e;
+
+/// One bit per boot attempt of the trial slot.
+const ATTEMPT_BITS: u8 = 0b111;
+
+static SLOT: spin::Once<Option<BootSlot>> = spin::Once::new();
+
+/// Record which slot was booted, from the command line.
+pub fn set(slot: Option<BootSlot>) {
+    SLOT.call_once(|| slot);
+    match slot {
+        Some(BootSlot::Trial) => info!("booted the trial slot"),
+        Some(BootSlot::Good) => warn!("booted the known-good slot; the trial slot failed to boot"),
+        None => (),
+    }
+}
+
+/// Note that the kernel booted far enough to run init. If it was booted from
+/// the trial slot, GRUB keeps booting it.
+pub fn confirm() {
+    if SLOT.get().copied().flatten() != Some(BootSlot::Trial) {
+        return;
+    }
+    without_interrupts(|| {
+        let state = read_cmos(STATE_REGISTER);
+        write_cmos(STATE_REGISTER, state & !ATTEMPT_BITS);
+    });
+    info!("confirmed the trial slot");
+}
+
+/// Interrupts must be disabled, so nothing else selects a register between
+/// this writing the index and reading the data.
+fn read_cmos(register: u8) -> u8 {
+    // SAFETY: only CMOS registers nothing else relies on are accessed.
+    unsafe {
+        PortWriteOnly::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// Interrupts must be disabled, as for `read_cmos`.
+fn write_cmos(register: u8, value: u8) {
+    // SAFETY: as for `read_cmos`.
+    unsafe {
+        PortWriteOnly::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
@@ -0,0 +1,130 @@
+//! Diagnostic snapshots of "should never happen" conditions
+//!
+//! Drivers that find themselves in a state they can recover from but
+//! shouldn't be in call `snapshot` instead of either panicking or staying
+//! quiet. It logs the reason, a backtrace, the current task and memory usage,
+//! so the records land in the log history and on the console for later
+//! analysis. Snapshots never block and are safe from interrupt handlers. They
+//! are rate limited so a condition that repeats can't flood the log.
+
+use crate::mm::{self, kernel_stack, VirtAddress, Zone};
+use crate::{sched, smp, time};
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+/// The most snapshots taken in each window of `WINDOW_TICKS`.
+const MAX_PER_WINDOW: u32 = 4;
+
+const WINDOW_TICKS: u64 = time::TIMER_HZ;
+
+/// The most return addresses logged.
+const MAX_FRAMES: usize = 16;
+
+struct Limiter {
+    window_start: u64,
+    taken: u32,
+    /// Snapshots dropped since the last one taken.
+    suppressed: u64,
+}
+
+static LIMITER: spin::Mutex<Limiter> = spin::Mutex::new(Limiter {
+    window_start: 0,
+    taken: 0,
+    suppressed: 0,
+});
+
+/// Dropped while another snapshot was being taken.
+static SUPPRESSED_WHILE_LOCKED: AtomicU64 = AtomicU64::new(0);
+
+static TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Record that something which should never happen did, for `reason`, and
+/// carry on.
+#[inline(never)]
+pub fn snapshot(reason: &str) {
+    // Holding the limiter while logging keeps a snapshot's records together.
+    // A snapshot from an interrupt handler meanwhile is dropped.
+    let Some(mut limiter) = LIMITER.try_lock() else {
+        SUPPRESSED_WHILE_LOCKED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let now = sched::current_tick();
+    if now - limiter.window_start >= WINDOW_TICKS {
+        limiter.window_start = now;
+        limiter.taken = 0;
+    }
+    if limiter.taken == MAX_PER_WINDOW {
+        limiter.suppressed += 1;
+        return;
+    }
+    limiter.taken += 1;
+    let suppressed = core::mem::take(&mut limiter.suppressed)
+        + SUPPRESSED_WHILE_LOCKED.swap(0, Ordering::Relaxed);
+
+    let id = TAKEN.fetch_add(1, Ordering::Relaxed);
+    warn!("snapshot {id}: {reason}");
+    if suppressed > 0 {
+        warn!("snapshot {id}: {suppressed} earlier snapshots suppressed");
+    }
+    warn!(
+        "snapshot {id}: task {} on CPU {} at {:?} uptime",
+        sched::current_task_label(),
+        smp::current_cpu(),
+        time::uptime()
+    );
+    for zone in Zone::ALL {
+        match mm::try_zone_info(zone) {
+            Some(info) => warn!(
+                "snapshot {id}: {zone:?} zone {} of {} frames free",
+                info.free, info.managed
+            ),
+            None => {
+                warn!("snapshot {id}: frame allocator busy");
+                break;
+            }
+        }
+    }
+    for (depth, address) in backtrace().enumerate() {
+        warn!("snapshot {id}: #{depth} {address:#x}");
+    }
+}
+
+/// The return addresses of the callers of the function calling this, found by
+/// following frame pointers. Only frames on the current task's kernel stack
+/// are followed; elsewhere, just the first return address is found.
+#[inline(always)]
+fn backtrace() -> impl Iterator<Item = u64> {
+    let rbp: u64;
+    // SAFETY: only reads a register.
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    let stack = kernel_stack::stack_slot_containing(VirtAddress::from_raw(rbp));
+
+    let mut frame = Some(rbp);
+    core::iter::from_fn(move || {
+        let rbp = frame.take()?;
+        if rbp % 8 != 0 {
+            return None;
+        }
+        // SAFETY: `rbp` is the address of the current function's frame, or a
+        // saved one checked below to be further up the same, mapped, stack.
+        // Each frame starts with the caller's frame pointer followed by the
+        // return address.
+        let (next, return_address) = unsafe {
+            let rbp = rbp as *const u64;
+            (rbp.read(), rbp.add(1).read())
+        };
+        let in_stack = |address: u64| {
+            stack.is_some_and(|stack| address > rbp && address + 16 <= stack.end_address().as_raw())
+        };
+        if in_stack(next) {
+            frame = Some(next);
+        }
+        (return_address != 0).then_some(return_address)
+    })
+    .take(MAX_FRAMES)
+}
@@ -0,0 +1,13 @@
+//! Device drivers
+//!
+//! Each driver that logs does so to its own target, registered in `log_ctl`, so its level
+//! can be set separately, e.g. with `log.serial=debug` on the command line.
+
+pub mod block;
+#[cfg(feature = "framebuffer")]
+pub mod framebuffer;
+pub mod pci;
+pub mod serial;
+#[cfg(not(feature = "framebuffer"))]
+pub mod vga;
+pub mod virtio;
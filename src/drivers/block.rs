@@ -0,0 +1,122 @@
+//! Block devices
+//!
+//! A `BlockDevice` reads and writes whole blocks, addressed by index. Drivers
+//! register the devices they find with `register`, and users look them up by
+//! the order they were found in with `get`.
+
+mod virtio_blk;
+
+use alloc::vec::Vec;
+
+use log::info;
+use shared::log::Target;
+use spin::Mutex;
+
+pub static LOG: Target = Target::new("block");
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlockError {
+    /// The buffer isn't a whole number of blocks.
+    Unaligned {
+        len: usize,
+        block_size: usize,
+    },
+    /// The blocks run past the end of the device.
+    OutOfRange {
+        first: u64,
+        count: u64,
+        num_blocks: u64,
+    },
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+}
+
+impl core::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BlockError::Unaligned { len, block_size } => {
+                write!(
+                    f,
+                    "{len} bytes is not a multiple of the {block_size} byte blocks"
+                )
+            }
+            BlockError::OutOfRange {
+                first,
+                count,
+                num_blocks,
+            } => write!(
+                f,
+                "blocks {first} to {} are past the end of the device, which has {num_blocks}",
+                first + count - 1
+            ),
+            BlockError::ReadOnly => write!(f, "the device is read-only"),
+            BlockError::Io => write!(f, "I/O error"),
+        }
+    }
+}
+
+/// A device storing fixed size blocks. Requests block the calling task until
+/// they complete, so must be made with interrupts enabled.
+pub trait BlockDevice: Send + Sync {
+    /// Identifies the device in logs.
+    fn name(&self) -> &str;
+
+    /// Bytes per block.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Read blocks starting at `first` to fill `buf`, which must be a whole
+    /// number of blocks.
+    fn read_blocks(&self, first: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf`, a whole number of blocks, to the blocks starting at
+    /// `first`.
+    fn write_blocks(&self, first: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Check that `len` bytes starting at block `first` are whole blocks on
+/// `device`. Returns how many blocks they are.
+pub fn check_request(device: &dyn BlockDevice, first: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if !len.is_multiple_of(block_size) {
+        return Err(BlockError::Unaligned { len, block_size });
+    }
+    let count = (len / block_size) as u64;
+    let num_blocks = device.num_blocks();
+    if first.checked_add(count).is_none_or(|end| end > num_blocks) {
+        return Err(BlockError::OutOfRange {
+            first,
+            count,
+            num_blocks,
+        });
+    }
+    Ok(count)
+}
+
+static DEVICES: Mutex<Vec<&'static dyn BlockDevice>> = Mutex::new(Vec::new());
+
+/// Find and set up the block devices. Must be called from a task with
+/// interrupts enabled, after `irq::init`.
+pub fn init() {
+    virtio_blk::probe();
+    info!(target: LOG.name(), "{} block devices", DEVICES.lock().len());
+}
+
+/// Make `device` available to `get`.
+pub fn register(device: &'static dyn BlockDevice) {
+    info!(
+        target: LOG.name(),
+        "{}: {} blocks of {} bytes",
+        device.name(),
+        device.num_blocks(),
+        device.block_size()
+    );
+    DEVICES.lock().push(device);
+}
+
+/// The `index`th device registered.
+pub fn get(index: usize) -> Option<&'static dyn BlockDevice> {
+    DEVICES.lock().get(index).copied()
+}
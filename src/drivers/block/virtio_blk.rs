@@ -0,0 +1,326 @@
+//! virtio-blk driver
+//!
+//! Drives the first legacy virtio block device found (see `virtio`), e.g.
+//! QEMU's `-drive if=virtio`. One request is in flight at a time: the
+//! requesting task adds it to the device's only queue and blocks until the
+//! device's interrupt says it was used. Data goes through a bounce buffer,
+//! since callers' buffers needn't be physically contiguous, so larger
+//! transfers are split into several requests.
+
+use super::{check_request, BlockDevice, BlockError, LOG};
+use crate::drivers::pci;
+use crate::drivers::virtio::{self, Buffer, Virtqueue};
+use crate::irq;
+use crate::itest::{kernel_test, TestResult};
+use crate::mm::{self, dma::DmaMapping};
+use crate::sync::{Mutex, Semaphore};
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec;
+
+use log::{info, warn};
+use shared::test_disk;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// The transitional device's ID, which has the legacy interface.
+const DEVICE_ID: u16 = 0x1001;
+
+const NAME: &str = "virtio-blk";
+
+/// Set if the device is read-only.
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+
+// Device configuration offsets.
+const CONFIG_CAPACITY: u16 = 0;
+
+/// The device always counts in sectors of this size.
+const SECTOR_SIZE: usize = 512;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+
+const STATUS_OK: u8 = 0;
+
+/// The bounce buffer is 2^order frames.
+const BOUNCE_ORDER: usize = 4;
+
+/// Starts each request.
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Where the device writes the request's status, in the same frame as the
+/// header.
+const STATUS_OFFSET: usize = core::mem::size_of::<RequestHeader>();
+
+/// The device interrupts for, if set up.
+static DEVICE: spin::Once<&'static VirtioBlk> = spin::Once::new();
+
+struct VirtioBlk {
+    device: virtio::Device,
+    num_sectors: u64,
+    read_only: bool,
+    requests: Mutex<Requests>,
+    /// Released by the interrupt handler when the device used the queue.
+    used: Semaphore,
+}
+
+/// What a request needs, held while it is in flight.
+struct Requests {
+    queue: Virtqueue,
+    /// The header and status. The device has the frames as long as it
+    /// lives, so they are never completed.
+    header: DmaMapping,
+    bounce: DmaMapping,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum InitError {
+    Virtio(virtio::Error),
+    /// The firmware didn't route the device's interrupt.
+    NoInterrupt,
+    OutOfMemory,
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::Virtio(e) => write!(f, "{e}"),
+            InitError::NoInterrupt => write!(f, "its interrupt isn't routed to an IRQ"),
+            InitError::OutOfMemory => write!(f, "can't allocate DMA buffers"),
+        }
+    }
+}
+
+impl From<virtio::Error> for InitError {
+    fn from(e: virtio::Error) -> Self {
+        InitError::Virtio(e)
+    }
+}
+
+/// Set up the first virtio block device, if there is one, and register it.
+pub fn probe() {
+    let Some(function) =
+        pci::functions().find(|f| f.vendor_id() == virtio::VENDOR_ID && f.device_id() == DEVICE_ID)
+    else {
+        return;
+    };
+    match init(function) {
+        Ok(device) => super::register(device),
+        Err(e) => warn!(target: LOG.name(), "{NAME} at {function}: {e}"),
+    }
+}
+
+fn init(function: pci::Function) -> Result<&'static VirtioBlk, InitError> {
+    let line = function
+        .interrupt_line()
+        .filter(|&line| line < irq::NUM_IRQS)
+        .ok_or(InitError::NoInterrupt)?;
+    let device = virtio::Device::new(function, NAME)?;
+    let result = (|| {
+        let features = device.negotiate(FEATURE_READ_ONLY);
+        let queue = device.setup_queue(0)?;
+        let header = mm::allocate_owned_frames(0).ok_or(InitError::OutOfMemory)?;
+        let bounce = mm::allocate_owned_frames(BOUNCE_ORDER).ok_or(InitError::OutOfMemory)?;
+        Ok((features, queue, header, bounce))
+    })();
+    let (features, queue, header, bounce) = match result {
+        Ok(parts) => parts,
+        Err(e) => {
+            device.fail();
+            return Err(e);
+        }
+    };
+
+    let blk: &'static VirtioBlk = Box::leak(Box::new(VirtioBlk {
+        num_sectors: device.config_u64(CONFIG_CAPACITY),
+        read_only: features & FEATURE_READ_ONLY != 0,
+        device,
+        requests: Mutex::new(Requests {
+            queue,
+            header: DmaMapping::new(NAME, header),
+            bounce: DmaMapping::new(NAME, bounce),
+        }),
+        used: Semaphore::new(0),
+    }));
+    DEVICE.call_once(|| blk);
+    irq::install_irq_handler(line, Some(handle_irq));
+    blk.device.driver_ok();
+    info!(target: LOG.name(), "{NAME} at {function}, IRQ {line}");
+    Ok(blk)
+}
+
+fn handle_irq(_: InterruptStackFrame) {
+    let Some(blk) = DEVICE.get() else {
+        return;
+    };
+    if blk.device.acknowledge_interrupt() {
+        blk.used.release();
+    }
+}
+
+impl VirtioBlk {
+    /// Transfer `len` bytes between the bounce buffer and the sectors
+    /// starting at `sector`. `len` must fit in the bounce buffer.
+    fn transfer(&self, requests: &mut Requests, kind: u32, sector: u64, len: usize) -> bool {
+        let header = requests.header.as_mut_ptr();
+        // SAFETY: the device doesn't use the header frame between requests.
+        unsafe {
+            header
+                .cast::<RequestHeader>()
+                .write_volatile(RequestHeader {
+                    kind,
+                    reserved: 0,
+                    sector,
+                });
+            header.add(STATUS_OFFSET).write_volatile(0xff);
+        }
+
+        let header_address = requests.header.device_address();
+        let buffers = [
+            Buffer {
+                address: header_address,
+                len: STATUS_OFFSET as u32,
+                device_writes: false,
+            },
+            Buffer {
+                address: requests.bounce.device_address(),
+                len: len as u32,
+                device_writes: kind == REQUEST_IN,
+            },
+            Buffer {
+                address: header_address + mm::Length::from_raw(STATUS_OFFSET as u64),
+                len: 1,
+                device_writes: true,
+            },
+        ];
+        // SAFETY: the buffers are owned by `requests`, which is locked until
+        // the chain is popped below.
+        let added = unsafe { requests.queue.add(&buffers) };
+        added.expect("virtio-blk queue full with one request in flight");
+        self.device.notify(0);
+
+        // A permit may be left over from an interrupt for a request that
+        // was already popped.
+        loop {
+            self.used.acquire();
+            if requests.queue.pop_used().is_some() {
+                break;
+            }
+        }
+        // SAFETY: the device wrote the status before using the chain.
+        unsafe { header.add(STATUS_OFFSET).read_volatile() == STATUS_OK }
+    }
+
+    /// Split `len` bytes from `first` into requests that fit in the bounce
+    /// buffer, calling `f` with the requests, each one's sector, and its
+    /// offset and length in the caller's buffer.
+    fn for_each_chunk(
+        &self,
+        first: u64,
+        len: usize,
+        mut f: impl FnMut(&mut Requests, u64, usize, usize) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let mut requests = self.requests.lock();
+        let chunk_len = requests.bounce.frames().count() as usize * mm::PAGE_SIZE.as_raw() as usize;
+        for offset in (0..len).step_by(chunk_len) {
+            let sector = first + (offset / SECTOR_SIZE) as u64;
+            f(&mut requests, sector, offset, chunk_len.min(len - offset))?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn read_blocks(&self, first: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, first, buf.len())?;
+        self.for_each_chunk(first, buf.len(), |requests, sector, offset, len| {
+            if !self.transfer(requests, REQUEST_IN, sector, len) {
+                return Err(BlockError::Io);
+            }
+            // SAFETY: the device is done with the bounce buffer.
+            let bounce = unsafe { core::slice::from_raw_parts(requests.bounce.as_mut_ptr(), len) };
+            buf[offset..offset + len].copy_from_slice(bounce);
+            Ok(())
+        })
+    }
+
+    fn write_blocks(&self, first: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, first, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.for_each_chunk(first, buf.len(), |requests, sector, offset, len| {
+            // SAFETY: the device doesn't use the bounce buffer between
+            // requests.
+            let bounce =
+                unsafe { core::slice::from_raw_parts_mut(requests.bounce.as_mut_ptr(), len) };
+            bounce.copy_from_slice(&buf[offset..offset + len]);
+            if !self.transfer(requests, REQUEST_OUT, sector, len) {
+                return Err(BlockError::Io);
+            }
+            Ok(())
+        })
+    }
+}
+
+kernel_test!("block: virtio-blk reads the test disk", reads_test_disk);
+fn reads_test_disk() -> TestResult {
+    if DEVICE.get().is_none() {
+        // Only `mkimage test` attaches the disk.
+        info!("no {NAME} device; skipping");
+        return Ok(());
+    }
+    let blk = super::get(0).ok_or("the device wasn't registered")?;
+    let expected = |sector: u64| -> alloc::vec::Vec<u8> {
+        (0..SECTOR_SIZE)
+            .map(|offset| test_disk::byte(sector, offset))
+            .collect()
+    };
+
+    // A known sector, then a range spanning several bounce buffers.
+    let mut buf = vec![0; SECTOR_SIZE];
+    blk.read_blocks(1, &mut buf).map_err(|e| format!("{e}"))?;
+    if buf != expected(1) {
+        return Err("sector 1 has the wrong contents".into());
+    }
+    // Sector 0 is the partition table.
+    let mut buf = vec![0; SECTOR_SIZE * (test_disk::NUM_SECTORS - 1) as usize];
+    blk.read_blocks(1, &mut buf).map_err(|e| format!("{e}"))?;
+    for (sector, data) in (1..).zip(buf.chunks(SECTOR_SIZE)) {
+        if data != expected(sector) {
+            return Err(format!("sector {sector} has the wrong contents"));
+        }
+    }
+
+    // The image is rewritten for every run, so writing it is fine.
+    let written: alloc::vec::Vec<u8> = expected(2).iter().map(|b| !b).collect();
+    blk.write_blocks(2, &written).map_err(|e| format!("{e}"))?;
+    let mut buf = vec![0; SECTOR_SIZE];
+    blk.read_blocks(2, &mut buf).map_err(|e| format!("{e}"))?;
+    if buf != written {
+        return Err("sector 2 didn't read back what was written".into());
+    }
+
+    let past_end = blk.read_blocks(blk.num_blocks(), &mut buf);
+    if !matches!(past_end, Err(BlockError::OutOfRange { .. })) {
+        return Err(format!("reading past the end gave {past_end:?}"));
+    }
+    Ok(())
+}
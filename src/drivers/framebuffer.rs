@@ -0,0 +1,110 @@
+//! Text console on the framebuffer set up by the bootloader
+//!
+//! With the `framebuffer` feature, the multiboot2 header asks GRUB for a
+//! graphics mode instead of VGA text mode. `init` maps the framebuffer GRUB
+//! reports, and `FramebufferWriter` draws on it from then on.
+
+use crate::mm::{self, PhysExtent};
+
+use core::fmt::Write;
+
+use log::debug;
+use multiboot2 as mb2;
+use shared::fb::{ColorField, FbConsole, FormatError, Framebuffer};
+use shared::log::Target;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub static LOG: Target = Target::new("framebuffer");
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitError {
+    /// The bootloader didn't report a framebuffer.
+    NotPresent,
+    /// The framebuffer isn't direct color, e.g. because GRUB stayed in text
+    /// mode.
+    NotRgb,
+    Format(FormatError),
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::NotPresent => write!(f, "no framebuffer"),
+            InitError::NotRgb => write!(f, "framebuffer is not RGB"),
+            InitError::Format(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Map the framebuffer described in `mbinfo` and start drawing log output on
+/// it. Returns its size in characters.
+pub fn init(mbinfo: &mb2::BootInformation) -> Result<(usize, usize), InitError> {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    let tag = match mbinfo.framebuffer_tag() {
+        None => return Err(InitError::NotPresent),
+        Some(Err(_)) => return Err(InitError::NotRgb),
+        Some(Ok(tag)) => tag,
+    };
+    let Ok(mb2::FramebufferType::RGB { red, green, blue }) = tag.buffer_type() else {
+        return Err(InitError::NotRgb);
+    };
+    debug!(
+        target: LOG.name(),
+        "{}x{} at {:#x}, {} bpp, pitch {}, red {red:?}, green {green:?}, blue {blue:?}",
+        tag.width(),
+        tag.height(),
+        tag.address(),
+        tag.bpp(),
+        tag.pitch()
+    );
+
+    let field = |field: mb2::FramebufferField| ColorField {
+        position: field.position,
+        size: field.size,
+    };
+    let mut fb = Framebuffer {
+        address: core::ptr::null_mut(),
+        width: tag.width() as usize,
+        height: tag.height() as usize,
+        pitch: tag.pitch() as usize,
+        bits_per_pixel: tag.bpp(),
+        red: field(red),
+        green: field(green),
+        blue: field(blue),
+    };
+
+    let phys = PhysExtent::from_raw(tag.address(), (fb.pitch * fb.height) as u64);
+    // SAFETY: the framebuffer is device memory, not RAM in use.
+    fb.address = unsafe { mm::map_mmio(phys) }
+        .extent()
+        .address()
+        .as_mut_ptr();
+
+    // SAFETY: the whole framebuffer was just mapped, and only CONSOLE uses it.
+    let console = unsafe { FbConsole::new(fb) }.map_err(InitError::Format)?;
+    let size = console.size();
+    without_interrupts(|| *CONSOLE.lock() = Some(console));
+    Ok(size)
+}
+
+/// Draws on the framebuffer console once `init` succeeds, and discards
+/// output until then.
+pub struct FramebufferWriter;
+
+impl Write for FramebufferWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        without_interrupts(|| match CONSOLE.lock().as_mut() {
+            Some(console) => console.write_str(s),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Only locked with interrupts disabled, since the logger may be used from
+/// interrupt handlers.
+static CONSOLE: Mutex<Option<FbConsole>> = Mutex::new(None);
@@ -0,0 +1,161 @@
+//! PCI configuration space access
+//!
+//! Functions are found by scanning every bus through the legacy I/O ports
+//! (configuration mechanism #1), which PC chipsets and QEMU's machines all
+//! support. PCIe's memory mapped configuration space isn't used, so only
+//! the first 256 bytes of each function's space can be reached.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const ADDRESS_ENABLE: u32 = 1 << 31;
+
+// Configuration space register offsets.
+const REG_VENDOR_ID: u8 = 0x00;
+const REG_DEVICE_ID: u8 = 0x02;
+const REG_COMMAND: u8 = 0x04;
+const REG_HEADER_TYPE: u8 = 0x0e;
+const REG_BAR0: u8 = 0x10;
+const REG_INTERRUPT_LINE: u8 = 0x3c;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+
+const BAR_IO: u32 = 1 << 0;
+
+/// Read as the vendor ID where there is no function.
+const NO_VENDOR: u16 = 0xffff;
+
+/// Serializes the address and data port pairs.
+static CONFIG: Mutex<()> = Mutex::new(());
+
+/// A function of a device on a PCI bus.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Function {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Function {
+    /// Read the 32 bit register containing `offset`.
+    pub fn read_u32(self, offset: u8) -> u32 {
+        without_interrupts(|| {
+            let _guard = CONFIG.lock();
+            // SAFETY: the ports only select and access configuration space.
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(self.address(offset));
+                Port::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    /// Write the 32 bit register containing `offset`.
+    pub fn write_u32(self, offset: u8, value: u32) {
+        without_interrupts(|| {
+            let _guard = CONFIG.lock();
+            // SAFETY: as in `read_u32`. What the write does to the device is
+            // the caller's business.
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(self.address(offset));
+                Port::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(self) -> u16 {
+        self.read_u16(REG_VENDOR_ID)
+    }
+
+    pub fn device_id(self) -> u16 {
+        self.read_u16(REG_DEVICE_ID)
+    }
+
+    /// Set `bits` in the command register, e.g. `COMMAND_BUS_MASTER` to let
+    /// the function do DMA.
+    pub fn enable(self, bits: u16) {
+        let command = self.read_u16(REG_COMMAND) | bits;
+        // The status register shares the dword, and writing ones to it
+        // clears its bits, so write zeros there.
+        self.write_u32(REG_COMMAND, command.into());
+    }
+
+    /// The I/O port base of base address register `index`, if it is an I/O
+    /// BAR.
+    pub fn io_bar(self, index: u8) -> Option<u16> {
+        assert!(index < 6);
+        let bar = self.read_u32(REG_BAR0 + index * 4);
+        if bar & BAR_IO == 0 {
+            return None;
+        }
+        u16::try_from(bar & !0b11).ok().filter(|&base| base != 0)
+    }
+
+    /// The ISA IRQ the firmware routed the function's interrupt pin to, if
+    /// any.
+    pub fn interrupt_line(self) -> Option<u8> {
+        match self.read_u8(REG_INTERRUPT_LINE) {
+            // 0xff means unknown or not connected.
+            0xff => None,
+            line => Some(line),
+        }
+    }
+
+    fn exists(self) -> bool {
+        self.vendor_id() != NO_VENDOR
+    }
+
+    fn address(self, offset: u8) -> u32 {
+        ADDRESS_ENABLE
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc)
+    }
+}
+
+impl core::fmt::Display for Function {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Every function on every bus.
+pub fn functions() -> impl Iterator<Item = Function> {
+    (0..=255u8)
+        .flat_map(|bus| (0..32).map(move |device| (bus, device)))
+        .flat_map(|(bus, device)| {
+            let first = Function {
+                bus,
+                device,
+                function: 0,
+            };
+            let count = if !first.exists() {
+                0
+            } else if first.read_u8(REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            (0..count).map(move |function| Function {
+                bus,
+                device,
+                function,
+            })
+        })
+        .filter(|function| function.exists())
+}
@@ -0,0 +1,329 @@
+//! 16550 UART driver for the first serial port
+//!
+//! Output is queued and sent from the UART's interrupt as its FIFO empties,
+//! and input is queued by the interrupt until read. Until `init` is called,
+//! or if there is no UART, output is sent by polling.
+
+use crate::irq;
+use crate::sched::{self, WaitQueue};
+use crate::shutdown;
+
+use core::time::Duration;
+
+use log::warn;
+use shared::io::{PolledSerialPort, PortCharSink, COM1};
+use shared::log::Target;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const IRQ: u8 = 4;
+
+// Register offsets from the I/O base.
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_INTERRUPT_ID: u16 = 2;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+// With the divisor latch bit set in the line control register.
+const REG_DIVISOR_LOW: u16 = 0;
+const REG_DIVISOR_HIGH: u16 = 1;
+
+const INTERRUPT_RECEIVED: u8 = 1 << 0;
+const INTERRUPT_TRANSMIT_EMPTY: u8 = 1 << 1;
+
+const INTERRUPT_ID_NONE_PENDING: u8 = 1 << 0;
+
+/// Enable and clear the FIFOs, interrupting when 14 bytes have been received.
+const FIFO_ENABLE_CLEAR_14: u8 = 0xc7;
+
+const LINE_DIVISOR_LATCH: u8 = 1 << 7;
+/// 8 data bits, no parity, one stop bit.
+const LINE_8N1: u8 = 0x03;
+
+const MODEM_DTR: u8 = 1 << 0;
+const MODEM_RTS: u8 = 1 << 1;
+/// Connects the UART's interrupt line on PC hardware.
+const MODEM_OUT2: u8 = 1 << 3;
+const MODEM_LOOPBACK: u8 = 1 << 4;
+
+const LINE_DATA_READY: u8 = 1 << 0;
+const LINE_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The transmit FIFO's size. It is empty whenever `LINE_TRANSMIT_EMPTY` is
+/// set.
+const FIFO_LEN: usize = 16;
+
+/// 115200 baud.
+const DIVISOR: u16 = 1;
+
+const QUEUE_LEN: usize = 4096;
+
+pub static LOG: Target = Target::new("serial");
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitError {
+    /// Nothing answered in loopback mode.
+    NotPresent,
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::NotPresent => write!(f, "no UART at {COM1:#x}"),
+        }
+    }
+}
+
+/// Program COM1 for 115200 8N1 and start using its interrupt.
+pub fn init() -> Result<(), InitError> {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    without_interrupts(|| {
+        let mut uart = UART.lock();
+        let uart = &mut *uart;
+
+        unsafe {
+            uart.write(REG_INTERRUPT_ENABLE, 0);
+            uart.write(REG_LINE_CONTROL, LINE_DIVISOR_LATCH);
+            uart.write(REG_DIVISOR_LOW, DIVISOR as u8);
+            uart.write(REG_DIVISOR_HIGH, (DIVISOR >> 8) as u8);
+            uart.write(REG_LINE_CONTROL, LINE_8N1);
+            uart.write(REG_FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+
+            // Check that something echoes in loopback mode.
+            uart.write(REG_MODEM_CONTROL, MODEM_LOOPBACK | MODEM_RTS | MODEM_OUT2);
+            uart.write(REG_DATA, 0xae);
+            if uart.read(REG_DATA) != 0xae {
+                return Err(InitError::NotPresent);
+            }
+
+            uart.write(REG_MODEM_CONTROL, MODEM_DTR | MODEM_RTS | MODEM_OUT2);
+            // Discard anything received before now.
+            while uart.read(REG_LINE_STATUS) & LINE_DATA_READY != 0 {
+                uart.read(REG_DATA);
+            }
+        }
+
+        uart.interrupts_enabled = true;
+        uart.update_interrupts();
+        Ok(())
+    })?;
+
+    irq::install_irq_handler(IRQ, Some(handle_irq));
+    shutdown::register(shutdown::Hook {
+        name: "serial flush",
+        order: 0,
+        timeout: Duration::from_secs(1),
+        run: flush,
+    });
+    Ok(())
+}
+
+/// Wait until everything queued has been handed to the UART.
+fn flush() {
+    loop {
+        let sent = without_interrupts(|| {
+            let mut uart = UART.lock();
+            uart.transmit();
+            uart.to_send.is_empty()
+                && unsafe { uart.read(REG_LINE_STATUS) } & LINE_TRANSMIT_EMPTY != 0
+        });
+        if sent {
+            return;
+        }
+        sched::yield_current();
+    }
+}
+
+/// Read received bytes into `buf`, blocking until there is at least one.
+/// Returns how many were read.
+#[allow(unused)]
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+
+    let mut len = 0;
+    RECEIVE_WAIT.wait_until(|| {
+        let mut uart = UART.lock();
+        while len < buf.len() {
+            let Some(byte) = uart.received.pop() else {
+                break;
+            };
+            buf[len] = byte;
+            len += 1;
+        }
+        len > 0
+    });
+    len
+}
+
+/// The number of received bytes dropped because nobody read them in time.
+#[allow(unused)]
+pub fn dropped() -> usize {
+    without_interrupts(|| UART.lock().dropped)
+}
+
+/// Sends to COM1 through the driver. Usable as a log sink at any time,
+/// including from interrupt handlers.
+#[allow(unused)]
+pub struct Com1;
+
+impl PortCharSink for Com1 {
+    const CRLF: bool = true;
+
+    fn put(&mut self, byte: u8) {
+        without_interrupts(|| UART.lock().send(byte));
+    }
+
+    fn put_all(&mut self, bytes: &[u8]) {
+        without_interrupts(|| {
+            let mut uart = UART.lock();
+            for &byte in bytes {
+                uart.send(byte);
+            }
+        });
+    }
+}
+
+fn handle_irq(_: InterruptStackFrame) {
+    let mut received = false;
+    let mut started_dropping = false;
+    {
+        let mut uart = UART.lock();
+        // The UART keeps its interrupt asserted until every cause is handled.
+        while unsafe { uart.read(REG_INTERRUPT_ID) } & INTERRUPT_ID_NONE_PENDING == 0 {
+            while unsafe { uart.read(REG_LINE_STATUS) } & LINE_DATA_READY != 0 {
+                let byte = unsafe { uart.read(REG_DATA) };
+                if uart.received.push(byte).is_err() {
+                    started_dropping |= uart.dropped == 0;
+                    uart.dropped += 1;
+                }
+                received = true;
+            }
+            uart.transmit();
+        }
+    }
+
+    if received {
+        RECEIVE_WAIT.wake_one();
+    }
+    // Only after unlocking, since the log may be written to the UART.
+    if started_dropping {
+        warn!(target: LOG.name(), "receive queue full, dropping input");
+    }
+}
+
+struct Uart {
+    /// Sent by polling, without the queues, until this is set.
+    interrupts_enabled: bool,
+    to_send: ByteQueue,
+    received: ByteQueue,
+    /// Received bytes dropped because `received` was full.
+    dropped: usize,
+}
+
+impl Uart {
+    unsafe fn read(&mut self, reg: u16) -> u8 {
+        unsafe { Port::new(COM1 + reg).read() }
+    }
+
+    unsafe fn write(&mut self, reg: u16, value: u8) {
+        unsafe { Port::new(COM1 + reg).write(value) }
+    }
+
+    #[allow(unused)]
+    fn send(&mut self, byte: u8) {
+        if !self.interrupts_enabled {
+            unsafe { PolledSerialPort::new(COM1) }.put(byte);
+            return;
+        }
+
+        // Rather than block, which might not be possible here, wait for the
+        // FIFO to make room.
+        while self.to_send.push(byte).is_err() {
+            core::hint::spin_loop();
+            self.transmit();
+        }
+        self.transmit();
+    }
+
+    /// Fill the transmit FIFO if it is empty.
+    fn transmit(&mut self) {
+        if unsafe { self.read(REG_LINE_STATUS) } & LINE_TRANSMIT_EMPTY != 0 {
+            for _ in 0..FIFO_LEN {
+                let Some(byte) = self.to_send.pop() else {
+                    break;
+                };
+                unsafe { self.write(REG_DATA, byte) };
+            }
+        }
+        self.update_interrupts();
+    }
+
+    /// Only ask for transmit interrupts while there is something to send.
+    fn update_interrupts(&mut self) {
+        let mut enable = INTERRUPT_RECEIVED;
+        if !self.to_send.is_empty() {
+            enable |= INTERRUPT_TRANSMIT_EMPTY;
+        }
+        unsafe { self.write(REG_INTERRUPT_ENABLE, enable) };
+    }
+}
+
+/// A fixed size FIFO of bytes.
+struct ByteQueue {
+    bytes: [u8; QUEUE_LEN],
+    start: usize,
+    len: usize,
+}
+
+impl ByteQueue {
+    const fn new() -> Self {
+        ByteQueue {
+            bytes: [0; QUEUE_LEN],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), ()> {
+        if self.len == QUEUE_LEN {
+            return Err(());
+        }
+        self.bytes[(self.start + self.len) % QUEUE_LEN] = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Only locked with interrupts disabled, since the interrupt handler takes it.
+static UART: Mutex<Uart> = Mutex::new(Uart {
+    interrupts_enabled: false,
+    to_send: ByteQueue::new(),
+    received: ByteQueue::new(),
+    dropped: 0,
+});
+
+static RECEIVE_WAIT: WaitQueue = WaitQueue::new();
@@ -0,0 +1,43 @@
+//! Text console on the VGA text buffer
+//!
+//! Without the `framebuffer` feature, GRUB leaves the display in VGA text
+//! mode. `init` maps the text buffer and clears the screen, and
+//! `VgaConsoleWriter` writes on it from then on.
+
+use crate::platform;
+
+use core::fmt::Write;
+
+use shared::vga::VgaWriter;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Start writing log output on the text buffer. Must be called once, after
+/// `mm::init`.
+pub fn init() {
+    // SAFETY: the text buffer is mapped, and only CONSOLE writes to it or
+    // uses the CRT controller.
+    let writer = unsafe { VgaWriter::new(platform::vga_text_buffer()) };
+    without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        assert!(console.is_none());
+        *console = Some(writer);
+    });
+}
+
+/// Writes on the text buffer once `init` is called, and discards output
+/// until then.
+pub struct VgaConsoleWriter;
+
+impl Write for VgaConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        without_interrupts(|| match CONSOLE.lock().as_mut() {
+            Some(console) => console.write_str(s),
+            None => Ok(()),
+        })
+    }
+}
+
+/// Only locked with interrupts disabled, since the logger may be used from
+/// interrupt handlers.
+static CONSOLE: Mutex<Option<VgaWriter>> = Mutex::new(None);
@@ -0,0 +1,368 @@
+//! Legacy virtio over PCI
+//!
+//! QEMU's virtio PCI devices are transitional by default: besides the modern
+//! interface, they implement the legacy one from virtio 0.9.5. Its registers
+//! are in I/O BAR 0, and each queue lives in physically contiguous memory
+//! whose page number the driver writes to the device. That is the only
+//! interface driven here, so devices with `disable-legacy=on` aren't found.
+//!
+//! Queues are split virtqueues: a descriptor table, a ring of buffers made
+//! available to the device, and a ring of buffers the device has used.
+
+use crate::mm::dma::{self, DmaBuffer};
+use crate::mm::{PhysAddress, Zone};
+
+use super::pci;
+
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::instructions::port::Port;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// Legacy register offsets from BAR 0.
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_DRIVER_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_ISR: u16 = 0x13;
+/// Device specific configuration starts here while MSI-X is disabled.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Set in the ISR status when a queue was used.
+const ISR_QUEUE: u8 = 1 << 0;
+
+/// The legacy interface's queue alignment, and the unit of `REG_QUEUE_PFN`.
+const QUEUE_ALIGN: usize = 4096;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// BAR 0 isn't an I/O BAR.
+    NoIoBar,
+    /// The device doesn't have the queue.
+    NoQueue(u16),
+    /// There isn't enough contiguous memory for the queue, or it is above
+    /// where the legacy interface can address it.
+    QueueMemory(u16),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::NoIoBar => write!(f, "BAR 0 is not an I/O BAR"),
+            Error::NoQueue(index) => write!(f, "queue {index} does not exist"),
+            Error::QueueMemory(index) => write!(f, "can't allocate memory for queue {index}"),
+        }
+    }
+}
+
+/// A legacy virtio device's registers.
+pub struct Device {
+    io_base: u16,
+    /// Names DMA mappings.
+    name: &'static str,
+}
+
+impl Device {
+    /// Reset the device at `function` and acknowledge it, letting it do DMA.
+    /// Must be followed by `negotiate`.
+    pub fn new(function: pci::Function, name: &'static str) -> Result<Device, Error> {
+        let io_base = function.io_bar(0).ok_or(Error::NoIoBar)?;
+        function.enable(pci::COMMAND_IO_SPACE | pci::COMMAND_BUS_MASTER);
+        let device = Device { io_base, name };
+        device.write_u8(REG_STATUS, 0);
+        device.write_u8(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(device)
+    }
+
+    /// Accept the features in `wanted` that the device offers, and return
+    /// them.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = self.read_u32(REG_DEVICE_FEATURES) & wanted;
+        self.write_u32(REG_DRIVER_FEATURES, features);
+        features
+    }
+
+    /// Allocate queue `index` and hand it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, Error> {
+        self.write_u16(REG_QUEUE_SELECT, index);
+        let size = self.read_u16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return Err(Error::NoQueue(index));
+        }
+        let queue = Virtqueue::new(self.name, size).ok_or(Error::QueueMemory(index))?;
+        let pfn = queue.memory.phys().as_raw() / QUEUE_ALIGN as u64;
+        let pfn = u32::try_from(pfn).map_err(|_| Error::QueueMemory(index))?;
+        self.write_u32(REG_QUEUE_PFN, pfn);
+        Ok(queue)
+    }
+
+    /// Tell the device the driver is set up. Its queues are live after this.
+    pub fn driver_ok(&self) {
+        self.write_u8(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+    }
+
+    /// Tell the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.write_u8(REG_STATUS, STATUS_FAILED);
+    }
+
+    /// Tell the device queue `index` has new buffers.
+    pub fn notify(&self, index: u16) {
+        // The ring must be written before the device looks at it.
+        fence(Ordering::SeqCst);
+        self.write_u16(REG_QUEUE_NOTIFY, index);
+    }
+
+    /// Acknowledge the device's interrupt. Returns whether it was for a used
+    /// queue, rather than e.g. a configuration change.
+    pub fn acknowledge_interrupt(&self) -> bool {
+        // Reading the register clears it, and deasserts the interrupt.
+        self.read_u8(REG_ISR) & ISR_QUEUE != 0
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        self.read_u32(REG_DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        // The legacy interface has no way to read both halves atomically, but
+        // the fields read here don't change.
+        u64::from(self.config_u32(offset)) | u64::from(self.config_u32(offset + 4)) << 32
+    }
+
+    fn read_u8(&self, reg: u16) -> u8 {
+        // SAFETY: the BAR's ports only reach the device's registers.
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn read_u16(&self, reg: u16) -> u16 {
+        // SAFETY: as in `read_u8`.
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn read_u32(&self, reg: u16) -> u32 {
+        // SAFETY: as in `read_u8`.
+        unsafe { Port::new(self.io_base + reg).read() }
+    }
+
+    fn write_u8(&self, reg: u16, value: u8) {
+        // SAFETY: as in `read_u8`. Devices only DMA to memory the driver
+        // gave them.
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+
+    fn write_u16(&self, reg: u16, value: u16) {
+        // SAFETY: as in `write_u8`.
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+
+    fn write_u32(&self, reg: u16, value: u32) {
+        // SAFETY: as in `write_u8`.
+        unsafe { Port::new(self.io_base + reg).write(value) }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    /// The head of the used descriptor chain.
+    id: u32,
+    /// Bytes the device wrote.
+    len: u32,
+}
+
+/// A buffer in a descriptor chain.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub address: PhysAddress,
+    pub len: u32,
+    /// Whether the device writes it, rather than reads it.
+    pub device_writes: bool,
+}
+
+/// A split virtqueue in the legacy layout. The device owns the buffers in a
+/// chain from when it is added until it is popped.
+pub struct Virtqueue {
+    /// Never completed, since the device is never reset.
+    memory: DmaBuffer,
+    size: u16,
+    /// The first unused descriptor. Unused ones are linked through `next`.
+    free_head: u16,
+    num_free: u16,
+    /// The used ring's index as of the last `pop_used`.
+    last_used: u16,
+}
+
+// SAFETY: the queue's memory is only accessed through `&mut self`.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    fn new(device: &'static str, size: u16) -> Option<Virtqueue> {
+        let size_bytes = Self::used_offset(size) + Self::used_len(size);
+        let memory = dma::alloc_coherent(device, size_bytes, Zone::Normal)?;
+
+        let queue = Virtqueue {
+            memory,
+            size,
+            free_head: 0,
+            num_free: size,
+            last_used: 0,
+        };
+        for index in 0..size {
+            // SAFETY: only the driver uses descriptors that aren't in a chain.
+            unsafe {
+                (*queue.descriptor(index)).next = index + 1;
+            }
+        }
+        Some(queue)
+    }
+
+    /// Make `buffers` available to the device as one chain. Returns the
+    /// chain's head, or `None` if there aren't enough free descriptors. The
+    /// device isn't notified.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay allocated, and only be accessed as the device
+    /// allows, until the chain is popped.
+    pub unsafe fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.num_free) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            // SAFETY: the descriptor is free.
+            let descriptor = unsafe { &mut *self.descriptor(index) };
+            descriptor.address = buffer.address.as_raw();
+            descriptor.len = buffer.len;
+            descriptor.flags = if buffer.device_writes {
+                DESCRIPTOR_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESCRIPTOR_NEXT;
+                index = descriptor.next;
+            } else {
+                self.free_head = descriptor.next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+
+        // SAFETY: the driver owns the available ring's entries and index.
+        unsafe {
+            let avail_index = self.avail_index().read_volatile();
+            self.avail_ring(avail_index % self.size)
+                .write_volatile(head);
+            // The entry must be visible before the index that publishes it.
+            fence(Ordering::SeqCst);
+            self.avail_index()
+                .write_volatile(avail_index.wrapping_add(1));
+        }
+        Some(head)
+    }
+
+    /// Take back the next chain the device used. Returns its head and how
+    /// many bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        // SAFETY: the device only writes the used ring's index and entries
+        // before the index.
+        let used_index = unsafe { self.used_index().read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        // The entry was written before the index.
+        fence(Ordering::SeqCst);
+        let element = unsafe { self.used_ring(self.last_used % self.size).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            // SAFETY: the device gave the chain back.
+            let descriptor = unsafe { &mut *self.descriptor(index) };
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+        Some((head, element.len))
+    }
+
+    fn avail_offset(size: u16) -> usize {
+        core::mem::size_of::<Descriptor>() * usize::from(size)
+    }
+
+    fn used_offset(size: u16) -> usize {
+        // Flags, index, ring and the used event index.
+        let avail_len = 2 * (3 + usize::from(size));
+        (Self::avail_offset(size) + avail_len).next_multiple_of(QUEUE_ALIGN)
+    }
+
+    fn used_len(size: u16) -> usize {
+        // Flags, index, ring and the available event index.
+        2 * 3 + core::mem::size_of::<UsedElement>() * usize::from(size)
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.memory.as_mut_ptr()
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        assert!(index < self.size);
+        // SAFETY: the table is at the start of the queue's memory.
+        unsafe { self.base().cast::<Descriptor>().add(usize::from(index)) }
+    }
+
+    fn avail_index(&self) -> *mut u16 {
+        // SAFETY: after the available ring's flags.
+        unsafe { self.base().add(Self::avail_offset(self.size) + 2).cast() }
+    }
+
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        // SAFETY: after the available ring's flags and index.
+        unsafe { self.avail_index().add(1 + usize::from(slot)) }
+    }
+
+    fn used_index(&self) -> *mut u16 {
+        // SAFETY: after the used ring's flags.
+        unsafe { self.base().add(Self::used_offset(self.size) + 2).cast() }
+    }
+
+    fn used_ring(&self, slot: u16) -> *mut UsedElement {
+        // SAFETY: after the used ring's flags and index, which keep the
+        // elements 4 byte aligned.
+        unsafe {
+            self.base()
+                .add(Self::used_offset(self.size) + 4)
+                .cast::<UsedElement>()
+                .add(usize::from(slot))
+        }
+    }
+}
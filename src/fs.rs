@@ -0,0 +1,179 @@
+//! Files
+//!
+//! Filesystems are reached through `vfs`'s mount table. `init` mounts the
+//! initrd at `/initrd` and the first FAT32 volume found on a block device
+//! (see `fat`) at `/`, both read-only. `open` finds files by absolute path,
+//! whichever filesystem they are on, and `File::read_at` reads them into
+//! kernel buffers. Nothing is cached, so every read of a file on disk goes to
+//! the device.
+
+mod fat;
+mod initrd;
+pub mod vfs;
+
+use crate::drivers::block::{self, BlockError};
+use crate::itest::{kernel_test, TestResult};
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+
+use log::info;
+use shared::test_disk;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// Something is already mounted there.
+    AlreadyMounted,
+    /// The filesystem's structures don't make sense.
+    Corrupt,
+    Io(BlockError),
+}
+
+impl core::fmt::Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::AlreadyMounted => write!(f, "something is already mounted there"),
+            FsError::Corrupt => write!(f, "the filesystem is corrupt"),
+            FsError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Mount the initrd and the root volume. Must be called from a task with
+/// interrupts enabled, after `initrd::init` and `drivers::block::init`.
+pub fn init() {
+    if let Some(archive) = crate::initrd::archive() {
+        let initrd: &'static initrd::InitrdFs = Box::leak(Box::new(initrd::InitrdFs::new(archive)));
+        vfs::mount("/initrd", initrd).unwrap();
+    }
+    match (0..).map_while(block::get).find_map(fat::mount) {
+        Some(volume) => vfs::mount("/", volume).unwrap(),
+        None => info!("No FAT32 volume to mount at /"),
+    }
+}
+
+/// An open regular file.
+pub struct File {
+    handle: Box<dyn vfs::FileHandle>,
+}
+
+/// Open the regular file at `path`, which is taken as absolute whether or not
+/// it starts with `/`.
+pub fn open(path: &str) -> Result<File, FsError> {
+    let handle = vfs::resolve(path)?.open()?;
+    Ok(File { handle })
+}
+
+impl File {
+    /// In bytes.
+    pub fn size(&self) -> u64 {
+        self.handle.size()
+    }
+
+    /// Read from `offset` into `buf`. Returns how many bytes were read, which
+    /// is less than `buf.len()` only at the end of the file.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.handle.read_at(offset, buf)
+    }
+
+    /// The whole file.
+    pub fn read_all(&self) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0; self.size() as usize];
+        let len = self.read_at(0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+kernel_test!(
+    "fs: files on the test disk read back",
+    reads_test_disk_files
+);
+fn reads_test_disk_files() -> TestResult {
+    if vfs::resolve(test_disk::BIG_FILE).err() == Some(FsError::NotFound) {
+        // Only `mkimage test` attaches the disk.
+        info!("no filesystem; skipping");
+        return Ok(());
+    }
+    for &(path, expected) in test_disk::FILES {
+        let data = open(path)
+            .and_then(|file| file.read_all())
+            .map_err(|e| format!("{path}: {e}"))?;
+        if data != expected {
+            return Err(format!("{path} has the wrong contents"));
+        }
+    }
+
+    // Uneven pieces, so reads start and end partway through sectors and
+    // clusters.
+    let big = open(test_disk::BIG_FILE).map_err(|e| format!("{e}"))?;
+    let expected = test_disk::big_file();
+    if big.size() != expected.len() as u64 {
+        return Err(format!("{} is {} bytes", test_disk::BIG_FILE, big.size()));
+    }
+    let mut data = Vec::new();
+    let mut piece = [0; 700];
+    loop {
+        let len = big
+            .read_at(data.len() as u64, &mut piece)
+            .map_err(|e| format!("{e}"))?;
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&piece[..len]);
+    }
+    if data != expected {
+        return Err(format!("{} has the wrong contents", test_disk::BIG_FILE));
+    }
+
+    // Through `..`, and differing in case.
+    open("/DIR/../Hello.TXT").map_err(|e| format!("{e}"))?;
+
+    let missing = open("/no/such/file").err();
+    if missing != Some(FsError::NotFound) {
+        return Err(format!("opening a missing file gave {missing:?}"));
+    }
+    let dir = open("/dir").err();
+    if dir != Some(FsError::IsADirectory) {
+        return Err(format!("opening a directory gave {dir:?}"));
+    }
+    Ok(())
+}
+
+kernel_test!("fs: the initrd is mounted", initrd_is_mounted);
+fn initrd_is_mounted() -> TestResult {
+    let Some(archive) = crate::initrd::archive() else {
+        info!("no initrd; skipping");
+        return Ok(());
+    };
+    // mkimage puts every init program in `/bin`.
+    let mut names = Vec::new();
+    vfs::resolve("/initrd/bin")
+        .and_then(|bin| {
+            bin.read_dir(&mut |name, _| {
+                names.push(String::from(name));
+                ControlFlow::Continue(())
+            })
+        })
+        .map_err(|e| format!("listing /initrd/bin: {e}"))?;
+    if !names.iter().any(|name| name == "init") {
+        return Err(format!("/initrd/bin has {names:?}"));
+    }
+    let data = open("/initrd/bin/init")
+        .and_then(|file| file.read_all())
+        .map_err(|e| format!("/initrd/bin/init: {e}"))?;
+    if Some(data.as_slice()) != archive.open("bin/init") {
+        return Err("/initrd/bin/init has the wrong contents".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! FAT32 volumes on block devices
+//!
+//! Adapts a `BlockDevice` to `shared::fat`, which does the actual work, and
+//! that to `vfs`. A volume is either the whole device or a FAT32 partition in
+//! its MBR.
+
+use super::vfs::{FileHandle, FileSystem, INode, NodeKind};
+use super::FsError;
+use crate::drivers::block::{BlockDevice, BlockError};
+
+use alloc::boxed::Box;
+use core::ops::ControlFlow;
+
+use log::info;
+use shared::fat::{self, Disk, Node, SECTOR_SIZE};
+
+type Volume = fat::Volume<Partition>;
+
+impl From<fat::Error<BlockError>> for FsError {
+    fn from(e: fat::Error<BlockError>) -> Self {
+        match e {
+            fat::Error::Disk(e) => FsError::Io(e),
+            fat::Error::NotFat32 | fat::Error::BadChain(_) => FsError::Corrupt,
+            fat::Error::NotFound => FsError::NotFound,
+            fat::Error::NotADirectory => FsError::NotADirectory,
+            fat::Error::IsADirectory => FsError::IsADirectory,
+        }
+    }
+}
+
+/// A mounted volume.
+pub struct FatFs {
+    volume: Volume,
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn root(&'static self) -> Box<dyn INode> {
+        Box::new(FatNode {
+            volume: &self.volume,
+            node: self.volume.root(),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FatNode {
+    volume: &'static Volume,
+    node: Node,
+}
+
+impl INode for FatNode {
+    fn kind(&self) -> NodeKind {
+        if self.node.is_dir {
+            NodeKind::Dir
+        } else {
+            NodeKind::File
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Box<dyn INode>, FsError> {
+        let node = self.volume.find(self.node, name)?;
+        Ok(Box::new(FatNode {
+            volume: self.volume,
+            node,
+        }))
+    }
+
+    fn read_dir(
+        &self,
+        f: &mut dyn FnMut(&str, NodeKind) -> ControlFlow<()>,
+    ) -> Result<(), FsError> {
+        self.volume.read_dir(self.node, |entry| {
+            if matches!(entry.name.as_str(), "." | "..") {
+                return ControlFlow::Continue(());
+            }
+            let kind = if entry.node.is_dir {
+                NodeKind::Dir
+            } else {
+                NodeKind::File
+            };
+            f(&entry.name, kind)
+        })?;
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Box<dyn FileHandle>, FsError> {
+        if self.node.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(Box::new(*self))
+    }
+}
+
+impl FileHandle for FatNode {
+    fn size(&self) -> u64 {
+        self.node.size.into()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(self.volume.read_at(self.node, offset, buf)?)
+    }
+}
+
+/// Sectors of a block device with 512 byte blocks, numbered from `start`.
+struct Partition {
+    device: &'static dyn BlockDevice,
+    start: u64,
+    len: u64,
+}
+
+impl Disk for Partition {
+    type Error = BlockError;
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = (buf.len() / SECTOR_SIZE) as u64;
+        if sector.checked_add(count).is_none_or(|end| end > self.len) {
+            return Err(BlockError::OutOfRange {
+                first: sector,
+                count,
+                num_blocks: self.len,
+            });
+        }
+        self.device.read_blocks(self.start + sector, buf)
+    }
+}
+
+/// Mount the FAT32 volume on `device`: the whole device if it is one, or
+/// else the first partition that is. `None` if there is none.
+pub fn mount(device: &'static dyn BlockDevice) -> Option<&'static FatFs> {
+    if device.block_size() != SECTOR_SIZE {
+        return None;
+    }
+    let whole = Partition {
+        device,
+        start: 0,
+        len: device.num_blocks(),
+    };
+    let mut mbr = [0; SECTOR_SIZE];
+    whole.read(0, &mut mbr).ok()?;
+    // A volume's boot sector has the same signature as an MBR, so try it as
+    // a volume first.
+    let volume = Volume::mount(whole).ok().or_else(|| {
+        fat::partitions(&mbr).find_map(|(start, len)| {
+            let fits = start
+                .checked_add(len)
+                .is_some_and(|end| end <= device.num_blocks());
+            fits.then(|| Volume::mount(Partition { device, start, len }).ok())
+                .flatten()
+        })
+    })?;
+    info!(
+        "FAT32 volume at block {} of {}",
+        volume.disk().start,
+        device.name()
+    );
+    Some(Box::leak(Box::new(FatFs { volume })))
+}
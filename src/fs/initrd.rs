@@ -0,0 +1,162 @@
+//! The initrd as a filesystem
+//!
+//! Adapts the initrd's cpio archive to `vfs`. The archive is a flat list of
+//! paths, so a node is just a path in it. Directories needn't have their own
+//! entries; any path with entries under it is one. Names are matched
+//! exactly.
+
+use super::vfs::{FileHandle, FileSystem, INode, NodeKind};
+use super::FsError;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use core::ops::ControlFlow;
+
+use shared::cpio::Archive;
+
+pub struct InitrdFs {
+    archive: &'static Archive<'static>,
+}
+
+impl InitrdFs {
+    pub fn new(archive: &'static Archive<'static>) -> Self {
+        InitrdFs { archive }
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &str {
+        "initrd"
+    }
+
+    fn root(&'static self) -> Box<dyn INode> {
+        Box::new(InitrdNode {
+            archive: self.archive,
+            path: String::new(),
+            data: None,
+        })
+    }
+}
+
+struct InitrdNode {
+    archive: &'static Archive<'static>,
+    /// Without a leading `/`, so the root is empty.
+    path: String,
+    /// The contents, for a regular file.
+    data: Option<&'static [u8]>,
+}
+
+impl InitrdNode {
+    /// The paths of the entries under this directory, relative to it, and
+    /// whether each is a directory.
+    fn children(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.archive.entries().filter_map(|entry| {
+            let relative = if self.path.is_empty() {
+                entry.path
+            } else {
+                entry
+                    .path
+                    .strip_prefix(self.path.as_str())?
+                    .strip_prefix('/')?
+            };
+            (!relative.is_empty() && relative != ".").then_some((relative, entry.is_dir()))
+        })
+    }
+}
+
+impl INode for InitrdNode {
+    fn kind(&self) -> NodeKind {
+        match self.data {
+            Some(_) => NodeKind::File,
+            None => NodeKind::Dir,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Box<dyn INode>, FsError> {
+        let path = if self.path.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}/{name}", self.path)
+        };
+        let data = match self.archive.get(&path) {
+            Some(entry) if entry.is_file() => Some(entry.data),
+            Some(entry) if entry.is_dir() => None,
+            // Such as a symlink.
+            Some(_) => return Err(FsError::NotFound),
+            None => {
+                let has_children = self.archive.entries().any(|entry| {
+                    entry
+                        .path
+                        .strip_prefix(path.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+                });
+                if !has_children {
+                    return Err(FsError::NotFound);
+                }
+                None
+            }
+        };
+        Ok(Box::new(InitrdNode {
+            archive: self.archive,
+            path,
+            data,
+        }))
+    }
+
+    fn read_dir(
+        &self,
+        f: &mut dyn FnMut(&str, NodeKind) -> ControlFlow<()>,
+    ) -> Result<(), FsError> {
+        if self.data.is_some() {
+            return Err(FsError::NotADirectory);
+        }
+        // Sorted, and each name once even if it has several entries under
+        // it.
+        let mut children = BTreeMap::new();
+        for (relative, is_dir) in self.children() {
+            let (name, kind) = match relative.split_once('/') {
+                Some((name, _)) => (name, NodeKind::Dir),
+                None if is_dir => (relative, NodeKind::Dir),
+                None => (relative, NodeKind::File),
+            };
+            children.entry(name).or_insert(kind);
+        }
+        for (name, kind) in children {
+            if f(name, kind).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Box<dyn FileHandle>, FsError> {
+        match self.data {
+            Some(data) => Ok(Box::new(InitrdFile { data })),
+            None => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+struct InitrdFile {
+    data: &'static [u8],
+}
+
+impl FileHandle for InitrdFile {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Some(rest) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.data.get(offset..))
+        else {
+            return Ok(0);
+        };
+        let len = buf.len().min(rest.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,148 @@
+//! The virtual filesystem
+//!
+//! Each filesystem driver implements `FileSystem`, whose nodes implement
+//! `INode`, and opening a regular file gives a `FileHandle`. Filesystems are
+//! mounted at absolute paths. `resolve` finds the mount with the longest
+//! path that prefixes the one given, whole components at a time, then walks
+//! the rest of the path from that filesystem's root. Mount points needn't
+//! exist in the filesystem they are under, and listing a directory doesn't
+//! show the mounts in it.
+//!
+//! `.` and `..` are removed from paths before resolving, without looking at
+//! the filesystems, so `a/..` is always the directory `a` is in, even if `a`
+//! is a mount.
+
+use super::FsError;
+use crate::itest::{kernel_test, TestResult};
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+
+use log::info;
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeKind {
+    File,
+    Dir,
+}
+
+/// A mountable filesystem. Filesystems stay mounted until the kernel exits.
+pub trait FileSystem: Send + Sync {
+    /// Identifies the filesystem in logs, e.g. `fat` or `initrd`.
+    fn name(&self) -> &str;
+
+    fn root(&'static self) -> Box<dyn INode>;
+}
+
+/// A file or directory in a filesystem.
+pub trait INode: Send + Sync {
+    fn kind(&self) -> NodeKind;
+
+    /// The child called `name` of this directory.
+    fn lookup(&self, name: &str) -> Result<Box<dyn INode>, FsError>;
+
+    /// Call `f` with the name and kind of each of this directory's children,
+    /// not including `.` and `..`, until it breaks.
+    fn read_dir(&self, f: &mut dyn FnMut(&str, NodeKind) -> ControlFlow<()>)
+        -> Result<(), FsError>;
+
+    /// Open this regular file.
+    fn open(&self) -> Result<Box<dyn FileHandle>, FsError>;
+}
+
+/// An open regular file.
+pub trait FileHandle: Send + Sync {
+    /// In bytes.
+    fn size(&self) -> u64;
+
+    /// Read from `offset` into `buf`. Returns how many bytes were read, which
+    /// is less than `buf.len()` only at the end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+}
+
+struct Mount {
+    /// Normalized, without the leading `/`, so the root is empty.
+    path: String,
+    fs: &'static dyn FileSystem,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mount `fs` at `path`.
+pub fn mount(path: &str, fs: &'static dyn FileSystem) -> Result<(), FsError> {
+    let path = normalize(path).join("/");
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::AlreadyMounted);
+    }
+    info!("Mounted {} at /{path}", fs.name());
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Find the node at `path`, which is taken as absolute whether or not it
+/// starts with `/`.
+pub fn resolve(path: &str) -> Result<Box<dyn INode>, FsError> {
+    let components = normalize(path);
+    let (fs, depth) = {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .filter_map(|mount| {
+                let mount_components = mount.path.split('/').filter(|c| !c.is_empty());
+                let depth = mount_components.clone().count();
+                let matches = components.len() >= depth
+                    && mount_components.zip(&components).all(|(a, b)| a == *b);
+                matches.then_some((mount.fs, depth))
+            })
+            .max_by_key(|&(_, depth)| depth)
+            .ok_or(FsError::NotFound)?
+    };
+
+    let mut node = fs.root();
+    for component in &components[depth..] {
+        if node.kind() != NodeKind::Dir {
+            return Err(FsError::NotADirectory);
+        }
+        node = node.lookup(component)?;
+    }
+    Ok(node)
+}
+
+/// The components of `path`, with empty ones and `.` removed and `..`
+/// applied.
+fn normalize(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components
+}
+
+kernel_test!("fs: paths are normalized", paths_are_normalized);
+fn paths_are_normalized() -> TestResult {
+    let cases: &[(&str, &[&str])] = &[
+        ("", &[]),
+        ("/", &[]),
+        ("//a///b/", &["a", "b"]),
+        ("a/./b/../c", &["a", "c"]),
+        ("/../../a", &["a"]),
+    ];
+    for &(path, expected) in cases {
+        let got = normalize(path);
+        if got != expected {
+            return Err(format!("{path:?} became {got:?}"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! What the boot loader hands the kernel
+//!
+//! GRUB describes the machine in a multiboot2 information structure.
+//! `BootInfo` collects what must be known before the frame allocator is set
+//! up: the memory map, the modules GRUB loaded, and the memory the loader's
+//! own data is in. `mm::init` keeps the modules and reserved ranges from the
+//! frame allocator, so they stay intact for as long as the kernel runs.
+
+use crate::mm::PhysExtent;
+
+use arrayvec::ArrayVec;
+use log::warn;
+use multiboot2 as mb2;
+use testos_memory::{Map, MapEntry, MemoryType};
+
+/// The most modules `BootInfo` lists. Any more are ignored.
+pub const MAX_MODULES: usize = 8;
+
+/// The most loader-reserved ranges `BootInfo` lists.
+pub const MAX_RESERVED: usize = 4;
+
+/// A file the loader put in memory for the kernel.
+#[derive(Clone, Copy, Debug)]
+pub struct Module<'a> {
+    /// The rest of the module's `module2` line in the GRUB config.
+    pub cmdline: &'a str,
+    pub extent: PhysExtent,
+}
+
+#[derive(Clone)]
+pub struct BootInfo<'a> {
+    pub memory_map: Map,
+    /// In the order the loader lists them. The first is init.
+    pub modules: ArrayVec<Module<'a>, MAX_MODULES>,
+    /// Memory that holds the loader's data the kernel still reads, other
+    /// than modules.
+    pub reserved: ArrayVec<PhysExtent, MAX_RESERVED>,
+}
+
+impl<'a> BootInfo<'a> {
+    /// Collect the boot information GRUB passed in `mbinfo`.
+    pub fn from_multiboot(mbinfo: &'a mb2::BootInformation<'_>) -> Self {
+        let mut modules = ArrayVec::new();
+        for module in mbinfo.module_tags() {
+            let cmdline = module.cmdline().unwrap_or_else(|e| {
+                warn!("a module's command line is invalid: {e:?}");
+                ""
+            });
+            let module = Module {
+                cmdline,
+                extent: PhysExtent::from_raw_range_exclusive(
+                    module.start_address().into(),
+                    module.end_address().into(),
+                ),
+            };
+            if let Err(e) = modules.try_push(module) {
+                warn!(
+                    "ignoring module {:?} past the first {MAX_MODULES}",
+                    e.element()
+                );
+            }
+        }
+
+        // ACPI and the framebuffer are found through the information
+        // structure after `mm::init`. It is still identity mapped, so its
+        // address is physical.
+        let mut reserved = ArrayVec::new();
+        reserved.push(PhysExtent::from_raw(
+            mbinfo.start_address() as u64,
+            mbinfo.total_size() as u64,
+        ));
+
+        BootInfo {
+            memory_map: translate_memory_map(mbinfo),
+            modules,
+            reserved,
+        }
+    }
+
+    /// The first module with command line `cmdline`.
+    pub fn module(&self, cmdline: &str) -> Option<&Module<'a>> {
+        self.modules.iter().find(|m| m.cmdline == cmdline)
+    }
+
+    /// Everything the frame allocator must not hand out, the modules and the
+    /// loader-reserved ranges, sorted by address.
+    pub fn reserved_extents(&self) -> ArrayVec<PhysExtent, { MAX_MODULES + MAX_RESERVED }> {
+        let mut extents: ArrayVec<_, { MAX_MODULES + MAX_RESERVED }> = self
+            .modules
+            .iter()
+            .map(|m| m.extent)
+            .chain(self.reserved.iter().copied())
+            .collect();
+        extents.sort_unstable_by_key(|e| e.address());
+        extents
+    }
+}
+
+/// GRUB passes on the firmware's map as is, so it is normalized here.
+fn translate_memory_map(mb2_info: &mb2::BootInformation) -> Map {
+    let mem_map_tag = mb2_info.memory_map_tag().unwrap();
+    let mut map = Map::from_entries(mem_map_tag.memory_areas().iter().map(|area| MapEntry {
+        // Empty areas are allowed until they are normalized away.
+        extent: PhysExtent::from_raw_range_exclusive(area.start_address(), area.end_address()),
+        mem_type: match area.typ().into() {
+            mb2::MemoryAreaType::Available => MemoryType::Available,
+            mb2::MemoryAreaType::Reserved => MemoryType::Reserved,
+            mb2::MemoryAreaType::AcpiAvailable => MemoryType::Acpi,
+            mb2::MemoryAreaType::ReservedHibernate => MemoryType::ReservedPreserveOnHibernation,
+            mb2::MemoryAreaType::Defective => MemoryType::Defective,
+            t => panic!("unknown mb2 memory type {t:?}"),
+        },
+    }));
+    map.normalize()
+        .unwrap_or_else(|e| panic!("can't normalize the memory map: {e}"));
+    map
+}
@@ -1,10 +1,40 @@
 //! IDT management
 //!
 //! The interrupt descriptor table maps CPU interrupts to handlers.
+//!
+//! Every architecture-defined exception goes through an entry stub that saves
+//! all general purpose registers, so `handle_exception` can log them along
+//! with the decoded error code and the current CPU and task before panicking.
+//! Two kinds can return instead: NMIs sent by `mm::tlb::shootdown`, once the
+//! TLB is flushed, and page faults that `mm::fault` resolves, e.g. by copying
+//! a copy-on-write page or backing anonymous memory.
+//! Double faults, NMIs, and machine checks run on their own stacks (see
+//! `gdt::DOUBLE_FAULT_IST_INDEX` and its neighbors), so they are still
+//! reported when the kernel stack is unusable, e.g. after it overflowed.
+//!
+//! Vectors that `vectors::allocate_handler` hands out enter through the same
+//! path, with stubs generated for the whole dynamic range, and
+//! `handle_exception` passes them on to `vectors::dispatch`.
+
+use crate::arch;
+use crate::arch::gdt;
+use crate::mm;
+use crate::mm::fault::{self, Outcome, PageFault};
+use crate::sched;
+use crate::smp;
+use crate::vectors;
+
+use core::arch::{asm, global_asm};
+use core::fmt;
 
+use log::error;
+use shared::fmt::HexDump;
 use spin::mutex::SpinMutex;
 use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::mxcsr;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::*;
+use x86_64::VirtAddr;
 
 // The wrapped InterruptDescriptorTable must never be dropped or moved.
 static IDT: SpinMutex<InterruptDescriptorTable> = SpinMutex::new(InterruptDescriptorTable::new());
@@ -23,38 +53,54 @@ fn init_impl() {
 
     // Only one GDT code selector is used in the kernel. These by default use
     // the current selector.
-    idt.divide_error.set_handler_fn(divide_error_handler);
-    idt.debug.set_handler_fn(debug_handler);
-    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.overflow.set_handler_fn(overflow_handler);
-    idt.bound_range_exceeded
-        .set_handler_fn(bound_range_exceeded_handler);
-    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-    idt.device_not_available
-        .set_handler_fn(device_not_available_handler);
-    idt.double_fault.set_handler_fn(double_fault_handler);
-    idt[9].set_handler_fn(unrecognized_exception_handler);
-    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-    idt.segment_not_present
-        .set_handler_fn(segment_not_present_handler);
-    idt.stack_segment_fault
-        .set_handler_fn(stack_segment_fault_handler);
-    idt.general_protection_fault
-        .set_handler_fn(general_protection_fault_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
-    // Entry 15 is reserved
-    idt.x87_floating_point
-        .set_handler_fn(x87_floating_point_handler);
-    idt.alignment_check.set_handler_fn(alignment_check_handler);
-    idt.machine_check.set_handler_fn(machine_check_handler);
-    idt.simd_floating_point
-        .set_handler_fn(simd_floating_point_handler);
-    idt.virtualization.set_handler_fn(virtualization_handler);
-    // Entries 21..30 are reserved
-    idt.security_exception
-        .set_handler_fn(security_exception_handler);
-    // Entry 31 is reserved
+    //
+    // SAFETY: each entry point expects its vector's stack layout: with an
+    // error code exactly when the CPU pushes one.
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error_entry));
+        idt.debug.set_handler_addr(addr(debug_entry));
+        idt.non_maskable_interrupt
+            .set_handler_addr(addr(nmi_entry))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(addr(breakpoint_entry));
+        idt.overflow.set_handler_addr(addr(overflow_entry));
+        idt.bound_range_exceeded
+            .set_handler_addr(addr(bound_range_exceeded_entry));
+        idt.invalid_opcode
+            .set_handler_addr(addr(invalid_opcode_entry));
+        idt.device_not_available
+            .set_handler_addr(addr(device_not_available_entry));
+        idt.double_fault
+            .set_handler_addr(addr(double_fault_entry))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt[9].set_handler_addr(addr(coprocessor_segment_overrun_entry));
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss_entry));
+        idt.segment_not_present
+            .set_handler_addr(addr(segment_not_present_entry));
+        idt.stack_segment_fault
+            .set_handler_addr(addr(stack_segment_fault_entry));
+        idt.general_protection_fault
+            .set_handler_addr(addr(general_protection_fault_entry));
+        idt.page_fault.set_handler_addr(addr(page_fault_entry));
+        // Entry 15 is reserved
+        idt.x87_floating_point
+            .set_handler_addr(addr(x87_floating_point_entry));
+        idt.alignment_check
+            .set_handler_addr(addr(alignment_check_entry));
+        idt.machine_check
+            .set_handler_addr(addr(machine_check_entry))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(addr(simd_floating_point_entry));
+        idt.virtualization
+            .set_handler_addr(addr(virtualization_entry));
+        // Entries 21..28 are reserved
+        idt.vmm_communication_exception
+            .set_handler_addr(addr(vmm_communication_entry));
+        idt.security_exception
+            .set_handler_addr(addr(security_exception_entry));
+        // Entry 31 is reserved
+    }
 
     for i in 32..256 {
         idt[i] = Entry::missing();
@@ -66,7 +112,18 @@ fn init_impl() {
     }
 }
 
+/// Load the IDT on an application processor. All CPUs share it.
+pub fn init_ap() {
+    // SAFETY: as in `init_impl`.
+    unsafe {
+        IDT.lock().load_unsafe();
+    }
+}
+
+/// Install or remove the handler for vector `num`, which must have been claimed
+/// with `vectors::claim` or `vectors::allocate`.
 pub unsafe fn install_interrupt_handler(num: u8, maybe_handler: Option<HandlerFunc>) {
+    assert!(vectors::is_claimed(num), "vector {num} was not claimed");
     without_interrupts(|| {
         let mut idt = IDT.lock();
         if let Some(handler) = maybe_handler {
@@ -77,112 +134,413 @@ pub unsafe fn install_interrupt_handler(num: u8, maybe_handler: Option<HandlerFu
     });
 }
 
-// Default exception handlers
-extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
-    panic!("divide error 0 {:?}", stack_frame);
+/// Point dynamically allocated vector `num` at its entry stub, which calls
+/// `vectors::dispatch`, or remove its entry.
+pub(crate) fn install_dynamic_entry(num: u8, present: bool) {
+    assert!(
+        (vectors::FIRST_DYNAMIC..=vectors::LAST_DYNAMIC).contains(&num),
+        "vector {num} isn't dynamically allocated"
+    );
+    assert!(vectors::is_claimed(num), "vector {num} was not claimed");
+    let stub = addr(dynamic_interrupt_entries)
+        + u64::from(num - vectors::FIRST_DYNAMIC) * DYNAMIC_ENTRY_SIZE;
+    without_interrupts(|| {
+        let mut idt = IDT.lock();
+        if present {
+            // SAFETY: the stub pushes a zero error code and `num`, as
+            // `exception_common` expects.
+            unsafe {
+                idt[num as usize].set_handler_addr(stub);
+            }
+        } else {
+            idt[num as usize] = Entry::missing();
+        }
+    });
 }
 
-extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
-    panic!("debug 1 {:?}", stack_frame);
+fn addr(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
 }
 
-extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
-    panic!("NMI 2 {:?}", stack_frame);
+// Exception entry points. Each pushes its vector, and a zero in place of an
+// error code if the CPU pushes none, so the stack always has the same layout
+// when `exception_common` builds an `ExceptionFrame` on it.
+macro_rules! exception_entry {
+    ($name:ident, $vector:literal) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            unsafe {
+                asm!(
+                    "push 0",
+                    "push {vector}",
+                    "jmp {common}",
+                    vector = const $vector,
+                    common = sym exception_common,
+                    options(noreturn),
+                )
+            }
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            unsafe {
+                asm!(
+                    "push {vector}",
+                    "jmp {common}",
+                    vector = const $vector,
+                    common = sym exception_common,
+                    options(noreturn),
+                )
+            }
+        }
+    };
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    panic!("breakpoint 3 {:?}", stack_frame);
-}
+exception_entry!(divide_error_entry, 0);
+exception_entry!(debug_entry, 1);
+exception_entry!(nmi_entry, 2);
+exception_entry!(breakpoint_entry, 3);
+exception_entry!(overflow_entry, 4);
+exception_entry!(bound_range_exceeded_entry, 5);
+exception_entry!(invalid_opcode_entry, 6);
+exception_entry!(device_not_available_entry, 7);
+exception_entry!(double_fault_entry, 8, error_code);
+exception_entry!(coprocessor_segment_overrun_entry, 9);
+exception_entry!(invalid_tss_entry, 10, error_code);
+exception_entry!(segment_not_present_entry, 11, error_code);
+exception_entry!(stack_segment_fault_entry, 12, error_code);
+exception_entry!(general_protection_fault_entry, 13, error_code);
+exception_entry!(page_fault_entry, 14, error_code);
+exception_entry!(x87_floating_point_entry, 16);
+exception_entry!(alignment_check_entry, 17, error_code);
+exception_entry!(machine_check_entry, 18);
+exception_entry!(simd_floating_point_entry, 19);
+exception_entry!(virtualization_entry, 20);
+exception_entry!(vmm_communication_entry, 29, error_code);
+exception_entry!(security_exception_entry, 30, error_code);
 
-extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
-    panic!("overflow 4 {:?}", stack_frame);
-}
+/// Each entry stub for a dynamically allocated vector is padded to this many
+/// bytes, so the one for a vector is found by its offset in the range.
+const DYNAMIC_ENTRY_SIZE: u64 = 16;
 
-extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
-    panic!("bound range exceeded 5 {:?}", stack_frame);
+extern "C" {
+    // Only meaningful by address.
+    fn dynamic_interrupt_entries();
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    panic!("invalid opcode 6 {:?}", stack_frame);
-}
+// Entry points for `vectors::FIRST_DYNAMIC` to `vectors::LAST_DYNAMIC`, like
+// those above without an error code. Pushing a vector above 127 takes a 32
+// bit immediate, so the largest stub is 12 bytes.
+global_asm!(
+    ".pushsection .text.dynamic_interrupt_entries, \"ax\"",
+    ".balign {size}",
+    ".global dynamic_interrupt_entries",
+    "dynamic_interrupt_entries:",
+    ".set dynamic_vector, {first}",
+    ".rept {count}",
+    ".balign {size}",
+    "pushq $0",
+    "pushq $dynamic_vector",
+    "jmp {common}",
+    ".set dynamic_vector, dynamic_vector + 1",
+    ".endr",
+    ".popsection",
+    size = const DYNAMIC_ENTRY_SIZE,
+    first = const vectors::FIRST_DYNAMIC,
+    count = const vectors::LAST_DYNAMIC - vectors::FIRST_DYNAMIC + 1,
+    common = sym exception_common,
+    options(att_syntax),
+);
 
-extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
-    panic!("device not available 7 {:?}", stack_frame);
+/// Save the general purpose registers below the vector and error code, call
+/// `handle_exception` with them, and return from the exception if it returns.
+#[naked]
+unsafe extern "C" fn exception_common() {
+    unsafe {
+        asm!(
+            // CS is above the vector, error code and RIP. If the exception
+            // came from user mode, swap in the kernel's GS base.
+            "test qword ptr [rsp + 24], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // The CPU aligned the stack to 16 bytes before pushing its 5
+            // words. With the 17 pushed since, it is still aligned.
+            "mov rdi, rsp",
+            "cld",
+            "call {handler}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "test qword ptr [rsp + 24], 3",
+            "jz 3f",
+            "swapgs",
+            "3:",
+            // Drop the vector and error code.
+            "add rsp, 16",
+            "iretq",
+            handler = sym handle_exception,
+            options(noreturn),
+        )
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("double fault 8 {:?}", stack_frame);
+/// The stack `exception_common` passes to `handle_exception`.
+#[repr(C)]
+struct ExceptionFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    /// Zero for exceptions without one.
+    error_code: u64,
+    // Pushed by the CPU.
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
 }
 
-extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!("invalid TSS 10 {} {:?}", error_code, stack_frame);
+impl ExceptionFrame {
+    fn log_registers(&self) {
+        error!(
+            "rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        );
+        error!(
+            "rsi {:016x} rdi {:016x} rbp {:016x} rsp {:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        );
+        error!(
+            "r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        );
+        error!(
+            "r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        );
+        error!(
+            "rip {:016x} cs {:04x} ss {:04x} cr3 {:016x}",
+            self.rip,
+            self.cs,
+            self.ss,
+            arch::regs::page_table_root().as_raw()
+        );
+        error!(
+            "rflags {:016x} {:?}",
+            self.rflags,
+            RFlags::from_bits_truncate(self.rflags)
+        );
+    }
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("segment not present 11 {} {:?}", error_code, stack_frame);
-}
+const NMI: u64 = 2;
+const DOUBLE_FAULT: u64 = 8;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+const X87_FLOATING_POINT: u64 = 16;
+const ALIGNMENT_CHECK: u64 = 17;
+const MACHINE_CHECK: u64 = 18;
+const SIMD_FLOATING_POINT: u64 = 19;
+const VMM_COMMUNICATION: u64 = 29;
+const SECURITY_EXCEPTION: u64 = 30;
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!("stack segment fault 12 {} {:?}", error_code, stack_frame);
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "divide error (#DE)",
+        1 => "debug (#DB)",
+        2 => "NMI",
+        3 => "breakpoint (#BP)",
+        4 => "overflow (#OF)",
+        5 => "bound range exceeded (#BR)",
+        6 => "invalid opcode (#UD)",
+        7 => "device not available (#NM)",
+        DOUBLE_FAULT => "double fault (#DF)",
+        9 => "coprocessor segment overrun",
+        INVALID_TSS => "invalid TSS (#TS)",
+        SEGMENT_NOT_PRESENT => "segment not present (#NP)",
+        STACK_SEGMENT_FAULT => "stack segment fault (#SS)",
+        GENERAL_PROTECTION_FAULT => "general protection fault (#GP)",
+        PAGE_FAULT => "page fault (#PF)",
+        X87_FLOATING_POINT => "x87 floating point (#MF)",
+        ALIGNMENT_CHECK => "alignment check (#AC)",
+        MACHINE_CHECK => "machine check (#MC)",
+        SIMD_FLOATING_POINT => "SIMD floating point (#XM)",
+        20 => "virtualization (#VE)",
+        VMM_COMMUNICATION => "VMM communication (#VC)",
+        SECURITY_EXCEPTION => "security exception (#SX)",
+        _ => "unrecognized exception",
+    }
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!(
-        "general protection fault 13 {} {:?}",
-        error_code, stack_frame
-    );
-}
+extern "C" fn handle_exception(frame: &mut ExceptionFrame) {
+    if frame.vector >= u64::from(vectors::FIRST_CLAIMABLE) {
+        vectors::dispatch(frame.vector as u8);
+        return;
+    }
+    if frame.vector == NMI && mm::tlb::handle_nmi() {
+        return;
+    }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    let cr2 = x86_64::registers::control::Cr2::read_raw();
-    panic!("page fault 14 {:?} {:X} {:?}", error_code, cr2, stack_frame);
-}
+    // Read CR2 before anything else can fault and overwrite it.
+    let fault_address = arch::regs::page_fault_address();
+    let page_fault =
+        (frame.vector == PAGE_FAULT).then(|| PageFault::new(fault_address, frame.error_code));
+    let fatal = match page_fault.as_ref().map(fault::dispatch) {
+        Some(Outcome::Resolved) => return,
+        Some(Outcome::Fatal(reason)) => Some(reason),
+        Some(Outcome::Declined) | None => None,
+    };
+    let name = exception_name(frame.vector);
+    let task = sched::current_task_label();
 
-extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
-    panic!("x87 floating point 16 {:?}", stack_frame);
-}
+    error!(
+        "{name} (vector {}) on CPU {}, task {}",
+        frame.vector,
+        smp::current_cpu(),
+        task
+    );
+    let code = frame.error_code;
+    match frame.vector {
+        PAGE_FAULT => {
+            error!(
+                "error code {:?}",
+                PageFaultErrorCode::from_bits_truncate(code)
+            );
+            fault::report(page_fault.as_ref().unwrap());
+        }
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            error!("error code {code:#x}: {}", SelectorErrorCode(code))
+        }
+        DOUBLE_FAULT | ALIGNMENT_CHECK | VMM_COMMUNICATION | SECURITY_EXCEPTION => {
+            error!("error code {code:#x}")
+        }
+        X87_FLOATING_POINT => {
+            let status: u16;
+            // SAFETY: only reads the x87 status word.
+            unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack)) };
+            error!("x87 status word {status:#06x}");
+        }
+        SIMD_FLOATING_POINT => error!("{:?}", mxcsr::read()),
+        MACHINE_CHECK => {
+            if let Ok(status) = arch::msr::MCG_STATUS.read() {
+                error!("IA32_MCG_STATUS {status:#x}");
+            }
+        }
+        _ => (),
+    }
+    frame.log_registers();
 
-extern "x86-interrupt" fn alignment_check_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("alignment check 17 {:?}", stack_frame);
-}
+    // The other exceptions either don't point at a fetched instruction or
+    // aren't caused by one.
+    let fetched = match frame.vector {
+        0 | 6 | GENERAL_PROTECTION_FAULT => true,
+        PAGE_FAULT => !PageFaultErrorCode::from_bits_truncate(code)
+            .contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+        _ => false,
+    };
+    if fetched {
+        dump_code(VirtAddr::new(frame.rip));
+    }
 
-extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
-    panic!("machine check 18 {:?}", stack_frame);
+    // A kernel stack overflow runs into the guard page below the stack. The
+    // page fault can't be delivered on that stack either, so it usually
+    // becomes a double fault, with CR2 still in the guard page. When it
+    // doesn't, `mm::fault` catches it.
+    let from_kernel = frame.cs & 3 == 0;
+    if frame.vector == DOUBLE_FAULT
+        && from_kernel
+        && mm::kernel_stack::is_stack_guard(fault_address)
+    {
+        panic!("kernel stack overflow in task {task}");
+    }
+    if let Some(reason) = fatal {
+        panic!("{reason} at {fault_address:?} in task {task}");
+    }
+    panic!("{name} at {:#x}", frame.rip);
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
-    panic!("SIMD floating point 19 {:?}", stack_frame);
-}
+/// The error code of an exception caused by loading a segment selector or
+/// delivering an interrupt: which descriptor table entry was involved.
+struct SelectorErrorCode(u64);
 
-extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
-    panic!("virtualization 20 {:?}", stack_frame);
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "no selector");
+        }
+        let table = match (code >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{table} entry {}", (code >> 3) & 0x1fff)?;
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
 }
 
-extern "x86-interrupt" fn security_exception_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("security exception 30 {:?}", stack_frame);
+/// Log the code at `ip` for working out what faulted. `ip` must be the
+/// address of an instruction that was fetched, so that its page is mapped.
+fn dump_code(ip: VirtAddr) {
+    // Stop at the end of the page, since the next one may not be mapped.
+    let len = CODE_DUMP_LEN.min((ip.align_down(4096u64) + 4096u64 - ip) as usize);
+    // SAFETY: the page is mapped, as above, and code isn't written while
+    // it runs.
+    let code = unsafe { core::slice::from_raw_parts(ip.as_ptr::<u8>(), len) };
+    error!("code at {:#x}:", ip.as_u64());
+    for line in HexDump::new(code).with_base(ip.as_u64()).lines() {
+        error!("{line}");
+    }
 }
 
-extern "x86-interrupt" fn unrecognized_exception_handler(stack_frame: InterruptStackFrame) {
-    panic!("unrecognized exception {:?}", stack_frame);
-}
+/// Bytes of code `dump_code` logs: enough for the faulting instruction and
+/// the next few.
+const CODE_DUMP_LEN: usize = 32;
@@ -0,0 +1,51 @@
+//! The initial ramdisk
+//!
+//! GRUB loads a cpio archive (see `shared::cpio`) as the multiboot2 module
+//! with the command line `initrd`. Its memory is kept from the frame
+//! allocator and never freed, so its files are read-only slices that live as
+//! long as the kernel.
+
+use crate::handoff::BootInfo;
+use crate::mm::{self, PhysExtent};
+
+use log::{info, warn};
+use shared::cpio::Archive;
+use shared::fmt::ByteSize;
+
+/// The command line of the module GRUB loads the initrd as.
+const MODULE_CMDLINE: &str = "initrd";
+
+static ARCHIVE: spin::Once<Archive<'static>> = spin::Once::new();
+
+/// The initrd module's memory, if GRUB loaded one. `mm::init` reserves it
+/// with the other modules.
+pub fn find(boot_info: &BootInfo) -> Option<PhysExtent> {
+    Some(boot_info.module(MODULE_CMDLINE)?.extent)
+}
+
+/// Parse the initrd at `extent`, from `find`. Must be called after
+/// `mm::init`. If it is malformed, `open` finds nothing.
+pub fn init(extent: PhysExtent) {
+    let virt = mm::phys_extent_to_virt(extent);
+    // SAFETY: `find`'s extent was reserved in `mm::init`, so nothing else
+    // uses it, and it is never freed or written.
+    let data: &'static [u8] = unsafe { &*virt.as_slice() };
+    let archive = match Archive::new(data) {
+        Ok(archive) => archive,
+        Err(e) => {
+            warn!("initrd is invalid: {e}");
+            return;
+        }
+    };
+
+    info!("initrd at {extent:x?}:");
+    for entry in archive.entries().filter(|e| e.is_file()) {
+        info!("  /{} ({})", entry.path, ByteSize(entry.data.len() as u64));
+    }
+    ARCHIVE.call_once(|| archive);
+}
+
+/// The initrd, if there is a valid one. `fs::init` mounts it.
+pub fn archive() -> Option<&'static Archive<'static>> {
+    ARCHIVE.get()
+}
@@ -0,0 +1,226 @@
+//! Device interrupts
+//!
+//! IRQs are numbered as on the ISA bus and delivered through the IOAPIC when
+//! there is one, or the legacy PICs otherwise. Either way, handlers are
+//! installed with `install_irq_handler`.
+
+use crate::apic;
+use crate::diag;
+use crate::idt::install_interrupt_handler;
+use crate::pic;
+use crate::vectors::{self, Route};
+
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::segmentation::GS;
+use x86_64::structures::idt::InterruptStackFrame;
+
+pub type IrqHandlerFunc = fn(stack: InterruptStackFrame);
+
+// The desired CPU interrupt number for the first IRQ
+pub const IRQ_INTERRUPT_OFFSET: u8 = 32;
+
+pub const NUM_IRQS: u8 = 16;
+
+/// Owner names for the IRQs' vectors.
+const IRQ_NAMES: [&str; NUM_IRQS as usize] = [
+    "irq 0", "irq 1", "irq 2", "irq 3", "irq 4", "irq 5", "irq 6", "irq 7", "irq 8", "irq 9",
+    "irq 10", "irq 11", "irq 12", "irq 13", "irq 14", "irq 15",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Controller {
+    Pic,
+    Apic,
+}
+
+static CONTROLLER: spin::Once<Controller> = spin::Once::new();
+
+/// Set up the interrupt controller, preferring the APIC. All IRQs start
+/// masked. Must be called after `acpi::init`, with interrupts disabled.
+pub unsafe fn init() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    // Both controllers deliver IRQs to the boot CPU.
+    for irq in 0..NUM_IRQS {
+        vectors::claim(
+            IRQ_INTERRUPT_OFFSET + irq,
+            IRQ_NAMES[irq as usize],
+            Route::Cpu(0),
+        )
+        .unwrap();
+    }
+
+    unsafe {
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET, Some(handle_irq0));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 1, Some(handle_irq1));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 2, Some(handle_irq2));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 3, Some(handle_irq3));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 4, Some(handle_irq4));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 5, Some(handle_irq5));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 6, Some(handle_irq6));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 7, Some(handle_irq7));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 8, Some(handle_irq8));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 9, Some(handle_irq9));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 10, Some(handle_irq10));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 11, Some(handle_irq11));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 12, Some(handle_irq12));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 13, Some(handle_irq13));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 14, Some(handle_irq14));
+        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 15, Some(handle_irq15));
+
+        pic::init();
+    }
+
+    let controller = match unsafe { apic::init() } {
+        Ok(()) => {
+            info!("Using the APIC for interrupts");
+            Controller::Apic
+        }
+        Err(e) => {
+            warn!("Can't use the APIC, falling back to the PIC: {e}");
+            Controller::Pic
+        }
+    };
+    CONTROLLER.call_once(|| controller);
+}
+
+fn controller() -> Controller {
+    *CONTROLLER.get().expect("irq::init was not called")
+}
+
+pub fn install_irq_handler(irq_num: u8, maybe_handler: Option<IrqHandlerFunc>) {
+    assert!(irq_num < NUM_IRQS);
+
+    without_interrupts(|| {
+        {
+            let mut handlers = IRQ_HANDLERS.lock();
+            if let Some(handler) = maybe_handler {
+                assert!(handlers[irq_num as usize].is_none());
+                handlers[irq_num as usize] = Some(handler);
+            } else {
+                handlers[irq_num as usize] = None;
+            }
+        }
+
+        let should_mask_irq = maybe_handler.is_none();
+        match controller() {
+            Controller::Pic => pic::set_masked(irq_num, should_mask_irq),
+            Controller::Apic => apic::set_masked(irq_num, should_mask_irq),
+        }
+    });
+}
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandlerFunc>; NUM_IRQS as usize]> =
+    Mutex::new([None; NUM_IRQS as usize]);
+
+// Internal IRQ handlers
+fn handle_irq(irq_num: u8, stack: InterruptStackFrame) {
+    // Interrupts from user mode arrive with the user's GS base, which
+    // `PerCpu` can't use.
+    let from_user = stack.code_segment & 3 == 3;
+    if from_user {
+        unsafe {
+            GS::swap();
+        }
+    }
+
+    without_interrupts(|| {
+        vectors::record(IRQ_INTERRUPT_OFFSET + irq_num);
+
+        let controller = controller();
+        if controller == Controller::Pic && pic::is_spurious(irq_num) {
+            return;
+        }
+
+        // An IRQ without a handler is masked, so it shouldn't arrive. If it
+        // does anyway, acknowledging it is enough to carry on.
+        let handler = IRQ_HANDLERS.lock()[irq_num as usize];
+        match handler {
+            Some(handler) => handler(stack),
+            None => {
+                warn!("IRQ {irq_num} has no handler");
+                diag::snapshot("unhandled IRQ");
+            }
+        }
+
+        match controller {
+            Controller::Pic => pic::acknowledge_irq(irq_num),
+            Controller::Apic => apic::end_of_interrupt(),
+        }
+    });
+
+    if from_user {
+        unsafe {
+            GS::swap();
+        }
+    }
+}
+
+extern "x86-interrupt" fn handle_irq0(stack: InterruptStackFrame) {
+    handle_irq(0, stack);
+}
+
+extern "x86-interrupt" fn handle_irq1(stack: InterruptStackFrame) {
+    handle_irq(1, stack);
+}
+
+extern "x86-interrupt" fn handle_irq2(stack: InterruptStackFrame) {
+    handle_irq(2, stack);
+}
+
+extern "x86-interrupt" fn handle_irq3(stack: InterruptStackFrame) {
+    handle_irq(3, stack);
+}
+
+extern "x86-interrupt" fn handle_irq4(stack: InterruptStackFrame) {
+    handle_irq(4, stack);
+}
+
+extern "x86-interrupt" fn handle_irq5(stack: InterruptStackFrame) {
+    handle_irq(5, stack);
+}
+
+extern "x86-interrupt" fn handle_irq6(stack: InterruptStackFrame) {
+    handle_irq(6, stack);
+}
+
+extern "x86-interrupt" fn handle_irq7(stack: InterruptStackFrame) {
+    handle_irq(7, stack);
+}
+
+extern "x86-interrupt" fn handle_irq8(stack: InterruptStackFrame) {
+    handle_irq(8, stack);
+}
+
+extern "x86-interrupt" fn handle_irq9(stack: InterruptStackFrame) {
+    handle_irq(9, stack);
+}
+
+extern "x86-interrupt" fn handle_irq10(stack: InterruptStackFrame) {
+    handle_irq(10, stack);
+}
+
+extern "x86-interrupt" fn handle_irq11(stack: InterruptStackFrame) {
+    handle_irq(11, stack);
+}
+
+extern "x86-interrupt" fn handle_irq12(stack: InterruptStackFrame) {
+    handle_irq(12, stack);
+}
+
+extern "x86-interrupt" fn handle_irq13(stack: InterruptStackFrame) {
+    handle_irq(13, stack);
+}
+
+extern "x86-interrupt" fn handle_irq14(stack: InterruptStackFrame) {
+    handle_irq(14, stack);
+}
+
+extern "x86-interrupt" fn handle_irq15(stack: InterruptStackFrame) {
+    handle_irq(15, stack);
+}
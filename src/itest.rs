@@ -0,0 +1,489 @@
+//! In-kernel integration tests, run instead of init with `test_mode` on the
+//! command line
+//!
+//! Tests anywhere in the kernel register with `kernel_test!`, which puts them
+//! in the `.itest` linker section. Each runs in `kernel_main`'s thread and
+//! spawns kernel threads of its own. Once all have run, QEMU exits through
+//! the isa-debug-exit device with success if all passed and failure
+//! otherwise, and a panic exits it too. See `cargo ktest`.
+//!
+//! The scheduler tests run mixes of workers for a fixed number of timer ticks
+//! and check how many turns each got. Scheduling is cooperative, so a turn is
+//! one pass through a worker's loop, ending in a yield or sleep.
+//!
+//! The synchronization tests pass data between threads through primitives
+//! that block, and check none of it is lost, duplicated or reordered, or
+//! that a lock held across yields still excludes other threads.
+
+use crate::qemu;
+use crate::sched::{self, Priority, BOOST_INTERVAL_TICKS};
+use crate::shutdown;
+use crate::sync::{self, Mutex, Semaphore};
+use crate::time::timer::Timer;
+use crate::time::TIMER_HZ;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{error, info};
+
+pub type TestResult = Result<(), String>;
+
+/// A test registered with `kernel_test!`.
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn() -> TestResult,
+}
+
+/// Register `$test`, a `fn() -> TestResult`, to run in test mode as `$name`.
+/// Tests run in link order.
+macro_rules! kernel_test {
+    ($name:expr, $test:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".itest"]
+            static TEST: $crate::itest::Test = $crate::itest::Test {
+                name: $name,
+                run: $test,
+            };
+        };
+    };
+}
+pub(crate) use kernel_test;
+
+static TEST_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Have `kernel_main` run the tests instead of init, and panics exit QEMU.
+pub fn enter_test_mode() {
+    TEST_MODE.store(true, Ordering::Relaxed);
+}
+
+pub fn is_test_mode() -> bool {
+    TEST_MODE.load(Ordering::Relaxed)
+}
+
+/// The tests in the `.itest` section.
+fn tests() -> &'static [Test] {
+    // SAFETY: the linker script puts the `.itest` sections between these
+    // symbols, and `kernel_test!` only puts `Test`s there, which are all the
+    // same size and aligned alike, so they form an array.
+    unsafe {
+        let begin = core::ptr::addr_of!(internal::ITESTS_BEGIN_SYM).cast::<Test>();
+        let end = core::ptr::addr_of!(internal::ITESTS_END_SYM).cast::<Test>();
+        core::slice::from_raw_parts(begin, end.offset_from(begin) as usize)
+    }
+}
+
+mod internal {
+    extern "C" {
+        #![allow(improper_ctypes)]
+        // These may not be dereferenced. Only their address is meaningful.
+        pub static ITESTS_BEGIN_SYM: ();
+        pub static ITESTS_END_SYM: ();
+    }
+}
+
+/// How long each scheduler test measures for.
+const MEASURE_TICKS: u64 = TIMER_HZ;
+
+/// Spin iterations between a busy worker's yields.
+const BUSY_SPINS: u64 = 100_000;
+
+/// Run the tests, then exit QEMU with whether they all passed.
+pub fn run() -> ! {
+    // Run above the workers, so we get the CPU back as soon as we wake.
+    let task = sched::current_task();
+    unsafe { sched::set_priority(task, Priority::Highest) };
+
+    let tests = tests();
+    let mut failures = 0;
+    for test in tests {
+        match (test.run)() {
+            Ok(()) => info!("PASS: {}", test.name),
+            Err(e) => {
+                error!("FAIL: {}: {e}", test.name);
+                failures += 1;
+            }
+        }
+    }
+
+    let code = if failures > 0 {
+        error!("{failures} of {} in-kernel tests failed", tests.len());
+        qemu::ExitCode::Failure
+    } else {
+        info!("all {} in-kernel tests passed", tests.len());
+        qemu::ExitCode::Success
+    };
+    shutdown::shutdown(shutdown::Action::ExitQemu(code));
+}
+
+kernel_test!("sched: equal tasks share turns", equal_tasks_share_turns);
+fn equal_tasks_share_turns() -> TestResult {
+    // `Highest` is where this thread runs, so it isn't tested.
+    for priority in [Priority::Low, Priority::Normal, Priority::High] {
+        let turns = run_workers(&[(Behavior::Yield, priority); 4]);
+        check_fair(&turns).map_err(|e| format!("at {priority:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+kernel_test!(
+    "sched: busy tasks don't starve yielding ones",
+    busy_and_yielding_tasks_share_turns
+);
+fn busy_and_yielding_tasks_share_turns() -> TestResult {
+    let turns = run_workers(&[
+        (Behavior::Busy, Priority::Normal),
+        (Behavior::Yield, Priority::Normal),
+        (Behavior::Busy, Priority::Normal),
+        (Behavior::Yield, Priority::Normal),
+    ]);
+    check_fair(&turns)
+}
+
+kernel_test!(
+    "sched: boosting keeps low priority tasks running",
+    low_priority_tasks_run
+);
+fn low_priority_tasks_run() -> TestResult {
+    let turns = run_workers(&[
+        (Behavior::Yield, Priority::High),
+        (Behavior::Yield, Priority::Low),
+    ]);
+    let (high, low) = (turns[0], turns[1]);
+    // The low priority worker runs at least once per boost. Allow for the
+    // boosts at the start and end being missed.
+    let boosts = MEASURE_TICKS / BOOST_INTERVAL_TICKS;
+    if low < boosts.saturating_sub(2) {
+        return Err(format!(
+            "low priority worker ran {low} times in {boosts} boosts"
+        ));
+    }
+    if high <= low {
+        return Err(format!("high priority worker ran {high} times, low {low}"));
+    }
+    Ok(())
+}
+
+kernel_test!("sched: sleeping tasks wake on time", sleeping_tasks_wake);
+fn sleeping_tasks_wake() -> TestResult {
+    let turns = run_workers(&[
+        (Behavior::Sleep, Priority::Normal),
+        (Behavior::Busy, Priority::Normal),
+        (Behavior::Busy, Priority::Normal),
+    ]);
+    // Each turn sleeps for a tick, and waits behind at most the two busy
+    // workers' turns once it wakes.
+    let sleeper = turns[0];
+    if sleeper < MEASURE_TICKS / 4 {
+        return Err(format!(
+            "sleeping worker woke {sleeper} times in {MEASURE_TICKS} ticks"
+        ));
+    }
+    Ok(())
+}
+
+kernel_test!(
+    "time: timers fire when due until cancelled",
+    timers_fire_until_cancelled
+);
+fn timers_fire_until_cancelled() -> TestResult {
+    static FIRED: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+    fn fire(context: usize) {
+        FIRED[context].fetch_add(1, Ordering::SeqCst);
+    }
+
+    let tick = Duration::from_nanos(1_000_000_000 / TIMER_HZ);
+    let oneshot = Timer::oneshot(tick * 2, fire, 0);
+    let periodic = Timer::periodic(tick, fire, 1);
+    let cancelled = Timer::oneshot(tick * 2, fire, 2);
+    if !cancelled.cancel() {
+        return Err("cancelling an armed timer failed".into());
+    }
+    sched::sleep_ticks(5);
+    if !periodic.cancel() {
+        return Err("cancelling a periodic timer failed".into());
+    }
+    if oneshot.cancel() {
+        return Err("an expired one-shot timer was still armed".into());
+    }
+
+    let fired = FIRED.each_ref().map(|f| f.load(Ordering::SeqCst));
+    // A tick may pass between arming the periodic timer and sleeping.
+    if fired[0] != 1 || !(4..=6).contains(&fired[1]) || fired[2] != 0 {
+        return Err(format!("timers fired {fired:?} times"));
+    }
+    Ok(())
+}
+
+/// Fail unless every worker got within 10% of the most turns any got.
+fn check_fair(turns: &[u64]) -> TestResult {
+    let max = turns.iter().copied().max().unwrap_or(0);
+    let min = turns.iter().copied().min().unwrap_or(0);
+    if max == 0 || min * 10 < max * 9 {
+        return Err(format!("unfair turns {turns:?}"));
+    }
+    Ok(())
+}
+
+/// What a worker does each turn.
+#[derive(Clone, Copy, Debug)]
+enum Behavior {
+    /// Yield straight away.
+    Yield,
+    /// Spin for `BUSY_SPINS` iterations, then yield.
+    Busy,
+    /// Sleep for a tick.
+    Sleep,
+}
+
+struct Worker {
+    behavior: Behavior,
+    turns: AtomicU64,
+}
+
+/// Tells the workers to quit.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Workers that haven't quit yet.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Run a worker for each of `workers` for `MEASURE_TICKS`, and return how many
+/// turns each got. Returns once all of them have quit.
+fn run_workers(workers: &[(Behavior, Priority)]) -> Vec<u64> {
+    let workers: Vec<(Box<Worker>, Priority)> = workers
+        .iter()
+        .map(|&(behavior, priority)| {
+            let worker = Box::new(Worker {
+                behavior,
+                turns: AtomicU64::new(0),
+            });
+            (worker, priority)
+        })
+        .collect();
+
+    STOP.store(false, Ordering::SeqCst);
+    RUNNING.store(workers.len(), Ordering::SeqCst);
+    for (worker, priority) in &workers {
+        let context = &**worker as *const Worker as usize;
+        sched::spawn_kthread_with_priority(worker_thread, context, *priority);
+    }
+
+    sched::sleep_ticks(MEASURE_TICKS);
+    // Nothing else runs until we sleep again, so this is a consistent
+    // snapshot.
+    let turns = workers
+        .iter()
+        .map(|(worker, _)| worker.turns.load(Ordering::SeqCst))
+        .collect();
+
+    STOP.store(true, Ordering::SeqCst);
+    while RUNNING.load(Ordering::SeqCst) > 0 {
+        sched::sleep_ticks(1);
+    }
+    turns
+}
+
+extern "C" fn worker_thread(context: usize) -> ! {
+    // SAFETY: `run_workers` keeps the worker alive until we quit.
+    let worker = unsafe { &*(context as *const Worker) };
+    while !STOP.load(Ordering::SeqCst) {
+        match worker.behavior {
+            Behavior::Yield => sched::yield_current(),
+            Behavior::Busy => {
+                for _ in 0..BUSY_SPINS {
+                    core::hint::spin_loop();
+                }
+                sched::yield_current();
+            }
+            Behavior::Sleep => sched::sleep_ticks(1),
+        }
+        worker.turns.fetch_add(1, Ordering::SeqCst);
+    }
+    RUNNING.fetch_sub(1, Ordering::SeqCst);
+    sched::quit_current();
+}
+
+/// Items `producer_consumer` passes through its buffer.
+const ITEMS: u64 = 1000;
+
+/// Slots in its buffer, few enough that the producer often fills it.
+const BUFFER_SLOTS: usize = 4;
+
+/// A bounded buffer guarded by the classic pair of semaphores.
+struct BoundedBuffer {
+    items: spin::Mutex<VecDeque<u64>>,
+    /// Counts free slots.
+    free: Semaphore,
+    /// Counts filled slots.
+    filled: Semaphore,
+    /// Released by each thread once it is done.
+    done: Semaphore,
+    /// The most items the buffer ever held.
+    max_len: AtomicUsize,
+    /// What the consumer got, in order.
+    received: spin::Mutex<Vec<u64>>,
+}
+
+kernel_test!(
+    "sync: semaphores pass items from producer to consumer",
+    producer_consumer
+);
+fn producer_consumer() -> TestResult {
+    let buffer = Box::new(BoundedBuffer {
+        items: spin::Mutex::new(VecDeque::new()),
+        free: Semaphore::new(BUFFER_SLOTS),
+        filled: Semaphore::new(0),
+        done: Semaphore::new(0),
+        max_len: AtomicUsize::new(0),
+        received: spin::Mutex::new(Vec::new()),
+    });
+    let context = &*buffer as *const BoundedBuffer as usize;
+    sched::spawn_kthread(producer_thread, context);
+    sched::spawn_kthread(consumer_thread, context);
+    buffer.done.acquire();
+    buffer.done.acquire();
+
+    let max_len = buffer.max_len.load(Ordering::SeqCst);
+    if max_len > BUFFER_SLOTS {
+        return Err(format!("buffer held {max_len} items"));
+    }
+    let received = buffer.received.lock();
+    if !received.iter().copied().eq(0..ITEMS) {
+        return Err(format!(
+            "consumer got {} items, expected 0 to {}",
+            received.len(),
+            ITEMS - 1
+        ));
+    }
+    Ok(())
+}
+
+extern "C" fn producer_thread(context: usize) -> ! {
+    // SAFETY: `producer_consumer` keeps the buffer alive until we're done.
+    let buffer = unsafe { &*(context as *const BoundedBuffer) };
+    for item in 0..ITEMS {
+        buffer.free.acquire();
+        let len = {
+            let mut items = buffer.items.lock();
+            items.push_back(item);
+            items.len()
+        };
+        buffer.max_len.fetch_max(len, Ordering::SeqCst);
+        buffer.filled.release();
+        // Give the consumer a chance to run before the buffer is full.
+        if item % 3 == 0 {
+            sched::yield_current();
+        }
+    }
+    buffer.done.release();
+    sched::quit_current();
+}
+
+extern "C" fn consumer_thread(context: usize) -> ! {
+    // SAFETY: as in `producer_thread`.
+    let buffer = unsafe { &*(context as *const BoundedBuffer) };
+    for _ in 0..ITEMS {
+        buffer.filled.acquire();
+        let item = buffer.items.lock().pop_front().unwrap();
+        buffer.free.release();
+        buffer.received.lock().push(item);
+    }
+    buffer.done.release();
+    sched::quit_current();
+}
+
+/// Threads `mutex_excludes` runs.
+const MUTEX_THREADS: usize = 4;
+
+/// Times each of them increments the counter.
+const MUTEX_ROUNDS: u64 = 100;
+
+struct Contended {
+    counter: Mutex<u64>,
+    /// Tasks between locking and unlocking `counter`.
+    holders: AtomicUsize,
+    /// The most there ever were.
+    max_holders: AtomicUsize,
+    done: Semaphore,
+}
+
+kernel_test!(
+    "sync: mutexes exclude tasks that yield while holding them",
+    mutex_excludes
+);
+fn mutex_excludes() -> TestResult {
+    let contended = Box::new(Contended {
+        counter: Mutex::new(0),
+        holders: AtomicUsize::new(0),
+        max_holders: AtomicUsize::new(0),
+        done: Semaphore::new(0),
+    });
+    let context = &*contended as *const Contended as usize;
+    for _ in 0..MUTEX_THREADS {
+        sched::spawn_kthread(mutex_thread, context);
+    }
+    for _ in 0..MUTEX_THREADS {
+        contended.done.acquire();
+    }
+
+    let max_holders = contended.max_holders.load(Ordering::SeqCst);
+    if max_holders != 1 {
+        return Err(format!("{max_holders} tasks held the mutex at once"));
+    }
+    let counter = *contended.counter.lock();
+    let expected = MUTEX_THREADS as u64 * MUTEX_ROUNDS;
+    if counter != expected {
+        return Err(format!("counter is {counter}, expected {expected}"));
+    }
+    Ok(())
+}
+
+extern "C" fn mutex_thread(context: usize) -> ! {
+    // SAFETY: `mutex_excludes` keeps the state alive until we're done.
+    let contended = unsafe { &*(context as *const Contended) };
+    for _ in 0..MUTEX_ROUNDS {
+        let mut counter = contended.counter.lock();
+        let holders = contended.holders.fetch_add(1, Ordering::SeqCst) + 1;
+        contended.max_holders.fetch_max(holders, Ordering::SeqCst);
+        let value = *counter;
+        // Let the other threads run, and block on the mutex.
+        sched::yield_current();
+        *counter = value + 1;
+        contended.holders.fetch_sub(1, Ordering::SeqCst);
+    }
+    contended.done.release();
+    sched::quit_current();
+}
+
+kernel_test!(
+    "sync: deferred frees happen after task switches",
+    deferred_free_runs
+);
+fn deferred_free_runs() -> TestResult {
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct SetOnDrop;
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    sync::defer_free(SetOnDrop);
+    if DROPPED.load(Ordering::SeqCst) {
+        return Err("dropped before any task switch".into());
+    }
+    // The epoch advances at most once per switch, and it takes two.
+    for _ in 0..3 {
+        sched::yield_current();
+    }
+    if !DROPPED.load(Ordering::SeqCst) {
+        return Err("not dropped after three task switches".into());
+    }
+    Ok(())
+}
@@ -6,16 +6,15 @@ use core::fmt::Write;
 use core::panic::PanicInfo;
 
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::{error, info, warn};
 use multiboot2 as mb2;
+use shared::elf64::Elf;
 use x86_64::instructions::interrupts;
 use x86_64::structures::idt::InterruptStackFrame;
 
-const VMEM: *mut u8 = 0xB8000 as *mut u8;
-
 #[no_mangle]
 pub extern "C" fn kernel_entry(mbinfo_addr: u64) -> ! {
-    init_logger();
+    log_ctl::init();
 
     info!("Multiboot info: {mbinfo_addr:X}");
     info!("{:X?}", *MB2_HEADER);
@@ -29,34 +28,105 @@ pub extern "C" fn kernel_entry(mbinfo_addr: u64) -> ! {
 
     info!("In kernel");
 
-    gdt::init();
+    arch::gdt::init();
+    smp::init_bsp();
     info!("Set up GDT");
 
+    arch::quirks::init();
+    arch::fpu::init();
+
     idt::init();
     info!("Set up IDT");
 
-    let init_module = mbinfo.module_tags().next().unwrap();
-    let init_extent = mm::PhysExtent::from_raw_range_exclusive(
-        init_module.start_address().into(),
-        init_module.end_address().into(),
-    );
+    syscall::init().unwrap_or_else(|e| panic!("can't enable system calls: {e}"));
+    info!("Set up syscall entry");
+
+    time::init();
+    info!("Set up timer");
+
+    let boot_info = handoff::BootInfo::from_multiboot(&mbinfo);
+    let init_module = boot_info
+        .modules
+        .first()
+        .expect("GRUB loaded no init module");
+    let init_extent = init_module.extent;
 
     info!("init_extent = {init_extent:?}");
 
-    mm::init(&mbinfo, core::iter::once(init_extent));
+    // GRUB passes the rest of the `module2` line, which becomes init's
+    // arguments.
+    let init_cmdline = init_module.cmdline;
+    info!("init command line: {init_cmdline:?}");
+
+    let cmdline = mbinfo
+        .command_line_tag()
+        .and_then(|tag| tag.cmdline().ok())
+        .unwrap_or("");
+    info!("Command line: {cmdline:?}");
+    log_ctl::apply_options(&shared::cmdline::LogOptions::parse(cmdline));
+    let memory_options = shared::cmdline::MemoryOptions::parse(cmdline);
+    panic_action::set(shared::cmdline::PanicOptions::parse(cmdline).action);
+    boot_slot::set(shared::cmdline::BootSlotOptions::parse(cmdline).slot);
+    if shared::cmdline::TestOptions::parse(cmdline).test_mode {
+        itest::enter_test_mode();
+        info!("Test mode: running the in-kernel tests instead of init");
+    }
+    if let Some(secs) = shared::cmdline::WatchdogOptions::parse(cmdline).timeout_secs {
+        watchdog::set_timeout_secs(secs);
+        match secs {
+            0 => info!("Lockup watchdog off"),
+            _ => info!("Lockup watchdog timeout: {secs} s"),
+        }
+    }
+    if let Some(len) = shared::cmdline::HeapOptions::parse(cmdline).quarantine {
+        mm::set_heap_quarantine_len(len);
+        info!("Quarantining the last {len} freed heap blocks");
+    }
+
+    let initrd_extent = initrd::find(&boot_info);
+    mm::init(&mbinfo, &boot_info, &memory_options);
     info!("Initialized frame allocator");
+    boot_metrics::phase("phase.mm");
+
+    acpi::init(&mbinfo);
+    time::init_hpet();
+    boot_metrics::phase("phase.acpi");
+
+    match initrd_extent {
+        Some(extent) => initrd::init(extent),
+        None => info!("No initrd"),
+    }
+
+    #[cfg(feature = "framebuffer")]
+    match drivers::framebuffer::init(&mbinfo) {
+        Ok((rows, cols)) => info!("Set up {cols}x{rows} framebuffer console"),
+        Err(e) => warn!("Can't use the framebuffer: {e}"),
+    }
+    #[cfg(not(feature = "framebuffer"))]
+    drivers::vga::init();
+    boot_metrics::phase("phase.console");
+
+    // Boot-time page table setup is done.
+    mm::protect::init();
+    boot_metrics::phase("phase.protect");
 
     let init_extent = phys_extent_to_virt(init_extent);
-    let init_elf = xmas_elf::ElfFile::new(unsafe { &*init_extent.as_slice() }).unwrap();
+    let init_elf = Elf::parse(unsafe { &*init_extent.as_slice() })
+        .unwrap_or_else(|e| panic!("init is not a valid executable: {e}"));
 
     info!("init sections:");
-    for section in init_elf
-        .section_iter()
-        .flat_map(|s| s.get_name(&init_elf).ok())
-    {
+    for section in init_elf.sections().flat_map(|s| init_elf.section_name(&s)) {
         info!("  {}", section);
     }
 
+    *INIT_MODULE.lock() = Some(InitModule {
+        image: init_extent,
+        cmdline: init_cmdline.into(),
+        disk_path: shared::cmdline::InitOptions::parse(cmdline)
+            .path
+            .map(Into::into),
+    });
+
     unsafe {
         sched::init_kernel_main_thread(kernel_main);
     }
@@ -69,12 +139,27 @@ pub fn kernel_main() -> ! {
     sched::yield_current();
 
     unsafe {
-        pic::init();
+        irq::init();
         interrupts::enable();
     }
-    info!("Set up PIC");
+    info!("Set up interrupt controller");
+    boot_metrics::phase("phase.irq");
+
+    match drivers::serial::init() {
+        Ok(()) => info!("Set up serial port"),
+        Err(e) => warn!("Can't use the serial port: {e}"),
+    }
+
+    smp::init();
+    boot_metrics::phase("phase.smp");
+
+    drivers::block::init();
+    fs::init();
 
-    pic::install_irq_handler(1, Some(keyboard_handler));
+    irq::install_irq_handler(0, Some(timer_handler));
+    irq::install_irq_handler(1, Some(keyboard_handler));
+    sched::spawn_kthread_with_priority(keyboard_thread, 0, sched::Priority::High);
+    sched::spawn_kthread_with_priority(mm::report_stats_thread, 0, sched::Priority::Low);
 
     sched::spawn_kthread(test_thread, 0);
     info!("kernel_main yield");
@@ -92,18 +177,92 @@ pub fn kernel_main() -> ! {
 
     info!("{string}");
 
-    halt_loop();
+    if itest::is_test_mode() {
+        itest::run();
+    }
+
+    sched::spawn_kthread_named("init", init_thread, 0);
+    sched::quit_current();
+}
+
+struct InitModule {
+    /// The executable, mapped in kernel space.
+    image: mm::VirtExtent,
+
+    /// Whitespace separated arguments, starting with the program name.
+    cmdline: alloc::string::String,
+
+    /// Where on disk to load init from instead, from `init=`.
+    disk_path: Option<alloc::string::String>,
+}
+
+/// Taken by `init_thread`.
+static INIT_MODULE: spin::Mutex<Option<InitModule>> = spin::Mutex::new(None);
+
+extern "C" fn init_thread(_context: usize) -> ! {
+    let init_module = INIT_MODULE.lock().take().unwrap();
+    let from_disk = init_module.disk_path.as_deref().map(|path| {
+        info!("Loading init from {path}");
+        fs::open(path)
+            .and_then(|file| file.read_all())
+            .unwrap_or_else(|e| panic!("can't read init from {path}: {e}"))
+    });
+    let init_elf = match &from_disk {
+        Some(image) => {
+            Elf::parse(image).unwrap_or_else(|e| panic!("init is not a valid executable: {e}"))
+        }
+        // Validated in `kernel_main`.
+        None => Elf::parse(unsafe { &*init_module.image.as_slice() }).unwrap(),
+    };
+    let args: alloc::vec::Vec<&str> = init_module.cmdline.split_whitespace().collect();
+    let init_process = process::Process::from_elf(&init_elf, &args)
+        .unwrap_or_else(|e| panic!("failed to load init: {e}"));
+    boot_slot::confirm();
+    boot_metrics::report_userspace_entry();
+    init_process.run();
 }
 
 pub extern "C" fn test_thread(_context: usize) -> ! {
     info!("Test thread before yield");
     sched::yield_current();
     info!("Test thread after yield");
+    let start = time::now();
+    sched::sleep_ticks(time::TIMER_HZ / 10);
+    info!(
+        "Test thread slept for {:?}, uptime {:?}",
+        start.elapsed(),
+        time::uptime()
+    );
     sched::quit_current();
 }
 
+fn timer_handler(_: InterruptStackFrame) {
+    time::timer_interrupt();
+    sched::timer_tick();
+    watchdog::timer_tick();
+}
+
+/// The last scancode received. Only the most recent one is kept.
+static SCANCODE: spin::Mutex<Option<u8>> = spin::Mutex::new(None);
+
+static KEYBOARD_WAIT: sched::WaitQueue = sched::WaitQueue::new();
+
 fn keyboard_handler(_: InterruptStackFrame) {
-    panic!("keyboard interrupt received");
+    // The controller won't raise another interrupt until the data is read.
+    let scancode = unsafe { x86_64::instructions::port::PortReadOnly::<u8>::new(0x60).read() };
+    *SCANCODE.lock() = Some(scancode);
+    KEYBOARD_WAIT.wake_one();
+}
+
+extern "C" fn keyboard_thread(_context: usize) -> ! {
+    loop {
+        let mut scancode = None;
+        KEYBOARD_WAIT.wait_until(|| {
+            scancode = SCANCODE.lock().take();
+            scancode.is_some()
+        });
+        info!("Scancode {:#04x}", scancode.unwrap());
+    }
 }
 
 extern "C" {
@@ -129,46 +288,40 @@ lazy_static! {
     };
 }
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "qemu_debugcon")] {
-        use shared::log::{LogTee, LogSink, QemuDebugWriter};
-        use shared::vga::VgaWriter;
-        lazy_static! {
-            static ref LOGGER: LogTee<LogSink<QemuDebugWriter>, LogSink<VgaWriter>> = unsafe { LogTee(LogSink::new(QemuDebugWriter::new()), LogSink::new(VgaWriter::new(VMEM))) };
-        }
-    } else {
-        use shared::log::LogSink;
-        use shared::vga::VgaWriter;
-        lazy_static! {
-            static ref LOGGER: LogSink<VgaWriter> = unsafe { LogSink::new(VgaWriter::new(VMEM)) };
-        }
-    }
-}
-
-fn init_logger() {
-    log::set_logger(&*LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Info);
-}
-
 #[panic_handler]
 fn panic(info: &PanicInfo<'_>) -> ! {
-    use shared::log::LogExt;
+    interrupts::disable();
 
-    // It is unlikely that we panicked while our LOGGER instance was locked, and
-    // if we were, we'll likely triple fault anyway. Try to use the existing
-    // LOGGER, and otherwise try to use a new VgaWriter.
-    if !LOGGER.is_locked() {
+    // Always write the panic to the emergency console first. It works even if
+    // the panic happened before the logger was set up or while it was in use.
+    let mut emergency =
+        unsafe { shared::log::EmergencyWriter::new(cfg!(feature = "qemu_debugcon")) };
+    let _ = writeln!(
+        &mut emergency,
+        "PANIC in task {}: {info}",
+        sched::current_task_label()
+    );
+    trace::dump(&mut emergency);
+    let quarantine = mm::heap_quarantine_stats();
+    if quarantine != Default::default() {
+        let _ = writeln!(&mut emergency, "heap quarantine: {quarantine:?}");
+    }
+    let _ = vectors::dump(&mut emergency);
+    let _ = writeln!(&mut emergency, "recent log records, newest first:");
+    let _ = log_ctl::dump_history(&mut emergency);
+
+    // It is unlikely that we panicked while the logger was locked, and if we
+    // were, we'll likely triple fault anyway. Try to use the existing logger,
+    // and otherwise try to use a new VgaWriter if the text buffer is mapped.
+    if !log_ctl::is_locked() {
         error!("{info}");
-    } else {
-        #[cfg(feature = "qemu_debugcon")]
-        {
-            let mut writer = unsafe { shared::log::QemuDebugWriter::new() };
-            let _ = write!(&mut writer, "{info}");
-        }
-
-        let mut writer = unsafe { shared::vga::VgaWriter::new(VMEM) };
+    } else if let Some(vmem) = platform::mapped_vga_text_buffer() {
+        let mut writer = unsafe { shared::vga::VgaWriter::new(vmem) };
         let _ = write!(&mut writer, "{info}");
     }
-    interrupts::disable();
-    halt_loop();
+
+    if cfg!(feature = "qemu_exit") || itest::is_test_mode() {
+        qemu::exit(qemu::ExitCode::Panic);
+    }
+    panic_action::run(&mut emergency);
 }
@@ -0,0 +1,167 @@
+//! The kernel logger and its levels
+//!
+//! Records are filtered by target before they reach the log ring. Drivers log
+//! to their own named targets, and each kernel module in `TARGETS` can have its
+//! level set too, covering everything it and its submodules log without an
+//! explicit target. Levels start at `info` and are set from the command line
+//! with `log=warn,mm=debug` or `log.mm=debug`, or at runtime with `set_level`.
+
+use crate::drivers;
+use crate::sched;
+
+use lazy_static::lazy_static;
+use log::{warn, LevelFilter};
+use shared::cmdline::LogOptions;
+use shared::log::{
+    ContextLogger, LogExt, LogTee, RingBufferSink, RingLogSink, Target, TargetFilter, UnknownTarget,
+};
+
+// Nothing is drawn on the screen until it is mapped, partway through
+// `kernel_entry`.
+cfg_if::cfg_if! {
+    if #[cfg(feature = "framebuffer")] {
+        use drivers::framebuffer::FramebufferWriter;
+        type ScreenWriter = FramebufferWriter;
+        unsafe fn screen_writer() -> ScreenWriter {
+            FramebufferWriter
+        }
+    } else {
+        use drivers::vga::VgaConsoleWriter;
+        type ScreenWriter = VgaConsoleWriter;
+        unsafe fn screen_writer() -> ScreenWriter {
+            VgaConsoleWriter
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "qemu_debugcon")] {
+        use shared::io::{Debugcon, LineWriter};
+        use shared::log::WriteTee;
+        type ConsoleWriter = WriteTee<LineWriter<Debugcon>, ScreenWriter>;
+        unsafe fn console_writer() -> ConsoleWriter {
+            unsafe { WriteTee(LineWriter::new(Debugcon::new()), screen_writer()) }
+        }
+    } else {
+        type ConsoleWriter = ScreenWriter;
+        unsafe fn console_writer() -> ConsoleWriter {
+            unsafe { screen_writer() }
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "serial_log")] {
+        type LogWriter = shared::log::WriteTee<ConsoleWriter, shared::io::LineWriter<drivers::serial::Com1>>;
+        unsafe fn log_writer() -> LogWriter {
+            shared::log::WriteTee(unsafe { console_writer() }, shared::io::LineWriter::new(drivers::serial::Com1))
+        }
+    } else {
+        type LogWriter = ConsoleWriter;
+        unsafe fn log_writer() -> LogWriter {
+            unsafe { console_writer() }
+        }
+    }
+}
+
+static ACPI: Target = Target::module("acpi", "kernel::acpi");
+static APIC: Target = Target::module("apic", "kernel::apic");
+static DIAG: Target = Target::module("diag", "kernel::diag");
+static FS: Target = Target::module("fs", "kernel::fs");
+static GDT: Target = Target::module("gdt", "kernel::gdt");
+static IDT: Target = Target::module("idt", "kernel::idt");
+static INITRD: Target = Target::module("initrd", "kernel::initrd");
+static IRQ: Target = Target::module("irq", "kernel::irq");
+static KMAIN: Target = Target::module("kmain", "kernel::kmain");
+static MM: Target = Target::module("mm", "kernel::mm");
+static PROCESS: Target = Target::module("process", "kernel::process");
+static SCHED: Target = Target::module("sched", "kernel::sched");
+static SMP: Target = Target::module("smp", "kernel::smp");
+static SYSCALL: Target = Target::module("syscall", "kernel::syscall");
+static TIME: Target = Target::module("time", "kernel::time");
+
+static TARGETS: &[&Target] = &[
+    &ACPI,
+    &APIC,
+    &DIAG,
+    &FS,
+    &GDT,
+    &IDT,
+    &INITRD,
+    &IRQ,
+    &KMAIN,
+    &MM,
+    &PROCESS,
+    &SCHED,
+    &SMP,
+    &SYSCALL,
+    &TIME,
+    &drivers::block::LOG,
+    #[cfg(feature = "framebuffer")]
+    &drivers::framebuffer::LOG,
+    &drivers::serial::LOG,
+];
+
+/// How much recent log output `dump_history` can show.
+const HISTORY_SIZE: usize = 16 << 10;
+
+static HISTORY: RingBufferSink<HISTORY_SIZE> = RingBufferSink::new();
+
+// Records are kept in `HISTORY` and go through a lock-free ring, so logging is
+// safe from interrupt handlers and while another task is writing out the log.
+// Messages logged by a named task start with its name.
+type Logger = TargetFilter<
+    ContextLogger<LogTee<&'static RingBufferSink<HISTORY_SIZE>, RingLogSink<LogWriter>>>,
+>;
+
+lazy_static! {
+    static ref LOGGER: Logger = TargetFilter::new(
+        ContextLogger::new(
+            LogTee(&HISTORY, RingLogSink::new(unsafe { log_writer() })),
+            sched::current_task_name
+        ),
+        TARGETS,
+        LevelFilter::Info
+    );
+}
+
+/// Install the logger. Must be called once, before anything logs.
+pub fn init() {
+    log::set_logger(&*LOGGER).unwrap();
+    log::set_max_level(LOGGER.max_level());
+}
+
+/// Set the level of the log target called `target`, or the default level for
+/// targets without their own if `None`.
+pub fn set_level(target: Option<&str>, level: LevelFilter) -> Result<(), UnknownTarget> {
+    match target {
+        None => LOGGER.set_default_level(level),
+        Some(target) => LOGGER.set_level(target, Some(level))?,
+    }
+    log::set_max_level(LOGGER.max_level());
+    Ok(())
+}
+
+/// Set the levels from the command line.
+pub fn apply_options(options: &LogOptions) {
+    if let Some(level) = options.default {
+        set_level(None, level).unwrap();
+    }
+    for &(target, level) in options.targets.iter() {
+        if let Err(e) = set_level(Some(target), level) {
+            warn!("ignoring log level {target}={level}: {e}");
+        }
+    }
+}
+
+/// Write the most recent log records to `writer`, newest first. Records below
+/// their target's level aren't kept.
+pub fn dump_history(writer: &mut impl core::fmt::Write) -> core::fmt::Result {
+    HISTORY.dump(writer)
+}
+
+/// Whether logging would deadlock, e.g. because we panicked while writing out
+/// the log.
+pub fn is_locked() -> bool {
+    LOGGER.is_locked()
+}
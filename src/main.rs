@@ -6,12 +6,36 @@
 
 extern crate alloc;
 
-mod gdt;
+mod acpi;
+mod apic;
+mod arch;
+mod boot_metrics;
+mod boot_slot;
+mod diag;
+mod drivers;
+mod fs;
+mod handoff;
 mod idt;
+mod initrd;
+mod irq;
+mod itest;
 mod kmain;
+mod log_ctl;
 mod mm;
+mod panic_action;
 mod pic;
+mod platform;
+mod process;
+mod qemu;
 mod sched;
+mod shutdown;
+mod smp;
+mod sync;
+mod syscall;
+mod time;
+mod trace;
+mod vectors;
+mod watchdog;
 
 fn halt_loop() -> ! {
     loop {
@@ -1,18 +1,46 @@
 //! Kernel memory management
 
+pub mod address_space;
+mod compaction;
+pub mod dma;
+pub mod executable;
+pub mod fault;
+pub mod kernel_heap;
+pub mod kernel_stack;
+mod map_check;
+pub mod mmio;
 pub mod paging;
-
-pub use shared::memory::addr::*;
-pub use shared::memory::page::*;
-
-use shared::memory::alloc::*;
-use shared::memory::*;
-
+pub mod protect;
+mod rmap;
+pub mod shared_frame;
+pub mod tlb;
+
+pub use address_space::{is_user_accessible, AddressSpace};
+pub use kernel_stack::{alloc_kernel_stack, KernelStack};
+pub use mmio::VolatileRegion;
+pub use shared_frame::SharedFrame;
+
+pub use testos_memory::addr::*;
+pub use testos_memory::alloc::Zone;
+pub use testos_memory::page::*;
+
+use testos_memory::alloc::*;
+use testos_memory::*;
+
+use crate::arch::msr::Feature;
+use crate::arch::regs;
+use crate::handoff::BootInfo;
+use crate::itest::{kernel_test, TestResult};
+use crate::sync::SpinLock;
 use paging::*;
 
+use ::alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use log::info;
 use multiboot2 as mb2;
-use x86_64::registers::control::{Cr3, Cr3Flags};
+use shared::cmdline::MemoryOptions;
+use shared::fmt::ByteSize;
 
 /// The map of virtual address space. Assigns different ranges to various
 /// purposes.
@@ -36,20 +64,47 @@ impl VirtualMap {
         )
     }
 
+    /// Where `AddressSpace::mmap_anonymous` places user mappings. Above the
+    /// area static-PIE executables are loaded in, and far below the user
+    /// stack.
+    pub const fn user_anonymous() -> VirtExtent {
+        VirtExtent::from_raw_range_exclusive(0x0000_2000_0000_0000, 0x0000_7000_0000_0000)
+    }
+
     /// Mapping of all physical memory in kernel space. This is currently 2^40
     /// bytes worth.
     pub const fn phys_map() -> VirtExtent {
         VirtExtent::from_raw_range_exclusive(0xffff_8000_0000_0000, 0xffff_80ff_ffff_ffff)
     }
 
+    /// Dedicated mappings for kernel code outside the image. See
+    /// `executable`. This is 1GiB at the start of the last L4 entry, which is
+    /// shared with the kernel image.
+    pub const fn executable() -> VirtExtent {
+        VirtExtent::from_raw(0xffff_ff80_0000_0000, 1024 * 1024 * 1024)
+    }
+
+    /// Task kernel stacks, with unmapped guard pages between them. See
+    /// `kernel_stack`. This is the 1GiB after `executable`, so it shares
+    /// the same top-level entry.
+    pub const fn kernel_stacks() -> VirtExtent {
+        VirtExtent::from_raw(0xffff_ff80_4000_0000, 1024 * 1024 * 1024)
+    }
+
+    /// The kernel heap, mapped on demand. See `kernel_heap`. This is the 1GiB
+    /// after `kernel_stacks`.
+    pub const fn heap() -> VirtExtent {
+        VirtExtent::from_raw(0xffff_ff80_8000_0000, 1024 * 1024 * 1024)
+    }
+
     /// Kernel image's address. This is the last 2GiB of memory.
     pub const fn kernel_image() -> VirtExtent {
         VirtExtent::from_raw_range_exclusive(0xffff_ffff_8000_0000, 0xffff_ffff_ffff_ffff)
     }
 }
 
-static FRAME_ALLOCATOR: spin::Mutex<once_cell::unsync::OnceCell<BitmapFrameAllocator>> =
-    spin::Mutex::new(once_cell::unsync::OnceCell::new());
+static FRAME_ALLOCATOR: SpinLock<once_cell::unsync::OnceCell<ZonedFrameAllocator>> =
+    SpinLock::new(once_cell::unsync::OnceCell::new());
 
 // Bitmap used by FRAME_ALLOCATOR. It is static to be allocated on kernel load,
 // but it doesn't need to be; for example, if there were a simpler bootstrap
@@ -71,7 +126,11 @@ const MAX_MEMORY_FRAMES: usize = MAX_MEMORY.as_raw() as usize / page::PAGE_SIZE.
 
 /// Initializes the memory management system. Must only be called once; panics
 /// otherwise.
-pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<Item = PhysExtent>) {
+///
+/// The memory map and what to keep from the frame allocator come from
+/// `boot_info`. `mbinfo` is only read for the kernel's ELF sections. `options`
+/// can further restrict the memory used, e.g. for testing.
+pub fn init(mbinfo: &mb2::BootInformation, boot_info: &BootInfo, options: &MemoryOptions) {
     // Make sure we are only called once.
     static IS_INITIALIZED: core::sync::atomic::AtomicBool =
         core::sync::atomic::AtomicBool::new(false);
@@ -80,17 +139,58 @@ pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<It
     let kernel_extent = get_kernel_phys_extent();
     info!("Kernel extent: {kernel_extent:x?}");
 
-    let orig_memory_map = translate_memory_map(boot_info);
+    let orig_memory_map = boot_info.memory_map.clone();
+    let reserved = boot_info.reserved_extents();
+    let reserved = reserved.iter().copied();
 
     // Rewrite the memory map to exclude kernel areas.
-    let mut memory_map = Map::from_entries(mark_kernel_areas(
+    let mut memory_map: Map = Map::from_entries(mark_kernel_areas(
         mark_kernel_areas(orig_memory_map.entries().iter().copied(), reserved.clone()),
         core::iter::once(kernel_extent),
     ));
 
+    // Then apply limits from the command line.
+    if !options.reserved.is_empty() {
+        info!("Reserving {:x?} by request", options.reserved);
+        memory_map = Map::from_entries(mark_areas(
+            memory_map.entries().iter().copied(),
+            options.reserved.iter().copied(),
+            MemoryType::Reserved,
+        ));
+    }
+    if let Some(limit) = options.limit {
+        info!("Limiting memory to below {limit:x?}");
+        memory_map =
+            Map::from_entries(limit_available(memory_map.entries().iter().copied(), limit));
+    }
+
+    map_check::check(
+        &orig_memory_map,
+        &memory_map,
+        reserved.clone().chain(core::iter::once(kernel_extent)),
+        options,
+    );
+
     for e in memory_map.entries().iter() {
-        info!("{e:x?}");
+        info!(
+            "{:#x}-{:#x} {:?} ({})",
+            e.extent.address().as_raw(),
+            e.extent.last_address().as_raw(),
+            e.mem_type,
+            ByteSize(e.extent.length().as_raw())
+        );
     }
+    let available: u64 = memory_map
+        .iter_type(MemoryType::Available)
+        .map(|e| e.extent.length().as_raw())
+        .sum();
+    info!("{} available", ByteSize(available));
+    let num_frames = memory_map
+        .iter_type(MemoryType::Available)
+        .map(|e| e.extent.end_address().as_raw().div_ceil(PAGE_SIZE.as_raw()))
+        .max()
+        .unwrap()
+        .min(MAX_MEMORY_FRAMES as u64);
 
     // Set up a bump allocator for bootstrapping allocations that will live
     // forever, especially the kernel page tables.
@@ -154,7 +254,7 @@ pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<It
 
     let page_table_template = unsafe {
         create_page_table_template(
-            boot_info,
+            mbinfo,
             &orig_memory_map,
             || init_allocator.allocate(),
             first_gb_translator,
@@ -166,7 +266,11 @@ pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<It
     // known to either `memory_map` or the future allocator.
     //
     // Restore the remaining frames to the map entry.
-    if let Some(remain) = init_allocator.unwrap() {
+    let remain = init_allocator.unwrap();
+    protect::add_page_table_frames(
+        init_alloc_frames.count() - remain.map_or(0, |remain| remain.count()),
+    );
+    if let Some(remain) = remain {
         let extent = &mut memory_map.entries_mut()[init_alloc_map_ndx].extent;
         *extent = PhysExtent::from_range_exclusive(remain.first().start(), extent.end_address());
     }
@@ -178,20 +282,18 @@ pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<It
     // Now `frame_allocator` has exclusive access to the frame bitmap.
     let frame_bitmap_ref = spin::MutexGuard::leak(frame_bitmap);
 
-    let mut frame_allocator = unsafe { BitmapFrameAllocator::new(frame_bitmap_ref) };
+    let mut frame_allocator =
+        ZonedFrameAllocator::new(unsafe { BitmapFrameAllocator::new(frame_bitmap_ref) });
 
     // Mark all reserved areas. Important so we don't hand out memory containing
     // kernel code or data structures.
     for reserved_extent in reserved.chain([
         // Exclude the kernel image itself.
         get_kernel_phys_extent(),
-        // Exclude the boot_info structure.
-        PhysExtent::from_raw(
-            boot_info.start_address() as u64,
-            boot_info.total_size() as u64,
-        ),
-        // Exclude the first MB.
-        PhysExtent::from_raw(0, 1024 * 1024),
+        // Exclude the first frame, which holds the real mode interrupt vector
+        // table and BIOS data area. The rest of the first MiB is left to the
+        // memory map, which reports what the firmware uses.
+        PhysExtent::from_raw(0, PAGE_SIZE.as_raw()),
     ]) {
         info!("reserving extent {reserved_extent:?}");
         for frame in FrameRange::containing_extent(reserved_extent).iter() {
@@ -201,24 +303,168 @@ pub fn init(boot_info: &mb2::BootInformation, reserved: impl Clone + Iterator<It
         }
     }
 
+    frame_allocator.set_default_watermarks();
+    for zone in Zone::ALL {
+        let info = frame_allocator.zone_info(zone);
+        info!(
+            "{zone:?} zone: {} free of {}, watermarks {:?}",
+            ByteSize(info.free * PAGE_SIZE.as_raw()),
+            ByteSize(info.managed * PAGE_SIZE.as_raw()),
+            info.watermarks
+        );
+    }
+
     FRAME_ALLOCATOR.lock().set(frame_allocator).unwrap();
 
+    // The new page tables mark data no-execute, which is a reserved bit
+    // unless EFER.NXE is set. `entry.nasm` sets it if the CPU has NX, and
+    // otherwise `arch::quirks` stopped entries being marked.
+    assert_eq!(regs::no_execute_enabled(), Feature::NoExecute.is_present());
     unsafe {
         set_up_initial_page_table(&page_table_template);
     }
+
+    kernel_heap::init();
+    shared_frame::init(num_frames);
+    rmap::init(num_frames);
+
+    TOTAL_FRAMES.store(
+        orig_memory_map
+            .iter_type(MemoryType::Available)
+            .filter_map(|e| FrameRange::contained_by_extent(e.extent))
+            .map(|frames| frames.count())
+            .sum(),
+        Ordering::Relaxed,
+    );
+    info!("{}", stats());
+}
+
+/// Frames of available memory in the firmware's memory map.
+static TOTAL_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// How often `report_stats_thread` logs `stats`.
+const STATS_REPORT_INTERVAL_SECS: u64 = 60;
+
+/// Memory usage, from `stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// Frames of available memory in the firmware's memory map.
+    pub total_frames: u64,
+    /// Frames the frame allocator has free.
+    pub free_frames: u64,
+    /// Frames of `total_frames` the frame allocator doesn't manage: the kernel
+    /// image, boot modules, boot page tables, and memory reserved or removed
+    /// since.
+    pub reserved_frames: u64,
+    /// Frames holding page tables, which are also counted as allocated or
+    /// reserved.
+    pub page_table_frames: u64,
+    pub heap: heap::HeapStats,
+}
+
+impl core::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let page = PAGE_SIZE.as_raw();
+        write!(
+            f,
+            "memory: {} free of {}, {} reserved, {} in page tables; heap: {} in use, {} allocated, {} freed",
+            ByteSize(self.free_frames * page),
+            ByteSize(self.total_frames * page),
+            ByteSize(self.reserved_frames * page),
+            ByteSize(self.page_table_frames * page),
+            ByteSize(self.heap.in_use() as u64),
+            ByteSize(self.heap.allocated as u64),
+            ByteSize(self.heap.freed as u64),
+        )
+    }
+}
+
+/// Current memory usage. Must be called after `init`.
+pub fn stats() -> MemoryStats {
+    let (free_frames, managed_frames) = {
+        let guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.get().unwrap();
+        Zone::ALL
+            .into_iter()
+            .map(|zone| frame_allocator.zone_info(zone))
+            .fold((0, 0), |(free, managed), info| {
+                (free + info.free, managed + info.managed)
+            })
+    };
+    let total_frames = TOTAL_FRAMES.load(Ordering::Relaxed);
+    MemoryStats {
+        total_frames,
+        free_frames,
+        reserved_frames: total_frames.saturating_sub(managed_frames),
+        page_table_frames: protect::page_table_frames(),
+        heap: GLOBAL_ALLOCATOR.get().stats(),
+    }
+}
+
+/// Logs `stats` every `STATS_REPORT_INTERVAL_SECS`. Meant to be spawned at
+/// low priority, so it only runs when nothing else wants to.
+pub extern "C" fn report_stats_thread(_: usize) -> ! {
+    loop {
+        crate::sched::sleep_ticks(STATS_REPORT_INTERVAL_SECS * crate::time::TIMER_HZ);
+        info!("{}", stats());
+    }
+}
+
+/// A zeroed table with a `T` for each of frames 0 to `num_frames - 1`, which
+/// is never freed. `what` names it if there is no memory for it.
+///
+/// # Safety
+///
+/// All zero bytes must be a valid `T`.
+unsafe fn allocate_frame_table<T>(num_frames: u64, what: &str) -> &'static [T] {
+    let len = Length::from_raw(num_frames * core::mem::size_of::<T>() as u64);
+    let pages = len.as_raw().div_ceil(PAGE_SIZE.as_raw()).max(1);
+    let order = pages.next_power_of_two().trailing_zeros() as usize;
+    let frames = allocate_frames(order).unwrap_or_else(|| panic!("out of memory for {what}"));
+
+    let table = phys_to_virt(frames.first().start()).as_mut_ptr::<T>();
+    // SAFETY: the frames are never freed, and zero is a valid `T`.
+    unsafe {
+        core::ptr::write_bytes(table, 0, num_frames as usize);
+        core::slice::from_raw_parts(table, num_frames as usize)
+    }
 }
 
 #[inline(never)]
-#[allow(unused)]
 pub fn allocate_frame() -> Option<Frame> {
     Some(allocate_frames(0)?.first())
 }
 
 #[inline(never)]
 pub fn allocate_frames(order: usize) -> Option<FrameRange> {
+    allocate_frames_from(order, Zone::Normal)
+}
+
+/// Allocate frames from `zone` or a lower one, e.g. for a device that can
+/// only address low memory. If there are enough free frames but not together,
+/// tries compacting `zone` before giving up.
+#[inline(never)]
+pub fn allocate_frames_from(order: usize, zone: Zone) -> Option<FrameRange> {
     let mut guard = FRAME_ALLOCATOR.lock();
     let frame_allocator = guard.get_mut().unwrap();
-    frame_allocator.allocate_range(order)
+    let frames = frame_allocator.allocate_range_from(order, zone);
+
+    // TODO: wake a reclaim task once there are caches to shrink.
+    for zone in frame_allocator.take_reclaim_requests() {
+        log::warn!(
+            "{zone:?} zone is low on memory: {:?}",
+            frame_allocator.zone_info(zone)
+        );
+    }
+    drop(guard);
+
+    frames.or_else(|| compaction::compact(order, zone))
+}
+
+/// Usage of `zone`, or `None` if the frame allocator is in use, e.g. by the
+/// code this interrupted.
+pub fn try_zone_info(zone: Zone) -> Option<ZoneInfo> {
+    Some(FRAME_ALLOCATOR.try_lock()?.get()?.zone_info(zone))
 }
 
 #[inline(never)]
@@ -228,13 +474,90 @@ pub unsafe fn deallocate_frames(frames: FrameRange) {
     frame_allocator.deallocate_range(frames);
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemoveRangeError {
+    /// The extent isn't a whole number of frames.
+    Unaligned,
+    /// Some of the frames can't be removed. Allocated frames aren't migrated,
+    /// since nothing records where they are mapped.
+    Frames(FrameRemoveError),
+}
+
+impl core::fmt::Display for RemoveRangeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RemoveRangeError::Unaligned => write!(f, "not a whole number of frames"),
+            RemoveRangeError::Frames(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Take the memory in `extent` away from the frame allocator for good, e.g.
+/// for a balloon driver to give to the host or before the memory is
+/// unplugged. Succeeds only once every frame in it is free; otherwise nothing
+/// changes. The physical memory map still covers it.
+#[allow(unused)]
+pub fn remove_physical_range(extent: PhysExtent) -> Result<(), RemoveRangeError> {
+    let page_size = PAGE_SIZE.as_raw();
+    if extent.length().as_raw() == 0
+        || !extent.address().is_aligned_to(page_size)
+        || !extent.length().is_aligned_to(page_size)
+    {
+        return Err(RemoveRangeError::Unaligned);
+    }
+    let frames = FrameRange::containing_extent(extent);
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.get_mut().unwrap();
+    frame_allocator
+        .remove_range(frames)
+        .map_err(RemoveRangeError::Frames)?;
+    info!(
+        "removed {:#x}-{:#x} ({})",
+        extent.address().as_raw(),
+        extent.last_address().as_raw(),
+        ByteSize(extent.length().as_raw())
+    );
+    Ok(())
+}
+
 #[inline(never)]
 pub fn allocate_owned_frames(order: usize) -> Option<OwnedFrameRange> {
+    allocate_owned_frames_from(order, Zone::Normal)
+}
+
+/// Like `allocate_owned_frames`, but from `zone` or a lower one, as with
+/// `allocate_frames_from`.
+#[inline(never)]
+pub fn allocate_owned_frames_from(order: usize, zone: Zone) -> Option<OwnedFrameRange> {
     Some(OwnedFrameRange {
-        frames: allocate_frames(order)?,
+        frames: allocate_frames_from(order, zone)?,
     })
 }
 
+kernel_test!(
+    "mm: constrained allocations stay in their zones",
+    constrained_allocations_stay_in_zones
+);
+fn constrained_allocations_stay_in_zones() -> TestResult {
+    for zone in Zone::ALL {
+        for order in [0, 2] {
+            // There may be no free memory in the lowest zones.
+            let Some(frames) = allocate_owned_frames_from(order, zone) else {
+                continue;
+            };
+            let highest = Zone::containing(frames.frames().last());
+            if highest > zone {
+                return Err(format!(
+                    "order {order} from {zone:?} gave {:?}, in {highest:?}",
+                    frames.frames()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 /// An exclusively owned frame range that will be deallocated on destruction.
 pub struct OwnedFrameRange {
     frames: FrameRange,
@@ -254,21 +577,6 @@ impl Drop for OwnedFrameRange {
     }
 }
 
-pub fn translate_memory_map(mb2_info: &mb2::BootInformation) -> Map {
-    let mem_map_tag = mb2_info.memory_map_tag().unwrap();
-    Map::from_entries(mem_map_tag.memory_areas().iter().map(|area| MapEntry {
-        extent: PhysExtent::from_raw(area.start_address(), area.size()),
-        mem_type: match area.typ().into() {
-            mb2::MemoryAreaType::Available => MemoryType::Available,
-            mb2::MemoryAreaType::Reserved => MemoryType::Reserved,
-            mb2::MemoryAreaType::AcpiAvailable => MemoryType::Acpi,
-            mb2::MemoryAreaType::ReservedHibernate => MemoryType::ReservedPreserveOnHibernation,
-            mb2::MemoryAreaType::Defective => MemoryType::Defective,
-            t => panic!("unknown mb2 memory type {t:?}"),
-        },
-    }))
-}
-
 unsafe fn create_page_table_template<
     F: FnMut() -> Option<Frame>,
     T: Fn(PhysAddress) -> Option<VirtAddress>,
@@ -287,10 +595,13 @@ unsafe fn create_page_table_template<
         PageTableFlags::PRESENT | PageTableFlags::GLOBAL | PageTableFlags::APP_PARENT_FROZEN;
 
     // First, set up the physical memory mapping. It must be read/write. For
-    // safety make it non-executable.
+    // safety make it non-executable. The parent entries are non-executable too,
+    // so nothing reached through the physical memory map, including the heap,
+    // can ever be executed whatever its leaf entries say.
     let leaf_flags =
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE;
-    let parent_flags = shared_parent_flags | PageTableFlags::WRITABLE;
+    let parent_flags =
+        shared_parent_flags | PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE;
     for frame in memory_map
         .entries()
         .iter()
@@ -305,9 +616,10 @@ unsafe fn create_page_table_template<
         }
     }
 
-    // We still identity map the first 1 MiB. We still hold a couple absolute
-    // pointers (e.g. VGA memory) here. TODO: fix this and get rid of this
-    // mapping.
+    // We still identity map the first 1 MiB, where application processors
+    // start in the trampoline `smp` copies there. Devices in it, like the VGA
+    // text buffer, are mapped separately in `platform`. TODO: map only the
+    // trampoline, while it is in use, and get rid of this mapping.
     let leaf_flags =
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE;
     let parent_flags = shared_parent_flags | PageTableFlags::WRITABLE;
@@ -359,6 +671,10 @@ unsafe fn create_page_table_template<
             assert!(!section_flags.contains(mb2::ElfSectionFlags::EXECUTABLE));
             leaf_flags |= PageTableFlags::WRITABLE;
         }
+        // The linker script must keep read-only data out of writable pages.
+        if section.name().unwrap_or("").starts_with(".rodata") {
+            assert!(!leaf_flags.contains(PageTableFlags::WRITABLE));
+        }
 
         for page in PageRange::containing_extent(section_extent).iter() {
             let frame = Frame::new(PhysAddress::from_zero(
@@ -388,6 +704,84 @@ unsafe fn set_up_initial_page_table(template: &PageTable) {
 static INIT_PAGE_TABLE: spin::Mutex<paging::PageTable> =
     spin::Mutex::new(paging::PageTable::zero());
 
+/// Switch back to the kernel's initial page table, e.g. before a process's
+/// address space is torn down.
+pub fn activate_kernel_address_space() {
+    // SAFETY: `INIT_PAGE_TABLE` is the kernel's root table set up by `init`.
+    unsafe {
+        install_page_table(&mut INIT_PAGE_TABLE.lock());
+    }
+}
+
+/// Physical address of the kernel's initial page table, for CPUs that load it
+/// themselves.
+pub fn kernel_page_table_phys() -> PhysAddress {
+    kernel_ptr_to_phys_addr(&*INIT_PAGE_TABLE.lock() as *const PageTable)
+}
+
+/// Make `frame`, which must be in the identity mapped first MiB, read-only and
+/// executable there. This is for code a CPU runs before it can reach the
+/// kernel image, like the application processor trampoline.
+///
+/// # Safety
+///
+/// `frame` must hold code, and nothing may write to it after this.
+pub unsafe fn map_low_code(frame: Frame) {
+    unsafe {
+        remap_low(frame, PageTableFlags::PRESENT);
+    }
+}
+
+/// Undo `map_low_code`, making `frame` writable and not executable again so
+/// it can be freed.
+///
+/// # Safety
+///
+/// No CPU may still be running code in `frame`.
+pub unsafe fn unmap_low_code(frame: Frame) {
+    unsafe {
+        remap_low(
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE,
+        );
+    }
+}
+
+/// Replace the identity mapping of `frame` in the first MiB with one with
+/// `flags`.
+///
+/// # Safety
+///
+/// Nothing may depend on the old mapping's permissions.
+unsafe fn remap_low(frame: Frame, flags: PageTableFlags) {
+    let page = Page::new(VirtAddress::from_raw(frame.start().as_raw()));
+    assert!(VirtualMap::first_mib().contains(page.extent()), "{frame:?}");
+
+    protect::with_page_tables_writable(|| {
+        let mut root_table = INIT_PAGE_TABLE.lock();
+        // SAFETY: as in `map_mmio`. The identity map's tables already exist,
+        // so this only replaces the leaf entry.
+        let mut mapper = unsafe {
+            paging::Mapper::new(
+                &mut root_table,
+                |phys| Some(phys_to_virt(phys)),
+                protect::allocate_page_table,
+            )
+        };
+        unsafe {
+            mapper
+                .map(
+                    page,
+                    frame,
+                    flags,
+                    PageTableFlags::empty(),
+                    PageTableFlags::all(),
+                )
+                .unwrap();
+        }
+    });
+}
+
 /// Install `root_table` as the active page table.
 ///
 /// # Safety
@@ -396,13 +790,7 @@ static INIT_PAGE_TABLE: spin::Mutex<paging::PageTable> =
 unsafe fn install_page_table(root_table: &mut paging::PageTable) {
     let phys_addr = kernel_ptr_to_phys_addr(root_table as *const _);
     unsafe {
-        Cr3::write(
-            x86_64::structures::paging::PhysFrame::from_start_address(x86_64::addr::PhysAddr::new(
-                phys_addr.as_raw(),
-            ))
-            .unwrap(),
-            Cr3Flags::empty(),
-        );
+        regs::set_page_table_root(phys_addr);
     }
 }
 
@@ -433,6 +821,98 @@ pub fn phys_extent_to_virt(phys: PhysExtent) -> VirtExtent {
     VirtExtent::new(phys_to_virt(phys.address()), phys.length())
 }
 
+/// Whether all of `phys` is mapped in the physical memory map. Only memory
+/// reported by the firmware and `map_mmio` regions are, so this walks the
+/// kernel's page tables rather than trusting the address.
+pub fn is_phys_mapped(phys: PhysExtent) -> bool {
+    if phys.end_address() > PhysAddress::from_zero(MAX_MEMORY) {
+        return false;
+    }
+    if phys.length().as_raw() == 0 {
+        return true;
+    }
+
+    let root_table = INIT_PAGE_TABLE.lock();
+    FrameRange::containing_extent(phys)
+        .iter()
+        .all(|frame| is_kernel_page_mapped(&root_table, Page::new(phys_to_virt(frame.start()))))
+}
+
+/// Whether `page` is mapped by the kernel's tables under `root_table`,
+/// possibly as part of a huge page.
+fn is_kernel_page_mapped(root_table: &paging::PageTable, page: Page) -> bool {
+    let mut table: *const paging::PageTable = root_table;
+    let indices = [
+        page.l4_index(),
+        page.l3_index(),
+        page.l2_index(),
+        page.l1_index(),
+    ];
+    for (level, index) in indices.into_iter().enumerate() {
+        // SAFETY: the kernel's page tables are all reachable through the
+        // physical memory map, and holding `INIT_PAGE_TABLE` keeps them from
+        // changing.
+        let mut entry = unsafe { (*table).entries()[index] };
+        let flags = entry.get_flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level == 3 || flags.contains(PageTableFlags::PAGE_SIZE) {
+            return true;
+        }
+        table = phys_to_virt(entry.get_addr()).as_ptr();
+    }
+    unreachable!()
+}
+
+/// Map device memory at `phys` uncached, returning its registers. Like RAM, it
+/// appears at the corresponding place in the physical memory map, and the
+/// mapping is shared with all address spaces.
+///
+/// # Safety
+///
+/// `phys` must not be RAM in use by anything else, since this changes the
+/// caching mode of any existing mapping. It must be device registers that are
+/// safe to read, as `VolatileRegion::new` requires.
+pub unsafe fn map_mmio(phys: PhysExtent) -> VolatileRegion {
+    let leaf_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::EXECUTE_DISABLE;
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::APP_PARENT_FROZEN
+        | PageTableFlags::EXECUTE_DISABLE;
+
+    protect::with_page_tables_writable(|| {
+        let mut root_table = INIT_PAGE_TABLE.lock();
+        // SAFETY: the kernel's page tables are all reachable through the
+        // physical memory map. The only changed translations are for `phys`,
+        // which the caller vouches for.
+        let mut mapper = unsafe {
+            paging::Mapper::new(
+                &mut root_table,
+                |phys| Some(phys_to_virt(phys)),
+                protect::allocate_page_table,
+            )
+        };
+        for frame in FrameRange::containing_extent(phys).iter() {
+            let page = Page::new(phys_to_virt(frame.start()));
+            unsafe {
+                mapper
+                    .map(page, frame, leaf_flags, parent_flags, PageTableFlags::all())
+                    .unwrap();
+            }
+        }
+    });
+
+    // SAFETY: just mapped, and the caller vouches for the registers.
+    unsafe { VolatileRegion::new(phys_extent_to_virt(phys)) }
+}
+
 /// Given a pointer `p` in the kernel's address space, return the physical
 /// address referenced. `p` *must* point within the kernel's address space above
 /// `get_kernel_virt_base()`.
@@ -465,32 +945,19 @@ pub fn get_kernel_phys_extent() -> PhysExtent {
     }
 }
 
-/// Provides "chunks" or pages to the heap implementation. This is very basic:
-/// it simply grabs frames, calculates the offset into our mapping of phys mem,
-/// and hands that pointer down.
-///
-/// TODO: manage this better. I'd like to set aside a portion of the kernel's
-/// address space for the heap.
-struct HeapProvider;
-
-unsafe impl heap::ChunkProvider for HeapProvider {
-    fn allocate(&mut self, num_chunks: usize) -> *mut [core::mem::MaybeUninit<u8>] {
-        let mut guard = FRAME_ALLOCATOR.lock();
-        let frame_alloc = guard.get_mut().unwrap();
-
-        let num_frames = num_chunks.next_power_of_two();
-        let order = num_frames.trailing_zeros() as usize;
-        let frames = frame_alloc.allocate_range(order).unwrap();
+#[global_allocator]
+static GLOBAL_ALLOCATOR: heap::CheckedHeap<kernel_heap::HeapProvider> =
+    heap::CheckedHeap::new(heap::Heap::new(kernel_heap::HeapProvider::new()));
 
-        let ptr: *mut core::mem::MaybeUninit<u8> =
-            phys_to_virt(frames.first().start()).as_mut_ptr();
-        core::ptr::slice_from_raw_parts_mut(ptr, num_chunks * PAGE_SIZE.as_raw() as usize)
-    }
+/// Hold the last `len` freed heap blocks before reusing them, to catch use
+/// after free. See `heap::Heap::set_quarantine_len`.
+pub fn set_heap_quarantine_len(len: usize) {
+    GLOBAL_ALLOCATOR.get().set_quarantine_len(len);
 }
 
-#[global_allocator]
-static GLOBAL_ALLOCATOR: heap::CheckedHeap<HeapProvider> =
-    heap::CheckedHeap::new(heap::Heap::new(HeapProvider));
+pub fn heap_quarantine_stats() -> heap::QuarantineStats {
+    GLOBAL_ALLOCATOR.get().quarantine_stats()
+}
 
 mod internal {
     extern "C" {
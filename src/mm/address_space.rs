@@ -0,0 +1,659 @@
+//! Per-process virtual address spaces
+
+use super::*;
+
+use ::alloc::vec::Vec;
+
+/// A virtual address space with its own root page table. The kernel half is
+/// shared with every other address space; the lower half holds user mappings.
+pub struct AddressSpace {
+    /// Frame holding the root (L4) page table, from
+    /// `protect::allocate_page_table`.
+    root: Frame,
+
+    /// Frames backing user mappings. These are released when the address
+    /// space is dropped, unless another still shares them copy-on-write.
+    user_pages: Vec<UserPage>,
+
+    /// How many page tables below `root` the mapper has allocated. They are
+    /// freed when the address space is dropped, which checks the count.
+    page_tables: usize,
+
+    /// Ranges from `mmap_anonymous`, sorted by address. Their pages are only
+    /// backed by frames once touched.
+    anonymous: Vec<AnonymousRegion>,
+}
+
+/// A user page and the frame backing it. The frame is shared with other
+/// address spaces while it is mapped copy-on-write, and freed once none of
+/// them has it.
+struct UserPage {
+    page: Page,
+    frame: SharedFrame,
+}
+
+/// A range of anonymous memory. Pages in it that aren't mapped yet are mapped
+/// to a zeroed frame on their first access.
+#[derive(Clone, Copy, Debug)]
+struct AnonymousRegion {
+    extent: VirtExtent,
+    protection: Protection,
+}
+
+bitflags::bitflags! {
+    /// Access user code has to a mapping, besides reading it, which is always
+    /// allowed.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Protection: u8 {
+        const WRITE = 1 << 0;
+        const EXECUTE = 1 << 1;
+    }
+}
+
+impl Protection {
+    /// The flags for user pages with this protection.
+    fn page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER;
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXECUTE) {
+            flags |= PageTableFlags::EXECUTE_DISABLE;
+        }
+        flags
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MmapError {
+    /// The length is zero.
+    ZeroLength,
+    /// There is no free range of the requested length.
+    OutOfAddressSpace,
+}
+
+impl core::fmt::Display for MmapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MmapError::ZeroLength => write!(f, "zero length mapping"),
+            MmapError::OutOfAddressSpace => write!(f, "out of user address space"),
+        }
+    }
+}
+
+impl AddressSpace {
+    /// Create an address space with no user mappings. Returns `None` if out of
+    /// memory.
+    pub fn new() -> Option<AddressSpace> {
+        protect::with_page_tables_writable(|| {
+            let root = protect::allocate_page_table()?;
+
+            // SAFETY: `root` was freshly allocated, so nothing else references
+            // it.
+            let root_table = unsafe { &mut *table_ptr(root) };
+            *root_table = PageTable::zero();
+
+            // The kernel half consists of frozen tables shared between all
+            // address spaces, so sharing the top-level entries is enough.
+            root_table.entries_mut()[256..]
+                .copy_from_slice(&INIT_PAGE_TABLE.lock().entries()[256..]);
+
+            let mut space = AddressSpace {
+                root,
+                user_pages: Vec::new(),
+                page_tables: 0,
+                anonymous: Vec::new(),
+            };
+
+            // The kernel still depends on the identity mapped first MiB (for
+            // the application processor trampoline), and it lives in the
+            // lower half. Give each
+            // address space its own kernel-only copy so user mappings never
+            // touch the shared tables. TODO: remove this once the first MiB is
+            // no longer needed.
+            let leaf_flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::EXECUTE_DISABLE;
+            for page in PageRange::containing_extent(VirtualMap::first_mib()).iter() {
+                let frame = Frame::new(PhysAddress::from_raw(page.start().as_raw()));
+                unsafe {
+                    space
+                        .mapper()
+                        .map(
+                            page,
+                            frame,
+                            leaf_flags,
+                            PageTableFlags::DEFAULT_PARENT_TABLE_FLAGS,
+                            PageTableFlags::all(),
+                        )
+                        .ok()?;
+                }
+            }
+
+            Some(space)
+        })
+    }
+
+    /// Allocate a zeroed frame and map it at `page` with `flags`, which are
+    /// combined with `PRESENT` and `USER`. Returns the frame's contents through
+    /// the kernel's physical memory map, e.g. to initialize it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page` is not in user address space.
+    pub fn map_new_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<&mut [u8], MapError> {
+        assert!(
+            VirtualMap::user().contains(page.extent()),
+            "{page:?} is not in user space"
+        );
+
+        let shared = SharedFrame::allocate().ok_or(MapError::FrameAllocationFailed)?;
+        let frame = shared.frame();
+
+        let contents: *mut [u8] = phys_extent_to_virt(frame.extent()).as_slice::<u8>() as *mut _;
+        // SAFETY: the frame was freshly allocated and is exclusively owned by
+        // us.
+        unsafe {
+            (*contents).fill(0);
+        }
+
+        let parent_flags = PageTableFlags::DEFAULT_PARENT_TABLE_FLAGS | PageTableFlags::USER;
+        protect::with_page_tables_writable(|| unsafe {
+            self.mapper().map(
+                page,
+                frame,
+                flags | PageTableFlags::PRESENT | PageTableFlags::USER,
+                parent_flags,
+                PageTableFlags::all(),
+            )
+        })?;
+
+        rmap::set(frame, page);
+        self.user_pages.push(UserPage {
+            page,
+            frame: shared,
+        });
+
+        // SAFETY: the frame is owned by `self`, and the returned borrow of
+        // `self` prevents it from being released.
+        Ok(unsafe { &mut *contents })
+    }
+
+    /// The contents of the user page mapped at `page` through the kernel's
+    /// physical memory map, e.g. to fix it up after loading. Returns `None` if
+    /// `page` isn't mapped by `map_new_user_page`, or if it is shared
+    /// copy-on-write and there is no memory to copy it.
+    pub fn user_page_contents(&mut self, page: Page) -> Option<&mut [u8]> {
+        if !VirtualMap::user().contains(page.extent()) {
+            return None;
+        }
+        // The caller may write to it.
+        self.break_cow(page).ok()?;
+        let frame = self.mapper().translate(page)?;
+        self.user_page_index(page, frame)?;
+
+        // SAFETY: the frame is owned by `self`, and the returned borrow of
+        // `self` prevents it from being released.
+        let contents = phys_extent_to_virt(frame.extent()).as_slice::<u8>() as *mut [u8];
+        Some(unsafe { &mut *contents })
+    }
+
+    /// Create an address space with the same user mappings as this one. Their
+    /// frames are shared copy-on-write: writable pages become read-only in
+    /// both, and the first write to one in either copies it for that address
+    /// space (see `handle_write_fault`). Returns `None` if out of memory.
+    // Nothing forks yet.
+    #[allow(unused)]
+    pub fn clone_cow(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        let parent_flags = PageTableFlags::DEFAULT_PARENT_TABLE_FLAGS | PageTableFlags::USER;
+
+        protect::with_page_tables_writable(|| {
+            for index in 0..self.user_pages.len() {
+                let page = self.user_pages[index].page;
+                let frame = self.user_pages[index].frame.frame();
+                // `map_new_user_page` may have replaced the page since.
+                let Some(mut entry) = self.mapper().leaf_entry(page) else {
+                    continue;
+                };
+                if entry.get_addr() != frame.start() {
+                    continue;
+                }
+
+                let mut flags = entry.get_flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(PageTableFlags::APP_COW);
+                    // SAFETY: the page only loses write access, which
+                    // `handle_write_fault` gives back.
+                    unsafe {
+                        self.mapper()
+                            .map(page, frame, flags, parent_flags, PageTableFlags::all())
+                            .ok()?;
+                    }
+                }
+                // SAFETY: `child` is new and not active.
+                unsafe {
+                    child
+                        .mapper()
+                        .map(page, frame, flags, parent_flags, PageTableFlags::all())
+                        .ok()?;
+                }
+                child.user_pages.push(UserPage {
+                    page,
+                    frame: self.user_pages[index].frame.clone(),
+                });
+            }
+            // Pages not touched yet are still zero in both.
+            child.anonymous = self.anonymous.clone();
+            Some(child)
+        })
+    }
+
+    /// Reserve `len` bytes, rounded up to whole pages, of anonymous memory in
+    /// `VirtualMap::user_anonymous()`. The memory reads as zero and is backed
+    /// by frames page by page on first access (see
+    /// `handle_not_present_fault`), mapped with `protection`.
+    ///
+    /// System calls only accept user buffers that are already backed, so
+    /// user code must touch the memory before passing it to the kernel.
+    // Nothing maps anonymous memory yet.
+    #[allow(unused)]
+    pub fn mmap_anonymous(
+        &mut self,
+        len: Length,
+        protection: Protection,
+    ) -> Result<VirtExtent, MmapError> {
+        if len.as_raw() == 0 {
+            return Err(MmapError::ZeroLength);
+        }
+        let area = VirtualMap::user_anonymous();
+        let len = Length::from_raw(
+            len.as_raw()
+                .checked_next_multiple_of(PAGE_SIZE.as_raw())
+                .ok_or(MmapError::OutOfAddressSpace)?,
+        );
+
+        // First fit, skipping other regions and pages mapped some other way.
+        let mut start = area.address();
+        let extent = loop {
+            let extent = VirtExtent::new_checked(start, len).ok_or(MmapError::OutOfAddressSpace)?;
+            if !area.contains(extent) {
+                return Err(MmapError::OutOfAddressSpace);
+            }
+            let conflict = self
+                .anonymous
+                .iter()
+                .map(|region| region.extent)
+                .chain(self.user_pages.iter().map(|user| user.page.extent()))
+                .filter(|other| other.has_overlap(extent))
+                .map(VirtExtent::end_address)
+                .max();
+            match conflict {
+                Some(end) => start = end,
+                None => break extent,
+            }
+        };
+
+        let index = self
+            .anonymous
+            .partition_point(|region| region.extent.address() < extent.address());
+        self.anonymous
+            .insert(index, AnonymousRegion { extent, protection });
+        Ok(extent)
+    }
+
+    /// Remove the anonymous memory in `pages`, freeing the frames backing it.
+    /// Regions only partly in `pages` shrink or are split. Other mappings in
+    /// `pages` are left alone.
+    // Nothing maps anonymous memory yet.
+    #[allow(unused)]
+    pub fn munmap(&mut self, pages: PageRange) {
+        let range = VirtExtent::new(
+            pages.first().start(),
+            Length::from_raw(pages.count() * PAGE_SIZE.as_raw()),
+        );
+
+        let mut remaining = Vec::with_capacity(self.anonymous.len() + 1);
+        let mut removed = Vec::new();
+        for region in self.anonymous.drain(..) {
+            let Some(overlap) = region.extent.overlap(range) else {
+                remaining.push(region);
+                continue;
+            };
+            removed.push(overlap);
+            for extent in [
+                region.extent.left_difference(range),
+                region.extent.right_difference(range),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                remaining.push(AnonymousRegion { extent, ..region });
+            }
+        }
+        self.anonymous = remaining;
+
+        for page in removed
+            .into_iter()
+            .flat_map(|extent| PageRange::containing_extent(extent).iter())
+        {
+            // SAFETY: the page is only used by user code, which must not
+            // expect it to stay mapped.
+            let frame = protect::with_page_tables_writable(|| unsafe { self.mapper().unmap(page) });
+            if let Some(frame) = frame {
+                // Dropping the last reference frees the frame, now that the
+                // mapper has flushed the TLB.
+                if let Some(index) = self.user_page_index(page, frame) {
+                    self.user_pages.remove(index);
+                }
+            }
+        }
+    }
+
+    /// Handle a user access to `page` that faulted because it isn't mapped.
+    /// Returns whether it is anonymous memory, which is now backed by a zeroed
+    /// frame so the access can be retried. Returns false if it isn't, or if
+    /// there is no memory to back it.
+    pub fn handle_not_present_fault(&mut self, page: Page) -> bool {
+        let Some(region) = self
+            .anonymous
+            .iter()
+            .find(|region| region.extent.contains(page.extent()))
+        else {
+            return false;
+        };
+        let flags = region.protection.page_flags();
+        if self.mapper().leaf_entry(page).is_some() {
+            // Already backed, so the fault wasn't from this page missing.
+            return false;
+        }
+        self.map_new_user_page(page, flags).is_ok()
+    }
+
+    /// Grow the stack occupying the top of `reserve` down to `page`, mapping
+    /// zeroed writable pages from `page` up to the lowest one already mapped.
+    /// Returns false if `page` isn't in `reserve`, or if there is no memory.
+    pub fn grow_stack(&mut self, page: Page, reserve: VirtExtent) -> bool {
+        if !reserve.contains(page.extent()) {
+            return false;
+        }
+        let top = Page::containing(reserve.last_address());
+        let mut next = page;
+        while next <= top && self.mapper().leaf_entry(next).is_none() {
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE;
+            if self.map_new_user_page(next, flags).is_err() {
+                return false;
+            }
+            let Some(above) = next.next(1) else {
+                break;
+            };
+            next = above;
+        }
+        true
+    }
+
+    /// Handle a user write to `page` that faulted because the page is
+    /// read-only. Returns whether it was a copy-on-write page, which is now
+    /// writable. Returns false if it wasn't, or if there is no memory to copy
+    /// it.
+    pub fn handle_write_fault(&mut self, page: Page) -> bool {
+        matches!(self.break_cow(page), Ok(true))
+    }
+
+    /// If `page` is mapped copy-on-write, make it writable, copying its
+    /// frame unless nothing else shares it anymore. Returns whether it was.
+    fn break_cow(&mut self, page: Page) -> Result<bool, MapError> {
+        let Some(mut entry) = self.mapper().leaf_entry(page) else {
+            return Ok(false);
+        };
+        let mut flags = entry.get_flags();
+        if !flags.contains(PageTableFlags::APP_COW) {
+            return Ok(false);
+        }
+        let frame = Frame::new(entry.get_addr());
+        let index = self
+            .user_page_index(page, frame)
+            .expect("copy-on-write page has no frame");
+
+        let copy = if self.user_pages[index].frame.ref_count() == 1 {
+            None
+        } else {
+            let copy = SharedFrame::allocate().ok_or(MapError::FrameAllocationFailed)?;
+            let from = phys_extent_to_virt(frame.extent()).as_slice::<u8>();
+            let to = phys_extent_to_virt(copy.frame().extent()).as_slice::<u8>();
+            // SAFETY: the new frame is exclusively ours, and the shared one
+            // is only read.
+            unsafe {
+                (*(to as *mut [u8])).copy_from_slice(&*from);
+            }
+            Some(copy)
+        };
+        let new_frame = copy.as_ref().map_or(frame, SharedFrame::frame);
+
+        flags.remove(PageTableFlags::APP_COW);
+        flags.insert(PageTableFlags::WRITABLE);
+        let parent_flags = PageTableFlags::DEFAULT_PARENT_TABLE_FLAGS | PageTableFlags::USER;
+        // SAFETY: the new frame has the same contents as the old one.
+        protect::with_page_tables_writable(|| unsafe {
+            self.mapper()
+                .map(page, new_frame, flags, parent_flags, PageTableFlags::all())
+        })?;
+
+        // The old frame is only released once nothing maps it here.
+        if let Some(copy) = copy {
+            rmap::set(new_frame, page);
+            self.user_pages[index].frame = copy;
+        }
+        Ok(true)
+    }
+
+    /// Move the contents of `frame`, mapped at `page`, to a new frame, so
+    /// compaction can use `frame`. Returns false if this address space isn't
+    /// the only one mapping `frame` there, or if there is no memory. `frame`
+    /// is freed.
+    ///
+    /// User code must not run in this address space meanwhile. Only the boot
+    /// CPU runs tasks, so none does while the kernel runs.
+    pub fn migrate_user_page(&mut self, page: Page, frame: Frame) -> bool {
+        let Some(mut entry) = self.mapper().leaf_entry(page) else {
+            return false;
+        };
+        if entry.get_addr() != frame.start() {
+            return false;
+        }
+        let Some(index) = self.user_page_index(page, frame) else {
+            return false;
+        };
+        if self.user_pages[index].frame.ref_count() != 1 {
+            return false;
+        }
+
+        let Some(copy) = SharedFrame::allocate() else {
+            return false;
+        };
+        let from = phys_extent_to_virt(frame.extent()).as_slice::<u8>();
+        let to = phys_extent_to_virt(copy.frame().extent()).as_slice::<u8>();
+        // SAFETY: the new frame is exclusively ours, and nothing else maps
+        // the old one.
+        unsafe {
+            (*(to as *mut [u8])).copy_from_slice(&*from);
+        }
+
+        let flags = entry.get_flags();
+        let parent_flags = PageTableFlags::DEFAULT_PARENT_TABLE_FLAGS | PageTableFlags::USER;
+        // SAFETY: the new frame has the same contents as the old one.
+        let mapped = protect::with_page_tables_writable(|| unsafe {
+            self.mapper().map(
+                page,
+                copy.frame(),
+                flags,
+                parent_flags,
+                PageTableFlags::all(),
+            )
+        });
+        if mapped.is_err() {
+            return false;
+        }
+
+        rmap::set(copy.frame(), page);
+        // Frees the old frame, now that the mapper has flushed the TLB.
+        self.user_pages[index].frame = copy;
+        true
+    }
+
+    /// The index in `user_pages` of `frame` backing `page`.
+    fn user_page_index(&self, page: Page, frame: Frame) -> Option<usize> {
+        self.user_pages
+            .iter()
+            .rposition(|user| user.page == page && user.frame.frame() == frame)
+    }
+
+    /// How many frames the address space owns, including its page tables.
+    /// Frames shared copy-on-write are counted in every address space
+    /// sharing them.
+    pub fn frame_count(&self) -> usize {
+        self.user_pages.len() + self.page_tables + 1
+    }
+
+    /// Make this the active address space.
+    ///
+    /// # Safety
+    ///
+    /// `self` must not be dropped while it is active.
+    pub unsafe fn activate(&self) {
+        unsafe {
+            regs::set_page_table_root(self.root.start());
+        }
+    }
+
+    fn mapper(
+        &mut self,
+    ) -> Mapper<
+        '_,
+        impl FnMut(PhysAddress) -> Option<VirtAddress>,
+        impl FnMut() -> Option<Frame> + '_,
+    > {
+        let page_tables = &mut self.page_tables;
+        // SAFETY: the root table is valid and owned by us. All tables below it
+        // are reachable through the physical memory map, and new tables come
+        // from the frame allocator.
+        unsafe {
+            Mapper::new(
+                &mut *table_ptr(self.root),
+                |phys| Some(phys_to_virt(phys)),
+                move || {
+                    let frame = protect::allocate_page_table()?;
+                    *page_tables += 1;
+                    Some(frame)
+                },
+            )
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Free the page tables. `user_pages` is freed afterwards, once nothing
+    /// maps it.
+    fn drop(&mut self) {
+        // SAFETY: the lower half tables are only referenced by `self`, which
+        // must not be active. The upper half is shared and left alone.
+        let freed: usize = unsafe { &*table_ptr(self.root) }.entries()[..256]
+            .iter()
+            .map(|&entry| unsafe { free_table_tree(entry, 3) })
+            .sum();
+        debug_assert_eq!(
+            freed, self.page_tables,
+            "page tables were mapped outside the address space's mapper"
+        );
+        unsafe {
+            protect::deallocate_page_table(self.root);
+        }
+    }
+}
+
+/// Free the table `entry` points to, a table at `level` (3 for an L3 table),
+/// and every table below it. Returns how many were freed.
+///
+/// # Safety
+///
+/// The tables must be from `protect::allocate_page_table` and no longer in
+/// use.
+unsafe fn free_table_tree(mut entry: PageTableEntry, level: u32) -> usize {
+    let flags = entry.get_flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return 0;
+    }
+    assert!(
+        !flags.contains(PageTableFlags::PAGE_SIZE),
+        "user memory is only mapped with 4 KiB pages"
+    );
+
+    let frame = Frame::new(entry.get_addr());
+    let mut freed = 1;
+    // An L1 table's entries are pages, not tables.
+    if level > 1 {
+        let table = unsafe { &*table_ptr(frame) };
+        freed += table
+            .entries()
+            .iter()
+            .map(|&entry| unsafe { free_table_tree(entry, level - 1) })
+            .sum::<usize>();
+    }
+    unsafe {
+        protect::deallocate_page_table(frame);
+    }
+    freed
+}
+
+/// Whether all of `extent` is mapped for user access in the active address
+/// space, so the kernel can read it on behalf of user code without faulting.
+pub fn is_user_accessible(extent: VirtExtent) -> bool {
+    if !VirtualMap::user().contains(extent) {
+        return false;
+    }
+    if extent.length().as_raw() == 0 {
+        return true;
+    }
+
+    let root = regs::page_table_root();
+    PageRange::containing_extent(extent)
+        .iter()
+        .all(|page| is_user_page(root, page))
+}
+
+/// Whether `page` is mapped for user access by the tables under `root`.
+fn is_user_page(root: PhysAddress, page: Page) -> bool {
+    let mut table = phys_to_virt(root).as_ptr::<PageTable>();
+    let indices = [
+        page.l4_index(),
+        page.l3_index(),
+        page.l2_index(),
+        page.l1_index(),
+    ];
+    for (level, index) in indices.into_iter().enumerate() {
+        // SAFETY: the active tables are reachable through the physical
+        // memory map, and user mappings only change in the process's own
+        // task.
+        let mut entry = unsafe { (*table).entries()[index] };
+        let flags = entry.get_flags();
+        // User memory is only ever mapped with 4 KiB pages.
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER)
+            || flags.contains(PageTableFlags::PAGE_SIZE)
+        {
+            return false;
+        }
+        if level < 3 {
+            table = phys_to_virt(entry.get_addr()).as_ptr();
+        }
+    }
+    true
+}
+
+fn table_ptr(table: Frame) -> *mut PageTable {
+    phys_to_virt(table.start()).as_mut_ptr()
+}
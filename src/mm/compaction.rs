@@ -0,0 +1,89 @@
+//! Freeing contiguous frames by moving user pages
+//!
+//! After long uptimes the free frames are scattered, so allocations of 2^order
+//! frames can fail with plenty of memory free. When one does,
+//! `allocate_frames_from` calls `compact`. It picks the aligned range needing
+//! the fewest moves (see `ZonedFrameAllocator::compaction_candidate`), takes
+//! the range's free frames, and moves the contents of its allocated ones
+//! elsewhere.
+//!
+//! Only frames in the reverse map (see `rmap`) that the running process alone
+//! maps can be moved. Kernel memory, page tables and other address spaces'
+//! frames stay put. If a frame can't be moved after all, e.g. because the
+//! process is locked by the code that is allocating, the frames taken so far
+//! are freed again.
+
+use super::*;
+use crate::process;
+
+use log::debug;
+
+/// The largest order compaction is tried for. The frames taken are tracked
+/// on the stack, since the kernel heap may be what is allocating.
+const MAX_ORDER: usize = 10;
+
+/// Bits for each frame of a range, whether it has been taken.
+struct Taken([u64; (1 << MAX_ORDER) / 64]);
+
+impl Taken {
+    fn get(&self, index: usize) -> bool {
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+}
+
+/// Try to free 2^order frames aligned to 2^order in `zone` by moving user
+/// pages out of the way, and allocate them.
+pub fn compact(order: usize, zone: Zone) -> Option<FrameRange> {
+    if order == 0 || order > MAX_ORDER {
+        return None;
+    }
+
+    // Take the free frames first, so the moved pages land outside the range.
+    let mut taken = Taken([0; (1 << MAX_ORDER) / 64]);
+    let range = {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.get_mut()?;
+        let range = frame_allocator.compaction_candidate(order, zone, rmap::is_movable)?;
+        for (index, frame) in range.iter().enumerate() {
+            if frame_allocator.allocate_at(frame) {
+                taken.set(index);
+            }
+        }
+        range
+    };
+
+    let mut moved = 0;
+    for (index, frame) in range.iter().enumerate() {
+        if taken.get(index) {
+            continue;
+        }
+        // Moving frees the frame. Something else may allocate it before it
+        // is taken, in which case compaction fails.
+        let took = rmap::page(frame).is_some_and(|page| {
+            rmap::is_movable(frame)
+                && process::migrate_frame(page, frame)
+                && FRAME_ALLOCATOR.lock().get_mut().unwrap().allocate_at(frame)
+        });
+        if !took {
+            debug!("compacting {range:?} failed: {frame:?} couldn't be moved");
+            for (index, frame) in range.iter().enumerate() {
+                if taken.get(index) {
+                    // SAFETY: the frame was allocated above.
+                    unsafe {
+                        deallocate_frames(FrameRange::one(frame));
+                    }
+                }
+            }
+            return None;
+        }
+        taken.set(index);
+        moved += 1;
+    }
+
+    debug!("compacted {range:?} for an order {order} allocation, moving {moved} pages");
+    Some(range)
+}
@@ -0,0 +1,211 @@
+//! Memory handed to devices for DMA
+//!
+//! A device writes to physical memory on its own schedule, so freeing a frame
+//! while a transfer into it may still be running lets the device corrupt
+//! whatever the frame is reused for. A `DmaMapping` holds its frames until
+//! the driver says the device is done with them with `complete`. Dropping one
+//! before that is a driver bug: debug builds panic, and release builds keep
+//! the frames until the device is reset, then `free_deferred` frees them.
+//!
+//! There is no IOMMU support, so devices use physical addresses. A device
+//! that can't address all of physical memory, such as one limited to 32 bits,
+//! needs frames from `mm::allocate_owned_frames_from` with a low enough zone.
+//!
+//! `alloc_coherent` is the usual way to get memory for a device: a
+//! `DmaBuffer` of zeroed, physically contiguous frames with both the address
+//! the device uses and the one the kernel uses.
+//!
+//! # Caches and ordering
+//!
+//! The physical memory map is write-back cacheable, and nothing here flushes
+//! caches: on x86, devices' accesses to memory snoop the CPU caches, so DMA is
+//! coherent. What is not automatic is ordering. The compiler may move or merge
+//! plain accesses to a buffer across the port or MMIO write that tells the
+//! device to look at it, or across the read that says the device is done. So
+//! access memory the device shares with volatile reads and writes, and put a
+//! `fence` between filling a buffer and handing it to the device, and between
+//! seeing that the device finished and reading what it wrote. On x86 these
+//! fences cost nothing beyond stopping the compiler reordering, except
+//! `SeqCst`, which is also needed if the device's writes must be seen in
+//! order with the CPU's own.
+
+use super::*;
+
+use ::alloc::vec::Vec;
+
+/// Frames dropped while their device may still have used them, with the
+/// device's name.
+static DEFERRED: spin::Mutex<Vec<(&'static str, OwnedFrameRange)>> = spin::Mutex::new(Vec::new());
+
+/// Frames pinned for a device's use. They are freed on drop, once the driver
+/// has called `complete`.
+pub struct DmaMapping {
+    /// The device's name, for deferring the free.
+    device: &'static str,
+    /// Always `Some` until dropped.
+    frames: Option<OwnedFrameRange>,
+    /// Whether the device is done with the frames.
+    complete: bool,
+}
+
+#[allow(unused)]
+impl DmaMapping {
+    /// Pin `frames` for `device`, which may access them until `complete` is
+    /// called.
+    pub fn new(device: &'static str, frames: OwnedFrameRange) -> Self {
+        DmaMapping {
+            device,
+            frames: Some(frames),
+            complete: false,
+        }
+    }
+
+    /// The frames the device accesses.
+    pub fn frames(&self) -> FrameRange {
+        self.frames.as_ref().unwrap().frames()
+    }
+
+    /// The address the device accesses the frames at.
+    pub fn device_address(&self) -> PhysAddress {
+        self.frames().first().start()
+    }
+
+    /// Where the kernel accesses the frames, in the physical memory map.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.device_address()).as_mut_ptr()
+    }
+
+    /// Record that the device no longer accesses the frames, e.g. because it
+    /// signaled that the transfer finished or it was reset. After this they
+    /// may be freed.
+    pub fn complete(&mut self) {
+        self.complete = true;
+    }
+
+    /// Take back the frames. Panics unless `complete` was called.
+    pub fn into_frames(mut self) -> OwnedFrameRange {
+        assert!(
+            self.complete,
+            "DMA frames for {} taken back while in use",
+            self.device
+        );
+        self.frames.take().unwrap()
+    }
+}
+
+impl Drop for DmaMapping {
+    fn drop(&mut self) {
+        let Some(frames) = self.frames.take() else {
+            return;
+        };
+        if self.complete {
+            return;
+        }
+
+        if cfg!(debug_assertions) {
+            panic!(
+                "DMA mapping of {:?} for {} dropped while in use",
+                frames.frames(),
+                self.device
+            );
+        }
+        log::warn!(
+            "DMA mapping of {:?} for {} dropped while in use; deferring its free",
+            frames.frames(),
+            self.device
+        );
+        crate::diag::snapshot("DMA mapping dropped while in use");
+        DEFERRED.lock().push((self.device, frames));
+    }
+}
+
+/// Physically contiguous memory shared with a device, from
+/// `alloc_coherent`. Like the `DmaMapping` it holds, it must be completed
+/// before it is dropped.
+pub struct DmaBuffer {
+    mapping: DmaMapping,
+    len: usize,
+}
+
+#[allow(unused)]
+impl DmaBuffer {
+    /// The address the device accesses the buffer at.
+    pub fn phys(&self) -> PhysAddress {
+        self.mapping.device_address()
+    }
+
+    /// Where the kernel accesses the buffer, in the physical memory map.
+    pub fn virt(&self) -> VirtAddress {
+        phys_to_virt(self.phys())
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.mapping.as_mut_ptr()
+    }
+
+    /// The length asked for. The frames may be longer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// As `DmaMapping::complete`.
+    pub fn complete(&mut self) {
+        self.mapping.complete();
+    }
+}
+
+/// Allocate `len` bytes of zeroed, physically contiguous memory for `device`,
+/// from `zone` or a lower one. Returns `None` if there isn't enough contiguous
+/// memory. The memory is rounded up to a power of 2 frames.
+pub fn alloc_coherent(device: &'static str, len: usize, zone: Zone) -> Option<DmaBuffer> {
+    let pages = len.max(1).div_ceil(PAGE_SIZE.as_raw() as usize);
+    let order = pages.next_power_of_two().trailing_zeros() as usize;
+    let frames = allocate_owned_frames_from(order, zone)?;
+    let size = frames.frames().count() as usize * PAGE_SIZE.as_raw() as usize;
+    let mapping = DmaMapping::new(device, frames);
+    // SAFETY: the frames were just allocated, and the device doesn't know
+    // about them yet.
+    unsafe {
+        core::ptr::write_bytes(mapping.as_mut_ptr(), 0, size);
+    }
+    Some(DmaBuffer { mapping, len })
+}
+
+/// Free frames whose `DmaMapping`s `device` dropped before completing. Must
+/// only be called once `device` can no longer access them, e.g. after it was
+/// reset.
+#[allow(unused)]
+pub fn free_deferred(device: &'static str) {
+    // Dropping the frames frees them.
+    DEFERRED.lock().retain(|&(owner, _)| owner != device);
+}
+
+kernel_test!(
+    "mm: coherent DMA buffers are zeroed and contiguous",
+    coherent_buffers
+);
+fn coherent_buffers() -> TestResult {
+    let len = 3 * PAGE_SIZE.as_raw() as usize + 1;
+    let mut buffer = alloc_coherent("test", len, Zone::Dma32).ok_or("out of memory")?;
+    let frames = buffer.mapping.frames();
+    if frames.count() != 4 {
+        return Err(format!("{len} bytes got {frames:?}"));
+    }
+    if Zone::containing(frames.last()) > Zone::Dma32 {
+        return Err(format!("{frames:?} is above the DMA32 zone"));
+    }
+    if buffer.virt() != phys_to_virt(frames.first().start()) {
+        return Err("the kernel's address isn't in the physical memory map".into());
+    }
+    // SAFETY: the device never saw the buffer.
+    let bytes = unsafe { core::slice::from_raw_parts(buffer.as_mut_ptr(), len) };
+    if bytes.iter().any(|&b| b != 0) {
+        return Err("the buffer isn't zeroed".into());
+    }
+    buffer.complete();
+    Ok(())
+}
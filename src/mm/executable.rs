@@ -0,0 +1,178 @@
+//! Executable memory for kernel code outside the image
+//!
+//! The heap is in the physical memory map, which is never executable, so code
+//! loaded at runtime (e.g. modules or trampolines) needs its own pages.
+//! `alloc_executable` maps them in `VirtualMap::executable()`, writable but
+//! not executable. Once the code is written, `ExecutableMemory::finish` makes
+//! them executable but read-only. The type state ensures the memory is never
+//! both writable and executable.
+
+use super::*;
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The state of an allocation before `finish`.
+pub struct Writable;
+
+/// The state of an allocation after `finish`.
+#[allow(unused)]
+pub struct Executable;
+
+/// Pages mapped for code, in state `Writable` or `Executable`. Unmapped and
+/// freed on drop.
+#[allow(unused)]
+pub struct ExecutableMemory<State> {
+    pages: PageRange,
+    /// The allocation's length, which may not fill the last page.
+    len: usize,
+    /// Backs `pages`, in the same order.
+    frames: OwnedFrameRange,
+    _state: PhantomData<State>,
+}
+
+/// Where the next allocation goes in `VirtualMap::executable()`. Address
+/// space is not reused after an allocation is freed.
+///
+/// TODO: reuse freed address space once there are enough allocations for it
+/// to matter.
+static NEXT_PAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Allocate zeroed memory for `len` bytes of code, mapped writable. Returns
+/// `None` if out of memory or address space.
+#[allow(unused)]
+pub fn alloc_executable(len: usize) -> Option<ExecutableMemory<Writable>> {
+    assert!(len > 0);
+    let num_pages = (len as u64).div_ceil(PAGE_SIZE.as_raw());
+
+    // Leave an unmapped page after each allocation, so running off the end
+    // faults.
+    let region = PageRange::containing_extent(VirtualMap::executable());
+    let index = NEXT_PAGE.fetch_add(num_pages + 1, Ordering::Relaxed);
+    if index + num_pages > region.count() {
+        return None;
+    }
+    let pages = PageRange::new(region.first().next(index)?, num_pages)?;
+
+    let order = num_pages.next_power_of_two().trailing_zeros() as usize;
+    let frames = allocate_owned_frames(order)?;
+
+    let memory = ExecutableMemory {
+        pages,
+        len,
+        frames,
+        _state: PhantomData,
+    };
+    // SAFETY: the frames were just allocated, and the pages are ours alone.
+    unsafe {
+        memory.map(PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE);
+        core::ptr::write_bytes(
+            pages.first().start().as_mut_ptr::<u8>(),
+            0,
+            (num_pages * PAGE_SIZE.as_raw()) as usize,
+        );
+    }
+    Some(memory)
+}
+
+#[allow(unused)]
+impl<State> ExecutableMemory<State> {
+    /// Where the memory is mapped. Its length is exactly as requested.
+    pub fn extent(&self) -> VirtExtent {
+        VirtExtent::new(
+            self.pages.first().start(),
+            Length::from_raw(self.len as u64),
+        )
+    }
+
+    /// Map the pages to the frames with `flags`, in addition to `PRESENT` and
+    /// `GLOBAL`, replacing any existing mappings.
+    ///
+    /// # Safety
+    /// Nothing may depend on the old mappings' permissions.
+    unsafe fn map(&self, flags: PageTableFlags) {
+        let leaf_flags = flags | PageTableFlags::PRESENT | PageTableFlags::GLOBAL;
+        // Unlike the physical memory map, the parents must allow execution.
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::APP_PARENT_FROZEN;
+
+        protect::with_page_tables_writable(|| {
+            let mut root_table = INIT_PAGE_TABLE.lock();
+            // SAFETY: as in `map_mmio`. The region shares its top-level entry
+            // with the kernel image, so every address space sees the change.
+            let mut mapper = unsafe {
+                paging::Mapper::new(
+                    &mut root_table,
+                    |phys| Some(phys_to_virt(phys)),
+                    protect::allocate_page_table,
+                )
+            };
+            for (page, frame) in self.pages.iter().zip(self.frames.frames().iter()) {
+                unsafe {
+                    mapper
+                        .map(page, frame, leaf_flags, parent_flags, PageTableFlags::all())
+                        .unwrap();
+                }
+            }
+        });
+    }
+}
+
+#[allow(unused)]
+impl ExecutableMemory<Writable> {
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the pages are mapped writable, zeroed on allocation, and
+        // only reachable through `self` until `finish`.
+        unsafe { core::slice::from_raw_parts_mut(self.extent().address().as_mut_ptr(), self.len) }
+    }
+
+    /// Make the memory executable and read-only.
+    pub fn finish(self) -> ExecutableMemory<Executable> {
+        // SAFETY: `self` is consumed, so nothing can write through the old
+        // mapping.
+        unsafe {
+            self.map(PageTableFlags::empty());
+        }
+
+        let this = core::mem::ManuallyDrop::new(self);
+        ExecutableMemory {
+            pages: this.pages,
+            len: this.len,
+            // SAFETY: `this` is never dropped, so the frames are moved.
+            frames: unsafe { core::ptr::read(&this.frames) },
+            _state: PhantomData,
+        }
+    }
+}
+
+#[allow(unused)]
+impl ExecutableMemory<Executable> {
+    /// The start of the code.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.extent().address().as_ptr()
+    }
+}
+
+impl<State> Drop for ExecutableMemory<State> {
+    fn drop(&mut self) {
+        protect::with_page_tables_writable(|| {
+            let mut root_table = INIT_PAGE_TABLE.lock();
+            // SAFETY: as in `map`. Only this allocation's pages change.
+            let mut mapper = unsafe {
+                paging::Mapper::new(
+                    &mut root_table,
+                    |phys| Some(phys_to_virt(phys)),
+                    protect::allocate_page_table,
+                )
+            };
+            for page in self.pages.iter() {
+                unsafe {
+                    mapper.unmap(page).unwrap();
+                }
+            }
+        });
+        // `frames` is freed once the mappings are gone.
+    }
+}
@@ -0,0 +1,204 @@
+//! Page fault dispatch
+//!
+//! The exception handler decodes a page fault into a `PageFault`, and
+//! `dispatch` offers it to each handler in `HANDLERS` in turn. A handler either resolves the fault, so the
+//! access can be retried, declines it, or declares it fatal with a reason.
+//! Faults nothing resolves are reported by `report` before the exception
+//! handler panics.
+
+use super::*;
+use crate::process;
+
+use core::fmt;
+
+use log::error;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// A page fault, decoded from the faulting address and error code.
+#[derive(Clone, Copy, Debug)]
+pub struct PageFault {
+    pub address: VirtAddress,
+    pub access: Access,
+    /// Whether the page was mapped, i.e. the access wasn't allowed rather
+    /// than the page missing.
+    pub present: bool,
+    /// Whether the access was made by user code.
+    pub user: bool,
+}
+
+/// The kind of access that faulted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl PageFault {
+    pub fn new(address: VirtAddress, error_code: u64) -> PageFault {
+        let code = PageFaultErrorCode::from_bits_truncate(error_code);
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        };
+        PageFault {
+            address,
+            access,
+            present: code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            user: code.contains(PageFaultErrorCode::USER_MODE),
+        }
+    }
+
+    fn page(&self) -> Page {
+        Page::containing(self.address)
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.user { "user" } else { "kernel" };
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "instruction fetch",
+        };
+        let page = if self.present {
+            "present"
+        } else {
+            "non-present"
+        };
+        write!(
+            f,
+            "{mode} {access} of {page} page at {:#x}",
+            self.address.as_raw()
+        )
+    }
+}
+
+/// What a handler did with a fault.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The fault is fixed, and the access can be retried.
+    Resolved,
+    /// The fault isn't one the handler deals with.
+    Declined,
+    /// The fault can't be fixed, for the given reason.
+    Fatal(&'static str),
+}
+
+/// A handler for some kind of page fault.
+struct Handler {
+    name: &'static str,
+    handle: fn(&PageFault) -> Outcome,
+}
+
+/// Handlers in the order they are tried. Guard page checks come first so a
+/// kernel overrun is never mistaken for something fixable.
+static HANDLERS: &[Handler] = &[
+    Handler {
+        name: "kernel stack guard",
+        handle: kernel_stack_guard,
+    },
+    Handler {
+        name: "kernel heap guard",
+        handle: kernel_heap_guard,
+    },
+    Handler {
+        name: "copy-on-write",
+        handle: copy_on_write,
+    },
+    Handler {
+        name: "demand paging",
+        handle: demand_paging,
+    },
+    Handler {
+        name: "user stack growth",
+        handle: user_stack_growth,
+    },
+];
+
+/// Offer `fault` to each handler until one doesn't decline it. Returns
+/// `Declined` if all did.
+pub fn dispatch(fault: &PageFault) -> Outcome {
+    for handler in HANDLERS {
+        let outcome = (handler.handle)(fault);
+        if outcome != Outcome::Declined {
+            if let Outcome::Fatal(reason) = outcome {
+                error!("{} handler: {reason}", handler.name);
+            }
+            return outcome;
+        }
+    }
+    Outcome::Declined
+}
+
+/// Log `fault` and how its address is mapped in the active page tables.
+pub fn report(fault: &PageFault) {
+    error!("{fault}");
+
+    let mut table = phys_to_virt(regs::page_table_root()).as_ptr::<PageTable>();
+    let page = fault.page();
+    let indices = [
+        page.l4_index(),
+        page.l3_index(),
+        page.l2_index(),
+        page.l1_index(),
+    ];
+    for (level, index) in (1..=4).rev().zip(indices) {
+        // SAFETY: the active tables are reachable through the physical
+        // memory map.
+        let mut entry = unsafe { (*table).entries()[index] };
+        let flags = entry.get_flags();
+        error!(
+            "L{level} entry {index}: {:#x} {flags:?}",
+            entry.get_addr().as_raw()
+        );
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::PAGE_SIZE) {
+            break;
+        }
+        table = phys_to_virt(entry.get_addr()).as_ptr();
+    }
+}
+
+fn kernel_stack_guard(fault: &PageFault) -> Outcome {
+    if !fault.user && kernel_stack::is_stack_guard(fault.address) {
+        Outcome::Fatal("kernel stack overflow")
+    } else {
+        Outcome::Declined
+    }
+}
+
+fn kernel_heap_guard(fault: &PageFault) -> Outcome {
+    if !fault.user && kernel_heap::is_heap_guard(fault.address) {
+        Outcome::Fatal("kernel heap overrun or use after free")
+    } else {
+        Outcome::Declined
+    }
+}
+
+fn copy_on_write(fault: &PageFault) -> Outcome {
+    resolved_if(
+        fault.present
+            && fault.access == Access::Write
+            && process::handle_write_fault(fault.address),
+    )
+}
+
+fn demand_paging(fault: &PageFault) -> Outcome {
+    resolved_if(!fault.present && process::handle_not_present_fault(fault.address))
+}
+
+fn user_stack_growth(fault: &PageFault) -> Outcome {
+    resolved_if(!fault.present && fault.user && process::grow_stack(fault.address))
+}
+
+fn resolved_if(resolved: bool) -> Outcome {
+    if resolved {
+        Outcome::Resolved
+    } else {
+        Outcome::Declined
+    }
+}
@@ -0,0 +1,219 @@
+//! Backing memory for the kernel heap
+//!
+//! Heap chunks are mapped on demand in `VirtualMap::heap()`, and every run of
+//! mapped chunks has unmapped pages on both sides. Overrunning a large
+//! allocation, or using one after it is freed, faults instead of silently
+//! corrupting other memory, and `is_heap_guard` lets the fault handlers
+//! recognize that.
+//!
+//! The heap grows while arbitrary locks are held, including the kernel page
+//! table's and `protect`'s. So the region's page tables are all allocated by
+//! `init`, and the provider edits their leaf entries directly rather than
+//! through a `Mapper`.
+
+use super::*;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Pages in `VirtualMap::heap()`.
+const REGION_PAGES: usize = (VirtualMap::heap().length().as_raw() / PAGE_SIZE.as_raw()) as usize;
+
+/// Entries in each page table.
+const TABLE_ENTRIES: usize = 512;
+
+/// The L2 table covering the region. It and its L1 tables are never freed.
+static HEAP_L2: spin::Once<PhysAddress> = spin::Once::new();
+
+/// Pages currently mapped for the heap.
+static MAPPED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate the page tables for the whole heap region. Must be called once,
+/// after the kernel's page table is installed and before `protect::init`, so
+/// the tables are protected with the rest.
+pub(super) fn init() {
+    let region = PageRange::containing_extent(VirtualMap::heap());
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::APP_PARENT_FROZEN;
+
+    let mut root_table = INIT_PAGE_TABLE.lock();
+    // SAFETY: as in `map_mmio`. Nothing uses the heap region yet.
+    let mut mapper = unsafe {
+        paging::Mapper::new(
+            &mut root_table,
+            |phys| Some(phys_to_virt(phys)),
+            protect::allocate_page_table,
+        )
+    };
+
+    // Mapping one page per L1 table creates all the tables. Map them to any
+    // frame, then unmap them again.
+    let scratch = allocate_frame().unwrap();
+    for table in region.chunks(TABLE_ENTRIES as u64) {
+        let page = table.first();
+        // SAFETY: the mapping is removed before anything could use it.
+        unsafe {
+            mapper
+                .map(
+                    page,
+                    scratch,
+                    PageTableFlags::PRESENT | PageTableFlags::EXECUTE_DISABLE,
+                    parent_flags,
+                    PageTableFlags::all(),
+                )
+                .unwrap();
+            mapper.unmap(page).unwrap();
+        }
+    }
+
+    let first = region.first();
+    let l3 = root_table.entries()[first.l4_index()];
+    // SAFETY: the tables above were just made, and are all in the physical
+    // memory map.
+    let mut l2 =
+        unsafe { (*phys_to_virt(l3.get_addr()).as_ptr::<PageTable>()).entries()[first.l3_index()] };
+    assert!(l2.get_flags().contains(PageTableFlags::PRESENT));
+    HEAP_L2.call_once(|| l2.get_addr());
+    core::mem::drop(root_table);
+
+    // SAFETY: nothing maps `scratch` anymore.
+    unsafe {
+        deallocate_frames(FrameRange::new(scratch, 1).unwrap());
+    }
+}
+
+/// Whether `address` is in the heap region but not mapped. A kernel fault
+/// there is almost certainly a heap overrun or a use after free.
+pub fn is_heap_guard(address: VirtAddress) -> bool {
+    let region = VirtualMap::heap();
+    if address < region.address() || address >= region.end_address() || HEAP_L2.get().is_none() {
+        return false;
+    }
+    let index = ((address - region.address()).as_raw() / PAGE_SIZE.as_raw()) as usize;
+    // SAFETY: only reads the entry.
+    !unsafe { (*leaf_entry(index)).get_flags() }.contains(PageTableFlags::PRESENT)
+}
+
+/// Bytes of memory backing the heap, including what is free within its
+/// chunks.
+pub fn mapped_bytes() -> u64 {
+    MAPPED_PAGES.load(Ordering::Relaxed) as u64 * PAGE_SIZE.as_raw()
+}
+
+/// The page table entry for the `index`th page of the region.
+fn leaf_entry(index: usize) -> *mut PageTableEntry {
+    assert!(index < REGION_PAGES);
+    let l2 = phys_to_virt(*HEAP_L2.get().unwrap()).as_mut_ptr::<PageTable>();
+    // SAFETY: `init` filled in every L2 entry for the region, and the tables
+    // are in the physical memory map.
+    unsafe {
+        let l1 = phys_to_virt((*l2).entries()[index / TABLE_ENTRIES].get_addr());
+        let l1 = l1.as_mut_ptr::<PageTable>();
+        &mut (*l1).entries_mut()[index % TABLE_ENTRIES]
+    }
+}
+
+fn is_mapped(index: usize) -> bool {
+    // SAFETY: only reads the entry.
+    unsafe { (*leaf_entry(index)).get_flags() }.contains(PageTableFlags::PRESENT)
+}
+
+/// Provides "chunks", i.e. pages, to the heap. Each allocation is mapped in
+/// the heap region to freshly allocated frames, which needn't be contiguous.
+///
+/// Chunks are never executable. Code goes in `executable` instead.
+pub(super) struct HeapProvider {
+    /// Page index to start looking for free space at: just past the last
+    /// allocation.
+    next: usize,
+}
+
+impl HeapProvider {
+    pub const fn new() -> Self {
+        HeapProvider { next: 0 }
+    }
+
+    /// Find `num_pages` unmapped pages with unmapped pages on either side.
+    /// Returns the index of the first.
+    fn find_free(&self, num_pages: usize) -> Option<usize> {
+        let run_len = num_pages + 2;
+        for from in [self.next, 0] {
+            let mut run_start = from;
+            for index in from..REGION_PAGES {
+                if is_mapped(index) {
+                    run_start = index + 1;
+                } else if index + 1 - run_start == run_len {
+                    return Some(run_start + 1);
+                }
+            }
+        }
+        None
+    }
+
+    /// Replace the entry for the `index`th page, which the caller owns, and
+    /// shoot down the old one if it was present. Returns the old entry. Must
+    /// be called in a writable window.
+    fn set_entry(&mut self, index: usize, entry: PageTableEntry) -> PageTableEntry {
+        // SAFETY: the provider's lock serializes edits to the region.
+        let mut old = unsafe { leaf_entry(index).replace(entry) };
+        if old.get_flags().contains(PageTableFlags::PRESENT) {
+            let page = PageRange::containing_extent(VirtualMap::heap())
+                .first()
+                .next(index as u64)
+                .unwrap();
+            tlb::shootdown(PageRange::new(page, 1).unwrap());
+        }
+        old
+    }
+}
+
+unsafe impl heap::ChunkProvider for HeapProvider {
+    fn allocate(&mut self, num_chunks: usize) -> *mut [core::mem::MaybeUninit<u8>] {
+        let leaf_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::EXECUTE_DISABLE;
+
+        let first = self
+            .find_free(num_chunks)
+            .expect("kernel heap region is full");
+        protect::with_page_tables_writable(|| {
+            for index in first..first + num_chunks {
+                let mut entry = PageTableEntry::zero();
+                entry.set_addr(allocate_frame().unwrap().start());
+                entry.set_flags(leaf_flags);
+                self.set_entry(index, entry);
+            }
+        });
+        self.next = first + num_chunks;
+        MAPPED_PAGES.fetch_add(num_chunks, Ordering::Relaxed);
+
+        let ptr: *mut core::mem::MaybeUninit<u8> = PageRange::containing_extent(VirtualMap::heap())
+            .first()
+            .next(first as u64)
+            .unwrap()
+            .start()
+            .as_mut_ptr();
+        core::ptr::slice_from_raw_parts_mut(ptr, num_chunks * PAGE_SIZE.as_raw() as usize)
+    }
+
+    unsafe fn release(&mut self, chunks: *mut [core::mem::MaybeUninit<u8>]) {
+        let offset = VirtAddress::from_ptr(chunks as *const u8) - VirtualMap::heap().address();
+        let first = (offset.as_raw() / PAGE_SIZE.as_raw()) as usize;
+        let num_chunks = chunks.len() / PAGE_SIZE.as_raw() as usize;
+
+        protect::with_page_tables_writable(|| {
+            for index in first..first + num_chunks {
+                let mut old = self.set_entry(index, PageTableEntry::zero());
+                assert!(old.get_flags().contains(PageTableFlags::PRESENT));
+                // SAFETY: the heap gave up the chunk, and it is no longer
+                // mapped.
+                unsafe {
+                    deallocate_frames(FrameRange::new(Frame::new(old.get_addr()), 1).unwrap());
+                }
+            }
+        });
+        MAPPED_PAGES.fetch_sub(num_chunks, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,144 @@
+//! Kernel stacks with guard pages
+//!
+//! Task stacks are mapped in `VirtualMap::kernel_stacks()`, which is divided
+//! into slots of `GUARD_PAGES` unmapped pages followed by a stack. Running off
+//! the bottom of a stack faults on the guard pages instead of silently
+//! corrupting whatever is below it, and `is_stack_guard` lets the fault
+//! handlers recognize that.
+
+use super::*;
+
+use ::alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Pages in each stack.
+pub const KERNEL_STACK_PAGES: u64 = 4;
+
+pub const KERNEL_STACK_LEN: Length = Length::from_raw(KERNEL_STACK_PAGES * PAGE_SIZE.as_raw());
+
+/// Unmapped pages below each stack.
+const GUARD_PAGES: u64 = 1;
+
+const SLOT_PAGES: u64 = GUARD_PAGES + KERNEL_STACK_PAGES;
+
+/// A stack mapped in a slot of `VirtualMap::kernel_stacks()`. Unmapped and
+/// freed on drop.
+pub struct KernelStack {
+    slot: u64,
+    /// Backs the stack's pages, in the same order.
+    frames: OwnedFrameRange,
+}
+
+/// The next slot that has never been used.
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+/// Slots whose stacks were freed, for reuse.
+static FREE_SLOTS: spin::Mutex<Vec<u64>> = spin::Mutex::new(Vec::new());
+
+/// Allocate and map a stack. Returns `None` if out of memory or address space.
+pub fn alloc_kernel_stack() -> Option<KernelStack> {
+    let order = KERNEL_STACK_PAGES.trailing_zeros() as usize;
+    let frames = allocate_owned_frames(order)?;
+
+    let slot = match FREE_SLOTS.lock().pop() {
+        Some(slot) => slot,
+        None => {
+            let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+            let region = PageRange::containing_extent(VirtualMap::kernel_stacks());
+            if (slot + 1) * SLOT_PAGES > region.count() {
+                return None;
+            }
+            slot
+        }
+    };
+
+    let stack = KernelStack { slot, frames };
+    stack.set_mapped(true);
+    Some(stack)
+}
+
+/// Whether `address` is in the kernel stack region but not in a stack. A
+/// kernel fault there is almost certainly a stack overflow.
+pub fn is_stack_guard(address: VirtAddress) -> bool {
+    let region = VirtualMap::kernel_stacks();
+    if address < region.address() || address >= region.end_address() {
+        return false;
+    }
+    let page = (address - region.address()).as_raw() / PAGE_SIZE.as_raw();
+    page % SLOT_PAGES < GUARD_PAGES
+}
+
+/// The stack slot `address` is in, not counting its guard pages. The stack
+/// is only mapped if the slot is in use.
+pub fn stack_slot_containing(address: VirtAddress) -> Option<VirtExtent> {
+    let region = VirtualMap::kernel_stacks();
+    if address < region.address() || address >= region.end_address() || is_stack_guard(address) {
+        return None;
+    }
+    let slot = (address - region.address()).as_raw() / PAGE_SIZE.as_raw() / SLOT_PAGES;
+    let start = region.address() + PAGE_SIZE * (slot * SLOT_PAGES + GUARD_PAGES);
+    Some(VirtExtent::new(start, KERNEL_STACK_LEN))
+}
+
+impl KernelStack {
+    /// Where the stack is mapped.
+    pub fn extent(&self) -> VirtExtent {
+        VirtExtent::new(self.pages().first().start(), KERNEL_STACK_LEN)
+    }
+
+    fn pages(&self) -> PageRange {
+        let region = PageRange::containing_extent(VirtualMap::kernel_stacks());
+        let first = region
+            .first()
+            .next(self.slot * SLOT_PAGES + GUARD_PAGES)
+            .unwrap();
+        PageRange::new(first, KERNEL_STACK_PAGES).unwrap()
+    }
+
+    /// Map or unmap the stack's pages.
+    fn set_mapped(&self, mapped: bool) {
+        let leaf_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::EXECUTE_DISABLE;
+        // The region shares its top-level entry with the kernel image, so the
+        // parents must allow execution.
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::APP_PARENT_FROZEN;
+
+        protect::with_page_tables_writable(|| {
+            let mut root_table = INIT_PAGE_TABLE.lock();
+            // SAFETY: as in `map_mmio`. Only this stack's pages change.
+            let mut mapper = unsafe {
+                paging::Mapper::new(
+                    &mut root_table,
+                    |phys| Some(phys_to_virt(phys)),
+                    protect::allocate_page_table,
+                )
+            };
+            for (page, frame) in self.pages().iter().zip(self.frames.frames().iter()) {
+                // SAFETY: the slot and frames belong to this stack, and
+                // nothing uses it once it is being unmapped.
+                unsafe {
+                    if mapped {
+                        mapper
+                            .map(page, frame, leaf_flags, parent_flags, PageTableFlags::all())
+                            .unwrap();
+                    } else {
+                        mapper.unmap(page).unwrap();
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        self.set_mapped(false);
+        FREE_SLOTS.lock().push(self.slot);
+        // `frames` is freed once the mappings are gone.
+    }
+}
@@ -0,0 +1,121 @@
+//! Cross-check of the kernel's memory map against the boot loader's
+//!
+//! `mm::init` starts from the map GRUB passes and marks the kernel, its
+//! modules and the command line's reservations and limit. Any other change
+//! means something went wrong, e.g. memory lost from the map, or a reservation
+//! that fell outside available memory and so was never marked. `check` logs
+//! every difference and warns about the unexpected ones.
+
+use super::{Map, MemoryType, PhysAddress, PhysExtent};
+
+use core::fmt;
+
+use log::{info, warn};
+use shared::cmdline::MemoryOptions;
+use testos_memory::diff::{self, MapChange};
+
+/// Compare `adjusted`, the kernel's map, with `original`, the boot loader's.
+/// `reserved` are the areas the kernel marked as `KernelLoad`.
+pub fn check(
+    original: &Map,
+    adjusted: &Map,
+    reserved: impl Clone + Iterator<Item = PhysExtent>,
+    options: &MemoryOptions,
+) {
+    let mut changes = 0;
+    let mut unexpected = 0;
+    for change in diff::diff(original.entries(), adjusted.entries()) {
+        changes += 1;
+        if is_expected(change, reserved.clone(), options) {
+            info!("memory map change: {}", Change(change));
+        } else {
+            warn!("unexpected memory map change: {}", Change(change));
+            unexpected += 1;
+        }
+    }
+
+    for (i, a) in reserved.clone().enumerate() {
+        for b in reserved.clone().skip(i + 1) {
+            if a.has_overlap(b) {
+                warn!("reservations {a:x?} and {b:x?} overlap");
+                unexpected += 1;
+            }
+        }
+        let usable = original
+            .entries()
+            .iter()
+            .filter(|e| matches!(e.mem_type, MemoryType::Available | MemoryType::Acpi))
+            .map(|e| e.extent);
+        if !is_covered(a, usable) {
+            warn!("reservation {a:x?} is not all in available memory, so isn't all marked");
+            unexpected += 1;
+        }
+    }
+
+    if unexpected == 0 {
+        info!("memory map: {changes} changes from the boot loader's, all expected");
+    } else {
+        warn!("memory map: {unexpected} unexpected differences from the boot loader's");
+    }
+}
+
+/// Whether `change` is one `mm::init` means to make.
+fn is_expected(
+    change: MapChange,
+    reserved: impl Iterator<Item = PhysExtent>,
+    options: &MemoryOptions,
+) -> bool {
+    if !matches!(
+        change.before,
+        Some(MemoryType::Available | MemoryType::Acpi)
+    ) {
+        return false;
+    }
+    match change.after {
+        Some(MemoryType::KernelLoad) => is_covered(change.extent, reserved),
+        Some(MemoryType::Reserved) => {
+            let above_limit = options.limit.map(|limit| {
+                PhysExtent::from_range_exclusive(limit, PhysAddress::from_raw(u64::MAX))
+            });
+            is_covered(
+                change.extent,
+                options.reserved.iter().copied().chain(above_limit),
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Whether `areas`, which must not overlap, cover all of `extent`.
+fn is_covered(extent: PhysExtent, areas: impl Iterator<Item = PhysExtent>) -> bool {
+    let covered: u64 = areas
+        .filter_map(|area| area.overlap(extent))
+        .map(|overlap| overlap.length().as_raw())
+        .sum();
+    covered == extent.length().as_raw()
+}
+
+/// Formats a change without allocating, since this runs before the heap is
+/// set up.
+struct Change(MapChange);
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}-{:#x} ",
+            self.0.extent.address().as_raw(),
+            self.0.extent.last_address().as_raw()
+        )?;
+        write_type(f, self.0.before)?;
+        f.write_str(" -> ")?;
+        write_type(f, self.0.after)
+    }
+}
+
+fn write_type(f: &mut fmt::Formatter<'_>, mem_type: Option<MemoryType>) -> fmt::Result {
+    match mem_type {
+        Some(mem_type) => write!(f, "{mem_type:?}"),
+        None => f.write_str("absent"),
+    }
+}
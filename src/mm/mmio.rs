@@ -0,0 +1,85 @@
+//! Access to memory-mapped device registers
+//!
+//! `map_mmio` returns a `VolatileRegion` for the registers it mapped, the
+//! kernel's `shared::mmio::Bus`. Its accesses are fenced as that module
+//! describes, and are checked to be in bounds and aligned, so a wrong offset
+//! panics instead of touching whatever is mapped next to the device. The
+//! mapping is uncached, so each access reaches the device in program order.
+
+use super::*;
+
+use shared::mmio::{self, Bus, Register};
+
+/// Device registers mapped by `map_mmio`. The mapping is never removed, so
+/// copies of a region stay valid.
+#[derive(Clone, Copy, Debug)]
+pub struct VolatileRegion {
+    extent: VirtExtent,
+}
+
+// SAFETY: the region is only a range of addresses. Whether concurrent
+// accesses to the device are safe is up to its driver.
+unsafe impl Send for VolatileRegion {}
+unsafe impl Sync for VolatileRegion {}
+
+impl VolatileRegion {
+    /// # Safety
+    ///
+    /// `extent` must be mapped to device registers for as long as the region
+    /// is used, and reading any of them must not have effects that break
+    /// memory safety.
+    pub(super) unsafe fn new(extent: VirtExtent) -> Self {
+        VolatileRegion { extent }
+    }
+
+    /// Where the registers are mapped.
+    pub fn extent(&self) -> VirtExtent {
+        self.extent
+    }
+
+    /// Read the register at `offset` bytes from the start. Panics if it is
+    /// outside the region or misaligned.
+    pub fn read<T: Register>(&self, offset: usize) -> T {
+        // SAFETY: the pointer is in bounds and aligned, and `new`'s caller
+        // vouches for reads.
+        unsafe { mmio::read(self.pointer::<T>(offset)) }
+    }
+
+    /// Write `value` to the register at `offset` bytes from the start. Panics
+    /// if it is outside the region or misaligned.
+    ///
+    /// # Safety
+    ///
+    /// Writing `value` to the register must be safe: for example, it must not
+    /// make the device write to memory it doesn't own.
+    pub unsafe fn write<T: Register>(&self, offset: usize, value: T) {
+        unsafe { mmio::write(self.pointer::<T>(offset), value) }
+    }
+
+    fn pointer<T: Register>(&self, offset: usize) -> *mut T {
+        let size = core::mem::size_of::<T>();
+        assert!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end as u64 <= self.extent.length().as_raw()),
+            "MMIO access of {size} bytes at {offset:#x} is outside {:?}",
+            self.extent
+        );
+        let address = self.extent.address() + Length::from_raw(offset as u64);
+        assert!(
+            address.as_raw().is_multiple_of(size as u64),
+            "MMIO access of {size} bytes at {address:?} is misaligned"
+        );
+        address.as_mut_ptr()
+    }
+}
+
+impl Bus for VolatileRegion {
+    fn read<T: Register>(&self, offset: usize) -> T {
+        VolatileRegion::read(self, offset)
+    }
+
+    unsafe fn write<T: Register>(&self, offset: usize, value: T) {
+        unsafe { VolatileRegion::write(self, offset, value) }
+    }
+}
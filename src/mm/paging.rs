@@ -1,7 +1,10 @@
-use shared::memory::{addr::*, page::*};
+use super::tlb;
+use crate::arch::regs;
+
+use testos_memory::{addr::*, page::*};
 
 use core::ptr;
-use core::sync::atomic::{compiler_fence, Ordering};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
 use static_assertions as sa;
 
@@ -22,6 +25,16 @@ impl PageTable {
             entries: [PageTableEntry::zero(); 512],
         }
     }
+
+    #[inline]
+    pub fn entries(&self) -> &[PageTableEntry; 512] {
+        &self.entries
+    }
+
+    #[inline]
+    pub fn entries_mut(&mut self) -> &mut [PageTableEntry; 512] {
+        &mut self.entries
+    }
 }
 
 // Assert that `PageTable` is 4 KiB.
@@ -67,12 +80,22 @@ impl PageTableEntry {
         PhysAddress::from_raw(self.raw & PAGE_TABLE_ENTRY_ADDR_BITS)
     }
 
-    /// Set flags (as documented in `PageTableFlags`).
+    /// Set flags (as documented in `PageTableFlags`). `EXECUTE_DISABLE` is
+    /// dropped if `disable_no_execute` was called.
     #[inline]
-    pub fn set_flags(&mut self, flags: PageTableFlags) {
+    pub fn set_flags(&mut self, mut flags: PageTableFlags) {
+        if !NO_EXECUTE.load(Ordering::Relaxed) {
+            flags.remove(PageTableFlags::EXECUTE_DISABLE);
+        }
         self.raw |= flags.bits();
     }
 
+    /// Clear flags (as documented in `PageTableFlags`).
+    #[inline]
+    pub fn remove_flags(&mut self, flags: PageTableFlags) {
+        self.raw &= !flags.bits();
+    }
+
     /// Get flags (as documented in `PageTableFlags`).
     #[inline]
     pub fn get_flags(&mut self) -> PageTableFlags {
@@ -85,6 +108,16 @@ impl PageTableEntry {
 
 pub const PAGE_TABLE_ENTRY_ADDR_BITS: u64 = ((1 << 36) - 1) << 12;
 
+/// Whether entries may be marked `EXECUTE_DISABLE`, which is a reserved bit
+/// unless EFER.NXE is set.
+static NO_EXECUTE: AtomicBool = AtomicBool::new(true);
+
+/// Never mark entries `EXECUTE_DISABLE`, since the CPU lacks NX. Must be
+/// called before any page tables are built.
+pub fn disable_no_execute() {
+    NO_EXECUTE.store(false, Ordering::Relaxed);
+}
+
 bitflags::bitflags! {
     /// Control bits for a page table entry. Documented in architecture manual.
     /// Note that some bits may not be valid for some table levels, and not
@@ -113,6 +146,11 @@ bitflags::bitflags! {
         /// `GLOBAL` bit set.
         const APP_PARENT_FROZEN = 1 << 62;
 
+        /// A read-only leaf entry with this bit maps a frame that is shared
+        /// copy-on-write. The first write faults, and the faulting address
+        /// space gets its own writable copy.
+        const APP_COW = 1 << 9;
+
         const DEFAULT_PARENT_TABLE_FLAGS = Self::PRESENT.bits() | Self::WRITABLE.bits();
     }
 }
@@ -167,7 +205,8 @@ where
     /// `parent_set_flags` will be set. If not present, a new table will be
     /// allocated and the parent entry will have `parent_set_flags`.
     ///
-    /// Note that this currently will overwrite any existing leaf entries.
+    /// Note that this currently will overwrite any existing leaf entries. If
+    /// one was present, the old translation is shot down as in `unmap`.
     pub unsafe fn map(
         &mut self,
         page: Page,
@@ -209,18 +248,101 @@ where
             )?
         };
         let mut l1e = PageTableEntry::zero();
-        // TODO: handle existing mapping.
         l1e.set_addr(frame.start());
         l1e.set_flags(leaf_flags);
+        let was_present = l1.entries[page.l1_index()]
+            .get_flags()
+            .contains(PageTableFlags::PRESENT);
         unsafe {
             compiler_fence(Ordering::AcqRel);
             ptr::write_volatile(&mut l1.entries[page.l1_index()] as *mut _, l1e);
             compiler_fence(Ordering::AcqRel);
         }
+        if was_present {
+            self.flush(page);
+        }
 
         Ok(())
     }
 
+    /// Find the frame `page` is mapped to, or `None` if it isn't mapped. Large
+    /// pages are not supported.
+    pub fn translate(&mut self, page: Page) -> Option<Frame> {
+        Some(Frame::new(self.leaf_entry(page)?.get_addr()))
+    }
+
+    /// The present leaf entry mapping `page`, or `None` if it isn't mapped.
+    /// Large pages are not supported.
+    pub fn leaf_entry(&mut self, page: Page) -> Option<PageTableEntry> {
+        let mut table: *const PageTable = &*self.level_4;
+        for index in [page.l4_index(), page.l3_index(), page.l2_index()] {
+            // SAFETY: `table` is the root or was reached through a present
+            // parent entry, and `translator` gives valid addresses per the
+            // contract of `new()`.
+            let mut entry = unsafe { (*table).entries[index] };
+            let flags = entry.get_flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            assert!(!flags.contains(PageTableFlags::PAGE_SIZE));
+            table = (self.translator)(entry.get_addr())?.as_ptr();
+        }
+
+        let mut entry = unsafe { (*table).entries[page.l1_index()] };
+        if !entry.get_flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Remove `page`'s mapping, returning the frame it was mapped to, or `None`
+    /// if it wasn't mapped. Page tables are not freed. Large pages are not
+    /// supported.
+    ///
+    /// If the table may be in use, the old translation is flushed from every
+    /// CPU's TLB before returning.
+    ///
+    /// # Safety
+    /// Nothing may use the mapping anymore.
+    #[allow(unused)]
+    pub unsafe fn unmap(&mut self, page: Page) -> Option<Frame> {
+        let mut table: *mut PageTable = &mut *self.level_4;
+        for index in [page.l4_index(), page.l3_index(), page.l2_index()] {
+            // SAFETY: as in `translate`.
+            let mut entry = unsafe { (*table).entries[index] };
+            let flags = entry.get_flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            assert!(!flags.contains(PageTableFlags::PAGE_SIZE));
+            table = (self.translator)(entry.get_addr())?.as_mut_ptr();
+        }
+
+        let entry = unsafe { &mut (*table).entries[page.l1_index()] };
+        if !entry.get_flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let frame = Frame::new(entry.get_addr());
+        unsafe {
+            compiler_fence(Ordering::AcqRel);
+            ptr::write_volatile(entry as *mut _, PageTableEntry::zero());
+            compiler_fence(Ordering::AcqRel);
+        }
+        self.flush(page);
+        Some(frame)
+    }
+
+    /// Shoot down a changed translation for `page` if any CPU may have it
+    /// cached: the upper half is shared by every address space, and the
+    /// lower half is only in use while the table is active.
+    fn flush(&mut self, page: Page) {
+        let active = (self.translator)(regs::page_table_root())
+            == Some(VirtAddress::from_ptr(&*self.level_4));
+        if page.l4_index() >= 256 || active {
+            tlb::shootdown(PageRange::new(page, 1).unwrap());
+        }
+    }
+
     /// Traverse from `entry` in a parent table to the lower-level table it
     /// points to. If it is not present, fetches a physical memory frame with
     /// `frame_allocator`, places an empty table there, and points `entry` to it
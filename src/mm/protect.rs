@@ -0,0 +1,194 @@
+//! Write protection of the kernel's page tables
+//!
+//! Page tables are reachable through the physical memory map like any other
+//! memory, so a stray write through a bad `phys_to_virt` pointer could
+//! silently corrupt translations. After `init`, every page table frame is
+//! mapped read-only there and supervisor writes honor read-only mappings.
+//! Code that legitimately edits page tables does so inside
+//! `with_page_tables_writable`.
+
+use super::*;
+
+use ::alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+static IS_PROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// Physical address of the L3 table covering the physical memory map. Its L4
+/// entry is shared by all address spaces and never changes.
+static PHYS_MAP_L3: spin::Once<PhysAddress> = spin::Once::new();
+
+/// Page tables allocated in the current writable window. They are protected
+/// when it closes, once nothing is referencing them.
+static NEW_TABLES: spin::Mutex<Vec<Frame>> = spin::Mutex::new(Vec::new());
+
+/// Frames holding page tables, counting those `mm::init` made at boot.
+static PAGE_TABLE_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Write protect all of the kernel's current page tables and enable write
+/// protection for supervisor accesses. Must be called once after `mm::init`.
+pub fn init() {
+    assert!(!IS_PROTECTED.load(Ordering::SeqCst));
+
+    let mut tables = Vec::new();
+    {
+        let root_table = INIT_PAGE_TABLE.lock();
+        let phys_map_l4_index = Page::new(VirtualMap::phys_map().address()).l4_index();
+        PHYS_MAP_L3.call_once(|| root_table.entries()[phys_map_l4_index].get_addr());
+
+        tables.push(Frame::new(kernel_ptr_to_phys_addr(
+            &*root_table as *const PageTable,
+        )));
+        collect_tables(&root_table, 4, &mut tables);
+    }
+
+    interrupts::without_interrupts(|| {
+        for &frame in tables.iter() {
+            // SAFETY: write protection is not enabled yet.
+            unsafe {
+                set_linear_map_writable(frame, false);
+            }
+        }
+        // SAFETY: the kernel no longer writes to read-only memory, other than
+        // page tables in writable windows.
+        unsafe {
+            regs::set_write_protect(true);
+        }
+    });
+
+    IS_PROTECTED.store(true, Ordering::SeqCst);
+    info!("Write protected {} page tables", tables.len());
+}
+
+/// Run `f` with writes to read-only kernel memory allowed, so it can edit page
+/// tables. Interrupts are disabled meanwhile. Page tables allocated with
+/// `allocate_page_table` in `f` are write protected afterwards.
+pub fn with_page_tables_writable<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let was_protected = regs::write_protect();
+        // SAFETY: only clears write protection, which `f` relies on.
+        unsafe {
+            regs::set_write_protect(false);
+        }
+
+        let result = f();
+
+        // Only the outermost window protects new tables. References into them
+        // can't outlive `f`.
+        if was_protected {
+            let new_tables = core::mem::take(&mut *NEW_TABLES.lock());
+            for frame in new_tables {
+                unsafe {
+                    set_linear_map_writable(frame, false);
+                }
+            }
+            unsafe {
+                regs::set_write_protect(true);
+            }
+        }
+
+        result
+    })
+}
+
+/// Allocate a frame for a page table. Once protection is enabled this must be
+/// called in a writable window, and the frame must be released with
+/// `deallocate_page_table`.
+pub fn allocate_page_table() -> Option<Frame> {
+    let frame = allocate_frame()?;
+    PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+    if IS_PROTECTED.load(Ordering::SeqCst) {
+        assert!(
+            !regs::write_protect(),
+            "page table allocated outside a writable window"
+        );
+        NEW_TABLES.lock().push(frame);
+    }
+    Some(frame)
+}
+
+/// Make a page table frame writable again and free it.
+///
+/// # Safety
+///
+/// `frame` must be from `allocate_page_table` and no longer in use.
+pub unsafe fn deallocate_page_table(frame: Frame) {
+    if IS_PROTECTED.load(Ordering::SeqCst) {
+        with_page_tables_writable(|| {
+            NEW_TABLES.lock().retain(|&f| f != frame);
+            unsafe {
+                set_linear_map_writable(frame, true);
+            }
+        });
+    }
+    unsafe {
+        deallocate_frames(FrameRange::new(frame, 1).unwrap());
+    }
+    PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Count `count` page table frames allocated other than by
+/// `allocate_page_table`, e.g. by `mm::init` before the frame allocator
+/// existed.
+pub(super) fn add_page_table_frames(count: u64) {
+    PAGE_TABLE_FRAMES.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Frames currently holding page tables, the kernel's and every address
+/// space's.
+pub fn page_table_frames() -> u64 {
+    PAGE_TABLE_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Push every table below `table`, a table at `level`, onto `tables`.
+fn collect_tables(table: &PageTable, level: u32, tables: &mut Vec<Frame>) {
+    if level == 1 {
+        return;
+    }
+    for mut entry in table.entries().iter().copied() {
+        let flags = entry.get_flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::PAGE_SIZE) {
+            continue;
+        }
+        let frame = Frame::new(entry.get_addr());
+        tables.push(frame);
+        // SAFETY: all page tables are in the physical memory map, and nothing
+        // modifies them while the kernel's root table is locked.
+        let next = unsafe { &*phys_to_virt(frame.start()).as_ptr::<PageTable>() };
+        collect_tables(next, level - 1, tables);
+    }
+}
+
+/// Change whether `frame` is writable through the physical memory map.
+///
+/// # Safety
+///
+/// Write protection must be disabled, and no references to the tables
+/// mapping the physical memory map may be live.
+unsafe fn set_linear_map_writable(frame: Frame, writable: bool) {
+    let page = Page::new(phys_to_virt(frame.start()));
+
+    let mut table = phys_to_virt(*PHYS_MAP_L3.get().unwrap()).as_mut_ptr::<PageTable>();
+    for index in [page.l3_index(), page.l2_index()] {
+        let mut entry = unsafe { (*table).entries()[index] };
+        let flags = entry.get_flags();
+        assert!(
+            flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::PAGE_SIZE),
+            "{frame:?} is not mapped by a 4 KiB page"
+        );
+        table = phys_to_virt(entry.get_addr()).as_mut_ptr();
+    }
+
+    let entry = unsafe { &mut (*table).entries_mut()[page.l1_index()] };
+    assert!(entry.get_flags().contains(PageTableFlags::PRESENT));
+    if writable {
+        entry.set_flags(PageTableFlags::WRITABLE);
+    } else {
+        entry.remove_flags(PageTableFlags::WRITABLE);
+    }
+    // Another CPU with a stale read-only entry would fault writing the frame
+    // once it is freed and reused.
+    tlb::shootdown(PageRange::new(page, 1).unwrap());
+}
@@ -0,0 +1,58 @@
+//! Reverse mappings from frames to the user pages mapping them
+//!
+//! Compaction must find where an allocated frame is mapped to move it.
+//! `AddressSpace` records the page each of its user frames is mapped at, and
+//! the record is cleared when the last `SharedFrame` for the frame is dropped.
+//! A frame shared copy-on-write is mapped by several address spaces, so only
+//! frames with a single reference can be moved. Which address space maps a
+//! frame isn't recorded: compaction only moves the running process's frames,
+//! and checks that they are its own.
+
+use super::*;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The start of the user page each frame is mapped at, or 0 if none is
+/// recorded. User space starts above 0.
+static PAGES: spin::Once<&'static [AtomicU64]> = spin::Once::new();
+
+/// Allocate entries for frames 0 to `num_frames - 1`. Must be called once,
+/// after the frame allocator and the physical memory map are set up.
+pub(super) fn init(num_frames: u64) {
+    // SAFETY: zero is a valid `AtomicU64`.
+    let pages = unsafe { allocate_frame_table::<AtomicU64>(num_frames, "reverse mappings") };
+    PAGES.call_once(|| pages);
+}
+
+fn entry(frame: Frame) -> Option<&'static AtomicU64> {
+    PAGES.get()?.get(usize::try_from(frame.index()).ok()?)
+}
+
+/// Record that `frame` is mapped at the user page `page`.
+pub fn set(frame: Frame, page: Page) {
+    assert!(VirtualMap::user().contains(page.extent()));
+    entry(frame)
+        .expect("mm::init has not run")
+        .store(page.start().as_raw(), Ordering::Relaxed);
+}
+
+/// Forget where `frame` is mapped, e.g. because it is being freed.
+pub fn clear(frame: Frame) {
+    if let Some(entry) = entry(frame) {
+        entry.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The user page `frame` is mapped at, if recorded.
+pub fn page(frame: Frame) -> Option<Page> {
+    match entry(frame)?.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(Page::new(VirtAddress::from_raw(address))),
+    }
+}
+
+/// Whether compaction may be able to move `frame`: it is mapped at a
+/// recorded user page by a single address space.
+pub fn is_movable(frame: Frame) -> bool {
+    page(frame).is_some() && shared_frame::ref_count(frame) == 1
+}
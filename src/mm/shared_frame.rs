@@ -0,0 +1,86 @@
+//! Frames with more than one owner
+//!
+//! `OwnedFrameRange` frees its frames when dropped, so it can't be used for a
+//! frame mapped in several places. A `SharedFrame` is a counted reference to
+//! one instead, using the per-frame counts in `FRAME_REFS`. The frame is freed
+//! when the last reference is dropped.
+
+use super::*;
+
+use core::sync::atomic::AtomicU32;
+
+/// Reference counts for every frame the allocator may hand out.
+static FRAME_REFS: spin::Once<FrameRefCounts<'static>> = spin::Once::new();
+
+/// Allocate counts for frames 0 to `num_frames - 1`. Must be called once,
+/// after the frame allocator and the physical memory map are set up.
+pub(super) fn init(num_frames: u64) {
+    // SAFETY: zero is a valid `AtomicU32`.
+    let counts = unsafe { allocate_frame_table::<AtomicU32>(num_frames, "frame reference counts") };
+    FRAME_REFS.call_once(|| FrameRefCounts::new(counts));
+}
+
+fn frame_refs() -> &'static FrameRefCounts<'static> {
+    FRAME_REFS.get().expect("mm::init has not run")
+}
+
+/// How many `SharedFrame`s refer to `frame`.
+pub fn ref_count(frame: Frame) -> u32 {
+    frame_refs().get(frame)
+}
+
+/// A counted reference to a frame. Cloning adds a reference, and dropping the
+/// last one frees the frame.
+pub struct SharedFrame {
+    frame: Frame,
+}
+
+impl SharedFrame {
+    /// Allocate a frame with a single reference. Returns `None` if out of
+    /// memory.
+    pub fn allocate() -> Option<SharedFrame> {
+        allocate_owned_frames(0).map(SharedFrame::from_owned)
+    }
+
+    /// Share the single frame in `frames`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frames` isn't exactly one frame.
+    pub fn from_owned(frames: OwnedFrameRange) -> SharedFrame {
+        assert_eq!(frames.frames().count(), 1, "only single frames are shared");
+        let frame = frames.frames().first();
+        // The reference takes over freeing the frame.
+        core::mem::forget(frames);
+        assert_eq!(frame_refs().add_ref(frame), 1);
+        SharedFrame { frame }
+    }
+
+    pub fn frame(&self) -> Frame {
+        self.frame
+    }
+
+    /// How many references there are to the frame, including this one.
+    pub fn ref_count(&self) -> u32 {
+        frame_refs().get(self.frame)
+    }
+}
+
+impl Clone for SharedFrame {
+    fn clone(&self) -> Self {
+        frame_refs().add_ref(self.frame);
+        SharedFrame { frame: self.frame }
+    }
+}
+
+impl Drop for SharedFrame {
+    fn drop(&mut self) {
+        if frame_refs().remove_ref(self.frame) == 0 {
+            rmap::clear(self.frame);
+            // SAFETY: this was the last reference.
+            unsafe {
+                deallocate_frames(FrameRange::new(self.frame, 1).unwrap());
+            }
+        }
+    }
+}
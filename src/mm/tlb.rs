@@ -0,0 +1,112 @@
+//! TLB invalidation across CPUs
+//!
+//! Each CPU caches translations in its own TLB, so changing or removing a
+//! mapping another CPU may use must invalidate it there too. `shootdown` does
+//! that: it flushes the pages locally, then sends every other online CPU an
+//! NMI and waits until each has flushed them and acknowledged.
+//!
+//! The request goes out as an NMI rather than on an interrupt vector because
+//! the receivers can't be relied on to have interrupts enabled. Page tables
+//! are edited with interrupts disabled, so two CPUs shooting down at once
+//! would otherwise wait on each other forever, and APs currently halt with
+//! interrupts disabled.
+//!
+//! `paging::Mapper` calls `shootdown` itself when it changes a translation
+//! in an active table. Code editing page table entries directly must do the
+//! same. Adding a translation needs no flush, since the TLB doesn't cache
+//! non-present entries.
+
+use super::*;
+use crate::{apic, smp};
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+/// Above this many pages, flushing the whole TLB is cheaper than flushing
+/// each page.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// Serializes shootdowns, so only one request is out at a time.
+static REQUEST_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// The pages of the request that is out. Only written while holding
+/// `REQUEST_LOCK` and before any CPU is asked to flush.
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Whether each CPU has been asked to flush the request's pages. The CPU
+/// clears its own when it does, which tells its NMI handler apart from
+/// other NMIs.
+static REQUESTED: smp::PerCpu<AtomicBool> =
+    smp::PerCpu::new([const { AtomicBool::new(false) }; smp::MAX_CPUS]);
+
+/// The number of CPUs yet to acknowledge the request.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Flush `page` from the executing CPU's TLB.
+pub fn flush_local(page: Page) {
+    x86_64::instructions::tlb::flush(x86_64::VirtAddr::new(page.start().as_raw()));
+}
+
+/// Flush the executing CPU's whole TLB. The kernel doesn't enable global
+/// pages, so reloading CR3 flushes every entry.
+pub fn flush_all_local() {
+    x86_64::instructions::tlb::flush_all();
+}
+
+fn flush_range_local(pages: PageRange) {
+    if pages.count() > FLUSH_ALL_THRESHOLD {
+        flush_all_local();
+    } else {
+        pages.iter().for_each(flush_local);
+    }
+}
+
+/// Flush `pages` from every online CPU's TLB, returning once all have. Must
+/// not be called from an NMI handler.
+pub fn shootdown(pages: PageRange) {
+    flush_range_local(pages);
+    let num_cpus = smp::num_cpus();
+    if num_cpus == 1 {
+        return;
+    }
+
+    // An interrupt handler shooting down in the middle would find the lock
+    // held by its own CPU.
+    interrupts::without_interrupts(|| {
+        let _request = REQUEST_LOCK.lock();
+        REQUEST_START.store(pages.first().start().as_raw(), Ordering::Relaxed);
+        REQUEST_COUNT.store(pages.count(), Ordering::Relaxed);
+        PENDING.store(num_cpus - 1, Ordering::Relaxed);
+
+        let current = smp::current_cpu();
+        for cpu in (0..num_cpus).filter(|&cpu| cpu != current) {
+            REQUESTED.get_for(cpu).store(true, Ordering::Release);
+            // SAFETY: an NMI only interrupts the CPU, and `handle_nmi`
+            // returns from it once the request is handled.
+            unsafe {
+                apic::send_nmi(smp::apic_id(cpu));
+            }
+        }
+
+        while PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Handle a shootdown request if one is out for the executing CPU. Called
+/// from its NMI handler; returns whether the NMI was the request, as
+/// opposed to a hardware NMI.
+pub fn handle_nmi() -> bool {
+    if !REQUESTED.get().swap(false, Ordering::Acquire) {
+        return false;
+    }
+
+    let start = VirtAddress::from_raw(REQUEST_START.load(Ordering::Relaxed));
+    let count = REQUEST_COUNT.load(Ordering::Relaxed);
+    flush_range_local(PageRange::new(Page::new(start), count).unwrap());
+    PENDING.fetch_sub(1, Ordering::Release);
+    true
+}
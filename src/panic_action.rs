@@ -0,0 +1,136 @@
+//! What the panic handler does once the panic is reported
+//!
+//! Chosen with `panic=` on the command line (see
+//! `shared::cmdline::PanicOptions`): halt, reboot after a delay so a test
+//! machine comes back on its own, or blink the keyboard LEDs and a corner of
+//! the VGA text screen so a panicked machine is easy to spot. Everything here
+//! polls, since interrupts are disabled by then.
+
+use crate::{halt_loop, platform, time};
+
+use core::fmt::Write;
+use core::time::Duration;
+
+use shared::cmdline::PanicAction;
+use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+static ACTION: spin::Once<PanicAction> = spin::Once::new();
+
+/// Choose the action. Until this is called, panics halt.
+pub fn set(action: PanicAction) {
+    ACTION.call_once(|| action);
+}
+
+/// Carry out the action, noting it on `writer`.
+pub fn run(writer: &mut impl Write) -> ! {
+    match ACTION.get().copied().unwrap_or_default() {
+        PanicAction::Halt => halt_loop(),
+        PanicAction::Reboot { delay_secs } => {
+            let _ = writeln!(writer, "rebooting in {delay_secs} seconds");
+            if !time::spin_wait(Duration::from_secs(delay_secs)) {
+                let _ = writeln!(writer, "no clock to wait with; rebooting now");
+            }
+            reset();
+        }
+        PanicAction::Blink => blink(),
+    }
+}
+
+/// The PS/2 controller's ports.
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS_COMMAND: u16 = 0x64;
+
+const PS2_STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Reset the machine: pulse the reset line through the PS/2 controller, and
+/// if that does nothing, triple fault.
+fn reset() -> ! {
+    wait_for_ps2();
+    // SAFETY: nothing else uses the PS/2 controller once we have panicked.
+    unsafe { PortWriteOnly::<u8>::new(PS2_STATUS_COMMAND).write(0xfe) };
+    time::spin_wait(Duration::from_millis(100));
+
+    // With an empty IDT no exception can be delivered, so the breakpoint
+    // escalates to a triple fault, which resets the CPU.
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    halt_loop()
+}
+
+/// How long each blink lasts.
+const BLINK_PERIOD: Duration = Duration::from_millis(500);
+
+/// Spin iterations per blink if there is no clock that runs without
+/// interrupts. Roughly as long, give or take a lot.
+const BLINK_SPINS: u64 = 50_000_000;
+
+fn blink() -> ! {
+    let mut on = false;
+    loop {
+        on = !on;
+        // Scroll, num, and caps lock.
+        set_keyboard_leds(if on { 0b111 } else { 0 });
+        if !cfg!(feature = "framebuffer") {
+            set_vga_indicator(on);
+        }
+        if !time::spin_wait(BLINK_PERIOD) {
+            for _ in 0..BLINK_SPINS {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+fn set_keyboard_leds(leds: u8) {
+    const SET_LEDS: u8 = 0xed;
+    for byte in [SET_LEDS, leds] {
+        wait_for_ps2();
+        // SAFETY: nothing else uses the PS/2 controller once we have
+        // panicked.
+        unsafe { PortWriteOnly::<u8>::new(PS2_DATA).write(byte) };
+    }
+}
+
+/// Wait until the PS/2 controller can take a byte, discarding any it sent,
+/// e.g. the keyboard's acknowledgements. Gives up after a while, in case there
+/// is no controller.
+fn wait_for_ps2() {
+    let mut status = PortReadOnly::<u8>::new(PS2_STATUS_COMMAND);
+    let mut data = PortReadOnly::<u8>::new(PS2_DATA);
+    for _ in 0..100_000 {
+        // SAFETY: as above.
+        let s = unsafe { status.read() };
+        if s & PS2_STATUS_OUTPUT_FULL != 0 {
+            unsafe { data.read() };
+        } else if s & PS2_STATUS_INPUT_FULL == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Show or hide a red `!` in the top right corner of the VGA text screen, if
+/// it was mapped before the panic.
+fn set_vga_indicator(on: bool) {
+    const COLUMNS: usize = 80;
+    let Some(vmem) = platform::mapped_vga_text_buffer() else {
+        return;
+    };
+    let (char, attribute) = if on { (b'!', 0x4f) } else { (b' ', 0x07) };
+    // SAFETY: the cell is inside the text buffer.
+    unsafe {
+        let cell = vmem.add((COLUMNS - 1) * 2);
+        cell.write_volatile(char);
+        cell.add(1).write_volatile(attribute);
+    }
+}
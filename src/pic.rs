@@ -1,13 +1,9 @@
 //! x86 PIC utilities
 
 use spin::Mutex;
-use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::*;
-use x86_64::structures::idt::InterruptStackFrame;
 
-use crate::idt::install_interrupt_handler;
-
-pub type IrqHandlerFunc = fn(stack: InterruptStackFrame);
+use crate::irq::IRQ_INTERRUPT_OFFSET;
 
 struct PicRegs {
     cmd_1: PortWriteOnly<u8>,
@@ -29,13 +25,12 @@ static PIC_REGS: Mutex<PicRegs> = Mutex::new(PicRegs {
     data_2: Port::new(0xa1),
 });
 
-// Interrupts should be disabled before this is called. It is safe to enable
-// interrupts after init().
+/// Remap the PICs' IRQs to start at `IRQ_INTERRUPT_OFFSET` and mask them all.
+/// This is also done before switching to the APIC, so that a stray IRQ from
+/// the PICs can't be mistaken for a CPU exception.
+///
+/// Interrupts must be disabled.
 pub unsafe fn init() {
-    without_interrupts(|| unsafe { init_impl() });
-}
-
-unsafe fn init_impl() {
     let mut pic_regs = PIC_REGS.lock();
 
     unsafe {
@@ -52,55 +47,25 @@ unsafe fn init_impl() {
         // Mask all interrupts
         pic_regs.data_1.write(0b11111111);
         pic_regs.data_2.write(0b11111111);
-
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET, Some(handle_irq0));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 1, Some(handle_irq1));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 2, Some(handle_irq2));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 3, Some(handle_irq3));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 4, Some(handle_irq4));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 5, Some(handle_irq5));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 6, Some(handle_irq6));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 7, Some(handle_irq7));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 8, Some(handle_irq8));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 9, Some(handle_irq9));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 10, Some(handle_irq10));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 11, Some(handle_irq11));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 12, Some(handle_irq12));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 13, Some(handle_irq13));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 14, Some(handle_irq14));
-        install_interrupt_handler(IRQ_INTERRUPT_OFFSET + 15, Some(handle_irq15));
     }
 }
 
-pub fn install_irq_handler(irq_num: u8, maybe_handler: Option<IrqHandlerFunc>) {
+pub fn set_masked(irq_num: u8, masked: bool) {
     assert!(irq_num < IRQS_PER_PIC * 2);
 
-    without_interrupts(|| {
-        {
-            let mut handlers = IRQ_HANDLERS.lock();
-            if let Some(handler) = maybe_handler {
-                assert!(handlers[irq_num as usize].is_none());
-                handlers[irq_num as usize] = Some(handler);
-            } else {
-                handlers[irq_num as usize] = None;
-            }
-        }
-
-        let should_mask_irq = maybe_handler.is_none();
-        let irq_chip = if irq_num < 8 { 0 } else { 1 };
-        let irq_line = irq_num - 8 * irq_chip;
+    let irq_chip = if irq_num < 8 { 0 } else { 1 };
+    let irq_line = irq_num - 8 * irq_chip;
 
-        let mut pic_regs = PIC_REGS.lock();
-        if irq_chip == 0 {
-            unsafe {
-                set_mask(&mut pic_regs.data_1, irq_line, should_mask_irq);
-            }
-        } else {
-            unsafe {
-                set_mask(&mut pic_regs.data_2, irq_line, should_mask_irq);
-            }
+    let mut pic_regs = PIC_REGS.lock();
+    if irq_chip == 0 {
+        unsafe {
+            set_mask(&mut pic_regs.data_1, irq_line, masked);
+        }
+    } else {
+        unsafe {
+            set_mask(&mut pic_regs.data_2, irq_line, masked);
         }
-    });
+    }
 }
 
 unsafe fn set_mask(data_port: &mut Port<u8>, irq_line: u8, set: bool) {
@@ -119,7 +84,7 @@ unsafe fn set_mask(data_port: &mut Port<u8>, irq_line: u8, set: bool) {
 // For various reasons, an IRQ might be invalid in which case we shouldn't
 // respond to the PIC. Only IRQs 7 and 15 may be spurious; in this case, we must
 // ask the PIC which IRQs are currently in service.
-fn is_spurious(irq_num: u8) -> bool {
+pub fn is_spurious(irq_num: u8) -> bool {
     if irq_num != 7 && irq_num != 15 {
         return false;
     }
@@ -137,12 +102,12 @@ fn is_spurious(irq_num: u8) -> bool {
         }
     };
 
-    let is_spurious = isr & 0b10000000 != 0;
+    let is_spurious = isr & 0b10000000 == 0;
 
     // If it's spurious, we shouldn't issue an EOI to the originating PIC.
     // However, if the secondary PIC sent the spurious IRQ (i.e. IRQ 15), we
     // must still send EOI to the primary PIC.
-    if irq_num == 15 {
+    if is_spurious && irq_num == 15 {
         unsafe {
             pic_regs.cmd_1.write(PIC_COMMAND_ACKNOWLEDGE_IRQ);
         }
@@ -151,7 +116,7 @@ fn is_spurious(irq_num: u8) -> bool {
     is_spurious
 }
 
-fn acknowledge_irq(irq_num: u8) {
+pub fn acknowledge_irq(irq_num: u8) {
     let mut pic_regs = PIC_REGS.lock();
 
     unsafe {
@@ -163,97 +128,8 @@ fn acknowledge_irq(irq_num: u8) {
     }
 }
 
-static IRQ_HANDLERS: Mutex<[Option<IrqHandlerFunc>; 16]> = Mutex::new([None; 16]);
-
-// Internal IRQ handlers
-fn handle_irq(irq_num: u8, stack: InterruptStackFrame) {
-    without_interrupts(|| {
-        if is_spurious(irq_num) {
-            return;
-        }
-
-        {
-            let handlers = IRQ_HANDLERS.lock();
-            if let Some(handler) = handlers[irq_num as usize] {
-                handler(stack);
-            } else {
-                panic!("Unhandled IRQ {} received", irq_num);
-            }
-        }
-
-        acknowledge_irq(irq_num);
-    });
-}
-
 const PIC_COMMAND_READ_ISR: u8 = 0x0b;
 const PIC_COMMAND_ACKNOWLEDGE_IRQ: u8 = 0x20;
 
-extern "x86-interrupt" fn handle_irq0(stack: InterruptStackFrame) {
-    handle_irq(0, stack);
-}
-
-extern "x86-interrupt" fn handle_irq1(stack: InterruptStackFrame) {
-    handle_irq(1, stack);
-}
-
-extern "x86-interrupt" fn handle_irq2(stack: InterruptStackFrame) {
-    handle_irq(2, stack);
-}
-
-extern "x86-interrupt" fn handle_irq3(stack: InterruptStackFrame) {
-    handle_irq(3, stack);
-}
-
-extern "x86-interrupt" fn handle_irq4(stack: InterruptStackFrame) {
-    handle_irq(4, stack);
-}
-
-extern "x86-interrupt" fn handle_irq5(stack: InterruptStackFrame) {
-    handle_irq(5, stack);
-}
-
-extern "x86-interrupt" fn handle_irq6(stack: InterruptStackFrame) {
-    handle_irq(6, stack);
-}
-
-extern "x86-interrupt" fn handle_irq7(stack: InterruptStackFrame) {
-    handle_irq(7, stack);
-}
-
-extern "x86-interrupt" fn handle_irq8(stack: InterruptStackFrame) {
-    handle_irq(8, stack);
-}
-
-extern "x86-interrupt" fn handle_irq9(stack: InterruptStackFrame) {
-    handle_irq(9, stack);
-}
-
-extern "x86-interrupt" fn handle_irq10(stack: InterruptStackFrame) {
-    handle_irq(10, stack);
-}
-
-extern "x86-interrupt" fn handle_irq11(stack: InterruptStackFrame) {
-    handle_irq(11, stack);
-}
-
-extern "x86-interrupt" fn handle_irq12(stack: InterruptStackFrame) {
-    handle_irq(12, stack);
-}
-
-extern "x86-interrupt" fn handle_irq13(stack: InterruptStackFrame) {
-    handle_irq(13, stack);
-}
-
-extern "x86-interrupt" fn handle_irq14(stack: InterruptStackFrame) {
-    handle_irq(14, stack);
-}
-
-extern "x86-interrupt" fn handle_irq15(stack: InterruptStackFrame) {
-    handle_irq(15, stack);
-}
-
-// The desired CPU interrupt number for the first IRQ
-pub const IRQ_INTERRUPT_OFFSET: u8 = 32;
-
 // The number of IRQs serviced by each of the two PICs
 const IRQS_PER_PIC: u8 = 8;
@@ -0,0 +1,31 @@
+//! Legacy PC devices at fixed physical addresses
+//!
+//! These are reached through their own mappings, made with `mm::map_mmio` the
+//! first time they are asked for, rather than the identity mapped first MiB.
+
+use crate::mm::{self, PhysExtent};
+
+/// The VGA text buffer: 80x25 cells, each a character and an attribute byte.
+const VGA_TEXT_BUFFER: PhysExtent = PhysExtent::from_raw(0xb8000, 80 * 25 * 2);
+
+/// Where `VGA_TEXT_BUFFER` is mapped, once it is.
+static VGA_TEXT_MAPPING: spin::Once<usize> = spin::Once::new();
+
+/// The VGA text buffer, mapped on the first call. Must not be called before
+/// `mm::init`, or while the kernel's page tables are locked, e.g. from the
+/// panic handler; use `mapped_vga_text_buffer` there.
+// Unused with the `framebuffer` feature, which leaves text mode.
+#[allow(unused)]
+pub fn vga_text_buffer() -> *mut u8 {
+    let address = VGA_TEXT_MAPPING.call_once(|| {
+        // SAFETY: the text buffer is device memory, not RAM.
+        let mapping = unsafe { mm::map_mmio(VGA_TEXT_BUFFER) };
+        mapping.extent().address().as_raw() as usize
+    });
+    *address as *mut u8
+}
+
+/// The VGA text buffer if `vga_text_buffer` has mapped it.
+pub fn mapped_vga_text_buffer() -> Option<*mut u8> {
+    VGA_TEXT_MAPPING.get().map(|&address| address as *mut u8)
+}
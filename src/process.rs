@@ -0,0 +1,590 @@
+//! User processes
+//!
+//! Loads static ELF executables, including static-PIE executables which are
+//! placed at a random address and relocated, into their own address space and
+//! runs them in ring 3.
+
+mod timer;
+
+use crate::arch::gdt;
+use crate::mm::paging::{MapError, PageTableFlags};
+use crate::mm::{self, AddressSpace, Length, Page, PageRange, VirtAddress, VirtExtent, VirtualMap};
+use crate::sched;
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::time::Duration;
+
+use log::info;
+use shared::elf64::{self, Elf, ElfError, FileKind, MapTarget};
+use x86_64::instructions::random::RdRand;
+
+/// The process in user mode. Only init runs, so there is at most one.
+///
+/// TODO: keep this per task once there can be more processes.
+static RUNNING: spin::Mutex<Option<Running>> = spin::Mutex::new(None);
+
+/// What the kernel keeps for the process in user mode.
+struct Running {
+    address_space: AddressSpace,
+    timers: timer::Timers,
+}
+
+/// A user process that has been loaded but not necessarily started.
+pub struct Process {
+    address_space: AddressSpace,
+    entry: VirtAddress,
+    stack_top: VirtAddress,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LoadError {
+    /// The ELF file's headers are malformed.
+    Elf(ElfError),
+    /// The ELF file's contents are malformed, e.g. its relocations.
+    InvalidElf(&'static str),
+    /// The ELF file is valid but not something we can run, e.g. a
+    /// dynamically linked executable.
+    Unsupported(&'static str),
+    /// A segment lies outside of user address space.
+    SegmentOutOfRange,
+    /// The arguments don't fit in the initial stack page.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LoadError::Elf(e) => write!(f, "invalid ELF: {e}"),
+            LoadError::InvalidElf(reason) => write!(f, "invalid ELF: {reason}"),
+            LoadError::Unsupported(reason) => write!(f, "unsupported ELF: {reason}"),
+            LoadError::SegmentOutOfRange => write!(f, "segment outside of user space"),
+            LoadError::ArgumentsTooLong => write!(f, "arguments too long"),
+            LoadError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(_: MapError) -> Self {
+        LoadError::OutOfMemory
+    }
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        LoadError::Elf(e)
+    }
+}
+
+impl Process {
+    /// Create a process from a static executable or static-PIE. Its PT_LOAD
+    /// segments are copied into a new address space, at a random base for a
+    /// static-PIE, and a stack is set up with `args` and an auxiliary vector.
+    pub fn from_elf(elf: &Elf, args: &[&str]) -> Result<Process, LoadError> {
+        if elf
+            .segments()
+            .any(|segment| segment.kind == elf64::PT_INTERP)
+        {
+            return Err(LoadError::Unsupported("dynamically linked executable"));
+        }
+
+        // Added to every address in the file.
+        let load_bias = match elf.kind() {
+            FileKind::Executable => 0,
+            FileKind::SharedObject => choose_load_bias(elf)?,
+        };
+
+        let mut address_space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+
+        elf.load_into(&mut address_space, load_bias)
+            .map_err(|e| match e {
+                elf64::LoadError::OutOfRange => LoadError::SegmentOutOfRange,
+                elf64::LoadError::Target(e) => e,
+            })?;
+        if load_bias != 0 {
+            apply_relocations(&mut address_space, elf, load_bias)?;
+        }
+
+        let stack_pages = PageRange::containing_extent(user_stack());
+        for page in stack_pages.iter() {
+            address_space.map_new_user_page(
+                page,
+                PageTableFlags::WRITABLE | PageTableFlags::EXECUTE_DISABLE,
+            )?;
+        }
+
+        let entry = elf.entry().wrapping_add(load_bias);
+        let mut auxv = Vec::new();
+        if let Some(phdr) = elf.program_headers_address() {
+            auxv.push((AT_PHDR, phdr.wrapping_add(load_bias)));
+        }
+        auxv.push((AT_PHENT, elf.program_header_len() as u64));
+        auxv.push((AT_PHNUM, elf.segment_count() as u64));
+        auxv.push((AT_PAGESZ, mm::PAGE_SIZE.as_raw()));
+        auxv.push((AT_ENTRY, entry));
+        let stack_top = set_up_initial_stack(&mut address_space, args, &auxv)?;
+
+        info!("Loaded executable with load bias {load_bias:#x}");
+        Ok(Process {
+            address_space,
+            entry: VirtAddress::from_raw(entry),
+            stack_top,
+        })
+    }
+
+    /// Switch to the process's address space and jump to its entry point in
+    /// ring 3. Never returns; the calling task is consumed by the process.
+    pub fn run(self) -> ! {
+        info!("Entering user mode at {:x?}", self.entry);
+
+        // Interrupts from ring 3 switch to the stack in the TSS, and system
+        // calls to the one given to the syscall module. Nothing on the current
+        // stack is ever returned to, so anything below the current stack
+        // pointer is free to use.
+        let kernel_rsp: u64;
+        unsafe {
+            asm!("mov {}, rsp", out(reg) kernel_rsp, options(nomem, nostack));
+        }
+        let kernel_stack_top = VirtAddress::from_raw(kernel_rsp).align_down(16);
+        sched::set_user_entry_stack(kernel_stack_top);
+
+        // SAFETY: the address space is kept in `RUNNING` until
+        // `exit_current` switches away from it.
+        unsafe {
+            self.address_space.activate();
+        }
+        let previous = RUNNING.lock().replace(Running {
+            address_space: self.address_space,
+            timers: timer::Timers::new(),
+        });
+        assert!(previous.is_none(), "only one process can run at a time");
+
+        // Build an interrupt return frame and clear all general purpose
+        // registers so no kernel data leaks into user mode.
+        unsafe {
+            asm!(
+                "push {ss}",
+                "push {rsp}",
+                "push {rflags}",
+                "push {cs}",
+                "push {rip}",
+                "xor eax, eax",
+                "xor ebx, ebx",
+                "xor ecx, ecx",
+                "xor edx, edx",
+                "xor esi, esi",
+                "xor edi, edi",
+                "xor ebp, ebp",
+                "xor r8d, r8d",
+                "xor r9d, r9d",
+                "xor r10d, r10d",
+                "xor r11d, r11d",
+                "xor r12d, r12d",
+                "xor r13d, r13d",
+                "xor r14d, r14d",
+                "xor r15d, r15d",
+                // Swap in the user's GS base. Interrupts are enabled again by
+                // `iretq`.
+                "cli",
+                "swapgs",
+                "iretq",
+                ss = in(reg) u64::from(gdt::USER_DATA_SELECTOR.0),
+                rsp = in(reg) self.stack_top.as_raw(),
+                rflags = in(reg) USER_RFLAGS,
+                cs = in(reg) u64::from(gdt::USER_CODE_SELECTOR.0),
+                rip = in(reg) self.entry.as_raw(),
+                options(noreturn),
+            )
+        }
+    }
+}
+
+/// End the running process and the task it runs in, freeing its memory and
+/// page tables.
+pub fn exit_current() -> ! {
+    mm::activate_kernel_address_space();
+    let Running { address_space, .. } = RUNNING.lock().take().expect("no process is running");
+    info!(
+        "Freeing {} frames of the exited process",
+        address_space.frame_count()
+    );
+    drop(address_space);
+    sched::quit_current();
+}
+
+/// Handle a write to `address` that faulted because the page is read-only.
+/// Returns whether it was a copy-on-write page of the running process, which
+/// is now writable so the write can be retried.
+pub fn handle_write_fault(address: VirtAddress) -> bool {
+    with_running_page(address, AddressSpace::handle_write_fault)
+}
+
+/// Handle an access to `address` that faulted because the page isn't mapped.
+/// Returns whether it was untouched anonymous memory of the running process,
+/// which is now backed so the access can be retried.
+pub fn handle_not_present_fault(address: VirtAddress) -> bool {
+    with_running_page(address, AddressSpace::handle_not_present_fault)
+}
+
+/// Move `frame`, mapped at `page`, to a new frame for compaction. Returns
+/// whether it was the running process's, which now uses the new frame.
+pub fn migrate_frame(page: Page, frame: mm::Frame) -> bool {
+    with_running_page(page.start(), |address_space, page| {
+        address_space.migrate_user_page(page, frame)
+    })
+}
+
+/// Handle a user access to `address` that faulted because the page isn't
+/// mapped. Returns whether it is below the running process's stack, within
+/// `MAX_USER_STACK_LEN` of the top, in which case the stack now reaches it.
+pub fn grow_stack(address: VirtAddress) -> bool {
+    with_running_page(address, |address_space, page| {
+        address_space.grow_stack(page, user_stack_reserve())
+    })
+}
+
+/// Create a timer for the running process. Returns its ID, or `None` if the
+/// process has too many.
+pub fn create_timer() -> Option<usize> {
+    with_running_timers(|timers| timers.create())
+}
+
+/// Delete the running process's timer `id`. Returns whether it existed.
+pub fn delete_timer(id: usize) -> bool {
+    with_running_timers(|timers| timers.delete(id))
+}
+
+/// Arm the running process's timer `id` to expire after `initial`, then
+/// every `interval` if that is nonzero, or disarm it if `initial` is zero.
+/// Returns whether the timer exists.
+pub fn set_timer(id: usize, initial: Duration, interval: Duration) -> bool {
+    let now = sched::current_tick();
+    with_running_timers(|timers| {
+        let Some(timer) = timers.get_mut(id) else {
+            return false;
+        };
+        timer.set(
+            now,
+            timer::duration_to_ticks(initial),
+            timer::duration_to_ticks(interval),
+        );
+        true
+    })
+}
+
+/// The number of times the running process's timer `id` has expired since
+/// it was last read. With `wait`, sleeps until it has expired at least once.
+/// Returns `None` if the timer doesn't exist, or if waiting on a disarmed
+/// timer, which would never wake.
+pub fn read_timer(id: usize, wait: bool) -> Option<u64> {
+    loop {
+        let now = sched::current_tick();
+        // The expiries, or the tick to sleep until.
+        let read = with_running_timers(|timers| {
+            let timer = timers.get_mut(id)?;
+            match timer.take_expiries(now) {
+                0 if wait => timer.next_expiry().map(Err),
+                expiries => Some(Ok(expiries)),
+            }
+        })?;
+        match read {
+            Ok(expiries) => return Some(expiries),
+            Err(wake_tick) => sched::sleep_ticks(wake_tick - now),
+        }
+    }
+}
+
+fn with_running_timers<T>(f: impl FnOnce(&mut timer::Timers) -> T) -> T {
+    let mut running = RUNNING.lock();
+    f(&mut running.as_mut().expect("no process is running").timers)
+}
+
+/// Call `handler` with the running process's address space and the page
+/// containing `address`, if it is in user space. Returns false otherwise.
+fn with_running_page(
+    address: VirtAddress,
+    handler: impl FnOnce(&mut AddressSpace, Page) -> bool,
+) -> bool {
+    if !VirtualMap::user().contains(VirtExtent::new(address, Length::from_raw(1))) {
+        return false;
+    }
+    // The fault may have interrupted something holding the lock.
+    let Some(mut running) = RUNNING.try_lock() else {
+        return false;
+    };
+    let Some(running) = running.as_mut() else {
+        return false;
+    };
+    handler(
+        &mut running.address_space,
+        Page::new(address.align_down(mm::PAGE_SIZE.as_raw())),
+    )
+}
+
+/// Segments are copied into newly allocated pages, so they must not share
+/// pages. Linkers normally align segments to pages.
+impl MapTarget for AddressSpace {
+    type Error = LoadError;
+
+    fn map_zeroed(&mut self, address: u64, len: u64, flags: u32) -> Result<(), LoadError> {
+        let extent = user_extent(address, len)?;
+        let mut page_flags = PageTableFlags::empty();
+        if flags & elf64::PF_W != 0 {
+            page_flags |= PageTableFlags::WRITABLE;
+        }
+        if flags & elf64::PF_X == 0 {
+            page_flags |= PageTableFlags::EXECUTE_DISABLE;
+        }
+        for page in PageRange::containing_extent(extent).iter() {
+            self.map_new_user_page(page, page_flags)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: u64, data: &[u8]) -> Result<(), LoadError> {
+        let extent = user_extent(address, data.len() as u64)?;
+        for page in PageRange::containing_extent(extent).iter() {
+            let contents = self
+                .user_page_contents(page)
+                .ok_or(LoadError::SegmentOutOfRange)?;
+            copy_file_data(page, contents, extent.address(), data);
+        }
+        Ok(())
+    }
+}
+
+/// `len` bytes at `address`, if they are all in user space.
+fn user_extent(address: u64, len: u64) -> Result<VirtExtent, LoadError> {
+    VirtExtent::new_checked(VirtAddress::from_raw(address), Length::from_raw(len))
+        .filter(|extent| VirtualMap::user().contains(*extent))
+        .ok_or(LoadError::SegmentOutOfRange)
+}
+
+/// Copy the part of `file_data`, which is loaded at `load_address`, that falls
+/// into `page` into `contents`.
+fn copy_file_data(page: Page, contents: &mut [u8], load_address: VirtAddress, file_data: &[u8]) {
+    if file_data.is_empty() {
+        return;
+    }
+
+    let data_extent = VirtExtent::new(load_address, Length::from_raw(file_data.len() as u64));
+    let Some(overlap) = data_extent.overlap(page.extent()) else {
+        return;
+    };
+
+    let src_offset = (overlap.address() - load_address).as_raw() as usize;
+    let dst_offset = (overlap.address() - page.start()).as_raw() as usize;
+    let len = overlap.length().as_raw() as usize;
+    contents[dst_offset..dst_offset + len]
+        .copy_from_slice(&file_data[src_offset..src_offset + len]);
+}
+
+/// Pick a random page aligned load bias for a static-PIE in the PIE area.
+fn choose_load_bias(elf: &Elf) -> Result<u64, LoadError> {
+    let mut image_end = 0u64;
+    let mut alignment = mm::PAGE_SIZE.as_raw();
+    for segment in elf.segments().filter(elf64::Segment::is_load) {
+        // Validated not to overflow.
+        image_end = image_end.max(segment.address + segment.mem_size);
+        alignment = alignment.max(segment.align);
+    }
+    if image_end > PIE_AREA_LEN / 2 {
+        return Err(LoadError::SegmentOutOfRange);
+    }
+
+    // Any base in the lower half of the area leaves room for the image.
+    let slots = PIE_AREA_LEN / 2 / alignment;
+    Ok(PIE_AREA_START + random_u64() % slots * alignment)
+}
+
+/// Apply the dynamic relocations of a static-PIE loaded at `load_bias`. Only
+/// relative relocations are supported since there is no symbol resolution.
+fn apply_relocations(
+    address_space: &mut AddressSpace,
+    elf: &Elf,
+    load_bias: u64,
+) -> Result<(), LoadError> {
+    let mut rela_address = None;
+    let mut rela_len = 0;
+    let mut rela_entry_len = RELA_ENTRY_LEN;
+    for (tag, value) in elf.dynamic()? {
+        match tag {
+            elf64::DT_NEEDED => return Err(LoadError::Unsupported("needs shared libraries")),
+            elf64::DT_RELA => rela_address = Some(value),
+            elf64::DT_RELASZ => rela_len = value,
+            elf64::DT_RELAENT => rela_entry_len = value,
+            elf64::DT_REL | elf64::DT_RELR | elf64::DT_JMPREL => {
+                return Err(LoadError::Unsupported("relocations other than RELA"))
+            }
+            _ => (),
+        }
+    }
+    let Some(rela_address) = rela_address else {
+        return Ok(());
+    };
+    if rela_entry_len < RELA_ENTRY_LEN {
+        return Err(LoadError::InvalidElf("bad relocation entry size"));
+    }
+
+    let relocations = elf
+        .data_at(rela_address, rela_len)
+        .ok_or(LoadError::InvalidElf("relocations outside of file"))?;
+    for rela in relocations.chunks_exact(rela_entry_len as usize) {
+        let field = |i: usize| u64::from_le_bytes(rela[i * 8..i * 8 + 8].try_into().unwrap());
+        let (offset, info, addend) = (field(0), field(1), field(2));
+        match info & 0xffff_ffff {
+            R_X86_64_NONE => (),
+            R_X86_64_RELATIVE => write_user_u64(
+                address_space,
+                offset.wrapping_add(load_bias),
+                addend.wrapping_add(load_bias),
+            )?,
+            _ => return Err(LoadError::Unsupported("relocation type")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `value` to the already loaded user memory at `address`.
+fn write_user_u64(
+    address_space: &mut AddressSpace,
+    address: u64,
+    value: u64,
+) -> Result<(), LoadError> {
+    let address = VirtAddress::from_raw(address);
+    // Aligned, so it doesn't cross pages.
+    if !address.is_aligned_to(8) {
+        return Err(LoadError::InvalidElf("misaligned relocation"));
+    }
+    let page = Page::containing(address);
+    let contents = address_space
+        .user_page_contents(page)
+        .ok_or(LoadError::InvalidElf("relocation outside of image"))?;
+    let offset = (address - page.start()).as_raw() as usize;
+    contents[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Lay out the top of the already mapped user stack as the System V ABI
+/// specifies: argc, null terminated argv and envp, and the auxiliary vector
+/// `auxv` with `AT_RANDOM` and `AT_NULL` added. The argument strings are
+/// placed above them. There is no environment yet. Returns the initial stack
+/// pointer.
+fn set_up_initial_stack(
+    address_space: &mut AddressSpace,
+    args: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddress, LoadError> {
+    let top = user_stack().end_address();
+    let top_page = Page::containing(top - Length::from_raw(1));
+    let contents = address_space.user_page_contents(top_page).unwrap();
+    let offset_of = |address: VirtAddress| (address - top_page.start()).as_raw() as usize;
+
+    // 16 random bytes, e.g. for stack protector canaries, at the very top.
+    let random_address = top - Length::from_raw(16);
+    let random_offset = offset_of(random_address);
+    contents[random_offset..random_offset + 8].copy_from_slice(&random_u64().to_le_bytes());
+    contents[random_offset + 8..random_offset + 16].copy_from_slice(&random_u64().to_le_bytes());
+
+    // Then the null terminated argument strings. Everything must fit in the
+    // top page, which is all that is initialized here.
+    let strings_len: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    if strings_len > random_offset {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+    let mut string_address = random_address - Length::from_raw(strings_len as u64);
+    let strings_address = string_address;
+
+    // argc, argv, then the argv and envp terminators.
+    let mut words: Vec<u64> = alloc::vec![args.len() as u64];
+    for arg in args {
+        let offset = offset_of(string_address);
+        contents[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+        contents[offset + arg.len()] = 0;
+        words.push(string_address.as_raw());
+        string_address += Length::from_raw(arg.len() as u64 + 1);
+    }
+    words.push(0);
+    words.push(0);
+    for &(kind, value) in auxv
+        .iter()
+        .chain(&[(AT_RANDOM, random_address.as_raw()), (AT_NULL, 0)])
+    {
+        words.push(kind);
+        words.push(value);
+    }
+
+    // The stack pointer must be 16 byte aligned on entry.
+    let words_len = 8 * words.len();
+    if words_len + 15 > offset_of(strings_address) {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+    let rsp = (strings_address - Length::from_raw(words_len as u64)).align_down(16);
+    let mut offset = offset_of(rsp);
+    for word in words {
+        contents[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+        offset += 8;
+    }
+
+    Ok(rsp)
+}
+
+/// Random bits for address space layout randomization and `AT_RANDOM`. Uses
+/// RDRAND when the CPU has it, and otherwise the time stamp counter, which is
+/// guessable but differs from boot to boot.
+fn random_u64() -> u64 {
+    if let Some(random) = RdRand::new().and_then(RdRand::get_u64) {
+        return random;
+    }
+
+    // SAFETY: reading the TSC has no side effects.
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    // splitmix64's finalizer, so nearby counts give unrelated results.
+    let mut z = tsc.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Static-PIE executables are loaded at a random base in this area, far below
+/// the stack.
+const PIE_AREA_START: u64 = 0x0000_1000_0000_0000;
+const PIE_AREA_LEN: u64 = 1 << 40;
+
+const RELA_ENTRY_LEN: u64 = 24;
+
+const R_X86_64_NONE: u64 = 0;
+const R_X86_64_RELATIVE: u64 = 8;
+
+// Auxiliary vector entry types, from the System V ABI.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Where the initial user stack is placed: the top of user address space.
+fn user_stack() -> VirtExtent {
+    let top = VirtualMap::user().end_address();
+    VirtExtent::from_range_exclusive(top - USER_STACK_LEN, top)
+}
+
+/// How far the stack can grow down, mapped on demand below `user_stack()`.
+fn user_stack_reserve() -> VirtExtent {
+    let top = VirtualMap::user().end_address();
+    VirtExtent::from_range_exclusive(top - MAX_USER_STACK_LEN, top)
+}
+
+/// The initially mapped part of the stack.
+const USER_STACK_LEN: Length = Length::from_raw(16 * mm::PAGE_SIZE.as_raw());
+
+const MAX_USER_STACK_LEN: Length = Length::from_raw(8 * 1024 * 1024);
+
+/// RFLAGS on entry to user mode: only the interrupt flag and the always-set
+/// reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
@@ -0,0 +1,42 @@
+//! Timers owned by a user process
+//!
+//! A process creates timers, arms them one-shot or periodic, and reads how
+//! many times each has expired since it last looked, either right away or
+//! after sleeping until the next expiry. They count scheduler ticks, so they
+//! have the timer interrupt's resolution, and go away with the process.
+
+use shared::timer::IntervalTimer;
+
+pub use crate::time::timer::duration_to_ticks;
+
+/// The most timers a process may have at once.
+pub const MAX_TIMERS: usize = 16;
+
+pub struct Timers {
+    slots: [Option<IntervalTimer>; MAX_TIMERS],
+}
+
+impl Timers {
+    pub const fn new() -> Timers {
+        Timers {
+            slots: [None; MAX_TIMERS],
+        }
+    }
+
+    /// Add a disarmed timer and return its ID, or `None` if the process has
+    /// `MAX_TIMERS` already.
+    pub fn create(&mut self) -> Option<usize> {
+        let id = self.slots.iter().position(Option::is_none)?;
+        self.slots[id] = Some(IntervalTimer::new());
+        Some(id)
+    }
+
+    /// Returns whether `id` was a timer.
+    pub fn delete(&mut self, id: usize) -> bool {
+        self.slots.get_mut(id).and_then(Option::take).is_some()
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut IntervalTimer> {
+        self.slots.get_mut(id)?.as_mut()
+    }
+}
@@ -0,0 +1,29 @@
+//! QEMU's isa-debug-exit device, for automated test runs
+//!
+//! QEMU must be started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+//! Writing a value `v` to the device makes QEMU exit with status
+//! `(v << 1) | 1`, so a successful run exits with 1.
+
+use x86_64::instructions::port::PortWriteOnly;
+
+const EXIT_PORT: u16 = 0xf4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ExitCode {
+    /// QEMU exits with status 1.
+    Success = 0,
+    /// QEMU exits with status 3.
+    Failure = 1,
+    /// The kernel panicked. QEMU exits with status 5.
+    Panic = 2,
+}
+
+/// Exit QEMU with `code`. Halts if the device isn't there.
+pub fn exit(code: ExitCode) -> ! {
+    // SAFETY: the port is only used by the exit device.
+    unsafe {
+        PortWriteOnly::<u32>::new(EXIT_PORT).write(code as u32);
+    }
+    crate::halt_loop()
+}
@@ -1,52 +1,133 @@
+mod wait_queue;
+
+pub use shared::sched::{Priority, BOOST_INTERVAL_TICKS};
+pub use wait_queue::WaitQueue;
+
+use crate::arch::fpu::FpuState;
+use crate::arch::gdt;
 use crate::mm;
+use crate::smp::{self, PerCpu, MAX_CPUS};
+use crate::sync::{self, SpinLock};
+use crate::syscall;
+use crate::time::timer::{self, Timer};
+use crate::trace;
+use crate::watchdog;
 
 use core::arch::asm;
+use core::fmt::Write;
 use core::mem;
 use core::num::NonZeroUsize;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use shared::collections::{IntrusiveList, Linked, Links};
+use shared::sched::RunQueue;
+use shared::trace::Event;
 use x86_64::instructions::interrupts;
 
 pub struct Task {
-    /// Owned frames on which the task's kernel stack resides. This task's
+    /// The task's kernel stack, with a guard page below it. This task's
     /// `Task` instance itself resides here.
-    stack_frames: mm::OwnedFrameRange,
+    stack: mm::KernelStack,
 
     /// The last stack pointer, if the task is not currently running.
     rsp: Option<NonZeroUsize>,
 
-    // Scheduler info
-    prev_in_list: Option<TaskPtr>,
-    next_in_list: Option<TaskPtr>,
+    /// The task's x87, SSE and AVX registers, while it isn't running.
+    fpu: FpuState,
+
+    /// Where interrupts and system calls from ring 3 start on the task's
+    /// stack, if it runs user code.
+    user_entry_stack: Option<mm::VirtAddress>,
+
+    priority: Priority,
+
+    /// Shown in logs and panics, if set.
+    name: Option<&'static str>,
+
+    /// The CPU whose ready lists the task goes on. Tasks never move between
+    /// CPUs.
+    cpu: usize,
+
+    /// How many `sync::Mutex`es the task holds. Only tracked in debug
+    /// builds.
+    mutexes_held: usize,
+
+    /// Links for whichever list the task is on: a ready list or a wait
+    /// queue. A task is on at most one at a time.
+    links: Links<Task>,
+}
+
+impl Task {
+    /// The part of the task's stack below the `Task` itself. The lowest word
+    /// holds `STACK_CANARY`.
+    fn stack_extent(&self) -> mm::VirtExtent {
+        let bottom = self.stack.extent().address();
+        mm::VirtExtent::from_range_exclusive(bottom, mm::VirtAddress::from_ptr(self))
+    }
 }
 
+// SAFETY: `links` always returns the same field of the task.
+unsafe impl Linked for Task {
+    fn links(this: NonNull<Self>) -> NonNull<Links<Self>> {
+        unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*this.as_ptr()).links)) }
+    }
+}
+
+// SAFETY: tasks are only accessed through the scheduler's locks.
+unsafe impl Send for Task {}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct TaskPtr(NonNull<Task>);
 
 unsafe impl Send for TaskPtr {}
 
+impl TaskPtr {
+    /// Identifies the task in traces.
+    pub fn id(self) -> u64 {
+        self.0.as_ptr() as u64
+    }
+
+    /// See `current_task_label`.
+    ///
+    /// # Safety
+    ///
+    /// The task must not have quit.
+    unsafe fn label(self) -> TaskLabel {
+        match unsafe { self.0.as_ref().name } {
+            Some(name) => TaskLabel::Named(name),
+            None => TaskLabel::Unnamed(self.id()),
+        }
+    }
+}
+
+/// A CPU's scheduler state. Which task runs next is decided by
+/// `shared::sched`, so the policy can be tested on the host.
 struct Scheduler {
-    ready_list_head: Option<TaskPtr>,
+    ready_tasks: RunQueue<Task>,
 }
 
 pub unsafe fn init_kernel_main_thread(kernel_main: fn() -> !) -> ! {
     // SAFETY: `kernel_main` is a primitive pointer-sized type. It is safe to
     // transmute to `usize`, even as a function argument.
     let mut main_task = unsafe { create_task_typed(kernel_main_init_fn, kernel_main) };
+    unsafe { main_task.0.as_mut().name = Some("kernel_main") };
 
     {
-        let mut current_task = CURRENT_TASK.lock();
+        let mut current_task = CURRENT_TASK.get().lock();
         if current_task.is_some() {
             drop(current_task);
             panic!("current task existed while initializing tasks");
         }
         *current_task = Some(main_task);
     }
+    TASKS_STARTED.store(true, Ordering::Release);
+    sync::epoch::join();
 
     {
-        *SCHEDULER.lock() = Some(Scheduler {
-            ready_list_head: None,
+        *SCHEDULER.get().lock() = Some(Scheduler {
+            ready_tasks: RunQueue::new(),
         });
     }
 
@@ -69,18 +150,207 @@ pub unsafe fn init_kernel_main_thread(kernel_main: fn() -> !) -> ! {
 }
 
 pub fn spawn_kthread(task_fn: extern "C" fn(usize) -> !, context: usize) {
-    let task = create_task(task_fn, context);
+    spawn_kthread_with_priority(task_fn, context, Priority::default());
+}
+
+/// Like `spawn_kthread`, but the task is called `name` in logs and panics.
+/// The returned pointer is only valid until the task quits.
+pub fn spawn_kthread_named(
+    name: &'static str,
+    task_fn: extern "C" fn(usize) -> !,
+    context: usize,
+) -> TaskPtr {
+    let mut task = create_task(task_fn, context);
+    unsafe {
+        task.0.as_mut().name = Some(name);
+        add_task_to_ready_list(task);
+    }
+    task
+}
+
+/// Like `spawn_kthread`, but with a specific priority. The returned pointer
+/// is only valid until the task quits.
+pub fn spawn_kthread_with_priority(
+    task_fn: extern "C" fn(usize) -> !,
+    context: usize,
+    priority: Priority,
+) -> TaskPtr {
+    let mut task = create_task(task_fn, context);
     unsafe {
+        task.0.as_mut().priority = priority;
         add_task_to_ready_list(task);
     }
+    task
+}
+
+/// The currently running task.
+#[allow(unused)]
+pub fn current_task() -> TaskPtr {
+    CURRENT_TASK.get().lock().unwrap()
+}
+
+/// The current task, or `None` if there is none or finding it would mean
+/// waiting for a lock. Safe to call from anywhere, even before the per-CPU
+/// data is set up.
+fn try_current_task() -> Option<TaskPtr> {
+    if !TASKS_STARTED.load(Ordering::Acquire) {
+        return None;
+    }
+    *CURRENT_TASK.get().try_lock()?
+}
+
+/// The current task's name, or `None` if it has none or is unknown, as in
+/// `current_task_label`. Used to label log records.
+pub fn current_task_name() -> Option<&'static str> {
+    // SAFETY: the current task hasn't quit.
+    try_current_task().and_then(|task| unsafe { task.0.as_ref().name })
+}
+
+/// Identifies the current task in logs and panics: by name if it has one,
+/// otherwise by ID as in scheduler traces. Safe to call from exception
+/// handlers.
+pub fn current_task_label() -> TaskLabel {
+    match try_current_task() {
+        // SAFETY: the current task hasn't quit.
+        Some(task) => unsafe { task.label() },
+        None => TaskLabel::Unknown,
+    }
+}
+
+/// Write each CPU's current and ready tasks, and the sleeping tasks, to
+/// `writer`, for the watchdog. Lists whose lock is held are skipped, since
+/// their owner may be stuck. Tasks blocked on a wait queue aren't listed
+/// anywhere, so they are missing.
+pub fn dump_tasks(writer: &mut impl Write) {
+    if !TASKS_STARTED.load(Ordering::Acquire) {
+        let _ = writeln!(writer, "tasks: not started");
+        return;
+    }
+    // SAFETY: tasks on the scheduler's lists haven't quit, and holding the
+    // list's lock keeps them there.
+    let label = |task: NonNull<Task>| unsafe { TaskPtr(task).label() };
+    for cpu in 0..smp::num_cpus() {
+        match CURRENT_TASK.get_for(cpu).try_lock() {
+            Some(task) => match *task {
+                Some(task) => {
+                    let _ = writeln!(writer, "tasks: CPU {cpu} running {}", label(task.0));
+                }
+                None => {
+                    let _ = writeln!(writer, "tasks: CPU {cpu} running nothing");
+                }
+            },
+            None => {
+                let _ = writeln!(writer, "tasks: CPU {cpu} current task locked");
+            }
+        }
+        match SCHEDULER.get_for(cpu).try_lock() {
+            Some(scheduler) => {
+                for task in scheduler.iter().flat_map(|s| s.ready_tasks.iter()) {
+                    let _ = writeln!(writer, "tasks: CPU {cpu} ready {}", label(task));
+                }
+            }
+            None => {
+                let _ = writeln!(writer, "tasks: CPU {cpu} ready list locked");
+            }
+        }
+    }
+    // Sleeping tasks are the timers calling `wake_sleeping_task`.
+    let listed = timer::try_for_each(|deadline, callback, context| {
+        if !core::ptr::fn_addr_eq(callback, wake_sleeping_task as fn(usize)) {
+            return;
+        }
+        // SAFETY: a sleeping task's timer is dropped before it can quit.
+        let task = unsafe { NonNull::new_unchecked(context as *mut Task) };
+        let _ = writeln!(
+            writer,
+            "tasks: sleeping {} until tick {deadline}",
+            label(task)
+        );
+    });
+    if !listed {
+        let _ = writeln!(writer, "tasks: timers locked");
+    }
+}
+
+/// The name of the scheduler lock at `addr`, as recorded by `trace::lock`,
+/// if it is one. The `SpinLock`s aren't tracked by the watchdog, so aren't
+/// named here.
+pub fn lock_name(addr: u64) -> Option<&'static str> {
+    fn is<T>(lock: &spin::Mutex<T>, addr: u64) -> bool {
+        lock as *const _ as u64 == addr
+    }
+    for cpu in 0..smp::num_cpus() {
+        if is(IDLE_TASK.get_for(cpu), addr) {
+            return Some("idle task");
+        }
+    }
+    None
+}
+
+/// See `current_task_label`.
+#[derive(Clone, Copy, Debug)]
+pub enum TaskLabel {
+    Named(&'static str),
+    Unnamed(u64),
+    /// There is no current task, or it can't be found safely.
+    Unknown,
+}
+
+impl core::fmt::Display for TaskLabel {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TaskLabel::Named(name) => write!(f, "'{name}'"),
+            TaskLabel::Unnamed(id) => write!(f, "{id:#x}"),
+            TaskLabel::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Change `task`'s priority. If it is already on a ready list, this takes
+/// effect the next time it is queued.
+///
+/// # Safety
+///
+/// `task` must not have quit.
+#[allow(unused)]
+pub unsafe fn set_priority(mut task: TaskPtr, priority: Priority) {
+    // Taking the scheduler lock serializes this with readers of the priority.
+    let _scheduler = SCHEDULER.get_for(unsafe { task.0.as_ref().cpu }).lock();
+    unsafe {
+        task.0.as_mut().priority = priority;
+    }
+}
+
+/// Count a `sync::Mutex` the current task took (`delta` 1) or released
+/// (`delta` -1), so quitting while holding one can be caught. Only called in
+/// debug builds.
+pub fn count_mutexes_held(delta: isize) {
+    let mut task = current_task();
+    // SAFETY: only the task itself changes its count.
+    unsafe {
+        let held = &mut task.0.as_mut().mutexes_held;
+        *held = held.checked_add_signed(delta).unwrap();
+    }
 }
 
 pub fn quit_current() -> ! {
+    if cfg!(debug_assertions) {
+        let task = current_task();
+        // SAFETY: the current task hasn't quit yet.
+        let held = unsafe { task.0.as_ref().mutexes_held };
+        // Its waiters would block forever.
+        assert_eq!(held, 0, "task {} quit holding {held} mutexes", unsafe {
+            task.label()
+        });
+    }
+    sync::epoch::quiescent();
+
     let (next_task_stack, old_task): (usize, *const Task) = {
-        let mut cur_task_guard = CURRENT_TASK.lock();
+        let mut cur_task_guard = CURRENT_TASK.get().lock();
         let cur_task = &mut *cur_task_guard;
 
         let old_task = cur_task.take().unwrap();
+        check_running_task(old_task);
 
         // We can't clean up the current task on its own stack frame. Dropping
         // the `Task` object effectively invalidates our stack immediately,
@@ -90,7 +360,19 @@ pub fn quit_current() -> ! {
         // function to the top of its stack. This is OK because we know there is
         // always a next task: worst case, it's the idle task.
         let mut next_task = pop_next_ready_task();
+        check_switched_out_task(next_task);
+        *cur_task = Some(next_task);
+        trace::record(Event::Switch {
+            from: old_task.id(),
+            to: next_task.id(),
+        });
+        watchdog::switched();
         let next_task_stack: usize = unsafe { next_task.0.as_mut().rsp.take().unwrap().get() };
+        // The quitting task's registers are discarded.
+        unsafe {
+            next_task.0.as_ref().fpu.restore();
+            load_user_entry_stack(next_task);
+        }
         let mut stack_writer = StackWriter::new(next_task_stack as *mut ());
         let next_task_stack = unsafe {
             stack_writer.push(clean_quit_task as unsafe extern "C" fn(*const Task));
@@ -115,22 +397,93 @@ unsafe extern "C" fn clean_quit_task(task: *const Task) {
     // Read the value out of the task's stack so we can drop it safely (it
     // owns its own stack).
     let task = unsafe { task.read() };
-    assert_eq!(task.next_in_list, None);
-    assert_eq!(task.prev_in_list, None);
+    assert!(!task.links.is_linked());
     assert_eq!(task.rsp, None);
+    // Other CPUs may still have pointers to the `Task` on its stack, e.g.
+    // from `dump_tasks`.
+    sync::defer_free(task);
 }
 
 pub fn yield_current() {
+    switch_current(|task| {
+        // The idle task is picked when nothing else is ready. It must never be
+        // on the ready list itself.
+        if Some(task) != *trace::lock(IDLE_TASK.get()) {
+            unsafe {
+                add_task_to_ready_list(task);
+            }
+        }
+    });
+}
+
+/// Block the current task for at least `ticks` timer ticks.
+pub fn sleep_ticks(ticks: u64) {
+    if ticks == 0 {
+        yield_current();
+        return;
+    }
+
+    let wake_tick = current_tick() + ticks;
+    let task = current_task();
+    // Armed with interrupts disabled, so it can't fire before the task is
+    // switched away from.
+    interrupts::without_interrupts(|| {
+        let timer = Timer::at_tick(wake_tick, wake_sleeping_task, task.0.as_ptr() as usize);
+        switch_current(|_| {});
+        drop(timer);
+    });
+}
+
+/// The callback of a sleeping task's timer. `context` is the task.
+fn wake_sleeping_task(context: usize) {
+    // SAFETY: the task is blocked in `sleep_ticks` until this runs.
+    unsafe {
+        add_task_to_ready_list(TaskPtr(NonNull::new_unchecked(context as *mut Task)));
+    }
+}
+
+/// The number of timer ticks since the timer was started.
+pub fn current_tick() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Advance the tick count, run any timers whose time has come, which makes
+/// sleeping tasks ready, and periodically boost ready tasks. Called from the
+/// timer interrupt handler.
+pub fn timer_tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+
+    if now.is_multiple_of(BOOST_INTERVAL_TICKS) {
+        boost_ready_tasks();
+    }
+
+    interrupts::without_interrupts(|| timer::expire(now));
+}
+
+/// Switch away from the current task to the next ready one. `park` is given
+/// the current task before the next one is chosen and must put it wherever it
+/// will later be found again, e.g. the ready list or a wait queue.
+fn switch_current(park: impl FnOnce(TaskPtr)) {
+    // The task is done with any other task it looked at.
+    sync::epoch::quiescent();
+
     let (mut next_task, mut prev_task) = {
-        let mut cur_task_guard = CURRENT_TASK.lock();
+        let mut cur_task_guard = CURRENT_TASK.get().lock();
         let cur_task = &mut *cur_task_guard;
 
         let prev_task = cur_task.take().unwrap();
-        unsafe {
-            add_task_to_ready_list(prev_task);
-        }
+        check_running_task(prev_task);
+        park(prev_task);
         let next_task = pop_next_ready_task();
         *cur_task = Some(next_task);
+        if next_task != prev_task {
+            check_switched_out_task(next_task);
+            trace::record(Event::Switch {
+                from: prev_task.id(),
+                to: next_task.id(),
+            });
+            watchdog::switched();
+        }
 
         (next_task, prev_task)
     };
@@ -143,44 +496,160 @@ pub fn yield_current() {
     let prev_rsp: *mut usize =
         unsafe { &mut prev_task.0.as_mut().rsp as *mut Option<NonZeroUsize> as *mut usize };
 
+    // The kernel doesn't use the registers itself, so they still hold the
+    // previous task's state.
+    unsafe {
+        prev_task.0.as_mut().fpu.save();
+        next_task.0.as_ref().fpu.restore();
+        load_user_entry_stack(next_task);
+    }
+
     unsafe {
         switch_to(next_rsp, prev_rsp, restore_task_state);
     }
+
+    // Some other task switched back to this one.
+    if cfg!(debug_assertions) {
+        let current_task = *CURRENT_TASK.get().lock();
+        assert_eq!(
+            current_task,
+            Some(prev_task),
+            "resumed a task that isn't current"
+        );
+        check_running_task(prev_task);
+    }
+}
+
+/// Make entries from ring 3 in the current task start with `stack_top` as
+/// their stack, now and whenever the task is switched back to.
+pub fn set_user_entry_stack(stack_top: mm::VirtAddress) {
+    let task = current_task();
+    // SAFETY: only the task itself changes the field.
+    unsafe { (*task.0.as_ptr()).user_entry_stack = Some(stack_top) };
+    gdt::set_kernel_stack(stack_top);
+    syscall::set_kernel_stack(stack_top);
 }
 
+/// Point the TSS and system call entry at `task`'s entry stack, if it has
+/// one, as it is about to run.
+///
+/// # Safety
+///
+/// `task` must not have quit.
+unsafe fn load_user_entry_stack(task: TaskPtr) {
+    if let Some(stack_top) = unsafe { task.0.as_ref().user_entry_stack } {
+        gdt::set_kernel_stack(stack_top);
+        syscall::set_kernel_stack(stack_top);
+    }
+}
+
+/// Check that `task`, which was just running, still owns a valid stack: it
+/// has no saved stack pointer, since the task switching to it took it, and
+/// its stack canary is intact. Only checked in debug builds.
+fn check_running_task(task: TaskPtr) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let task_ref = unsafe { task.0.as_ref() };
+    assert_eq!(
+        task_ref.rsp,
+        None,
+        "running task {:#x} has a saved stack pointer",
+        task.id()
+    );
+    check_stack_canary(task);
+}
+
+/// Check that `task`, about to be switched to, is off every list and its
+/// saved stack pointer is within its stack. Only checked in debug builds.
+fn check_switched_out_task(task: TaskPtr) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let task_ref = unsafe { task.0.as_ref() };
+    assert!(
+        !task_ref.links.is_linked(),
+        "task {:#x} is still queued",
+        task.id()
+    );
+
+    let stack = task_ref.stack_extent();
+    let rsp = task_ref
+        .rsp
+        .map(|rsp| mm::VirtAddress::from_raw(rsp.get() as u64));
+    let Some(rsp) = rsp else {
+        panic!("task {:#x} has no saved stack pointer", task.id());
+    };
+    // The canary is below the lowest valid stack pointer.
+    assert!(
+        rsp > stack.address() && rsp <= stack.end_address(),
+        "task {:#x} saved stack pointer {rsp:?} outside its stack {stack:?}",
+        task.id()
+    );
+    check_stack_canary(task);
+}
+
+/// Panic if `task` overflowed its stack, overwriting the canary at the
+/// bottom.
+fn check_stack_canary(task: TaskPtr) {
+    let canary = unsafe { task.0.as_ref() }
+        .stack_extent()
+        .address()
+        .as_ptr::<u64>();
+    // SAFETY: the task's stack is mapped and `create_task` wrote the canary.
+    let canary = unsafe { canary.read_volatile() };
+    assert_eq!(
+        canary,
+        STACK_CANARY,
+        "task {:#x} overflowed its stack",
+        task.id()
+    );
+}
+
+/// Take the next task from the current CPU's ready lists, or its idle task.
 fn pop_next_ready_task() -> TaskPtr {
-    interrupts::without_interrupts(|| {
-        let mut scheduler_guard = SCHEDULER.lock();
-        let scheduler = scheduler_guard.as_mut().unwrap();
-        if let Some(mut list_head) = scheduler.ready_list_head {
-            let head_task = unsafe { list_head.0.as_mut() };
-            scheduler.ready_list_head = head_task.next_in_list;
-            head_task.next_in_list = None;
-            head_task.prev_in_list = None;
-            list_head
-        } else {
-            IDLE_TASK.lock().unwrap()
-        }
-    })
+    let mut scheduler_guard = SCHEDULER.get().lock();
+    let scheduler = scheduler_guard.as_mut().unwrap();
+    scheduler
+        .ready_tasks
+        .pop()
+        .map(TaskPtr)
+        .unwrap_or_else(|| trace::lock(IDLE_TASK.get()).unwrap())
 }
 
-unsafe fn add_task_to_ready_list(mut task: TaskPtr) {
-    interrupts::without_interrupts(|| {
-        let mut scheduler_guard = SCHEDULER.lock();
-        let scheduler = scheduler_guard.as_mut().unwrap();
-        if let Some(mut list_tail) = scheduler.ready_list_head {
-            while let Some(next) = unsafe { list_tail.0.as_mut().next_in_list } {
-                list_tail = next;
-            }
+/// Whether the current CPU has no ready tasks.
+fn is_ready_list_empty() -> bool {
+    SCHEDULER
+        .get()
+        .lock()
+        .as_ref()
+        .unwrap()
+        .ready_tasks
+        .is_empty()
+}
 
-            unsafe {
-                task.0.as_mut().prev_in_list = Some(list_tail);
-                list_tail.0.as_mut().next_in_list = Some(task);
-            }
-        } else {
-            scheduler.ready_list_head = Some(task);
-        }
-    });
+/// Queue `task` on its CPU's ready lists.
+unsafe fn add_task_to_ready_list(task: TaskPtr) {
+    let (cpu, priority) = unsafe { (task.0.as_ref().cpu, task.0.as_ref().priority) };
+    trace::record(Event::Wake { task: task.id() });
+    let mut scheduler_guard = SCHEDULER.get_for(cpu).lock();
+    let scheduler = scheduler_guard.as_mut().unwrap();
+    unsafe {
+        scheduler.ready_tasks.push(task.0, priority);
+    }
+}
+
+/// Move every ready task on each CPU to the highest priority list, keeping
+/// their relative order. Each returns to its own priority the next time it is
+/// queued.
+fn boost_ready_tasks() {
+    for scheduler in SCHEDULER.iter() {
+        let mut scheduler_guard = scheduler.lock();
+        let Some(scheduler) = scheduler_guard.as_mut() else {
+            continue;
+        };
+        scheduler.ready_tasks.boost();
+    }
 }
 
 #[naked]
@@ -199,7 +668,7 @@ unsafe extern "C" fn switch_to(
             "push r14",
             "push r15",
             "push rdx",
-            "test rax, rax",
+            "test rsi, rsi",
             "jz 2f",
             "mov [rsi], rsp",
             "2:",
@@ -251,17 +720,23 @@ unsafe fn create_task_typed<T>(task_fn: extern "C" fn(T) -> !, context: T) -> Ta
 /// contained on the stack).
 fn create_task(task_fn: extern "C" fn(usize) -> !, context: usize) -> TaskPtr {
     let task = Task {
-        // Allocate 2^1 = 2 frames for the stack.
-        stack_frames: mm::allocate_owned_frames(1).unwrap(),
+        stack: mm::alloc_kernel_stack().unwrap(),
         rsp: None,
-        prev_in_list: None,
-        next_in_list: None,
+        fpu: FpuState::new(),
+        user_entry_stack: None,
+        priority: Priority::default(),
+        name: None,
+        cpu: smp::current_cpu(),
+        mutexes_held: 0,
+        links: Links::new(),
     };
 
-    // For the stack pointer, simply use our direct mapping of physical to virtual memory.
-    let stack_bottom: mm::VirtAddress =
-        mm::phys_to_virt(task.stack_frames.frames().first().start());
-    let stack_top = stack_bottom + mm::Length::from_raw(STACK_LEN as u64);
+    let stack_bottom = task.stack.extent().address();
+    let stack_top = task.stack.extent().end_address();
+    // The canary goes at the very bottom, where an overflow reaches first.
+    unsafe {
+        stack_bottom.as_mut_ptr::<u64>().write(STACK_CANARY);
+    }
 
     // We write three things to the stack, from top downward:
     // 1. the Task instance (which is never accessed by the task),
@@ -303,14 +778,31 @@ unsafe extern "C" fn task_init_trampoline() -> ! {
 #[allow(improper_ctypes_definitions)]
 extern "C" fn kernel_main_init_fn(kernel_main: fn() -> !) -> ! {
     // Now we are in a task context. Set up the idle task.
-    let idle_task = create_task(idle_task_fn, 0);
-    *IDLE_TASK.lock() = Some(idle_task);
+    let mut idle_task = create_task(idle_task_fn, 0);
+    unsafe { idle_task.0.as_mut().name = Some("idle") };
+    *trace::lock(IDLE_TASK.get()) = Some(idle_task);
 
     kernel_main()
 }
 
 extern "C" fn idle_task_fn(_context: usize) -> ! {
-    crate::halt_loop();
+    loop {
+        // Write out any log records that couldn't be written when they were
+        // logged.
+        log::logger().flush();
+
+        // Sleep until an interrupt arrives, unless a task became ready since
+        // we last checked. Checking with interrupts disabled ensures we don't
+        // miss a wakeup between the check and `hlt`.
+        interrupts::disable();
+        if is_ready_list_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+
+        yield_current();
+    }
 }
 
 /// Helper to push values onto a stack, given a stack pointer.
@@ -357,16 +849,26 @@ impl StackWriter {
     }
 }
 
-/// The currently running task. Null before the scheduling system is
+/// The task running on each CPU. Null before the scheduling system is
 /// initialized.
-static CURRENT_TASK: spin::Mutex<Option<TaskPtr>> = spin::Mutex::new(None);
+static CURRENT_TASK: PerCpu<SpinLock<Option<TaskPtr>>> =
+    PerCpu::new([const { SpinLock::new(None) }; MAX_CPUS]);
+
+/// Each CPU's "idle task" which runs when no other task is ready.
+static IDLE_TASK: PerCpu<spin::Mutex<Option<TaskPtr>>> =
+    PerCpu::new([const { spin::Mutex::new(None) }; MAX_CPUS]);
 
-/// The "idle task" which runs when no other task is ready.
-static IDLE_TASK: spin::Mutex<Option<TaskPtr>> = spin::Mutex::new(None);
+/// Each CPU's ready lists. Only the boot CPU's exist for now.
+static SCHEDULER: PerCpu<SpinLock<Option<Scheduler>>> =
+    PerCpu::new([const { SpinLock::new(None) }; MAX_CPUS]);
 
-static SCHEDULER: spin::Mutex<Option<Scheduler>> = spin::Mutex::new(None);
+/// Whether `init_kernel_main_thread` has set the first current task. Before
+/// then, the per-CPU data `CURRENT_TASK` lives in may not be set up.
+static TASKS_STARTED: AtomicBool = AtomicBool::new(false);
 
-pub const STACK_FRAMES_ORDER: usize = 2;
-pub const STACK_FRAMES: usize = 2 << STACK_FRAMES_ORDER;
+/// Timer ticks since the timer was started.
+static TICKS: AtomicU64 = AtomicU64::new(0);
 
-pub const STACK_LEN: usize = STACK_FRAMES * (mm::PAGE_SIZE.as_raw() as usize);
+/// Written at the bottom of each task's stack. Overflowing the stack
+/// overwrites it, which the context switch checks catch.
+const STACK_CANARY: u64 = 0x5354_4143_4b5f_454e;
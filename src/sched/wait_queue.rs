@@ -0,0 +1,90 @@
+//! Wait queues for blocking tasks until an event occurs
+
+use super::*;
+
+/// A FIFO queue of tasks blocked on some event. Tasks are woken by
+/// `wake_one` or `wake_all`, which may be called from interrupt handlers.
+pub struct WaitQueue {
+    waiters: spin::Mutex<IntrusiveList<Task>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: spin::Mutex::new(IntrusiveList::new()),
+        }
+    }
+
+    /// Block the current task until it is woken.
+    ///
+    /// A wakeup that happens before this is called is lost. Prefer
+    /// `wait_until` unless something else guarantees a later wakeup.
+    #[allow(unused)]
+    pub fn wait(&self) {
+        interrupts::without_interrupts(|| self.block_current());
+    }
+
+    /// Block the current task until `condition` returns true. `condition` is
+    /// checked with interrupts disabled, and checked again with the queue
+    /// locked before blocking, so a wakeup from an interrupt handler or
+    /// another CPU cannot be missed between checking it and blocking. Wakers
+    /// must make `condition` true before calling `wake_one` or `wake_all`.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            let mut done = condition();
+            while !done {
+                switch_current(|task| {
+                    let mut waiters = trace::lock(&self.waiters);
+                    if condition() {
+                        // Too late to block, so just yield.
+                        drop(waiters);
+                        done = true;
+                        unsafe { add_task_to_ready_list(task) };
+                    } else {
+                        unsafe { waiters.push_back(task.0) };
+                    }
+                });
+                if !done {
+                    done = condition();
+                }
+            }
+        });
+    }
+
+    /// Make the longest waiting task ready. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let Some(task) = interrupts::without_interrupts(|| self.pop()) else {
+            return false;
+        };
+
+        unsafe {
+            add_task_to_ready_list(task);
+        }
+        true
+    }
+
+    /// Make all waiting tasks ready. Returns how many there were.
+    #[allow(unused)]
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+        while self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Must be called with interrupts disabled.
+    fn block_current(&self) {
+        switch_current(|task| self.push(task));
+    }
+
+    fn push(&self, task: TaskPtr) {
+        unsafe {
+            trace::lock(&self.waiters).push_back(task.0);
+        }
+    }
+
+    fn pop(&self) -> Option<TaskPtr> {
+        trace::lock(&self.waiters).pop_front().map(TaskPtr)
+    }
+}
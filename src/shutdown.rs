@@ -0,0 +1,107 @@
+//! Orderly shutdown
+//!
+//! Subsystems `register` hooks to run before the kernel stops, e.g. to send
+//! output still queued for a device. So far the only way to stop is a test
+//! run exiting QEMU; powering off and rebooting will come through here too
+//! once something does them.
+//! `shutdown` runs them in order, each in its own kernel thread so one that
+//! hangs can't hold up the rest: once a hook's timeout passes, it is logged
+//! as a laggard and the next one runs anyway.
+//!
+//! The panic handler doesn't come through here, even to reboot, since it
+//! can't rely on the scheduler or on anything hooks might lock.
+
+use crate::{qemu, sched, time};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use x86_64::instructions::interrupts;
+
+/// What to do once the hooks have run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Exit QEMU through its debug exit device.
+    ExitQemu(qemu::ExitCode),
+}
+
+/// Something to do before shutting down.
+#[derive(Clone, Copy, Debug)]
+pub struct Hook {
+    /// Names the hook's thread and any timeout warning.
+    pub name: &'static str,
+    /// Hooks run in increasing order, and in the order they were registered
+    /// when it is the same.
+    pub order: u32,
+    /// How long to wait for `run` before moving on.
+    pub timeout: Duration,
+    pub run: fn(),
+}
+
+/// Registered hooks, sorted by `order`.
+static HOOKS: spin::Mutex<Vec<Hook>> = spin::Mutex::new(Vec::new());
+
+/// The hooks being run, each with whether it has finished. Set once shutdown
+/// starts, after which no more hooks can be registered.
+static RUNNING: spin::Once<Vec<(Hook, AtomicBool)>> = spin::Once::new();
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Add `hook` to run on shutdown. Does nothing if shutdown has started.
+pub fn register(hook: Hook) {
+    let mut hooks = HOOKS.lock();
+    if STARTED.load(Ordering::Acquire) {
+        warn!("shutdown has started; not registering {}", hook.name);
+        return;
+    }
+    let index = hooks.partition_point(|other| other.order <= hook.order);
+    hooks.insert(index, hook);
+}
+
+/// Run the registered hooks, then carry out `action`. Must be called from a
+/// task. If another task is already shutting down, the calling task quits.
+pub fn shutdown(action: Action) -> ! {
+    {
+        let _hooks = HOOKS.lock();
+        if STARTED.swap(true, Ordering::AcqRel) {
+            info!("already shutting down");
+            sched::quit_current();
+        }
+    }
+    info!("shutting down: {action:?}");
+
+    let hooks = RUNNING.call_once(|| {
+        core::mem::take(&mut *HOOKS.lock())
+            .into_iter()
+            .map(|hook| (hook, AtomicBool::new(false)))
+            .collect()
+    });
+    for (index, (hook, finished)) in hooks.iter().enumerate() {
+        sched::spawn_kthread_named(hook.name, run_hook, index);
+        let start = time::now();
+        while !finished.load(Ordering::Acquire) {
+            if start.elapsed() >= hook.timeout {
+                warn!(
+                    "shutdown hook {} didn't finish in {:?}; continuing",
+                    hook.name, hook.timeout
+                );
+                break;
+            }
+            sched::sleep_ticks(1);
+        }
+    }
+
+    interrupts::disable();
+    match action {
+        Action::ExitQemu(code) => qemu::exit(code),
+    }
+}
+
+extern "C" fn run_hook(index: usize) -> ! {
+    let (hook, finished) = &RUNNING.get().unwrap()[index];
+    (hook.run)();
+    finished.store(true, Ordering::Release);
+    sched::quit_current();
+}
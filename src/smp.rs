@@ -0,0 +1,422 @@
+//! Multiprocessor support
+//!
+//! The boot CPU starts the application processors (APs) listed in the MADT
+//! with the INIT-SIPI-SIPI sequence. An AP starts in real mode at a page in
+//! low memory holding a small trampoline, which switches straight to long mode
+//! on the kernel's page table and calls `ap_entry` on the AP's own stack.
+//! There it loads its own GDT and TSS and the shared IDT. The trampoline's
+//! frame comes from the low zone and is freed once every AP has left it.
+//!
+//! Each CPU's GS base points to its `CpuLocal` block while in the kernel,
+//! which is how `PerCpu<T>` finds the current CPU's value. Entries from user
+//! mode swap in the kernel's GS base with `swapgs`.
+//!
+//! APs don't run tasks yet. Nothing sends them timer interrupts or wakes them
+//! when a task becomes ready on their lists, so once online they halt with
+//! interrupts disabled.
+
+use crate::acpi;
+use crate::apic;
+use crate::arch::{fpu, gdt, msr};
+use crate::halt_loop;
+use crate::idt;
+use crate::mm::{self, Frame, FrameRange, Length, VirtAddress, Zone};
+use crate::time;
+
+use core::arch::x86_64::__cpuid;
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use shared::acpi::Madt;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::EferFlags;
+
+/// The most CPUs the kernel will use. Any others are left halted.
+pub const MAX_CPUS: usize = 16;
+
+/// A value for each CPU, indexed by the CPU's index. The boot CPU's index is
+/// 0 and the others are numbered in the order they start.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// The current CPU's value. Tasks never move between CPUs, so it stays
+    /// the current CPU's for as long as the caller holds it.
+    pub fn get(&self) -> &T {
+        &self.values[current_cpu()]
+    }
+
+    /// CPU `cpu`'s value.
+    pub fn get_for(&self, cpu: usize) -> &T {
+        &self.values[cpu]
+    }
+
+    /// The value of every CPU, in index order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.values[..num_cpus()].iter()
+    }
+}
+
+/// Per-CPU data the GS base points to in the kernel.
+#[repr(C)]
+struct CpuLocal {
+    /// The CPU's index. `current_cpu` reads it at offset 0.
+    index: usize,
+}
+
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = {
+    let mut locals = [const { CpuLocal { index: 0 } }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        locals[i].index = i;
+        i += 1;
+    }
+    locals
+};
+
+/// The local APIC ID of each CPU.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// The number of CPUs online, including the boot CPU.
+static NUM_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// The index of the CPU executing this.
+pub fn current_cpu() -> usize {
+    let index: usize;
+    // SAFETY: `init_bsp` or `ap_entry` pointed the GS base at this CPU's
+    // `CpuLocal`, and entries from user mode swap it back in.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) index,
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    index
+}
+
+/// The local APIC ID of CPU `cpu`.
+pub fn apic_id(cpu: usize) -> u32 {
+    APIC_IDS[cpu].load(Ordering::Relaxed)
+}
+
+pub fn num_cpus() -> usize {
+    NUM_CPUS.load(Ordering::Acquire)
+}
+
+/// Set up the boot CPU's per-CPU data. Must be called right after
+/// `gdt::init`, since loading segment registers clears the GS base, and before
+/// anything uses `PerCpu`.
+pub fn init_bsp() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, Ordering::SeqCst));
+
+    APIC_IDS[0].store(initial_apic_id(), Ordering::Relaxed);
+    set_gs_base(0);
+}
+
+/// Start the application processors. Must be called after `irq::init`, with
+/// interrupts enabled so the clock advances.
+pub fn init() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, Ordering::SeqCst));
+
+    if !apic::is_enabled() {
+        info!("Not using the APIC; only using the boot CPU");
+        return;
+    }
+    let madt = match acpi::find_table(Madt::SIGNATURE) {
+        Ok(Some(madt)) => Madt::parse(madt),
+        Ok(None) => unreachable!("the APIC is enabled without a MADT"),
+        Err(e) => Err(e),
+    };
+    let madt = match madt {
+        Ok(madt) => madt,
+        Err(e) => {
+            warn!("bad MADT, only using the boot CPU: {e}");
+            return;
+        }
+    };
+
+    let Some(trampoline) = install_trampoline() else {
+        warn!("no low memory for the AP trampoline, only using the boot CPU");
+        return;
+    };
+
+    let mut all_started = true;
+    let bsp_apic_id = APIC_IDS[0].load(Ordering::Relaxed);
+    for apic_id in madt.processor_apic_ids() {
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+        let index = num_cpus();
+        if index == MAX_CPUS {
+            warn!("only using {MAX_CPUS} CPUs");
+            break;
+        }
+        if !start_ap(trampoline, index, apic_id) {
+            // It may still start later and read the boot parameters, so they
+            // can't be reused for another AP.
+            warn!("CPU with APIC ID {apic_id} did not start");
+            all_started = false;
+            break;
+        }
+        NUM_CPUS.store(index + 1, Ordering::Release);
+    }
+
+    if all_started {
+        // SAFETY: every AP that was sent a startup IPI is in `ap_entry`.
+        unsafe {
+            remove_trampoline(trampoline);
+        }
+    } else {
+        // An AP that didn't start in time may still run the trampoline.
+        warn!("leaking the AP trampoline at {:?}", trampoline.start());
+    }
+
+    info!("{} CPUs online", num_cpus());
+}
+
+/// Point the executing CPU's GS base at CPU `index`'s `CpuLocal`. The user
+/// GS base, swapped in when entering user mode, starts as zero.
+fn set_gs_base(index: usize) {
+    // SAFETY: this CPU hasn't used its GS base yet. Long mode, which we are
+    // in, provides both MSRs.
+    unsafe {
+        msr::set_gs_base(VirtAddress::from_ptr(&CPU_LOCALS[index])).unwrap();
+        msr::set_kernel_gs_base(VirtAddress::from_raw(0)).unwrap();
+    }
+}
+
+/// The executing CPU's APIC ID, as reported by CPUID. This matches the MADT
+/// and the local APIC's ID register.
+fn initial_apic_id() -> u32 {
+    if __cpuid(0).eax >= 0xb && __cpuid(0xb).ebx != 0 {
+        // The x2APIC ID, which may not fit in 8 bits.
+        __cpuid(0xb).edx
+    } else {
+        __cpuid(1).ebx >> 24
+    }
+}
+
+/// Order of each AP's stack, in frames.
+const AP_STACK_ORDER: usize = 2;
+
+/// Boot parameters of the AP being started. APs are started one at a time.
+static AP_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+static AP_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Set by the AP being started once it is set up.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Copy the trampoline to a frame in low memory, relocate it there and make
+/// it executable. Returns the frame, or `None` if no low memory is free.
+fn install_trampoline() -> Option<Frame> {
+    let start = unsafe { &ap_trampoline_start as *const u8 };
+    let end = unsafe { &ap_trampoline_end as *const u8 };
+    let len = end as usize - start as usize;
+    assert!(len as u64 <= mm::PAGE_SIZE.as_raw());
+
+    let cr3 = mm::kernel_page_table_phys();
+    assert!(cr3.as_raw() <= u64::from(u32::MAX), "{cr3:?}");
+    // The EFER bits to set, as on the boot CPU.
+    let efer = msr::efer().ok()? & (EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE);
+
+    // A startup IPI gives the CPU only the page number, so the frame must be
+    // below 1 MiB.
+    let frame = mm::allocate_frames_from(0, Zone::Low)?.first();
+    let base = frame.start().as_raw() as u32;
+
+    // The first MiB is identity mapped.
+    let dest = VirtAddress::from_raw(frame.start().as_raw()).as_mut_ptr::<u8>();
+    // The address of `field` in the copy.
+    let field =
+        |field: *const u32| unsafe { dest.add(field as usize - start as usize).cast::<u32>() };
+    unsafe {
+        core::ptr::copy_nonoverlapping(start, dest, len);
+        field(&ap_trampoline_cr3).write_unaligned(cr3.as_raw() as u32);
+        field(&ap_trampoline_efer).write_unaligned(efer.bits() as u32);
+        // These hold offsets from the start, which become addresses.
+        for reloc in [&ap_trampoline_far_jump, &ap_trampoline_gdt_base] {
+            let slot = field(reloc);
+            slot.write_unaligned(slot.read_unaligned() + base);
+        }
+        mm::map_low_code(frame);
+    }
+    Some(frame)
+}
+
+/// Free the trampoline installed by `install_trampoline` in `frame`.
+///
+/// # Safety
+///
+/// No CPU may still be running the trampoline or be about to.
+unsafe fn remove_trampoline(frame: Frame) {
+    unsafe {
+        mm::unmap_low_code(frame);
+        mm::deallocate_frames(FrameRange::new(frame, 1).unwrap());
+    }
+}
+
+/// Start the AP with `apic_id` as CPU `index`, using the trampoline in
+/// `trampoline`, and wait for it to come online. Returns whether it did.
+fn start_ap(trampoline: Frame, index: usize, apic_id: u32) -> bool {
+    let stack = mm::allocate_frames(AP_STACK_ORDER).expect("out of memory for AP stack");
+    let stack_top = mm::phys_to_virt(stack.first().start())
+        + Length::from_raw(stack.count() * mm::PAGE_SIZE.as_raw());
+
+    APIC_IDS[index].store(apic_id, Ordering::Relaxed);
+    AP_INDEX.store(index, Ordering::Relaxed);
+    AP_STACK_TOP.store(stack_top.as_raw(), Ordering::Relaxed);
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    let page = u8::try_from(trampoline.index()).unwrap();
+    unsafe {
+        apic::send_init(apic_id);
+    }
+    wait_until(Duration::from_millis(10), || false);
+
+    // The second startup IPI is only needed by some older CPUs.
+    for timeout in [Duration::from_millis(1), Duration::from_millis(100)] {
+        unsafe {
+            apic::send_startup(apic_id, page);
+        }
+        if wait_until(timeout, || AP_STARTED.load(Ordering::SeqCst)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Busy wait until `condition` returns true or `timeout` passes. Returns
+/// whether `condition` became true.
+fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let start = time::now();
+    loop {
+        if condition() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Where the trampoline continues on an AP, on its own stack with interrupts
+/// disabled.
+extern "C" fn ap_entry() -> ! {
+    let index = AP_INDEX.load(Ordering::SeqCst);
+
+    gdt::init_ap(index);
+    set_gs_base(index);
+    idt::init_ap();
+    fpu::init_ap();
+    unsafe {
+        apic::init_ap();
+    }
+
+    info!(
+        "CPU {} online, APIC ID {}",
+        current_cpu(),
+        APIC_IDS[index].load(Ordering::Relaxed)
+    );
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    // TODO: schedule tasks here once APs get timer interrupts and wakeups.
+    interrupts::disable();
+    halt_loop();
+}
+
+extern "C" {
+    // These are only meaningful by address.
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u32;
+    static ap_trampoline_efer: u32;
+    static ap_trampoline_far_jump: u32;
+    static ap_trampoline_gdt_base: u32;
+}
+
+// The trampoline runs wherever `install_trampoline` copies it, not where it
+// is linked, so it only uses addresses relative to its start until it is in
+// long mode. There it can reach the kernel through the higher half mapping.
+// The two absolute addresses it needs, the far jump target and the GDT base,
+// are assembled as offsets from the start and relocated when installed.
+//
+// It uses a temporary GDT with a 64 bit code segment at 0x08 and a data
+// segment at 0x10. Paging is enabled like on the boot CPU, plus write
+// protection as set up by `mm::protect`.
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".code16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov %cs, %ax",
+    "mov %ax, %ds",
+    // Enable PAE and load the kernel's page table.
+    "mov $0x20, %eax",
+    "mov %eax, %cr4",
+    "mov (ap_trampoline_cr3 - ap_trampoline_start), %eax",
+    "mov %eax, %cr3",
+    // Enable long mode, and no-execute if the boot CPU did.
+    "mov $0xc0000080, %ecx",
+    "rdmsr",
+    "or (ap_trampoline_efer - ap_trampoline_start), %eax",
+    "wrmsr",
+    "lgdtl (ap_trampoline_gdt_pointer - ap_trampoline_start)",
+    // Enable protected mode, paging and write protection together, which
+    // goes straight to long mode.
+    "mov $0x80010001, %eax",
+    "mov %eax, %cr0",
+    "ljmpl *(ap_trampoline_far_jump - ap_trampoline_start)",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov $0x10, %ax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    "movabs ${stack_top}, %rax",
+    "mov (%rax), %rsp",
+    "movabs ${entry}, %rax",
+    "call *%rax",
+    "ud2",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    "ap_trampoline_gdt_pointer:",
+    ".word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1",
+    ".global ap_trampoline_gdt_base",
+    "ap_trampoline_gdt_base:",
+    ".long ap_trampoline_gdt - ap_trampoline_start",
+    ".global ap_trampoline_far_jump",
+    "ap_trampoline_far_jump:",
+    ".long ap_trampoline_long_mode - ap_trampoline_start",
+    ".word 0x08",
+    // Filled in by `install_trampoline`.
+    ".global ap_trampoline_cr3",
+    "ap_trampoline_cr3:",
+    ".long 0",
+    ".global ap_trampoline_efer",
+    "ap_trampoline_efer:",
+    ".long 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+    stack_top = sym AP_STACK_TOP,
+    entry = sym ap_entry,
+    options(att_syntax),
+);
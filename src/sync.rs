@@ -0,0 +1,167 @@
+//! Locks and semaphores
+//!
+//! `SpinLock` is for data shared with interrupt handlers or held only
+//! briefly. `Mutex` and `Semaphore` block the waiting task on a wait queue
+//! instead of spinning, so they suit long waits, but only tasks with
+//! interrupts enabled may wait on them.
+//!
+//! A `spin::Mutex` taken both by tasks and by interrupt handlers deadlocks if
+//! the interrupt arrives on the CPU holding it. `SpinLock` disables interrupts
+//! on the current CPU while held, so that can't happen. In debug builds each
+//! lock also records which CPU holds it and where it was taken, and taking a
+//! lock the current CPU already holds panics instead of spinning forever.
+//!
+//! `SpinLock` acquisitions and releases are recorded like `trace::lock`'s.
+//! The watchdog doesn't track these locks, since its timer interrupt can't arrive while
+//! one is held.
+
+pub mod epoch;
+mod mutex;
+mod semaphore;
+
+pub use epoch::defer_free;
+pub use mutex::Mutex;
+pub use semaphore::Semaphore;
+
+use crate::smp;
+use crate::trace;
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use shared::trace::Event;
+use x86_64::instructions::interrupts;
+
+/// `SpinLock::owner_cpu` when no CPU holds the lock.
+const NO_OWNER: usize = usize::MAX;
+
+pub struct SpinLock<T> {
+    mutex: spin::Mutex<T>,
+    /// In debug builds, the CPU holding the lock, or `NO_OWNER`. The holder is
+    /// that CPU's current task, since tasks can't be switched out with
+    /// interrupts disabled.
+    owner_cpu: AtomicUsize,
+    /// In debug builds, where the holder took the lock.
+    owner_location: AtomicPtr<Location<'static>>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            mutex: spin::Mutex::new(value),
+            owner_cpu: AtomicUsize::new(NO_OWNER),
+            owner_location: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Disable interrupts and take the lock. Interrupts are enabled again
+    /// when the guard is dropped if they were enabled before, so guards must
+    /// be dropped in the reverse order they were taken.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if cfg!(debug_assertions) {
+            self.check_not_held();
+        }
+        let guard = self.mutex.lock();
+        self.acquired(guard, interrupts_were_enabled)
+    }
+
+    /// Like `lock`, but fails instead of waiting if the lock is held,
+    /// including by the current CPU.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.mutex.try_lock() {
+            Some(guard) => Some(self.acquired(guard, interrupts_were_enabled)),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Identifies the lock in traces.
+    pub fn id(&self) -> u64 {
+        self as *const Self as u64
+    }
+
+    /// Panic if the current CPU holds the lock, which would otherwise spin
+    /// forever.
+    #[track_caller]
+    fn check_not_held(&self) {
+        let cpu = smp::current_cpu();
+        if self.owner_cpu.load(Ordering::Relaxed) != cpu {
+            return;
+        }
+        // SAFETY: only this CPU changes the location while it holds the lock,
+        // and it always stores a `&'static Location` first.
+        let location = unsafe { &*self.owner_location.load(Ordering::Relaxed) };
+        panic!("recursive locking: CPU {cpu} already took this lock at {location}");
+    }
+
+    #[track_caller]
+    fn acquired<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        interrupts_were_enabled: bool,
+    ) -> SpinLockGuard<'a, T> {
+        if cfg!(debug_assertions) {
+            let location: *const Location<'static> = Location::caller();
+            self.owner_location
+                .store(location.cast_mut(), Ordering::Relaxed);
+            self.owner_cpu.store(smp::current_cpu(), Ordering::Relaxed);
+        }
+        trace::record(Event::Acquire { lock: self.id() });
+        SpinLockGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            interrupts_were_enabled,
+        }
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.lock.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        }
+        // Recorded before the lock is released, as in `trace::Guard`.
+        trace::record(Event::Release {
+            lock: self.lock.id(),
+        });
+        // SAFETY: the guard isn't used again.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
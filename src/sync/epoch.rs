@@ -0,0 +1,86 @@
+//! Epoch-based deferred freeing
+//!
+//! Something another CPU may still hold a pointer to, like a quit task's
+//! stack, can't be freed right away. `defer_free` queues it instead, and it is
+//! dropped once every CPU running tasks has passed a quiescent point since,
+//! where it holds no pointers into other tasks. Task switches are quiescent
+//! points.
+//!
+//! A global epoch counts up, advancing once every such CPU has passed a
+//! quiescent point in the current one. A value queued in epoch `e` is dropped
+//! in epoch `e + 2`: advancing past `e + 1` needed every CPU to pass a
+//! quiescent point after it was queued.
+
+use super::SpinLock;
+use crate::smp::{self, PerCpu, MAX_CPUS};
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// `SEEN` for a CPU that doesn't run tasks, and so holds no pointers into
+/// them.
+const OFFLINE: u64 = u64::MAX;
+
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The epoch each CPU was in at its last quiescent point.
+static SEEN: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(OFFLINE) }; MAX_CPUS]);
+
+/// Values waiting to be dropped and the epochs they were queued in, oldest
+/// first.
+static DEFERRED: SpinLock<VecDeque<(u64, Box<dyn Send>)>> = SpinLock::new(VecDeque::new());
+
+/// Start tracking the current CPU, which is about to run tasks. Until it
+/// does, nothing queued waits for it.
+pub fn join() {
+    let seen = SEEN.get();
+    assert_eq!(seen.load(Ordering::Relaxed), OFFLINE, "CPU joined twice");
+    seen.store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+}
+
+/// Drop `value` once no CPU can hold a pointer into it. May be called with
+/// interrupts disabled.
+pub fn defer_free<T: Send + 'static>(value: T) {
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    DEFERRED.lock().push_back((epoch, Box::new(value)));
+}
+
+/// Note that the current CPU holds no pointers into other tasks, advance the
+/// epoch if every CPU has, and drop whatever no CPU can use anymore.
+pub fn quiescent() {
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    let seen = SEEN.get();
+    if seen.load(Ordering::Relaxed) == OFFLINE {
+        return;
+    }
+    seen.store(epoch, Ordering::SeqCst);
+
+    let all_seen = (0..smp::num_cpus())
+        .map(|cpu| SEEN.get_for(cpu).load(Ordering::SeqCst))
+        .all(|seen| seen == epoch || seen == OFFLINE);
+    // Another CPU may have advanced it already.
+    let epoch = if all_seen {
+        match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => epoch + 1,
+            Err(current) => current,
+        }
+    } else {
+        epoch
+    };
+
+    // Dropped one at a time, outside the lock.
+    loop {
+        let value = {
+            let mut deferred = DEFERRED.lock();
+            match deferred.front() {
+                Some(&(queued, _)) if queued + 2 <= epoch => deferred.pop_front(),
+                _ => None,
+            }
+        };
+        let Some((_, value)) = value else {
+            break;
+        };
+        drop(value);
+    }
+}
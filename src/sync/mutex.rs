@@ -0,0 +1,159 @@
+//! Mutexes that block waiting tasks
+
+use crate::sched::{self, WaitQueue};
+use crate::trace;
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use shared::trace::Event;
+use x86_64::instructions::interrupts;
+
+/// `Mutex::owner` when no task holds the lock.
+const NO_OWNER: u64 = 0;
+
+/// A lock whose waiters block until it is released, rather than spinning.
+/// Waiters are woken in FIFO order, but a task locking without blocking may
+/// take the lock first, as with `Semaphore`.
+///
+/// There is no poisoning, since a panic stops the kernel. The only way for a
+/// holder to die is to quit, which panics in debug builds rather than leave
+/// the waiters blocked forever. Debug builds also panic on recursive locking,
+/// naming where the lock was first taken.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    /// In debug builds, the ID of the task holding the lock, or `NO_OWNER`.
+    owner: AtomicU64,
+    /// In debug builds, where the holder took the lock.
+    owner_location: AtomicPtr<Location<'static>>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is only accessed by the task holding the lock.
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(NO_OWNER),
+            owner_location: AtomicPtr::new(core::ptr::null_mut()),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Take the lock, blocking the current task until it is free. Must be
+    /// called from a task with interrupts enabled, so not while holding a
+    /// `SpinLock`.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if cfg!(debug_assertions) {
+            self.check_can_block();
+        }
+        self.waiters.wait_until(|| self.try_take());
+        self.acquired()
+    }
+
+    /// Like `lock`, but fails instead of blocking if the lock is held.
+    // Only `lock` is used so far.
+    #[allow(unused)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.try_take() {
+            Some(self.acquired())
+        } else {
+            None
+        }
+    }
+
+    /// Identifies the lock in traces.
+    pub fn id(&self) -> u64 {
+        self as *const Self as u64
+    }
+
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Panic if blocking would hang the CPU or the current task forever.
+    #[track_caller]
+    fn check_can_block(&self) {
+        assert!(
+            interrupts::are_enabled(),
+            "Mutex::lock called with interrupts disabled"
+        );
+        if self.owner.load(Ordering::Relaxed) != sched::current_task().id() {
+            return;
+        }
+        // SAFETY: only the current task changes the location while it holds
+        // the lock, and it always stores a `&'static Location` first.
+        let location = unsafe { &*self.owner_location.load(Ordering::Relaxed) };
+        panic!(
+            "recursive locking: task {} already took this mutex at {location}",
+            sched::current_task_label()
+        );
+    }
+
+    #[track_caller]
+    fn acquired(&self) -> MutexGuard<'_, T> {
+        if cfg!(debug_assertions) {
+            let location: *const Location<'static> = Location::caller();
+            self.owner_location
+                .store(location.cast_mut(), Ordering::Relaxed);
+            self.owner
+                .store(sched::current_task().id(), Ordering::Relaxed);
+            sched::count_mutexes_held(1);
+        }
+        trace::record(Event::Acquire { lock: self.id() });
+        MutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    /// Released by the task that took it, which debug builds count on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+            sched::count_mutexes_held(-1);
+        }
+        // Recorded before the lock is released, as in `trace::Guard`.
+        trace::record(Event::Release {
+            lock: self.lock.id(),
+        });
+        self.lock.locked.store(false, Ordering::Release);
+        // After unlocking, so a waiter checking again before blocking sees it.
+        self.lock.waiters.wake_one();
+    }
+}
@@ -0,0 +1,50 @@
+//! Counting semaphores for blocking tasks until a resource is available
+
+use crate::sched::WaitQueue;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A count of available permits. `acquire` takes one, blocking the current
+/// task while there are none, and `release` gives one back and wakes a
+/// waiter. Waiters are woken in FIFO order, but a task acquiring without
+/// blocking may take a released permit first.
+#[allow(unused)]
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+#[allow(unused)]
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, blocking until one is available.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Take a permit if one is available. Returns whether one was taken.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Give back a permit. May be called from interrupt handlers.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// The number of permits available right now.
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,273 @@
+//! System calls
+//!
+//! User code enters the kernel with the `syscall` instruction. The system call
+//! number is passed in rax and up to three arguments in rdi, rsi and rdx. The
+//! result is returned in rax. rcx and r11 are clobbered by the instruction
+//! itself; all other registers are preserved.
+
+use crate::arch::gdt;
+use crate::arch::msr::{self, MsrError};
+use crate::arch::regs;
+use crate::mm::{self, Length, VirtAddress, VirtExtent};
+use crate::process;
+use crate::qemu;
+use crate::sched;
+use crate::shutdown;
+use crate::time;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::info;
+use x86_64::registers::rflags::RFlags;
+
+/// Log a message. Args: pointer, length. Returns 0 or `SYSCALL_ERROR`.
+pub const SYS_WRITE_LOG: u64 = 0;
+
+/// Let other tasks run. Returns 0.
+pub const SYS_YIELD: u64 = 1;
+
+/// Terminate the calling process. Args: exit code. Does not return.
+pub const SYS_EXIT: u64 = 2;
+
+/// Returns the nanoseconds since boot.
+pub const SYS_UPTIME: u64 = 3;
+
+/// Create a disarmed timer. Returns its ID or `SYSCALL_ERROR`.
+pub const SYS_TIMER_CREATE: u64 = 4;
+
+/// Arm a timer. Args: timer ID, nanoseconds until the first expiry (0 to
+/// disarm), nanoseconds between later expiries (0 for a one-shot timer).
+/// Expiries not yet read are discarded. Returns 0 or `SYSCALL_ERROR`.
+pub const SYS_TIMER_SET: u64 = 5;
+
+/// Read how many times a timer has expired since it was last read. Args:
+/// timer ID, flags. With `TIMER_WAIT`, blocks until it has expired at least
+/// once, failing if it is disarmed. Returns the count or `SYSCALL_ERROR`.
+pub const SYS_TIMER_READ: u64 = 6;
+
+/// Delete a timer. Args: timer ID. Returns 0 or `SYSCALL_ERROR`.
+pub const SYS_TIMER_DELETE: u64 = 7;
+
+/// `SYS_TIMER_READ` flag to wait for an expiry.
+pub const TIMER_WAIT: u64 = 1;
+
+/// Returned in rax when a system call fails.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+/// The longest message `SYS_WRITE_LOG` accepts.
+const MAX_LOG_LEN: u64 = 1024;
+
+const NUM_SYSCALLS: usize = 8;
+
+type SyscallFn = fn(u64, u64, u64) -> u64;
+
+/// Handlers, indexed by system call number.
+static SYSCALL_TABLE: [SyscallFn; NUM_SYSCALLS] = {
+    let mut table: [SyscallFn; NUM_SYSCALLS] = [sys_invalid; NUM_SYSCALLS];
+    table[SYS_WRITE_LOG as usize] = sys_write_log;
+    table[SYS_YIELD as usize] = sys_yield;
+    table[SYS_EXIT as usize] = sys_exit;
+    table[SYS_UPTIME as usize] = sys_uptime;
+    table[SYS_TIMER_CREATE as usize] = sys_timer_create;
+    table[SYS_TIMER_SET as usize] = sys_timer_set;
+    table[SYS_TIMER_READ as usize] = sys_timer_read;
+    table[SYS_TIMER_DELETE as usize] = sys_timer_delete;
+    table
+};
+
+/// Top of the kernel stack `syscall_entry` switches to. Read directly by the
+/// entry stub.
+static KERNEL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// Scratch space for the user stack pointer while `syscall_entry` switches
+/// stacks. Only used with interrupts disabled, before the value is pushed.
+static USER_RSP_SCRATCH: AtomicU64 = AtomicU64::new(0);
+
+/// Enable the `syscall` instruction. Must be called after `gdt::init`. Fails
+/// if the CPU doesn't support it.
+pub fn init() -> Result<(), MsrError> {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    unsafe {
+        msr::set_syscall_segments(
+            gdt::USER_CODE_SELECTOR,
+            gdt::USER_DATA_SELECTOR,
+            gdt::KERNEL_CODE_SELECTOR,
+            gdt::KERNEL_DATA_SELECTOR,
+        )?;
+        msr::set_syscall_entry(VirtAddress::from_raw(syscall_entry as *const () as u64))?;
+
+        // Enter the kernel with interrupts disabled until we are on the kernel
+        // stack, and with a clean direction flag as the ABI requires.
+        msr::set_syscall_flag_mask(
+            RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG,
+        )?;
+
+        regs::enable_syscall()
+    }
+}
+
+/// Set the stack `syscall_entry` switches to. The scheduler sets it whenever
+/// it switches to a task that runs user code.
+///
+/// TODO: this is global rather than per-CPU, so only one CPU may run user
+/// code.
+pub fn set_kernel_stack(stack_top: VirtAddress) {
+    assert!(stack_top.is_aligned_to(16), "{stack_top:?}");
+    KERNEL_STACK_TOP.store(stack_top.as_raw(), Ordering::SeqCst);
+}
+
+/// Target of the `syscall` instruction. On entry rcx holds the user rip, r11
+/// the user rflags, and rsp is still the user stack. The GS base is the
+/// user's until the `swapgs`.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    unsafe {
+        asm!(
+            "swapgs",
+            "mov [rip + {user_rsp}], rsp",
+            "mov rsp, [rip + {kernel_rsp}]",
+            "push qword ptr [rip + {user_rsp}]",
+            "push rcx",
+            "push r11",
+            // Save the remaining caller-saved registers. The stack top is
+            // aligned, so 9 pushes plus padding keeps 16 byte alignment for
+            // the call.
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push r8",
+            "push r9",
+            "push r10",
+            "sub rsp, 8",
+            "mov rcx, rdx",
+            "mov rdx, rsi",
+            "mov rsi, rdi",
+            "mov rdi, rax",
+            "call {dispatch}",
+            "add rsp, 8",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop r11",
+            "pop rcx",
+            // The system call may have enabled interrupts. None may arrive
+            // while the user's GS base is in place.
+            "cli",
+            "swapgs",
+            "pop rsp",
+            "sysretq",
+            user_rsp = sym USER_RSP_SCRATCH,
+            kernel_rsp = sym KERNEL_STACK_TOP,
+            dispatch = sym dispatch,
+            options(noreturn),
+        )
+    }
+}
+
+extern "C" fn dispatch(num: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    match SYSCALL_TABLE.get(num as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn sys_invalid(_: u64, _: u64, _: u64) -> u64 {
+    SYSCALL_ERROR
+}
+
+fn sys_write_log(ptr: u64, len: u64, _: u64) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+    if len > MAX_LOG_LEN {
+        return SYSCALL_ERROR;
+    }
+
+    let Some(extent) = VirtExtent::new_checked(VirtAddress::from_raw(ptr), Length::from_raw(len))
+    else {
+        return SYSCALL_ERROR;
+    };
+    if !mm::is_user_accessible(extent) {
+        return SYSCALL_ERROR;
+    }
+
+    // SAFETY: the range is mapped in user space of the current address space.
+    let bytes: &[u8] = unsafe { &*extent.as_slice() };
+    match core::str::from_utf8(bytes) {
+        Ok(msg) => {
+            info!("user: {msg}");
+            0
+        }
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_yield(_: u64, _: u64, _: u64) -> u64 {
+    sched::yield_current();
+    0
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> u64 {
+    info!("user process exited with code {code}");
+
+    // The only process is init, so its exit ends a test run.
+    if cfg!(feature = "qemu_exit") {
+        shutdown::shutdown(shutdown::Action::ExitQemu(if code == 0 {
+            qemu::ExitCode::Success
+        } else {
+            qemu::ExitCode::Failure
+        }));
+    }
+
+    process::exit_current();
+}
+
+fn sys_uptime(_: u64, _: u64, _: u64) -> u64 {
+    time::uptime().as_nanos() as u64
+}
+
+fn sys_timer_create(_: u64, _: u64, _: u64) -> u64 {
+    match process::create_timer() {
+        Some(id) => id as u64,
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn sys_timer_set(id: u64, initial: u64, interval: u64) -> u64 {
+    let (initial, interval) = (
+        Duration::from_nanos(initial),
+        Duration::from_nanos(interval),
+    );
+    match usize::try_from(id) {
+        Ok(id) if process::set_timer(id, initial, interval) => 0,
+        _ => SYSCALL_ERROR,
+    }
+}
+
+fn sys_timer_read(id: u64, flags: u64, _: u64) -> u64 {
+    if flags & !TIMER_WAIT != 0 {
+        return SYSCALL_ERROR;
+    }
+    let Ok(id) = usize::try_from(id) else {
+        return SYSCALL_ERROR;
+    };
+    // The count can't reach `SYSCALL_ERROR`: that would take billions of
+    // years of expiries every tick.
+    process::read_timer(id, flags & TIMER_WAIT != 0).unwrap_or(SYSCALL_ERROR)
+}
+
+fn sys_timer_delete(id: u64, _: u64, _: u64) -> u64 {
+    match usize::try_from(id) {
+        Ok(id) if process::delete_timer(id) => 0,
+        _ => SYSCALL_ERROR,
+    }
+}
@@ -0,0 +1,214 @@
+//! Timekeeping
+//!
+//! A monotonic clock backed by a `TickSource`. The PIT always drives the
+//! periodic timer interrupt. If the TSC can be calibrated against it at boot,
+//! the clock reads the TSC instead for much finer resolution. Otherwise, once
+//! ACPI tables can be read, `init_hpet` switches the clock to the HPET if
+//! there is one. Software timers (see `timer`) run callbacks off the periodic
+//! interrupt.
+
+mod hpet;
+mod pit;
+pub mod timer;
+mod tsc;
+
+use crate::acpi;
+use crate::arch::quirks;
+
+use core::time::Duration;
+
+use log::{info, warn};
+
+pub use pit::TIMER_HZ;
+
+/// A free running counter usable as a clock.
+pub trait TickSource {
+    /// The current count. Never decreases.
+    fn ticks(&self) -> u64;
+
+    /// The number of ticks per second.
+    fn frequency(&self) -> u64;
+
+    /// How long `ticks` ticks take. Sources whose period isn't a whole
+    /// number of nanoseconds can convert more precisely than this.
+    fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        (u128::from(ticks) * 1_000_000_000 / u128::from(self.frequency())) as u64
+    }
+}
+
+/// A point on the monotonic clock. Only meaningful within one boot.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Instant {
+    /// Nanoseconds since `init`.
+    nanos: u64,
+}
+
+impl Instant {
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier` is
+    /// later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// The time elapsed since `self`.
+    pub fn elapsed(self) -> Duration {
+        now().duration_since(self)
+    }
+}
+
+enum ClockSource {
+    Pit(pit::Pit),
+    Tsc(tsc::Tsc),
+    Hpet(hpet::Hpet),
+}
+
+impl ClockSource {
+    fn get(&self) -> &dyn TickSource {
+        match self {
+            ClockSource::Pit(pit) => pit,
+            ClockSource::Tsc(tsc) => tsc,
+            ClockSource::Hpet(hpet) => hpet,
+        }
+    }
+}
+
+struct Clock {
+    source: ClockSource,
+
+    /// The source's count when the clock was set up.
+    base: u64,
+
+    /// Nanoseconds since `init` when the clock was set up.
+    base_nanos: u64,
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        let source = self.source.get();
+        let ticks = source.ticks() - self.base;
+        Instant {
+            nanos: self.base_nanos + source.ticks_to_nanos(ticks),
+        }
+    }
+}
+
+/// Chosen by `init`.
+static CLOCK: spin::Once<Clock> = spin::Once::new();
+
+/// Chosen by `init_hpet`. Replaces `CLOCK` once set.
+static LATE_CLOCK: spin::Once<Clock> = spin::Once::new();
+
+fn clock() -> &'static Clock {
+    LATE_CLOCK
+        .get()
+        .or_else(|| CLOCK.get())
+        .expect("time::init was not called")
+}
+
+/// Start the periodic timer and choose a clock source. Must be called with
+/// interrupts disabled, before the timer IRQ is unmasked, and after
+/// `quirks::init`.
+pub fn init() {
+    // Make sure we are only called once.
+    static IS_INITIALIZED: core::sync::atomic::AtomicBool =
+        core::sync::atomic::AtomicBool::new(false);
+    assert!(!IS_INITIALIZED.swap(true, core::sync::atomic::Ordering::SeqCst));
+
+    unsafe {
+        pit::start_periodic_timer();
+    }
+
+    let source = if !quirks::tsc_clock_usable() {
+        // `quirks` already warned why.
+        ClockSource::Pit(pit::Pit)
+    } else {
+        match tsc::Tsc::calibrate() {
+            Some(tsc) => {
+                info!("TSC frequency: {} kHz", tsc.frequency() / 1000);
+                ClockSource::Tsc(tsc)
+            }
+            None => {
+                warn!("TSC calibration failed; using the PIT as the clock");
+                ClockSource::Pit(pit::Pit)
+            }
+        }
+    };
+
+    let base = source.get().ticks();
+    CLOCK.call_once(|| Clock {
+        source,
+        base,
+        base_nanos: 0,
+    });
+}
+
+/// Switch the clock from the PIT to the HPET, if there is one. Must be
+/// called once with interrupts disabled, after `init`, `mm::init` and
+/// `acpi::init`.
+pub fn init_hpet() {
+    let table = match acpi::find_table(shared::acpi::Hpet::SIGNATURE)
+        .and_then(|table| table.map(shared::acpi::Hpet::parse).transpose())
+    {
+        Ok(Some(table)) => table,
+        // `acpi::init` already said why.
+        Ok(None) | Err(_) => return,
+    };
+    if !matches!(clock().source, ClockSource::Pit(_)) {
+        info!("Keeping the TSC as the clock rather than the HPET");
+        return;
+    }
+    // SAFETY: only called once, and the HPET isn't used elsewhere.
+    let hpet = match unsafe { hpet::Hpet::new(&table) } {
+        Ok(hpet) => hpet,
+        Err(e) => {
+            warn!("Can't use the HPET as the clock: {e}");
+            return;
+        }
+    };
+    info!(
+        "Using the HPET as the clock: period {} fs, {} kHz",
+        hpet.period(),
+        hpet.frequency() / 1000
+    );
+    // Carry on from the PIT's time, so the clock never goes backwards.
+    let base_nanos = now().nanos;
+    let base = hpet.ticks();
+    LATE_CLOCK.call_once(|| Clock {
+        source: ClockSource::Hpet(hpet),
+        base,
+        base_nanos,
+    });
+}
+
+/// Must be called from the timer interrupt handler.
+pub fn timer_interrupt() {
+    pit::tick();
+}
+
+/// The current time.
+pub fn now() -> Instant {
+    clock().now()
+}
+
+/// The time since `init`.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now().nanos)
+}
+
+/// Busy-wait for `duration` without relying on interrupts, e.g. in a panic
+/// handler. Returns false without waiting if the clock only advances on timer
+/// interrupts, or isn't set up yet.
+pub fn spin_wait(duration: Duration) -> bool {
+    match LATE_CLOCK.get().or_else(|| CLOCK.get()) {
+        Some(Clock {
+            source: ClockSource::Tsc(_) | ClockSource::Hpet(_),
+            ..
+        }) => (),
+        _ => return false,
+    }
+    let start = now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+    true
+}
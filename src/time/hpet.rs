@@ -0,0 +1,147 @@
+//! The High Precision Event Timer
+//!
+//! Only the main counter is used, as a clock. The comparators are left
+//! alone, so the PIT still drives the timer interrupt. The counter's period
+//! is given in femtoseconds, which rarely divides a second evenly, so ticks
+//! are converted to nanoseconds with the period rather than the rounded
+//! frequency.
+
+use super::TickSource;
+use crate::itest::{kernel_test, TestResult};
+use crate::mm::{self, PhysExtent, VolatileRegion};
+
+use alloc::format;
+
+use shared::acpi::hpet::AddressSpace;
+use shared::acpi::Hpet as HpetTable;
+use shared::mmio::Bus;
+
+// Register offsets.
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+
+/// The register block's size.
+const REGS_LEN: u64 = 0x400;
+
+/// In the capabilities register: the main counter is 64 bits wide.
+const CAP_64_BIT: u64 = 1 << 13;
+
+/// In the configuration register: the main counter runs.
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+/// The longest period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FS_PER_NS: u128 = 1_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HpetError {
+    /// The registers aren't memory mapped.
+    NotMemory(AddressSpace),
+    /// The period is 0 or longer than the specification allows.
+    BadPeriod(u64),
+    /// A 32 bit counter wraps within minutes, and wrapping isn't tracked.
+    NarrowCounter,
+}
+
+impl core::fmt::Display for HpetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HpetError::NotMemory(space) => {
+                write!(f, "its registers are in address space {space:?}")
+            }
+            HpetError::BadPeriod(period) => write!(f, "its period of {period} fs is invalid"),
+            HpetError::NarrowCounter => write!(f, "its counter is only 32 bits"),
+        }
+    }
+}
+
+pub struct Hpet {
+    regs: VolatileRegion,
+    /// Femtoseconds per tick.
+    period: u64,
+}
+
+impl Hpet {
+    /// Map the HPET `table` describes and start its main counter.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once, after `mm::init`. Nothing else may use the
+    /// HPET.
+    pub unsafe fn new(table: &HpetTable) -> Result<Hpet, HpetError> {
+        match table.address_space() {
+            AddressSpace::Memory => (),
+            space => return Err(HpetError::NotMemory(space)),
+        }
+        // SAFETY: the firmware says the HPET's registers are there.
+        let regs = unsafe { mm::map_mmio(PhysExtent::from_raw(table.base_address(), REGS_LEN)) };
+        let capabilities = regs.read::<u64>(REG_CAPABILITIES);
+        let period = capabilities >> 32;
+        if period == 0 || period > MAX_PERIOD_FS {
+            return Err(HpetError::BadPeriod(period));
+        }
+        if capabilities & CAP_64_BIT == 0 {
+            return Err(HpetError::NarrowCounter);
+        }
+        // SAFETY: the caller vouches that nothing else uses the registers.
+        // Enabling the counter doesn't enable any comparator's interrupts,
+        // and legacy replacement stays off.
+        unsafe { regs.modify::<u64>(REG_CONFIG, |config| config | CONFIG_ENABLE) };
+        Ok(Hpet { regs, period })
+    }
+
+    /// Femtoseconds per tick.
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+}
+
+impl TickSource for Hpet {
+    fn ticks(&self) -> u64 {
+        self.regs.read(REG_MAIN_COUNTER)
+    }
+
+    fn frequency(&self) -> u64 {
+        (FS_PER_SEC + self.period / 2) / self.period
+    }
+
+    fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        ticks_to_nanos(ticks, self.period)
+    }
+}
+
+fn ticks_to_nanos(ticks: u64, period: u64) -> u64 {
+    (u128::from(ticks) * u128::from(period) / FS_PER_NS) as u64
+}
+
+kernel_test!(
+    "time: HPET ticks convert at the exact period",
+    ticks_convert_exactly
+);
+fn ticks_convert_exactly() -> TestResult {
+    // A typical chipset's 14.31818 MHz counter, whose frequency in Hz isn't
+    // a whole number, for a second and for long enough that the product
+    // overflows 64 bits, then QEMU's 100 MHz one.
+    let cases = [
+        (14_318_180, 69_841_279, 1_000_000_004),
+        (
+            1_000_000_000 * 14_318_180,
+            69_841_279,
+            1_000_000_004_152_220_000,
+        ),
+        (100_000_000, 10_000_000, 1_000_000_000),
+        (u64::MAX / 10, 10_000_000, u64::MAX / 10 * 10),
+    ];
+    for (ticks, period, nanos) in cases {
+        let got = ticks_to_nanos(ticks, period);
+        if got != nanos {
+            return Err(format!(
+                "{ticks} ticks of {period} fs gave {got} ns, not {nanos}"
+            ));
+        }
+    }
+    Ok(())
+}
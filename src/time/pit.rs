@@ -0,0 +1,60 @@
+//! The 8253/8254 programmable interval timer
+
+use super::TickSource;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+/// The rate of the periodic timer interrupt.
+pub const TIMER_HZ: u64 = 100;
+
+/// The PIT's input clock.
+pub(super) const BASE_HZ: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+pub(super) const CHANNEL_2: u16 = 0x42;
+pub(super) const COMMAND: u16 = 0x43;
+
+/// Channel 2's gate is bit 0, the speaker enable bit 1, and channel 2's
+/// output can be read back from bit 5.
+pub(super) const GATE_CONTROL: u16 = 0x61;
+
+/// Timer interrupts since `start_periodic_timer`.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The PIT as a clock, counting timer interrupts.
+pub struct Pit;
+
+impl TickSource for Pit {
+    fn ticks(&self) -> u64 {
+        TICKS.load(Ordering::SeqCst)
+    }
+
+    fn frequency(&self) -> u64 {
+        TIMER_HZ
+    }
+}
+
+/// Program channel 0 to interrupt at `TIMER_HZ`.
+///
+/// # Safety
+///
+/// Must only be called during `time::init`.
+pub(super) unsafe fn start_periodic_timer() {
+    const DIVISOR: u64 = BASE_HZ / TIMER_HZ;
+    static_assertions::const_assert!(DIVISOR > 0 && DIVISOR <= 0xffff);
+
+    let mut command = PortWriteOnly::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_0);
+    unsafe {
+        // Channel 0, low then high byte, mode 2 (rate generator), binary.
+        command.write(0b0011_0100);
+        data.write(DIVISOR as u8);
+        data.write((DIVISOR >> 8) as u8);
+    }
+}
+
+pub(super) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
@@ -0,0 +1,178 @@
+//! Software timers
+//!
+//! A `Timer` calls a function from the timer interrupt once its deadline
+//! passes, either once or periodically, until it is cancelled or dropped.
+//! Deadlines are in scheduler ticks, so timers have the timer interrupt's
+//! resolution. Armed timers are kept in a `SleepQueue` in deadline order,
+//! which `expire` checks every tick.
+//!
+//! The kernel heap can't be used from interrupt handlers, so a timer's entry
+//! is allocated when it is created and freed when it is dropped, both in task
+//! context. The interrupt handler only unlinks it.
+
+use crate::sched;
+use crate::sync::SpinLock;
+
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+use core::time::Duration;
+
+use shared::collections::{Linked, Links};
+use shared::sched::{SleepQueue, Sleeper};
+
+use super::TIMER_HZ;
+
+struct Entry {
+    /// The tick the timer next expires at.
+    deadline: u64,
+    /// The ticks between expiries, or 0 for a one-shot timer.
+    period: u64,
+    callback: fn(usize),
+    context: usize,
+    links: Links<Entry>,
+}
+
+// SAFETY: `links` always returns the same field of the entry.
+unsafe impl Linked for Entry {
+    fn links(this: NonNull<Self>) -> NonNull<Links<Self>> {
+        unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*this.as_ptr()).links)) }
+    }
+}
+
+impl Sleeper for Entry {
+    fn wake_tick(this: NonNull<Self>) -> u64 {
+        // SAFETY: entries are only freed once they are out of the queue.
+        unsafe { this.as_ref().deadline }
+    }
+}
+
+// SAFETY: entries are only accessed through `TIMERS`' lock, or by the `Timer`
+// owning them once they are out of the queue.
+unsafe impl Send for Entry {}
+
+/// Armed timers, soonest first.
+static TIMERS: SpinLock<SleepQueue<Entry>> = SpinLock::new(SleepQueue::new());
+
+/// An armed timer. Dropping it cancels it.
+///
+/// Callbacks run in the timer interrupt handler with interrupts disabled, so
+/// they must not block or use the kernel heap, and so can't drop a `Timer`.
+/// Making a task ready, or releasing a `Semaphore`, is fine.
+pub struct Timer {
+    entry: NonNull<Entry>,
+}
+
+// SAFETY: see `Entry`.
+unsafe impl Send for Timer {}
+
+impl Timer {
+    /// Call `callback(context)` once `after` has passed.
+    pub fn oneshot(after: Duration, callback: fn(usize), context: usize) -> Timer {
+        Timer::at_tick(
+            sched::current_tick() + duration_to_ticks(after),
+            callback,
+            context,
+        )
+    }
+
+    /// Call `callback(context)` every `interval`, starting one `interval`
+    /// from now. If ticks are missed, the missed calls are made at once.
+    pub fn periodic(interval: Duration, callback: fn(usize), context: usize) -> Timer {
+        let period = duration_to_ticks(interval).max(1);
+        Timer::new(sched::current_tick() + period, period, callback, context)
+    }
+
+    /// Call `callback(context)` once scheduler tick `deadline` is reached.
+    pub fn at_tick(deadline: u64, callback: fn(usize), context: usize) -> Timer {
+        Timer::new(deadline, 0, callback, context)
+    }
+
+    fn new(deadline: u64, period: u64, callback: fn(usize), context: usize) -> Timer {
+        let entry = NonNull::from(Box::leak(Box::new(Entry {
+            deadline,
+            period,
+            callback,
+            context,
+            links: Links::new(),
+        })));
+        // SAFETY: the entry stays allocated until it is removed in `drop`.
+        unsafe {
+            TIMERS.lock().insert(entry);
+        }
+        Timer { entry }
+    }
+
+    /// Stop the timer. Returns whether it was armed, i.e. it was periodic or
+    /// hadn't expired yet.
+    pub fn cancel(self) -> bool {
+        self.disarm()
+    }
+
+    fn disarm(&self) -> bool {
+        let mut timers = TIMERS.lock();
+        // SAFETY: the lock keeps the interrupt handler from changing it.
+        if !unsafe { self.entry.as_ref().links.is_linked() } {
+            return false;
+        }
+        // SAFETY: the entry is linked, and only ever into `TIMERS`.
+        unsafe {
+            timers.remove(self.entry);
+        }
+        true
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.disarm();
+        // SAFETY: the entry is out of the queue, so nothing else refers to it.
+        drop(unsafe { Box::from_raw(self.entry.as_ptr()) });
+    }
+}
+
+/// Call back the timers due by tick `now`. Must be called from the timer
+/// interrupt handler.
+pub fn expire(now: u64) {
+    loop {
+        let (callback, context) = {
+            let mut timers = TIMERS.lock();
+            let Some(mut entry) = timers.pop_due(now) else {
+                break;
+            };
+            // SAFETY: the entry was in the queue, so its timer hasn't been
+            // dropped, and the lock is held.
+            let entry_ref = unsafe { entry.as_mut() };
+            let call = (entry_ref.callback, entry_ref.context);
+            if entry_ref.period != 0 {
+                entry_ref.deadline += entry_ref.period;
+                unsafe {
+                    timers.insert(entry);
+                }
+            }
+            call
+        };
+        callback(context);
+    }
+}
+
+/// Call `f` with the deadline, callback and context of each armed timer,
+/// soonest first. Returns false without calling it if the timers are locked,
+/// e.g. by the code this interrupted.
+pub fn try_for_each(mut f: impl FnMut(u64, fn(usize), usize)) -> bool {
+    let Some(timers) = TIMERS.try_lock() else {
+        return false;
+    };
+    for entry in timers.iter() {
+        // SAFETY: the lock keeps the entries in the queue.
+        let entry = unsafe { entry.as_ref() };
+        f(entry.deadline, entry.callback, entry.context);
+    }
+    true
+}
+
+/// The number of ticks in `duration`, rounded up so a timer never expires
+/// early.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * u128::from(TIMER_HZ)).div_ceil(1_000_000_000);
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
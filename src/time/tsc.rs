@@ -0,0 +1,75 @@
+//! The CPU's time stamp counter
+
+use super::pit;
+use super::TickSource;
+
+use core::arch::x86_64::_rdtsc;
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+/// How long to count for when calibrating.
+const CALIBRATION_HZ: u64 = 100;
+
+/// Give up calibrating after this many polls of the PIT. Far more than the
+/// calibration interval takes on any real machine or emulator.
+const MAX_CALIBRATION_POLLS: u64 = 100_000_000;
+
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl Tsc {
+    /// Measure the TSC's frequency by counting how far it advances while PIT
+    /// channel 2 counts down a known interval. Returns `None` if the PIT
+    /// never finishes or the TSC doesn't advance.
+    pub fn calibrate() -> Option<Tsc> {
+        const COUNT: u64 = pit::BASE_HZ / CALIBRATION_HZ;
+
+        let mut gate = Port::<u8>::new(pit::GATE_CONTROL);
+        let mut command = PortWriteOnly::<u8>::new(pit::COMMAND);
+        let mut data = PortWriteOnly::<u8>::new(pit::CHANNEL_2);
+
+        // SAFETY: channel 2 is only used here, and only drives the speaker
+        // which is kept disabled.
+        let elapsed = unsafe {
+            // Raise the gate and disable the speaker.
+            let old_gate = gate.read();
+            gate.write((old_gate & !0b10) | 0b01);
+
+            // Channel 2, low then high byte, mode 0 (interrupt on terminal
+            // count), binary. The output goes high when the count reaches
+            // zero.
+            command.write(0b1011_0000);
+            data.write(COUNT as u8);
+            data.write((COUNT >> 8) as u8);
+
+            let start = _rdtsc();
+            let mut polls = 0;
+            while gate.read() & 0b10_0000 == 0 {
+                polls += 1;
+                if polls == MAX_CALIBRATION_POLLS {
+                    gate.write(old_gate);
+                    return None;
+                }
+            }
+            let end = _rdtsc();
+
+            gate.write(old_gate);
+            end.checked_sub(start)?
+        };
+
+        let frequency = elapsed * CALIBRATION_HZ;
+        (frequency > 0).then_some(Tsc { frequency })
+    }
+}
+
+impl TickSource for Tsc {
+    fn ticks(&self) -> u64 {
+        // SAFETY: rdtsc is always available in long mode.
+        unsafe { _rdtsc() }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
@@ -0,0 +1,89 @@
+//! Scheduler event recording for debugging ordering bugs
+//!
+//! With the `sched_trace` feature, context switches, wake-ups and scheduler
+//! lock acquisitions are recorded in a `shared::trace::TraceBuffer`, and the
+//! last records are written out on panic. The schedtrace tool reconstructs the
+//! interleaving from the output. Without the feature, recording compiles to
+//! nothing.
+//!
+//! Locks taken with `lock` are also registered with the watchdog, which
+//! panics if a CPU holds or waits for them too long without switching tasks.
+
+use crate::watchdog;
+
+use core::fmt::Write;
+use core::ops::{Deref, DerefMut};
+
+use shared::trace::Event;
+
+/// How many of the latest records are kept.
+#[cfg(feature = "sched_trace")]
+const TRACE_LEN: usize = 4096;
+
+#[cfg(feature = "sched_trace")]
+static TRACE: shared::trace::TraceBuffer<TRACE_LEN> = shared::trace::TraceBuffer::new();
+
+/// Record `event` on the current CPU.
+#[inline]
+pub fn record(event: Event) {
+    #[cfg(feature = "sched_trace")]
+    TRACE.record(crate::smp::current_cpu() as u32, event);
+    #[cfg(not(feature = "sched_trace"))]
+    let _ = event;
+}
+
+/// Write the recorded events to `writer`, one per line.
+pub fn dump(writer: &mut impl Write) {
+    #[cfg(feature = "sched_trace")]
+    {
+        let _ = writeln!(
+            writer,
+            "trace: {} records, last {TRACE_LEN} kept",
+            TRACE.recorded()
+        );
+        TRACE.for_each(|record| {
+            let _ = writeln!(writer, "trace: {record}");
+        });
+    }
+    #[cfg(not(feature = "sched_trace"))]
+    let _ = writer;
+}
+
+/// Lock `mutex`, recording the acquisition and later the release, and
+/// registering it with the watchdog while waiting and while held.
+pub fn lock<T>(mutex: &spin::Mutex<T>) -> Guard<'_, T> {
+    let lock = mutex as *const _ as u64;
+    let was_waiting = watchdog::waiting(lock);
+    let guard = mutex.lock();
+    watchdog::acquired(lock, was_waiting);
+    record(Event::Acquire { lock });
+    Guard { guard, lock }
+}
+
+pub struct Guard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    lock: u64,
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        // Recorded just before the lock is actually released, so the release
+        // can't appear after another CPU's acquisition.
+        record(Event::Release { lock: self.lock });
+        watchdog::released(self.lock);
+    }
+}
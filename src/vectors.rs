@@ -0,0 +1,281 @@
+//! Interrupt vector allocation
+//!
+//! Vectors 0 to 31 are the CPU's exceptions, set up by `idt::init`. Every
+//! other vector must be claimed here before a handler is installed for it, so
+//! two subsystems can't silently end up sharing one. `dump` lists the claimed
+//! vectors with their owners, how many times each CPU took them, and where
+//! they are routed.
+//!
+//! MSIs and software interrupts don't need a handler of their own in the
+//! IDT: `allocate_handler` takes any `IrqHandler`, including a closure with
+//! whatever context it needs, and the vector's entry stub reaches it through
+//! `dispatch`.
+//!
+//! System calls use the `syscall` instruction, so they don't need a vector.
+//! TLB shootdowns are sent as NMIs, so they don't either.
+
+use crate::apic;
+use crate::diag;
+use crate::idt;
+use crate::itest::{kernel_test, TestResult};
+use crate::smp::{self, PerCpu, MAX_CPUS};
+use crate::sync;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+/// The first vector that isn't an exception.
+pub const FIRST_CLAIMABLE: u8 = 32;
+
+/// `allocate` hands out vectors from `FIRST_DYNAMIC` to `LAST_DYNAMIC`. Those
+/// below are the ISA IRQs, and those above are for fixed system vectors like
+/// the APIC's spurious interrupt.
+pub const FIRST_DYNAMIC: u8 = 48;
+pub const LAST_DYNAMIC: u8 = 0xef;
+
+/// Which CPUs a vector is delivered to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// Only the CPU with this index.
+    Cpu(usize),
+    /// Whichever CPU raised or received it, e.g. for local APIC interrupts.
+    AllCpus,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Cpu(cpu) => write!(f, "CPU {cpu}"),
+            Route::AllCpus => write!(f, "all CPUs"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Owner {
+    name: &'static str,
+    route: Route,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClaimError {
+    /// The vector is a CPU exception.
+    Exception(u8),
+    /// Someone else has the vector.
+    InUse { vector: u8, owner: &'static str },
+    /// There are no free vectors left for `allocate`.
+    Exhausted,
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaimError::Exception(vector) => write!(f, "vector {vector} is an exception"),
+            ClaimError::InUse { vector, owner } => {
+                write!(f, "vector {vector} is already used by {owner}")
+            }
+            ClaimError::Exhausted => write!(f, "no free interrupt vectors"),
+        }
+    }
+}
+
+/// Handles interrupts on a vector from `allocate_handler`.
+pub trait IrqHandler: Send + Sync {
+    /// Called with interrupts disabled on the CPU that took the interrupt,
+    /// which is acknowledged afterwards.
+    fn handle(&self, vector: u8);
+}
+
+impl<F: Fn(u8) + Send + Sync> IrqHandler for F {
+    fn handle(&self, vector: u8) {
+        self(vector)
+    }
+}
+
+static OWNERS: Mutex<[Option<Owner>; 256]> = Mutex::new([None; 256]);
+
+/// The handlers of vectors from `allocate_handler`. Only written with
+/// interrupts disabled, so `dispatch` can't wait on its own CPU.
+static HANDLERS: RwLock<[Option<&'static dyn IrqHandler>; 256]> = RwLock::new([None; 256]);
+
+/// How many times each CPU took each vector.
+static COUNTS: PerCpu<[AtomicU64; 256]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 256] }; MAX_CPUS]);
+
+/// Claim `vector` for `name`, which is delivered to `route`.
+pub fn claim(vector: u8, name: &'static str, route: Route) -> Result<(), ClaimError> {
+    if vector < FIRST_CLAIMABLE {
+        return Err(ClaimError::Exception(vector));
+    }
+    let mut owners = OWNERS.lock();
+    if let Some(owner) = owners[vector as usize] {
+        return Err(ClaimError::InUse {
+            vector,
+            owner: owner.name,
+        });
+    }
+    owners[vector as usize] = Some(Owner { name, route });
+    Ok(())
+}
+
+/// Claim any free vector for `name`.
+pub fn allocate(name: &'static str, route: Route) -> Result<u8, ClaimError> {
+    let mut owners = OWNERS.lock();
+    let vector = (FIRST_DYNAMIC..=LAST_DYNAMIC)
+        .find(|&v| owners[v as usize].is_none())
+        .ok_or(ClaimError::Exhausted)?;
+    owners[vector as usize] = Some(Owner { name, route });
+    // Counts from an earlier owner would be attributed to this one.
+    for counts in COUNTS.iter() {
+        counts[vector as usize].store(0, Ordering::Relaxed);
+    }
+    Ok(vector)
+}
+
+/// Claim any free vector for `name`, and call `handler` for each interrupt
+/// on it.
+pub fn allocate_handler(
+    name: &'static str,
+    route: Route,
+    handler: impl IrqHandler + 'static,
+) -> Result<u8, ClaimError> {
+    let vector = allocate(name, route)?;
+    let handler: &'static dyn IrqHandler = Box::leak(Box::new(handler));
+    without_interrupts(|| HANDLERS.write()[vector as usize] = Some(handler));
+    idt::install_dynamic_entry(vector, true);
+    Ok(vector)
+}
+
+/// Remove the handler of `vector`, from `allocate_handler`, and give it up.
+/// The handler is dropped once no CPU can still be running it.
+pub fn release_handler(vector: u8) {
+    idt::install_dynamic_entry(vector, false);
+    let handler = without_interrupts(|| HANDLERS.write()[vector as usize].take())
+        .unwrap_or_else(|| panic!("vector {vector} has no handler"));
+    release(vector);
+    let handler = handler as *const dyn IrqHandler as *mut dyn IrqHandler;
+    // SAFETY: `allocate_handler` leaked the box, and it is no longer
+    // reachable from `HANDLERS`.
+    let handler = unsafe { Box::from_raw(handler) };
+    sync::defer_free(handler);
+}
+
+/// Give up `vector`. Its handler must already be removed.
+pub fn release(vector: u8) {
+    let owner = OWNERS.lock()[vector as usize].take();
+    assert!(owner.is_some(), "vector {vector} was not claimed");
+}
+
+/// Whether `vector` has been claimed.
+pub fn is_claimed(vector: u8) -> bool {
+    OWNERS.lock()[vector as usize].is_some()
+}
+
+/// Count an interrupt on `vector`. Must be called with the kernel's GS base.
+pub fn record(vector: u8) {
+    COUNTS.get()[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count and handle an interrupt on `vector`, which has a handler from
+/// `allocate_handler`, then acknowledge it if the local APIC delivered it.
+/// Must be called with the kernel's GS base and interrupts disabled.
+pub(crate) fn dispatch(vector: u8) {
+    record(vector);
+    let handler = HANDLERS.read()[vector as usize];
+    match handler {
+        Some(handler) => handler.handle(vector),
+        None => {
+            warn!("interrupt on vector {vector}, which has no handler");
+            diag::snapshot("interrupt on a vector with no handler");
+        }
+    }
+    if apic::is_in_service(vector) {
+        apic::end_of_interrupt();
+    }
+}
+
+/// Interrupts taken on all vectors and CPUs.
+pub fn total_count() -> u64 {
+    COUNTS
+        .iter()
+        .flat_map(|counts| counts.iter())
+        .map(|count| count.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Write a line for each claimed vector to `writer`: its owner, count on
+/// each CPU, and route. Safe to call when panicking; it gives up if the
+/// owners are locked.
+pub fn dump(writer: &mut impl Write) -> fmt::Result {
+    let Some(owners) = OWNERS.try_lock() else {
+        return writeln!(writer, "interrupt vectors are locked");
+    };
+
+    write!(writer, "vector  {:<16}", "owner")?;
+    for cpu in 0..smp::num_cpus() {
+        let digits = if cpu < 10 { 1 } else { 2 };
+        write!(writer, " {:>width$}{cpu}", "CPU", width = 10 - digits)?;
+    }
+    writeln!(writer, "  route")?;
+
+    for (vector, owner) in owners.iter().enumerate() {
+        let Some(owner) = owner else {
+            continue;
+        };
+        write!(writer, "{vector:>#6x}  {:<16}", owner.name)?;
+        for counts in COUNTS.iter() {
+            write!(writer, " {:>10}", counts[vector].load(Ordering::Relaxed))?;
+        }
+        writeln!(writer, "  {}", owner.route)?;
+    }
+    Ok(())
+}
+
+kernel_test!(
+    "vectors: allocated handlers run with their context",
+    allocated_handlers_run
+);
+fn allocated_handlers_run() -> TestResult {
+    if !apic::is_enabled() {
+        return Ok(());
+    }
+    // The interrupt is taken when `without_interrupts` below returns only if
+    // interrupts were enabled to begin with.
+    assert!(
+        interrupts::are_enabled(),
+        "allocated_handlers_run called with interrupts disabled"
+    );
+    let seen = Arc::new(AtomicU64::new(0));
+    let vector = {
+        let seen = seen.clone();
+        allocate_handler("test", Route::AllCpus, move |vector| {
+            seen.store(u64::from(vector), Ordering::Relaxed)
+        })
+        .map_err(|e| format!("{e}"))?
+    };
+
+    // Delivered as soon as interrupts are enabled again.
+    // SAFETY: the vector has a handler.
+    without_interrupts(|| unsafe { apic::send_self(vector) });
+    let seen = seen.load(Ordering::Relaxed);
+    let count: u64 = COUNTS
+        .iter()
+        .map(|counts| counts[vector as usize].load(Ordering::Relaxed))
+        .sum();
+    release_handler(vector);
+
+    if seen != u64::from(vector) {
+        return Err(format!("the handler for vector {vector} saw {seen}"));
+    }
+    if count != 1 {
+        return Err(format!("vector {vector} was counted {count} times"));
+    }
+    Ok(())
+}
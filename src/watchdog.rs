@@ -0,0 +1,173 @@
+//! Lockup detection
+//!
+//! A deadlock on a spin lock otherwise just hangs. Locks taken with
+//! `trace::lock` are registered here while held or waited for, and each timer
+//! tick checks the current CPU: if it has held or waited for one at every tick
+//! for the timeout without switching tasks, the watchdog writes out every
+//! CPU's tasks and locks and panics.
+//!
+//! The check runs in the timer interrupt, so lockups with interrupts disabled,
+//! such as on the scheduler's own locks, aren't caught. Catching those would
+//! need an NMI watchdog.
+
+use crate::sched;
+use crate::smp::{self, PerCpu, MAX_CPUS};
+use crate::time::TIMER_HZ;
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// The default for `watchdog=<seconds>`.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// The most registered locks a CPU can hold at once. Any more are not
+/// tracked.
+const MAX_HELD: usize = 8;
+
+/// A CPU's registered locks. Only changed by its own CPU, including from
+/// interrupt handlers, but read by the dump from any CPU.
+struct CpuLocks {
+    /// Addresses of the locks held, or zero for a free slot.
+    held: [AtomicU64; MAX_HELD],
+    /// The address of the lock being waited for, or zero.
+    waiting: AtomicU64,
+    /// The last tick the CPU switched tasks or was seen holding nothing.
+    progress: AtomicU64,
+}
+
+impl CpuLocks {
+    const fn new() -> CpuLocks {
+        CpuLocks {
+            held: [const { AtomicU64::new(0) }; MAX_HELD],
+            waiting: AtomicU64::new(0),
+            progress: AtomicU64::new(0),
+        }
+    }
+
+    fn held(&self) -> impl Iterator<Item = u64> + '_ {
+        self.held
+            .iter()
+            .map(|lock| lock.load(Ordering::Relaxed))
+            .filter(|&lock| lock != 0)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) == 0 && self.held().next().is_none()
+    }
+}
+
+static CPU_LOCKS: PerCpu<CpuLocks> = PerCpu::new([const { CpuLocks::new() }; MAX_CPUS]);
+
+/// The timeout in ticks, or zero if the watchdog is off.
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECS * TIMER_HZ);
+
+/// Set once the watchdog fires, so the dump and panic happen once.
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Set the timeout from `watchdog=<seconds>`. Zero turns the watchdog off.
+pub fn set_timeout_secs(secs: u64) {
+    TIMEOUT_TICKS.store(secs.saturating_mul(TIMER_HZ), Ordering::Relaxed);
+}
+
+/// Note that the current CPU is about to wait for the lock at `lock`. Returns
+/// what it was waiting for before, e.g. when an interrupt handler takes a lock
+/// while the task it interrupted waits, to pass to `acquired`.
+pub fn waiting(lock: u64) -> u64 {
+    CPU_LOCKS.get().waiting.swap(lock, Ordering::Relaxed)
+}
+
+/// Note that the current CPU took the lock at `lock`. `was_waiting` is what
+/// `waiting` returned.
+pub fn acquired(lock: u64, was_waiting: u64) {
+    let cpu = CPU_LOCKS.get();
+    if let Some(slot) = cpu
+        .held
+        .iter()
+        .find(|slot| slot.load(Ordering::Relaxed) == 0)
+    {
+        slot.store(lock, Ordering::Relaxed);
+    }
+    cpu.waiting.store(was_waiting, Ordering::Relaxed);
+}
+
+/// Note that the current CPU released the lock at `lock`.
+pub fn released(lock: u64) {
+    let cpu = CPU_LOCKS.get();
+    if let Some(slot) = cpu
+        .held
+        .iter()
+        .rev()
+        .find(|slot| slot.load(Ordering::Relaxed) == lock)
+    {
+        slot.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Note that the current CPU switched tasks.
+pub fn switched() {
+    CPU_LOCKS
+        .get()
+        .progress
+        .store(sched::current_tick(), Ordering::Relaxed);
+}
+
+/// Check the current CPU for a lockup. Called from the timer interrupt
+/// handler after `sched::timer_tick`.
+pub fn timer_tick() {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return;
+    }
+    let cpu = CPU_LOCKS.get();
+    let now = sched::current_tick();
+    if cpu.is_idle() {
+        cpu.progress.store(now, Ordering::Relaxed);
+        return;
+    }
+    let stuck = now.saturating_sub(cpu.progress.load(Ordering::Relaxed));
+    if stuck < timeout || FIRED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut emergency =
+        unsafe { shared::log::EmergencyWriter::new(cfg!(feature = "qemu_debugcon")) };
+    let _ = writeln!(
+        &mut emergency,
+        "watchdog: CPU {} stuck for {stuck} ticks in task {}",
+        smp::current_cpu(),
+        sched::current_task_label()
+    );
+    dump_locks(&mut emergency);
+    sched::dump_tasks(&mut emergency);
+    panic!(
+        "watchdog: no task switch for {} s while holding or waiting for a lock",
+        stuck / TIMER_HZ
+    );
+}
+
+/// Write each CPU's registered locks to `writer`.
+fn dump_locks(writer: &mut impl Write) {
+    for (index, cpu) in CPU_LOCKS.iter().enumerate() {
+        for lock in cpu.held() {
+            let _ = writeln!(writer, "locks: CPU {index} holds {}", LockName(lock));
+        }
+        match cpu.waiting.load(Ordering::Relaxed) {
+            0 => {}
+            lock => {
+                let _ = writeln!(writer, "locks: CPU {index} waits for {}", LockName(lock));
+            }
+        }
+    }
+}
+
+/// Formats a lock address, with the lock's name if it is known.
+struct LockName(u64);
+
+impl core::fmt::Display for LockName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match sched::lock_name(self.0) {
+            Some(name) => write!(f, "{:#x} ({name})", self.0),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}